@@ -57,6 +57,35 @@ pub fn benchmark(c: &mut Criterion) {
         })
     });
 
+    let mut pooled_sidecar = runtime
+        .block_on(
+            JsSidecar::builder()
+                .num_workers(1)
+                .context_pool_size(4)
+                .build(),
+        )
+        .unwrap();
+
+    group.bench_function("only_execution_context_pool", |b| {
+        b.to_async(&runtime).iter_custom(|iters| {
+            pooled_sidecar.connect().then(move |conn| async move {
+                let mut conn = conn.unwrap();
+                let now = std::time::Instant::now();
+                for _ in 0..iters {
+                    conn.run_script_and_wait(RunScriptArgs {
+                        code: "2 + 2".into(),
+                        expr: true,
+                        recreate_context: true,
+                        ..Default::default()
+                    })
+                    .await
+                    .unwrap();
+                }
+                now.elapsed()
+            })
+        })
+    });
+
     group.bench_function("connection_recycle_overhead", |b| {
         b.to_async(&runtime).iter_with_large_drop(|| async {
             let _ = sidecar.connect().await.unwrap();
@@ -78,6 +107,7 @@ pub fn benchmark(c: &mut Criterion) {
     });
 
     runtime.block_on(sidecar.close());
+    runtime.block_on(pooled_sidecar.close());
 
     group.finish();
 }
@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{Connection, PoolConnection};
+
+/// How many session keys keep a connection by default.
+pub(crate) const DEFAULT_MAX_SESSION_KEYS: usize = 64;
+
+/// Connections set aside for session keys, so that later calls to
+/// [JsSidecar::connect_for_key](crate::JsSidecar::connect_for_key) with the same key get the same
+/// worker context back.
+///
+/// These connections stay checked out of the pool while they are parked here, so that the pool
+/// doesn't reset their contexts.
+pub(crate) struct SessionConnections {
+    connections: Mutex<HashMap<String, PoolConnection>>,
+    max_keys: usize,
+}
+
+impl SessionConnections {
+    pub fn new(max_keys: usize) -> Self {
+        Self {
+            connections: Mutex::new(HashMap::new()),
+            max_keys,
+        }
+    }
+
+    /// Take the connection parked for `key`, if there is one.
+    pub fn take(&self, key: &str) -> Option<PoolConnection> {
+        self.connections.lock().unwrap().remove(key)
+    }
+
+    /// Park a connection for `key`. If the maximum number of keys is reached, the connection that
+    /// was least recently active goes back to the pool to make room.
+    ///
+    /// If another connection was parked for the key while this one was in use, the existing
+    /// connection is kept and this one goes back to the pool.
+    pub fn park(&self, key: String, conn: PoolConnection) {
+        if self.max_keys == 0 {
+            return;
+        }
+
+        let mut connections = self.connections.lock().unwrap();
+        if connections.contains_key(&key) {
+            return;
+        }
+
+        if connections.len() >= self.max_keys {
+            take_oldest(&mut connections);
+        }
+
+        connections.insert(key, conn);
+    }
+
+    /// Return the connection that was least recently active to the pool, so that a checkout that
+    /// would otherwise wait can have it. Returns false if no connections are parked.
+    pub fn evict_oldest(&self) -> bool {
+        let oldest = take_oldest(&mut self.connections.lock().unwrap());
+        oldest.is_some()
+    }
+
+    /// Return connections that haven't been used for `timeout` to the pool.
+    pub fn evict_idle(&self, timeout: Duration) {
+        self.connections
+            .lock()
+            .unwrap()
+            .retain(|_, conn| !conn.is_idle(timeout));
+    }
+
+    /// Return all the parked connections to the pool.
+    pub fn clear(&self) {
+        self.connections.lock().unwrap().clear();
+    }
+}
+
+/// Remove the connection that was least recently active.
+fn take_oldest(connections: &mut HashMap<String, PoolConnection>) -> Option<PoolConnection> {
+    let oldest = connections
+        .iter()
+        .min_by_key(|(_, conn)| conn.last_activity())
+        .map(|(key, _)| key.clone())?;
+    connections.remove(&oldest)
+}
+
+/// A connection for a session key, from [JsSidecar::connect_for_key](crate::JsSidecar::connect_for_key).
+///
+/// When this is dropped, the connection is kept for the key instead of returning to the pool,
+/// so that the next connection for the key can reuse its context.
+pub struct KeyedConnection {
+    key: String,
+    conn: Option<PoolConnection>,
+    resumed: bool,
+    sessions: Arc<SessionConnections>,
+}
+
+impl KeyedConnection {
+    pub(crate) fn new(
+        key: String,
+        conn: PoolConnection,
+        resumed: bool,
+        sessions: Arc<SessionConnections>,
+    ) -> Self {
+        Self {
+            key,
+            conn: Some(conn),
+            resumed,
+            sessions,
+        }
+    }
+
+    /// The session key that this connection is for.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns true if this connection was used for the same key before, so its context still
+    /// contains the state left by the earlier runs. Otherwise this is a new connection from the
+    /// pool with a fresh context.
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// Return the connection to the pool instead of keeping it for the key.
+    pub fn into_pool_connection(mut self) -> PoolConnection {
+        self.conn
+            .take()
+            .expect("connection is present until dropped")
+    }
+}
+
+impl std::fmt::Debug for KeyedConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyedConnection")
+            .field("key", &self.key)
+            .field("resumed", &self.resumed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Deref for KeyedConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn
+            .as_ref()
+            .expect("connection is present until dropped")
+    }
+}
+
+impl DerefMut for KeyedConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.conn
+            .as_mut()
+            .expect("connection is present until dropped")
+    }
+}
+
+impl Drop for KeyedConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.sessions.park(std::mem::take(&mut self.key), conn);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::{collections::HashSet, sync::Arc};
+
+use sha2::{Digest, Sha256};
+
+use crate::{corpus::hex_string, Error, RunScriptArgs};
+
+/// Decides whether a script may run, for example by checking a signature passed in
+/// [RunScriptArgs::signature]. Set it with
+/// [JsSidecarBuilder::script_verifier](crate::JsSidecarBuilder::script_verifier).
+///
+/// `verify` is called inline before each script is sent to the worker, so it should be quick.
+pub trait ScriptVerifier: Send + Sync + 'static {
+    /// Return `Err` with the reason if the script isn't approved.
+    fn verify(&self, args: &RunScriptArgs) -> Result<(), String>;
+}
+
+impl std::fmt::Debug for dyn ScriptVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ScriptVerifier")
+    }
+}
+
+/// The checks that scripts must pass before they are sent to the worker.
+#[derive(Debug, Default)]
+pub(crate) struct ScriptApproval {
+    /// The hex-encoded SHA-256 hashes of the code that is allowed to run.
+    pub allowed_hashes: Option<HashSet<String>>,
+    pub verifier: Option<Arc<dyn ScriptVerifier>>,
+}
+
+impl ScriptApproval {
+    /// Check that every piece of code in the script is approved.
+    pub fn check(&self, args: &RunScriptArgs) -> Result<(), Error> {
+        let not_approved = |reason: String| Error::ScriptNotApproved {
+            name: args.name.to_string(),
+            reason,
+        };
+
+        if let Some(allowed) = &self.allowed_hashes {
+            let code = (!args.code.is_empty()).then(|| ("code", args.code.as_bytes()));
+            let modules = args
+                .modules
+                .iter()
+                .map(|m| (m.name.as_ref(), m.code.as_bytes()));
+            let functions = args
+                .functions
+                .iter()
+                .map(|f| (f.name.as_ref(), f.code.as_bytes()));
+            let wasm = args
+                .wasm_modules
+                .iter()
+                .map(|w| (w.name.as_ref(), &w.bytes[..]));
+
+            for (name, code) in code.into_iter().chain(modules).chain(functions).chain(wasm) {
+                let hash = hex_string(&Sha256::digest(code));
+                if !allowed.contains(&hash) {
+                    return Err(not_approved(format!(
+                        "{name} has hash {hash}, which is not in the allowlist"
+                    )));
+                }
+            }
+        }
+
+        if let Some(verifier) = &self.verifier {
+            verifier.verify(args).map_err(not_approved)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CodeModule;
+
+    fn hash(code: &str) -> String {
+        hex_string(&Sha256::digest(code.as_bytes()))
+    }
+
+    #[test]
+    fn allowlist() {
+        let approval = ScriptApproval {
+            allowed_hashes: Some([hash("1 + 1"), hash("export const a = 1")].into()),
+            verifier: None,
+        };
+
+        let mut args = RunScriptArgs {
+            name: "script".into(),
+            code: "1 + 1".into(),
+            ..Default::default()
+        };
+        approval.check(&args).unwrap();
+
+        args.modules.push(CodeModule {
+            name: "a".into(),
+            code: "export const a = 1".into(),
+        });
+        approval.check(&args).unwrap();
+
+        args.modules.push(CodeModule {
+            name: "b".into(),
+            code: "export const b = 2".into(),
+        });
+        let Err(Error::ScriptNotApproved { name, reason }) = approval.check(&args) else {
+            panic!("Expected module b to be rejected");
+        };
+        assert_eq!(name, "script");
+        assert!(reason.starts_with("b has hash "), "{reason}");
+
+        // Runs that only set up the context have no code to check.
+        approval.check(&RunScriptArgs::default()).unwrap();
+    }
+
+    #[test]
+    fn verifier() {
+        struct Signed;
+
+        impl ScriptVerifier for Signed {
+            fn verify(&self, args: &RunScriptArgs) -> Result<(), String> {
+                match args.signature.as_deref() {
+                    Some("valid") => Ok(()),
+                    _ => Err("bad signature".to_string()),
+                }
+            }
+        }
+
+        let approval = ScriptApproval {
+            allowed_hashes: None,
+            verifier: Some(Arc::new(Signed)),
+        };
+
+        let mut args = RunScriptArgs {
+            code: "1".into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            approval.check(&args),
+            Err(Error::ScriptNotApproved { reason, .. }) if reason == "bad signature"
+        ));
+
+        args.signature = Some("valid".to_string());
+        approval.check(&args).unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{corpus::hex_string, CodeModule, FunctionDef, RunScriptArgs};
+
+/// The `previous_hash` of the first record in an audit log.
+pub const AUDIT_GENESIS_HASH: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// How a run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditStatus {
+    /// The worker sent the run's response.
+    Succeeded,
+    /// The worker sent an error, such as an exception thrown by the script.
+    Failed,
+    /// The connection was recycled or closed before the run finished.
+    Abandoned,
+}
+
+impl AuditStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuditStatus::Succeeded => "succeeded",
+            AuditStatus::Failed => "failed",
+            AuditStatus::Abandoned => "abandoned",
+        }
+    }
+}
+
+/// The full code of a run, included in an [AuditRecord] when
+/// [AuditLog::record_code] is enabled.
+#[derive(Debug, Clone)]
+pub struct AuditCode {
+    /// The main code of the script
+    pub code: String,
+    /// The modules passed with the script
+    pub modules: Vec<CodeModule>,
+    /// The functions passed with the script
+    pub functions: Vec<FunctionDef>,
+}
+
+/// A record of one script run.
+///
+/// The records form a hash chain: each one's [hash](Self::hash) covers its own fields and the
+/// hash of the record before it, so a record that is changed, removed, or reordered after the
+/// fact is detected by [verify_audit_chain].
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    /// The position of the record in the log, starting at 0.
+    pub sequence: u64,
+    /// When the run was sent to the worker.
+    pub started_at: SystemTime,
+    /// How long the run took, from sending it to receiving its response or error.
+    pub duration: Duration,
+    /// The script's [name](RunScriptArgs::name).
+    pub name: String,
+    /// The hex-encoded SHA-256 hash of the script's code, together with the name and code of each
+    /// of its modules and functions and the name and bytes of each of its WebAssembly modules.
+    pub code_hash: String,
+    /// The run's [tenant](RunScriptArgs::tenant).
+    pub tenant: Option<String>,
+    /// How the run ended.
+    pub status: AuditStatus,
+    /// The script's code, when [AuditLog::record_code] is enabled. This isn't covered by the
+    /// record's hash, since [code_hash](Self::code_hash) already is.
+    pub code: Option<AuditCode>,
+    /// The [hash](Self::hash) of the previous record, or [AUDIT_GENESIS_HASH] for the first one.
+    pub previous_hash: String,
+    /// The hex-encoded SHA-256 hash of this record.
+    pub hash: String,
+}
+
+impl AuditRecord {
+    /// Compute the hash of the record from its fields, to compare with [hash](Self::hash).
+    pub fn compute_hash(&self) -> String {
+        let started_at = self
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.previous_hash.as_bytes());
+        hasher.update(self.sequence.to_be_bytes());
+        hasher.update(started_at.to_be_bytes());
+        hasher.update(self.duration.as_nanos().to_be_bytes());
+        update_field(&mut hasher, &self.name);
+        update_field(&mut hasher, &self.code_hash);
+        match &self.tenant {
+            Some(tenant) => {
+                hasher.update([1]);
+                update_field(&mut hasher, tenant);
+            }
+            None => hasher.update([0]),
+        }
+        update_field(&mut hasher, self.status.as_str());
+        hex_string(&hasher.finalize())
+    }
+}
+
+/// Check that `records`, a contiguous run of an audit log, link together and haven't been
+/// altered. Returns the index of the first record that doesn't check out.
+///
+/// Pass records from the start of the log to also check that none were removed before them.
+/// Records removed from the end of the log can only be detected by comparing with a later
+/// record's hash kept elsewhere.
+pub fn verify_audit_chain(records: &[AuditRecord]) -> Result<(), usize> {
+    for (i, record) in records.iter().enumerate() {
+        let linked = match i.checked_sub(1).map(|prev| &records[prev]) {
+            Some(prev) => record.previous_hash == prev.hash && record.sequence == prev.sequence + 1,
+            None => record.sequence != 0 || record.previous_hash == AUDIT_GENESIS_HASH,
+        };
+        if !linked || record.hash != record.compute_hash() {
+            return Err(i);
+        }
+    }
+    Ok(())
+}
+
+/// Hash a length-prefixed field, so that adjacent fields can't run into each other.
+fn update_field(hasher: &mut Sha256, value: &str) {
+    update_bytes(hasher, value.as_bytes());
+}
+
+/// Hash length-prefixed bytes, as with [update_field].
+fn update_bytes(hasher: &mut Sha256, value: &[u8]) {
+    hasher.update((value.len() as u64).to_be_bytes());
+    hasher.update(value);
+}
+
+/// Receives a record of every script run.
+///
+/// `record` is called inline as runs finish, in the order of the hash chain, so implementations
+/// should hand the record off to a channel or buffer rather than doing slow I/O directly.
+pub trait AuditSink: Send + Sync + 'static {
+    /// Record a finished run.
+    fn record(&self, record: AuditRecord);
+}
+
+/// Passes a record of every script run on the sidecar's connections to an [AuditSink]. Enable it
+/// with [JsSidecarBuilder::audit_log](crate::JsSidecarBuilder::audit_log).
+pub struct AuditLog {
+    sink: Box<dyn AuditSink>,
+    record_code: bool,
+    /// The sequence number and hash of the last record.
+    chain: Mutex<(u64, String)>,
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("record_code", &self.record_code)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AuditLog {
+    /// Create an audit log which sends a record of each run to `sink`.
+    pub fn new(sink: impl AuditSink) -> Self {
+        Self {
+            sink: Box::new(sink),
+            record_code: false,
+            chain: Mutex::new((0, AUDIT_GENESIS_HASH.to_string())),
+        }
+    }
+
+    /// Include the full code of each run in its record, rather than just its hash.
+    pub fn record_code(mut self, record_code: bool) -> Self {
+        self.record_code = record_code;
+        self
+    }
+
+    /// Continue an existing log from its last record, so that the new records link to it.
+    pub fn resume_after(self, last: &AuditRecord) -> Self {
+        *self.chain.lock().unwrap() = (last.sequence + 1, last.hash.clone());
+        self
+    }
+
+    /// Note the details of a run as it is sent to the worker.
+    pub(crate) fn start(&self, args: &RunScriptArgs) -> PendingAudit {
+        let mut hasher = Sha256::new();
+        update_field(&mut hasher, &args.code);
+        for module in &args.modules {
+            update_field(&mut hasher, &module.name);
+            update_field(&mut hasher, &module.code);
+        }
+        for function in &args.functions {
+            update_field(&mut hasher, &function.name);
+            update_field(&mut hasher, &function.code);
+        }
+        for module in &args.wasm_modules {
+            update_field(&mut hasher, &module.name);
+            update_bytes(&mut hasher, &module.bytes);
+        }
+
+        PendingAudit {
+            started_at: SystemTime::now(),
+            start: Instant::now(),
+            name: args.name.to_string(),
+            code_hash: hex_string(&hasher.finalize()),
+            tenant: args.tenant.clone(),
+            code: self.record_code.then(|| AuditCode {
+                code: args.code.to_string(),
+                modules: args.modules.clone(),
+                functions: args.functions.clone(),
+            }),
+        }
+    }
+
+    /// Add the finished run to the chain and pass its record to the sink.
+    pub(crate) fn finish(&self, run: PendingAudit, status: AuditStatus) {
+        let mut chain = self.chain.lock().unwrap();
+        let mut record = AuditRecord {
+            sequence: chain.0,
+            started_at: run.started_at,
+            duration: run.start.elapsed(),
+            name: run.name,
+            code_hash: run.code_hash,
+            tenant: run.tenant,
+            status,
+            code: run.code,
+            previous_hash: std::mem::take(&mut chain.1),
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash();
+        *chain = (record.sequence + 1, record.hash.clone());
+
+        // Keep the lock while recording, so that the sink sees the records in chain order.
+        self.sink.record(record);
+    }
+}
+
+/// A run that has been sent to the worker but hasn't finished.
+#[derive(Debug)]
+pub(crate) struct PendingAudit {
+    started_at: SystemTime,
+    start: Instant,
+    name: String,
+    code_hash: String,
+    tenant: Option<String>,
+    code: Option<AuditCode>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::WasmModule;
+
+    #[derive(Clone, Default)]
+    struct Records(Arc<Mutex<Vec<AuditRecord>>>);
+
+    impl AuditSink for Records {
+        fn record(&self, record: AuditRecord) {
+            self.0.lock().unwrap().push(record);
+        }
+    }
+
+    fn args(code: &'static str) -> RunScriptArgs {
+        RunScriptArgs {
+            name: "test".into(),
+            code: code.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hash_chain() {
+        let records = Records::default();
+        let log = AuditLog::new(records.clone());
+        log.finish(log.start(&args("1")), AuditStatus::Succeeded);
+        log.finish(log.start(&args("2")), AuditStatus::Failed);
+        log.finish(log.start(&args("1")), AuditStatus::Abandoned);
+
+        let mut records = records.0.lock().unwrap().clone();
+        assert_eq!(verify_audit_chain(&records), Ok(()));
+        assert_eq!(records[0].previous_hash, AUDIT_GENESIS_HASH);
+        assert_eq!(records[2].sequence, 2);
+        assert_eq!(records[0].code_hash, records[2].code_hash);
+        assert_ne!(records[0].code_hash, records[1].code_hash);
+        assert!(records[0].code.is_none());
+
+        // The WebAssembly modules are part of the code.
+        let mut with_wasm = args("1");
+        with_wasm.wasm_modules.push(WasmModule {
+            name: "wasm".into(),
+            bytes: Bytes::from_static(b"\0asm"),
+        });
+        let pending = log.start(&with_wasm);
+        assert_ne!(pending.code_hash, records[0].code_hash);
+        // A later part of the log verifies on its own.
+        assert_eq!(verify_audit_chain(&records[1..]), Ok(()));
+
+        let mut altered = records.clone();
+        altered[1].status = AuditStatus::Succeeded;
+        assert_eq!(verify_audit_chain(&altered), Err(1));
+
+        records.remove(1);
+        assert_eq!(verify_audit_chain(&records), Err(1));
+    }
+
+    #[test]
+    fn resume_and_record_code() {
+        let records = Records::default();
+        let log = AuditLog::new(records.clone());
+        log.finish(log.start(&args("1")), AuditStatus::Succeeded);
+        let first = records.0.lock().unwrap()[0].clone();
+
+        let log = AuditLog::new(records.clone())
+            .record_code(true)
+            .resume_after(&first);
+        log.finish(log.start(&args("2")), AuditStatus::Succeeded);
+
+        let records = records.0.lock().unwrap();
+        assert_eq!(verify_audit_chain(&records), Ok(()));
+        assert_eq!(records[1].code.as_ref().unwrap().code, "2");
+    }
+}
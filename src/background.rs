@@ -0,0 +1,196 @@
+//! Run the sidecar on a dedicated Tokio runtime, for applications that use a different async
+//! runtime such as `smol` or `async-std`.
+
+use std::{borrow::Cow, future::Future, sync::Arc};
+
+use futures::channel::oneshot;
+use serde::de::DeserializeOwned;
+use tokio::runtime::Runtime;
+
+use crate::{Error, JsSidecar, JsSidecarBuilder, RunScriptAndWaitResult, RunScriptArgs};
+
+/// A [JsSidecar] running on its own background Tokio runtime.
+///
+/// The sidecar's sockets, processes, and timers all live on a runtime with a dedicated thread,
+/// and the methods here return futures that can be awaited from any executor.
+///
+/// ```no_run
+/// # fn example() -> Result<(), js_sidecar::Error> {
+/// use js_sidecar::{background::BackgroundSidecar, JsSidecarBuilder};
+///
+/// futures::executor::block_on(async {
+///     let sidecar = BackgroundSidecar::start(JsSidecarBuilder::new().num_workers(1)).await?;
+///     let value: i32 = sidecar.eval("1 + 1").await?;
+///     assert_eq!(value, 2);
+///     sidecar.close().await;
+///     Ok(())
+/// })
+/// # }
+/// ```
+pub struct BackgroundSidecar {
+    sidecar: Option<Arc<JsSidecar>>,
+    runtime: Option<Runtime>,
+}
+
+impl BackgroundSidecar {
+    /// Start a runtime and then start the sidecar on it, with the configuration from `builder`.
+    pub async fn start(builder: JsSidecarBuilder) -> Result<Self, Error> {
+        // A single worker thread keeps connections reading from their sockets and the worker
+        // output flowing while nothing is waiting on them.
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("js_sidecar")
+            .enable_all()
+            .build()
+            .map_err(Error::StartWorker)?;
+
+        let sidecar = spawn_on(&runtime, builder.build()).await?;
+        Ok(Self {
+            sidecar: Some(Arc::new(sidecar)),
+            runtime: Some(runtime),
+        })
+    }
+
+    pub(crate) fn runtime(&self) -> &Runtime {
+        self.runtime
+            .as_ref()
+            .expect("runtime is present until dropped")
+    }
+
+    pub(crate) fn sidecar(&self) -> &JsSidecar {
+        self.sidecar
+            .as_deref()
+            .expect("sidecar is present until closed")
+    }
+
+    /// Run a task on the background runtime with access to the [JsSidecar], and wait for its
+    /// result. This can be used to reach the parts of the async API that aren't wrapped here.
+    pub async fn spawn<F, Fut>(&self, f: F) -> Fut::Output
+    where
+        F: FnOnce(Arc<JsSidecar>) -> Fut,
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let sidecar = self
+            .sidecar
+            .clone()
+            .expect("sidecar is present until closed");
+        spawn_on(self.runtime(), f(sidecar)).await
+    }
+
+    /// Run a script on a connection from the pool and wait for it to finish.
+    /// See [JsSidecar::run].
+    pub async fn run(&self, args: RunScriptArgs) -> Result<RunScriptAndWaitResult, Error> {
+        self.spawn(|sidecar| async move { sidecar.run(args).await })
+            .await
+    }
+
+    /// Evaluate a JavaScript expression and convert its value to `T`. See [JsSidecar::eval].
+    pub async fn eval<T: DeserializeOwned + Send + 'static>(
+        &self,
+        expr: impl Into<Cow<'static, str>>,
+    ) -> Result<T, Error> {
+        let expr = expr.into();
+        self.spawn(|sidecar| async move { sidecar.eval(expr).await })
+            .await
+    }
+
+    /// Shut down Node.js and wait for it to exit, then stop the runtime.
+    ///
+    /// If this isn't called, dropping the value stops the runtime without waiting, and the
+    /// Node.js process may be left to exit on its own.
+    pub async fn close(mut self) {
+        if let Some(sidecar) = self.sidecar.take() {
+            spawn_on(self.runtime(), async move {
+                // Tasks from `spawn` may still hold a reference, in which case the last one to
+                // finish closes the sidecar when it drops it.
+                if let Some(mut sidecar) = Arc::into_inner(sidecar) {
+                    sidecar.close().await;
+                }
+            })
+            .await;
+        }
+
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+impl Drop for BackgroundSidecar {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            {
+                // The sidecar's own cleanup spawns tasks, so it has to be dropped inside the
+                // runtime.
+                let _guard = runtime.enter();
+                self.sidecar.take();
+            }
+
+            // Dropping a runtime blocks, which isn't allowed inside another async runtime.
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Run `future` on `runtime` and return a future, usable from any executor, for its output.
+async fn spawn_on<F>(runtime: &Runtime, future: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(async move {
+        tx.send(future.await).ok();
+    });
+
+    rx.await
+        .expect("background task panicked or its runtime stopped")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn run_without_tokio() {
+        futures::executor::block_on(async {
+            let sidecar = BackgroundSidecar::start(JsSidecarBuilder::new().num_workers(1))
+                .await
+                .unwrap();
+
+            let result = sidecar
+                .run(RunScriptArgs {
+                    code: "input + 1".into(),
+                    expr: true,
+                    globals: [("input".into(), json!(1))].into_iter().collect(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(result.response.return_value, Some(json!(2)));
+
+            let value: String = sidecar.eval("'a' + 'b'").await.unwrap();
+            assert_eq!(value, "ab");
+
+            let connected = sidecar
+                .spawn(|sidecar| async move { sidecar.connect().await.map(|_| ()) })
+                .await;
+            assert!(connected.is_ok());
+
+            sidecar.close().await;
+        });
+    }
+
+    #[test]
+    fn drop_without_close() {
+        futures::executor::block_on(async {
+            let sidecar = BackgroundSidecar::start(JsSidecarBuilder::new().num_workers(1))
+                .await
+                .unwrap();
+            drop(sidecar);
+        });
+    }
+}
@@ -0,0 +1,127 @@
+//! A synchronous wrapper around [JsSidecar], for code that doesn't otherwise use async.
+
+use std::{borrow::Cow, future::Future};
+
+use serde::de::DeserializeOwned;
+
+use crate::{
+    background::BackgroundSidecar, Error, JsSidecar, JsSidecarBuilder, RunScriptAndWaitResult,
+    RunScriptArgs,
+};
+
+/// A [JsSidecar] with its own Tokio runtime, exposing a synchronous API. This is a
+/// [BackgroundSidecar] whose methods wait for their results.
+///
+/// This is meant for code that doesn't otherwise use async, such as build scripts, CLI tools,
+/// and tests. The methods block the calling thread, so they must not be called from inside an
+/// async runtime.
+///
+/// ```no_run
+/// # fn example() -> Result<(), js_sidecar::Error> {
+/// use js_sidecar::blocking::JsSidecarBlocking;
+///
+/// let sidecar = JsSidecarBlocking::new(Some(1))?;
+/// let value: i32 = sidecar.eval("1 + 1")?;
+/// assert_eq!(value, 2);
+/// # Ok(())
+/// # }
+/// ```
+pub struct JsSidecarBlocking {
+    inner: Option<BackgroundSidecar>,
+}
+
+impl JsSidecarBlocking {
+    /// Start Node.js with `num_workers` worker processes, or one per CPU if omitted.
+    ///
+    /// Use [JsSidecarBlocking::from_builder] for more configuration options.
+    pub fn new(num_workers: Option<u32>) -> Result<Self, Error> {
+        let mut builder = JsSidecarBuilder::new();
+        builder.num_workers = num_workers;
+        Self::from_builder(builder)
+    }
+
+    /// Start Node.js with the configuration from `builder`.
+    pub fn from_builder(builder: JsSidecarBuilder) -> Result<Self, Error> {
+        let inner = futures::executor::block_on(BackgroundSidecar::start(builder))?;
+        Ok(Self { inner: Some(inner) })
+    }
+
+    fn inner(&self) -> &BackgroundSidecar {
+        self.inner
+            .as_ref()
+            .expect("sidecar is present until dropped")
+    }
+
+    /// The underlying [JsSidecar].
+    pub fn sidecar(&self) -> &JsSidecar {
+        self.inner().sidecar()
+    }
+
+    /// Run a future on the sidecar's runtime and wait for it to finish. This can be used to reach
+    /// the parts of the async API that aren't wrapped here.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.inner().runtime().block_on(future)
+    }
+
+    /// Run a script on a connection from the pool and wait for it to finish.
+    /// See [JsSidecar::run].
+    pub fn run_script_and_wait(
+        &self,
+        args: RunScriptArgs,
+    ) -> Result<RunScriptAndWaitResult, Error> {
+        self.block_on(self.sidecar().run(args))
+    }
+
+    /// Evaluate a JavaScript expression and convert its value to `T`. See [JsSidecar::eval].
+    pub fn eval<T: DeserializeOwned>(
+        &self,
+        expr: impl Into<Cow<'static, str>>,
+    ) -> Result<T, Error> {
+        self.block_on(self.sidecar().eval(expr))
+    }
+
+    /// Shut down Node.js and wait for it to exit. This also happens when the value is dropped.
+    pub fn close(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            futures::executor::block_on(inner.close());
+        }
+    }
+}
+
+impl Drop for JsSidecarBlocking {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn run_and_eval() {
+        let sidecar = JsSidecarBlocking::new(Some(1)).unwrap();
+
+        let result = sidecar
+            .run_script_and_wait(RunScriptArgs {
+                code: "console.log('hi'); globalThis.output = input * 2;".into(),
+                globals: [("input".into(), json!(21))].into_iter().collect(),
+                return_keys: vec!["output".to_string()],
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(result.response.globals["output"], json!(42));
+        assert_eq!(result.logs[0].message, json!(["hi"]));
+
+        let value: String = sidecar.eval("'a' + 'b'").unwrap();
+        assert_eq!(value, "ab");
+
+        sidecar.close();
+    }
+}
@@ -0,0 +1,707 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use deadpool::managed::QueueMode;
+
+use crate::{
+    process::ProcessSettings, AuditLog, CgroupLimits, ChannelOverflow, CorpusCollector, Error,
+    Hardening, JsSidecar, KvBackend, LogLevel, ModuleResolver, ScheduleStore, ScriptVerifier,
+    TenantQuota, Transport,
+};
+
+/// How the workers keep a script that stops responding from affecting other runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Isolation {
+    /// Run every connection to a worker on the worker's main thread. A script that blocks the
+    /// thread past its timeout gets the whole worker process killed, failing the other
+    /// connections to it, after [kill_after_timeout](JsSidecarBuilder::kill_after_timeout).
+    #[default]
+    Process,
+    /// Run each connection in its own Node.js worker thread. A script that blocks the thread past
+    /// its timeout has just that thread terminated, one second after the timeout, and the
+    /// connection carries on with a fresh context. Each connection costs a thread and a V8
+    /// isolate, which adds a few milliseconds to opening it and some memory while it is open.
+    Thread,
+}
+
+/// Configuration for starting a [JsSidecar].
+#[derive(Debug, Clone, Default)]
+pub struct JsSidecarBuilder {
+    pub(crate) num_workers: Option<u32>,
+    pub(crate) socket_dir: Option<PathBuf>,
+    pub(crate) script_dir: Option<PathBuf>,
+    pub(crate) corpus: Option<Arc<CorpusCollector>>,
+    pub(crate) audit: Option<Arc<AuditLog>>,
+    pub(crate) allowed_script_hashes: Option<HashSet<String>>,
+    pub(crate) script_verifier: Option<Arc<dyn ScriptVerifier>>,
+    pub(crate) capture_output: bool,
+    pub(crate) max_string_bytes: Option<usize>,
+    pub(crate) max_log_messages: Option<u32>,
+    pub(crate) max_log_bytes: Option<usize>,
+    pub(crate) min_log_level: Option<LogLevel>,
+    pub(crate) replace_invalid_unicode: bool,
+    pub(crate) channel_size: Option<usize>,
+    pub(crate) channel_overflow: ChannelOverflow,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) min_idle: Option<usize>,
+    pub(crate) run_retries: Option<u32>,
+    pub(crate) kill_after_timeout: Option<Duration>,
+    pub(crate) isolation: Isolation,
+    pub(crate) process: ProcessSettings,
+    pub(crate) managed_cgroup: Option<PathBuf>,
+    pub(crate) cgroup_limits: Option<CgroupLimits>,
+    pub(crate) hardening: Option<Hardening>,
+    pub(crate) prefer_fast_workers: bool,
+    pub(crate) prefer_idle_workers: bool,
+    pub(crate) load_report_interval: Option<Duration>,
+    pub(crate) context_pool_size: Option<usize>,
+    pub(crate) max_session_keys: Option<usize>,
+    pub(crate) max_requests_per_connection: Option<u64>,
+    pub(crate) max_connection_age: Option<Duration>,
+    pub(crate) memory_report_interval: Option<Duration>,
+    pub(crate) max_worker_heap: Option<u64>,
+    pub(crate) pool_max_size: Option<usize>,
+    pub(crate) pool_wait_timeout: Option<Duration>,
+    pub(crate) max_queued_requests: Option<usize>,
+    pub(crate) slow_checkout_threshold: Option<Duration>,
+    pub(crate) tenant_quotas: HashMap<String, TenantQuota>,
+    pub(crate) default_tenant_quota: Option<TenantQuota>,
+    pub(crate) pool_create_timeout: Option<Duration>,
+    pub(crate) pool_recycle_timeout: Option<Duration>,
+    pub(crate) pool_verify_interval: Option<Duration>,
+    pub(crate) pool_heartbeat_interval: Option<Duration>,
+    pub(crate) pool_queue_mode: Option<QueueMode>,
+    pub(crate) websocket_addr: Option<SocketAddr>,
+    pub(crate) worker_url: Option<String>,
+    pub(crate) websocket_token: Option<String>,
+    pub(crate) transport: Option<Arc<dyn Transport>>,
+    pub(crate) management_addr: Option<SocketAddr>,
+    pub(crate) frame_checksums: bool,
+    pub(crate) strict_messages: bool,
+    pub(crate) compress_frames_over: Option<usize>,
+    pub(crate) shared_memory_over: Option<usize>,
+    pub(crate) max_frame_bytes: Option<usize>,
+    pub(crate) max_response_bytes: Option<usize>,
+    pub(crate) kv: Option<Arc<dyn KvBackend>>,
+    pub(crate) module_resolver: Option<Arc<dyn ModuleResolver>>,
+    pub(crate) url_imports: Vec<String>,
+    pub(crate) url_import_ttl: Option<Duration>,
+    pub(crate) schedule_store: Option<Arc<dyn ScheduleStore>>,
+}
+
+impl JsSidecarBuilder {
+    /// Create a builder with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of worker processes to start. If not set, Node.js will start one worker per CPU.
+    pub fn num_workers(mut self, num_workers: u32) -> Self {
+        self.num_workers = Some(num_workers);
+        self
+    }
+
+    /// The directory in which to create the Unix socket used to talk to the workers. Each sidecar
+    /// makes its own directory inside it for the socket. Defaults to the system temporary
+    /// directory.
+    ///
+    /// Unix socket paths are limited to around 100 bytes on most systems, so this should be a
+    /// short path.
+    pub fn socket_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.socket_dir = Some(dir.into());
+        self
+    }
+
+    /// The directory in which to write the worker script that Node.js runs.
+    /// Defaults to the system temporary directory.
+    pub fn script_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.script_dir = Some(dir.into());
+        self
+    }
+
+    /// Use a single pre-created directory for both the socket and the worker script.
+    pub fn runtime_dir(self, dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        self.socket_dir(dir.clone()).script_dir(dir)
+    }
+
+    /// Also accept connections over WebSocket at this address, so that the workers can be
+    /// reached through an HTTP proxy or from another host. Connections from this sidecar still
+    /// use the Unix socket.
+    ///
+    /// Requests that carry an `Origin` header are refused, so that web pages in a browser can't
+    /// connect. Set a [websocket_token](Self::websocket_token) to require one from every
+    /// connection; without it there is no authentication, and the listener should only be
+    /// exposed to trusted networks.
+    pub fn websocket_addr(mut self, addr: SocketAddr) -> Self {
+        self.websocket_addr = Some(addr);
+        self
+    }
+
+    /// Connect to workers at a WebSocket URL, such as `ws://localhost:9000`, instead of starting
+    /// Node.js. The other end can be another sidecar's [websocket_addr](Self::websocket_addr)
+    /// listener, a proxy in front of one, or any runtime that speaks the worker protocol over
+    /// binary WebSocket messages.
+    ///
+    /// The settings that control the Node.js process, such as [num_workers](Self::num_workers),
+    /// have no effect on the remote workers, but `num_workers` is still used to decide how many
+    /// connections [JsSidecar::prewarm] opens.
+    pub fn worker_url(mut self, url: impl Into<String>) -> Self {
+        self.worker_url = Some(url.into());
+        self
+    }
+
+    /// A secret that WebSocket connections present as an `Authorization: Bearer` header. The
+    /// [websocket_addr](Self::websocket_addr) listener refuses connections without it, and
+    /// connections to a [worker_url](Self::worker_url) send it.
+    pub fn websocket_token(mut self, token: impl Into<String>) -> Self {
+        self.websocket_token = Some(token.into());
+        self
+    }
+
+    /// Connect to workers through a custom [Transport] instead of starting Node.js, such as over
+    /// vsock to workers in a virtual machine. This takes the place of
+    /// [worker_url](Self::worker_url), and the settings that control the Node.js process have no
+    /// effect, as with `worker_url`.
+    pub fn transport(mut self, transport: impl Transport) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Add a CRC32 checksum to every message sent in either direction, for transports that can
+    /// damage data on the way, such as a proxy in front of [worker_url](Self::worker_url).
+    /// A message that fails its checksum is returned as [Error::ProtocolCorruption] from the call
+    /// waiting on the connection, and the connection is checked before it is reused.
+    ///
+    /// Without checksums, damaged frame headers are still detected, but a damaged payload is only
+    /// noticed if it fails to parse.
+    pub fn frame_checksums(mut self, enabled: bool) -> Self {
+        self.frame_checksums = enabled;
+        self
+    }
+
+    /// Fail a run with [Error::UnexpectedMessage] when a message arrives that doesn't belong to
+    /// any run that is still going, such as a pong for a ping that was already given up on, or a
+    /// late async error from a run that already finished. The run is abandoned and the connection
+    /// is checked before it is reused.
+    ///
+    /// By default these messages are set aside in
+    /// [take_stale_messages](crate::Connection::take_stale_messages) and the run carries on.
+    pub fn strict_messages(mut self, strict: bool) -> Self {
+        self.strict_messages = strict;
+        self
+    }
+
+    /// Compress messages in either direction with gzip when their payload is larger than
+    /// `min_bytes`. This trades CPU time for bandwidth, so it mostly helps when the workers are
+    /// reached over a network through [worker_url](Self::worker_url). Messages that don't get
+    /// smaller are sent uncompressed.
+    ///
+    /// The [max_frame_bytes](Self::max_frame_bytes) limit applies to a message both before and
+    /// after it is decompressed.
+    pub fn compress_frames(mut self, min_bytes: usize) -> Self {
+        self.compress_frames_over = Some(min_bytes);
+        self
+    }
+
+    /// Pass messages in either direction whose payload is larger than `min_bytes` through files
+    /// in shared memory, instead of copying them through the socket. This speeds up runs that
+    /// send or return tens of megabytes of globals. The files are created in a private directory
+    /// under `/dev/shm`, or the system temporary directory where that doesn't exist.
+    ///
+    /// This only applies to workers started by this sidecar, and has no effect when using
+    /// [worker_url](Self::worker_url). Messages that go through shared memory aren't compressed,
+    /// but they are still subject to [max_frame_bytes](Self::max_frame_bytes).
+    pub fn shared_memory_frames(mut self, min_bytes: usize) -> Self {
+        self.shared_memory_over = Some(min_bytes);
+        self
+    }
+
+    /// The largest single message that will be read from a worker. Larger messages are skipped
+    /// without being read into memory, and the call waiting on them fails with
+    /// [Error::MessageTooLarge]. Run responses larger than this are split into chunks by the
+    /// worker, so this limits memory use while reading rather than the size of a result; see
+    /// [max_response_bytes](Self::max_response_bytes) for that. Defaults to 64 MiB.
+    pub fn max_frame_bytes(mut self, limit: usize) -> Self {
+        self.max_frame_bytes = Some(limit);
+        self
+    }
+
+    /// The largest run response, the serialized return value and globals, that will be accepted
+    /// from a worker. The worker checks this after serializing the response, and sends an
+    /// [Error::MessageTooLarge] instead of a larger one. By default there is no limit.
+    pub fn max_response_bytes(mut self, limit: usize) -> Self {
+        self.max_response_bytes = Some(limit);
+        self
+    }
+
+    /// Serve a small HTTP management API at this address, with the health of the Node.js
+    /// process and statistics for each worker, for inspecting a running sidecar. The API is read
+    /// through [JsSidecar::health] and [JsSidecar::worker_stats], or directly over HTTP at
+    /// `/health` and `/workers`.
+    ///
+    /// When connecting to remote workers with [worker_url](Self::worker_url), this is instead the
+    /// address of the remote sidecar's management API.
+    pub fn management_addr(mut self, addr: SocketAddr) -> Self {
+        self.management_addr = Some(addr);
+        self
+    }
+
+    /// Capture the Node.js process's stdout and stderr instead of inheriting them from this
+    /// process. Captured lines are logged through `tracing` with the target `js_sidecar::worker`
+    /// and sent as [SidecarEvent](crate::SidecarEvent)s to [JsSidecar::subscribe] receivers.
+    pub fn capture_output(mut self, capture: bool) -> Self {
+        self.capture_output = capture;
+        self
+    }
+
+    /// Limit the size, in UTF-8 bytes, of each individual string in the globals sent to the
+    /// worker and in the globals and return value sent back. A string over the limit fails the
+    /// run with an error naming the path of the offending value.
+    ///
+    /// This can be overridden per run with [RunScriptArgs::max_string_bytes](crate::RunScriptArgs::max_string_bytes).
+    pub fn max_string_bytes(mut self, limit: usize) -> Self {
+        self.max_string_bytes = Some(limit);
+        self
+    }
+
+    /// Limit the number of console messages that each run can send, so that a script that logs
+    /// in a loop can't flood the connection. Messages over the limit are dropped, and replaced by
+    /// a warning that counts them.
+    ///
+    /// This can be overridden per run with [RunScriptArgs::max_log_messages](crate::RunScriptArgs::max_log_messages).
+    pub fn max_log_messages(mut self, limit: u32) -> Self {
+        self.max_log_messages = Some(limit);
+        self
+    }
+
+    /// Limit the total size of the console messages that each run can send, in bytes.
+    ///
+    /// This can be overridden per run with [RunScriptArgs::max_log_bytes](crate::RunScriptArgs::max_log_bytes).
+    pub fn max_log_bytes(mut self, limit: usize) -> Self {
+        self.max_log_bytes = Some(limit);
+        self
+    }
+
+    /// Only send console messages at `level` or above from the workers, so that chatty scripts
+    /// don't fill the connection with messages that would be ignored anyway. For example,
+    /// [LogLevel::Warn] only sends the messages that Node.js would write to stderr.
+    ///
+    /// This can be overridden per run with [RunScriptArgs::min_log_level](crate::RunScriptArgs::min_log_level).
+    pub fn min_log_level(mut self, level: LogLevel) -> Self {
+        self.min_log_level = Some(level);
+        self
+    }
+
+    /// JavaScript strings can contain unpaired UTF-16 surrogates, which can't be represented in a
+    /// Rust string. By default, a result containing one fails with an error naming the path of the
+    /// invalid string. Set this to replace the unpaired surrogates with U+FFFD instead.
+    pub fn replace_invalid_unicode(mut self, replace: bool) -> Self {
+        self.replace_invalid_unicode = replace;
+        self
+    }
+
+    /// How many messages from the worker to buffer for each connection before applying the
+    /// [channel_overflow](Self::channel_overflow) policy. Defaults to 16.
+    pub fn message_channel_size(mut self, size: usize) -> Self {
+        self.channel_size = Some(size.max(1));
+        self
+    }
+
+    /// What to do when a connection receives console messages faster than they are consumed.
+    /// Defaults to [ChannelOverflow::Block].
+    pub fn channel_overflow(mut self, overflow: ChannelOverflow) -> Self {
+        self.channel_overflow = overflow;
+        self
+    }
+
+    /// The maximum number of connections that the pool will open. Defaults to 1024.
+    pub fn pool_max_size(mut self, size: usize) -> Self {
+        self.pool_max_size = Some(size);
+        self
+    }
+
+    /// How long to wait for a connection when the pool is at its
+    /// [maximum size](Self::pool_max_size) and every connection is in use. By default, this waits
+    /// indefinitely.
+    ///
+    /// Set this to zero to fail immediately instead of waiting. The error from a timeout can be
+    /// recognized with [Error::is_pool_timeout].
+    pub fn pool_wait_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_wait_timeout = Some(timeout);
+        self
+    }
+
+    /// The most requests that can wait for a connection when the pool is at its
+    /// [maximum size](Self::pool_max_size) and every connection is in use. Past this,
+    /// [JsSidecar::run](crate::JsSidecar::run) and [JsSidecar::connect](crate::JsSidecar::connect)
+    /// fail immediately with [Error::Overloaded](crate::Error::Overloaded), so that callers can
+    /// push back on their own clients instead of queueing without bound. By default, any number
+    /// of requests can wait.
+    pub fn max_queued_requests(mut self, max: usize) -> Self {
+        self.max_queued_requests = Some(max);
+        self
+    }
+
+    /// Send a [SidecarEvent::SlowCheckout](crate::SidecarEvent::SlowCheckout) event, and log a
+    /// warning, when getting a connection from the pool takes longer than this, whether it waited
+    /// for a connection to be returned or for a new one to open. Frequent slow checkouts mean
+    /// that the pool, or the number of workers, is too small for the load. By default, slow
+    /// checkouts aren't reported.
+    pub fn slow_checkout_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_checkout_threshold = Some(threshold);
+        self
+    }
+
+    /// Limit the runs of one [tenant](crate::RunScriptArgs::tenant). This replaces the
+    /// [default quota](Self::default_tenant_quota) for that tenant.
+    pub fn tenant_quota(mut self, tenant: impl Into<String>, quota: TenantQuota) -> Self {
+        self.tenant_quotas.insert(tenant.into(), quota);
+        self
+    }
+
+    /// Limit the runs of each tenant that doesn't have its own
+    /// [quota](Self::tenant_quota). By default, tenants aren't limited, and are only counted in
+    /// [JsSidecar::tenant_stats](crate::JsSidecar::tenant_stats).
+    pub fn default_tenant_quota(mut self, quota: TenantQuota) -> Self {
+        self.default_tenant_quota = Some(quota);
+        self
+    }
+
+    /// How long to wait for a new connection to the workers' socket to open. By default, this
+    /// waits indefinitely.
+    pub fn pool_create_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_create_timeout = Some(timeout);
+        self
+    }
+
+    /// How long to wait for a worker to answer the ping that checks a connection before reusing
+    /// it, as described in [pool_verify_interval](Self::pool_verify_interval). A connection that
+    /// doesn't answer in time is closed and replaced. Defaults to 1 second.
+    ///
+    /// This also limits how long a new connection to a [worker_url](Self::worker_url) or through a
+    /// [custom transport](Self::transport) can take to open and complete its handshake.
+    pub fn pool_recycle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_recycle_timeout = Some(timeout);
+        self
+    }
+
+    /// How long a pooled connection can go unused before it is checked with a ping when it is
+    /// next taken from the pool. Connections that saw an error, or that were returned with a run
+    /// still in progress, are always checked, and connections whose socket has closed are
+    /// replaced without one. Set this to zero to check every connection on every checkout.
+    /// Defaults to 30 seconds.
+    pub fn pool_verify_interval(mut self, interval: Duration) -> Self {
+        self.pool_verify_interval = Some(interval);
+        self
+    }
+
+    /// Ping the workers of the connections waiting in the pool this often, and close the
+    /// connections whose worker has died or doesn't respond within the
+    /// [recycle timeout](Self::pool_recycle_timeout). This finds dead workers before a caller
+    /// checks out one of their connections, instead of failing that caller's request or delaying
+    /// it while the connection is verified. Heartbeats don't count as activity for the
+    /// [idle timeout](Self::idle_timeout). By default, idle connections are only checked when they
+    /// are taken from the pool.
+    pub fn pool_heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.pool_heartbeat_interval = Some(interval);
+        self
+    }
+
+    /// The order in which idle connections are reused. The default, [QueueMode::Lifo], reuses
+    /// the most recently returned connection, which keeps a small set of connections busy and
+    /// lets the rest go idle. [QueueMode::Fifo] cycles through all the idle connections.
+    pub fn pool_queue_mode(mut self, mode: QueueMode) -> Self {
+        self.pool_queue_mode = Some(mode);
+        self
+    }
+
+    /// Close pooled connections, and their worker contexts, once they have gone this long without
+    /// sending or receiving a message. By default, idle connections are kept indefinitely.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Open this many connections while starting the sidecar, and keep at least this many waiting
+    /// in the pool afterwards, replacing the ones that are checked out or closed, so that a burst
+    /// of requests doesn't wait for new connections or contexts. Connections are opened one at a
+    /// time, and each creates its context before it joins the pool. The
+    /// [idle timeout](Self::idle_timeout) won't close connections below this number.
+    /// [build](Self::build) fails if the initial connections can't be opened. Defaults to 0.
+    pub fn min_idle(mut self, n: usize) -> Self {
+        self.min_idle = Some(n);
+        self
+    }
+
+    /// Close a connection, and its worker context, instead of reusing it once it has run this many
+    /// scripts. This bounds the memory that a leaky script can accumulate in a context that would
+    /// otherwise live as long as its connection. By default, connections are reused indefinitely.
+    pub fn max_requests_per_connection(mut self, max: u64) -> Self {
+        self.max_requests_per_connection = Some(max);
+        self
+    }
+
+    /// Close a connection, and its worker context, instead of reusing it once it is this old.
+    /// By default, connections are reused indefinitely.
+    pub fn max_connection_age(mut self, age: Duration) -> Self {
+        self.max_connection_age = Some(age);
+        self
+    }
+
+    /// Have each worker report its memory usage at this interval. The latest reports can be seen
+    /// with [JsSidecar::worker_memory](crate::JsSidecar::worker_memory). Reports are sent on
+    /// every open connection, so they only arrive while there are connections to a worker.
+    ///
+    /// Defaults to 5 seconds when [max_worker_heap](Self::max_worker_heap) is set, and off
+    /// otherwise.
+    pub fn memory_report_interval(mut self, interval: Duration) -> Self {
+        self.memory_report_interval = Some(interval);
+        self
+    }
+
+    /// Replace workers whose JavaScript heap grows past this many bytes. The worker stops
+    /// accepting connections, finishes the requests that it is running, and exits, and Node.js
+    /// starts a new worker in its place. This keeps scripts that leak memory into their contexts
+    /// from growing a worker without bound.
+    ///
+    /// Connections to the old worker fail once it exits, and are retried by
+    /// [JsSidecar::run](crate::JsSidecar::run) as with any broken connection.
+    pub fn max_worker_heap(mut self, bytes: u64) -> Self {
+        self.max_worker_heap = Some(bytes);
+        self
+    }
+
+    /// How many times [JsSidecar::run](crate::JsSidecar::run) retries a script on a new connection
+    /// when the connection breaks before the script is sent. Defaults to 0, so that runs are
+    /// only retried when asked for.
+    pub fn run_retries(mut self, retries: u32) -> Self {
+        self.run_retries = Some(retries);
+        self
+    }
+
+    /// How long past a run's [timeout](crate::RunScriptArgs::timeout_ms) to wait for the worker
+    /// to answer before killing it. The worker interrupts scripts that run past their timeout on
+    /// its own, so this only happens when something blocks the whole worker, such as an infinite
+    /// loop in a timer callback. Node.js then starts a new worker in its place, and the run fails
+    /// with [Error::Timeout].
+    ///
+    /// Other connections to the killed worker fail too, and are retried by
+    /// [JsSidecar::run](crate::JsSidecar::run) as with any broken connection. Workers reached
+    /// through [worker_url](Self::worker_url) are never killed. Defaults to 5 seconds.
+    pub fn kill_after_timeout(mut self, grace: Duration) -> Self {
+        self.kill_after_timeout = Some(grace);
+        self
+    }
+
+    /// How the workers isolate connections from each other. With [Isolation::Thread], a script
+    /// stuck in an infinite loop is stopped by terminating its connection's thread, without
+    /// restarting the worker process. Defaults to [Isolation::Process].
+    pub fn isolation(mut self, isolation: Isolation) -> Self {
+        self.isolation = isolation;
+        self
+    }
+
+    /// Only run Node.js and its workers on these CPUs, numbered from 0, so that they don't compete
+    /// with the host application's latency-critical threads on a shared machine. Linux only; on
+    /// other systems, starting the sidecar fails.
+    ///
+    /// This and the other process settings apply to the Node.js process that the sidecar starts,
+    /// and are inherited by its workers. They have no effect on workers reached through
+    /// [worker_url](Self::worker_url).
+    pub fn cpu_affinity(mut self, cpus: impl IntoIterator<Item = usize>) -> Self {
+        self.process.cpu_affinity = Some(cpus.into_iter().collect());
+        self
+    }
+
+    /// The niceness of Node.js and its workers, from -20 (favored the most) to 19 (favored the
+    /// least), so that the scheduler favors the host application when the CPUs are busy. Lowering
+    /// it below the host application's niceness needs privileges.
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.process.nice = Some(nice);
+        self
+    }
+
+    /// Move Node.js and its workers into an existing cgroup, given by its directory such as
+    /// `/sys/fs/cgroup/js_sidecar`, so that the limits set on the cgroup apply to them. The
+    /// process needs permission to write to the cgroup's `cgroup.procs` file.
+    pub fn cgroup(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.process.cgroup = Some(dir.into());
+        self
+    }
+
+    /// Create a cgroup for this sidecar under `parent`, such as a cgroup v2 directory delegated to
+    /// the application, and move Node.js and its workers into it instead of an existing
+    /// [cgroup](Self::cgroup). The cgroup is removed when the sidecar is closed.
+    pub fn managed_cgroup(mut self, parent: impl Into<PathBuf>) -> Self {
+        self.managed_cgroup = Some(parent.into());
+        self
+    }
+
+    /// Set memory and CPU limits on the sidecar's [cgroup](Self::cgroup) or
+    /// [managed_cgroup](Self::managed_cgroup), which needs the `memory` and `cpu` controllers of
+    /// cgroup v2. Starting the sidecar fails if neither is set.
+    pub fn cgroup_limits(mut self, limits: CgroupLimits) -> Self {
+        self.cgroup_limits = Some(limits);
+        self
+    }
+
+    /// Start Node.js in a sandbox that restricts the files it can reach and blocks network access,
+    /// for deployments that run untrusted code. What Node.js needs is worked out from the rest of
+    /// the configuration; see [Hardening] for the details. Linux only; on other systems, or on
+    /// kernels without Landlock, starting the sidecar fails.
+    pub fn hardened(mut self, hardening: Hardening) -> Self {
+        self.hardening = Some(hardening);
+        self
+    }
+
+    /// When checking out a connection, skip connections to workers whose recent latency is well
+    /// above that of the other workers, if other connections are available. This helps to route
+    /// traffic away from workers that are bogged down by heavy contexts.
+    ///
+    /// Latency is always tracked, and can be seen with [JsSidecar::worker_latencies].
+    pub fn prefer_fast_workers(mut self, prefer: bool) -> Self {
+        self.prefer_fast_workers = prefer;
+        self
+    }
+
+    /// When checking out a connection, skip connections to workers that are running more
+    /// scripts than the least busy worker, if other connections are available. Without this,
+    /// new connections are spread across the workers in whatever order Node.js hands them out,
+    /// regardless of how busy each worker is.
+    ///
+    /// This uses the load that workers report every
+    /// [load_report_interval](Self::load_report_interval), which defaults to 250 milliseconds
+    /// when this is enabled.
+    pub fn prefer_idle_workers(mut self, prefer: bool) -> Self {
+        self.prefer_idle_workers = prefer;
+        self
+    }
+
+    /// Have each worker report how many scripts it is running at this interval. The latest
+    /// reports can be seen with [JsSidecar::worker_loads](crate::JsSidecar::worker_loads).
+    /// Reports are sent on every open connection, so they only arrive while there are
+    /// connections to a worker.
+    ///
+    /// Defaults to 250 milliseconds when [prefer_idle_workers](Self::prefer_idle_workers) is
+    /// set, and off otherwise.
+    pub fn load_report_interval(mut self, interval: Duration) -> Self {
+        self.load_report_interval = Some(interval);
+        self
+    }
+
+    /// Have each worker keep this many fresh contexts built ahead of time, so that a run that
+    /// creates a context, such as the first run on each connection taken from the pool or one
+    /// with [recreate_context](crate::RunScriptArgs::recreate_context) set, takes a ready one
+    /// instead of building it while the request waits. Workers build replacements between
+    /// requests. Only contexts with real timers and without deterministic mode come from the
+    /// pool, and in [Isolation::Thread] each connection's thread keeps its own contexts.
+    ///
+    /// This has no effect on remote workers. Defaults to 0, which builds every context on demand.
+    pub fn context_pool_size(mut self, size: usize) -> Self {
+        self.context_pool_size = Some(size);
+        self
+    }
+
+    /// How many session keys can hold on to a connection for
+    /// [JsSidecar::connect_for_key](crate::JsSidecar::connect_for_key) at once. When a new key
+    /// would go over the limit, the least recently used key's connection returns to the pool.
+    /// Defaults to 64.
+    pub fn max_session_keys(mut self, max: usize) -> Self {
+        self.max_session_keys = Some(max);
+        self
+    }
+
+    /// Give scripts a `kv` global that stores values in `backend`, so that they can keep state
+    /// between runs without any other access to the host. See [KvBackend].
+    pub fn kv_backend(mut self, backend: impl KvBackend) -> Self {
+        self.kv = Some(Arc::new(backend));
+        self
+    }
+
+    /// Ask `resolver` for the code of any imported module that isn't in the run's
+    /// [modules](crate::RunScriptArgs::modules), so that modules can be loaded on demand instead
+    /// of being sent with every run. See [ModuleResolver].
+    pub fn module_resolver(mut self, resolver: impl ModuleResolver) -> Self {
+        self.module_resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Let scripts import modules from URLs that start with one of `prefixes`, such as
+    /// `https://esm.sh/`. The workers fetch the modules themselves and cache them for the
+    /// [url_import_ttl](Self::url_import_ttl). Imports inside a fetched module that use a path
+    /// are resolved against the module's URL, and have to match a prefix too.
+    ///
+    /// Calling this again adds to the prefixes.
+    pub fn allow_url_imports(
+        mut self,
+        prefixes: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.url_imports
+            .extend(prefixes.into_iter().map(|prefix| prefix.into()));
+        self
+    }
+
+    /// How long a worker uses a module fetched for a URL import before fetching it again.
+    /// Defaults to 5 minutes.
+    pub fn url_import_ttl(mut self, ttl: Duration) -> Self {
+        self.url_import_ttl = Some(ttl);
+        self
+    }
+
+    /// Keep the scripts added with [JsSidecar::schedule] in `store`, and start the ones that it
+    /// already has when the sidecar starts. See [ScheduleStore].
+    pub fn schedule_store(mut self, store: impl ScheduleStore) -> Self {
+        self.schedule_store = Some(Arc::new(store));
+        self
+    }
+
+    /// Record each unique piece of code executed by the sidecar, for offline analysis.
+    pub fn collect_corpus(mut self, collector: CorpusCollector) -> Self {
+        self.corpus = Some(Arc::new(collector));
+        self
+    }
+
+    /// Keep a tamper-evident record of every script run on the sidecar's connections.
+    pub fn audit_log(mut self, log: AuditLog) -> Self {
+        self.audit = Some(Arc::new(log));
+        self
+    }
+
+    /// Only run code whose hex-encoded SHA-256 hash is in `hashes`, and fail other runs with
+    /// [Error::ScriptNotApproved](crate::Error::ScriptNotApproved) before they reach the worker.
+    /// The script's code, and each of its modules, functions, and WebAssembly modules, are
+    /// checked separately. These are the same hashes that a [CorpusCollector] records, so a
+    /// corpus gathered in a trusted environment can seed the allowlist. Calling this again adds
+    /// to the allowlist.
+    ///
+    /// Code that the worker fetches itself, through [URL imports](Self::allow_url_imports) or a
+    /// [module_resolver](Self::module_resolver), isn't checked.
+    pub fn allow_script_hashes(
+        mut self,
+        hashes: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        self.allowed_script_hashes
+            .get_or_insert_with(HashSet::new)
+            .extend(hashes.into_iter().map(|h| h.as_ref().to_ascii_lowercase()));
+        self
+    }
+
+    /// Check each script with `verifier` before it is sent to the worker, and fail the ones that
+    /// it rejects with [Error::ScriptNotApproved](crate::Error::ScriptNotApproved). When
+    /// [allow_script_hashes](Self::allow_script_hashes) is also set, scripts have to pass both.
+    pub fn script_verifier(mut self, verifier: impl ScriptVerifier) -> Self {
+        self.script_verifier = Some(Arc::new(verifier));
+        self
+    }
+
+    /// Start the sidecar.
+    pub async fn build(self) -> Result<JsSidecar, Error> {
+        JsSidecar::start(self).await
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Limits written to the cgroup of the Node.js process, with
+/// [JsSidecarBuilder::cgroup_limits](crate::JsSidecarBuilder::cgroup_limits). The kernel enforces
+/// them on the workers no matter what the scripts do, which makes them the last line of defense
+/// against hostile scripts.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CgroupLimits {
+    /// The most memory that Node.js and all of its workers can use together, in bytes, written to
+    /// `memory.max`. When they go over it, the kernel kills one of them, and the runs on it fail
+    /// with [Error::CgroupOutOfMemory](crate::Error::CgroupOutOfMemory).
+    pub memory_max: Option<u64>,
+    /// How many CPUs' worth of time Node.js and its workers can use together, such as `1.5`,
+    /// written to `cpu.max`. The workers are slowed down, rather than killed, when they use more.
+    pub cpus: Option<f64>,
+}
+
+/// The period for `cpu.max`, in microseconds.
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// The controllers that [CgroupLimits] need, which the parent has to enable for its children.
+const CONTROLLERS: [&str; 2] = ["+memory", "+cpu"];
+
+/// How long to wait for the processes left in a cgroup to exit before removing it.
+const EMPTY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A cgroup v2 directory that the Node.js process is placed in.
+#[derive(Debug)]
+pub(crate) struct Cgroup {
+    dir: PathBuf,
+    /// Set when the sidecar created the cgroup, so it removes the cgroup when it closes.
+    managed: bool,
+}
+
+impl Cgroup {
+    /// Use an existing cgroup.
+    pub fn existing(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            managed: false,
+        }
+    }
+
+    /// Create a cgroup named `name` under `parent`, enabling the memory and CPU controllers in
+    /// the parent so that the new cgroup has the files for [CgroupLimits]. A controller that the
+    /// parent can't enable is logged and skipped, and setting a limit that needs it fails later.
+    pub fn create(parent: &Path, name: &str) -> io::Result<Self> {
+        let subtree_control = parent.join("cgroup.subtree_control");
+        for controller in CONTROLLERS {
+            if let Err(e) = std::fs::write(&subtree_control, controller) {
+                tracing::warn!(error = ?e, parent = %parent.display(), controller, "Failed to enable cgroup controller");
+            }
+        }
+
+        let dir = parent.join(name);
+        std::fs::create_dir(&dir)?;
+        Ok(Self { dir, managed: true })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn set_limits(&self, limits: &CgroupLimits) -> io::Result<()> {
+        if let Some(bytes) = limits.memory_max {
+            std::fs::write(self.dir.join("memory.max"), bytes.to_string())?;
+        }
+        if let Some(cpus) = limits.cpus {
+            let quota = ((cpus * CPU_PERIOD_US as f64) as u64).max(1000);
+            std::fs::write(self.dir.join("cpu.max"), format!("{quota} {CPU_PERIOD_US}"))?;
+        }
+        Ok(())
+    }
+
+    /// How many processes in the cgroup the kernel has killed for going over `memory.max`, or 0
+    /// if that can't be read.
+    pub fn oom_kills(&self) -> u64 {
+        std::fs::read_to_string(self.dir.join("memory.events"))
+            .ok()
+            .and_then(|events| {
+                events.lines().find_map(|line| {
+                    line.strip_prefix("oom_kill ")
+                        .and_then(|count| count.trim().parse().ok())
+                })
+            })
+            .unwrap_or(0)
+    }
+
+    /// Kill the processes left in the cgroup, such as workers that outlived the Node.js primary,
+    /// wait for them to exit, and then remove the cgroup if the sidecar created it.
+    pub async fn close(&self) {
+        if !self.managed {
+            return;
+        }
+
+        let deadline = Instant::now() + EMPTY_TIMEOUT;
+        loop {
+            let pids = self.pids();
+            if pids.is_empty() {
+                break;
+            }
+            if Instant::now() >= deadline {
+                tracing::warn!(dir = %self.dir.display(), "Processes are still in the cgroup");
+                break;
+            }
+            for pid in pids {
+                nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), nix::sys::signal::SIGKILL)
+                    .ok();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        self.remove();
+    }
+
+    /// The processes in the cgroup, or none if that can't be read.
+    fn pids(&self) -> Vec<i32> {
+        std::fs::read_to_string(self.dir.join("cgroup.procs"))
+            .map(|procs| {
+                procs
+                    .lines()
+                    .filter_map(|pid| pid.trim().parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Remove the cgroup if the sidecar created it. This fails while processes are still in it,
+    /// so it is only for cgroups that no process was started in; see [close](Self::close).
+    pub fn remove(&self) {
+        if self.managed {
+            if let Err(e) = std::fs::remove_dir(&self.dir) {
+                tracing::warn!(error = ?e, dir = %self.dir.display(), "Failed to remove cgroup");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_and_events() {
+        let parent = tempfile::tempdir().unwrap();
+        let cgroup = Cgroup::create(parent.path(), "sidecar").unwrap();
+        // Each controller is enabled with its own write, so the file keeps the last one here.
+        let subtree_control = parent.path().join("cgroup.subtree_control");
+        assert_eq!(std::fs::read_to_string(subtree_control).unwrap(), "+cpu");
+        cgroup
+            .set_limits(&CgroupLimits {
+                memory_max: Some(256 << 20),
+                cpus: Some(1.5),
+            })
+            .unwrap();
+        let read = |name| std::fs::read_to_string(cgroup.dir().join(name)).unwrap();
+        assert_eq!(read("memory.max"), "268435456");
+        assert_eq!(read("cpu.max"), "150000 100000");
+
+        assert_eq!(cgroup.oom_kills(), 0);
+        std::fs::write(
+            cgroup.dir().join("memory.events"),
+            "low 0\nhigh 0\nmax 3\noom 2\noom_kill 2\n",
+        )
+        .unwrap();
+        assert_eq!(cgroup.oom_kills(), 2);
+
+        std::fs::remove_file(cgroup.dir().join("memory.events")).unwrap();
+        std::fs::remove_file(cgroup.dir().join("memory.max")).unwrap();
+        std::fs::remove_file(cgroup.dir().join("cpu.max")).unwrap();
+        cgroup.remove();
+        assert!(!cgroup.dir().exists());
+    }
+}
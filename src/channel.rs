@@ -0,0 +1,282 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::wire::{WorkerToHostMessage, WorkerToHostMessageData};
+
+/// The default number of messages buffered for each connection.
+pub const DEFAULT_CHANNEL_SIZE: usize = 16;
+
+/// What to do when a script sends console messages faster than the connection's receiver
+/// consumes them.
+///
+/// Only console messages are ever dropped or coalesced. Other messages, such as the script
+/// response, always wait for space in the channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChannelOverflow {
+    /// Wait for the receiver to make space. This stops reading from the worker until the
+    /// receiver catches up.
+    #[default]
+    Block,
+    /// Drop console messages that don't fit in the channel, and count them in
+    /// [Connection::dropped_messages](crate::Connection::dropped_messages).
+    DropLogs,
+    /// Hold console messages that don't fit in the channel and deliver them once there is space,
+    /// even if the worker sends nothing else, merging consecutive identical messages into one
+    /// with a higher [count](crate::LogResponseData::count). Once the channel size worth of
+    /// messages is held, further messages are dropped as with [ChannelOverflow::DropLogs].
+    CoalesceLogs,
+}
+
+/// Sends messages from the read loop into the connection's channel, applying the overflow policy.
+pub(crate) struct MessageForwarder {
+    sender: mpsc::Sender<WorkerToHostMessage>,
+    overflow: ChannelOverflow,
+    pending: VecDeque<WorkerToHostMessage>,
+    max_pending: usize,
+    dropped: Arc<AtomicU64>,
+}
+
+impl MessageForwarder {
+    pub fn new(
+        sender: mpsc::Sender<WorkerToHostMessage>,
+        overflow: ChannelOverflow,
+        dropped: Arc<AtomicU64>,
+    ) -> Self {
+        let max_pending = sender.max_capacity();
+        Self {
+            sender,
+            overflow,
+            pending: VecDeque::new(),
+            max_pending,
+            dropped,
+        }
+    }
+
+    /// Forward a message to the channel. Returns false if the receiver has closed.
+    pub async fn forward(&mut self, message: WorkerToHostMessage) -> bool {
+        if self.overflow == ChannelOverflow::Block {
+            return self.sender.send(message).await.is_ok();
+        }
+
+        if !self.flush_pending() {
+            return false;
+        }
+
+        if !matches!(message.data, WorkerToHostMessageData::Log(_)) {
+            // Only logs can be dropped, so wait for everything held to be delivered, and then this message.
+            while let Some(held) = self.pending.pop_front() {
+                if self.sender.send(held).await.is_err() {
+                    return false;
+                }
+            }
+
+            return self.sender.send(message).await.is_ok();
+        }
+
+        if !self.pending.is_empty() {
+            // Keep the messages in order
+            self.overflowed(message);
+            return true;
+        }
+
+        match self.sender.try_send(message) {
+            Ok(()) => true,
+            Err(TrySendError::Full(message)) => {
+                self.overflowed(message);
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Returns true if there are held messages waiting for space in the channel.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Wait for space in the channel and deliver the oldest held message, so that held messages
+    /// don't wait for the worker to send another one. This is cancel safe. Returns false if the
+    /// receiver has closed.
+    pub async fn deliver_pending(&mut self) -> bool {
+        let Ok(permit) = self.sender.reserve().await else {
+            return false;
+        };
+        if let Some(held) = self.pending.pop_front() {
+            permit.send(held);
+        }
+        true
+    }
+
+    fn overflowed(&mut self, message: WorkerToHostMessage) {
+        if self.overflow == ChannelOverflow::CoalesceLogs {
+            if let Some(last) = self.pending.back_mut() {
+                if let (WorkerToHostMessageData::Log(last), WorkerToHostMessageData::Log(new)) =
+                    (&mut last.data, &message.data)
+                {
+                    if last.level == new.level
+                        && last.namespace == new.namespace
+                        && last.message == new.message
+                    {
+                        last.count += new.count;
+                        return;
+                    }
+                }
+            }
+
+            if self.pending.len() < self.max_pending {
+                self.pending.push_back(message);
+                return;
+            }
+        }
+
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Move held messages into the channel as space allows. Returns false if the receiver has closed.
+    fn flush_pending(&mut self) -> bool {
+        while let Some(held) = self.pending.pop_front() {
+            match self.sender.try_send(held) {
+                Ok(()) => {}
+                Err(TrySendError::Full(held)) => {
+                    self.pending.push_front(held);
+                    break;
+                }
+                Err(TrySendError::Closed(_)) => return false,
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{LogLevel, LogResponseData, RunResponseData};
+
+    fn log(message: &str) -> WorkerToHostMessage {
+        WorkerToHostMessage {
+            request_id: 0,
+            message_id: 0,
+            wire_bytes: 0,
+            data: WorkerToHostMessageData::Log(LogResponseData {
+                level: LogLevel::Info,
+                message: json!([message]),
+                timestamp: 0,
+                request_id: 0,
+                namespace: None,
+                count: 1,
+            }),
+        }
+    }
+
+    fn response() -> WorkerToHostMessage {
+        WorkerToHostMessage {
+            request_id: 0,
+            message_id: 0,
+            wire_bytes: 0,
+            data: WorkerToHostMessageData::RunResponse(RunResponseData {
+                globals: Default::default(),
+                return_value: None,
+                request_id: 0,
+                stats: None,
+                skipped_globals: Vec::new(),
+                effects: Vec::new(),
+            }),
+        }
+    }
+
+    fn log_message(message: &WorkerToHostMessage) -> (serde_json::Value, u32) {
+        let WorkerToHostMessageData::Log(log) = &message.data else {
+            panic!("Expected log message, saw {message:?}");
+        };
+        (log.message.clone(), log.count)
+    }
+
+    #[tokio::test]
+    async fn drop_logs() {
+        let (sender, mut receiver) = mpsc::channel(2);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let mut forwarder =
+            MessageForwarder::new(sender, ChannelOverflow::DropLogs, dropped.clone());
+
+        for i in 0..4 {
+            assert!(forwarder.forward(log(&i.to_string())).await);
+        }
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+
+        assert_eq!(log_message(&receiver.recv().await.unwrap()).0, json!(["0"]));
+        assert_eq!(log_message(&receiver.recv().await.unwrap()).0, json!(["1"]));
+
+        // The response is never dropped
+        assert!(forwarder.forward(response()).await);
+        let msg = receiver.recv().await.unwrap();
+        assert!(matches!(msg.data, WorkerToHostMessageData::RunResponse(_)));
+    }
+
+    #[tokio::test]
+    async fn coalesce_logs() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let mut forwarder =
+            MessageForwarder::new(sender, ChannelOverflow::CoalesceLogs, dropped.clone());
+
+        assert!(forwarder.forward(log("a")).await);
+        // These don't fit in the channel
+        assert!(forwarder.forward(log("b")).await);
+        assert!(forwarder.forward(log("b")).await);
+        assert!(forwarder.forward(log("b")).await);
+        // The channel size is 1 so this one is dropped.
+        assert!(forwarder.forward(log("c")).await);
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+
+        let receive = async {
+            let mut messages = Vec::new();
+            while let Some(msg) = receiver.recv().await {
+                let done = matches!(msg.data, WorkerToHostMessageData::RunResponse(_));
+                messages.push(msg);
+                if done {
+                    break;
+                }
+            }
+            messages
+        };
+
+        let (sent, messages) = tokio::join!(forwarder.forward(response()), receive);
+        assert!(sent);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(log_message(&messages[0]), (json!(["a"]), 1));
+        assert_eq!(log_message(&messages[1]), (json!(["b"]), 3));
+    }
+
+    #[tokio::test]
+    async fn deliver_pending_without_more_messages() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let mut forwarder =
+            MessageForwarder::new(sender, ChannelOverflow::CoalesceLogs, dropped.clone());
+
+        assert!(forwarder.forward(log("a")).await);
+        assert!(forwarder.forward(log("b")).await);
+        assert!(forwarder.forward(log("b")).await);
+        assert!(forwarder.has_pending());
+
+        let (delivered, first) = tokio::join!(forwarder.deliver_pending(), receiver.recv());
+        assert!(delivered);
+        assert_eq!(log_message(&first.unwrap()), (json!(["a"]), 1));
+        assert!(!forwarder.has_pending());
+        assert_eq!(
+            log_message(&receiver.recv().await.unwrap()),
+            (json!(["b"]), 2)
+        );
+    }
+}
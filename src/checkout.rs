@@ -0,0 +1,337 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use deadpool::managed::{Manager, Object, Pool, PoolError, Timeouts};
+use tokio::sync::Notify;
+
+use crate::Priority;
+
+/// Counts of the connections in a [JsSidecar](crate::JsSidecar)'s pool, from
+/// [JsSidecar::pool_status](crate::JsSidecar::pool_status).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStatus {
+    /// The most connections that the pool will open, from
+    /// [JsSidecarBuilder::pool_max_size](crate::JsSidecarBuilder::pool_max_size).
+    pub max_size: usize,
+    /// The number of open connections, both idle and in use.
+    pub size: usize,
+    /// The number of idle connections waiting in the pool.
+    pub available: usize,
+    /// The number of checkouts waiting for a connection.
+    pub waiting: usize,
+}
+
+impl PoolStatus {
+    /// The number of connections that are checked out, including the ones held for
+    /// [session keys](crate::JsSidecar::connect_for_key).
+    pub fn in_use(&self) -> usize {
+        self.size.saturating_sub(self.available)
+    }
+}
+
+/// Orders checkouts from the pool by priority, and then by arrival. Only the first waiter in the
+/// queue waits on the pool, and the others wait to be woken when they move to the front. A waiter
+/// with a higher priority goes ahead of the ones already waiting, and the first waiter gives back
+/// any object it gets while one has arrived, so that the pool hands it to the new first waiter.
+#[derive(Debug, Default)]
+pub(crate) struct CheckoutQueue {
+    waiting: Mutex<Waiting>,
+    /// The most checkouts that can be waiting while the pool is exhausted.
+    max_waiting: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct Waiting {
+    next_id: u64,
+    /// Wakes each waiter, keyed by its rank and then its arrival, so that the first entry is the
+    /// waiter whose turn it is.
+    waiters: BTreeMap<(usize, u64), Arc<Notify>>,
+}
+
+#[derive(Debug)]
+pub(crate) enum CheckoutError<E> {
+    Pool(PoolError<E>),
+    /// The pool is exhausted and too many checkouts are already waiting.
+    Overloaded {
+        queued: usize,
+        limit: usize,
+    },
+}
+
+impl CheckoutQueue {
+    pub fn new(max_waiting: Option<usize>) -> Self {
+        Self {
+            waiting: Mutex::default(),
+            max_waiting,
+        }
+    }
+
+    /// Get an object from the pool, ahead of any waiters with a lower priority. This keeps to the
+    /// pool's wait timeout.
+    pub async fn get<M: Manager>(
+        &self,
+        pool: &Pool<M>,
+        priority: Priority,
+    ) -> Result<Object<M>, CheckoutError<M::Error>> {
+        let waiter = Waiter::new(self, rank(priority), pool)?;
+
+        let timeouts = pool.timeouts();
+        let deadline = timeouts.wait.map(|wait| Instant::now() + wait);
+        loop {
+            let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            let out_of_time = remaining == Some(Duration::ZERO);
+            if !waiter.is_first() && !out_of_time {
+                let notified = waiter.notify.notified();
+                match remaining {
+                    Some(remaining) => {
+                        let _ = tokio::time::timeout(remaining, notified).await;
+                    }
+                    None => notified.await,
+                }
+                continue;
+            }
+
+            let wait = Timeouts {
+                wait: remaining,
+                ..timeouts
+            };
+            match pool.timeout_get(&wait).await {
+                // A waiter that is out of time still takes an object that is idle right now. The
+                // pool hands returned objects straight to the waiters blocked on it, so this can't
+                // take one that the first waiter is waiting for.
+                Ok(obj) if out_of_time || waiter.is_first() => return Ok(obj),
+                // A more urgent waiter arrived while this one was waiting, so let it have the
+                // object instead.
+                Ok(obj) => drop(obj),
+                Err(e) => return Err(CheckoutError::Pool(e)),
+            }
+        }
+    }
+
+    /// The status of `pool`, counting the checkouts waiting in this queue.
+    pub fn status<M: Manager>(&self, pool: &Pool<M>) -> PoolStatus {
+        let status = pool.status();
+        PoolStatus {
+            max_size: status.max_size,
+            size: status.size,
+            available: status.available,
+            waiting: self.waiting.lock().unwrap().waiters.len(),
+        }
+    }
+}
+
+fn rank(priority: Priority) -> usize {
+    match priority {
+        Priority::High => 0,
+        Priority::Normal => 1,
+        Priority::Low => 2,
+    }
+}
+
+/// A waiter's place in the queue, which it keeps until it is dropped.
+struct Waiter<'a> {
+    queue: &'a CheckoutQueue,
+    key: (usize, u64),
+    notify: Arc<Notify>,
+}
+
+impl<'a> Waiter<'a> {
+    /// Join the queue, unless the pool has no objects to spare and the queue is already full.
+    /// Checkouts that the pool can serve right away don't count against the limit.
+    fn new<M: Manager, E>(
+        queue: &'a CheckoutQueue,
+        rank: usize,
+        pool: &Pool<M>,
+    ) -> Result<Self, CheckoutError<E>> {
+        let mut waiting = queue.waiting.lock().unwrap();
+        if let Some(limit) = queue.max_waiting {
+            let status = pool.status();
+            let queued = waiting.waiters.len();
+            let exhausted = status.available == 0 && status.size >= status.max_size;
+            if exhausted && queued >= limit {
+                return Err(CheckoutError::Overloaded { queued, limit });
+            }
+        }
+
+        let key = (rank, waiting.next_id);
+        waiting.next_id += 1;
+        let notify = Arc::new(Notify::new());
+        waiting.waiters.insert(key, notify.clone());
+        Ok(Self { queue, key, notify })
+    }
+
+    fn is_first(&self) -> bool {
+        let waiting = self.queue.waiting.lock().unwrap();
+        waiting.waiters.first_key_value().map(|(key, _)| *key) == Some(self.key)
+    }
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        let mut waiting = self.queue.waiting.lock().unwrap();
+        let was_first = waiting.waiters.first_key_value().map(|(key, _)| *key) == Some(self.key);
+        waiting.waiters.remove(&self.key);
+        if was_first {
+            if let Some((_, next)) = waiting.waiters.first_key_value() {
+                next.notify_one();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, sync::Arc};
+
+    use deadpool::managed::{Metrics, RecycleResult, TimeoutType};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Counter;
+
+    impl Manager for Counter {
+        type Type = ();
+        type Error = Infallible;
+
+        async fn create(&self) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        async fn recycle(&self, _: &mut (), _: &Metrics) -> RecycleResult<Infallible> {
+            Ok(())
+        }
+    }
+
+    fn pool(wait: Option<Duration>) -> Pool<Counter> {
+        Pool::builder(Counter)
+            .max_size(1)
+            .wait_timeout(wait)
+            .runtime(deadpool::Runtime::Tokio1)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn higher_priority_first() {
+        let pool = pool(None);
+        let queue = Arc::new(CheckoutQueue::default());
+        let held = queue.get(&pool, Priority::Normal).await.unwrap();
+
+        let (order_tx, mut order_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut tasks = Vec::new();
+        for priority in [Priority::Low, Priority::Normal, Priority::High] {
+            let pool = pool.clone();
+            let queue = queue.clone();
+            let order_tx = order_tx.clone();
+            tasks.push(tokio::spawn(async move {
+                let obj = queue.get(&pool, priority).await.unwrap();
+                order_tx.send(priority).unwrap();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                drop(obj);
+            }));
+            // Make sure the waiters arrive in order, with the least urgent first.
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        drop(held);
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let mut order = Vec::new();
+        while let Ok(priority) = order_rx.try_recv() {
+            order.push(priority);
+        }
+        assert_eq!(order, [Priority::High, Priority::Normal, Priority::Low]);
+        assert!(queue.waiting.lock().unwrap().waiters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn same_priority_in_order() {
+        let pool = pool(None);
+        let queue = Arc::new(CheckoutQueue::default());
+        let held = queue.get(&pool, Priority::Normal).await.unwrap();
+
+        let (order_tx, mut order_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut tasks = Vec::new();
+        for i in 0..5 {
+            let pool = pool.clone();
+            let queue = queue.clone();
+            let order_tx = order_tx.clone();
+            tasks.push(tokio::spawn(async move {
+                let obj = queue.get(&pool, Priority::Normal).await.unwrap();
+                order_tx.send(i).unwrap();
+                drop(obj);
+            }));
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(queue.status(&pool).waiting, 5);
+
+        drop(held);
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let mut order = Vec::new();
+        while let Ok(i) = order_rx.try_recv() {
+            order.push(i);
+        }
+        assert_eq!(order, [0, 1, 2, 3, 4]);
+        assert!(queue.waiting.lock().unwrap().waiters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn wait_timeout() {
+        let pool = pool(Some(Duration::from_millis(30)));
+        let queue = CheckoutQueue::default();
+        let _held = queue.get(&pool, Priority::Normal).await.unwrap();
+
+        let start = Instant::now();
+        let err = queue.get(&pool, Priority::High).await.unwrap_err();
+        assert!(matches!(
+            err,
+            CheckoutError::Pool(PoolError::Timeout(TimeoutType::Wait))
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        let pool = self::pool(Some(Duration::ZERO));
+        let _held = queue.get(&pool, Priority::Low).await.unwrap();
+        let err = queue.get(&pool, Priority::Low).await.unwrap_err();
+        assert!(matches!(
+            err,
+            CheckoutError::Pool(PoolError::Timeout(TimeoutType::Wait))
+        ));
+    }
+
+    #[tokio::test]
+    async fn overloaded() {
+        let pool = pool(None);
+        let queue = Arc::new(CheckoutQueue::new(Some(1)));
+        let held = queue.get(&pool, Priority::Normal).await.unwrap();
+
+        let waiter = tokio::spawn({
+            let pool = pool.clone();
+            let queue = queue.clone();
+            async move { queue.get(&pool, Priority::Normal).await.map(drop) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let err = queue.get(&pool, Priority::High).await.unwrap_err();
+        assert!(matches!(
+            err,
+            CheckoutError::Overloaded {
+                queued: 1,
+                limit: 1
+            }
+        ));
+
+        drop(held);
+        waiter.await.unwrap().unwrap();
+        // With an object to spare, checkouts don't count as queued.
+        let _held = queue.get(&pool, Priority::Normal).await.unwrap();
+    }
+}
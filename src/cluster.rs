@@ -0,0 +1,417 @@
+use std::{
+    borrow::Cow,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures::future::try_join_all;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    Error, JsSidecar, JsSidecarBuilder, KeyedConnection, RunScriptAndWaitResult, RunScriptArgs,
+};
+
+/// How many points each member gets on the hash ring by default.
+const DEFAULT_VIRTUAL_NODES: usize = 64;
+
+/// How long a member that failed is passed over by default.
+const DEFAULT_RETRY_FAILED_AFTER: Duration = Duration::from_secs(5);
+
+/// Several sidecars, each with its own Node.js process, that share the work by key. A single
+/// sidecar's process accepts every connection on one event loop before handing it to a worker,
+/// which limits how far it can scale, so a cluster spreads the load over several of them. The
+/// members can be local, or on other machines through [worker_url](JsSidecarBuilder::worker_url).
+///
+/// Keys are routed with consistent hashing, so each key goes to the same member every time, and
+/// adding or removing a member only moves the keys that belong to it. When a member's connections
+/// fail, its keys go to the next member on the ring until
+/// [retry_failed_after](SidecarClusterBuilder::retry_failed_after) has passed.
+///
+/// ```no_run
+/// # use js_sidecar::{JsSidecar, SidecarCluster};
+/// # async fn example() -> Result<(), js_sidecar::Error> {
+/// let cluster = SidecarCluster::builder()
+///     .member("local", JsSidecar::builder().num_workers(4))
+///     .member("remote", JsSidecar::builder().worker_url("ws://10.0.0.2:9000"))
+///     .build()
+///     .await?;
+/// let value: i32 = cluster.eval("user-1", "1 + 1").await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct SidecarCluster {
+    members: Vec<ClusterMember>,
+    ring: HashRing,
+    retry_failed_after: Duration,
+}
+
+struct ClusterMember {
+    name: String,
+    sidecar: JsSidecar,
+    /// When the member last failed, if it is still being passed over.
+    failed_at: Mutex<Option<Instant>>,
+}
+
+/// The state of a member of a [SidecarCluster], from [SidecarCluster::members].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterMemberStatus {
+    /// The name given to [SidecarClusterBuilder::member]
+    pub name: String,
+    /// False if the member failed recently, in which case its keys are routed to other members.
+    pub healthy: bool,
+}
+
+/// Configures and starts a [SidecarCluster].
+pub struct SidecarClusterBuilder {
+    members: Vec<(String, JsSidecarBuilder)>,
+    virtual_nodes: usize,
+    retry_failed_after: Duration,
+}
+
+impl SidecarClusterBuilder {
+    /// Add a member, started from `builder` when the cluster is built. The name places the member
+    /// on the hash ring, so a member should keep its name across restarts for keys to keep going
+    /// to it.
+    pub fn member(mut self, name: impl Into<String>, builder: JsSidecarBuilder) -> Self {
+        self.members.push((name.into(), builder));
+        self
+    }
+
+    /// How many points each member gets on the hash ring. More points spread the keys more
+    /// evenly. Defaults to 64.
+    pub fn virtual_nodes(mut self, virtual_nodes: usize) -> Self {
+        self.virtual_nodes = virtual_nodes.max(1);
+        self
+    }
+
+    /// How long a member whose connections failed is passed over before keys are routed to it
+    /// again. Defaults to 5 seconds.
+    pub fn retry_failed_after(mut self, duration: Duration) -> Self {
+        self.retry_failed_after = duration;
+        self
+    }
+
+    /// Start every member, and return an error if any of them fails to start.
+    pub async fn build(self) -> Result<SidecarCluster, Error> {
+        if self.members.is_empty() {
+            return Err(Error::InvalidValue(
+                "A cluster needs at least one member".to_string(),
+            ));
+        }
+
+        let (names, builders): (Vec<_>, Vec<_>) = self.members.into_iter().unzip();
+        let ring = HashRing::new(names.iter().map(String::as_str), self.virtual_nodes);
+        let sidecars = try_join_all(builders.into_iter().map(JsSidecarBuilder::build)).await?;
+        let members = names
+            .into_iter()
+            .zip(sidecars)
+            .map(|(name, sidecar)| ClusterMember {
+                name,
+                sidecar,
+                failed_at: Mutex::new(None),
+            })
+            .collect();
+
+        Ok(SidecarCluster {
+            members,
+            ring,
+            retry_failed_after: self.retry_failed_after,
+        })
+    }
+}
+
+impl SidecarCluster {
+    /// Create a [SidecarClusterBuilder].
+    pub fn builder() -> SidecarClusterBuilder {
+        SidecarClusterBuilder {
+            members: Vec::new(),
+            virtual_nodes: DEFAULT_VIRTUAL_NODES,
+            retry_failed_after: DEFAULT_RETRY_FAILED_AFTER,
+        }
+    }
+
+    /// The members of the cluster, in the order they were added.
+    pub fn members(&self) -> Vec<ClusterMemberStatus> {
+        self.members
+            .iter()
+            .map(|member| ClusterMemberStatus {
+                name: member.name.clone(),
+                healthy: self.is_healthy(member),
+            })
+            .collect()
+    }
+
+    /// The sidecar of the member with this name.
+    pub fn member(&self, name: &str) -> Option<&JsSidecar> {
+        self.members
+            .iter()
+            .find(|member| member.name == name)
+            .map(|member| &member.sidecar)
+    }
+
+    /// The name of the member that `key` is routed to right now.
+    pub fn member_for_key(&self, key: &str) -> &str {
+        &self.members[self.route(key)[0]].name
+    }
+
+    /// Run a script on the member for `key`. See [JsSidecar::run].
+    ///
+    /// If the member's connections fail before the script was sent, even after the retries set by
+    /// [run_retries](JsSidecarBuilder::run_retries), the script runs on the next member instead.
+    /// A connection that fails once the script was sent still marks the member as failed, but
+    /// the error is returned rather than running the script again, since it may have already
+    /// performed some of its side effects.
+    pub async fn run(
+        &self,
+        key: &str,
+        args: RunScriptArgs,
+    ) -> Result<RunScriptAndWaitResult, Error> {
+        let mut result = Err(Error::ScriptEndedEarly);
+        for index in self.route(key) {
+            let sent;
+            (result, sent) = self.members[index]
+                .sidecar
+                .run_tracking_sent(args.clone())
+                .await;
+            if !self.check_failure(index, &result) || sent {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Evaluate a JavaScript expression on the member for `key` and convert its value to `T`.
+    /// See [JsSidecar::eval].
+    pub async fn eval<T: DeserializeOwned>(
+        &self,
+        key: &str,
+        expr: impl Into<Cow<'static, str>>,
+    ) -> Result<T, Error> {
+        let result = self
+            .run(
+                key,
+                RunScriptArgs {
+                    code: expr.into(),
+                    expr: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        let value = result
+            .response
+            .return_value
+            .unwrap_or(serde_json::Value::Null);
+        serde_json::from_value(value).map_err(Error::ResultType)
+    }
+
+    /// Get a connection for a session key from the member for the key. See
+    /// [JsSidecar::connect_for_key]. If the member can't be reached, the connection comes from the
+    /// next member instead, with a fresh context.
+    pub async fn connect_for_key(&self, key: &str) -> Result<KeyedConnection, Error> {
+        let mut result = Err(Error::ScriptEndedEarly);
+        for index in self.route(key) {
+            result = self.members[index].sidecar.connect_for_key(key).await;
+            if !self.check_failure(index, &result) {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Close every member.
+    pub async fn close(&mut self) {
+        for member in &mut self.members {
+            member.sidecar.close().await;
+        }
+    }
+
+    /// The members to try for a key, in order: the healthy ones in the order that they follow the
+    /// key on the ring, and then the ones that failed recently, as a last resort.
+    fn route(&self, key: &str) -> Vec<usize> {
+        let (healthy, failed): (Vec<_>, Vec<_>) = self
+            .ring
+            .members_for(key)
+            .partition(|&index| self.is_healthy(&self.members[index]));
+        healthy.into_iter().chain(failed).collect()
+    }
+
+    fn is_healthy(&self, member: &ClusterMember) -> bool {
+        member
+            .failed_at
+            .lock()
+            .unwrap()
+            .is_none_or(|failed_at| failed_at.elapsed() >= self.retry_failed_after)
+    }
+
+    /// Mark the member as failed if the result is from a broken connection, returning true if it
+    /// was. A member that works again is marked healthy.
+    fn check_failure<T>(&self, index: usize, result: &Result<T, Error>) -> bool {
+        let member = &self.members[index];
+        let failed = result.as_ref().is_err_and(Error::is_connection_failure);
+        if failed {
+            tracing::warn!(member = %member.name, "Cluster member failed, routing its keys elsewhere");
+            *member.failed_at.lock().unwrap() = Some(Instant::now());
+        } else {
+            *member.failed_at.lock().unwrap() = None;
+        }
+        failed
+    }
+}
+
+impl std::fmt::Debug for SidecarCluster {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SidecarCluster")
+            .field("members", &self.members())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A consistent hash ring, with several points for each member.
+struct HashRing {
+    /// The points on the ring and the index of the member at each, sorted by point.
+    points: Vec<(u64, usize)>,
+    num_members: usize,
+}
+
+impl HashRing {
+    fn new<'a>(names: impl Iterator<Item = &'a str>, virtual_nodes: usize) -> Self {
+        let mut points = Vec::new();
+        let mut num_members = 0;
+        for (index, name) in names.enumerate() {
+            num_members += 1;
+            for node in 0..virtual_nodes {
+                points.push((hash(&format!("{name}#{node}")), index));
+            }
+        }
+        points.sort_unstable();
+        Self {
+            points,
+            num_members,
+        }
+    }
+
+    /// Every member, in the order that they first appear on the ring after the key's point.
+    fn members_for(&self, key: &str) -> impl Iterator<Item = usize> {
+        let start = self.points.partition_point(|(point, _)| *point < hash(key));
+        let mut seen = vec![false; self.num_members];
+        let mut members = Vec::with_capacity(self.num_members);
+        for (_, index) in self.points[start..].iter().chain(&self.points[..start]) {
+            if !seen[*index] {
+                seen[*index] = true;
+                members.push(*index);
+            }
+        }
+        members.into_iter()
+    }
+}
+
+/// A hash that stays the same across processes and versions, so that every host routes a key to
+/// the same member.
+fn hash(value: &str) -> u64 {
+    let digest = Sha256::digest(value.as_bytes());
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consistent_routing() {
+        let keys = (0..1000).map(|i| format!("key-{i}")).collect::<Vec<_>>();
+        let ring = HashRing::new(["a", "b", "c"].into_iter(), DEFAULT_VIRTUAL_NODES);
+        let first = keys
+            .iter()
+            .map(|key| ring.members_for(key).next().unwrap())
+            .collect::<Vec<_>>();
+        for member in 0..3 {
+            let count = first.iter().filter(|&&m| m == member).count();
+            assert!(
+                (200..=470).contains(&count),
+                "member {member} got {count} keys"
+            );
+        }
+
+        // Adding a member only moves keys to the new member.
+        let bigger = HashRing::new(["a", "b", "c", "d"].into_iter(), DEFAULT_VIRTUAL_NODES);
+        for (key, before) in keys.iter().zip(&first) {
+            let after = bigger.members_for(key).next().unwrap();
+            assert!(
+                after == *before || after == 3,
+                "{key} moved from {before} to {after}"
+            );
+        }
+
+        let order = ring.members_for("key-1").collect::<Vec<_>>();
+        assert_eq!(order.len(), 3);
+        assert_eq!(order[0], first[1]);
+    }
+
+    #[tokio::test]
+    async fn failover() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut server = JsSidecar::builder()
+            .num_workers(1)
+            .websocket_addr(([127, 0, 0, 1], port).into())
+            .build()
+            .await
+            .unwrap();
+
+        let mut cluster = SidecarCluster::builder()
+            .member("local", JsSidecar::builder().num_workers(1))
+            .member(
+                "remote",
+                JsSidecar::builder()
+                    .worker_url(format!("ws://127.0.0.1:{port}"))
+                    .run_retries(0),
+            )
+            .build()
+            .await
+            .unwrap();
+
+        let remote_key = (0..)
+            .map(|i| format!("key-{i}"))
+            .find(|key| cluster.member_for_key(key) == "remote")
+            .unwrap();
+        let value: i32 = cluster.eval(&remote_key, "1 + 1").await.unwrap();
+        assert_eq!(value, 2);
+
+        let mut conn = cluster.connect_for_key(&remote_key).await.unwrap();
+        conn.run_script_and_wait(RunScriptArgs {
+            code: "globalThis.count = 1".into(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        drop(conn);
+        let conn = cluster.connect_for_key(&remote_key).await.unwrap();
+        assert!(conn.resumed());
+        drop(conn);
+
+        server.close().await;
+        let value: i32 = cluster.eval(&remote_key, "2 + 2").await.unwrap();
+        assert_eq!(value, 4);
+        assert_eq!(cluster.member_for_key(&remote_key), "local");
+        assert_eq!(
+            cluster.members(),
+            vec![
+                ClusterMemberStatus {
+                    name: "local".into(),
+                    healthy: true,
+                },
+                ClusterMemberStatus {
+                    name: "remote".into(),
+                    healthy: false,
+                },
+            ]
+        );
+
+        cluster.close().await;
+    }
+}
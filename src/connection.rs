@@ -1,65 +1,516 @@
 use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
     io,
-    path::PathBuf,
-    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
-    time::Duration,
+    net::SocketAddr,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    pin::Pin,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
 };
 
-use deadpool::managed::{Metrics, Pool};
-use futures::stream::{self, StreamExt};
-use tempfile::NamedTempFile;
+use deadpool::managed::{Manager, Metrics, Pool, PoolError, QueueMode, Timeouts, WeakPool};
+use futures::{future::BoxFuture, Sink, Stream};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use tempfile::{NamedTempFile, TempDir};
 use tokio::{
-    io::AsyncWriteExt,
-    net::{unix::OwnedWriteHalf, UnixStream},
+    io::{AsyncWrite, AsyncWriteExt},
     process::{Child, Command},
-    sync::mpsc,
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
 };
 
 use crate::{
+    affinity::{KeyedConnection, SessionConnections, DEFAULT_MAX_SESSION_KEYS},
+    approval::ScriptApproval,
+    audit::{AuditLog, AuditStatus, PendingAudit},
+    cgroup::Cgroup,
+    channel::{ChannelOverflow, MessageForwarder, DEFAULT_CHANNEL_SIZE},
+    checkout::{CheckoutError, CheckoutQueue, PoolStatus},
+    corpus::{hex_string, CorpusCollector},
     error::RunScriptError,
-    messages::RunScriptArgs,
-    protocol::{
-        HostToWorkerMessage, HostToWorkerMessageData, WorkerToHostMessage, WorkerToHostMessageData,
+    events::{
+        forward_events, forward_output, OutputStream, SidecarEvent, SidecarEvents,
+        EVENT_CHANNEL_SIZE,
+    },
+    jobs::JobHandle,
+    kv::{self, KvBackend},
+    latency::{Ewma, LatencyStats, WorkerLatencies},
+    limits::{check_string_lengths, key_path},
+    load::WorkerLoads,
+    management::{self, SidecarHealth, WorkerStats},
+    memory::WorkerMemory,
+    messages::{ErrorKind, Priority, RunScriptArgs, StartedJob, TimerMode},
+    prewarm::{ContextReadiness, PrewarmContext, PrewarmManifest, PrewarmReport},
+    process::bind_unix_socket,
+    registry::ScriptRegistry,
+    replay::{self, RecordedMessage, Recorder, Recording, ReplayDivergence, ReplayResult},
+    resolver::{self, ModuleResolver},
+    schedule::Scheduler,
+    shared_memory::SharedMemory,
+    sink::MessageSink,
+    tenants::{TenantStats, Tenants},
+    transport::{ReadHalf, WorkerAddress, WriteHalf},
+    versions::PROTOCOL_VERSION,
+    wire::{
+        self, to_worker, CachedModule, FrameLimits, FrameOptions, FrameReader, HostToWorkerMessage,
+        HostToWorkerMessageData, RegisteredScriptRef, RunScriptMessage, WorkerToHostMessage,
+        WorkerToHostMessageData,
     },
-    Error, RunResponseData,
+    AdvanceTimeData, AdvanceTimeResult, CheckScriptData, CheckScriptOptions, CompleteData,
+    Completions, CpuProfileData, DebuggerWaitingData, Error, HandshakeData, IntrospectData,
+    Isolation, JsSidecarBuilder, LogLevel, LogResponseData, MemoryUsageData, PropertyHint,
+    RunResponseData, ScriptCheck, WorkerLoadData,
 };
 
 const SCRIPT: &str = include_str!("./worker/dist/index.js");
 
+/// The SHA-256 hash of the bundled worker code, as hex, which tells whether a [Recording] was made
+/// with the same worker.
+fn worker_hash() -> String {
+    hex_string(&Sha256::digest(SCRIPT.as_bytes()))
+}
+
 /// To ensure unique sockets per instance
 static COUNTER: AtomicU64 = AtomicU64::new(0);
 
+/// The default for [JsSidecarBuilder::max_frame_bytes].
+const DEFAULT_MAX_FRAME_BYTES: usize = 64 * 1024 * 1024;
+
+/// How long to wait for the Node.js process to start listening on its socket.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often workers report their memory usage by default when
+/// [JsSidecarBuilder::max_worker_heap] is set.
+const DEFAULT_MEMORY_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often workers report their load by default when [JsSidecarBuilder::prefer_idle_workers]
+/// is set.
+const DEFAULT_LOAD_REPORT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The default maximum number of connections in the pool.
+const DEFAULT_POOL_MAX_SIZE: usize = 1024;
+
+/// How long a worker has to answer a ping before a pooled connection is reused, by default.
+const DEFAULT_RECYCLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a pooled connection can sit unused before it is pinged on checkout, by default.
+const DEFAULT_VERIFY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often the pool is topped up to [JsSidecarBuilder::min_idle] connections.
+const MIN_IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long workers cache the modules fetched for URL imports by default.
+const DEFAULT_URL_IMPORT_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// How long past a run's timeout a worker has to answer before it is killed, by default.
+const DEFAULT_KILL_AFTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many messages for requests that already ended a connection keeps, from
+/// [Connection::take_stale_messages].
+const MAX_STALE_MESSAGES: usize = 100;
+
+/// How many times [JsSidecar::run] retries by default.
+const DEFAULT_RUN_RETRIES: u32 = 0;
+
+/// How long [JsSidecar::run] waits before each retry, multiplied by the attempt number. When a
+/// worker dies, Node.js can still hand new connections to it until it notices the exit, and those
+/// connections hang, so retrying immediately is likely to fail.
+const RUN_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How many connections to slow workers [JsSidecar::connect] will pass over when
+/// [JsSidecarBuilder::prefer_fast_workers] is enabled.
+const MAX_SLOW_CONNECTION_SKIPS: usize = 3;
+
+/// `sun_path` is 108 bytes on Linux and 104 on macOS, including the trailing NUL.
+const MAX_SOCKET_PATH_LEN: usize = 103;
+
 /// The result of running a script
 #[derive(Debug, Clone)]
 pub struct RunScriptAndWaitResult {
     /// The result of running the script
     pub response: RunResponseData,
-    /// Other messages that arrived in the meantime, such as console logs.
-    pub messages: Vec<WorkerToHostMessageData>,
+    /// Console messages logged by the script.
+    pub logs: Vec<LogResponseData>,
+    /// Messages for other runs on the connection that were still going, which arrived in the
+    /// meantime. Messages for requests that had already ended are set aside instead, in
+    /// [Connection::take_stale_messages].
+    pub other: Vec<WorkerToHostMessageData>,
+    /// The CPU profile of the run, if [RunScriptArgs::profile] was set.
+    pub cpu_profile: Option<CpuProfileData>,
+    /// The number of bytes that the run's messages took up on the connection.
+    pub wire: WireSizes,
+}
+
+/// Byte counts for the messages of a run, as they went over the connection, after compression
+/// and including frame headers. Compare them against the sizes of the globals and the result to
+/// see what [compression](crate::JsSidecarBuilder::compress_frames) saves, or to find the scripts
+/// that move large payloads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WireSizes {
+    /// Bytes sent to the worker: the script and its arguments, and values sent on a [Channel].
+    pub request_bytes: u64,
+    /// Bytes received from the worker for the run: its result, console messages, output, and
+    /// profile. A response sent in chunks counts all of its chunks.
+    pub response_bytes: u64,
+}
+
+impl RunScriptAndWaitResult {
+    /// Console messages logged at `level` or higher.
+    pub fn logs_at_level(&self, level: LogLevel) -> impl Iterator<Item = &LogResponseData> {
+        self.logs.iter().filter(move |log| log.level >= level)
+    }
+
+    /// Console messages logged with the given namespace.
+    pub fn logs_in_namespace<'a>(
+        &'a self,
+        namespace: &'a str,
+    ) -> impl Iterator<Item = &'a LogResponseData> {
+        self.logs
+            .iter()
+            .filter(move |log| log.namespace.as_deref() == Some(namespace))
+    }
 }
 
 /// JsSidecar starts the Node.js process and allows connecting to its socket.
 pub struct JsSidecar {
     node_process: Option<Child>,
-    socket_path: PathBuf,
-    _script_file: NamedTempFile,
+    socket_path: Option<PathBuf>,
+    _script_file: Option<NamedTempFile>,
+    _socket_dir: Option<TempDir>,
     pool: Pool<ConnectionManager>,
+    checkout: CheckoutQueue,
+    events: broadcast::Sender<SidecarEvent>,
+    idle_eviction_task: Option<JoinHandle<()>>,
+    heartbeat_task: Option<JoinHandle<()>>,
+    slow_checkout_threshold: Option<Duration>,
+    min_idle_task: Option<JoinHandle<()>>,
+    run_retries: u32,
+    num_workers: usize,
+    latencies: Arc<WorkerLatencies>,
+    prefer_fast_workers: bool,
+    prefer_idle_workers: bool,
+    sessions: Arc<SessionConnections>,
+    tenants: Tenants,
+    memory: Arc<WorkerMemory>,
+    loads: Arc<WorkerLoads>,
+    inspector_url: Arc<Mutex<Option<String>>>,
+    management_addr: Option<SocketAddr>,
+    cgroup: Option<Arc<Cgroup>>,
+    registry: Arc<ScriptRegistry>,
+    scheduler: Scheduler,
 }
 
 impl JsSidecar {
     /// Start Node.js and set up the socket.
     /// `num_workers` is the number of worker processes to start, and will use the number of CPUs
     /// on the system if omitted.
+    ///
+    /// Use [JsSidecar::builder] for more configuration options.
     pub async fn new(num_workers: Option<u32>) -> Result<Self, Error> {
-        let pid = std::process::id();
-        let counter = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        let temp_dir = std::env::temp_dir();
-        let socket_path = temp_dir.join(format!("js_sidecar.{}.{}.sock", pid, counter));
+        let mut builder = JsSidecarBuilder::new();
+        builder.num_workers = num_workers;
+        builder.build().await
+    }
+
+    /// Create a [JsSidecarBuilder] to configure the sidecar before starting it.
+    pub fn builder() -> JsSidecarBuilder {
+        JsSidecarBuilder::new()
+    }
+
+    pub(crate) async fn start(options: JsSidecarBuilder) -> Result<Self, Error> {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_SIZE);
+        let remote = options.transport.is_some() || options.worker_url.is_some();
+        let memory = Arc::new(WorkerMemory::new(options.max_worker_heap, events.clone()));
+        if !remote {
+            // Subscribe before starting Node.js, so that no worker's start is missed.
+            memory.track_children(events.subscribe());
+        }
+        // Remote workers can't see this machine's shared memory.
+        let shared_memory = match (remote, options.shared_memory_over) {
+            (false, Some(min_bytes)) => Some(Arc::new(
+                SharedMemory::new(min_bytes).map_err(Error::StartWorker)?,
+            )),
+            _ => None,
+        };
+        let cgroup = if remote {
+            None
+        } else {
+            Self::create_cgroup(&options)?.map(Arc::new)
+        };
+        let (address, node_process, script_file, socket_dir) =
+            match (&options.transport, &options.worker_url) {
+                (Some(transport), _) => {
+                    (WorkerAddress::Custom(transport.clone()), None, None, None)
+                }
+                (None, Some(url)) => {
+                    let address = WorkerAddress::WebSocket {
+                        url: url.clone(),
+                        token: options.websocket_token.clone(),
+                    };
+                    (address, None, None, None)
+                }
+                (None, None) => {
+                    let started = Self::start_node(
+                        &options,
+                        &events,
+                        cgroup.as_deref(),
+                        shared_memory.as_deref(),
+                    )
+                    .await;
+                    let (socket_path, node_process, script_file, socket_dir) = match started {
+                        Ok(started) => started,
+                        Err(e) => {
+                            if let Some(cgroup) = &cgroup {
+                                cgroup.remove();
+                            }
+                            return Err(e);
+                        }
+                    };
+                    (
+                        WorkerAddress::Socket(socket_path),
+                        Some(node_process),
+                        Some(script_file),
+                        Some(socket_dir),
+                    )
+                }
+            };
+
+        let num_workers = options
+            .num_workers
+            .map(|n| n as usize)
+            .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+            .unwrap_or(1);
+
+        let start_time = Instant::now();
+        loop {
+            // Wait until the socket exists and can be connected
+            let stream = address.connect().await;
+            if stream.is_ok() {
+                break;
+            }
+
+            if start_time.elapsed() > STARTUP_TIMEOUT {
+                return Err(Error::StartWorker(io::Error::other(
+                    "Timed out waiting for socket to be ready",
+                )));
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let latencies = Arc::new(WorkerLatencies::default());
+        let inspector_url = Arc::new(Mutex::new(None));
+        let registry = Arc::new(ScriptRegistry::default());
+        let loads = Arc::new(WorkerLoads::new(
+            options
+                .load_report_interval
+                .unwrap_or(DEFAULT_LOAD_REPORT_INTERVAL),
+        ));
+        let socket_path = match &address {
+            WorkerAddress::Socket(path) => Some(path.clone()),
+            WorkerAddress::WebSocket { .. } | WorkerAddress::Custom(_) => None,
+        };
+        let kill_after_timeout = socket_path.is_some().then(|| {
+            options
+                .kill_after_timeout
+                .unwrap_or(DEFAULT_KILL_AFTER_TIMEOUT)
+        });
+        let pool = Pool::builder(ConnectionManager {
+            recycle_calls: AtomicUsize::new(0),
+            recycle_success: AtomicUsize::new(0),
+            filling: tokio::sync::Mutex::new(()),
+            prepared: Mutex::new(Vec::new()),
+            options: Arc::new(ConnectionOptions {
+                address,
+                corpus: options.corpus,
+                audit: options.audit,
+                approval: ScriptApproval {
+                    allowed_hashes: options.allowed_script_hashes,
+                    verifier: options.script_verifier,
+                },
+                max_string_bytes: options.max_string_bytes,
+                max_log_messages: options.max_log_messages,
+                max_log_bytes: options.max_log_bytes,
+                min_log_level: options.min_log_level,
+                replace_invalid_unicode: options.replace_invalid_unicode,
+                channel_size: options.channel_size.unwrap_or(DEFAULT_CHANNEL_SIZE),
+                channel_overflow: options.channel_overflow,
+                latencies: latencies.clone(),
+                max_requests: options.max_requests_per_connection,
+                max_age: options.max_connection_age,
+                memory: memory.clone(),
+                loads: loads.clone(),
+                events: events.clone(),
+                inspector_url: inspector_url.clone(),
+                recycle_timeout: options
+                    .pool_recycle_timeout
+                    .unwrap_or(DEFAULT_RECYCLE_TIMEOUT),
+                verify_interval: options
+                    .pool_verify_interval
+                    .unwrap_or(DEFAULT_VERIFY_INTERVAL),
+                kill_after_timeout,
+                frame_checksums: options.frame_checksums,
+                strict_messages: options.strict_messages,
+                compress_frames_over: options.compress_frames_over,
+                shared_memory,
+                frame_limits: FrameLimits {
+                    max_frame_bytes: options.max_frame_bytes.unwrap_or(DEFAULT_MAX_FRAME_BYTES),
+                    max_response_bytes: options.max_response_bytes,
+                },
+                kv: options.kv,
+                module_resolver: options.module_resolver,
+                url_imports: options.url_imports,
+                url_import_ttl: options.url_import_ttl.unwrap_or(DEFAULT_URL_IMPORT_TTL),
+                cgroup: cgroup.clone(),
+                registry: registry.clone(),
+                pool: OnceLock::new(),
+            }),
+        })
+        .max_size(options.pool_max_size.unwrap_or(DEFAULT_POOL_MAX_SIZE))
+        .queue_mode(options.pool_queue_mode.unwrap_or(QueueMode::Lifo))
+        .wait_timeout(options.pool_wait_timeout)
+        .create_timeout(options.pool_create_timeout)
+        .runtime(deadpool::Runtime::Tokio1)
+        .build()
+        .map_err(Error::BuildPool)?;
+        pool.manager().options.pool.set(pool.weak()).ok();
+
+        let sessions = Arc::new(SessionConnections::new(
+            options.max_session_keys.unwrap_or(DEFAULT_MAX_SESSION_KEYS),
+        ));
+
+        let min_idle = options.min_idle.unwrap_or(0);
+        if min_idle > 0 {
+            fill_idle(&pool, min_idle).await?;
+        }
+        let min_idle_task =
+            (min_idle > 0).then(|| tokio::task::spawn(maintain_min_idle(pool.clone(), min_idle)));
+
+        let idle_eviction_task = options.idle_timeout.map(|timeout| {
+            tokio::task::spawn(evict_idle_connections(
+                pool.clone(),
+                sessions.clone(),
+                timeout,
+                min_idle,
+            ))
+        });
+
+        let heartbeat_task = options
+            .pool_heartbeat_interval
+            .map(|interval| tokio::task::spawn(heartbeat_idle_connections(pool.clone(), interval)));
+
+        let scheduler =
+            Scheduler::start(pool.clone(), events.clone(), options.schedule_store).await?;
+
+        Ok(JsSidecar {
+            node_process,
+            pool,
+            checkout: CheckoutQueue::new(options.max_queued_requests),
+            tenants: Tenants::new(options.tenant_quotas, options.default_tenant_quota),
+            socket_path,
+            events,
+            idle_eviction_task,
+            heartbeat_task,
+            slow_checkout_threshold: options.slow_checkout_threshold,
+            min_idle_task,
+            run_retries: options.run_retries.unwrap_or(DEFAULT_RUN_RETRIES),
+            num_workers,
+            latencies,
+            prefer_fast_workers: options.prefer_fast_workers,
+            prefer_idle_workers: options.prefer_idle_workers,
+            management_addr: options.management_addr,
+            cgroup,
+            sessions,
+            memory,
+            loads,
+            inspector_url,
+            registry,
+            scheduler,
+            // Make sure we keep the script file alive as long as the sidecar is alive.
+            _script_file: script_file,
+            _socket_dir: socket_dir,
+        })
+    }
+
+    /// Set up the cgroup to start Node.js in, if there is one, with its limits.
+    fn create_cgroup(options: &JsSidecarBuilder) -> Result<Option<Cgroup>, Error> {
+        let cgroup = match (&options.managed_cgroup, &options.process.cgroup) {
+            (Some(parent), _) => {
+                let counter = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let name = format!("js_sidecar.{}.{}", std::process::id(), counter);
+                Cgroup::create(parent, &name).map_err(Error::StartWorker)?
+            }
+            (None, Some(dir)) => Cgroup::existing(dir.clone()),
+            (None, None) if options.cgroup_limits.is_some() => {
+                return Err(Error::StartWorker(io::Error::other(
+                    "cgroup_limits needs a cgroup or managed_cgroup",
+                )));
+            }
+            (None, None) => return Ok(None),
+        };
+
+        if let Some(limits) = &options.cgroup_limits {
+            if let Err(e) = cgroup.set_limits(limits) {
+                cgroup.remove();
+                return Err(Error::StartWorker(e));
+            }
+        }
+        Ok(Some(cgroup))
+    }
+
+    /// Write out the worker script and start Node.js, returning the path of its socket and the
+    /// directory that the socket is in.
+    async fn start_node(
+        options: &JsSidecarBuilder,
+        events: &broadcast::Sender<SidecarEvent>,
+        cgroup: Option<&Cgroup>,
+        shared_memory: Option<&SharedMemory>,
+    ) -> Result<(PathBuf, Child, NamedTempFile, TempDir), Error> {
+        // Each sidecar gets its own directory for its socket, which is the only part of the
+        // socket directory that a hardened Node.js process can write to.
+        let socket_dir = tempfile::Builder::new()
+            .prefix("js_sidecar.")
+            .tempdir_in(
+                options
+                    .socket_dir
+                    .clone()
+                    .unwrap_or_else(std::env::temp_dir),
+            )
+            .map_err(Error::StartWorker)?;
+        let socket_path = socket_dir.path().join("worker.sock");
 
+        if socket_path.as_os_str().len() > MAX_SOCKET_PATH_LEN {
+            return Err(Error::StartWorker(io::Error::other(format!(
+                "Socket path {} is too long, try a shorter socket_dir",
+                socket_path.display()
+            ))));
+        }
+
+        // The socket that the workers listen on, and one end of a pair for Node.js to send its
+        // events over, are created here and handed to Node.js, so that Node.js never has to
+        // create a Unix socket itself. A hardened process isn't allowed to. Connecting to the
+        // socket fails until a worker starts listening on it, as if Node.js had created it.
+        let listener = bind_unix_socket(&socket_path).map_err(Error::StartWorker)?;
+        let (events_stream, node_events) =
+            std::os::unix::net::UnixStream::pair().map_err(Error::StartWorker)?;
+
+        let script_dir = options
+            .script_dir
+            .clone()
+            .unwrap_or_else(std::env::temp_dir);
         let input_script = tempfile::Builder::new()
             .prefix("js_sidecar")
             .suffix(".mjs")
-            .tempfile()
+            .tempfile_in(&script_dir)
             .map_err(Error::StartWorker)?;
 
         let script_path = input_script.path();
@@ -71,69 +522,518 @@ impl JsSidecar {
         let mut command = Command::new("node");
 
         command
+            // `close` and dropping the sidecar shut down Node.js gracefully, but if that doesn't
+            // get to run, such as when the runtime stops first, don't leave the process behind.
+            .kill_on_drop(true)
             // Silence warning for experimental-vm-modules
             .arg("--no-warnings=ExperimentalWarning")
             // Enable ES Module functionality in vm package
             .arg("--experimental-vm-modules")
             .arg(script_path)
             .arg("--socket")
-            .arg(&socket_path);
+            .arg(&socket_path)
+            .arg("--socket-fd")
+            .arg(listener.as_raw_fd().to_string())
+            .arg("--events-fd")
+            .arg(node_events.as_raw_fd().to_string());
 
-        if let Some(num_workers) = num_workers {
+        if let Some(num_workers) = options.num_workers {
             command.arg("--workers").arg(num_workers.to_string());
         }
+        if let Some(addr) = options.websocket_addr {
+            command.arg("--websocket").arg(addr.to_string());
+            // Passed in the environment rather than as an argument, which other users can see.
+            if let Some(token) = &options.websocket_token {
+                command.env("WEBSOCKET_TOKEN", token);
+            }
+        }
+        if let Some(addr) = options.management_addr {
+            command.arg("--management").arg(addr.to_string());
+        }
+        if options.isolation == Isolation::Thread {
+            command.arg("--isolation").arg("thread");
+        }
+        let mut process = options.process.clone();
+        process.inherited_fds = vec![listener.as_raw_fd(), node_events.as_raw_fd()];
+        if let Some(cgroup) = cgroup {
+            process.cgroup = Some(cgroup.dir().to_path_buf());
+        }
+        let sandbox = options
+            .hardening
+            .as_ref()
+            .map(|hardening| {
+                // Node.js fetches URL imports, and listens for WebSocket and management
+                // connections, over the network.
+                let network = !options.url_imports.is_empty()
+                    || options.websocket_addr.is_some()
+                    || options.management_addr.is_some();
+                let write_paths = std::iter::once(socket_dir.path())
+                    .chain(shared_memory.map(|shm| shm.path()))
+                    .collect::<Vec<_>>();
+                hardening.sandbox(network, &[script_path], &write_paths)
+            })
+            .transpose()?;
+        process.apply(&mut command, sandbox)?;
+        let memory_report_interval = options.memory_report_interval.or(options
+            .max_worker_heap
+            .map(|_| DEFAULT_MEMORY_REPORT_INTERVAL));
+        if let Some(interval) = memory_report_interval {
+            command
+                .arg("--memory-report-interval")
+                .arg(interval.as_millis().max(1).to_string());
+        }
+        let load_report_interval = options.load_report_interval.or(options
+            .prefer_idle_workers
+            .then_some(DEFAULT_LOAD_REPORT_INTERVAL));
+        if let Some(interval) = load_report_interval {
+            command
+                .arg("--load-report-interval")
+                .arg(interval.as_millis().max(1).to_string());
+        }
+        if let Some(size) = options.context_pool_size {
+            command.arg("--context-pool-size").arg(size.to_string());
+        }
 
-        let node_process = command.spawn().map_err(Error::StartWorker)?;
+        if options.capture_output {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
 
-        let mut checks = 0;
+        let mut node_process = command.spawn().map_err(Error::StartWorker)?;
+        // Node.js has its own copies now.
+        drop((listener, node_events));
 
-        while checks < 50 {
-            // Wait until the socket exists and can be connected
-            let stream = UnixStream::connect(&socket_path).await;
-            if stream.is_ok() {
-                break;
-            }
+        events_stream
+            .set_nonblocking(true)
+            .map_err(Error::StartWorker)?;
+        let events_stream =
+            tokio::net::UnixStream::from_std(events_stream).map_err(Error::StartWorker)?;
+        tokio::task::spawn(forward_events(events_stream, events.clone()));
 
-            tokio::time::sleep(Duration::from_millis(10)).await;
-            checks += 1;
+        if let Some(stdout) = node_process.stdout.take() {
+            tokio::task::spawn(forward_output(stdout, OutputStream::Stdout, events.clone()));
         }
-
-        if checks == 50 {
-            return Err(Error::StartWorker(io::Error::other(
-                "Timed out waiting for socket to be ready",
-            )));
+        if let Some(stderr) = node_process.stderr.take() {
+            tokio::task::spawn(forward_output(stderr, OutputStream::Stderr, events.clone()));
         }
 
-        let pool = Pool::builder(ConnectionManager {
-            socket_path: socket_path.clone(),
-            recycle_calls: AtomicUsize::new(0),
-            recycle_success: AtomicUsize::new(0),
-        })
-        .max_size(1024)
-        .queue_mode(deadpool::managed::QueueMode::Lifo)
-        .build()
-        .map_err(Error::BuildPool)?;
+        Ok((socket_path, node_process, input_script, socket_dir))
+    }
 
-        Ok(JsSidecar {
-            node_process: Some(node_process),
-            pool,
-            socket_path,
-            // Make sure we keep the script file alive as long as the sidecar is alive.
-            _script_file: input_script,
-        })
+    /// The path of the Unix socket that the workers are listening on, or `None` when connecting
+    /// to workers at a [worker_url](JsSidecarBuilder::worker_url).
+    pub fn socket_path(&self) -> Option<&Path> {
+        self.socket_path.as_deref()
+    }
+
+    /// Subscribe to events from the sidecar that aren't associated with a particular connection,
+    /// such as workers starting, exiting, and crashing. Only events sent after subscribing are
+    /// received. Worker lifecycle events aren't available when connecting to a
+    /// [worker_url](JsSidecarBuilder::worker_url), since the sidecar doesn't start Node.js then.
+    pub fn subscribe(&self) -> SidecarEvents {
+        self.events.subscribe()
     }
 
     /// Create a new connection with its own run context.
     pub async fn connect(&self) -> Result<PoolConnection, Error> {
-        self.pool.get().await.map_err(|e| Error::Pool(Box::new(e)))
+        self.connect_with_priority(Priority::Normal).await
+    }
+
+    /// Create a new connection, ahead of callers waiting with a lower priority when the pool is
+    /// exhausted. Waiters with the same priority get connections in the order they asked for them.
+    pub async fn connect_with_priority(&self, priority: Priority) -> Result<PoolConnection, Error> {
+        let mut conn = self.get_connection(priority).await?;
+        if !self.prefer_fast_workers && !self.prefer_idle_workers {
+            return Ok(conn);
+        }
+
+        // Hold on to the skipped connections until we're done, so that we don't get them again.
+        let mut skipped = Vec::new();
+        while skipped.len() < MAX_SLOW_CONNECTION_SKIPS
+            && self.pool.status().available > 0
+            && conn.worker_pid.is_some_and(|pid| self.should_skip(pid))
+        {
+            skipped.push(conn);
+            conn = self.get_connection(priority).await?;
+        }
+
+        Ok(conn)
+    }
+
+    /// Get a connection for a session key. Connections for the same key reuse the same worker
+    /// context when possible, so state left by earlier runs, such as caches or per-user data, is
+    /// still there. Distinct keys get separate connections, which are spread across the workers.
+    ///
+    /// When the [KeyedConnection] is dropped, it is kept for the key instead of returning to the
+    /// pool. A new context is used instead if the key's connection is already in use, if its
+    /// worker has stopped responding, or if the key was dropped to make room for others, as
+    /// limited by [JsSidecarBuilder::max_session_keys]. When the pool has no connections left, a
+    /// checkout ends the least recently used session rather than waiting.
+    pub async fn connect_for_key(&self, key: &str) -> Result<KeyedConnection, Error> {
+        if let Some(mut conn) = self
+            .sessions
+            .take(key)
+            .filter(|conn| conn.retirement_reason().is_none())
+        {
+            match conn.verify().await {
+                Ok(()) => {
+                    return Ok(KeyedConnection::new(
+                        key.to_string(),
+                        conn,
+                        true,
+                        self.sessions.clone(),
+                    ))
+                }
+                Err(e) => {
+                    tracing::debug!(error = ?e, key, "Discarding broken session connection");
+                    let _ = deadpool::managed::Object::take(conn);
+                }
+            }
+        }
+
+        let conn = self.connect().await?;
+        Ok(KeyedConnection::new(
+            key.to_string(),
+            conn,
+            false,
+            self.sessions.clone(),
+        ))
+    }
+
+    /// Open a connection with its own socket that doesn't belong to the pool, for latency-critical
+    /// callers that keep it for their whole lifetime. Runs on it skip the pool's checkout and the
+    /// ping that checks a connection before it is reused, and its context is never recreated
+    /// behind the caller's back, so state left by earlier runs is always there.
+    ///
+    /// The connection doesn't count toward the pool's size, and closes when it is dropped. If it
+    /// breaks, open a new one.
+    pub async fn dedicated_connection(&self) -> Result<Connection, Error> {
+        self.pool.manager().create().await
+    }
+
+    /// Returns true if connections to the worker should be passed over in favor of others.
+    fn should_skip(&self, pid: u32) -> bool {
+        (self.prefer_fast_workers && self.latencies.is_slow(pid))
+            || (self.prefer_idle_workers && self.loads.is_busier(pid))
+    }
+
+    async fn get_connection(&self, priority: Priority) -> Result<PoolConnection, Error> {
+        let start = Instant::now();
+        // Connections parked for session keys stay checked out, so rather than wait for one of
+        // them to come back, end the session that was least recently used.
+        let status = self.pool.status();
+        if status.available == 0 && status.size >= status.max_size && self.sessions.evict_oldest() {
+            tracing::debug!("Pool is exhausted, returning a session's connection to it");
+        }
+        let conn = self
+            .checkout
+            .get(&self.pool, priority)
+            .await
+            .map_err(|e| match e {
+                CheckoutError::Pool(e) => Error::Pool(Box::new(e)),
+                CheckoutError::Overloaded { queued, limit } => Error::Overloaded { queued, limit },
+            })?;
+
+        let waited = start.elapsed();
+        if self.slow_checkout_threshold.is_some_and(|t| waited > t) {
+            let status = self.pool_status();
+            tracing::warn!(?waited, ?priority, ?status, "Slow connection checkout");
+            self.events
+                .send(SidecarEvent::SlowCheckout {
+                    waited,
+                    priority,
+                    status,
+                })
+                .ok();
+        }
+
+        Ok(conn)
+    }
+
+    /// The number of connections in the pool, and of checkouts waiting for one. Connections held
+    /// for [session keys](Self::connect_for_key) count as in use.
+    pub fn pool_status(&self) -> PoolStatus {
+        self.checkout.status(&self.pool)
+    }
+
+    /// The recent latency of each worker process, keyed by process ID. Workers that haven't run
+    /// a script in the last ten minutes are left out.
+    pub fn worker_latencies(&self) -> HashMap<u32, LatencyStats> {
+        self.latencies.snapshot()
+    }
+
+    /// The latest memory usage reported by each worker process, keyed by process ID. This is only
+    /// populated when [JsSidecarBuilder::memory_report_interval] or
+    /// [JsSidecarBuilder::max_worker_heap] is set.
+    pub fn worker_memory(&self) -> HashMap<u32, MemoryUsageData> {
+        self.memory.snapshot()
+    }
+
+    /// The latest load reported by each worker process, keyed by process ID. This is only
+    /// populated when [JsSidecarBuilder::load_report_interval] or
+    /// [JsSidecarBuilder::prefer_idle_workers] is set, and leaves out workers that have stopped
+    /// reporting.
+    pub fn worker_loads(&self) -> HashMap<u32, WorkerLoadData> {
+        self.loads.snapshot()
+    }
+
+    /// Statistics for each [tenant](RunScriptArgs::tenant) that has run a script through
+    /// [JsSidecar::run], keyed by tenant.
+    pub fn tenant_stats(&self) -> HashMap<String, TenantStats> {
+        self.tenants.snapshot()
+    }
+
+    /// The WebSocket URL of the inspector that a run with [RunScriptArgs::debug] set is waiting on,
+    /// for Chrome DevTools or VS Code to attach to. This is `None` until a debug run starts, and
+    /// then keeps the URL of the most recent one. Each worker has its own inspector.
+    pub fn inspector_url(&self) -> Option<String> {
+        self.inspector_url.lock().unwrap().clone()
+    }
+
+    /// Get the health of the Node.js process from the management API enabled by
+    /// [JsSidecarBuilder::management_addr].
+    pub async fn health(&self) -> Result<SidecarHealth, Error> {
+        management::get_json(self.management_addr()?, "/health").await
+    }
+
+    /// Get statistics for each worker process from the management API enabled by
+    /// [JsSidecarBuilder::management_addr]. Workers that don't answer within a second, such as
+    /// one that is stuck running a script, are left out.
+    pub async fn worker_stats(&self) -> Result<Vec<WorkerStats>, Error> {
+        management::get_json(self.management_addr()?, "/workers").await
+    }
+
+    fn management_addr(&self) -> Result<SocketAddr, Error> {
+        self.management_addr
+            .ok_or_else(|| Error::Management("The management API is not enabled".to_string()))
+    }
+
+    /// Run a script on a connection from the pool and wait for it to finish.
+    ///
+    /// If [JsSidecarBuilder::run_retries] is set and the connection turns out to be broken before
+    /// the script was sent, for example because the worker restarted, it is discarded and the
+    /// script is retried on a new connection. Once the script has been written to the worker, any
+    /// failure is returned as is, whether the worker dies partway through the run
+    /// ([Error::ScriptEndedEarly]) or the connection breaks while waiting for the result, since
+    /// the script may have already performed some of its side effects, or may have been what
+    /// crashed the worker.
+    ///
+    /// The connection is checked out with the run's [priority](RunScriptArgs::priority), after
+    /// the run's [tenant](RunScriptArgs::tenant), if any, has room for it under its quota.
+    pub async fn run(&self, args: RunScriptArgs) -> Result<RunScriptAndWaitResult, Error> {
+        self.run_tracking_sent(args).await.0
+    }
+
+    /// Like [run](Self::run), and also returns whether the script was sent to a worker, so that
+    /// a failed run is only tried elsewhere if it can't have started.
+    pub(crate) async fn run_tracking_sent(
+        &self,
+        args: RunScriptArgs,
+    ) -> (Result<RunScriptAndWaitResult, Error>, bool) {
+        let Some(tenant) = args.tenant.as_deref() else {
+            return self.run_with_retries(args).await;
+        };
+
+        let permit = match self.tenants.acquire(tenant).await {
+            Ok(permit) => permit,
+            Err(e) => return (Err(e), false),
+        };
+        let (result, sent) = self.run_with_retries(args).await;
+        permit.finish(
+            result.is_ok(),
+            result.as_ref().ok().and_then(|r| r.response.stats.as_ref()),
+        );
+        (result, sent)
+    }
+
+    async fn run_with_retries(
+        &self,
+        args: RunScriptArgs,
+    ) -> (Result<RunScriptAndWaitResult, Error>, bool) {
+        let mut attempt = 0;
+        loop {
+            let (result, sent) = match self.connect_with_priority(args.priority).await {
+                Ok(mut conn) => {
+                    let runs = conn.run_count;
+                    let result = conn.run_script_and_wait(args.clone()).await;
+                    // The run count only goes up once the whole RunScript frame is written.
+                    let sent = conn.run_count != runs;
+                    if result.as_ref().is_err_and(Error::is_connection_failure) {
+                        // Don't return the broken connection to the pool.
+                        let _ = deadpool::managed::Object::take(conn);
+                    }
+                    (result, sent)
+                }
+                Err(e) => (Err(e), false),
+            };
+
+            match result {
+                Err(e) if !sent && e.is_connection_failure() && attempt < self.run_retries => {
+                    attempt += 1;
+                    tracing::debug!(error = ?e, attempt, "Retrying script on a new connection");
+                    tokio::time::sleep(RUN_RETRY_BACKOFF * attempt).await;
+                }
+                result => return (result, sent),
+            }
+        }
+    }
+
+    /// Run `args` on a connection from the pool each time that the cron expression `cron`
+    /// comes up, until [unschedule](Self::unschedule) is called with the returned ID or the
+    /// sidecar closes. See [CronSchedule](crate::CronSchedule) for the syntax. Each run's result or error is sent to
+    /// [subscribers](Self::subscribe) as a [SidecarEvent::ScheduledRun].
+    ///
+    /// Scheduled runs take their connections straight from the pool, without
+    /// [retries](JsSidecarBuilder::run_retries) or [tenant quotas](JsSidecarBuilder::tenant_quota).
+    /// A run that is still going when the next one is due delays it, and missed runs are skipped.
+    /// The schedule is saved to the [schedule_store](JsSidecarBuilder::schedule_store), if there
+    /// is one.
+    pub async fn schedule(&self, cron: &str, args: RunScriptArgs) -> Result<u64, Error> {
+        self.scheduler.add(cron, args).await
+    }
+
+    /// Stop running the scheduled script with ID `id`, and remove it from the
+    /// [schedule_store](JsSidecarBuilder::schedule_store). A run that is in progress is
+    /// abandoned, as if its [run](Self::run) future were dropped. Returns false if there was no
+    /// such schedule.
+    pub async fn unschedule(&self, id: u64) -> Result<bool, Error> {
+        self.scheduler.remove(id).await
+    }
+
+    /// Evaluate a JavaScript expression on a connection from the pool and convert its value to `T`.
+    /// The connection is retried as in [JsSidecar::run].
+    ///
+    /// ```no_run
+    /// # async fn example() -> Result<(), js_sidecar::Error> {
+    /// let sidecar = js_sidecar::JsSidecar::new(None).await?;
+    /// let value: i32 = sidecar.eval("1 + 1").await?;
+    /// assert_eq!(value, 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn eval<T: DeserializeOwned>(
+        &self,
+        expr: impl Into<Cow<'static, str>>,
+    ) -> Result<T, Error> {
+        let result = self
+            .run(RunScriptArgs {
+                code: expr.into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await?;
+
+        let value = result
+            .response
+            .return_value
+            .unwrap_or(serde_json::Value::Null);
+        serde_json::from_value(value).map_err(Error::ResultType)
+    }
+
+    /// The catalog of named, versioned scripts that can be run with
+    /// [run_registered](Self::run_registered).
+    ///
+    /// ```no_run
+    /// # async fn example(sidecar: js_sidecar::JsSidecar) -> Result<(), js_sidecar::Error> {
+    /// sidecar
+    ///     .registry()
+    ///     .register("transform", 3, "export default input.toUpperCase();")?;
+    /// let globals = [("input".into(), "abc".into())].into_iter().collect();
+    /// let result = sidecar.run_registered("transform", 3, globals).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn registry(&self) -> &ScriptRegistry {
+        &self.registry
+    }
+
+    /// Run a version of a script from the [registry](Self::registry) with the given globals, as
+    /// in [JsSidecar::run]. To set other options on the run, get its arguments from
+    /// [ScriptRegistry::run_args] instead.
+    pub async fn run_registered(
+        &self,
+        name: &str,
+        version: u32,
+        globals: HashMap<Cow<'static, str>, serde_json::Value>,
+    ) -> Result<RunScriptAndWaitResult, Error> {
+        let args = self.registry.run_args(name, version, globals)?;
+        self.run(args).await
+    }
+
+    /// Build the contexts in `manifest` on each worker ahead of time, so that the first runs
+    /// using them don't pay the cost of creating a context and compiling its code. Each worker
+    /// keeps a context for each name ready, and hands it to the next run that creates a context
+    /// with [prewarmed_context](RunScriptArgs::prewarmed_context) set to the name, building
+    /// another in the background to replace it.
+    ///
+    /// Each context is built over a set of concurrent connections outside the pool, one per
+    /// worker, so this doesn't wait for pooled connections that are in use. Node.js distributes
+    /// new connections across its workers, so this usually reaches every worker, but two
+    /// connections may share a worker. Workers that start later, such as replacements for ones
+    /// that exited, don't have the contexts until this is called again.
+    pub async fn prewarm(&self, manifest: &PrewarmManifest) -> PrewarmReport {
+        let mut report = PrewarmReport::default();
+        for context in &manifest.contexts {
+            let start = Instant::now();
+            let results = futures::future::join_all((0..self.num_workers).map(|_| async {
+                let mut conn = self.dedicated_connection().await?;
+                conn.prewarm(context).await
+            }))
+            .await;
+
+            let mut errors = Vec::new();
+            let mut ready_workers = 0;
+            for result in results {
+                match result {
+                    Ok(_) => ready_workers += 1,
+                    Err(e) => errors.push(e),
+                }
+            }
+
+            report.contexts.push(ContextReadiness {
+                name: context.name.clone(),
+                ready_workers,
+                total_workers: self.num_workers,
+                errors,
+                elapsed: start.elapsed(),
+            });
+        }
+
+        report
+    }
+
+    /// Open connections until at least `n` are waiting in the pool, so that the next `n` requests
+    /// don't pay for connecting to a worker. This is limited by the pool's maximum size, counting
+    /// the connections that are in use. See [JsSidecarBuilder::min_idle] to keep connections
+    /// ready automatically.
+    ///
+    /// Each new connection also creates a plain context, which the first run on it reuses unless
+    /// it asks for a different kind, such as a deterministic one or one with virtual timers. Use
+    /// [JsSidecar::prewarm] to build those contexts ahead of time for runs that ask for them by
+    /// name.
+    pub async fn warm_up(&self, n: usize) -> Result<(), Error> {
+        fill_idle(&self.pool, n).await
     }
 
     /// Close Node.js
     pub async fn close(&mut self) {
+        self.scheduler.stop();
+        if let Some(task) = self.idle_eviction_task.take() {
+            task.abort();
+        }
+        if let Some(task) = self.heartbeat_task.take() {
+            task.abort();
+        }
+        if let Some(task) = self.min_idle_task.take() {
+            task.abort();
+        }
+        self.sessions.clear();
         self.pool.close();
         if let Some(child) = self.node_process.take() {
             Self::close_child(child).await;
         }
+        if let Some(cgroup) = self.cgroup.take() {
+            cgroup.close().await;
+        }
     }
 
     async fn close_child(mut child: Child) {
@@ -159,72 +1059,699 @@ impl JsSidecar {
 
 impl Drop for JsSidecar {
     fn drop(&mut self) {
+        if let Some(task) = self.idle_eviction_task.take() {
+            task.abort();
+        }
+        if let Some(task) = self.heartbeat_task.take() {
+            task.abort();
+        }
+        if let Some(task) = self.min_idle_task.take() {
+            task.abort();
+        }
         if let Some(child) = self.node_process.take() {
+            let cgroup = self.cgroup.take();
             tokio::task::spawn(async move {
                 Self::close_child(child).await;
+                if let Some(cgroup) = cgroup {
+                    cgroup.close().await;
+                }
             });
         }
     }
 }
 
-/// deadpool Manager for Sidecar connections
-pub struct ConnectionManager {
-    socket_path: PathBuf,
-    recycle_calls: AtomicUsize,
-    recycle_success: AtomicUsize,
+/// Periodically close pooled connections that haven't been used for `timeout`, keeping at least
+/// `min_idle` in the pool. Idle session connections are returned to the pool first, so they are
+/// closed on a later pass.
+async fn evict_idle_connections(
+    pool: Pool<ConnectionManager>,
+    sessions: Arc<SessionConnections>,
+    timeout: Duration,
+    min_idle: usize,
+) {
+    let mut interval = tokio::time::interval((timeout / 2).max(Duration::from_millis(100)));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if pool.is_closed() {
+            break;
+        }
+
+        sessions.evict_idle(timeout);
+        let mut excess = pool.status().available.saturating_sub(min_idle);
+        pool.retain(|conn, _| {
+            if excess > 0 && conn.is_idle(timeout) {
+                excess -= 1;
+                false
+            } else {
+                true
+            }
+        });
+    }
 }
 
-impl deadpool::managed::Manager for ConnectionManager {
-    type Type = Connection;
-    type Error = Error;
+/// Add connections to the pool until `n` of them are idle, or until the pool would be full.
+///
+/// Connections are opened one at a time, and each one creates its context before it joins the
+/// pool, so that topping up the pool doesn't hit the workers with a burst of new connections and
+/// the first run on each connection doesn't pay for the context.
+async fn fill_idle(pool: &Pool<ConnectionManager>, n: usize) -> Result<(), Error> {
+    let manager = pool.manager();
+    let _filling = manager.filling.lock().await;
+    loop {
+        let status = pool.status();
+        let in_use = status.size.saturating_sub(status.available);
+        let target = n.min(status.max_size.saturating_sub(in_use));
+        if status.available >= target || pool.is_closed() {
+            return Ok(());
+        }
 
-    async fn create(&self) -> Result<Self::Type, Self::Error> {
-        let stream = UnixStream::connect(&self.socket_path)
-            .await
-            .map_err(Error::ConnectWorker)?;
-        Connection::new(stream)
+        let mut conn = Connection::open(manager.options.clone()).await?;
+        conn.warm_context().await?;
+        add_to_pool(pool, conn).await?;
     }
+}
 
-    async fn recycle(
-        &self,
-        conn: &mut Self::Type,
-        _metrics: &Metrics,
-    ) -> deadpool::managed::RecycleResult<Error> {
-        self.recycle_calls.fetch_add(1, Ordering::Relaxed);
-        let req_id = conn.ping().await?;
-        let msg = tokio::time::timeout(Duration::from_secs(1), conn.receive_message())
-            .await
-            .map_err(|_| Error::Timeout)?
-            .ok_or(Error::ReadStream(io::Error::other("Worker is closed")))?;
+/// Give `conn` to the pool.
+///
+/// The pool only adds a connection when it has no idle ones to hand out, so the idle connections
+/// are checked out until the pool takes `conn` from [ConnectionManager::prepared] instead of
+/// connecting to a worker. If the pool fills up first, `conn` is closed.
+async fn add_to_pool(pool: &Pool<ConnectionManager>, conn: Connection) -> Result<(), Error> {
+    let manager = pool.manager();
+    manager.prepared.lock().unwrap().push(conn);
 
-        if msg.request_id != req_id || !matches!(msg.data, WorkerToHostMessageData::Pong) {
-            // if the message is anything other than a Pong, then we're out of sync somehow.
-            return Err(deadpool::managed::RecycleError::Backend(
-                Error::ConnectionOutOfSync,
-            ));
+    let no_wait = Timeouts {
+        wait: Some(Duration::ZERO),
+        ..pool.timeouts()
+    };
+    let mut held = Vec::new();
+    let result = loop {
+        if manager.prepared.lock().unwrap().is_empty() {
+            break Ok(());
         }
+        match pool.timeout_get(&no_wait).await {
+            Ok(conn) => held.push(conn),
+            Err(PoolError::Timeout(_)) => break Ok(()),
+            Err(e) => break Err(Error::Pool(Box::new(e))),
+        }
+    };
+    // Close it if the pool didn't take it, rather than keeping it around to go stale.
+    manager.prepared.lock().unwrap().clear();
+    drop(held);
+    result
+}
 
-        conn.recreate_context_on_next = true;
+/// Periodically replace the idle connections that were checked out or closed, so that
+/// [JsSidecarBuilder::min_idle] connections are ready for a burst of requests.
+async fn maintain_min_idle(pool: Pool<ConnectionManager>, min_idle: usize) {
+    let mut interval = tokio::time::interval(MIN_IDLE_CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if pool.is_closed() {
+            break;
+        }
 
-        self.recycle_success.fetch_add(1, Ordering::Relaxed);
-        Ok(())
+        if let Err(e) = fill_idle(&pool, min_idle).await {
+            tracing::warn!(error = %e, "Failed to create idle connections");
+        }
     }
 }
 
-/// A connection obtained from the connectiion pool inside the [JsSidecar].
-pub type PoolConnection = deadpool::managed::Object<ConnectionManager>;
+/// Periodically ping the workers of the connections waiting in the pool, closing the connections
+/// whose worker has died or stopped responding before a caller checks them out. Connections are
+/// only taken from the pool when it has idle ones, so this never creates new connections, and
+/// the pings don't count as activity for the [idle timeout](JsSidecarBuilder::idle_timeout).
+///
+/// The connections are pinged one at a time, and a pass stops early once a caller is waiting for
+/// a connection, so that the heartbeat never leaves callers short of idle connections.
+async fn heartbeat_idle_connections(pool: Pool<ConnectionManager>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval.max(Duration::from_millis(100)));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let timeouts = Timeouts {
+        wait: Some(Duration::ZERO),
+        create: Some(Duration::ZERO),
+        ..pool.timeouts()
+    };
+    loop {
+        ticker.tick().await;
+        if pool.is_closed() {
+            break;
+        }
 
-/// A connection to Node.js. Multiple calls on a connection will reuse the execution context,
-/// unless explicitly specified otherwise using the [recreate_context] argument.
-pub struct Connection {
-    stream: OwnedWriteHalf,
-    /// The receiver for messages from the Node.js process.
-    pub receiver: mpsc::Receiver<WorkerToHostMessage>,
-    next_id: u32,
+        // A closed socket doesn't need a ping to show that the worker is gone. Note when the rest
+        // were last used, since checking them out may verify them.
+        let mut last_used = HashMap::new();
+        pool.retain(|conn, _| {
+            let closed = conn.activity.closed.load(Ordering::Relaxed);
+            if !closed {
+                last_used.insert(
+                    Arc::as_ptr(&conn.activity) as usize,
+                    conn.activity.snapshot(),
+                );
+            }
+            !closed
+        });
+
+        // A connection that goes back to the pool is the next one handed out when the pool is
+        // LIFO, so the checked ones are held until the pass is done to reach the others.
+        let mut checked = Vec::new();
+        for _ in 0..last_used.len() {
+            let status = pool.status();
+            if status.available == 0 || status.waiting > 0 {
+                break;
+            }
+            let Ok(mut conn) = pool.timeout_get(&timeouts).await else {
+                break;
+            };
+            let Some(used) = last_used.remove(&(Arc::as_ptr(&conn.activity) as usize)) else {
+                checked.push(conn);
+                continue;
+            };
+
+            // Recycling the connection already pinged it if it hadn't been verified lately.
+            if conn.activity.snapshot().1 == used.1 {
+                let timeout = conn.options.recycle_timeout;
+                if let Err(e) = conn.check_worker(timeout).await {
+                    tracing::warn!(error = %e, "Closing connection that failed a heartbeat");
+                    drop(PoolConnection::take(conn));
+                    continue;
+                }
+            }
+            conn.activity.restore(used);
+            checked.push(conn);
+        }
+    }
+}
+
+/// deadpool Manager for Sidecar connections
+pub struct ConnectionManager {
+    recycle_calls: AtomicUsize,
+    recycle_success: AtomicUsize,
+    /// Held while topping up the idle connections, so that [JsSidecar::warm_up] and the
+    /// [min_idle](JsSidecarBuilder::min_idle) task don't both open connections for the same gap.
+    filling: tokio::sync::Mutex<()>,
+    /// Connections that were opened ahead of time for the pool to take when it adds a connection.
+    prepared: Mutex<Vec<Connection>>,
+    options: Arc<ConnectionOptions>,
+}
+
+/// Settings from the [JsSidecarBuilder] that apply to every connection.
+#[derive(Debug)]
+pub(crate) struct ConnectionOptions {
+    /// Where new connections are opened
+    pub address: WorkerAddress,
+    pub corpus: Option<Arc<CorpusCollector>>,
+    pub audit: Option<Arc<AuditLog>>,
+    pub approval: ScriptApproval,
+    pub max_string_bytes: Option<usize>,
+    pub max_log_messages: Option<u32>,
+    pub max_log_bytes: Option<usize>,
+    pub min_log_level: Option<LogLevel>,
+    pub replace_invalid_unicode: bool,
+    pub channel_size: usize,
+    pub channel_overflow: ChannelOverflow,
+    pub latencies: Arc<WorkerLatencies>,
+    pub max_requests: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub memory: Arc<WorkerMemory>,
+    pub loads: Arc<WorkerLoads>,
+    pub events: broadcast::Sender<SidecarEvent>,
+    /// The URL of the inspector that a worker most recently asked a debugger to connect to.
+    pub inspector_url: Arc<Mutex<Option<String>>>,
+    pub recycle_timeout: Duration,
+    pub verify_interval: Duration,
+    /// How long past a run's timeout to wait before killing an unresponsive worker. This is
+    /// `None` for remote workers, which can't be killed.
+    pub kill_after_timeout: Option<Duration>,
+    pub frame_checksums: bool,
+    pub strict_messages: bool,
+    pub compress_frames_over: Option<usize>,
+    pub shared_memory: Option<Arc<SharedMemory>>,
+    pub frame_limits: FrameLimits,
+    pub kv: Option<Arc<dyn KvBackend>>,
+    pub module_resolver: Option<Arc<dyn ModuleResolver>>,
+    pub url_imports: Vec<String>,
+    pub url_import_ttl: Duration,
+    /// The cgroup that the Node.js process was placed in, when it has one
+    pub cgroup: Option<Arc<Cgroup>>,
+    pub registry: Arc<ScriptRegistry>,
+    /// The pool that the connections belong to, set once it has been built. [JobHandle]s check
+    /// connections out of it.
+    pub pool: OnceLock<WeakPool<ConnectionManager>>,
+}
+
+impl ConnectionOptions {
+    fn frame_options(&self, recorder: &Arc<Recorder>) -> FrameOptions {
+        FrameOptions {
+            checksum: self.frame_checksums,
+            compress_over: self.compress_frames_over,
+            shared_memory: self.shared_memory.clone(),
+            recorder: Some(recorder.clone()),
+        }
+    }
+
+    fn debugger_waiting(&self, waiting: &DebuggerWaitingData) {
+        tracing::info!(pid = waiting.pid, url = %waiting.url, "Waiting for debugger");
+        *self.inspector_url.lock().unwrap() = Some(waiting.url.clone());
+        self.events
+            .send(SidecarEvent::DebuggerWaiting {
+                pid: waiting.pid,
+                url: waiting.url.clone(),
+            })
+            .ok();
+    }
+}
+
+impl deadpool::managed::Manager for ConnectionManager {
+    type Type = Connection;
+    type Error = Error;
+
+    async fn create(&self) -> Result<Self::Type, Self::Error> {
+        if let Some(conn) = self.prepared.lock().unwrap().pop() {
+            return Ok(conn);
+        }
+        Connection::open(self.options.clone()).await
+    }
+
+    async fn recycle(
+        &self,
+        conn: &mut Self::Type,
+        _metrics: &Metrics,
+    ) -> deadpool::managed::RecycleResult<Error> {
+        self.recycle_calls.fetch_add(1, Ordering::Relaxed);
+        if let Some(reason) = conn.retirement_reason() {
+            return Err(deadpool::managed::RecycleError::message(reason));
+        }
+        conn.verify().await?;
+
+        // A connection that was added with a fresh context keeps it until its first run, even if
+        // it is checked out and returned before then.
+        if !conn.warm_context {
+            conn.recreate_context_on_next = true;
+        }
+        conn.pending_runs.clear();
+        conn.stale_messages.clear();
+        conn.abandon_audits();
+
+        self.recycle_success.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// A connection obtained from the connectiion pool inside the [JsSidecar].
+pub type PoolConnection = deadpool::managed::Object<ConnectionManager>;
+
+/// A connection to Node.js. Multiple calls on a connection will reuse the execution context,
+/// unless explicitly specified otherwise using the
+/// [recreate_context](RunScriptArgs::recreate_context) argument.
+pub struct Connection {
+    /// Shared with the read task, which answers the worker's KV requests.
+    stream: Arc<tokio::sync::Mutex<WriteHalf>>,
+    /// The receiver for messages from the Node.js process.
+    pub receiver: mpsc::Receiver<WorkerToHostMessage>,
+    next_id: u32,
     next_req_id: u32,
     _task_close_tx: tokio::sync::oneshot::Sender<()>,
 
     recreate_context_on_next: bool,
+    /// Set when the connection created a plain context before joining the pool, until the first
+    /// run uses it.
+    warm_context: bool,
+    options: Arc<ConnectionOptions>,
+    dropped_messages: Arc<AtomicU64>,
+    activity: Arc<Activity>,
+    worker_pid: Option<u32>,
+    /// When each run that hasn't finished yet was sent
+    pending_runs: HashMap<u32, Instant>,
+    /// The audit details of each unfinished run, when an [AuditLog] is set.
+    pending_audits: HashMap<u32, PendingAudit>,
+    latency: Ewma,
+    run_count: u64,
+    /// Set when something went wrong on the connection, so that it is checked before it is
+    /// reused.
+    dirty: bool,
+    /// The run that [run_script_and_wait](Self::run_script_and_wait) last waited on. If it is
+    /// still pending the next time the connection is used, the future was dropped or panicked
+    /// before the run finished.
+    awaited_run: Option<u32>,
+    /// Runs that were given up on while they were still going. Their messages are discarded as
+    /// they arrive, until the run ends.
+    abandoned_runs: HashSet<u32>,
+    /// Messages for requests that had already ended, which arrived while waiting for a run.
+    stale_messages: VecDeque<WorkerToHostMessage>,
+    /// The hash of each module in the worker's context, by name, so that a module sent again
+    /// with the same code can refer to the one that the context already has.
+    context_modules: HashMap<String, String>,
+    /// The hashes of the registered scripts that the worker has kept for this connection.
+    registered_scripts: HashSet<String>,
+    /// The hash of the registered script whose code was sent with each unfinished run. The
+    /// worker only has the code once the run succeeds.
+    pending_registered: HashMap<u32, String>,
+    /// Shared with the read task, which records the messages from the worker.
+    recorder: Arc<Recorder>,
+}
+
+/// A stream of the messages from the Node.js process, from [Connection::messages].
+pub struct Messages<'a> {
+    connection: &'a mut Connection,
+}
+
+impl Stream for Messages<'_> {
+    type Item = WorkerToHostMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let connection = &mut *self.connection;
+        loop {
+            let message = ready!(connection.receiver.poll_recv(cx));
+            if let Some(message) = &message {
+                connection.observe(message);
+                if connection.is_abandoned(message) {
+                    continue;
+                }
+            }
+            return Poll::Ready(message);
+        }
+    }
+}
+
+/// A channel for exchanging JSON values with a running script, from [Connection::open_channel].
+///
+/// As a [Stream], it yields the values that the script passes to `channel.send`, and ends when
+/// the run finishes. Console messages from the run are collected along the way, and messages for
+/// other requests on the connection are set aside, as in
+/// [run_script_and_wait](Connection::run_script_and_wait). Call [finish](Self::finish) to get the
+/// run's result.
+///
+/// As a [Sink], it sends values to the script, which reads them with `await channel.receive()`
+/// or `for await (const value of channel)`. Closing the sink ends the script's loop once it has
+/// read the values that were already sent.
+pub struct Channel<'a> {
+    connection: &'a mut Connection,
+    request_id: u32,
+    /// The write in progress for the sink
+    sending: Option<BoxFuture<'static, Result<u64, Error>>>,
+    closed: bool,
+    logs: Vec<LogResponseData>,
+    other: Vec<WorkerToHostMessageData>,
+    cpu_profile: Option<CpuProfileData>,
+    /// The sizes of the messages that the channel has sent and received. The run's own script
+    /// message was sent before the channel was opened, so it isn't counted.
+    wire: WireSizes,
+    /// How the run ended, once the stream has seen it
+    result: Option<Result<RunResponseData, Error>>,
+}
+
+impl Channel<'_> {
+    /// The ID of the run that the channel talks to
+    pub fn request_id(&self) -> u32 {
+        self.request_id
+    }
+
+    /// Wait for the run to finish, and return its result. Values from the script that haven't been
+    /// read from the stream yet are dropped.
+    pub async fn finish(mut self) -> Result<RunScriptAndWaitResult, Error> {
+        use futures::StreamExt;
+
+        while self.next().await.is_some() {}
+        match self.result.take() {
+            Some(Ok(response)) => Ok(RunScriptAndWaitResult {
+                response,
+                logs: self.logs,
+                other: self.other,
+                cpu_profile: self.cpu_profile,
+                wire: self.wire,
+            }),
+            Some(Err(e)) => Err(e),
+            None => Err(Error::ScriptEndedEarly),
+        }
+    }
+
+    /// Finish the write in progress, if any.
+    fn poll_sending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let Some(sending) = self.sending.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+
+        let result = ready!(sending.as_mut().poll(cx));
+        self.sending = None;
+        match result {
+            Ok(length) => {
+                self.wire.request_bytes += length;
+                Poll::Ready(Ok(()))
+            }
+            Err(e) => {
+                self.connection.dirty = true;
+                Poll::Ready(Err(e))
+            }
+        }
+    }
+
+    fn start_write(&mut self, data: HostToWorkerMessageData) {
+        let message_id = self.connection.next_id;
+        self.connection.next_id += 1;
+        let message = HostToWorkerMessage::new(self.request_id, message_id, data);
+        self.sending = Some(Box::pin(self.connection.write_message(message)));
+    }
+}
+
+impl Stream for Channel<'_> {
+    type Item = serde_json::Value;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if this.result.is_some() {
+                return Poll::Ready(None);
+            }
+
+            let Some(message) = ready!(Pin::new(&mut this.connection.messages()).poll_next(cx))
+            else {
+                this.result = Some(Err(Error::ScriptEndedEarly));
+                continue;
+            };
+
+            match message.data {
+                WorkerToHostMessageData::Corrupted(corruption) => {
+                    this.result = Some(Err(Error::ProtocolCorruption(corruption)));
+                    continue;
+                }
+                WorkerToHostMessageData::OutOfSequence(sequence) => {
+                    this.result = Some(Err(Error::ProtocolSequence(sequence)));
+                    continue;
+                }
+                _ => {}
+            }
+
+            if message.request_id != this.request_id || !message.data.is_run_message() {
+                if let Err(e) = this.connection.set_aside(message, &mut this.other) {
+                    this.result = Some(Err(e));
+                }
+                continue;
+            }
+
+            this.wire.response_bytes += message.wire_bytes;
+            match message.data {
+                WorkerToHostMessageData::ChannelMessage(value) => return Poll::Ready(Some(value)),
+                WorkerToHostMessageData::RunResponse(response) => {
+                    this.result = Some(Ok(response));
+                }
+                WorkerToHostMessageData::Error(error) => {
+                    this.result = Some(Err(Error::Script(Box::new(RunScriptError {
+                        error,
+                        logs: std::mem::take(&mut this.logs),
+                        other: std::mem::take(&mut this.other),
+                    }))));
+                }
+                WorkerToHostMessageData::MessageTooLarge(too_large) => {
+                    this.result = Some(Err(Error::MessageTooLarge {
+                        length: too_large.length,
+                        limit: too_large.limit,
+                    }));
+                }
+                WorkerToHostMessageData::Log(log) => this.logs.push(log),
+                WorkerToHostMessageData::CpuProfile(profile) => this.cpu_profile = Some(profile),
+                data => this.other.push(data),
+            }
+        }
+    }
+}
+
+impl Sink<serde_json::Value> for Channel<'_> {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.poll_sending(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: serde_json::Value) -> Result<(), Error> {
+        self.start_write(HostToWorkerMessageData::ChannelMessage(item));
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.poll_sending(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        ready!(self.poll_sending(cx))?;
+        if !self.closed {
+            self.closed = true;
+            self.start_write(HostToWorkerMessageData::ChannelClose);
+        }
+        self.poll_sending(cx)
+    }
+}
+
+/// An interactive session on a connection's context, from [Connection::repl], for embedding a
+/// developer console in an application.
+///
+/// Each entry runs in the same context as the connection's other runs, so globals set up by an
+/// earlier run are available, and `let` and `const` declarations carry over from one entry to
+/// the next.
+///
+/// ```no_run
+/// # use js_sidecar::Connection;
+/// # async fn example(conn: &mut Connection) -> Result<(), js_sidecar::Error> {
+/// let mut repl = conn.repl();
+/// repl.eval("let total = 0").await?;
+/// let output = repl.eval("total += 5").await?;
+/// assert_eq!(output.value, "5");
+/// let completions = repl.complete("tot").await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Repl<'a> {
+    connection: &'a mut Connection,
+    /// How many entries have been evaluated, to name each one in stack traces
+    entries: u32,
+    timeout_ms: Option<u64>,
+}
+
+/// The result of an entry in a [Repl]
+#[derive(Debug, Clone)]
+pub struct ReplOutput {
+    /// The entry's value, shown the way that Node's REPL shows it, such as `'text'`,
+    /// `{ a: 1 }`, or `[Function: f]`. Promises are awaited first.
+    pub value: String,
+    /// Console messages logged by the entry
+    pub logs: Vec<LogResponseData>,
+}
+
+impl Repl<'_> {
+    /// Stop each entry that runs for longer than `ms` milliseconds, as with
+    /// [RunScriptArgs::timeout_ms]. An entry that times out throws away the context, along with
+    /// everything that earlier entries declared.
+    pub fn timeout(mut self, ms: u64) -> Self {
+        self.timeout_ms = Some(ms);
+        self
+    }
+
+    /// Evaluate an entry. An entry that throws returns [Error::Script], and the session carries
+    /// on with whatever the entry declared before it threw.
+    pub async fn eval(&mut self, code: impl Into<String>) -> Result<ReplOutput, Error> {
+        self.entries += 1;
+        let args = RunScriptArgs {
+            name: format!("<repl:{}>", self.entries).into(),
+            code: code.into().into(),
+            repl: true,
+            timeout_ms: self.timeout_ms,
+            ..Default::default()
+        };
+        let result = self.connection.run_script_and_wait(args).await?;
+        let value = match result.response.return_value {
+            Some(serde_json::Value::String(value)) => value,
+            _ => String::new(),
+        };
+        Ok(ReplOutput {
+            value,
+            logs: result.logs,
+        })
+    }
+
+    /// Complete the name at the end of `line`, the code up to the cursor. This offers the
+    /// context's globals and the names that earlier entries declared, or, after a dot, the
+    /// properties of the value that the path before the dot refers to, as in `user.profile.na`.
+    ///
+    /// Only simple paths of names are followed, reading each property without running getters or
+    /// Proxy traps, so completing never runs any code in the context. A path through a getter or a
+    /// Proxy has no completions.
+    pub async fn complete(&mut self, line: impl Into<String>) -> Result<Completions, Error> {
+        let data = CompleteData { line: line.into() };
+        let (completions, _) = self
+            .connection
+            .call_worker(HostToWorkerMessageData::Complete(data))
+            .await?;
+        Ok(completions)
+    }
+}
+
+/// How a run started by [Connection::start_run] is waited on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunMode {
+    /// The caller reads the run's messages itself.
+    Started,
+    /// [Connection::run_script_and_wait] waits for the run.
+    Awaited,
+    /// [Connection::spawn_script] waits for the worker to start the run as a background job.
+    Detached,
+    /// [Connection::warm_context] creates the context before the connection joins the pool. The
+    /// run isn't approved, recorded or audited, and doesn't count towards
+    /// [JsSidecarBuilder::max_requests_per_connection].
+    Warmup,
+}
+
+/// Tracks when a connection last sent or received a message.
+#[derive(Debug)]
+struct Activity {
+    created: Instant,
+    /// Microseconds since `created`
+    last_send: AtomicU64,
+    /// Microseconds since `created`
+    last_receive: AtomicU64,
+    /// Set when the socket is closed or fails.
+    closed: AtomicBool,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            created: Instant::now(),
+            last_send: AtomicU64::new(0),
+            last_receive: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn touch(&self, field: &AtomicU64) {
+        let elapsed = self.created.elapsed().as_micros() as u64;
+        field.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    fn instant(&self, field: &AtomicU64) -> Instant {
+        self.created + Duration::from_micros(field.load(Ordering::Relaxed))
+    }
+
+    /// The last send and receive times, for [restore](Self::restore).
+    fn snapshot(&self) -> (u64, u64) {
+        (
+            self.last_send.load(Ordering::Relaxed),
+            self.last_receive.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Forget messages sent and received since a [snapshot](Self::snapshot) was taken.
+    fn restore(&self, (last_send, last_receive): (u64, u64)) {
+        self.last_send.store(last_send, Ordering::Relaxed);
+        self.last_receive.store(last_receive, Ordering::Relaxed);
+    }
+}
+
+impl std::fmt::Debug for ConnectionManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionManager").finish_non_exhaustive()
+    }
 }
 
 impl std::fmt::Debug for Connection {
@@ -234,20 +1761,132 @@ impl std::fmt::Debug for Connection {
 }
 
 impl Connection {
-    fn new(stream: UnixStream) -> Result<Self, Error> {
-        let (sender, receiver) = mpsc::channel(16);
-        let (mut read_stream, write_stream) = stream.into_split();
+    fn new(
+        read_stream: ReadHalf,
+        write_stream: WriteHalf,
+        options: Arc<ConnectionOptions>,
+    ) -> Result<Self, Error> {
+        let (sender, receiver) = mpsc::channel(options.channel_size);
+        let dropped_messages = Arc::new(AtomicU64::new(0));
+        let mut forwarder =
+            MessageForwarder::new(sender, options.channel_overflow, dropped_messages.clone());
+        let activity = Arc::new(Activity::new());
+        let task_activity = activity.clone();
+        let task_options = options.clone();
+        let recorder = Arc::new(Recorder::default());
+        let task_recorder = recorder.clone();
 
         let (close_tx, close_rx) = tokio::sync::oneshot::channel::<()>();
 
+        let write_stream = Arc::new(tokio::sync::Mutex::new(write_stream));
+        let task_stream = write_stream.clone();
+        let reader = FrameReader::new(
+            read_stream,
+            options.frame_limits,
+            options.shared_memory.clone(),
+        );
         tokio::task::spawn(async move {
             tokio::pin!(close_rx);
+            // Reading a frame isn't cancel safe, so the same read carries on when another branch
+            // wins, rather than a new one starting partway through a frame.
+            let read_next = |mut reader: FrameReader<ReadHalf>| async move {
+                let message = reader.read().await;
+                (reader, message)
+            };
+            let mut read = Box::pin(read_next(reader));
             loop {
                 tokio::select! {
-                    message = WorkerToHostMessage::read_from(&mut read_stream) => {
+                    (reader, message) = &mut read => {
+                        read.set(read_next(reader));
+                        if let Ok(message) = &message {
+                            task_recorder.received(message);
+                        }
                         match message {
+                            Ok(WorkerToHostMessage {
+                                data: WorkerToHostMessageData::MemoryUsage(usage),
+                                ..
+                            }) => {
+                                // Memory reports aren't part of any request, so they are handled
+                                // here instead of going to the receiver.
+                                task_options.memory.record(usage);
+                            }
+                            Ok(WorkerToHostMessage {
+                                data: WorkerToHostMessageData::WorkerLoad(load),
+                                ..
+                            }) => {
+                                task_options.loads.record(load);
+                            }
+                            Ok(WorkerToHostMessage {
+                                request_id,
+                                data: WorkerToHostMessageData::KvRequest(request),
+                                ..
+                            }) if task_options.kv.is_some() && !task_recorder.is_replaying() => {
+                                // Answer from a separate task so that a slow backend doesn't hold
+                                // up the other messages.
+                                tokio::spawn(kv::serve(
+                                    task_options.kv.clone().unwrap(),
+                                    request_id,
+                                    request,
+                                    task_stream.clone(),
+                                    task_options.frame_options(&task_recorder),
+                                ));
+                            }
+                            Ok(WorkerToHostMessage {
+                                request_id,
+                                data: WorkerToHostMessageData::ResolveModule(request),
+                                ..
+                            }) if task_options.module_resolver.is_some()
+                                && !task_recorder.is_replaying() =>
+                            {
+                                tokio::spawn(resolver::serve(
+                                    task_options.module_resolver.clone().unwrap(),
+                                    request_id,
+                                    request,
+                                    task_stream.clone(),
+                                    task_options.frame_options(&task_recorder),
+                                ));
+                            }
                             Ok(message) => {
-                                if sender.send(message).await.is_err() {
+                                if let WorkerToHostMessageData::DebuggerWaiting(waiting) = &message.data {
+                                    task_options.debugger_waiting(waiting);
+                                }
+                                task_activity.touch(&task_activity.last_receive);
+                                if !forwarder.forward(message).await {
+                                    break;
+                                }
+                            }
+                            Err(Error::ProtocolCorruption(corruption)) => {
+                                // The reader has already found the next frame, so pass the error
+                                // on to whoever is waiting and keep going.
+                                tracing::warn!(
+                                    reason = %corruption.reason,
+                                    skipped_bytes = corruption.skipped_bytes,
+                                    "Corrupted message from worker"
+                                );
+                                let message = WorkerToHostMessage {
+                                    request_id: corruption.request_id.unwrap_or_default(),
+                                    message_id: 0,
+                                    data: WorkerToHostMessageData::Corrupted(corruption),
+                                    wire_bytes: 0,
+                                };
+                                if !forwarder.forward(message).await {
+                                    break;
+                                }
+                            }
+                            Err(Error::ProtocolSequence(sequence)) => {
+                                tracing::warn!(
+                                    request_id = sequence.request_id,
+                                    expected = sequence.expected,
+                                    received = sequence.received,
+                                    "Message from worker out of sequence"
+                                );
+                                let message = WorkerToHostMessage {
+                                    request_id: sequence.request_id,
+                                    message_id: sequence.received,
+                                    data: WorkerToHostMessageData::OutOfSequence(sequence),
+                                    wire_bytes: 0,
+                                };
+                                if !forwarder.forward(message).await {
                                     break;
                                 }
                             }
@@ -258,271 +1897,6469 @@ impl Connection {
                         }
                     }
 
-                    _ = &mut close_rx  => {
-                        break;
-                    }
+                    delivered = forwarder.deliver_pending(), if forwarder.has_pending() => {
+                        if !delivered {
+                            break;
+                        }
+                    }
+
+                    _ = &mut close_rx  => {
+                        break;
+                    }
+
+                }
+            }
+
+            task_activity.closed.store(true, Ordering::Relaxed);
+        });
+
+        Ok(Connection {
+            stream: write_stream,
+            receiver,
+            next_id: 0,
+            next_req_id: 0,
+            recreate_context_on_next: false,
+            warm_context: false,
+            options,
+            dropped_messages,
+            activity,
+            worker_pid: None,
+            pending_runs: HashMap::new(),
+            pending_audits: HashMap::new(),
+            latency: Ewma::default(),
+            run_count: 0,
+            dirty: false,
+            awaited_run: None,
+            abandoned_runs: HashSet::new(),
+            stale_messages: VecDeque::new(),
+            context_modules: HashMap::new(),
+            registered_scripts: HashSet::new(),
+            pending_registered: HashMap::new(),
+            recorder,
+            _task_close_tx: close_tx,
+        })
+    }
+
+    /// Open a new connection to a worker and check that it speaks the same protocol.
+    ///
+    /// A WebSocket or a custom transport can reach a peer that accepts the connection and never
+    /// answers, so opening one of those gives up after the
+    /// [recycle timeout](JsSidecarBuilder::pool_recycle_timeout).
+    pub(crate) async fn open(options: Arc<ConnectionOptions>) -> Result<Connection, Error> {
+        let timeout = match options.address {
+            WorkerAddress::Socket(_) => None,
+            WorkerAddress::WebSocket { .. } | WorkerAddress::Custom(_) => {
+                Some(options.recycle_timeout)
+            }
+        };
+
+        let open = async {
+            let (read_stream, write_stream) = options.address.connect().await?;
+            let mut conn = Connection::new(read_stream, write_stream, options.clone())?;
+            conn.handshake(PROTOCOL_VERSION).await?;
+            Ok(conn)
+        };
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, open)
+                .await
+                .map_err(|_| Error::Timeout)?,
+            None => open.await,
+        }
+    }
+
+    /// Start running a script, returning the ID of the request. Every message from the worker
+    /// about this run, including its logs and its response or error, carries the same ID.
+    pub async fn run_script(&mut self, args: RunScriptArgs) -> Result<u32, Error> {
+        let (req_id, _) = self.start_run(args, RunMode::Started).await?;
+        Ok(req_id)
+    }
+
+    /// Start a run, marking it as the one that `run_script_and_wait` waits on unless `mode` is
+    /// [RunMode::Started].
+    ///
+    /// Returns the request ID and the number of bytes sent.
+    async fn start_run(
+        &mut self,
+        mut args: RunScriptArgs,
+        mode: RunMode,
+    ) -> Result<(u32, u64), Error> {
+        let detach = mode == RunMode::Detached;
+        // A background job has its own context, so it doesn't use the connection's.
+        if self.recreate_context_on_next && !detach {
+            self.recreate_context_on_next = false;
+            args.recreate_context = true;
+        }
+        // The warmed context is a plain one, and these settings only apply when a context is
+        // created.
+        if !detach
+            && std::mem::take(&mut self.warm_context)
+            && (args.deterministic
+                || args.timers != TimerMode::Real
+                || args.prewarmed_context.is_some())
+        {
+            args.recreate_context = true;
+        }
+
+        if args.max_string_bytes.is_none() {
+            args.max_string_bytes = self.options.max_string_bytes;
+        }
+        if args.max_log_messages.is_none() {
+            args.max_log_messages = self.options.max_log_messages;
+        }
+        if args.max_log_bytes.is_none() {
+            args.max_log_bytes = self.options.max_log_bytes;
+        }
+        if args.min_log_level.is_none() {
+            args.min_log_level = self.options.min_log_level;
+        }
+        args.replace_invalid_unicode |= self.options.replace_invalid_unicode;
+
+        if let Some(limit) = args.max_string_bytes {
+            for (key, value) in &args.globals {
+                check_string_lengths(value, &key_path("globals", key), limit)?;
+            }
+        }
+
+        let warmup = mode == RunMode::Warmup;
+        if !warmup {
+            self.options.approval.check(&args)?;
+        }
+
+        if let Some(corpus) = self.options.corpus.as_ref().filter(|_| !warmup) {
+            corpus.observe(&args);
+        }
+
+        let audit = self
+            .options
+            .audit
+            .as_ref()
+            .filter(|_| !warmup)
+            .map(|log| log.start(&args));
+        let message_id = self.next_id;
+        let req_id = self.next_req_id;
+        self.next_req_id += 1;
+        self.next_id += 1;
+        let (cached_modules, registered_script) = if detach {
+            (Vec::new(), None)
+        } else {
+            (
+                self.take_cached_modules(&mut args),
+                self.take_registered_script(req_id, &mut args),
+            )
+        };
+        let message = HostToWorkerMessage::new(
+            req_id,
+            message_id,
+            HostToWorkerMessageData::RunScript(Box::new(RunScriptMessage {
+                args,
+                cached_modules,
+                registered_script,
+                detach,
+            })),
+        );
+        // Recorded before sending, so that a run whose caller goes away mid-send is still tracked.
+        self.pending_runs.insert(req_id, Instant::now());
+        if mode != RunMode::Started {
+            self.awaited_run = Some(req_id);
+        }
+        let sent = match self.send(message).await {
+            Ok(sent) => sent,
+            Err(e) => {
+                self.pending_runs.remove(&req_id);
+                self.pending_registered.remove(&req_id);
+                return Err(e);
+            }
+        };
+        if let Some(audit) = audit {
+            self.pending_audits.insert(req_id, audit);
+        }
+        if !warmup {
+            self.run_count += 1;
+        }
+        Ok((req_id, sent))
+    }
+
+    /// Remove the modules that the worker's context already has with the same code from `args`,
+    /// and return references to them instead.
+    fn take_cached_modules(&mut self, args: &mut RunScriptArgs) -> Vec<CachedModule> {
+        if args.recreate_context {
+            self.context_modules.clear();
+        }
+
+        let mut cached = Vec::new();
+        args.modules.retain(|module| {
+            let hash = hex_string(&Sha256::digest(module.code.as_bytes()));
+            let name = module.name.to_string();
+            if self.context_modules.get(&name) == Some(&hash) {
+                cached.push(CachedModule { name, hash });
+                false
+            } else {
+                self.context_modules.insert(name, hash);
+                true
+            }
+        });
+        cached
+    }
+
+    /// If the code is a registered script, refer to it so that the worker keeps it, and leave the
+    /// code out if the worker already has it. The script only counts as sent once run `req_id`
+    /// succeeds, since the run can fail or be cancelled before the worker stores the code.
+    fn take_registered_script(
+        &mut self,
+        req_id: u32,
+        args: &mut RunScriptArgs,
+    ) -> Option<RegisteredScriptRef> {
+        if args.code.is_empty() || args.expr || args.call || args.repl {
+            return None;
+        }
+
+        let script = self.options.registry.find(&args.code)?;
+        if self.registered_scripts.contains(&script.hash) {
+            args.code = Cow::Borrowed("");
+        } else {
+            self.pending_registered.insert(req_id, script.hash.clone());
+        }
+        Some(RegisteredScriptRef {
+            name: format!("{}@{}", script.name, script.version),
+            hash: script.hash,
+        })
+    }
+
+    /// When a message was last sent to the worker, or when the connection was created if no
+    /// message has been sent yet.
+    pub fn last_send(&self) -> Instant {
+        self.activity.instant(&self.activity.last_send)
+    }
+
+    /// When a message was last received from the worker, or when the connection was created if no
+    /// message has been received yet.
+    pub fn last_receive(&self) -> Instant {
+        self.activity.instant(&self.activity.last_receive)
+    }
+
+    /// The later of [last_send](Self::last_send) and [last_receive](Self::last_receive).
+    pub fn last_activity(&self) -> Instant {
+        self.last_send().max(self.last_receive())
+    }
+
+    /// The number of scripts that have been run on this connection.
+    pub fn run_count(&self) -> u64 {
+        self.run_count
+    }
+
+    /// How long ago the connection was created.
+    pub fn age(&self) -> Duration {
+        self.activity.created.elapsed()
+    }
+
+    /// Why the connection should be closed instead of being reused, if it has reached the limits
+    /// set by [JsSidecarBuilder::max_requests_per_connection] or
+    /// [JsSidecarBuilder::max_connection_age].
+    fn retirement_reason(&self) -> Option<&'static str> {
+        if self
+            .options
+            .max_requests
+            .is_some_and(|max| self.run_count >= max)
+        {
+            Some("Connection reached the maximum number of requests")
+        } else if self.options.max_age.is_some_and(|max| self.age() >= max) {
+            Some("Connection reached the maximum age")
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the connection hasn't sent or received any messages for at least `duration`.
+    pub fn is_idle(&self, duration: Duration) -> bool {
+        self.last_activity().elapsed() >= duration
+    }
+
+    /// The number of console messages dropped because the receiver wasn't keeping up.
+    /// See [ChannelOverflow].
+    pub fn dropped_messages(&self) -> u64 {
+        self.dropped_messages.load(Ordering::Relaxed)
+    }
+
+    /// Receive a message from the Node.js process
+    pub async fn receive_message(&mut self) -> Option<WorkerToHostMessage> {
+        self.abandon_awaited_run();
+        self.next_message().await
+    }
+
+    /// Receive the next message that isn't for an abandoned run.
+    async fn next_message(&mut self) -> Option<WorkerToHostMessage> {
+        loop {
+            let message = self.receiver.recv().await?;
+            self.observe(&message);
+            if !self.is_abandoned(&message) {
+                return Some(message);
+            }
+        }
+    }
+
+    /// Receive a message, turning a corrupted frame or a gap in a request's messages into an
+    /// error since it may have held the message being waited for.
+    async fn receive_intact(&mut self) -> Result<Option<WorkerToHostMessage>, Error> {
+        match self.next_message().await {
+            Some(WorkerToHostMessage {
+                data: WorkerToHostMessageData::Corrupted(corruption),
+                ..
+            }) => Err(Error::ProtocolCorruption(corruption)),
+            Some(WorkerToHostMessage {
+                data: WorkerToHostMessageData::OutOfSequence(sequence),
+                ..
+            }) => Err(Error::ProtocolSequence(sequence)),
+            message => Ok(message),
+        }
+    }
+
+    /// The messages from the Node.js process as a [Stream], for use with
+    /// [StreamExt](futures::StreamExt) combinators, `select!`, and timeouts. The stream ends when
+    /// the connection closes.
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use futures::{future, StreamExt};
+    /// # use js_sidecar::{Connection, RunScriptArgs};
+    /// # async fn example(conn: &mut Connection) -> Result<(), js_sidecar::Error> {
+    /// let req_id = conn
+    ///     .run_script(RunScriptArgs {
+    ///         code: "console.log('hi')".into(),
+    ///         ..Default::default()
+    ///     })
+    ///     .await?;
+    ///
+    /// // Wait up to a second for the first message about the run.
+    /// let first = tokio::time::timeout(
+    ///     Duration::from_secs(1),
+    ///     conn.messages()
+    ///         .filter(|message| future::ready(message.request_id == req_id))
+    ///         .next(),
+    /// )
+    /// .await;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn messages(&mut self) -> Messages<'_> {
+        self.abandon_awaited_run();
+        Messages { connection: self }
+    }
+
+    /// Give up on the run that [run_script_and_wait](Self::run_script_and_wait) was waiting on,
+    /// if the future was dropped or panicked before the run finished, and return its ID. The run's
+    /// remaining messages are discarded, and the connection is checked before it is reused.
+    fn abandon_awaited_run(&mut self) -> Option<u32> {
+        let req_id = self
+            .awaited_run
+            .take()
+            .filter(|req_id| self.pending_runs.contains_key(req_id))?;
+        self.abandoned_runs.insert(req_id);
+        self.dirty = true;
+        Some(req_id)
+    }
+
+    /// Like [abandon_awaited_run](Self::abandon_awaited_run), and also cancel the run so that the
+    /// worker doesn't keep running it.
+    async fn cancel_awaited_run(&mut self) -> Result<(), Error> {
+        match self.abandon_awaited_run() {
+            Some(req_id) => self.cancel(req_id).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Returns true if the message is for an abandoned run, which is forgotten once it ends.
+    fn is_abandoned(&mut self, message: &WorkerToHostMessage) -> bool {
+        if !self.abandoned_runs.contains(&message.request_id) {
+            return false;
+        }
+        if matches!(
+            message.data,
+            WorkerToHostMessageData::RunResponse(_) | WorkerToHostMessageData::Error(_)
+        ) {
+            self.abandoned_runs.remove(&message.request_id);
+        }
+        true
+    }
+
+    /// Handle a message that isn't part of the run being waited for. Messages for other runs that
+    /// are still going are added to `other`, and messages for requests that already ended, or that
+    /// no run sends, such as a late pong, are kept as [stale messages](Self::take_stale_messages).
+    /// With [strict_messages](JsSidecarBuilder::strict_messages) set, those are an error instead.
+    fn set_aside(
+        &mut self,
+        message: WorkerToHostMessage,
+        other: &mut Vec<WorkerToHostMessageData>,
+    ) -> Result<(), Error> {
+        if message.data.is_run_message() && self.pending_runs.contains_key(&message.request_id) {
+            other.push(message.data);
+            return Ok(());
+        }
+
+        if self.options.strict_messages {
+            // The run's remaining messages can't be trusted to line up either.
+            self.abandon_awaited_run();
+            self.dirty = true;
+            return Err(Error::UnexpectedMessage {
+                request_id: message.request_id,
+                message_type: message.data.message_type(),
+            });
+        }
+
+        tracing::debug!(
+            request_id = message.request_id,
+            message_type = message.data.message_type(),
+            "Setting aside a message that isn't part of a running request"
+        );
+        if self.stale_messages.len() == MAX_STALE_MESSAGES {
+            self.stale_messages.pop_front();
+        }
+        self.stale_messages.push_back(message);
+        Ok(())
+    }
+
+    /// Return the messages for requests that had already ended, such as a run's async errors or
+    /// a ping that was answered late, which arrived while
+    /// [run_script_and_wait](Self::run_script_and_wait) or a [Channel] was waiting for a different
+    /// run. Only the last 100 are kept, and they are cleared when the connection goes back to the
+    /// pool.
+    pub fn take_stale_messages(&mut self) -> Vec<WorkerToHostMessage> {
+        self.stale_messages.drain(..).collect()
+    }
+
+    /// Update the run bookkeeping for a message that was received.
+    fn observe(&mut self, message: &WorkerToHostMessage) {
+        if matches!(
+            message.data,
+            WorkerToHostMessageData::Corrupted(_) | WorkerToHostMessageData::OutOfSequence(_)
+        ) {
+            // Whatever was in the frame is lost, so the connection needs to be checked.
+            self.dirty = true;
+        }
+
+        if matches!(
+            message.data,
+            WorkerToHostMessageData::RunResponse(_) | WorkerToHostMessageData::Error(_)
+        ) {
+            if let Some(hash) = self.pending_registered.remove(&message.request_id) {
+                if matches!(message.data, WorkerToHostMessageData::RunResponse(_)) {
+                    self.registered_scripts.insert(hash);
+                }
+            }
+
+            if let Some(start) = self.pending_runs.remove(&message.request_id) {
+                let latency = start.elapsed();
+                self.latency.record(latency);
+                if let Some(pid) = self.worker_pid {
+                    self.options.latencies.record(pid, latency);
+                }
+            }
+
+            if let Some((log, audit)) = self
+                .options
+                .audit
+                .as_ref()
+                .zip(self.pending_audits.remove(&message.request_id))
+            {
+                let status = match message.data {
+                    WorkerToHostMessageData::RunResponse(_) => AuditStatus::Succeeded,
+                    _ => AuditStatus::Failed,
+                };
+                log.finish(audit, status);
+            }
+        }
+
+        if let WorkerToHostMessageData::Error(error) = &message.data {
+            // A run can fail before its modules are added to the context, so send their code
+            // again next time.
+            self.context_modules.clear();
+            // A run that times out can take the worker thread that kept the registered scripts
+            // down with it, and a run sent without code that the worker doesn't have fails with
+            // an internal error.
+            if error.timed_out || error.kind == ErrorKind::InternalWorkerError {
+                self.registered_scripts.clear();
+            }
+        }
+    }
+
+    /// Record the runs that never finished in the audit log.
+    fn abandon_audits(&mut self) {
+        if let Some(log) = &self.options.audit {
+            let mut pending = self.pending_audits.drain().collect::<Vec<_>>();
+            pending.sort_by_key(|(req_id, _)| *req_id);
+            for (_, audit) in pending {
+                log.finish(audit, AuditStatus::Abandoned);
+            }
+        }
+    }
+
+    /// Returns true if the connection should be checked with a ping before it is reused: after
+    /// an error, when a run was abandoned or messages were left unread, when the worker's process
+    /// ID isn't known yet, or when the connection has been idle for
+    /// [JsSidecarBuilder::pool_verify_interval].
+    fn needs_verification(&self) -> bool {
+        self.dirty
+            || !self.pending_runs.is_empty()
+            || !self.receiver.is_empty()
+            || self.worker_pid.is_none()
+            || self.is_idle(self.options.verify_interval)
+    }
+
+    /// Check that a connection can be reused, pinging the worker only if
+    /// [needs_verification](Self::needs_verification) says so.
+    async fn verify(&mut self) -> Result<(), Error> {
+        if self.activity.closed.load(Ordering::Relaxed) {
+            return Err(Error::ReadStream(io::Error::other("Worker is closed")));
+        }
+
+        self.cancel_awaited_run().await?;
+        if self.needs_verification() {
+            self.check_worker(self.options.recycle_timeout).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a plain context on a new connection, so that the first run on it doesn't have to.
+    async fn warm_context(&mut self) -> Result<(), Error> {
+        let args = RunScriptArgs {
+            name: "warm_context".into(),
+            code: "undefined".into(),
+            expr: true,
+            ..Default::default()
+        };
+        let (req_id, request_bytes) = self.start_run(args, RunMode::Warmup).await?;
+        tokio::time::timeout(
+            self.options.recycle_timeout,
+            self.wait_for_run(req_id, request_bytes, None, None, None),
+        )
+        .await
+        .map_err(|_| Error::Timeout)??;
+        self.awaited_run = None;
+        self.warm_context = true;
+        Ok(())
+    }
+
+    /// Ping the worker and wait for the response, recording the worker's process ID.
+    async fn check_worker(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.dirty = true;
+        let req_id = self.ping().await?;
+        let msg = tokio::time::timeout(timeout, self.receive_intact())
+            .await
+            .map_err(|_| Error::Timeout)??
+            .ok_or(Error::ReadStream(io::Error::other("Worker is closed")))?;
+
+        match msg.data {
+            WorkerToHostMessageData::Pong(pong) if msg.request_id == req_id => {
+                if pong.pid.is_some() {
+                    self.worker_pid = pong.pid;
+                }
+                self.dirty = false;
+                Ok(())
+            }
+            // if the message is anything other than a Pong, then we're out of sync somehow.
+            _ => Err(Error::ConnectionOutOfSync),
+        }
+    }
+
+    /// Send our protocol version to the worker and check that it speaks the same one. The worker
+    /// closes the connection after rejecting a handshake. This has no timeout of its own, since it
+    /// runs inside the pool's [create timeout](JsSidecarBuilder::pool_create_timeout), and inside
+    /// the recycle timeout for remote workers.
+    async fn handshake(&mut self, version: u32) -> Result<(), Error> {
+        let message_id = self.next_id;
+        let req_id = self.next_req_id;
+        self.next_req_id += 1;
+        self.next_id += 1;
+        let message = HostToWorkerMessage::new(
+            req_id,
+            message_id,
+            HostToWorkerMessageData::Handshake(HandshakeData {
+                version,
+                checksums: self.options.frame_checksums,
+                max_frame_bytes: self.options.frame_limits.max_frame_bytes,
+                max_response_bytes: self.options.frame_limits.max_response_bytes,
+                kv: self.options.kv.is_some(),
+                resolve_modules: self.options.module_resolver.is_some(),
+                url_imports: self.options.url_imports.clone(),
+                url_import_ttl_ms: self.options.url_import_ttl.as_millis() as u64,
+                compress_frames_over: self.options.compress_frames_over,
+                shared_memory_dir: self
+                    .options
+                    .shared_memory
+                    .as_ref()
+                    .map(|shm| shm.path().to_path_buf()),
+                shared_memory_over: self.options.shared_memory.as_ref().map(|shm| shm.min_bytes),
+            }),
+        );
+        self.send(message).await?;
+
+        let msg = self
+            .receive_intact()
+            .await?
+            .ok_or(Error::ReadStream(io::Error::other("Worker is closed")))?;
+
+        match msg.data {
+            WorkerToHostMessageData::Handshake(response) if msg.request_id == req_id => {
+                if !response.accepted || response.version != version {
+                    return Err(Error::ProtocolMismatch {
+                        host: version,
+                        worker: response.version,
+                    });
+                }
+
+                self.worker_pid = Some(response.pid);
+                Ok(())
+            }
+            // Workers from before the handshake existed answer it like an empty script.
+            WorkerToHostMessageData::RunResponse(_) if msg.request_id == req_id => {
+                Err(Error::ProtocolMismatch {
+                    host: version,
+                    worker: 0,
+                })
+            }
+            _ => Err(Error::ConnectionOutOfSync),
+        }
+    }
+
+    /// The process ID of the worker handling this connection. The worker reports this in the
+    /// handshake when the pool opens a connection, and again in response to each ping.
+    pub fn worker_pid(&self) -> Option<u32> {
+        self.worker_pid
+    }
+
+    /// The recent latency of scripts run on this connection, measured from sending the script
+    /// to receiving its result. This is `None` until a run has finished.
+    pub fn latency(&self) -> Option<LatencyStats> {
+        self.latency.snapshot()
+    }
+
+    /// Send a ping message to the Node.js process
+    pub async fn ping(&mut self) -> Result<u32, Error> {
+        let message_id = self.next_id;
+        let req_id = self.next_req_id;
+        self.next_req_id += 1;
+        self.next_id += 1;
+        let message = HostToWorkerMessage::new(req_id, message_id, HostToWorkerMessageData::Ping);
+        self.send(message).await?;
+        Ok(req_id)
+    }
+
+    /// Cancel a run started with [run_script](Self::run_script), by aborting the `signal` global
+    /// that the script sees. Code that passed the signal to a cancellable API, or listens for its
+    /// `abort` event, can clean up before the run ends.
+    ///
+    /// The run then fails with an `AbortError` as soon as the script settles, or after a second if
+    /// it doesn't. The error, and any console messages logged while cleaning up, arrive as usual
+    /// for the request. The script may still be running in the context, so the worker throws it
+    /// away, and the next run on the connection starts with a fresh one. Nothing happens if the
+    /// run has already finished.
+    pub async fn cancel(&mut self, request_id: u32) -> Result<(), Error> {
+        let message_id = self.next_id;
+        self.next_id += 1;
+        let message =
+            HostToWorkerMessage::new(request_id, message_id, HostToWorkerMessageData::Cancel);
+        self.send(message).await?;
+        Ok(())
+    }
+
+    /// Send a chunk of bytes to the `input` global of a run started with
+    /// [run_script](Self::run_script) with [stream_input](RunScriptArgs::stream_input) set. The
+    /// worker holds on to chunks until the script reads them, so a host sending a lot of input
+    /// should wait for the script to report progress rather than sending it all at once.
+    ///
+    /// Input sent to a run that has already finished is dropped.
+    pub async fn send_input(
+        &mut self,
+        request_id: u32,
+        data: impl Into<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let message_id = self.next_id;
+        self.next_id += 1;
+        let message = HostToWorkerMessage::new(
+            request_id,
+            message_id,
+            HostToWorkerMessageData::Input(data.into()),
+        );
+        self.send(message).await?;
+        Ok(())
+    }
+
+    /// End the input of a run, so that the script's loop over the `input` global finishes once it
+    /// has read the chunks that were already sent.
+    pub async fn end_input(&mut self, request_id: u32) -> Result<(), Error> {
+        let message_id = self.next_id;
+        self.next_id += 1;
+        let message =
+            HostToWorkerMessage::new(request_id, message_id, HostToWorkerMessageData::InputEnd);
+        self.send(message).await?;
+        Ok(())
+    }
+
+    /// Open a [Channel] to a run started with [run_script](Self::run_script), for exchanging JSON
+    /// values with the script while it runs, such as in an agent's tool loop. The script uses the
+    /// `channel` global:
+    ///
+    /// ```js
+    /// for await (const request of channel) {
+    ///   await channel.send(await handle(request));
+    /// }
+    /// ```
+    ///
+    /// ```no_run
+    /// # use futures::{SinkExt, StreamExt};
+    /// # use js_sidecar::{Connection, RunScriptArgs};
+    /// # async fn example(conn: &mut Connection, req_id: u32) -> Result<(), js_sidecar::Error> {
+    /// let mut channel = conn.open_channel(req_id);
+    /// channel.send(serde_json::json!({ "question": 1 })).await?;
+    /// let answer = channel.next().await;
+    /// channel.close().await?;
+    /// let result = channel.finish().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Values sent to the script wait in the worker until it reads them. Values the script sends
+    /// after the run has finished arrive as ordinary messages.
+    pub fn open_channel(&mut self, request_id: u32) -> Channel<'_> {
+        Channel {
+            connection: self,
+            request_id,
+            sending: None,
+            closed: false,
+            logs: Vec::new(),
+            other: Vec::new(),
+            cpu_profile: None,
+            wire: WireSizes::default(),
+            result: None,
+        }
+    }
+
+    /// Take a V8 heap snapshot of the worker handling this connection and write it to `output`,
+    /// returning the number of bytes written. Save the output to a `.heapsnapshot` file to open
+    /// it in Chrome DevTools.
+    ///
+    /// The snapshot covers the whole worker process, including the contexts of every connection
+    /// that it handles, so comparing snapshots of the same [worker_pid](Self::worker_pid) over
+    /// time shows which scripts are holding on to memory. The worker is paused while the snapshot
+    /// is taken. Messages for other requests that arrive in the meantime are discarded.
+    pub async fn heap_snapshot(
+        &mut self,
+        mut output: impl AsyncWrite + Unpin,
+    ) -> Result<u64, Error> {
+        let message_id = self.next_id;
+        let req_id = self.next_req_id;
+        self.next_req_id += 1;
+        self.next_id += 1;
+        let message =
+            HostToWorkerMessage::new(req_id, message_id, HostToWorkerMessageData::HeapSnapshot);
+        self.send(message).await?;
+
+        let mut written = 0;
+        while let Some(message) = self.receive_intact().await? {
+            if message.request_id != req_id {
+                continue;
+            }
+
+            match message.data {
+                WorkerToHostMessageData::HeapSnapshotChunk(chunk) => {
+                    if let Err(e) = output.write_all(&chunk).await {
+                        // The rest of the snapshot is still on its way.
+                        self.dirty = true;
+                        return Err(Error::WriteStream(e));
+                    }
+                    written += chunk.len() as u64;
+                }
+                WorkerToHostMessageData::RunResponse(_) => {
+                    output.flush().await.map_err(Error::WriteStream)?;
+                    return Ok(written);
+                }
+                WorkerToHostMessageData::MessageTooLarge(too_large) => {
+                    self.dirty = true;
+                    return Err(Error::MessageTooLarge {
+                        length: too_large.length,
+                        limit: too_large.limit,
+                    });
+                }
+                WorkerToHostMessageData::Error(error) => {
+                    return Err(Error::Script(Box::new(RunScriptError {
+                        error,
+                        logs: Vec::new(),
+                        other: Vec::new(),
+                    })));
+                }
+                _ => {}
+            }
+        }
+
+        Err(Error::ScriptEndedEarly)
+    }
+
+    /// Move the clock of this connection's context forward by `ms` milliseconds, running each
+    /// timer that comes due along the way in order. Promises started by a timer settle before the
+    /// next timer runs. One call runs at most 100,000 timers, and fails with [Error::Script] if
+    /// that isn't enough to reach the end, such as for a short interval over a long time. The
+    /// clock stays where the last timer ran, so a later call carries on from there.
+    ///
+    /// The context must have been created by a run with [TimerMode::Virtual](crate::TimerMode::Virtual).
+    pub async fn advance_time(&mut self, ms: u64) -> Result<AdvanceTimeResult, Error> {
+        let (mut result, logs): (AdvanceTimeResult, _) = self
+            .call_worker(HostToWorkerMessageData::AdvanceTime(AdvanceTimeData { ms }))
+            .await?;
+        result.logs = logs;
+        Ok(result)
+    }
+
+    /// Start a [Repl] session on this connection's context.
+    pub fn repl(&mut self) -> Repl<'_> {
+        Repl {
+            connection: self,
+            entries: 0,
+            timeout_ms: None,
+        }
+    }
+
+    /// List the properties of the value at `path` in this connection's context, or its globals
+    /// if `path` is empty, whose names start with `prefix`. This suits script editors that offer
+    /// completions backed by the live state of a context, such as the globals that earlier runs
+    /// left behind.
+    ///
+    /// As with [Repl::complete], `path` must be a simple path of names, such as `user.profile`,
+    /// and listing properties never runs any code in the context. Each property comes with a hint
+    /// about what it holds, found without running getters. The names are in sorted order, and
+    /// include inherited properties, such as `toString`, up to any Proxy in the prototype chain.
+    /// A `path` that isn't a path of names, that goes through a getter or a Proxy, or that can't
+    /// be followed, returns [Error::Script], and a connection with no context yet has no
+    /// properties to list.
+    pub async fn complete(&mut self, path: &str, prefix: &str) -> Result<Vec<PropertyHint>, Error> {
+        let data = IntrospectData {
+            expr: path.to_string(),
+            prefix: prefix.to_string(),
+        };
+        let (hints, _) = self
+            .call_worker(HostToWorkerMessageData::Introspect(data))
+            .await?;
+        Ok(hints)
+    }
+
+    /// Build `context` in the worker, and keep it ready for runs that set
+    /// [prewarmed_context](RunScriptArgs::prewarmed_context) to its name. This is what
+    /// [JsSidecar::prewarm] does on each worker. Returns an error if the context's functions or
+    /// modules fail to compile.
+    ///
+    /// This doesn't touch the connection's context.
+    pub async fn prewarm(&mut self, context: &PrewarmContext) -> Result<(), Error> {
+        let ((), _) = self
+            .call_worker(HostToWorkerMessageData::Prewarm(context.clone()))
+            .await?;
+        Ok(())
+    }
+
+    /// Compile `code` in the worker without running it, and report its syntax errors with their
+    /// positions, so that scripts can be checked when they are saved rather than when they run.
+    /// With [lint](CheckScriptOptions::lint) set, problems that would fail the run, such as
+    /// imports of missing modules, are reported too.
+    ///
+    /// This doesn't touch the connection's context.
+    pub async fn check_script(
+        &mut self,
+        code: impl Into<String>,
+        options: CheckScriptOptions,
+    ) -> Result<ScriptCheck, Error> {
+        let data = CheckScriptData {
+            code: code.into(),
+            options,
+        };
+        let (check, _) = self
+            .call_worker(HostToWorkerMessageData::CheckScript(data))
+            .await?;
+        Ok(check)
+    }
+
+    /// Start recording the messages exchanged with the worker, to [replay](Self::replay) them
+    /// later while debugging. The recording includes each message sent to the worker, such as
+    /// runs, input, and [advance_time](Self::advance_time) calls, and the answers to the worker's
+    /// `kv` and module resolution requests, along with the type of each message that came back.
+    ///
+    /// The next run starts with a fresh context, so that the recording doesn't depend on earlier
+    /// runs. Replays only go the same way as the original if the scripts are
+    /// [deterministic](RunScriptArgs::deterministic) and use [TimerMode::Virtual](crate::TimerMode::Virtual).
+    pub fn start_recording(&mut self) {
+        self.recorder.start();
+        self.recreate_context_on_next = true;
+        self.context_modules.clear();
+    }
+
+    /// Stop recording and return what was recorded since
+    /// [start_recording](Self::start_recording), or `None` if the connection wasn't recording.
+    pub fn stop_recording(&mut self) -> Option<Recording> {
+        let messages = self.recorder.stop()?;
+        Some(Recording {
+            protocol_version: PROTOCOL_VERSION,
+            worker_hash: worker_hash(),
+            messages,
+        })
+    }
+
+    /// Send the messages of a [Recording] to the worker again, in the same order, and wait for
+    /// each message that the worker sent back in the original. The worker's `kv` and module
+    /// resolution requests get the recorded answers rather than going to the backends, so
+    /// replaying doesn't change anything outside the worker.
+    ///
+    /// The replay stops at the first message from the worker that has a different type than the
+    /// recorded one, and reports it in [divergence](ReplayResult::divergence). Recordings can
+    /// only be replayed by a host with the same worker code, and return [Error::RecordingMismatch]
+    /// otherwise. The replay runs in its own context, and the next run after it starts with a
+    /// fresh one.
+    ///
+    /// The recorded runs are checked against the sidecar's script approval and written to its
+    /// audit log, as they would be if they were run directly, and a run that isn't approved
+    /// stops the replay with [Error::ScriptNotApproved]. Runs get `[REDACTED]` in place of the
+    /// values of their [secrets](RunScriptArgs::secrets), which aren't recorded.
+    pub async fn replay(&mut self, recording: &Recording) -> Result<ReplayResult, Error> {
+        if recording.protocol_version != PROTOCOL_VERSION || recording.worker_hash != worker_hash()
+        {
+            return Err(Error::RecordingMismatch);
+        }
+
+        self.recorder.set_replaying(true);
+        let result = self.replay_messages(recording).await;
+        self.recorder.set_replaying(false);
+        self.recreate_context_on_next = true;
+        self.context_modules.clear();
+        result
+    }
+
+    async fn replay_messages(&mut self, recording: &Recording) -> Result<ReplayResult, Error> {
+        // The request IDs and call IDs of the replay, by the ones in the recording.
+        let mut request_ids = HashMap::new();
+        let mut call_ids: HashMap<(u32, u32), u32> = HashMap::new();
+        let mut messages = Vec::new();
+
+        for (index, recorded) in recording.messages.iter().enumerate() {
+            match recorded {
+                RecordedMessage::Sent {
+                    request_id,
+                    message_type,
+                    payload,
+                } => {
+                    let req_id = *request_ids.entry(*request_id).or_insert_with(|| {
+                        self.next_req_id += 1;
+                        self.next_req_id - 1
+                    });
+                    let mut payload = payload.clone();
+                    if replay::answers_call(*message_type) {
+                        let mut answer: serde_json::Value = serde_json::from_slice(&payload)?;
+                        let call_id = answer["id"]
+                            .as_u64()
+                            .and_then(|id| call_ids.get(&(*request_id, id as u32)));
+                        if let Some(call_id) = call_id {
+                            answer["id"] = (*call_id).into();
+                            payload = serde_json::to_vec(&answer)?;
+                        }
+                    }
+
+                    // Runs go through the same approval and audit log as new ones.
+                    let audit = if matches!(
+                        *message_type,
+                        to_worker::RUN_SCRIPT | to_worker::RUN_SCRIPT_BINARY
+                    ) {
+                        let args = wire::parse_run_script(*message_type, &payload)?.args;
+                        self.options.approval.check(&args)?;
+                        self.options.audit.as_ref().map(|log| log.start(&args))
+                    } else {
+                        None
+                    };
+
+                    let message_id = self.next_id;
+                    self.next_id += 1;
+                    let message = HostToWorkerMessage::new(
+                        req_id,
+                        message_id,
+                        HostToWorkerMessageData::Replayed {
+                            message_type: *message_type,
+                            payload,
+                        },
+                    );
+                    self.send(message).await?;
+                    if let Some(audit) = audit {
+                        self.pending_audits.insert(req_id, audit);
+                    }
+                }
+                RecordedMessage::Received {
+                    request_id,
+                    message_type,
+                    call_id,
+                } => {
+                    let Some(&req_id) = request_ids.get(request_id) else {
+                        continue;
+                    };
+                    let message = loop {
+                        match self.receive_intact().await? {
+                            Some(message) if message.request_id != req_id => continue,
+                            message => break message,
+                        }
+                    };
+
+                    let received = message.as_ref().map(|message| message.data.message_type());
+                    let Some(mut message) = message.filter(|_| received == Some(*message_type))
+                    else {
+                        // The rest of the replay's messages may still be on their way.
+                        self.dirty = true;
+                        return Ok(ReplayResult {
+                            messages,
+                            divergence: Some(ReplayDivergence {
+                                index,
+                                expected: *message_type,
+                                received,
+                            }),
+                        });
+                    };
+
+                    if let Some((recorded, replayed)) = call_id.zip(replay::call_id(&message.data))
+                    {
+                        call_ids.insert((*request_id, recorded), replayed);
+                    }
+                    message.request_id = *request_id;
+                    messages.push(message);
+                }
+            }
+        }
+
+        Ok(ReplayResult {
+            messages,
+            divergence: None,
+        })
+    }
+
+    /// Send a request that the worker answers with a value in a RunResponse, and wait for the
+    /// value, collecting the console messages logged along the way.
+    pub(crate) async fn call_worker<T: DeserializeOwned>(
+        &mut self,
+        data: HostToWorkerMessageData,
+    ) -> Result<(T, Vec<LogResponseData>), Error> {
+        let message_id = self.next_id;
+        let req_id = self.next_req_id;
+        self.next_req_id += 1;
+        self.next_id += 1;
+        let message = HostToWorkerMessage::new(req_id, message_id, data);
+        self.send(message).await?;
+
+        let mut logs = Vec::new();
+        while let Some(message) = self.receive_intact().await? {
+            if message.request_id != req_id {
+                continue;
+            }
+
+            match message.data {
+                WorkerToHostMessageData::Log(log) => logs.push(log),
+                WorkerToHostMessageData::RunResponse(response) => {
+                    let value = serde_json::from_value(response.return_value.unwrap_or_default())?;
+                    return Ok((value, logs));
+                }
+                WorkerToHostMessageData::Error(error) => {
+                    return Err(Error::Script(Box::new(RunScriptError {
+                        error,
+                        logs,
+                        other: Vec::new(),
+                    })));
+                }
+                _ => {}
+            }
+        }
+
+        Err(Error::ScriptEndedEarly)
+    }
+
+    /// Kill the worker process after it stopped answering, so that Node.js starts a new one in its
+    /// place. The connection dies with it.
+    fn kill_worker(&mut self) {
+        self.dirty = true;
+        // Zero and negative values would signal whole process groups.
+        let Some(pid) = self
+            .worker_pid
+            .and_then(|pid| i32::try_from(pid).ok())
+            .filter(|pid| *pid > 0)
+        else {
+            return;
+        };
+
+        tracing::warn!(
+            pid,
+            "Killing a worker that didn't answer after a run timed out"
+        );
+        nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), nix::sys::signal::SIGKILL).ok();
+    }
+
+    /// Send a message to the worker, returning the number of bytes written.
+    async fn send(&mut self, message: HostToWorkerMessage) -> Result<u64, Error> {
+        if self.activity.closed.load(Ordering::Relaxed) {
+            // Fail before writing anything, so that callers know the worker never saw the message.
+            self.dirty = true;
+            return Err(Error::WriteStream(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Worker is closed",
+            )));
+        }
+
+        let result = self.write_message(message).await;
+        if result.is_err() {
+            self.dirty = true;
+        }
+        result
+    }
+
+    /// Write a message to the worker, in a future that doesn't borrow the connection.
+    ///
+    /// The write runs in its own task, so that it finishes even if the future is dropped. Stopping
+    /// partway through would leave the worker with half a frame, and nothing after it would make
+    /// sense.
+    fn write_message(
+        &self,
+        message: HostToWorkerMessage,
+    ) -> impl Future<Output = Result<u64, Error>> + Send + 'static {
+        let stream = self.stream.clone();
+        let frame = self.options.frame_options(&self.recorder);
+        let activity = self.activity.clone();
+        let write = tokio::spawn(async move {
+            let mut stream = stream.lock().await;
+            let length = message.write_to(frame, &mut *stream).await?;
+            activity.touch(&activity.last_send);
+            Ok(length)
+        });
+        async move {
+            write
+                .await
+                .map_err(|e| Error::WriteStream(io::Error::other(e)))?
+        }
+    }
+
+    /// Run a script and wait for it to finish, accumulating console messages seen along the way.
+    /// Messages from other runs on the connection are placed in
+    /// [other](RunScriptAndWaitResult::other), and messages from requests that already ended, or
+    /// that no run sends, such as a late pong, are set aside in
+    /// [take_stale_messages](Self::take_stale_messages), unless
+    /// [strict_messages](JsSidecarBuilder::strict_messages) is set.
+    ///
+    /// If the run has a [timeout](RunScriptArgs::timeout_ms) and the worker still hasn't answered
+    /// [kill_after_timeout](JsSidecarBuilder::kill_after_timeout) after it passes, the worker is
+    /// killed and this returns [Error::Timeout].
+    ///
+    /// If the kernel kills the worker for taking the sidecar's cgroup over its
+    /// [memory limit](crate::CgroupLimits::memory_max), this returns [Error::CgroupOutOfMemory].
+    ///
+    /// This is cancel safe. If the future is dropped before the run finishes, such as by a
+    /// timeout around it, the run is cancelled the next time the connection is used, and its
+    /// remaining messages are discarded rather than showing up in the next run's results.
+    pub async fn run_script_and_wait(
+        &mut self,
+        args: RunScriptArgs,
+    ) -> Result<RunScriptAndWaitResult, Error> {
+        self.run_and_wait(args, None).await
+    }
+
+    /// Run a script like [run_script_and_wait](Self::run_script_and_wait), with a `write` global
+    /// that streams strings and bytes from the script straight into `output` as it runs. Strings
+    /// are written as UTF-8. This suits scripts that generate large output, such as reports, which
+    /// would otherwise have to be built up in a global and sent back as JSON all at once.
+    ///
+    /// The output is flushed once the run finishes. If writing to `output` fails, this returns
+    /// [Error::WriteStream] without waiting for the run, and the connection isn't reused.
+    pub async fn run_script_to_writer(
+        &mut self,
+        mut args: RunScriptArgs,
+        mut output: impl AsyncWrite + Unpin + Send,
+    ) -> Result<RunScriptAndWaitResult, Error> {
+        args.stream_output = true;
+        self.run_and_wait(args, Some(&mut output)).await
+    }
+
+    /// Start a script as a background job, which keeps running in the worker after this connection
+    /// goes back to the pool or closes, and return a [JobHandle] to check on it later.
+    ///
+    /// The job runs in a fresh context of its own, rather than the connection's, and its console
+    /// messages and result are kept in the worker until [JobHandle::result] reads them. It can't
+    /// use anything that needs the connection that started it: the `kv` global and the
+    /// [ModuleResolver](crate::ModuleResolver) aren't available, and its output and channel
+    /// messages are dropped. The job's [timeout](RunScriptArgs::timeout_ms) still applies.
+    ///
+    /// Jobs are lost if their worker exits, and aren't supported with
+    /// [Isolation::Thread](crate::Isolation::Thread), where the worker thread exits with the
+    /// connection.
+    pub async fn spawn_script(&mut self, args: RunScriptArgs) -> Result<JobHandle, Error> {
+        self.cancel_awaited_run().await?;
+        let (req_id, request_bytes) = self.start_run(args, RunMode::Detached).await?;
+        let result = self
+            .wait_for_run(req_id, request_bytes, None, None, None)
+            .await;
+        self.awaited_run = None;
+
+        let started: StartedJob =
+            serde_json::from_value(result?.response.return_value.unwrap_or_default())?;
+        Ok(JobHandle::new(started, self.options.clone()))
+    }
+
+    /// Run a script and pass each of its messages to `sink` as it arrives, instead of collecting
+    /// them into a [RunScriptAndWaitResult]. The sink gets the run's console messages, output
+    /// chunks, and channel messages in order, followed by its response or error, so they can be
+    /// consumed elsewhere, such as by another task through a channel, while this waits. Messages
+    /// for other runs on the connection that arrive in the meantime, which
+    /// [run_script_and_wait](Self::run_script_and_wait) would return in
+    /// [other](RunScriptAndWaitResult::other), go to the sink too.
+    ///
+    /// This returns once the run has finished. A script error is also returned as
+    /// [Error::Script], with empty logs since they went to the sink.
+    pub async fn run_script_with_sink(
+        &mut self,
+        args: RunScriptArgs,
+        mut sink: impl MessageSink,
+    ) -> Result<(), Error> {
+        let (deadline, oom_kills) = self.run_limits(&args);
+        self.cancel_awaited_run().await?;
+        let (req_id, _) = self.start_run(args, RunMode::Awaited).await?;
+        let result = self
+            .wait_with_sink(req_id, deadline, oom_kills, &mut sink)
+            .await;
+        self.awaited_run = None;
+        result
+    }
+
+    /// The time by which a run has to finish before its worker is killed, and the cgroup's OOM
+    /// kill count before it starts.
+    fn run_limits(&self, args: &RunScriptArgs) -> (Option<Instant>, Option<u64>) {
+        let deadline = args
+            .timeout_ms
+            .zip(self.options.kill_after_timeout)
+            .map(|(timeout, grace)| Instant::now() + Duration::from_millis(timeout) + grace);
+        let oom_kills = self
+            .options
+            .cgroup
+            .as_ref()
+            .map(|cgroup| cgroup.oom_kills());
+        (deadline, oom_kills)
+    }
+
+    async fn run_and_wait(
+        &mut self,
+        args: RunScriptArgs,
+        output: Option<&mut (dyn AsyncWrite + Unpin + Send)>,
+    ) -> Result<RunScriptAndWaitResult, Error> {
+        let (deadline, oom_kills) = self.run_limits(&args);
+        self.cancel_awaited_run().await?;
+        let (req_id, request_bytes) = self.start_run(args, RunMode::Awaited).await?;
+        let result = self
+            .wait_for_run(req_id, request_bytes, deadline, oom_kills, output)
+            .await;
+        // The future wasn't dropped, so the run isn't abandoned, even if waiting for it failed.
+        self.awaited_run = None;
+        result
+    }
+
+    async fn wait_for_run(
+        &mut self,
+        req_id: u32,
+        request_bytes: u64,
+        deadline: Option<Instant>,
+        oom_kills: Option<u64>,
+        mut output: Option<&mut (dyn AsyncWrite + Unpin + Send)>,
+    ) -> Result<RunScriptAndWaitResult, Error> {
+        let mut logs = Vec::new();
+        let mut other = Vec::new();
+        let mut cpu_profile = None;
+        let mut wire = WireSizes {
+            request_bytes,
+            response_bytes: 0,
+        };
+
+        while let Some(message) = self.receive_by(deadline).await? {
+            if message.request_id != req_id || !message.data.is_run_message() {
+                self.set_aside(message, &mut other)?;
+                continue;
+            }
+
+            wire.response_bytes += message.wire_bytes;
+            match message.data {
+                WorkerToHostMessageData::RunResponse(response) => {
+                    if let Some(output) = output {
+                        output.flush().await.map_err(Error::WriteStream)?;
+                    }
+                    return Ok(RunScriptAndWaitResult {
+                        response,
+                        logs,
+                        other,
+                        cpu_profile,
+                        wire,
+                    });
+                }
+                WorkerToHostMessageData::Error(error) => {
+                    if let Some(output) = output {
+                        // The script's error says more than a failure to flush what it wrote.
+                        output.flush().await.ok();
+                    }
+                    return Err(Error::Script(Box::new(RunScriptError {
+                        error,
+                        logs,
+                        other,
+                    })));
+                }
+                WorkerToHostMessageData::Output(chunk) => match output.as_mut() {
+                    Some(output) => {
+                        if let Err(e) = output.write_all(&chunk).await {
+                            // The rest of the run's messages are still on their way.
+                            self.dirty = true;
+                            return Err(Error::WriteStream(e));
+                        }
+                    }
+                    None => other.push(WorkerToHostMessageData::Output(chunk)),
+                },
+                WorkerToHostMessageData::MessageTooLarge(too_large) => {
+                    return Err(Error::MessageTooLarge {
+                        length: too_large.length,
+                        limit: too_large.limit,
+                    });
+                }
+                WorkerToHostMessageData::Log(log) => logs.push(log),
+                WorkerToHostMessageData::CpuProfile(profile) => cpu_profile = Some(profile),
+                data => other.push(data),
+            }
+        }
+
+        Err(self.ended_early(oom_kills))
+    }
+
+    async fn wait_with_sink(
+        &mut self,
+        req_id: u32,
+        deadline: Option<Instant>,
+        oom_kills: Option<u64>,
+        sink: &mut dyn MessageSink,
+    ) -> Result<(), Error> {
+        let mut other = Vec::new();
+        while let Some(message) = self.receive_by(deadline).await? {
+            if message.request_id != req_id || !message.data.is_run_message() {
+                self.set_aside(message, &mut other)?;
+                other.drain(..).for_each(|data| sink.send(data));
+                continue;
+            }
+
+            match message.data {
+                WorkerToHostMessageData::RunResponse(response) => {
+                    sink.send(WorkerToHostMessageData::RunResponse(response));
+                    return Ok(());
+                }
+                WorkerToHostMessageData::Error(error) => {
+                    sink.send(WorkerToHostMessageData::Error(error.clone()));
+                    return Err(Error::Script(Box::new(RunScriptError {
+                        error,
+                        logs: Vec::new(),
+                        other: Vec::new(),
+                    })));
+                }
+                WorkerToHostMessageData::MessageTooLarge(too_large) => {
+                    sink.send(WorkerToHostMessageData::MessageTooLarge(too_large.clone()));
+                    return Err(Error::MessageTooLarge {
+                        length: too_large.length,
+                        limit: too_large.limit,
+                    });
+                }
+                data => sink.send(data),
+            }
+        }
+
+        Err(self.ended_early(oom_kills))
+    }
+
+    /// Receive the next message, killing the worker and returning [Error::Timeout] if it doesn't
+    /// arrive by `deadline`.
+    async fn receive_by(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> Result<Option<WorkerToHostMessage>, Error> {
+        let Some(deadline) = deadline else {
+            return self.receive_intact().await;
+        };
+        match tokio::time::timeout_at(deadline.into(), self.receive_intact()).await {
+            Ok(received) => received,
+            Err(_) => {
+                self.kill_worker();
+                Err(Error::Timeout)
+            }
+        }
+    }
+
+    /// The error for a run whose worker went away before it finished.
+    fn ended_early(&self, oom_kills: Option<u64>) -> Error {
+        // The kernel kills workers that take the cgroup over its memory limit, so this tells
+        // whether that is why the worker went away.
+        match self.options.cgroup.as_ref().zip(oom_kills) {
+            Some((cgroup, before)) if cgroup.oom_kills() > before => Error::CgroupOutOfMemory,
+            _ => Error::ScriptEndedEarly,
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.abandon_audits();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::{
+        future::BoxFuture,
+        stream::{self, StreamExt},
+        SinkExt,
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        verify_audit_chain, wire::WorkerToHostMessageData, AsyncErrorSource, AuditRecord,
+        AuditSink, CgroupLimits, CheckScriptOptions, CodeModule, Diagnostic, DrainPolicy, Effect,
+        ErrorKind, FunctionDef, Hardening, JobState, JsValue, KeyedConnection, KvOperation,
+        ScheduleStore, ScheduleStoreResult, ScheduledScript, ScriptTemplate, SkippedGlobal,
+        TenantQuota, TimerMode, Transport, TypedArrayKind, ValueKind, WasmModule,
+    };
+
+    // Compile error if Connection is not Send + Sync
+    #[allow(dead_code)]
+    trait IsSync: Send + Sync {}
+    impl IsSync for Connection {}
+
+    #[tokio::test]
+    async fn regular_execution() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let args = RunScriptArgs {
+            code: r##"
+                console.log('Hello, World!');
+                output = output + 15;
+            "##
+            .into(),
+            globals: [("output".into(), json!(5))].into_iter().collect(),
+            ..Default::default()
+        };
+        let req_id = connection.run_script(args).await.unwrap();
+
+        let mut messages = Vec::new();
+
+        while let Some(message) = connection.receive_message().await {
+            assert_eq!(message.request_id, req_id);
+            let finished = matches!(message.data, WorkerToHostMessageData::RunResponse(_));
+            println!("{message:#?}");
+            messages.push(message);
+
+            if finished {
+                drop(connection);
+                break;
+            }
+        }
+
+        assert_eq!(messages.len(), 2);
+
+        let console_msg = &messages[0];
+
+        let WorkerToHostMessageData::Log(log) = &console_msg.data else {
+            panic!("Expected log message, saw {console_msg:#?}");
+        };
+
+        assert_eq!(log.message, json!(["Hello, World!"]));
+        assert_eq!(log.level, LogLevel::Info);
+        assert_eq!(log.request_id, messages[1].request_id);
+        assert!(log.time() <= std::time::SystemTime::now());
+
+        let response_msg = &messages[1];
+
+        let WorkerToHostMessageData::RunResponse(response) = &response_msg.data else {
+            panic!("Expected response message, saw {response_msg:#?}");
+        };
+
+        assert_eq!(response.globals["output"], json!(20));
+        assert_eq!(response.request_id, req_id);
+        eprintln!("Closing");
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn correlation_ids() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let first = connection
+            .run_script(RunScriptArgs {
+                code: "console.log('first'); throw new Error('failed');".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let second = connection
+            .run_script(RunScriptArgs {
+                code: "(console.log('second'), 2)".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_ne!(first, second);
+
+        let mut finished = 0;
+        while finished < 2 {
+            let message = connection.receive_message().await.unwrap();
+            match message.data {
+                WorkerToHostMessageData::Log(log) => {
+                    let expected = if log.request_id == first {
+                        "first"
+                    } else {
+                        "second"
+                    };
+                    assert_eq!(log.message, json!([expected]));
+                    assert_eq!(log.request_id, message.request_id);
+                }
+                WorkerToHostMessageData::Error(error) => {
+                    assert_eq!(error.request_id, first);
+                    finished += 1;
+                }
+                WorkerToHostMessageData::RunResponse(response) => {
+                    assert_eq!(response.request_id, second);
+                    assert_eq!(response.return_value, Some(json!(2)));
+                    finished += 1;
+                }
+                data => panic!("Unexpected message {data:#?}"),
+            }
+        }
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn expression_execution() {
+        let mut sidecar = JsSidecar::new(None).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let args = RunScriptArgs {
+            code: r##"
+                output + 15
+            "##
+            .into(),
+            expr: true,
+            globals: [("output".into(), json!(5))].into_iter().collect(),
+            ..Default::default()
+        };
+        connection.run_script(args).await.unwrap();
+
+        let mut messages = Vec::new();
+
+        while let Some(message) = connection.receive_message().await {
+            if matches!(&message.data, WorkerToHostMessageData::Error(_)) {
+                panic!("Saw error: {message:#?}");
+            }
+
+            let finished = matches!(message.data, WorkerToHostMessageData::RunResponse(_));
+            println!("{message:#?}");
+            messages.push(message);
+
+            if finished {
+                break;
+            }
+        }
+
+        assert_eq!(messages.len(), 1);
+
+        let response_msg = &messages[0];
+
+        let WorkerToHostMessageData::RunResponse(response) = &response_msg.data else {
+            panic!("Expected response message, saw {response_msg:#?}");
+        };
+
+        assert_eq!(response.return_value, Some(json!(20)));
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn run_script_and_wait() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let args = RunScriptArgs {
+            code: r##"
+                console.log('abc');
+                output = 15
+            "##
+            .into(),
+            globals: [("output".into(), json!(5))].into_iter().collect(),
+            ..Default::default()
+        };
+        let result = connection.run_script_and_wait(args).await.unwrap();
+
+        assert_eq!(result.response.globals["output"], json!(15));
+        assert_eq!(result.logs.len(), 1);
+        assert_eq!(result.logs[0].message, json!(["abc"]));
+        assert!(result.other.is_empty());
+
+        let stats = result.response.stats.unwrap();
+        assert!(stats.compile_ms >= 0.0);
+        assert!(stats.execute_ms > 0.0);
+        assert!(stats.macrotasks_drained);
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn message_stream() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let req_id = connection
+            .run_script(RunScriptArgs {
+                code: "console.log('a'); console.log('b');".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let messages = tokio::time::timeout(
+            Duration::from_secs(5),
+            connection
+                .messages()
+                .take_while(|message| {
+                    std::future::ready(!matches!(
+                        message.data,
+                        WorkerToHostMessageData::RunResponse(_)
+                    ))
+                })
+                .collect::<Vec<_>>(),
+        )
+        .await
+        .unwrap();
+
+        let logs = messages
+            .iter()
+            .map(|message| {
+                assert_eq!(message.request_id, req_id);
+                let WorkerToHostMessageData::Log(log) = &message.data else {
+                    panic!("Expected a log message");
+                };
+                log.message.clone()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(logs, vec![json!(["a"]), json!(["b"])]);
+        // The stream saw the response, so the run is no longer pending.
+        assert!(connection.pending_runs.is_empty());
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn call_mode() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+
+        let result = sidecar
+            .run(RunScriptArgs {
+                code: "async (items, factor) => items.map((x) => x * factor)".into(),
+                call: true,
+                args: vec![json!([1, 2, 3]), json!(10)],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(result.response.return_value, Some(json!([10, 20, 30])));
+        assert!(result.response.globals.is_empty());
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn await_result() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+
+        let result = sidecar
+            .run(RunScriptArgs {
+                code:
+                    "globalThis.output = Promise.resolve(5); export default (async () => 'done')();"
+                        .into(),
+                return_keys: vec!["output".to_string()],
+                await_result: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.globals["output"], json!(5));
+        assert_eq!(result.response.return_value, Some(json!("done")));
+
+        let err = sidecar
+            .run(RunScriptArgs {
+                code: "Promise.reject('bad')".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        let Error::Script(err) = err else {
+            panic!("Expected Script error, saw {err:#?}");
+        };
+        assert_eq!(err.error.message, "bad");
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn pool_limits() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .pool_max_size(1)
+            .pool_wait_timeout(Duration::ZERO)
+            .pool_queue_mode(QueueMode::Fifo)
+            .build()
+            .await
+            .unwrap();
+
+        let conn = sidecar.connect().await.unwrap();
+        let Err(err) = sidecar.connect().await else {
+            panic!("Expected pool timeout");
+        };
+        assert!(err.is_pool_timeout(), "{err:?}");
+        assert!(!err.is_connection_failure());
+
+        drop(conn);
+        drop(sidecar.connect().await.unwrap());
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn max_queued_requests() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .pool_max_size(1)
+            .max_queued_requests(0)
+            .build()
+            .await
+            .unwrap();
+
+        let conn = sidecar.connect().await.unwrap();
+        let start = Instant::now();
+        let Err(err) = sidecar.eval::<i32>("1").await else {
+            panic!("Expected the sidecar to be overloaded");
+        };
+        assert!(
+            matches!(
+                err,
+                Error::Overloaded {
+                    queued: 0,
+                    limit: 0
+                }
+            ),
+            "{err:?}"
+        );
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        drop(conn);
+        assert_eq!(sidecar.eval::<i32>("1").await.unwrap(), 1);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn slow_checkout() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .pool_max_size(1)
+            .slow_checkout_threshold(Duration::from_millis(50))
+            .build()
+            .await
+            .unwrap();
+        let mut events = sidecar.subscribe();
+
+        let conn = sidecar.connect().await.unwrap();
+        assert_eq!(
+            sidecar.pool_status(),
+            PoolStatus {
+                max_size: 1,
+                size: 1,
+                available: 0,
+                waiting: 0,
+            }
+        );
+
+        let waiter = sidecar.connect_with_priority(Priority::High);
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let status = sidecar.pool_status();
+            drop(conn);
+            status
+        };
+        let (waiter, status) = tokio::join!(waiter, release);
+        assert_eq!(status.waiting, 1);
+        assert_eq!(status.in_use(), 1);
+        drop(waiter.unwrap());
+
+        let event = loop {
+            match events.recv().await.unwrap() {
+                SidecarEvent::SlowCheckout {
+                    waited,
+                    priority,
+                    status,
+                } => break (waited, priority, status),
+                _ => continue,
+            }
+        };
+        assert!(event.0 >= Duration::from_millis(100));
+        assert_eq!(event.1, Priority::High);
+        assert_eq!(event.2.waiting, 0);
+
+        // Checkouts that don't wait aren't reported.
+        drop(sidecar.connect().await.unwrap());
+        while let Ok(event) = events.try_recv() {
+            assert!(!matches!(event, SidecarEvent::SlowCheckout { .. }));
+        }
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn tenant_quotas() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .tenant_quota(
+                "batch",
+                TenantQuota {
+                    max_concurrent_runs: Some(1),
+                    ..Default::default()
+                },
+            )
+            .default_tenant_quota(TenantQuota {
+                run_time_per_minute: Some(Duration::from_millis(1)),
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+
+        let batch_run = || {
+            sidecar.run(RunScriptArgs {
+                code: "await new Promise((resolve) => setTimeout(resolve, 200))".into(),
+                tenant: Some("batch".to_string()),
+                ..Default::default()
+            })
+        };
+
+        // The batch tenant's runs go one at a time.
+        let start = Instant::now();
+        let (a, b) = tokio::join!(batch_run(), batch_run());
+        a.unwrap();
+        b.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        // Other tenants get the default quota, and are cut off once they use up their run time.
+        let busy = RunScriptArgs {
+            code: "const end = Date.now() + 20; while (Date.now() < end) {}".into(),
+            tenant: Some("busy".to_string()),
+            ..Default::default()
+        };
+        sidecar.run(busy.clone()).await.unwrap();
+        let err = sidecar.run(busy).await.unwrap_err();
+        let Error::TenantQuotaExceeded { tenant, .. } = err else {
+            panic!("Expected the quota to be exceeded, saw {err:?}");
+        };
+        assert_eq!(tenant, "busy");
+
+        let stats = sidecar.tenant_stats();
+        assert_eq!(stats["batch"].completed_runs, 2);
+        assert_eq!(stats["batch"].active_runs, 0);
+        assert_eq!(stats["busy"].completed_runs, 1);
+        assert_eq!(stats["busy"].rejected_runs, 1);
+        assert!(stats["busy"].run_time >= Duration::from_millis(20));
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn audit_log() {
+        #[derive(Clone, Default)]
+        struct Records(Arc<Mutex<Vec<AuditRecord>>>);
+
+        impl AuditSink for Records {
+            fn record(&self, record: AuditRecord) {
+                self.0.lock().unwrap().push(record);
+            }
+        }
+
+        let records = Records::default();
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .audit_log(AuditLog::new(records.clone()).record_code(true))
+            .build()
+            .await
+            .unwrap();
+
+        sidecar
+            .run(RunScriptArgs {
+                name: "ok".into(),
+                code: "1 + 1".into(),
+                expr: true,
+                tenant: Some("acme".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        sidecar
+            .run(RunScriptArgs {
+                name: "throws".into(),
+                code: "throw new Error('bad')".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+
+        let records = records.0.lock().unwrap().clone();
+        assert_eq!(records.len(), 2);
+        assert_eq!(verify_audit_chain(&records), Ok(()));
+        assert_eq!(records[0].name, "ok");
+        assert_eq!(records[0].status, AuditStatus::Succeeded);
+        assert_eq!(records[0].tenant.as_deref(), Some("acme"));
+        assert_eq!(records[0].code.as_ref().unwrap().code, "1 + 1");
+        assert_eq!(records[1].name, "throws");
+        assert_eq!(records[1].status, AuditStatus::Failed);
+        assert_eq!(records[1].tenant, None);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn script_allowlist() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .allow_script_hashes([hex_string(&Sha256::digest(b"1 + 1"))])
+            .build()
+            .await
+            .unwrap();
+
+        let mut connection = sidecar.connect().await.unwrap();
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                name: "unvetted".into(),
+                code: "2 + 2".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        let Error::ScriptNotApproved { name, .. } = err else {
+            panic!("Expected the script to be rejected, saw {err:?}");
+        };
+        assert_eq!(name, "unvetted");
+
+        // Nothing was sent, so the connection can keep going.
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "1 + 1".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(2)));
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn secrets() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let secrets: HashMap<String, String> =
+            [("API_KEY".to_string(), "sk-live-1234".to_string())].into();
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: r#"
+                    console.log('Using key', secrets.API_KEY);
+                    globalThis.header = `Bearer ${secrets.API_KEY}`;
+                    export default { key: secrets.API_KEY, length: secrets.API_KEY.length };
+                "#
+                .into(),
+                secrets: secrets.clone(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            result.response.globals["header"],
+            json!("Bearer [REDACTED]")
+        );
+        assert!(!result.response.globals.contains_key("secrets"));
+        assert_eq!(
+            result.response.return_value,
+            Some(json!({ "key": "[REDACTED]", "length": 12 }))
+        );
+        assert_eq!(result.logs[0].message, json!(["Using key", "[REDACTED]"]));
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "throw new Error(`Request with ${secrets.API_KEY} failed`)".into(),
+                secrets,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        let Error::Script(err) = err else {
+            panic!("Expected a script error, saw {err:?}");
+        };
+        assert_eq!(err.error.message, "Request with [REDACTED] failed");
+        assert!(!err.error.stack.unwrap_or_default().contains("sk-live"));
+
+        // The secrets aren't left behind for later runs.
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "typeof secrets".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!("undefined")));
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn timeout_interrupts_script() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        for (code, call) in [
+            (
+                "globalThis.before = 1; console.log('started'); while (true) {}",
+                false,
+            ),
+            (
+                "function spin() { console.log('started'); while (true) {} }",
+                true,
+            ),
+        ] {
+            let err = connection
+                .run_script_and_wait(RunScriptArgs {
+                    code: code.into(),
+                    call,
+                    timeout_ms: Some(100),
+                    ..Default::default()
+                })
+                .await
+                .unwrap_err();
+            assert!(err.is_timeout(), "{err:?}");
+            let Error::Script(err) = err else {
+                panic!("Expected a script error, saw {err:?}");
+            };
+            assert_eq!(err.logs[0].message, json!(["started"]));
+        }
+
+        // The interrupted context was thrown away.
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "typeof before".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!("undefined")));
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn timeout_kills_blocked_worker() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .kill_after_timeout(Duration::from_millis(200))
+            // Connections can fail while Node.js replaces the worker.
+            .run_retries(2)
+            .build()
+            .await
+            .unwrap();
+        let mut events = sidecar.subscribe();
+        let mut connection = sidecar.connect().await.unwrap();
+        let pid = connection.worker_pid().unwrap();
+
+        // A timer callback runs outside of the script, so the timeout can't interrupt it.
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: r#"
+                    setTimeout(() => { while (true) {} }, 10);
+                    await new Promise((resolve) => setTimeout(resolve, 1000));
+                "#
+                .into(),
+                timeout_ms: Some(100),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Timeout), "{err:?}");
+        drop(connection);
+
+        // Node.js replaces the killed worker.
+        let mut exited = None;
+        let new_pid = loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+                .await
+                .expect("Timed out waiting for event")
+                .unwrap();
+            match event {
+                SidecarEvent::WorkerExited { pid, signal, .. } => exited = Some((pid, signal)),
+                SidecarEvent::WorkerStarted { pid } => break pid,
+                _ => {}
+            }
+        };
+        assert_eq!(exited, Some((pid, Some("SIGKILL".to_string()))));
+        assert_ne!(new_pid, pid);
+
+        let result = sidecar
+            .run(RunScriptArgs {
+                code: "1 + 1".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(2)));
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn thread_isolation_terminates_blocked_thread() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .isolation(Isolation::Thread)
+            .build()
+            .await
+            .unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+        let pid = connection.worker_pid().unwrap();
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: r#"
+                    globalThis.value = 5;
+                    setTimeout(() => { while (true) {} }, 10);
+                    await new Promise((resolve) => setTimeout(resolve, 1000));
+                "#
+                .into(),
+                timeout_ms: Some(100),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(err.is_timeout(), "{err:?}");
+
+        // The connection keeps working in a new thread, with a fresh context, and the worker
+        // process is still the same one.
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "typeof value".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!("undefined")));
+        assert_eq!(connection.worker_pid(), Some(pid));
+        drop(connection);
+
+        let connection = sidecar.connect().await.unwrap();
+        assert_eq!(connection.worker_pid(), Some(pid));
+        drop(connection);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn thread_isolation_times_queued_run_from_start() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .isolation(Isolation::Thread)
+            .build()
+            .await
+            .unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let first = connection
+            .run_script(RunScriptArgs {
+                code: r#"
+                    await new Promise((resolve) => setTimeout(resolve, 1500));
+                    export default 1;
+                "#
+                .into(),
+                priority: Priority::High,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        // This run waits in its lane behind the first one, and then times out. The thread is only
+        // terminated if the run is still going after its timeout and the grace period, counted
+        // from when it started, so the first run isn't failed along with it.
+        let second = connection
+            .run_script(RunScriptArgs {
+                code: "await new Promise((resolve) => setTimeout(resolve, 5000))".into(),
+                priority: Priority::Low,
+                timeout_ms: Some(200),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let mut finished = 0;
+        while finished < 2 {
+            let message = connection.receive_message().await.unwrap();
+            match message.data {
+                WorkerToHostMessageData::RunResponse(response) => {
+                    assert_eq!(response.request_id, first);
+                    assert_eq!(response.return_value, Some(json!(1)));
+                    finished += 1;
+                }
+                WorkerToHostMessageData::Error(error) => {
+                    assert_eq!(error.request_id, second, "{error:?}");
+                    assert!(error.timed_out, "{error:?}");
+                    finished += 1;
+                }
+                data => panic!("Unexpected message {data:#?}"),
+            }
+        }
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn error_kinds() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        for (code, kind, name, error_code) in [
+            ("let (", ErrorKind::SyntaxError, Some("SyntaxError"), None),
+            (
+                "missing.value",
+                ErrorKind::ReferenceError,
+                Some("ReferenceError"),
+                None,
+            ),
+            ("null.value", ErrorKind::Script, Some("TypeError"), None),
+            ("throw 'a string'", ErrorKind::Script, None, None),
+            (
+                "throw Object.assign(new Error('failed'), { code: 'E_CUSTOM' })",
+                ErrorKind::Script,
+                Some("Error"),
+                Some("E_CUSTOM"),
+            ),
+            (
+                "'x'.repeat(2 ** 30)",
+                ErrorKind::OutOfMemory,
+                Some("RangeError"),
+                None,
+            ),
+            (
+                "import { value } from './missing.js'",
+                ErrorKind::ModuleResolution,
+                Some("Error"),
+                Some("ERR_MODULE_NOT_FOUND"),
+            ),
+        ] {
+            let err = connection
+                .run_script_and_wait(RunScriptArgs {
+                    code: code.into(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), Some(kind), "{code}: {err:?}");
+            let Error::Script(err) = err else {
+                panic!("Expected a script error, saw {err:?}");
+            };
+            assert_eq!(err.error.name.as_deref(), name, "{code}");
+            assert_eq!(err.error.code.as_deref(), error_code, "{code}");
+        }
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "while (true) {}".into(),
+                timeout_ms: Some(50),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), Some(ErrorKind::Timeout));
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn error_causes() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: r#"
+                    try {
+                        await Promise.any([
+                            Promise.reject(new TypeError('first')),
+                            Promise.reject('second'),
+                        ]);
+                    } catch (e) {
+                        throw new Error('lookup failed', { cause: e });
+                    }
+                "#
+                .into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        let Error::Script(err) = err else {
+            panic!("Expected a script error, saw {err:?}");
+        };
+
+        let messages = err
+            .error
+            .chain()
+            .map(|e| e.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["lookup failed", "All promises were rejected"]);
+        let aggregate = err.error.cause.as_deref().unwrap();
+        assert_eq!(aggregate.name.as_deref(), Some("AggregateError"));
+        let errors = aggregate
+            .errors
+            .iter()
+            .map(|e| (e.message.as_str(), e.name.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(errors, [("first", Some("TypeError")), ("second", None)]);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn async_errors() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: r#"
+                    setTimeout(() => {
+                        Promise.reject(new TypeError('nobody handled this'));
+                        throw new Error('thrown from a timer');
+                    }, 50);
+                "#
+                .into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(result.other.is_empty());
+
+        let mut errors = Vec::new();
+        while errors.len() < 2 {
+            let message =
+                tokio::time::timeout(Duration::from_secs(5), connection.receive_message())
+                    .await
+                    .expect("async error")
+                    .unwrap();
+            assert_eq!(message.request_id, result.response.request_id);
+            let WorkerToHostMessageData::AsyncError(error) = message.data else {
+                panic!("Unexpected message {:?}", message.data);
+            };
+            assert_eq!(error.error.request_id, result.response.request_id);
+            errors.push((error.source, error.error.message, error.error.name));
+        }
+        assert_eq!(
+            errors,
+            [
+                (
+                    AsyncErrorSource::UncaughtException,
+                    "thrown from a timer".to_string(),
+                    Some("Error".to_string())
+                ),
+                (
+                    AsyncErrorSource::UnhandledRejection,
+                    "nobody handled this".to_string(),
+                    Some("TypeError".to_string())
+                ),
+            ]
+        );
+
+        // The worker is still running.
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "1 + 1".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(2)));
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn cancel() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let req_id = connection
+            .run_script(RunScriptArgs {
+                code: r#"
+                    signal.addEventListener('abort', () => console.log('aborted', signal.reason.name));
+                    await new Promise((resolve) => setTimeout(resolve, 10000));
+                "#
+                .into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        // Let the script start listening before cancelling it.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        connection.cancel(req_id).await.unwrap();
+
+        let mut logs = Vec::new();
+        let error = loop {
+            let message =
+                tokio::time::timeout(Duration::from_secs(5), connection.receive_message())
+                    .await
+                    .expect("run to end")
+                    .unwrap();
+            assert_eq!(message.request_id, req_id);
+            match message.data {
+                WorkerToHostMessageData::Log(log) => logs.push(log.message),
+                WorkerToHostMessageData::Error(error) => break error,
+                data => panic!("Unexpected message {data:?}"),
+            }
+        };
+        assert_eq!(logs, vec![json!(["aborted", "AbortError"])]);
+        assert_eq!(error.message, "The run was cancelled by the host");
+        assert_eq!(error.kind, ErrorKind::Cancelled);
+
+        // A timeout aborts the signal too, and the script's cleanup finishes before the error.
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: r#"
+                    await new Promise((resolve, reject) => {
+                        signal.addEventListener('abort', () => {
+                            console.log('cleaning up');
+                            reject(signal.reason);
+                        });
+                    });
+                "#
+                .into(),
+                timeout_ms: Some(100),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        let Error::Script(err) = err else {
+            panic!("Expected a script error, saw {err:?}");
+        };
+        assert_eq!(err.error.message, "The run timed out after 100ms");
+        assert_eq!(err.logs[0].message, json!(["cleaning up"]));
+
+        // Each run gets a fresh signal.
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "signal.aborted".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(false)));
+        assert!(!result.response.globals.contains_key("signal"));
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        let mut sidecar = JsSidecar::builder().num_workers(1).build().await.unwrap();
+
+        // The worker reports its PID in the handshake, before any ping.
+        let conn = sidecar.connect().await.unwrap();
+        assert!(conn.worker_pid().is_some());
+        assert_eq!(conn.next_req_id, 1);
+        drop(conn);
+
+        let manager = sidecar.pool.manager();
+        let (read_stream, write_stream) = manager.options.address.connect().await.unwrap();
+        let mut conn = Connection::new(read_stream, write_stream, manager.options.clone()).unwrap();
+        let err =
+            tokio::time::timeout(Duration::from_secs(5), conn.handshake(PROTOCOL_VERSION + 1))
+                .await
+                .unwrap()
+                .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::ProtocolMismatch { host, worker }
+                    if host == PROTOCOL_VERSION + 1 && worker == PROTOCOL_VERSION
+            ),
+            "{err:?}"
+        );
+
+        // The worker hangs up after rejecting the handshake.
+        let next = tokio::time::timeout(Duration::from_secs(5), conn.receive_message())
+            .await
+            .unwrap();
+        assert!(next.is_none());
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn frame_checksums() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .frame_checksums(true)
+            .build()
+            .await
+            .unwrap();
+
+        let mut connection = sidecar.connect().await.unwrap();
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "console.log('hi'); 'é'.repeat(1000)".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!("é".repeat(1000))));
+        assert_eq!(result.logs.len(), 1);
+        drop(connection);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn compressed_frames() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .frame_checksums(true)
+            .compress_frames(256)
+            .max_frame_bytes(4096)
+            .build()
+            .await
+            .unwrap();
+
+        let mut connection = sidecar.connect().await.unwrap();
+        // Both the script and the response are large enough to be compressed. The response is
+        // also over the frame limit, so it is split into chunks that are compressed separately.
+        let code = format!(
+            "const padding = '{}'; 'abc'.repeat(10000)",
+            "y".repeat(2000)
+        );
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: code.into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            result.response.return_value,
+            Some(json!("abc".repeat(10000)))
+        );
+        drop(connection);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn wire_sizes() {
+        async fn sizes(compress: bool) -> WireSizes {
+            let mut builder = JsSidecar::builder().num_workers(1).max_frame_bytes(4096);
+            if compress {
+                builder = builder.compress_frames(256);
+            }
+            let mut sidecar = builder.build().await.unwrap();
+
+            let mut connection = sidecar.connect().await.unwrap();
+            let result = connection
+                .run_script_and_wait(RunScriptArgs {
+                    code: "console.log('done'); input.repeat(3)".into(),
+                    expr: true,
+                    globals: [("input".into(), json!("abc".repeat(5000)))]
+                        .into_iter()
+                        .collect(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(result.logs.len(), 1);
+            drop(connection);
+            sidecar.close().await;
+            result.wire
+        }
+
+        let plain = sizes(false).await;
+        assert!(plain.request_bytes > 15_000, "{plain:?}");
+        // The response is split into chunks, and all of them are counted.
+        assert!(plain.response_bytes > 45_000, "{plain:?}");
+
+        let compressed = sizes(true).await;
+        assert!(compressed.request_bytes < 2_000, "{compressed:?}");
+        assert!(compressed.response_bytes < 2_000, "{compressed:?}");
+    }
+
+    #[tokio::test]
+    async fn shared_memory_frames() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .shared_memory_frames(1024)
+            .build()
+            .await
+            .unwrap();
+
+        let mut connection = sidecar.connect().await.unwrap();
+        let input = "x".repeat(1_000_000);
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "input.length + ':' + input.slice(0, 3) + 'y'.repeat(2000)".into(),
+                expr: true,
+                globals: [("input".into(), json!(input))].into_iter().collect(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            result.response.return_value,
+            Some(json!(format!("1000000:xxx{}", "y".repeat(2000))))
+        );
+        drop(connection);
+
+        // Every payload that went through shared memory was removed by its reader.
+        let shared_memory = sidecar
+            .pool
+            .manager()
+            .options
+            .shared_memory
+            .clone()
+            .unwrap();
+        assert_eq!(std::fs::read_dir(shared_memory.path()).unwrap().count(), 0);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn message_size_limits() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .max_frame_bytes(1024)
+            .max_response_bytes(100_000)
+            .build()
+            .await
+            .unwrap();
+
+        let mut connection = sidecar.connect().await.unwrap();
+
+        // Responses over the frame limit arrive in chunks.
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "'x'.repeat(50000)".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!("x".repeat(50000))));
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "'x'.repeat(200000)".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::MessageTooLarge { limit: 100_000, .. }),
+            "{err:?}"
+        );
+
+        // Other messages can't be split, so they are replaced.
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "console.log('x'.repeat(2000))".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::MessageTooLarge { limit: 1024, .. }),
+            "{err:?}"
+        );
+        drop(connection);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn return_key_paths() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: r#"
+                    globalThis.data = {
+                        user: { name: "Bob", password: "hunter2" },
+                        items: [{ id: 1, body: "..." }, { id: 2, body: "..." }],
+                    };
+                "#
+                .into(),
+                return_keys: vec![
+                    "data.user.name".to_string(),
+                    "data.items[*].id".to_string(),
+                    "data.missing".to_string(),
+                ],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let globals = &result.response.globals;
+        assert_eq!(globals.len(), 2);
+        assert_eq!(globals["data.user.name"], json!("Bob"));
+        assert_eq!(globals["data.items[*].id"], json!([1, 2]));
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "globalThis.ran = true".into(),
+                return_keys: vec!["data[".to_string()],
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("unclosed"), "{err}");
+        drop(connection);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn skipped_globals() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let code = r#"
+            globalThis.ok = 1;
+            globalThis.loop = {};
+            loop.self = loop;
+            globalThis.big = 5n;
+            globalThis.fn = () => 1;
+        "#;
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: code.into(),
+                return_keys: vec![
+                    "ok".to_string(),
+                    "loop".to_string(),
+                    "big".to_string(),
+                    "fn".to_string(),
+                ],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.globals.len(), 1);
+        assert_eq!(result.response.globals["ok"], json!(1));
+        assert_eq!(
+            result.response.skipped_globals,
+            vec![
+                SkippedGlobal {
+                    key: "loop".to_string(),
+                    reason: "loop.self is a circular reference".to_string(),
+                },
+                SkippedGlobal {
+                    key: "big".to_string(),
+                    reason: "big is a BigInt".to_string(),
+                },
+                SkippedGlobal {
+                    key: "fn".to_string(),
+                    reason: "fn is a function".to_string(),
+                },
+            ]
+        );
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: code.into(),
+                strict_globals: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("loop.self is a circular reference"),
+            "{err}"
+        );
+        drop(connection);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn dry_run() {
+        let kv = crate::MemoryKv::new();
+        kv.set("count".into(), json!(1)).await.unwrap();
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .kv_backend(kv)
+            .build()
+            .await
+            .unwrap();
+
+        let mut connection = sidecar.connect().await.unwrap();
+        let code = r#"
+            const count = await kv.get('count');
+            await kv.set('count', count + 1);
+            await kv.delete('missing');
+            await write(`count is ${await kv.get('count')}`);
+            await channel.send({ count: await kv.get('count') });
+            export default await kv.list('');
+        "#;
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: code.into(),
+                dry_run: true,
+                stream_output: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(["count"])));
+        assert_eq!(
+            result.response.effects,
+            vec![
+                Effect::Kv(KvOperation::Set {
+                    key: "count".into(),
+                    value: json!(2),
+                }),
+                Effect::Kv(KvOperation::Delete {
+                    key: "missing".into(),
+                }),
+                Effect::Output {
+                    data: b"count is 2".to_vec(),
+                },
+                Effect::Channel {
+                    value: json!({ "count": 2 }),
+                },
+            ]
+        );
+        assert!(result.other.is_empty());
+
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "export default [await kv.get('count'), await kv.list('')]".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!([1, ["count"]])));
+        assert!(result.response.effects.is_empty());
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let kv = crate::MemoryKv::new();
+        kv.set("count".into(), json!(1)).await.unwrap();
+        let sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .kv_backend(kv)
+            .build()
+            .await
+            .unwrap();
+
+        let mut connection = sidecar.connect().await.unwrap();
+        connection.start_recording();
+        let code = r#"
+            const count = await kv.get('count');
+            await kv.set('count', count + 1);
+            globalThis.fired = [];
+            setTimeout(() => fired.push(Math.random()), 100);
+            console.log('count', count);
+            export default count;
+        "#;
+        let first = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: code.into(),
+                deterministic: true,
+                timers: TimerMode::Virtual,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        connection.advance_time(100).await.unwrap();
+        let second = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "fired".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let recording = connection.stop_recording().unwrap();
+        assert!(connection.stop_recording().is_none());
+
+        let json = serde_json::to_string(&recording).unwrap();
+        let recording: Recording = serde_json::from_str(&json).unwrap();
+
+        // The replay gets the recorded answers from kv, not the current ones, and its writes
+        // don't reach the backend.
+        connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "await kv.set('count', 10)".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let replayed = connection.replay(&recording).await.unwrap();
+        assert_eq!(replayed.divergence, None);
+        let responses = replayed
+            .messages
+            .iter()
+            .filter_map(|message| match &message.data {
+                WorkerToHostMessageData::RunResponse(response) => {
+                    Some((message.request_id, response.return_value.clone()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(responses.len(), 3);
+        assert_eq!(
+            responses[0],
+            (first.response.request_id, first.response.return_value)
+        );
+        assert_eq!(
+            responses[2],
+            (second.response.request_id, second.response.return_value)
+        );
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "export default await kv.get('count')".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(10)));
+
+        let mut changed = recording.clone();
+        let index = changed
+            .messages
+            .iter()
+            .position(|message| matches!(message, RecordedMessage::Received { .. }))
+            .unwrap();
+        if let RecordedMessage::Received { message_type, .. } = &mut changed.messages[index] {
+            *message_type = 0x1003;
+        }
+        let replayed = connection.replay(&changed).await.unwrap();
+        let divergence = replayed.divergence.unwrap();
+        assert_eq!(divergence.index, index);
+        assert_eq!(divergence.expected, 0x1003);
+
+        let mut changed = recording;
+        changed.worker_hash = "other".into();
+        let err = connection.replay(&changed).await.unwrap_err();
+        assert!(matches!(err, Error::RecordingMismatch), "{err}");
+    }
+
+    #[tokio::test]
+    async fn replay_checks_approval_and_audit() {
+        #[derive(Clone, Default)]
+        struct Records(Arc<Mutex<Vec<AuditRecord>>>);
+
+        impl AuditSink for Records {
+            fn record(&self, record: AuditRecord) {
+                self.0.lock().unwrap().push(record);
+            }
+        }
+
+        let code = "export default secrets.API_KEY.length";
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+        connection.start_recording();
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                name: "secret".into(),
+                code: code.into(),
+                secrets: [("API_KEY".to_string(), "hunter2".to_string())].into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(7)));
+        let recording = connection.stop_recording().unwrap();
+        drop(connection);
+        sidecar.close().await;
+
+        // The secret's value isn't in the recording, so the replay runs with the placeholder.
+        let RecordedMessage::Sent { payload, .. } = &recording.messages[0] else {
+            panic!("Expected the run to be recorded first");
+        };
+        let payload = String::from_utf8_lossy(payload);
+        assert!(!payload.contains("hunter2"), "{payload}");
+        assert!(payload.contains("[REDACTED]"), "{payload}");
+
+        let records = Records::default();
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .audit_log(AuditLog::new(records.clone()))
+            .allow_script_hashes([hex_string(&Sha256::digest(code.as_bytes()))])
+            .build()
+            .await
+            .unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+        let replayed = connection.replay(&recording).await.unwrap();
+        assert_eq!(replayed.divergence, None);
+        let WorkerToHostMessageData::RunResponse(response) = &replayed.messages[0].data else {
+            panic!("Expected a response, saw {:?}", replayed.messages[0]);
+        };
+        assert_eq!(response.return_value, Some(json!(10)));
+
+        let records = records.0.lock().unwrap().clone();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].name, "secret");
+        assert_eq!(records[0].status, AuditStatus::Succeeded);
+        drop(connection);
+        sidecar.close().await;
+
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .allow_script_hashes([hex_string(&Sha256::digest(b"1 + 1"))])
+            .build()
+            .await
+            .unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+        let err = connection.replay(&recording).await.unwrap_err();
+        assert!(matches!(err, Error::ScriptNotApproved { .. }), "{err}");
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn kv_store() {
+        let sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .kv_backend(crate::MemoryKv::new())
+            .build()
+            .await
+            .unwrap();
+
+        let mut connection = sidecar.connect().await.unwrap();
+        let code = r#"
+            await kv.set('user/1', { name: 'a' });
+            await kv.set('user/2', { name: 'b' });
+            await kv.set('other', 1);
+            export default await kv.list('user/');
+        "#;
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: code.into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            result.response.return_value,
+            Some(json!(["user/1", "user/2"]))
+        );
+        assert!(!result.response.globals.contains_key("kv"));
+
+        // The values outlive the context.
+        let code = r#"
+            const deleted = await kv.delete('user/1');
+            export default [deleted, await kv.get('user/1'), await kv.get('user/2')];
+        "#;
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: code.into(),
+                recreate_context: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            result.response.return_value,
+            Some(json!([true, null, { "name": "b" }]))
+        );
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "await kv.set('f', () => 1)".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("can't be converted to JSON"),
+            "{err}"
+        );
+
+        let sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "export default typeof kv".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!("undefined")));
+    }
+
+    #[tokio::test]
+    async fn timer_modes() {
+        let sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let code = r#"
+            globalThis.start = Date.now();
+            globalThis.fired = [];
+            setTimeout(() => fired.push('a'), 1000);
+            const interval = setInterval(() => {
+                fired.push(Date.now() - start);
+                if (fired.length > 4) clearInterval(interval);
+            }, 400);
+        "#;
+        connection
+            .run_script_and_wait(RunScriptArgs {
+                code: code.into(),
+                timers: TimerMode::Virtual,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let result = connection.advance_time(900).await.unwrap();
+        assert_eq!(result.timers_run, 2);
+        assert_eq!(result.pending_timers, 2);
+        let result = connection.advance_time(10_000).await.unwrap();
+        assert_eq!(result.timers_run, 3);
+        assert_eq!(result.pending_timers, 0);
+
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "fired".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            result.response.return_value,
+            Some(json!([400, 800, "a", 1200, 1600]))
+        );
+
+        // Calling `Date` without `new` gives the virtual time as a string.
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "Date() === new Date(start + 10_900).toString()".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(true)));
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "setTimeout(() => {}, 10)".into(),
+                recreate_context: true,
+                timers: TimerMode::Disabled,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("disabled"), "{err}");
+        let err = connection.advance_time(10).await.unwrap_err();
+        assert!(err.to_string().contains("virtual time"), "{err}");
+
+        let code = r#"
+            for (let i = 0; i < 3; i++) setTimeout(() => {}, 1);
+        "#;
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: code.into(),
+                recreate_context: true,
+                max_timers: Some(2),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("at most 2"), "{err}");
+
+        let code = r#"
+            export default await new Promise((resolve) => setTimeout(() => resolve('done'), 5));
+        "#;
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: code.into(),
+                recreate_context: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!("done")));
+    }
+
+    #[tokio::test]
+    async fn drain_policies() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let code = r#"
+            globalThis.steps = [];
+            setTimeout(() => {
+                steps.push('first');
+                setTimeout(() => Promise.resolve().then(() => steps.push('second')), 20);
+            }, 20);
+        "#;
+        let run = |drain, max_drain_ms| RunScriptArgs {
+            code: code.into(),
+            drain,
+            max_drain_ms,
+            // Clears the timers left by the last run.
+            recreate_context: true,
+            return_keys: vec!["steps".into()],
+            ..Default::default()
+        };
+        let steps = |result: RunScriptAndWaitResult| result.response.globals["steps"].clone();
+
+        let result = connection
+            .run_script_and_wait(run(DrainPolicy::Return, None))
+            .await
+            .unwrap();
+        assert_eq!(steps(result), json!([]));
+
+        let result = connection
+            .run_script_and_wait(run(DrainPolicy::Wait, None))
+            .await
+            .unwrap();
+        let stats = result.response.stats.unwrap();
+        assert!(stats.execute_ms >= 40.0, "{stats:?}");
+        assert_eq!(steps(result), json!(["first", "second"]));
+
+        let result = connection
+            .run_script_and_wait(run(DrainPolicy::Wait, Some(30)))
+            .await
+            .unwrap();
+        assert_eq!(steps(result), json!(["first"]));
+
+        connection
+            .run_script_and_wait(run(DrainPolicy::Cancel, None))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "steps".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!([])));
+
+        // Cancelling only clears the run's own timers, and not those left by earlier runs.
+        connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "globalThis.ticks = []; setTimeout(() => ticks.push('earlier'), 50);".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "setTimeout(() => ticks.push('cancelled'), 10);".into(),
+                drain: DrainPolicy::Cancel,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "ticks".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(["earlier"])));
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn output_streaming() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .max_frame_bytes(4096)
+            .build()
+            .await
+            .unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        // The large write is over the frame limit, so it arrives in several messages.
+        let code = r#"
+            write('header\n');
+            await write(new Uint8Array(10000).fill(0x78));
+            setTimeout(() => write('\nfooter'), 5);
+            globalThis.done = true;
+        "#;
+        let mut output = Vec::new();
+        let result = connection
+            .run_script_to_writer(
+                RunScriptArgs {
+                    code: code.into(),
+                    drain: DrainPolicy::Wait,
+                    return_keys: vec!["done".into()],
+                    ..Default::default()
+                },
+                &mut output,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.response.globals["done"], json!(true));
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("header\n{}\nfooter", "x".repeat(10000))
+        );
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "write('a')".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not enabled"), "{err}");
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn input_streaming() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let code = r#"
+            let total = 0;
+            for await (const text of input.text()) {
+                for (const n of text.split(',')) total += Number(n);
+                console.log(`total ${total}`);
+            }
+            export default total;
+        "#;
+        let req_id = connection
+            .run_script(RunScriptArgs {
+                code: code.into(),
+                stream_input: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        connection.send_input(req_id, "1,2").await.unwrap();
+
+        let mut logs = Vec::new();
+        let response = loop {
+            let message = connection.receive_message().await.unwrap();
+            match message.data {
+                WorkerToHostMessageData::Log(log) => {
+                    logs.push(log.message);
+                    // Feed the script more once it has read what it had.
+                    if logs.len() == 1 {
+                        connection.send_input(req_id, b"3".to_vec()).await.unwrap();
+                        connection.end_input(req_id).await.unwrap();
+                    }
+                }
+                WorkerToHostMessageData::RunResponse(response) => break response,
+                data => panic!("Unexpected message {data:?}"),
+            }
+        };
+        assert_eq!(logs, vec![json!(["total 3"]), json!(["total 6"])]);
+        assert_eq!(response.return_value, Some(json!(6)));
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn script_channel() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let code = r#"
+            let total = 0;
+            for await (const n of channel) {
+                total += n;
+                console.log(`got ${n}`);
+                await channel.send({ total });
+            }
+            export default total;
+        "#;
+        let req_id = connection
+            .run_script(RunScriptArgs {
+                code: code.into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let mut channel = connection.open_channel(req_id);
+        channel.send(json!(1)).await.unwrap();
+        assert_eq!(channel.next().await, Some(json!({ "total": 1 })));
+        channel.send(json!(2)).await.unwrap();
+        assert_eq!(channel.next().await, Some(json!({ "total": 3 })));
+        channel.close().await.unwrap();
+        assert_eq!(channel.next().await, None);
+
+        let result = channel.finish().await.unwrap();
+        assert_eq!(result.response.return_value, Some(json!(3)));
+        assert_eq!(result.logs.len(), 2);
+
+        let req_id = connection
+            .run_script(RunScriptArgs {
+                code: "await channel.receive(); throw new Error('failed')".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let mut channel = connection.open_channel(req_id);
+        channel.send(json!(null)).await.unwrap();
+        let err = channel.finish().await.unwrap_err();
+        assert!(err.to_string().contains("failed"), "{err}");
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn repl_session() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+        let mut repl = connection.repl();
+
+        assert_eq!(repl.eval("let count = 1").await.unwrap().value, "undefined");
+        assert_eq!(
+            repl.eval("const user = { name: 'a' }").await.unwrap().value,
+            "undefined"
+        );
+        assert_eq!(repl.eval("count + 1").await.unwrap().value, "2");
+        assert_eq!(repl.eval("user").await.unwrap().value, "{ name: 'a' }");
+        assert_eq!(
+            repl.eval("function double(n) { return n * 2 }; double")
+                .await
+                .unwrap()
+                .value,
+            "[Function: double]"
+        );
+
+        let output = repl
+            .eval("console.log('hi'); Promise.resolve(count)")
+            .await
+            .unwrap();
+        assert_eq!(output.value, "1");
+        assert_eq!(output.logs[0].message, json!(["hi"]));
+
+        let err = repl.eval("count.missing.x").await.unwrap_err();
+        assert!(err.to_string().contains("Cannot read properties"), "{err}");
+        assert_eq!(repl.eval("count").await.unwrap().value, "1");
+
+        let completions = repl.complete("cou").await.unwrap();
+        assert_eq!(completions.prefix, "cou");
+        assert_eq!(completions.candidates, vec!["count".to_string()]);
+        let completions = repl.complete("double(user.na").await.unwrap();
+        assert_eq!(completions.candidates, vec!["name".to_string()]);
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn complete_from_context() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+        assert!(connection.complete("", "").await.unwrap().is_empty());
+
+        connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "globalThis.api = { fetchUser(id, opts) {}, users: [1, 2, 3], cache: new Map() }"
+                    .into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let hints = connection.complete("", "ap").await.unwrap();
+        assert_eq!(
+            hints,
+            vec![PropertyHint {
+                name: "api".into(),
+                kind: ValueKind::Object,
+                detail: Some("Object".into()),
+            }]
+        );
+
+        let hints = connection.complete("api", "").await.unwrap();
+        let hint = |name: &str| hints.iter().find(|hint| hint.name == name).unwrap();
+        assert_eq!(hint("fetchUser").kind, ValueKind::Function);
+        assert_eq!(hint("fetchUser").detail.as_deref(), Some("(id, opts)"));
+        assert_eq!(hint("users").detail.as_deref(), Some("Array(3)"));
+        assert_eq!(hint("cache").detail.as_deref(), Some("Map"));
+        assert_eq!(hint("toString").kind, ValueKind::Function);
+
+        let hints = connection.complete("api.cache", "si").await.unwrap();
+        assert_eq!(hints[0].kind, ValueKind::Accessor);
+
+        let err = connection.complete("missing", "").await.unwrap_err();
+        assert!(err.to_string().contains("missing is not defined"), "{err}");
+
+        let err = connection
+            .complete("(globalThis.ran = true)", "")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Not a path"), "{err}");
+        let hints = connection.complete("", "ran").await.unwrap();
+        assert!(hints.is_empty(), "{hints:?}");
+
+        connection
+            .run_script_and_wait(RunScriptArgs {
+                // Not enumerable, so that returning the globals doesn't run the getter.
+                code: r#"
+                    Object.defineProperty(globalThis, 'lazy', {
+                        value: { get user() { globalThis.ran = true; return {}; } },
+                    });
+                "#
+                .into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let err = connection.complete("lazy.user", "").await.unwrap_err();
+        assert!(
+            err.to_string().contains("without running its getter"),
+            "{err}"
+        );
+        let hints = connection.complete("", "ran").await.unwrap();
+        assert!(hints.is_empty(), "{hints:?}");
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn check_script() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let check = connection
+            .check_script(
+                "import { a } from './lib.js';\nconst b = ;",
+                CheckScriptOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert!(!check.is_ok());
+        assert_eq!(
+            check.errors,
+            vec![Diagnostic {
+                message: "SyntaxError: Unexpected token ';'".into(),
+                line: Some(2),
+                column: Some(11),
+            }]
+        );
+
+        let options = CheckScriptOptions {
+            name: "main.js".into(),
+            lint: true,
+            modules: vec!["lib.js".into()],
+            ..Default::default()
+        };
+        let check = connection
+            .check_script(
+                "import { a } from './lib';\nimport b from 'b';\nglobalThis.ran = true;",
+                options,
+            )
+            .await
+            .unwrap();
+        assert!(check.is_ok());
+        assert_eq!(check.warnings.len(), 1);
+        assert_eq!(check.warnings[0].message, "Module not found: b");
+        assert_eq!(check.warnings[0].line, Some(2));
+
+        let check = connection
+            .check_script(
+                "(a, b) => a +",
+                CheckScriptOptions {
+                    call: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(check.errors[0].line, Some(1));
+        assert_eq!(check.errors[0].column, Some(14));
+
+        // Nothing ran, so the global isn't there.
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "typeof ran".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!("undefined")));
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn deterministic_mode() {
+        let sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let code = r#"[
+            Math.random(),
+            Math.random(),
+            Date.now(),
+            new Date().toISOString(),
+            typeof WeakRef,
+            typeof FinalizationRegistry,
+        ]"#;
+        let args = |random_seed| RunScriptArgs {
+            code: code.into(),
+            expr: true,
+            recreate_context: true,
+            deterministic: true,
+            random_seed,
+            ..Default::default()
+        };
+
+        let mut results = Vec::new();
+        for seed in [None, None, Some(7)] {
+            let result = connection.run_script_and_wait(args(seed)).await.unwrap();
+            results.push(result.response.return_value.unwrap());
+        }
+        let [first, second, seeded] = &results[..] else {
+            unreachable!()
+        };
+        assert_eq!(first, second);
+        assert_ne!(first[0], seeded[0]);
+        assert_ne!(first[0], first[1]);
+        assert_eq!(first[2], json!(0));
+        assert_eq!(first[3], json!("1970-01-01T00:00:00.000Z"));
+        assert_eq!(first[4], json!("undefined"));
+        assert_eq!(first[5], json!("undefined"));
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "setTimeout(() => {}, 1)".into(),
+                recreate_context: true,
+                deterministic: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("disabled"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn log_limits() {
+        let sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .max_log_messages(5)
+            .build()
+            .await
+            .unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let code = "for (let i = 0; i < 100; i++) console.log('line', i)";
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: code.into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.logs.len(), 6);
+        assert_eq!(result.logs[4].message, json!(["line", 4]));
+        let summary = &result.logs[5];
+        assert_eq!(summary.level, LogLevel::Warn);
+        assert!(
+            summary
+                .message
+                .as_str()
+                .unwrap()
+                .starts_with("Dropped 95 console messages"),
+            "{summary:?}"
+        );
+
+        // A per-run byte limit, along with the sidecar's message limit
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "console.log('x'.repeat(1000)); console.log('y'); throw new Error('oops')"
+                    .into(),
+                max_log_bytes: Some(500),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        let Error::Script(err) = result else {
+            panic!("Expected a script error, got {result:?}");
+        };
+        assert_eq!(err.logs.len(), 1);
+        assert!(err.logs[0]
+            .message
+            .as_str()
+            .unwrap()
+            .ends_with("over the run's limit of 5 messages or 500 bytes"));
+    }
+
+    #[tokio::test]
+    async fn wasm_modules() {
+        // (module (func (export "add") (param i32 i32) (result i32) local.get 0 local.get 1 i32.add))
+        const ADD: &[u8] = &[
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x60, 0x02, 0x7f,
+            0x7f, 0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x61, 0x64, 0x64,
+            0x00, 0x00, 0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+        ];
+
+        let sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .frame_checksums(true)
+            .build()
+            .await
+            .unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "[math.add(2, 3), other.add(-1, 1)]".into(),
+                expr: true,
+                wasm_modules: vec![
+                    WasmModule {
+                        name: "math".into(),
+                        bytes: Bytes::from_static(ADD),
+                    },
+                    WasmModule {
+                        name: "other".into(),
+                        bytes: Bytes::from_static(ADD),
+                    },
+                ],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!([5, 0])));
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "1".into(),
+                expr: true,
+                wasm_modules: vec![WasmModule {
+                    name: "bad".into(),
+                    bytes: Bytes::from_static(b"not wasm"),
+                }],
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Failed to compile WASM module bad"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn module_graph() {
+        let sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let module = |name: &'static str, code: &'static str| CodeModule {
+            name: name.into(),
+            code: code.into(),
+        };
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "import { greet } from 'lib/index.js'; export default greet('a');".into(),
+                modules: vec![
+                    module(
+                        "lib/index.js",
+                        "import { wrap } from './format'; \
+                         export const greet = (name) => wrap(`hello ${name}`);",
+                    ),
+                    module(
+                        "lib/format.js",
+                        "import { SUFFIX } from '../constants.mjs'; \
+                         export const wrap = (s) => s + SUFFIX;",
+                    ),
+                    module("constants.mjs", "export const SUFFIX = '!';"),
+                ],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!("hello a!")));
+
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "import { isEven } from 'even.js'; export default isEven(4);".into(),
+                recreate_context: true,
+                modules: vec![
+                    module(
+                        "even.js",
+                        "import { isOdd } from './odd.js'; \
+                         export function isEven(n) { return n === 0 || isOdd(n - 1); }",
+                    ),
+                    module(
+                        "odd.js",
+                        "import { isEven } from './even.js'; \
+                         export function isOdd(n) { return n !== 0 && isEven(n - 1); }",
+                    ),
+                ],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(true)));
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "import 'a.js';".into(),
+                recreate_context: true,
+                modules: vec![
+                    module("a.js", "import { b } from './b.js'; export const a = 1;"),
+                    module(
+                        "b.js",
+                        "import { a } from './a.js'; export const b = a + 1;",
+                    ),
+                ],
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Import cycle between modules a.js -> b.js -> a.js: Cannot access"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn module_reuse() {
+        let sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let counter = |code: &'static str| CodeModule {
+            name: "counter.js".into(),
+            code: code.into(),
+        };
+        let v1 = "let count = 0; export const next = () => ++count;";
+        let v2 = "let count = 10; export const next = () => ++count;";
+        let args = |module: CodeModule, recreate_context: bool| RunScriptArgs {
+            code: "import { next } from 'counter.js'; export default next();".into(),
+            modules: vec![module],
+            recreate_context,
+            ..Default::default()
+        };
+
+        let mut run = async |module, recreate_context| {
+            let result = connection
+                .run_script_and_wait(args(module, recreate_context))
+                .await
+                .unwrap();
+            result.response.return_value
+        };
+        assert_eq!(run(counter(v1), false).await, Some(json!(1)));
+        // Each run evaluates a new instance of the module, even when its code isn't sent again.
+        assert_eq!(run(counter(v1), false).await, Some(json!(1)));
+        assert_eq!(run(counter(v2), false).await, Some(json!(11)));
+        assert_eq!(run(counter(v2), true).await, Some(json!(11)));
+
+        let mut same = args(counter(v2), false);
+        assert_eq!(connection.take_cached_modules(&mut same).len(), 1);
+        assert!(same.modules.is_empty());
+
+        // A run that fails before the modules reach the context makes the next run send them.
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                return_keys: vec!["a[".into()],
+                ..args(counter(v1), true)
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("a["), "{err}");
+        let result = connection
+            .run_script_and_wait(args(counter(v1), false))
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(1)));
+    }
+
+    /// Serves `plugins/<name>` from a fixed set of plugins, and records each request.
+    #[derive(Default)]
+    struct PluginResolver {
+        requests: Arc<Mutex<Vec<(String, String)>>>,
+    }
+
+    impl crate::ModuleResolver for PluginResolver {
+        fn resolve(
+            &self,
+            request: crate::ResolveModuleRequest,
+        ) -> futures::future::BoxFuture<'_, crate::ResolveResult> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push((request.specifier.clone(), request.referrer.clone()));
+            let (name, code) = match request.specifier.as_str() {
+                "plugins/upper" => (
+                    "plugins/upper/index.js",
+                    "import { apply } from './apply.js'; \
+                     export default (s) => apply(s, (c) => c.toUpperCase());",
+                ),
+                "plugins/upper/apply.js" => (
+                    "plugins/upper/apply.js",
+                    "export const apply = (s, f) => f(s);",
+                ),
+                "plugins/broken" => {
+                    return Box::pin(async { Err("database is down".into()) });
+                }
+                _ => return Box::pin(async { Ok(None) }),
+            };
+            let module = CodeModule {
+                name: name.into(),
+                code: code.into(),
+            };
+            Box::pin(async { Ok(Some(module)) })
+        }
+    }
+
+    #[tokio::test]
+    async fn module_resolver() {
+        let resolver = PluginResolver::default();
+        let requests = resolver.requests.clone();
+        let sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .module_resolver(resolver)
+            .build()
+            .await
+            .unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let code = "import upper from 'plugins/upper'; export default upper('a');";
+        for _ in 0..2 {
+            let result = connection
+                .run_script_and_wait(RunScriptArgs {
+                    code: code.into(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(result.response.return_value, Some(json!("A")));
+        }
+        // The second run reuses the modules from the first.
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                ("plugins/upper".to_string(), "<script>".to_string()),
+                (
+                    "plugins/upper/apply.js".to_string(),
+                    "plugins/upper/index.js".to_string()
+                ),
+            ]
+        );
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "import 'plugins/missing';".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Module not found: plugins/missing, referenced from <script>"),
+            "{err}"
+        );
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "import 'plugins/broken';".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains(
+                "Failed to resolve module plugins/broken from <script>: database is down"
+            ),
+            "{err}"
+        );
+
+        // A dry run can use the modules that the context already has, but doesn't call the
+        // resolver for new ones.
+        requests.lock().unwrap().clear();
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: code.into(),
+                dry_run: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!("A")));
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "import 'plugins/other';".into(),
+                dry_run: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Can't resolve module plugins/other from <script> in a dry run"),
+            "{err}"
+        );
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    /// Serve `/lib/index.js` and `/lib/util.js` over HTTP, counting the requests.
+    async fn serve_modules() -> (String, Arc<AtomicUsize>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request);
+                let path = request.split(' ').nth(1).unwrap_or_default();
+                let (status, body) = match path {
+                    "/lib/index.js" => (
+                        "200 OK",
+                        "import { double } from './util.js'; export default double;",
+                    ),
+                    "/lib/util.js" => ("200 OK", "export const double = (x) => x * 2;"),
+                    _ => ("404 Not Found", ""),
+                };
+                let response = format!(
+                    "HTTP/1.1 {status}\r\ncontent-type: text/javascript\r\n\
+                     content-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                socket.write_all(response.as_bytes()).await.ok();
+            }
+        });
+        (base, requests)
+    }
+
+    #[tokio::test]
+    async fn url_imports() {
+        let (base, requests) = serve_modules().await;
+        let sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .allow_url_imports([format!("{base}/lib/")])
+            .build()
+            .await
+            .unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let code = format!("import double from '{base}/lib/index.js'; export default double(2);");
+        for recreate_context in [false, true] {
+            let result = connection
+                .run_script_and_wait(RunScriptArgs {
+                    code: code.clone().into(),
+                    recreate_context,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(result.response.return_value, Some(json!(4)));
+        }
+        // The new context uses the modules that the worker already fetched.
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: format!("import '{base}/other.js';").into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains(&format!(
+                "Import of {base}/other.js from <script> is not allowed"
+            )),
+            "{err}"
+        );
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: format!("import '{base}/lib/missing.js';").into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("404 Not Found"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn protocol_corruption() {
+        let mut sidecar = JsSidecar::builder().num_workers(1).build().await.unwrap();
+        let options = sidecar.pool.manager().options.clone();
+
+        let (read_stream, mut worker) = tokio::io::duplex(1024);
+        let mut conn =
+            Connection::new(Box::new(read_stream), Box::new(tokio::io::sink()), options).unwrap();
+
+        let response = crate::wire::encode_frame(0, 0, 0x1000, b"{}", false);
+        let mut data = b"garbage".to_vec();
+        data.extend_from_slice(&response);
+        worker.write_all(&data).await.unwrap();
+
+        let err = conn
+            .run_script_and_wait(RunScriptArgs::default())
+            .await
+            .unwrap_err();
+        let Error::ProtocolCorruption(corruption) = &err else {
+            panic!("expected corruption, got {err:?}");
+        };
+        assert_eq!(corruption.skipped_bytes, 7);
+        assert!(err.is_connection_failure());
+        assert!(conn.needs_verification());
+
+        // The reader picks up again at the next frame.
+        let next = conn.receive_message().await.unwrap();
+        assert!(matches!(next.data, WorkerToHostMessageData::RunResponse(_)));
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn verify_on_checkout() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .pool_max_size(1)
+            .build()
+            .await
+            .unwrap();
+
+        drop(sidecar.connect().await.unwrap());
+        let mut conn = sidecar.connect().await.unwrap();
+        conn.run_script_and_wait(RunScriptArgs::default())
+            .await
+            .unwrap();
+        let next_req_id = conn.next_req_id;
+        drop(conn);
+
+        // A healthy connection is reused without a ping.
+        let mut conn = sidecar.connect().await.unwrap();
+        assert_eq!(conn.next_req_id, next_req_id);
+
+        // A connection that saw an error is checked before it is reused.
+        conn.dirty = true;
+        drop(conn);
+        let conn = sidecar.connect().await.unwrap();
+        assert_eq!(conn.next_req_id, next_req_id + 1);
+        assert!(!conn.dirty);
+        drop(conn);
+
+        sidecar.close().await;
+
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .pool_max_size(1)
+            .pool_verify_interval(Duration::ZERO)
+            .build()
+            .await
+            .unwrap();
+
+        drop(sidecar.connect().await.unwrap());
+        let conn = sidecar.connect().await.unwrap();
+        let next_req_id = conn.next_req_id;
+        drop(conn);
+        let conn = sidecar.connect().await.unwrap();
+        assert_eq!(
+            conn.next_req_id,
+            next_req_id + 1,
+            "pinged on every checkout"
+        );
+        drop(conn);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn cancel_safe_wait() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .pool_max_size(1)
+            .build()
+            .await
+            .unwrap();
+        let slow = || RunScriptArgs {
+            code: "console.log('slow'); await new Promise((resolve) => setTimeout(resolve, 300));"
+                .into(),
+            ..Default::default()
+        };
+        let quick = || RunScriptArgs {
+            code: "1 + 1".into(),
+            expr: true,
+            ..Default::default()
+        };
+
+        let mut conn = sidecar.connect().await.unwrap();
+        tokio::time::timeout(Duration::from_millis(50), conn.run_script_and_wait(slow()))
+            .await
+            .unwrap_err();
+
+        // The abandoned run is cancelled, and its messages never reach the runs after it.
+        let result = conn.run_script_and_wait(quick()).await.unwrap();
+        assert_eq!(result.response.return_value, Some(json!(2)));
+        assert!(result.logs.is_empty(), "{:?}", result.logs);
+        assert!(result.other.is_empty(), "{:?}", result.other);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let result = conn.run_script_and_wait(quick()).await.unwrap();
+        assert!(result.other.is_empty(), "{:?}", result.other);
+        assert!(conn.abandoned_runs.is_empty());
+
+        // The pool repairs a connection that comes back with an abandoned run, instead of
+        // throwing it away.
+        tokio::time::timeout(Duration::from_millis(50), conn.run_script_and_wait(slow()))
+            .await
+            .unwrap_err();
+        let pid = conn.worker_pid();
+        drop(conn);
+        let mut conn = sidecar.connect().await.unwrap();
+        assert_eq!(conn.worker_pid(), pid);
+        assert!(!conn.dirty);
+        let manager = sidecar.pool.manager();
+        assert_eq!(
+            manager.recycle_success.load(Ordering::Relaxed),
+            manager.recycle_calls.load(Ordering::Relaxed)
+        );
+        let result = conn.run_script_and_wait(quick()).await.unwrap();
+        assert!(
+            result.logs.is_empty() && result.other.is_empty(),
+            "{result:?}"
+        );
+        drop(conn);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn stale_messages() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut conn = sidecar.connect().await.unwrap();
+
+        let first = conn
+            .run_script_and_wait(RunScriptArgs {
+                code: "setTimeout(() => { throw new Error('late'); }, 50);".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let ping = conn.ping().await.unwrap();
+
+        // Neither the first run's async error nor the pong belong to the second run.
+        let second = conn
+            .run_script_and_wait(RunScriptArgs {
+                code: "await new Promise((resolve) => setTimeout(resolve, 300));".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(second.other.is_empty(), "{:?}", second.other);
+
+        let stale = conn.take_stale_messages();
+        assert_eq!(stale.len(), 2, "{stale:?}");
+        assert!(stale.iter().any(|message| message.request_id == ping
+            && matches!(message.data, WorkerToHostMessageData::Pong(_))));
+        assert!(stale
+            .iter()
+            .any(|message| message.request_id == first.response.request_id
+                && matches!(message.data, WorkerToHostMessageData::AsyncError(_))));
+        assert!(conn.take_stale_messages().is_empty());
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn strict_messages() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .strict_messages(true)
+            .build()
+            .await
+            .unwrap();
+        let mut conn = sidecar.connect().await.unwrap();
+
+        let ping = conn.ping().await.unwrap();
+        let err = conn
+            .run_script_and_wait(RunScriptArgs {
+                code: "await new Promise((resolve) => setTimeout(resolve, 100));".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::UnexpectedMessage { request_id, message_type }
+                    if request_id == ping
+                        && message_type == WorkerToHostMessageData::Pong(Default::default())
+                            .message_type()
+            ),
+            "{err:?}"
+        );
+
+        // The abandoned run's messages don't show up in the next one.
+        let result = conn
+            .run_script_and_wait(RunScriptArgs {
+                code: "2".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(2)));
+        assert!(result.other.is_empty(), "{:?}", result.other);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn websocket_token() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut server = JsSidecar::builder()
+            .num_workers(1)
+            .websocket_addr(([127, 0, 0, 1], port).into())
+            .websocket_token("secret")
+            .build()
+            .await
+            .unwrap();
+
+        let url = format!("ws://127.0.0.1:{port}");
+        for token in [None, Some("wrong".to_string())] {
+            let address = WorkerAddress::WebSocket {
+                url: url.clone(),
+                token,
+            };
+            assert!(matches!(
+                address.connect().await,
+                Err(Error::ConnectWorker(_))
+            ));
+        }
+
+        let mut sidecar = JsSidecar::builder()
+            .worker_url(url)
+            .websocket_token("secret")
+            .build()
+            .await
+            .unwrap();
+        let value: i32 = sidecar.eval("1 + 1").await.unwrap();
+        assert_eq!(value, 2);
+
+        sidecar.close().await;
+        server.close().await;
+    }
+
+    #[tokio::test]
+    async fn websocket_transport() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut server = JsSidecar::builder()
+            .num_workers(1)
+            .websocket_addr(([127, 0, 0, 1], port).into())
+            .build()
+            .await
+            .unwrap();
+
+        let mut sidecar = JsSidecar::builder()
+            .worker_url(format!("ws://127.0.0.1:{port}"))
+            .build()
+            .await
+            .unwrap();
+        assert!(sidecar.socket_path().is_none());
+
+        let mut conn = sidecar.connect().await.unwrap();
+        let result = conn
+            .run_script_and_wait(RunScriptArgs {
+                code: "console.log('over websocket'); globalThis.output = 'x'.repeat(100000);"
+                    .into(),
+                return_keys: vec!["output".to_string()],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.logs[0].message, json!(["over websocket"]));
+        assert_eq!(
+            result.response.globals["output"].as_str().unwrap().len(),
+            100000
+        );
+        drop(conn);
+
+        let value: i32 = sidecar.eval("1 + 1").await.unwrap();
+        assert_eq!(value, 2);
+
+        sidecar.close().await;
+        server.close().await;
+    }
+
+    /// Connects through an in-memory pipe that is copied to and from a worker's socket.
+    struct DuplexTransport {
+        socket_path: PathBuf,
+        connections: Arc<AtomicUsize>,
+    }
+
+    impl Transport for DuplexTransport {
+        fn connect(&self) -> BoxFuture<'_, io::Result<(ReadHalf, WriteHalf)>> {
+            Box::pin(async move {
+                let mut socket = tokio::net::UnixStream::connect(&self.socket_path).await?;
+                let (local, mut remote) = tokio::io::duplex(64 * 1024);
+                tokio::spawn(async move {
+                    tokio::io::copy_bidirectional(&mut remote, &mut socket)
+                        .await
+                        .ok();
+                });
+                self.connections.fetch_add(1, Ordering::Relaxed);
+                let (read, write) = tokio::io::split(local);
+                Ok((Box::new(read) as ReadHalf, Box::new(write) as WriteHalf))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_transport() {
+        let mut server = JsSidecar::new(Some(1)).await.unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let mut sidecar = JsSidecar::builder()
+            .transport(DuplexTransport {
+                socket_path: server.socket_path().unwrap().to_path_buf(),
+                connections: connections.clone(),
+            })
+            .build()
+            .await
+            .unwrap();
+        assert!(sidecar.socket_path().is_none());
+
+        let result = sidecar
+            .run(RunScriptArgs {
+                code: "console.log('over a pipe'); globalThis.output = 'x'.repeat(100000);".into(),
+                return_keys: vec!["output".to_string()],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.logs[0].message, json!(["over a pipe"]));
+        assert_eq!(
+            result.response.globals["output"].as_str().unwrap().len(),
+            100000
+        );
+        assert!(connections.load(Ordering::Relaxed) > 0);
+
+        sidecar.close().await;
+        server.close().await;
+    }
+
+    /// Accepts connections and never answers.
+    struct SilentTransport;
+
+    impl Transport for SilentTransport {
+        fn connect(&self) -> BoxFuture<'_, io::Result<(ReadHalf, WriteHalf)>> {
+            Box::pin(async move {
+                let (local, remote) = tokio::io::duplex(64 * 1024);
+                // Keep the other end open, so that the handshake waits rather than failing.
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_secs(30)).await;
+                    drop(remote);
+                });
+                let (read, write) = tokio::io::split(local);
+                Ok((Box::new(read) as ReadHalf, Box::new(write) as WriteHalf))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_transport_handshake_timeout() {
+        let mut sidecar = JsSidecar::builder()
+            .transport(SilentTransport)
+            .pool_recycle_timeout(Duration::from_millis(200))
+            .build()
+            .await
+            .unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(5), sidecar.connect())
+            .await
+            .expect("Opening the connection should time out");
+        let Err(Error::Pool(err)) = result else {
+            panic!("Expected a pool error, saw {:?}", result.err());
+        };
+        assert!(
+            matches!(*err, deadpool::managed::PoolError::Backend(Error::Timeout)),
+            "Expected a timeout, saw {err:?}"
+        );
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn management_api() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .management_addr(([127, 0, 0, 1], port).into())
+            .build()
+            .await
+            .unwrap();
+
+        let mut conn = sidecar.connect().await.unwrap();
+        conn.run_script_and_wait(RunScriptArgs {
+            code: "globalThis.x = 1".into(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        // The management server starts alongside the workers, so it may not be ready yet.
+        let mut health = sidecar.health().await;
+        for _ in 0..50 {
+            if health.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            health = sidecar.health().await;
+        }
+        assert_eq!(health.unwrap().workers, 1);
+
+        let stats = sidecar.worker_stats().await.unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].connections, 1);
+        assert_eq!(stats[0].contexts, 1);
+        assert_eq!(stats[0].requests_handled, 1);
+        assert!(stats[0].memory.heap_used > 0);
+        drop(conn);
+        sidecar.close().await;
+
+        let sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let err = sidecar.health().await.unwrap_err();
+        assert!(matches!(err, Error::Management(_)));
+    }
+
+    #[tokio::test]
+    async fn debug_with_inspector() {
+        use tokio_tungstenite::tungstenite::Message;
+
+        /// Attach like DevTools does, check that the script pauses, and resume it.
+        async fn attach_and_resume(url: &str) {
+            let (mut debugger, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+            debugger
+                .send(Message::text(
+                    json!({ "id": 1, "method": "Debugger.enable" }).to_string(),
+                ))
+                .await
+                .unwrap();
+
+            // A client this quick can get its messages in before the worker starts waiting, so
+            // keep telling it to continue until it pauses.
+            let mut retry = tokio::time::interval(Duration::from_millis(100));
+            loop {
+                tokio::select! {
+                    _ = retry.tick() => {
+                        debugger
+                            .send(Message::text(
+                                json!({ "id": 2, "method": "Runtime.runIfWaitingForDebugger" })
+                                    .to_string(),
+                            ))
+                            .await
+                            .unwrap();
+                    }
+                    message = debugger.next() => {
+                        let message = message.unwrap().unwrap();
+                        let message: serde_json::Value =
+                            serde_json::from_str(message.to_text().unwrap()).unwrap();
+                        if message["method"] == "Debugger.paused" {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            debugger
+                .send(Message::text(
+                    json!({ "id": 3, "method": "Debugger.resume" }).to_string(),
+                ))
+                .await
+                .unwrap();
+        }
+
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        assert!(sidecar.inspector_url().is_none());
+        let mut events = sidecar.subscribe();
+
+        let mut conn = sidecar.connect().await.unwrap();
+        let debug_run = |code: &'static str| RunScriptArgs {
+            code: code.into(),
+            expr: true,
+            debug: true,
+            ..Default::default()
+        };
+
+        // The second debug run waits for a debugger again, after the first one has gone.
+        for (code, expected) in [("1 + 1", 2), ("2 + 2", 4)] {
+            let (result, _) = tokio::join!(conn.run_script_and_wait(debug_run(code)), async {
+                let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+                    .await
+                    .expect("Expected the run to wait for a debugger")
+                    .unwrap();
+                let SidecarEvent::DebuggerWaiting { url, .. } = event else {
+                    panic!("Expected DebuggerWaiting event");
+                };
+                assert_eq!(sidecar.inspector_url(), Some(url.clone()));
+                attach_and_resume(&url).await;
+            });
+
+            let result = result.unwrap();
+            assert_eq!(result.response.return_value, Some(json!(expected)));
+            assert!(matches!(
+                result.other[..],
+                [WorkerToHostMessageData::DebuggerWaiting(_)]
+            ));
+        }
+
+        drop(conn);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn cpu_profile() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut conn = sidecar.connect().await.unwrap();
+
+        let result = conn
+            .run_script_and_wait(RunScriptArgs {
+                code: "function busy() { let x = 0; for (let i = 0; i < 1e6; i++) { x += i; } return x; } busy()".into(),
+                expr: true,
+                profile: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(result.response.return_value, Some(json!(499999500000u64)));
+        let profile = result.cpu_profile.expect("CPU profile");
+        assert_eq!(profile.request_id, result.response.request_id);
+        let profile: serde_json::Value = serde_json::from_str(&profile.profile).unwrap();
+        let functions = profile["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|node| node["callFrame"]["functionName"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert!(functions.contains(&"busy"), "{functions:?}");
+        assert!(result.other.is_empty());
+
+        // Runs without the option don't get a profile.
+        let result = conn
+            .run_script_and_wait(RunScriptArgs {
+                code: "1".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(result.cpu_profile.is_none());
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn heap_snapshot() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut conn = sidecar.connect().await.unwrap();
+
+        conn.run_script_and_wait(RunScriptArgs {
+            code: "class LeakyThing {}; globalThis.leaks = [new LeakyThing()]".into(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let mut output = Vec::new();
+        let size = conn.heap_snapshot(&mut output).await.unwrap();
+        assert_eq!(size, output.len() as u64);
+
+        let snapshot: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert!(snapshot["snapshot"]["node_count"].as_u64().unwrap() > 0);
+        let strings = snapshot["strings"].as_array().unwrap();
+        assert!(strings.contains(&json!("LeakyThing")));
+
+        // The connection is still usable afterward.
+        let result = conn
+            .run_script_and_wait(RunScriptArgs {
+                code: "leaks.length".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(1)));
+
+        sidecar.close().await;
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn process_settings() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .cpu_affinity([0])
+            .nice(5)
+            .build()
+            .await
+            .unwrap();
+
+        // The worker inherits the settings from the Node.js process.
+        let pid = sidecar.connect().await.unwrap().worker_pid().unwrap();
+        let status = std::fs::read_to_string(format!("/proc/{pid}/status")).unwrap();
+        assert!(status.contains("Cpus_allowed_list:\t0\n"), "{status}");
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).unwrap();
+        // The fields after the command name, which is in parentheses, start with the state.
+        let fields = stat
+            .rsplit_once(") ")
+            .unwrap()
+            .1
+            .split(' ')
+            .collect::<Vec<_>>();
+        assert_eq!(fields[16], "5");
+        sidecar.close().await;
+
+        let result = JsSidecar::builder()
+            .num_workers(1)
+            .cgroup("/nonexistent/cgroup")
+            .build()
+            .await;
+        assert!(matches!(result, Err(Error::StartWorker(_))));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn hardened() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .shared_memory_frames(1024)
+            .hardened(Hardening::default())
+            .build()
+            .await
+            .unwrap();
+
+        let mut connection = sidecar.connect().await.unwrap();
+        let pid = connection.worker_pid().unwrap();
+        let status = std::fs::read_to_string(format!("/proc/{pid}/status")).unwrap();
+        assert!(status.contains("NoNewPrivs:\t1\n"), "{status}");
+        assert!(status.contains("Seccomp:\t2\n"), "{status}");
+
+        // Large payloads go through the shared memory directory, which Node.js can still write to.
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "'x'.repeat(4096)".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!("x".repeat(4096))));
+        drop(connection);
+        sidecar.close().await;
+
+        // Listening for WebSocket connections needs the network, so only Unix sockets are
+        // refused, and scripts still can't reach other services through their socket files.
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .websocket_addr("127.0.0.1:0".parse().unwrap())
+            .hardened(Hardening::default())
+            .build()
+            .await
+            .unwrap();
+        let connection = sidecar.connect().await.unwrap();
+        let pid = connection.worker_pid().unwrap();
+        let status = std::fs::read_to_string(format!("/proc/{pid}/status")).unwrap();
+        assert!(status.contains("Seccomp:\t2\n"), "{status}");
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn cgroup_limits() {
+        // A directory that stands in for the cgroup, since the tests may not be allowed to create
+        // real ones.
+        let cgroup = tempfile::tempdir().unwrap();
+        std::fs::write(cgroup.path().join("cgroup.procs"), "").unwrap();
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .cgroup(cgroup.path())
+            .cgroup_limits(CgroupLimits {
+                memory_max: Some(512 << 20),
+                cpus: Some(0.5),
+            })
+            .build()
+            .await
+            .unwrap();
+        let read = |name| std::fs::read_to_string(cgroup.path().join(name)).unwrap();
+        assert_eq!(read("memory.max"), "536870912");
+        assert_eq!(read("cpu.max"), "50000 100000");
+
+        let mut conn = sidecar.connect().await.unwrap();
+        let pid = conn.worker_pid().unwrap();
+        let events = cgroup.path().join("memory.events");
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            std::fs::write(events, "oom 1\noom_kill 1\n").unwrap();
+            nix::sys::signal::kill(
+                nix::unistd::Pid::from_raw(pid as i32),
+                nix::sys::signal::SIGKILL,
+            )
+            .unwrap();
+        });
+        let err = conn
+            .run_script_and_wait(RunScriptArgs {
+                code: "while (true) {}".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::CgroupOutOfMemory), "{err:?}");
+        drop(conn);
+        sidecar.close().await;
+
+        let result = JsSidecar::builder()
+            .num_workers(1)
+            .cgroup_limits(CgroupLimits::default())
+            .build()
+            .await;
+        assert!(matches!(result, Err(Error::StartWorker(_))));
+    }
+
+    #[tokio::test]
+    async fn worker_lifecycle_events() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut events = sidecar.subscribe();
+        let mut conn = sidecar.connect().await.unwrap();
+
+        // An uncaught exception that can't be traced back to a context crashes the worker.
+        conn.run_script_and_wait(RunScriptArgs {
+            code: r#"
+                signal.addEventListener('abort', () => { throw 'boom'; });
+                await new Promise(() => {});
+            "#
+            .into(),
+            timeout_ms: Some(50),
+            ..Default::default()
+        })
+        .await
+        .ok();
+
+        async fn next_event(events: &mut SidecarEvents) -> SidecarEvent {
+            loop {
+                let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+                    .await
+                    .expect("Timed out waiting for event")
+                    .unwrap();
+                match event {
+                    SidecarEvent::WorkerStdout(_) | SidecarEvent::WorkerStderr(_) => continue,
+                    event => return event,
+                }
+            }
+        }
+
+        let SidecarEvent::WorkerCrashed { pid, report } = next_event(&mut events).await else {
+            panic!("Expected WorkerCrashed event");
+        };
+        assert!(report.contains("boom"), "{report}");
+
+        let SidecarEvent::WorkerExited {
+            pid: exited_pid,
+            code,
+            signal: signal_dbg,
+            ..
+        } = next_event(&mut events).await
+        else {
+            panic!("Expected WorkerExited event");
+        };
+        assert_eq!(exited_pid, pid);
+        assert_eq!(code, Some(1), "{:?}", signal_dbg);
+
+        let SidecarEvent::WorkerStarted { pid: new_pid } = next_event(&mut events).await else {
+            panic!("Expected WorkerStarted event");
+        };
+        assert_ne!(new_pid, pid);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn connection_retirement() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .max_requests_per_connection(2)
+            .max_connection_age(Duration::from_secs(1))
+            .build()
+            .await
+            .unwrap();
+
+        let mut conn = sidecar.connect().await.unwrap();
+        conn.run_script_and_wait(RunScriptArgs::default())
+            .await
+            .unwrap();
+        drop(conn);
+
+        let mut conn = sidecar.connect().await.unwrap();
+        assert_eq!(conn.run_count(), 1, "connection is reused");
+        conn.run_script_and_wait(RunScriptArgs::default())
+            .await
+            .unwrap();
+        drop(conn);
+
+        let mut conn = sidecar.connect().await.unwrap();
+        assert_eq!(conn.run_count(), 0, "connection retired after 2 runs");
+        conn.run_script_and_wait(RunScriptArgs::default())
+            .await
+            .unwrap();
+        drop(conn);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let conn = sidecar.connect().await.unwrap();
+        assert_eq!(conn.run_count(), 0, "connection retired after max age");
+        drop(conn);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn memory_watchdog() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .memory_report_interval(Duration::from_millis(50))
+            .max_worker_heap(100 * 1024 * 1024)
+            .build()
+            .await
+            .unwrap();
+        let mut events = sidecar.subscribe();
+
+        let mut conn = sidecar.connect().await.unwrap();
+        conn.run_script_and_wait(RunScriptArgs::default())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let memory = sidecar.worker_memory();
+        assert_eq!(memory.len(), 1);
+        let (&original_pid, usage) = memory.iter().next().unwrap();
+        assert!(usage.heap_used > 0);
+
+        conn.run_script_and_wait(RunScriptArgs {
+            code: "globalThis.leak = new Array(16_000_000).fill(0.5); globalThis.done = true"
+                .into(),
+            return_keys: vec!["done".to_string()],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("worker should be retired")
+            .unwrap();
+        let SidecarEvent::WorkerRetired { pid, heap_used } = event else {
+            panic!("Expected WorkerRetired event, saw {event:?}");
+        };
+        assert_eq!(pid, original_pid);
+        assert!(heap_used > 100 * 1024 * 1024);
+        drop(conn);
+
+        // The replacement worker takes over.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let value: i32 = sidecar.eval("1 + 1").await.unwrap();
+        assert_eq!(value, 2);
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn session_affinity() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(2)
+            .max_session_keys(2)
+            .build()
+            .await
+            .unwrap();
+
+        async fn count(conn: &mut KeyedConnection) -> Option<serde_json::Value> {
+            let args = RunScriptArgs {
+                code: "globalThis.count = (globalThis.count ?? 0) + 1".into(),
+                expr: true,
+                ..Default::default()
+            };
+            conn.run_script_and_wait(args)
+                .await
+                .unwrap()
+                .response
+                .return_value
+        }
+
+        let mut a = sidecar.connect_for_key("a").await.unwrap();
+        assert!(!a.resumed());
+        assert_eq!(count(&mut a).await, Some(json!(1)));
+
+        // The key is in use, so this gets a separate context.
+        let mut a2 = sidecar.connect_for_key("a").await.unwrap();
+        assert!(!a2.resumed());
+        assert_eq!(count(&mut a2).await, Some(json!(1)));
+        drop(a);
+        drop(a2);
+
+        let mut a = sidecar.connect_for_key("a").await.unwrap();
+        assert!(a.resumed());
+        assert_eq!(a.key(), "a");
+        assert_eq!(count(&mut a).await, Some(json!(2)));
+
+        let mut b = sidecar.connect_for_key("b").await.unwrap();
+        assert!(!b.resumed());
+        assert_eq!(count(&mut b).await, Some(json!(1)));
+        assert_eq!(count(&mut a).await, Some(json!(3)));
+        drop(b);
+        drop(a);
+
+        // Going over the key limit drops the least recently used key.
+        drop(sidecar.connect_for_key("c").await.unwrap());
+        let mut b = sidecar.connect_for_key("b").await.unwrap();
+        assert!(!b.resumed());
+        assert_eq!(count(&mut b).await, Some(json!(1)));
+        drop(b);
+
+        // Returning the connection to the pool ends the session.
+        let b = sidecar.connect_for_key("b").await.unwrap();
+        assert!(b.resumed());
+        drop(b.into_pool_connection());
+        assert!(!sidecar.connect_for_key("b").await.unwrap().resumed());
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn session_evicted_when_pool_exhausted() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .pool_max_size(2)
+            .pool_wait_timeout(Duration::from_secs(1))
+            .build()
+            .await
+            .unwrap();
+
+        drop(sidecar.connect_for_key("a").await.unwrap());
+        drop(sidecar.connect_for_key("b").await.unwrap());
+
+        // Both of the pool's connections are parked for keys, so "a", which was used least
+        // recently, gives its connection back instead of the checkout timing out.
+        let held = sidecar.connect().await.unwrap();
+        drop(held);
+        assert!(sidecar.connect_for_key("b").await.unwrap().resumed());
+        assert!(!sidecar.connect_for_key("a").await.unwrap().resumed());
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn dedicated_connection() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .pool_max_size(1)
+            .build()
+            .await
+            .unwrap();
+
+        let mut dedicated = sidecar.dedicated_connection().await.unwrap();
+        // The pool is full, but the dedicated connection isn't part of it.
+        let mut pooled = sidecar.connect().await.unwrap();
+        assert_eq!(sidecar.pool.status().size, 1);
+
+        for expected in 1..=2 {
+            let result = dedicated
+                .run_script_and_wait(RunScriptArgs {
+                    code: "globalThis.count = (globalThis.count ?? 0) + 1".into(),
+                    expr: true,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(result.response.return_value, Some(json!(expected)));
+        }
+
+        let result = pooled
+            .run_script_and_wait(RunScriptArgs {
+                code: "typeof count".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!("undefined")));
+
+        drop(pooled);
+        drop(dedicated);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn extended_values() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+
+        let result = sidecar
+            .run(RunScriptArgs {
+                code: r##"
+                    if (!(input instanceof Date)) throw new Error('input is not a Date');
+                    globalThis.output = new Map([['when', new Date(input.getTime() + 1000)]]);
+                    export default [2n ** 64n, new Set([undefined]), new Uint16Array([1, 256])];
+                "##
+                .into(),
+                globals: [("input".into(), JsValue::Date(1718000000000.0).to_tagged())]
+                    .into_iter()
+                    .collect(),
+                return_keys: vec!["output".to_string()],
+                extended_values: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            result.response.global_js_value("output").unwrap(),
+            Some(JsValue::Map(vec![(
+                JsValue::String("when".to_string()),
+                JsValue::Date(1718000001000.0)
+            )]))
+        );
+        assert_eq!(
+            result.response.return_js_value().unwrap(),
+            Some(JsValue::Array(vec![
+                JsValue::BigInt("18446744073709551616".to_string()),
+                JsValue::Set(vec![JsValue::Undefined]),
+                JsValue::TypedArray {
+                    kind: TypedArrayKind::Uint16,
+                    bytes: vec![1, 0, 0, 1],
+                },
+            ]))
+        );
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn log_namespace_filter() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let args = RunScriptArgs {
+            code: r##"
+                console.log('[db] query');
+                console.log('[http] request');
+                logger('db').info('from logger');
+                console.log('no namespace');
+            "##
+            .into(),
+            log_namespaces: vec!["db".to_string()],
+            ..Default::default()
+        };
+        let result = connection.run_script_and_wait(args).await.unwrap();
+
+        let logs = &result.logs;
+        assert_eq!(logs.len(), 3);
+        assert_eq!(logs[0].namespace.as_deref(), Some("db"));
+        assert_eq!(logs[1].message, json!(["from logger"]));
+        assert_eq!(logs[1].namespace.as_deref(), Some("db"));
+        assert_eq!(logs[2].namespace, None);
+        assert_eq!(result.logs_in_namespace("db").count(), 2);
+        assert_eq!(result.logs_at_level(LogLevel::Info).count(), 3);
+        assert_eq!(result.logs_at_level(LogLevel::Warn).count(), 0);
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn console_handler() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let handler = FunctionDef {
+            name: "sampled".into(),
+            params: vec!["entry".into(), "forward".into()],
+            code: r#"
+                globalThis.seen = (globalThis.seen ?? 0) + 1;
+                if (seen % 2 === 0) return;
+                forward(entry.level, [...entry.args, { seen }], entry.namespace);
+            "#
+            .into(),
+        };
+        let args = RunScriptArgs {
+            code: r#"
+                for (let i = 0; i < 4; i++) {
+                    logger('job').warn('step', i);
+                }
+            "#
+            .into(),
+            functions: vec![handler],
+            console_handler: Some("sampled".into()),
+            ..Default::default()
+        };
+        let result = connection.run_script_and_wait(args).await.unwrap();
+        let messages = result.logs.iter().map(|l| &l.message).collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                &json!(["step", 0, { "seen": 1 }]),
+                &json!(["step", 2, { "seen": 3 }])
+            ]
+        );
+        assert!(result.logs.iter().all(|l| l.level == LogLevel::Warn));
+        assert_eq!(result.logs[0].namespace.as_deref(), Some("job"));
+
+        // The handler only applies to the run that names it.
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "console.log('plain')".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.logs[0].message, json!(["plain"]));
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn min_log_level() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .min_log_level(LogLevel::Info)
+            .build()
+            .await
+            .unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let code = r#"
+            console.debug('debug');
+            console.log('log');
+            console.warn('warn');
+            console.error('error');
+            stderr.write('written\n');
+            stderr.write(new Uint8Array([98, 121, 116, 101, 115]));
+        "#;
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: code.into(),
+                min_log_level: Some(LogLevel::Warn),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let logs = result
+            .logs
+            .iter()
+            .map(|l| (l.level, l.message.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            logs,
+            [
+                (LogLevel::Warn, json!(["warn"])),
+                (LogLevel::Error, json!(["error"])),
+                (LogLevel::Error, json!(["written"])),
+                (LogLevel::Error, json!(["bytes"])),
+            ]
+        );
+        assert!(result.logs.iter().all(|l| l.level.is_stderr()));
+
+        // Runs that don't set a level use the sidecar's.
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: code.into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.logs.len(), 5);
+        assert!(!result.logs[0].level.is_stderr());
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn background_jobs() {
+        let mut sidecar = JsSidecar::builder().num_workers(2).build().await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+        let job = connection
+            .spawn_script(RunScriptArgs {
+                code: r#"
+                    globalThis.fromJob = true;
+                    console.log('started');
+                    await new Promise((resolve) => setTimeout(resolve, 300));
+                    export default input * 2;
+                "#
+                .into(),
+                globals: [("input".into(), json!(21))].into_iter().collect(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(Some(job.worker_pid()), connection.worker_pid());
+
+        // The job has its own context.
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "typeof fromJob".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!("undefined")));
+
+        // The job keeps running after the connection goes back to the pool.
+        drop(connection);
+        assert_eq!(job.status().await.unwrap().state, JobState::Running);
+
+        let result = job.result().await.unwrap();
+        assert_eq!(result.response.return_value, Some(json!(42)));
+        assert_eq!(result.logs[0].message, json!(["started"]));
+        assert!(matches!(
+            job.status().await,
+            Err(Error::JobNotFound { job_id, .. }) if job_id == job.id()
+        ));
+
+        let job = sidecar
+            .connect()
+            .await
+            .unwrap()
+            .spawn_script(RunScriptArgs {
+                code: r#"
+                    await new Promise((resolve) => signal.addEventListener('abort', resolve));
+                    throw signal.reason;
+                "#
+                .into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        job.cancel().await.unwrap();
+        let err = job.result().await.unwrap_err();
+        assert_eq!(err.kind(), Some(ErrorKind::Cancelled), "{err:?}");
+
+        // A handle with the right ID but without the job's token can't reach it.
+        let mut connection = sidecar.connect().await.unwrap();
+        let job = connection
+            .spawn_script(RunScriptArgs {
+                code: "await new Promise((resolve) => setTimeout(resolve, 2000));".into(),
+                priority: Priority::High,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let forged = JobHandle::new(
+            StartedJob {
+                job_id: job.id(),
+                pid: job.worker_pid(),
+                token: "00".repeat(16),
+            },
+            connection.options.clone(),
+        );
+        assert!(matches!(
+            forged.status().await,
+            Err(Error::JobNotFound { .. })
+        ));
+
+        // The job doesn't hold its priority lane, so lower-priority runs on the worker don't wait
+        // for it.
+        let start = Instant::now();
+        connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "1".into(),
+                expr: true,
+                priority: Priority::Low,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        job.cancel().await.unwrap();
+        drop(connection);
+
+        sidecar.close().await;
+    }
+
+    #[derive(Default, Clone)]
+    struct TestScheduleStore(Arc<Mutex<Vec<ScheduledScript>>>);
+
+    impl ScheduleStore for TestScheduleStore {
+        fn load(&self) -> BoxFuture<'_, ScheduleStoreResult<Vec<ScheduledScript>>> {
+            Box::pin(async { Ok(self.0.lock().unwrap().clone()) })
+        }
+
+        fn save(&self, schedule: ScheduledScript) -> BoxFuture<'_, ScheduleStoreResult<()>> {
+            self.0.lock().unwrap().push(schedule);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn remove(&self, id: u64) -> BoxFuture<'_, ScheduleStoreResult<()>> {
+            self.0.lock().unwrap().retain(|s| s.id != id);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn scheduled_scripts() {
+        let store = TestScheduleStore::default();
+        store.0.lock().unwrap().push(ScheduledScript {
+            id: 5,
+            cron: "* * * * * *".to_string(),
+            args: RunScriptArgs {
+                code: "'saved'".into(),
+                expr: true,
+                ..Default::default()
+            },
+        });
+        // A saved schedule that no longer parses is skipped rather than failing the build.
+        store.0.lock().unwrap().push(ScheduledScript {
+            id: 2,
+            cron: "* * *".to_string(),
+            args: RunScriptArgs::default(),
+        });
+
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .schedule_store(store.clone())
+            .build()
+            .await
+            .unwrap();
+        let mut events = sidecar.subscribe();
+
+        let id = sidecar
+            .schedule(
+                "*/1 * * * * *",
+                RunScriptArgs {
+                    code: "throw new Error('scheduled failure')".into(),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(id, 6);
+        assert_eq!(store.0.lock().unwrap().len(), 3);
+
+        let (mut saved_ran, mut failure_ran) = (false, false);
+        while !(saved_ran && failure_ran) {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+                .await
+                .expect("scheduled runs")
+                .unwrap();
+            let SidecarEvent::ScheduledRun { id, due, result } = event else {
+                continue;
+            };
+            assert!(due <= std::time::SystemTime::now());
+            match id {
+                5 => {
+                    let result = result.as_ref().as_ref().unwrap();
+                    assert_eq!(result.response.return_value, Some(json!("saved")));
+                    saved_ran = true;
+                }
+                6 => {
+                    let err = result.as_ref().as_ref().unwrap_err();
+                    assert!(err.to_string().contains("scheduled failure"), "{err:?}");
+                    failure_ran = true;
+                }
+                id => panic!("unexpected schedule {id}"),
+            }
+        }
+
+        assert!(sidecar.unschedule(6).await.unwrap());
+        assert!(!sidecar.unschedule(6).await.unwrap());
+        let saved = store.0.lock().unwrap().clone();
+        assert_eq!(saved.iter().map(|s| s.id).collect::<Vec<_>>(), vec![5, 2]);
+
+        assert!(matches!(
+            sidecar
+                .schedule("0 0 31 2 *", RunScriptArgs::default())
+                .await,
+            Err(Error::InvalidSchedule(_))
+        ));
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn run_with_sink() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        // Fan the messages out to two subscribers in other tasks.
+        let (sender, _) = tokio::sync::broadcast::channel(16);
+        let subscribers = (0..2)
+            .map(|_| {
+                let mut receiver = sender.subscribe();
+                tokio::spawn(async move {
+                    let mut kinds = Vec::new();
+                    while let Ok(message) = receiver.recv().await {
+                        kinds.push(match message {
+                            WorkerToHostMessageData::Log(log) => format!("log {}", log.message),
+                            WorkerToHostMessageData::RunResponse(response) => {
+                                format!("response {}", response.return_value.unwrap())
+                            }
+                            other => format!("{other:?}"),
+                        });
+                    }
+                    kinds
+                })
+            })
+            .collect::<Vec<_>>();
+
+        connection
+            .run_script_with_sink(
+                RunScriptArgs {
+                    code: "console.log('one'); console.log('two'); export default 3;".into(),
+                    ..Default::default()
+                },
+                sender,
+            )
+            .await
+            .unwrap();
+
+        for subscriber in subscribers {
+            assert_eq!(
+                subscriber.await.unwrap(),
+                vec![r#"log ["one"]"#, r#"log ["two"]"#, "response 3"]
+            );
+        }
+
+        let mut messages = Vec::new();
+        let err = connection
+            .run_script_with_sink(
+                RunScriptArgs {
+                    code: "console.log('before'); throw new Error('failed')".into(),
+                    ..Default::default()
+                },
+                |message| messages.push(message),
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::Script(e) if e.error.message == "failed"),
+            "{err:?}"
+        );
+        assert!(matches!(&messages[..], [
+            WorkerToHostMessageData::Log(log),
+            WorkerToHostMessageData::Error(error),
+        ] if log.message == json!(["before"]) && error.message == "failed"));
+
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn string_limits() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .max_string_bytes(10)
+            .build()
+            .await
+            .unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let args = RunScriptArgs {
+            globals: [("input".into(), json!({ "name": "a long string" }))]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let err = connection.run_script_and_wait(args).await.unwrap_err();
+        assert_eq!(err.kind(), Some(ErrorKind::StringTooLong));
+        let Error::StringTooLong { path, .. } = err else {
+            panic!("Expected StringTooLong error, saw {err:#?}");
+        };
+        assert_eq!(path, "globals.input.name");
+
+        let args = RunScriptArgs {
+            code: "output = { list: ['ok', 'a long string'] }".into(),
+            globals: [("output".into(), json!(null))].into_iter().collect(),
+            return_keys: vec!["output".to_string()],
+            ..Default::default()
+        };
+        let err = connection.run_script_and_wait(args).await.unwrap_err();
+        assert_eq!(err.kind(), Some(ErrorKind::StringTooLong));
+        let Error::Script(err) = err else {
+            panic!("Expected Script error, saw {err:#?}");
+        };
+        assert_eq!(
+            err.error.message,
+            "String at globals.output.list[1] is 13 bytes, exceeding the limit of 10"
+        );
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn unpaired_surrogate() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let args = RunScriptArgs {
+            code: "'abc\\uD800'".into(),
+            expr: true,
+            ..Default::default()
+        };
+        let err = connection
+            .run_script_and_wait(args.clone())
+            .await
+            .unwrap_err();
+        let Error::Script(err) = err else {
+            panic!("Expected Script error, saw {err:#?}");
+        };
+        assert_eq!(
+            err.error.message,
+            "String at returnValue contains an unpaired UTF-16 surrogate"
+        );
+
+        let args = RunScriptArgs {
+            replace_invalid_unicode: true,
+            ..args
+        };
+        let result = connection.run_script_and_wait(args).await.unwrap();
+        assert_eq!(result.response.return_value, Some(json!("abc\u{FFFD}")));
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn error() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let args = RunScriptArgs {
+            code: r##"
+                throw new Error('This is an error');
+            "##
+            .into(),
+            ..Default::default()
+        };
+        let result = connection.run_script_and_wait(args).await.unwrap_err();
+
+        let Error::Script(err) = result else {
+            panic!("Expected Script error, saw {result:#?}");
+        };
+
+        assert_eq!(err.error.message, "This is an error");
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn error_annotations() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let args = RunScriptArgs {
+            code: "throw new Error('failed');".into(),
+            annotations: [("feature".to_string(), "search".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let result = connection.run_script_and_wait(args).await.unwrap_err();
+
+        let Error::Script(err) = result else {
+            panic!("Expected Script error, saw {result:#?}");
+        };
+
+        assert_eq!(err.error.annotations["feature"], "search");
+        let stack = err.error.stack.unwrap();
+        assert!(
+            stack.ends_with("[annotations: feature=search]"),
+            "stack was {stack}"
+        );
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn syntax_error() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let args = RunScriptArgs {
+            code: r##"
+                23jklsdfhio
+            "##
+            .into(),
+            ..Default::default()
+        };
+        let result = connection.run_script_and_wait(args).await.unwrap_err();
+
+        let Error::Script(err) = result else {
+            panic!("Expected Script error, saw {result:#?}");
+        };
+
+        assert_eq!(err.error.message, "Invalid or unexpected token");
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn custom_runtime_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .runtime_dir(dir.path())
+            .build()
+            .await
+            .unwrap();
+
+        // The socket is in the sidecar's own directory inside the runtime directory.
+        assert_eq!(
+            sidecar
+                .socket_path()
+                .and_then(|path| path.parent()?.parent()),
+            Some(dir.path())
+        );
+
+        let mut connection = sidecar.connect().await.unwrap();
+        let args = RunScriptArgs {
+            code: "2 + 2".into(),
+            expr: true,
+            ..Default::default()
+        };
+        let result = connection.run_script_and_wait(args).await.unwrap();
+        assert_eq!(result.response.return_value, Some(json!(4)));
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn socket_path_too_long() {
+        let dir = std::env::temp_dir().join("a".repeat(120));
+        let result = JsSidecar::builder().socket_dir(dir).build().await;
+        assert!(matches!(result, Err(Error::StartWorker(_))));
+    }
+
+    #[tokio::test]
+    async fn idle_tracking() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        let before = Instant::now();
+        connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "1".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        assert!(connection.last_send() >= before);
+        assert!(connection.last_receive() >= connection.last_send());
+        assert_eq!(connection.last_activity(), connection.last_receive());
+        assert!(!connection.is_idle(Duration::from_secs(10)));
+        assert!(connection.is_idle(Duration::ZERO));
+
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn idle_eviction() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .idle_timeout(Duration::from_millis(200))
+            .build()
+            .await
+            .unwrap();
+
+        let connection = sidecar.connect().await.unwrap();
+        drop(connection);
+        assert_eq!(sidecar.pool.status().size, 1);
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(sidecar.pool.status().size, 0);
+
+        // A new connection is created on demand
+        let mut connection = sidecar.connect().await.unwrap();
+        connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "1".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn min_idle() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .min_idle(2)
+            .idle_timeout(Duration::from_millis(200))
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(sidecar.pool.status().available, 2);
+
+        // Checked out connections are replaced.
+        let connection = sidecar.connect().await.unwrap();
+        assert_eq!(sidecar.pool.status().available, 1);
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(sidecar.pool.status().available, 2);
+
+        // The idle timeout only closes the connections past the minimum.
+        drop(connection);
+        assert_eq!(sidecar.pool.status().available, 3);
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(sidecar.pool.status().available, 2);
+        sidecar.close().await;
+
+        // Without an idle timeout, so that the warmed up connections aren't closed right away.
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .min_idle(2)
+            .build()
+            .await
+            .unwrap();
+        sidecar.warm_up(4).await.unwrap();
+        assert_eq!(sidecar.pool.status().available, 4);
+        // The background task doesn't take any of them out of the pool.
+        tokio::time::sleep(MIN_IDLE_CHECK_INTERVAL * 2).await;
+        assert_eq!(sidecar.pool.status().available, 4);
+        assert_eq!(sidecar.pool.status().size, 4);
+
+        // Each connection created its context before joining the pool, and keeps it for its
+        // first run.
+        let mut connection = sidecar.connect().await.unwrap();
+        assert!(connection.warm_context);
+        assert_eq!(connection.run_count(), 0);
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "globalThis.seen = 1".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(1)));
+        assert!(!connection.warm_context);
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn registered_scripts() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .pool_max_size(1)
+            .build()
+            .await
+            .unwrap();
+        let code = format!(
+            "const padding = '{}'; export default input * 3;",
+            "x".repeat(10_000)
+        );
+        sidecar.registry().register("triple", 1, code).unwrap();
+        sidecar
+            .registry()
+            .register("triple", 2, "export default input * 30;")
+            .unwrap();
+
+        let globals = |input: i32| [("input".into(), json!(input))].into_iter().collect();
+
+        // The code only counts as sent once a run of it succeeds.
+        let checked = format!(
+            "const padding = '{}'; if (input < 0) throw new Error('negative'); export default input;",
+            "x".repeat(10_000)
+        );
+        sidecar.registry().register("checked", 1, checked).unwrap();
+        let failed = sidecar.run_registered("checked", 1, globals(-1)).await;
+        assert!(matches!(failed, Err(Error::Script(_))), "{failed:?}");
+        let resent = sidecar
+            .run_registered("checked", 1, globals(1))
+            .await
+            .unwrap();
+        assert!(resent.wire.request_bytes > 10_000, "{:?}", resent.wire);
+
+        let first = sidecar
+            .run_registered("triple", 1, globals(2))
+            .await
+            .unwrap();
+        assert_eq!(first.response.return_value, Some(json!(6)));
+        assert!(first.wire.request_bytes > 10_000, "{:?}", first.wire);
+
+        // The connection already sent the code, so the worker runs the copy that it kept.
+        let second = sidecar
+            .run_registered("triple", 1, globals(4))
+            .await
+            .unwrap();
+        assert_eq!(second.response.return_value, Some(json!(12)));
+        assert!(second.wire.request_bytes < 1_000, "{:?}", second.wire);
+
+        let other = sidecar
+            .run_registered("triple", 2, globals(4))
+            .await
+            .unwrap();
+        assert_eq!(other.response.return_value, Some(json!(120)));
 
-                }
-            }
-        });
+        assert!(matches!(
+            sidecar.run_registered("triple", 3, globals(4)).await,
+            Err(Error::ScriptNotRegistered { version: 3, .. })
+        ));
 
-        Ok(Connection {
-            stream: write_stream,
-            receiver,
-            next_id: 0,
-            next_req_id: 0,
-            recreate_context_on_next: false,
-            _task_close_tx: close_tx,
-        })
+        sidecar.close().await;
     }
 
-    /// Start running a script
-    pub async fn run_script(&mut self, mut args: RunScriptArgs) -> Result<(), Error> {
-        if self.recreate_context_on_next {
-            self.recreate_context_on_next = false;
-            args.recreate_context = true;
-        }
+    #[tokio::test]
+    async fn script_template() {
+        let mut sidecar = JsSidecar::builder().num_workers(1).build().await.unwrap();
+        let template = ScriptTemplate::new(
+            "greet",
+            &["name", "count"],
+            "await null; return `${name}`.repeat(count);",
+        )
+        .unwrap();
 
-        let message_id = self.next_id;
-        let req_id = self.next_req_id;
-        self.next_req_id += 1;
-        self.next_id += 1;
-        let message =
-            HostToWorkerMessage::new(req_id, message_id, HostToWorkerMessageData::RunScript(args));
-        message.write_to(&mut self.stream).await?;
-        Ok(())
-    }
+        // A value that would break out of a string if it were substituted into the code.
+        let name = "'); globalThis.injected = true; ('";
+        let mut connection = sidecar.connect().await.unwrap();
+        let args = template
+            .run_args([("name", json!(name)), ("count", json!(2))])
+            .unwrap();
+        let result = connection.run_script_and_wait(args).await.unwrap();
+        assert_eq!(result.response.return_value, Some(json!(name.repeat(2))));
 
-    /// Receive a message from the Node.js process
-    pub async fn receive_message(&mut self) -> Option<WorkerToHostMessage> {
-        self.receiver.recv().await
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "typeof injected".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!("undefined")));
+        drop(connection);
+
+        sidecar.close().await;
     }
 
-    /// Send a ping message to the Node.js process
-    pub async fn ping(&mut self) -> Result<u32, Error> {
-        let message_id = self.next_id;
-        let req_id = self.next_req_id;
-        self.next_req_id += 1;
-        self.next_id += 1;
-        let message = HostToWorkerMessage::new(req_id, message_id, HostToWorkerMessageData::Ping);
-        message.write_to(&mut self.stream).await?;
-        Ok(req_id)
+    #[tokio::test]
+    async fn context_pool() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .context_pool_size(2)
+            .build()
+            .await
+            .unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+
+        for i in 0..4 {
+            let result = connection
+                .run_script_and_wait(RunScriptArgs {
+                    code: r#"
+                        const leaked = typeof leak;
+                        globalThis.leak = true;
+                        [value, leaked, typeof console.log, typeof setTimeout]
+                    "#
+                    .into(),
+                    expr: true,
+                    recreate_context: true,
+                    globals: [
+                        ("value".into(), json!(i)),
+                        ("console".into(), json!("replaced")),
+                        ("setTimeout".into(), json!("replaced")),
+                    ]
+                    .into_iter()
+                    .collect(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                result.response.return_value,
+                Some(json!([i, "undefined", "function", "function"]))
+            );
+        }
+
+        // Contexts with other settings are still built on demand.
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "[typeof leak, Date.now()]".into(),
+                expr: true,
+                recreate_context: true,
+                deterministic: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(["undefined", 0])));
+
+        drop(connection);
+        sidecar.close().await;
     }
 
-    /// Run a script and wait for it to finish, accumulating console messages seen along the way.
-    pub async fn run_script_and_wait(
-        &mut self,
-        args: RunScriptArgs,
-    ) -> Result<RunScriptAndWaitResult, Error> {
-        self.run_script(args).await?;
+    #[tokio::test]
+    async fn heartbeat() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .pool_heartbeat_interval(Duration::from_millis(100))
+            .pool_recycle_timeout(Duration::from_millis(200))
+            .idle_timeout(Duration::from_millis(800))
+            .build()
+            .await
+            .unwrap();
 
-        let mut intermediate_messages = Vec::new();
+        let connection = sidecar.connect().await.unwrap();
+        let pid = connection.worker_pid().unwrap();
+        let next_req_id = connection.next_req_id;
+        let last_activity = connection.last_activity();
+        drop(connection);
 
-        while let Some(message) = self.receive_message().await {
-            match message.data {
-                WorkerToHostMessageData::RunResponse(response) => {
-                    return Ok(RunScriptAndWaitResult {
-                        response,
-                        messages: intermediate_messages,
-                    });
-                }
-                WorkerToHostMessageData::Error(error) => {
-                    return Err(Error::Script(RunScriptError {
-                        error,
-                        messages: intermediate_messages,
-                    }));
-                }
-                _ => {
-                    intermediate_messages.push(message.data);
-                }
-            }
-        }
+        // The idle connection is pinged, but heartbeats don't count as activity.
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        assert_eq!(sidecar.pool.status().size, 1);
+        let connection = sidecar.connect().await.unwrap();
+        assert!(connection.next_req_id > next_req_id + 1);
+        assert_eq!(connection.last_activity(), last_activity);
+        drop(connection);
 
-        Err(Error::ScriptEndedEarly)
+        // A worker that stops responding has its connections closed without a checkout.
+        let pid = nix::unistd::Pid::from_raw(pid as i32);
+        nix::sys::signal::kill(pid, nix::sys::signal::SIGSTOP).unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        nix::sys::signal::kill(pid, nix::sys::signal::SIGCONT).unwrap();
+        assert_eq!(sidecar.pool.status().size, 0);
+
+        let mut connection = sidecar.connect().await.unwrap();
+        connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "1".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        drop(connection);
+        sidecar.close().await;
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use serde_json::json;
+    #[tokio::test]
+    async fn run_with_pool() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
 
-    use super::*;
-    use crate::protocol::WorkerToHostMessageData;
+        let result = sidecar
+            .run(RunScriptArgs {
+                code: "1 + 2".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(3)));
 
-    // Compile error if Connection is not Send + Sync
-    #[allow(dead_code)]
-    trait IsSync: Send + Sync {}
-    impl IsSync for Connection {}
+        sidecar.close().await;
+    }
 
     #[tokio::test]
-    async fn regular_execution() {
+    async fn eval() {
         let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
-        let mut connection = sidecar.connect().await.unwrap();
 
-        let args = RunScriptArgs {
-            code: r##"
-                console.log('Hello, World!');
-                output = output + 15;
-            "##
-            .into(),
-            globals: [("output".into(), json!(5))].into_iter().collect(),
-            ..Default::default()
-        };
-        connection.run_script(args).await.unwrap();
+        let value: i32 = sidecar.eval("1 + 1").await.unwrap();
+        assert_eq!(value, 2);
 
-        let mut messages = Vec::new();
+        let value: Vec<String> = sidecar.eval("['a', 'b'].map((x) => x + x)").await.unwrap();
+        assert_eq!(value, vec!["aa", "bb"]);
 
-        while let Some(message) = connection.receive_message().await {
-            let finished = matches!(message.data, WorkerToHostMessageData::RunResponse(_));
-            println!("{message:#?}");
-            messages.push(message);
+        let value: Option<i32> = sidecar.eval("undefined").await.unwrap();
+        assert_eq!(value, None);
 
-            if finished {
-                drop(connection);
-                break;
-            }
-        }
+        let err = sidecar.eval::<i32>("'abc'").await.unwrap_err();
+        assert!(matches!(err, Error::ResultType(_)), "{err:?}");
 
-        assert_eq!(messages.len(), 2);
+        let err = sidecar.eval::<i32>("missingVariable").await.unwrap_err();
+        assert!(matches!(err, Error::Script(_)), "{err:?}");
 
-        let console_msg = &messages[0];
+        sidecar.close().await;
+    }
 
-        let WorkerToHostMessageData::Log(log) = &console_msg.data else {
-            panic!("Expected log message, saw {console_msg:#?}");
-        };
+    #[tokio::test]
+    async fn run_retries_broken_connections() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(1)
+            .run_retries(1)
+            .build()
+            .await
+            .unwrap();
 
-        assert_eq!(log.message, json!(["Hello, World!"]));
-        assert_eq!(log.level, "info");
+        // A connection whose worker has already gone fails before anything is sent, so the run
+        // can be retried safely.
+        let options = sidecar.pool.manager().options.clone();
+        let (read_stream, worker) = tokio::io::duplex(1024);
+        let mut conn =
+            Connection::new(Box::new(read_stream), Box::new(tokio::io::sink()), options).unwrap();
+        drop(worker);
+        while !conn.activity.closed.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let err = conn
+            .run_script_and_wait(RunScriptArgs::default())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::WriteStream(_)) && err.is_connection_failure(),
+            "{err:?}"
+        );
+        assert_eq!(conn.run_count(), 0);
 
-        let response_msg = &messages[1];
+        // A connection that breaks once the script was sent isn't retried, since the worker may
+        // have started running it.
+        let options = sidecar.pool.manager().options.clone();
+        let (read_stream, mut worker) = tokio::io::duplex(1024);
+        let mut conn =
+            Connection::new(Box::new(read_stream), Box::new(tokio::io::sink()), options).unwrap();
+        let mut data = b"garbage".to_vec();
+        data.extend_from_slice(&crate::wire::encode_frame(0, 0, 0x1000, b"{}", false));
+        worker.write_all(&data).await.unwrap();
+        let err = conn
+            .run_script_and_wait(RunScriptArgs::default())
+            .await
+            .unwrap_err();
+        assert!(err.is_connection_failure(), "{err:?}");
+        assert_eq!(conn.run_count(), 1);
 
-        let WorkerToHostMessageData::RunResponse(response) = &response_msg.data else {
-            panic!("Expected response message, saw {response_msg:#?}");
-        };
+        // An uncaught exception that can't be traced back to a context, such as a string thrown
+        // from an event listener, crashes the worker partway through the run. That isn't retried,
+        // since running the script again would crash the next worker too.
+        let err = sidecar
+            .run(RunScriptArgs {
+                code: r#"
+                    signal.addEventListener('abort', () => { throw 'crash'; });
+                    await new Promise(() => {});
+                "#
+                .into(),
+                timeout_ms: Some(50),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ScriptEndedEarly), "{err:?}");
+        assert!(!err.is_connection_failure());
+
+        // The worker restarts and works again.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let result = sidecar
+            .run(RunScriptArgs {
+                code: "1 + 2".into(),
+                expr: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(3)));
 
-        assert_eq!(response.globals["output"], json!(20));
-        eprintln!("Closing");
         sidecar.close().await;
     }
 
     #[tokio::test]
-    async fn expression_execution() {
-        let mut sidecar = JsSidecar::new(None).await.unwrap();
-        let mut connection = sidecar.connect().await.unwrap();
+    async fn prewarm() {
+        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
 
-        let args = RunScriptArgs {
-            code: r##"
-                output + 15
-            "##
-            .into(),
-            expr: true,
-            globals: [("output".into(), json!(5))].into_iter().collect(),
-            ..Default::default()
-        };
-        connection.run_script(args).await.unwrap();
+        let manifest: PrewarmManifest = serde_json::from_value(json!({
+            "contexts": [
+                {
+                    "name": "math",
+                    "modules": [{ "name": "math", "code": "export const double = (x) => x * 2;" }],
+                    "functions": [{ "name": "triple", "params": ["x"], "code": "return x * 3;" }],
+                },
+                {
+                    "name": "broken",
+                    "modules": [{ "name": "broken", "code": "export const = ;" }],
+                },
+            ]
+        }))
+        .unwrap();
 
-        let mut messages = Vec::new();
+        let report = sidecar.prewarm(&manifest).await;
+        assert!(!report.all_ready());
 
-        while let Some(message) = connection.receive_message().await {
-            if matches!(&message.data, WorkerToHostMessageData::Error(_)) {
-                panic!("Saw error: {message:#?}");
-            }
+        let math = &report.contexts[0];
+        assert_eq!(math.name, "math");
+        assert!(math.is_ready(), "{math:?}");
+        assert_eq!(math.ready_workers, 1);
 
-            let finished = matches!(message.data, WorkerToHostMessageData::RunResponse(_));
-            println!("{message:#?}");
-            messages.push(message);
+        let broken = &report.contexts[1];
+        assert!(!broken.is_ready());
+        assert_eq!(broken.ready_workers, 0);
+        assert_eq!(broken.errors.len(), 1);
 
-            if finished {
-                break;
-            }
+        // The prewarmed context already has the module and the function, so the run doesn't need
+        // to send them.
+        let run = RunScriptArgs {
+            code: "import { double } from 'math'; output = triple(double(2));".into(),
+            globals: [("output".into(), json!(null))].into_iter().collect(),
+            return_keys: vec!["output".to_string()],
+            prewarmed_context: Some("math".to_string()),
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            // The second run recreates its context, which takes the replacement that the worker
+            // built.
+            let result = sidecar
+                .run(RunScriptArgs {
+                    recreate_context: true,
+                    ..run.clone()
+                })
+                .await
+                .unwrap();
+            assert_eq!(result.response.globals["output"], json!(12));
         }
 
-        assert_eq!(messages.len(), 1);
+        let err = sidecar
+            .run(RunScriptArgs {
+                prewarmed_context: None,
+                recreate_context: true,
+                ..run
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Module not found"), "{err:?}");
 
-        let response_msg = &messages[0];
+        sidecar.close().await;
+    }
 
-        let WorkerToHostMessageData::RunResponse(response) = &response_msg.data else {
-            panic!("Expected response message, saw {response_msg:#?}");
-        };
+    #[tokio::test]
+    async fn prewarm_with_small_pool() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(2)
+            .pool_max_size(1)
+            .build()
+            .await
+            .unwrap();
+        let held = sidecar.connect().await.unwrap();
 
-        assert_eq!(response.return_value, Some(json!(20)));
+        let manifest = PrewarmManifest {
+            contexts: vec![PrewarmContext {
+                name: "empty".to_string(),
+                ..Default::default()
+            }],
+        };
+        let report = tokio::time::timeout(Duration::from_secs(10), sidecar.prewarm(&manifest))
+            .await
+            .expect("prewarm shouldn't wait for the pool");
+        assert!(report.all_ready(), "{report:?}");
+        assert_eq!(report.contexts[0].total_workers, 2);
 
-        drop(connection);
+        drop(held);
         sidecar.close().await;
     }
 
     #[tokio::test]
-    async fn run_script_and_wait() {
-        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
+    async fn latency_tracking() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(2)
+            .prefer_fast_workers(true)
+            .build()
+            .await
+            .unwrap();
+
         let mut connection = sidecar.connect().await.unwrap();
+        assert!(connection.latency().is_none());
+        let pid = connection.worker_pid().expect("worker pid");
 
-        let args = RunScriptArgs {
-            code: r##"
-                console.log('abc');
-                output = 15
-            "##
-            .into(),
-            globals: [("output".into(), json!(5))].into_iter().collect(),
-            ..Default::default()
-        };
-        let result = connection.run_script_and_wait(args).await.unwrap();
+        for _ in 0..3 {
+            connection
+                .run_script_and_wait(RunScriptArgs {
+                    code: "1".into(),
+                    expr: true,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
 
-        assert_eq!(result.response.globals["output"], json!(15));
-        assert_eq!(result.messages.len(), 1);
-        assert!(matches!(
-            result.messages[0],
-            WorkerToHostMessageData::Log(_)
-        ));
+        let latency = connection.latency().unwrap();
+        assert_eq!(latency.samples, 3);
+        assert!(latency.average > Duration::ZERO);
+
+        let workers = sidecar.worker_latencies();
+        assert_eq!(workers[&pid].samples, 3);
 
         drop(connection);
         sidecar.close().await;
     }
 
     #[tokio::test]
-    async fn error() {
-        let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
-        let mut connection = sidecar.connect().await.unwrap();
+    async fn prefer_idle_workers() {
+        let mut sidecar = JsSidecar::builder()
+            .num_workers(2)
+            .prefer_idle_workers(true)
+            .load_report_interval(Duration::from_millis(20))
+            .build()
+            .await
+            .unwrap();
 
-        let args = RunScriptArgs {
-            code: r##"
-                throw new Error('This is an error');
-            "##
-            .into(),
+        // Open connections to both workers and return them to the pool.
+        let mut connections = Vec::new();
+        for _ in 0..6 {
+            let mut connection = sidecar.connect().await.unwrap();
+            connection
+                .run_script_and_wait(RunScriptArgs::default())
+                .await
+                .unwrap();
+            connections.push(connection);
+        }
+        let pids = connections
+            .iter()
+            .filter_map(|c| c.worker_pid())
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(pids.len(), 2, "connections should reach both workers");
+
+        let mut busy = connections.pop().unwrap();
+        let busy_pid = busy.worker_pid().unwrap();
+        drop(connections);
+        busy.run_script(RunScriptArgs {
+            code: "await new Promise((resolve) => setTimeout(resolve, 1000))".into(),
             ..Default::default()
-        };
-        let result = connection.run_script_and_wait(args).await.unwrap_err();
+        })
+        .await
+        .unwrap();
 
-        let Error::Script(err) = result else {
-            panic!("Expected Script error, saw {result:#?}");
-        };
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let loads = sidecar.worker_loads();
+        assert_eq!(loads.len(), 2);
+        assert_eq!(loads[&busy_pid].active_runs, 1);
 
-        assert_eq!(err.error.message, "This is an error");
+        // Node.js hands out connections round-robin, so the pool alternates between the workers.
+        // The connections are kept open so that each checkout has to pass over one of the busy
+        // worker's connections.
+        let mut idle = Vec::new();
+        for _ in 0..2 {
+            let connection = sidecar.connect().await.unwrap();
+            assert_ne!(connection.worker_pid(), Some(busy_pid));
+            idle.push(connection);
+        }
 
-        drop(connection);
+        drop(idle);
+        drop(busy);
         sidecar.close().await;
     }
 
     #[tokio::test]
-    async fn syntax_error() {
+    async fn priority_lanes() {
         let mut sidecar = JsSidecar::new(Some(1)).await.unwrap();
-        let mut connection = sidecar.connect().await.unwrap();
 
-        let args = RunScriptArgs {
-            code: r##"
-                23jklsdfhio
-            "##
-            .into(),
-            ..Default::default()
-        };
-        let result = connection.run_script_and_wait(args).await.unwrap_err();
+        let mut urgent = sidecar.connect_with_priority(Priority::High).await.unwrap();
+        urgent
+            .run_script(RunScriptArgs {
+                code: "await new Promise((resolve) => setTimeout(resolve, 500))".into(),
+                priority: Priority::High,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
 
-        let Error::Script(err) = result else {
-            panic!("Expected Script error, saw {result:#?}");
-        };
+        // Other high-priority runs don't wait for the active one.
+        let start = Instant::now();
+        let result = sidecar
+            .run(RunScriptArgs {
+                code: "1 + 1".into(),
+                expr: true,
+                priority: Priority::High,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(2)));
+        assert!(start.elapsed() < Duration::from_millis(300));
 
-        assert_eq!(err.error.message, "Invalid or unexpected token");
+        // Lower-priority runs wait until it is done.
+        let start = Instant::now();
+        sidecar
+            .run(RunScriptArgs {
+                code: "1 + 1".into(),
+                expr: true,
+                priority: Priority::Low,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(300));
 
-        drop(connection);
+        drop(urgent);
         sidecar.close().await;
     }
 
@@ -545,11 +8382,8 @@ mod tests {
                 let result = connection.run_script_and_wait(args).await.unwrap();
 
                 assert_eq!(result.response.globals["output"], json!(15));
-                assert_eq!(result.messages.len(), 1);
-                assert!(matches!(
-                    result.messages[0],
-                    WorkerToHostMessageData::Log(_)
-                ));
+                assert_eq!(result.logs.len(), 1);
+                assert!(result.other.is_empty());
             })
             .collect::<Vec<_>>();
 
@@ -576,11 +8410,8 @@ mod tests {
                 let result = connection.run_script_and_wait(args).await.unwrap();
 
                 assert_eq!(result.response.globals["output"], json!(15));
-                assert_eq!(result.messages.len(), 1);
-                assert!(matches!(
-                    result.messages[0],
-                    WorkerToHostMessageData::Log(_)
-                ));
+                assert_eq!(result.logs.len(), 1);
+                assert!(result.other.is_empty());
             })
             .collect::<Vec<_>>();
 
@@ -0,0 +1,307 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::RunScriptArgs;
+
+/// How many code hashes a collector remembers by default.
+const DEFAULT_MAX_SEEN: usize = 100_000;
+
+/// The kind of code unit recorded in the corpus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeKind {
+    /// The main code of a script run in module mode
+    Script,
+    /// The main code of a script run in expression mode
+    Expression,
+    /// A [CodeModule](crate::CodeModule) passed with the script
+    Module,
+    /// A [FunctionDef](crate::FunctionDef) passed with the script
+    Function,
+}
+
+/// Information about where a piece of code came from.
+#[derive(Debug, Clone)]
+pub struct CorpusMetadata {
+    /// What type of code this is.
+    pub kind: CodeKind,
+    /// The module or function name. For scripts and expressions this is the same as `script_name`.
+    pub name: String,
+    /// The name of the script run that first contained this code.
+    pub script_name: String,
+}
+
+/// A unique piece of code executed by the sidecar.
+#[derive(Debug, Clone)]
+pub struct CorpusEntry {
+    /// The hex-encoded SHA-256 hash of the code.
+    pub hash: String,
+    /// The code itself. This is `None` when the collector is in hash-only mode.
+    pub code: Option<String>,
+    /// Information about the code.
+    pub metadata: CorpusMetadata,
+}
+
+/// Receives each unique piece of code executed by the sidecar.
+///
+/// `record` is called inline before the script is sent to the worker, so implementations should
+/// hand the entry off to a channel or buffer rather than doing slow I/O directly.
+pub trait CorpusSink: Send + Sync + 'static {
+    /// Record a newly-seen piece of code.
+    fn record(&self, entry: CorpusEntry);
+}
+
+/// Collects the corpus of code executed by the sidecar and passes each unique piece of code to
+/// a [CorpusSink]. Enable it with [JsSidecarBuilder::collect_corpus](crate::JsSidecarBuilder::collect_corpus).
+pub struct CorpusCollector {
+    sink: Box<dyn CorpusSink>,
+    sample_rate: f64,
+    hash_only: bool,
+    max_seen: usize,
+    seen: Mutex<SeenHashes>,
+}
+
+impl std::fmt::Debug for CorpusCollector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CorpusCollector")
+            .field("sample_rate", &self.sample_rate)
+            .field("hash_only", &self.hash_only)
+            .field("max_seen", &self.max_seen)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CorpusCollector {
+    /// Create a collector which sends all unique code to `sink`.
+    pub fn new(sink: impl CorpusSink) -> Self {
+        Self {
+            sink: Box::new(sink),
+            sample_rate: 1.0,
+            hash_only: false,
+            max_seen: DEFAULT_MAX_SEEN,
+            seen: Mutex::new(SeenHashes::default()),
+        }
+    }
+
+    /// Only record this fraction of unique code, between 0.0 and 1.0.
+    /// Sampling is based on the code hash, so a given piece of code is either always
+    /// sampled or never sampled.
+    pub fn sample_rate(mut self, rate: f64) -> Self {
+        self.sample_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Only record the hash of the code and not the code itself, for cases where policy forbids
+    /// storing the source.
+    pub fn hash_only(mut self, hash_only: bool) -> Self {
+        self.hash_only = hash_only;
+        self
+    }
+
+    /// How many hashes of recorded code to remember, so that the same code isn't recorded again.
+    /// When the limit is reached, the least recently run code is forgotten, and is recorded again
+    /// if it runs later. Defaults to 100,000, which uses a few megabytes.
+    pub fn max_seen(mut self, max: usize) -> Self {
+        self.max_seen = max.max(1);
+        self
+    }
+
+    /// Record the code units in a script that haven't been seen before.
+    pub(crate) fn observe(&self, args: &RunScriptArgs) {
+        let script_name = args.name.as_ref();
+        if !args.code.is_empty() {
+            let kind = if args.expr {
+                CodeKind::Expression
+            } else {
+                CodeKind::Script
+            };
+            self.observe_code(kind, script_name, script_name, &args.code);
+        }
+
+        for module in &args.modules {
+            self.observe_code(CodeKind::Module, &module.name, script_name, &module.code);
+        }
+
+        for function in &args.functions {
+            self.observe_code(
+                CodeKind::Function,
+                &function.name,
+                script_name,
+                &function.code,
+            );
+        }
+    }
+
+    fn observe_code(&self, kind: CodeKind, name: &str, script_name: &str, code: &str) {
+        let hash: [u8; 32] = Sha256::digest(code.as_bytes()).into();
+        if !self.sampled(&hash) {
+            return;
+        }
+
+        let new = self.seen.lock().unwrap().insert(hash, self.max_seen);
+        if !new {
+            return;
+        }
+
+        self.sink.record(CorpusEntry {
+            hash: hex_string(&hash),
+            code: (!self.hash_only).then(|| code.to_string()),
+            metadata: CorpusMetadata {
+                kind,
+                name: name.to_string(),
+                script_name: script_name.to_string(),
+            },
+        });
+    }
+
+    fn sampled(&self, hash: &[u8; 32]) -> bool {
+        if self.sample_rate >= 1.0 {
+            return true;
+        }
+
+        let value = u64::from_le_bytes(hash[0..8].try_into().unwrap());
+        (value as f64 / u64::MAX as f64) < self.sample_rate
+    }
+}
+
+/// The hashes of recorded code, in the order that they were last run.
+#[derive(Default)]
+struct SeenHashes {
+    last_used: HashMap<[u8; 32], u64>,
+    by_use: BTreeMap<u64, [u8; 32]>,
+    counter: u64,
+}
+
+impl SeenHashes {
+    /// Mark `hash` as the most recently run code, forgetting the least recently run hash if there
+    /// are more than `max`. Returns true if the hash wasn't already known.
+    fn insert(&mut self, hash: [u8; 32], max: usize) -> bool {
+        self.counter += 1;
+        let previous = self.last_used.insert(hash, self.counter);
+        if let Some(previous) = previous {
+            self.by_use.remove(&previous);
+        }
+        self.by_use.insert(self.counter, hash);
+
+        while self.last_used.len() > max {
+            let Some((_, oldest)) = self.by_use.pop_first() else {
+                break;
+            };
+            self.last_used.remove(&oldest);
+        }
+
+        previous.is_none()
+    }
+}
+
+impl<T: CorpusSink> CorpusSink for Arc<T> {
+    fn record(&self, entry: CorpusEntry) {
+        self.as_ref().record(entry)
+    }
+}
+
+pub(crate) fn hex_string(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut s, b| {
+            write!(s, "{b:02x}").unwrap();
+            s
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CodeModule;
+
+    #[derive(Default)]
+    struct VecSink(Mutex<Vec<CorpusEntry>>);
+
+    impl CorpusSink for VecSink {
+        fn record(&self, entry: CorpusEntry) {
+            self.0.lock().unwrap().push(entry);
+        }
+    }
+
+    fn args() -> RunScriptArgs {
+        RunScriptArgs {
+            name: "test".into(),
+            code: "import { x } from 'mod'; output = x;".into(),
+            modules: vec![CodeModule {
+                name: "mod".into(),
+                code: "export const x = 1;".into(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn records_unique_code() {
+        let sink = Arc::new(VecSink::default());
+        let collector = CorpusCollector::new(sink.clone());
+
+        collector.observe(&args());
+        collector.observe(&args());
+
+        let entries = sink.0.lock().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].metadata.kind, CodeKind::Script);
+        assert_eq!(entries[0].code.as_deref(), Some(args().code.as_ref()));
+        assert_eq!(entries[0].hash.len(), 64);
+        assert_eq!(entries[1].metadata.kind, CodeKind::Module);
+        assert_eq!(entries[1].metadata.name, "mod");
+        assert_eq!(entries[1].metadata.script_name, "test");
+    }
+
+    #[test]
+    fn hash_only() {
+        let sink = Arc::new(VecSink::default());
+        let collector = CorpusCollector::new(sink.clone()).hash_only(true);
+
+        collector.observe(&args());
+
+        let entries = sink.0.lock().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|e| e.code.is_none()));
+    }
+
+    #[test]
+    fn forgets_least_recently_run() {
+        let sink = Arc::new(VecSink::default());
+        let collector = CorpusCollector::new(sink.clone()).max_seen(2);
+        let expr = |code: &str| RunScriptArgs {
+            code: code.to_string().into(),
+            expr: true,
+            ..Default::default()
+        };
+
+        collector.observe(&expr("1"));
+        collector.observe(&expr("2"));
+        collector.observe(&expr("1"));
+        // Over the limit, so "2" is forgotten rather than "1", which ran more recently.
+        collector.observe(&expr("3"));
+        collector.observe(&expr("1"));
+        collector.observe(&expr("2"));
+
+        let entries = sink.0.lock().unwrap();
+        let recorded = entries
+            .iter()
+            .map(|e| e.code.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(recorded, ["1", "2", "3", "2"]);
+    }
+
+    #[test]
+    fn sample_none() {
+        let sink = Arc::new(VecSink::default());
+        let collector = CorpusCollector::new(sink.clone()).sample_rate(0.0);
+
+        collector.observe(&args());
+
+        assert!(sink.0.lock().unwrap().is_empty());
+    }
+}
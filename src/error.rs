@@ -1,12 +1,18 @@
 use deadpool::managed::BuildError;
 use thiserror::Error;
 
-use crate::{protocol::WorkerToHostMessageData, ErrorResponseData};
+use crate::{
+    wire::WorkerToHostMessageData, ErrorKind, ErrorResponseData, LogResponseData,
+    ProtocolCorruptionData, ProtocolSequenceData,
+};
 
 #[derive(Debug)]
 pub struct RunScriptError {
     pub error: ErrorResponseData,
-    pub messages: Vec<WorkerToHostMessageData>,
+    /// Console messages logged before the error.
+    pub logs: Vec<LogResponseData>,
+    /// Other messages that arrived before the error.
+    pub other: Vec<WorkerToHostMessageData>,
 }
 
 #[derive(Debug, Error)]
@@ -42,8 +48,189 @@ pub enum Error {
     InvalidMessageType(u32),
 
     #[error("ScriptError: {}", .0.error.message)]
-    Script(RunScriptError),
+    Script(Box<RunScriptError>),
 
     #[error("Script ended without a response")]
     ScriptEndedEarly,
+
+    #[error("Worker was killed for going over the cgroup's memory limit")]
+    CgroupOutOfMemory,
+
+    #[error("Failed to convert the script result to the requested type")]
+    ResultType(#[source] serde_json::Error),
+
+    #[error("String at {path} is {length} bytes, exceeding the limit of {limit}")]
+    StringTooLong {
+        path: String,
+        length: usize,
+        limit: usize,
+    },
+
+    #[error("Invalid value: {0}")]
+    InvalidValue(String),
+
+    #[error("Management API request failed: {0}")]
+    Management(String),
+
+    #[error("Worker speaks protocol version {worker}, but this host speaks version {host}")]
+    ProtocolMismatch {
+        /// This crate's [PROTOCOL_VERSION](crate::versions::PROTOCOL_VERSION)
+        host: u32,
+        /// The worker's protocol version
+        worker: u32,
+    },
+
+    #[error("Corrupted message from worker: {}", .0.reason)]
+    ProtocolCorruption(ProtocolCorruptionData),
+
+    #[error(
+        "Message {} for request {} arrived out of sequence, expected message {}",
+        .0.received,
+        .0.request_id,
+        .0.expected
+    )]
+    ProtocolSequence(ProtocolSequenceData),
+
+    #[error("Message from worker is {length} bytes, exceeding the limit of {limit}")]
+    MessageTooLarge {
+        /// The size of the message, in bytes
+        length: u64,
+        /// The limit set by [JsSidecarBuilder::max_frame_bytes](crate::JsSidecarBuilder::max_frame_bytes)
+        /// or [JsSidecarBuilder::max_response_bytes](crate::JsSidecarBuilder::max_response_bytes)
+        limit: u64,
+    },
+
+    #[error("Sidecar is overloaded, with {queued} requests already waiting for a connection")]
+    Overloaded {
+        /// The number of requests that were waiting for a connection
+        queued: usize,
+        /// The limit set by [JsSidecarBuilder::max_queued_requests](crate::JsSidecarBuilder::max_queued_requests)
+        limit: usize,
+    },
+
+    #[error("Script {name} is not approved to run: {reason}")]
+    ScriptNotApproved {
+        /// The script's [name](crate::RunScriptArgs::name)
+        name: String,
+        /// Why the script was rejected
+        reason: String,
+    },
+
+    #[error("Script {name} version {version} is not registered")]
+    ScriptNotRegistered {
+        /// The name of the script
+        name: String,
+        /// The version that was asked for
+        version: u32,
+    },
+
+    #[error("Script {name} version {version} is already registered with different code")]
+    ScriptVersionConflict {
+        /// The name of the script
+        name: String,
+        /// The version that was already registered
+        version: u32,
+    },
+
+    #[error("Invalid script template: {0}")]
+    InvalidTemplate(String),
+
+    #[error("Job {job_id} was not found in worker {pid}")]
+    JobNotFound {
+        /// The job's ID
+        job_id: u64,
+        /// The process ID of the worker that ran the job. The job is gone if the worker has
+        /// exited, or if its result was already read.
+        pid: u32,
+    },
+
+    #[error("Unexpected message of type {message_type:#x} for request {request_id}")]
+    UnexpectedMessage {
+        /// The message's request ID
+        request_id: u32,
+        /// The message's [type](crate::WorkerToHostMessageData::message_type)
+        message_type: u32,
+    },
+
+    #[error("Invalid schedule: {0}")]
+    InvalidSchedule(String),
+
+    #[error("Schedule store failed")]
+    ScheduleStore(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("Recording was made with a different version of the worker")]
+    RecordingMismatch,
+
+    #[error("Tenant {tenant} has used up its run time, and can run again in {retry_after:?}")]
+    TenantQuotaExceeded {
+        /// The [tenant](crate::RunScriptArgs::tenant) of the run
+        tenant: String,
+        /// How long until the tenant's budget has refilled enough to run again
+        retry_after: std::time::Duration,
+    },
+}
+
+impl Error {
+    /// Returns true if the error came from a broken connection to the worker, rather than from
+    /// the script itself. The connection shouldn't be used again. This doesn't say whether the
+    /// worker received the script before the connection broke, so it isn't safe to run the
+    /// script again on that basis alone unless it has no side effects.
+    ///
+    /// [Error::ScriptEndedEarly] isn't counted, since the worker stopped partway through the run,
+    /// possibly because of the script, and may have already performed some of its side effects.
+    pub fn is_connection_failure(&self) -> bool {
+        match self {
+            Error::ReadStream(_)
+            | Error::WriteStream(_)
+            | Error::ConnectWorker(_)
+            | Error::ConnectionOutOfSync
+            | Error::ProtocolCorruption(_)
+            | Error::ProtocolSequence(_) => true,
+            Error::Pool(e) => {
+                matches!(
+                    e.as_ref(),
+                    deadpool::managed::PoolError::Backend(e) if e.is_connection_failure()
+                ) || matches!(
+                    e.as_ref(),
+                    deadpool::managed::PoolError::Timeout(deadpool::managed::TimeoutType::Create)
+                )
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns true if the run passed its [timeout](crate::RunScriptArgs::timeout_ms), or the
+    /// worker didn't answer in time.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::Timeout => true,
+            Error::Script(e) => e.error.timed_out,
+            _ => false,
+        }
+    }
+
+    /// The [kind](crate::ErrorResponseData::kind) of an error thrown by the script or the worker,
+    /// [ErrorKind::Timeout] if the worker didn't answer in time, or [ErrorKind::StringTooLong]
+    /// if the host found a string over the limit. Returns `None` for other errors that didn't
+    /// come from the worker.
+    pub fn kind(&self) -> Option<ErrorKind> {
+        match self {
+            Error::Timeout => Some(ErrorKind::Timeout),
+            Error::StringTooLong { .. } => Some(ErrorKind::StringTooLong),
+            Error::Script(e) => Some(e.error.kind),
+            _ => None,
+        }
+    }
+
+    /// Returns true if no connection became available within
+    /// [JsSidecarBuilder::pool_wait_timeout](crate::JsSidecarBuilder::pool_wait_timeout).
+    pub fn is_pool_timeout(&self) -> bool {
+        matches!(
+            self,
+            Error::Pool(e) if matches!(
+                e.as_ref(),
+                deadpool::managed::PoolError::Timeout(deadpool::managed::TimeoutType::Wait)
+            )
+        )
+    }
 }
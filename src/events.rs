@@ -0,0 +1,228 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    sync::broadcast,
+};
+
+use crate::{Error, PoolStatus, Priority, RunScriptAndWaitResult};
+
+/// How many events can be buffered for each subscriber before the oldest are dropped.
+pub(crate) const EVENT_CHANNEL_SIZE: usize = 256;
+
+/// Receives the events from [JsSidecar::subscribe](crate::JsSidecar::subscribe). A subscriber
+/// that falls behind by more than 256 events skips the oldest ones, and its next `recv` returns
+/// [RecvError::Lagged](tokio::sync::broadcast::error::RecvError::Lagged).
+pub type SidecarEvents = broadcast::Receiver<SidecarEvent>;
+
+/// Events from the sidecar which are not tied to a particular connection.
+#[derive(Debug, Clone)]
+pub enum SidecarEvent {
+    /// A line that the Node.js process wrote to stdout
+    WorkerStdout(String),
+    /// A line that the Node.js process wrote to stderr
+    WorkerStderr(String),
+    /// A worker's heap grew past [max_worker_heap](crate::JsSidecarBuilder::max_worker_heap),
+    /// so it was told to exit once its current requests finish. Node.js starts a new worker to
+    /// replace it.
+    WorkerRetired {
+        /// The process ID of the worker
+        pid: u32,
+        /// The heap size that the worker reported, in bytes
+        heap_used: u64,
+    },
+    /// A run with [RunScriptArgs::debug](crate::RunScriptArgs::debug) set is waiting for a
+    /// debugger to attach.
+    DebuggerWaiting {
+        /// The process ID of the worker
+        pid: u32,
+        /// The WebSocket URL of the worker's inspector
+        url: String,
+    },
+    /// Node.js started a worker process, either at startup or to replace one that exited.
+    WorkerStarted {
+        /// The process ID of the worker
+        pid: u32,
+    },
+    /// A worker process exited. Unless the sidecar is shutting down, Node.js starts another one
+    /// to replace it.
+    WorkerExited {
+        /// The process ID of the worker
+        pid: u32,
+        /// The worker's exit code, if it exited normally
+        code: Option<i32>,
+        /// The signal that stopped the worker, such as `SIGKILL`
+        signal: Option<String>,
+    },
+    /// A checkout waited longer than
+    /// [slow_checkout_threshold](crate::JsSidecarBuilder::slow_checkout_threshold) for a
+    /// connection from the pool.
+    SlowCheckout {
+        /// How long the checkout waited, including the time to open a new connection
+        waited: Duration,
+        /// The priority of the checkout
+        priority: Priority,
+        /// The pool's status when the checkout finished
+        status: PoolStatus,
+    },
+    /// A worker hit an uncaught exception or unhandled rejection, usually from code that a script
+    /// left running after it finished, and is exiting. The requests that it was running fail.
+    WorkerCrashed {
+        /// The process ID of the worker
+        pid: u32,
+        /// The error, and the requests that were running when it happened
+        report: String,
+    },
+    /// A run of a script scheduled with [JsSidecar::schedule](crate::JsSidecar::schedule)
+    /// finished.
+    ScheduledRun {
+        /// The schedule's ID
+        id: u64,
+        /// The time that the run was due
+        due: SystemTime,
+        /// The run's result, or the error from the script or from getting a connection
+        result: Arc<Result<RunScriptAndWaitResult, Error>>,
+    },
+}
+
+/// An event from the Node.js primary process, sent as a line of JSON on the events socket.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum NodeEvent {
+    #[serde(rename = "workerStarted")]
+    Started { pid: u32 },
+    #[serde(rename = "workerExited")]
+    Exited {
+        pid: u32,
+        code: Option<i32>,
+        signal: Option<String>,
+    },
+    #[serde(rename = "workerCrashed")]
+    Crashed { pid: u32, report: String },
+}
+
+impl From<NodeEvent> for SidecarEvent {
+    fn from(event: NodeEvent) -> Self {
+        match event {
+            NodeEvent::Started { pid } => SidecarEvent::WorkerStarted { pid },
+            NodeEvent::Exited { pid, code, signal } => {
+                SidecarEvent::WorkerExited { pid, code, signal }
+            }
+            NodeEvent::Crashed { pid, report } => SidecarEvent::WorkerCrashed { pid, report },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Read lines from the Node.js process's output and send them to the event channel and the
+/// tracing subscriber.
+pub(crate) async fn forward_output(
+    output: impl AsyncRead + Unpin,
+    stream: OutputStream,
+    sender: broadcast::Sender<SidecarEvent>,
+) {
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let event = match stream {
+            OutputStream::Stdout => {
+                tracing::info!(target: "js_sidecar::worker", "{line}");
+                SidecarEvent::WorkerStdout(line)
+            }
+            OutputStream::Stderr => {
+                tracing::warn!(target: "js_sidecar::worker", "{line}");
+                SidecarEvent::WorkerStderr(line)
+            }
+        };
+
+        // An error just means that there are no subscribers right now.
+        sender.send(event).ok();
+    }
+}
+
+/// Read events from the Node.js process, one JSON object per line, and send them to the event
+/// channel.
+pub(crate) async fn forward_events(
+    input: impl AsyncRead + Unpin,
+    sender: broadcast::Sender<SidecarEvent>,
+) {
+    let mut lines = BufReader::new(input).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let event = match serde_json::from_str::<NodeEvent>(&line) {
+            Ok(event) => event,
+            Err(e) => {
+                tracing::warn!(target: "js_sidecar::worker", "Invalid event {line}: {e}");
+                continue;
+            }
+        };
+
+        match &event {
+            NodeEvent::Crashed { report, .. } => {
+                tracing::error!(target: "js_sidecar::worker", "{report}");
+            }
+            event => tracing::debug!(target: "js_sidecar::worker", "{event:?}"),
+        }
+
+        // An error just means that there are no subscribers right now.
+        sender.send(event.into()).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn forwards_lines() {
+        let (sender, mut receiver) = broadcast::channel(16);
+        let input: &[u8] = b"first line\nsecond line\n";
+        forward_output(input, OutputStream::Stderr, sender).await;
+
+        let SidecarEvent::WorkerStderr(line) = receiver.recv().await.unwrap() else {
+            panic!("Expected stderr event");
+        };
+        assert_eq!(line, "first line");
+
+        let SidecarEvent::WorkerStderr(line) = receiver.recv().await.unwrap() else {
+            panic!("Expected stderr event");
+        };
+        assert_eq!(line, "second line");
+    }
+
+    #[tokio::test]
+    async fn forwards_events() {
+        let (sender, mut receiver) = broadcast::channel(16);
+        let input: &[u8] = br#"{"type":"workerStarted","pid":10}
+not json
+{"type":"workerCrashed","pid":10,"report":"Worker 10 crashed: Error: boom"}
+{"type":"workerExited","pid":10,"code":1,"signal":null}
+"#;
+        forward_events(input, sender).await;
+
+        let SidecarEvent::WorkerStarted { pid } = receiver.recv().await.unwrap() else {
+            panic!("Expected WorkerStarted event");
+        };
+        assert_eq!(pid, 10);
+
+        // The invalid line is skipped.
+        let SidecarEvent::WorkerCrashed { pid, report } = receiver.recv().await.unwrap() else {
+            panic!("Expected WorkerCrashed event");
+        };
+        assert_eq!(pid, 10);
+        assert!(report.contains("boom"));
+
+        let SidecarEvent::WorkerExited { pid, code, signal } = receiver.recv().await.unwrap()
+        else {
+            panic!("Expected WorkerExited event");
+        };
+        assert_eq!((pid, code, signal), (10, Some(1), None));
+    }
+}
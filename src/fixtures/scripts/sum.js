@@ -0,0 +1,3 @@
+const values = [1, 2, 3];
+console.log(`sum of ${values.length} values`);
+globalThis.total = values.reduce((a, b) => a + b, 0);
@@ -0,0 +1,154 @@
+use serde::{
+    de::{self, DeserializeOwned, Visitor},
+    forward_to_deserialize_any, Deserializer,
+};
+
+use crate::{Error, RunResponseData};
+
+/// A type that a script fills in through its globals, such as a struct whose fields are the
+/// globals that the script sets. Every type that implements [Deserialize](serde::Deserialize) can
+/// be used, so consumers don't have to keep a list of key names in sync with the struct.
+///
+/// ```no_run
+/// # use js_sidecar::{FromScriptGlobals, JsSidecar, RunScriptArgs};
+/// # async fn example(sidecar: &JsSidecar) -> Result<(), js_sidecar::Error> {
+/// #[derive(serde::Deserialize)]
+/// #[serde(rename_all = "camelCase")]
+/// struct Summary {
+///     total_count: u32,
+///     #[serde(rename = "user.name")]
+///     user_name: Option<String>,
+/// }
+///
+/// let result = sidecar
+///     .run(RunScriptArgs {
+///         code: "globalThis.totalCount = 3; globalThis.user = { name: 'a' };".into(),
+///         return_keys: Summary::return_keys(),
+///         ..Default::default()
+///     })
+///     .await?;
+/// let summary = Summary::from_globals(&result.response)?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait FromScriptGlobals: DeserializeOwned {
+    /// The names of the struct's fields, after serde renames them, for
+    /// [return_keys](crate::RunScriptArgs::return_keys). Since return keys can be paths, a field
+    /// renamed to a path such as `user.name` gets just that part of a global.
+    ///
+    /// Types that aren't structs, or are structs with a flattened field, return an empty list,
+    /// which returns every global.
+    fn return_keys() -> Vec<String> {
+        let mut fields = FieldNames(None);
+        Self::deserialize(&mut fields).ok();
+        fields
+            .0
+            .unwrap_or_default()
+            .iter()
+            .map(|field| field.to_string())
+            .collect()
+    }
+
+    /// Convert the globals of a run's response to this type. Globals that the script didn't set
+    /// are missing, so fields for them should be an [Option] or have a default.
+    fn from_globals(response: &RunResponseData) -> Result<Self, Error> {
+        let globals = response
+            .globals
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        serde_json::from_value(serde_json::Value::Object(globals)).map_err(Error::ResultType)
+    }
+}
+
+impl<T: DeserializeOwned> FromScriptGlobals for T {}
+
+/// A deserializer that doesn't deserialize anything, but records the field names that a struct
+/// asks for.
+struct FieldNames(Option<&'static [&'static str]>);
+
+impl<'de> Deserializer<'de> for &mut FieldNames {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0 = Some(fields);
+        Err(de::Error::custom("found the fields"))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{JsSidecar, RunScriptArgs};
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Summary {
+        total_count: u32,
+        #[serde(rename = "user.name")]
+        user_name: Option<String>,
+        #[serde(default)]
+        tags: Vec<String>,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Wrapper(Summary);
+
+    #[test]
+    fn return_keys() {
+        assert_eq!(Summary::return_keys(), ["totalCount", "user.name", "tags"]);
+        assert_eq!(Wrapper::return_keys(), Summary::return_keys());
+        assert!(<std::collections::HashMap<String, u32>>::return_keys().is_empty());
+        assert!(u32::return_keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn from_globals() {
+        let sidecar = JsSidecar::new(Some(1)).await.unwrap();
+        let result = sidecar
+            .run(RunScriptArgs {
+                code: "globalThis.totalCount = 3; globalThis.user = { name: 'a', age: 2 };".into(),
+                return_keys: Summary::return_keys(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.globals["user.name"], json!("a"));
+        assert_eq!(
+            Summary::from_globals(&result.response).unwrap(),
+            Summary {
+                total_count: 3,
+                user_name: Some("a".into()),
+                tags: Vec::new(),
+            }
+        );
+
+        let err = u32::from_globals(&result.response).unwrap_err();
+        assert!(matches!(err, Error::ResultType(_)), "{err:?}");
+    }
+}
@@ -0,0 +1,429 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::Error;
+
+/// Options for the hardened spawn mode set with
+/// [JsSidecarBuilder::hardened](crate::JsSidecarBuilder::hardened), which restricts what the
+/// Node.js process can reach in case a script escapes its sandbox. Linux only.
+///
+/// The filesystem is restricted with Landlock: Node.js can read its own installation, the
+/// system's programs, libraries and configuration, `/proc`, `/sys` and `/dev`, and can only write
+/// to the sidecar's own socket and shared memory directories. A seccomp filter stops Node.js from
+/// creating Unix sockets, so it can't connect to other services on the machine through their
+/// socket files; the sidecar hands it the sockets that it needs. Network sockets are blocked too
+/// unless the sidecar is configured with something that needs them, such as
+/// [allow_url_imports](crate::JsSidecarBuilder::allow_url_imports),
+/// [websocket_addr](crate::JsSidecarBuilder::websocket_addr) or
+/// [management_addr](crate::JsSidecarBuilder::management_addr). Without network access, the
+/// inspector can't be opened, so runs that wait for a debugger fail.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hardening {
+    /// More directories or files that Node.js may read, such as the directory of a Node.js
+    /// installation outside of the `PATH`.
+    pub read_paths: Vec<PathBuf>,
+    /// More directories or files that Node.js may write to.
+    pub write_paths: Vec<PathBuf>,
+}
+
+/// Directories that Node.js reads from no matter where it is installed. Ones that don't exist
+/// are skipped.
+const SYSTEM_READ_PATHS: &[&str] = &[
+    "/usr", "/bin", "/sbin", "/lib", "/lib64", "/etc", "/proc", "/sys", "/dev",
+];
+
+impl Hardening {
+    /// Prepare the sandbox for a Node.js process, in the parent since little can be done between
+    /// forking and running Node.js. `network` allows network sockets, and the paths are the
+    /// sidecar's own directories that Node.js reads from and writes to.
+    pub(crate) fn sandbox(
+        &self,
+        network: bool,
+        read_paths: &[&Path],
+        write_paths: &[&Path],
+    ) -> Result<Sandbox, Error> {
+        let mut read = SYSTEM_READ_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .chain(node_prefix())
+            .chain(read_paths.iter().map(|path| path.to_path_buf()))
+            .chain(self.read_paths.iter().cloned())
+            .collect::<Vec<_>>();
+        read.retain(|path| path.exists());
+        let write = write_paths
+            .iter()
+            .map(|path| path.to_path_buf())
+            .chain(self.write_paths.iter().cloned())
+            .collect::<Vec<_>>();
+
+        Sandbox::new(&read, &write, network).map_err(Error::StartWorker)
+    }
+}
+
+/// The directory that the `node` on the `PATH` is installed in, such as `/usr/local` for
+/// `/usr/local/bin/node`.
+fn node_prefix() -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join("node"))
+        .find(|node| node.is_file())
+        .and_then(|node| node.canonicalize().ok())
+        .and_then(|node| Some(node.parent()?.parent()?.to_path_buf()))
+}
+
+/// A Landlock ruleset and seccomp filter, ready to be applied to a process.
+#[derive(Debug)]
+pub(crate) struct Sandbox {
+    #[cfg(target_os = "linux")]
+    ruleset: std::os::fd::OwnedFd,
+    #[cfg(target_os = "linux")]
+    filter: Vec<libc::sock_filter>,
+}
+
+#[cfg(target_os = "linux")]
+mod landlock {
+    pub const CREATE_RULESET_VERSION: u32 = 1;
+    pub const RULE_PATH_BENEATH: u32 = 1;
+
+    pub const ACCESS_EXECUTE: u64 = 1 << 0;
+    pub const ACCESS_WRITE_FILE: u64 = 1 << 1;
+    pub const ACCESS_READ_FILE: u64 = 1 << 2;
+    pub const ACCESS_READ_DIR: u64 = 1 << 3;
+    pub const ACCESS_TRUNCATE: u64 = 1 << 14;
+
+    /// The rights that can be given to a file, rather than a directory.
+    pub const FILE_ACCESS: u64 =
+        ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE | ACCESS_TRUNCATE;
+    pub const READ_ACCESS: u64 = ACCESS_EXECUTE | ACCESS_READ_FILE | ACCESS_READ_DIR;
+
+    /// Every filesystem right that a version of the Landlock ABI knows of.
+    pub fn all_access(abi: i64) -> u64 {
+        let rights = match abi {
+            1 => 13,
+            2 => 14,
+            3 | 4 => 15,
+            _ => 16,
+        };
+        (1 << rights) - 1
+    }
+
+    #[repr(C)]
+    pub struct RulesetAttr {
+        pub handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    pub struct PathBeneathAttr {
+        pub allowed_access: u64,
+        pub parent_fd: i32,
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Sandbox {
+    fn new(read_paths: &[PathBuf], write_paths: &[PathBuf], network: bool) -> io::Result<Self> {
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+        // SAFETY: asking for the ABI version passes no attributes.
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<landlock::RulesetAttr>(),
+                0,
+                landlock::CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "The kernel doesn't support Landlock",
+            ));
+        }
+
+        let all_access = landlock::all_access(abi);
+        let attr = landlock::RulesetAttr {
+            handled_access_fs: all_access,
+        };
+        // SAFETY: the attributes outlive the call, and the size matches them.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                std::mem::size_of::<landlock::RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the kernel just opened the file descriptor, with close-on-exec set.
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        let rules = read_paths
+            .iter()
+            .map(|path| (path.as_path(), landlock::READ_ACCESS))
+            .chain(write_paths.iter().map(|path| (path.as_path(), all_access)))
+            // Node.js and the libraries that it uses open /dev/null for writing.
+            .chain(std::iter::once((
+                Path::new("/dev/null"),
+                landlock::ACCESS_READ_FILE | landlock::ACCESS_WRITE_FILE,
+            )));
+        for (path, access) in rules {
+            let file = std::fs::File::open(path).map_err(|e| {
+                io::Error::new(e.kind(), format!("Opening {}: {e}", path.display()))
+            })?;
+            let access = if file.metadata()?.is_dir() {
+                access
+            } else {
+                access & landlock::FILE_ACCESS
+            };
+            let rule = landlock::PathBeneathAttr {
+                allowed_access: access & all_access,
+                parent_fd: file.as_raw_fd(),
+            };
+            // SAFETY: the rule outlives the call, and both file descriptors are open.
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset.as_raw_fd(),
+                    landlock::RULE_PATH_BENEATH,
+                    &rule,
+                    0,
+                )
+            };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(Self {
+            ruleset,
+            filter: socket_filter(network)?,
+        })
+    }
+
+    /// Restrict the current process, and everything it runs later. This is called between
+    /// forking and running Node.js, so it only makes system calls.
+    pub fn enter(&self) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: these calls only read their arguments, and the filter outlives the last one.
+        unsafe {
+            // Both Landlock and seccomp need this to be used without privileges.
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::syscall(
+                libc::SYS_landlock_restrict_self,
+                self.ruleset.as_raw_fd(),
+                0,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            let program = libc::sock_fprog {
+                len: self.filter.len() as u16,
+                filter: self.filter.as_ptr().cast_mut(),
+            };
+            if libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                0,
+                &program,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Sandbox {
+    fn new(_read_paths: &[PathBuf], _write_paths: &[PathBuf], _network: bool) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Hardened mode is only supported on Linux",
+        ))
+    }
+
+    pub fn enter(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// A seccomp filter that fails the creation of Unix sockets with `EACCES`, along with IPv4 and
+/// IPv6 sockets unless `network` is set, and allows everything else. Connected pairs of stream
+/// sockets are still allowed, since Node.js talks to its workers through them, but other kinds of
+/// pairs are refused, since a datagram socket can be pointed at any socket file. The sidecar binds
+/// the sockets that Node.js needs before starting it. io_uring is turned off too, since it can
+/// create sockets without going through the filter, and Node.js falls back to threads without it.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn socket_filter(network: bool) -> io::Result<Vec<libc::sock_filter>> {
+    use libc::{
+        BPF_ABS, BPF_ALU, BPF_AND, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W,
+    };
+
+    fn statement(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+    fn jump(op: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: (BPF_JMP | op | BPF_K) as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+    let load = |offset: usize| statement(BPF_LD | BPF_W | BPF_ABS, offset as u32);
+    let ret = |action: u32| statement(BPF_RET | BPF_K, action);
+
+    let arch = std::mem::offset_of!(libc::seccomp_data, arch);
+    let nr = std::mem::offset_of!(libc::seccomp_data, nr);
+    // The low half of the first argument, the socket's domain.
+    let domain = std::mem::offset_of!(libc::seccomp_data, args);
+    // The low half of the second argument, the socket's type and flags.
+    let kind = domain + 8;
+    // System calls of the x32 ABI, which share the x86_64 architecture, have this bit set.
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    let denied_domains = if network {
+        vec![libc::AF_UNIX]
+    } else {
+        vec![libc::AF_UNIX, libc::AF_INET, libc::AF_INET6]
+    };
+    // Where each check and shared return lands. Jumps are relative to the next instruction.
+    let check_domain = 9;
+    let check_pair = check_domain + denied_domains.len() + 2;
+    let deny = check_pair + 4;
+    let unsupported = deny + 1;
+    let to = |from: usize, target: usize| (target - from - 1) as u8;
+
+    let mut filter = vec![
+        load(arch),
+        jump(BPF_JEQ, AUDIT_ARCH, 1, 0),
+        ret(libc::SECCOMP_RET_KILL_PROCESS),
+        load(nr),
+        jump(BPF_JGE, X32_SYSCALL_BIT, to(4, unsupported), 0),
+        jump(
+            BPF_JEQ,
+            libc::SYS_io_uring_setup as u32,
+            to(5, unsupported),
+            0,
+        ),
+        jump(BPF_JEQ, libc::SYS_socket as u32, to(6, check_domain), 0),
+        jump(BPF_JEQ, libc::SYS_socketpair as u32, to(7, check_pair), 0),
+        ret(libc::SECCOMP_RET_ALLOW),
+        load(domain),
+    ];
+    for domain in denied_domains {
+        let at = filter.len();
+        filter.push(jump(BPF_JEQ, domain as u32, to(at, deny), 0));
+    }
+    filter.extend([
+        ret(libc::SECCOMP_RET_ALLOW),
+        load(kind),
+        // Leave out SOCK_NONBLOCK and SOCK_CLOEXEC.
+        statement(BPF_ALU | BPF_AND | BPF_K, 0xf),
+        jump(BPF_JEQ, libc::SOCK_STREAM as u32, 0, 1),
+        ret(libc::SECCOMP_RET_ALLOW),
+        ret(libc::SECCOMP_RET_ERRNO | libc::EACCES as u32),
+        ret(libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+    ]);
+    debug_assert_eq!(filter.len(), unsupported + 1);
+    Ok(filter)
+}
+
+#[cfg(all(
+    target_os = "linux",
+    not(any(target_arch = "x86_64", target_arch = "aarch64"))
+))]
+fn socket_filter(_network: bool) -> io::Result<Vec<libc::sock_filter>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Blocking sockets is only supported on x86_64 and aarch64",
+    ))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use tokio::process::Command;
+
+    use super::*;
+    use crate::process::ProcessSettings;
+
+    async fn run(sandbox: Sandbox, program: &str, args: &[&str]) -> (bool, String) {
+        let mut command = Command::new(program);
+        command.args(args);
+        ProcessSettings::default()
+            .apply(&mut command, Some(sandbox))
+            .unwrap();
+        let output = command.output().await.unwrap();
+        (
+            output.status.success(),
+            String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn restricts_files_and_network() {
+        let allowed = tempfile::tempdir().unwrap();
+        let denied = tempfile::tempdir().unwrap();
+        let hardening = Hardening::default();
+        let sandbox = || hardening.sandbox(false, &[], &[allowed.path()]).unwrap();
+
+        let write = |dir: &Path| format!("echo hi > {}/file", dir.display());
+        let (success, _) = run(sandbox(), "sh", &["-c", &write(allowed.path())]).await;
+        assert!(success);
+        let (success, _) = run(sandbox(), "sh", &["-c", &write(denied.path())]).await;
+        assert!(!success);
+        assert!(!denied.path().join("file").exists());
+
+        // Node.js gets the seccomp filter's error when it tries to open a TCP socket.
+        let connect =
+            "require('net').connect(9, '127.0.0.1').on('error', (e) => console.log(e.code))";
+        let (_, code) = run(sandbox(), "node", &["-e", connect]).await;
+        assert_eq!(code, "EACCES");
+        let network = hardening.sandbox(true, &[], &[]).unwrap();
+        let (_, code) = run(network, "node", &["-e", connect]).await;
+        assert_eq!(code, "ECONNREFUSED");
+
+        // Unix sockets are refused either way, so a socket file that Node.js can see, such as a
+        // container runtime's, can't be connected to.
+        let path = allowed.path().join("other.sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let connect = format!(
+            "require('net').connect({:?}).on('connect', () => console.log('connected')).on('error', (e) => console.log(e.code))",
+            path.display().to_string()
+        );
+        for network in [false, true] {
+            let sandbox = hardening.sandbox(network, &[], &[]).unwrap();
+            let (_, code) = run(sandbox, "node", &["-e", &connect]).await;
+            assert_eq!(code, "EACCES");
+        }
+
+        // Child processes still get their IPC channel, which is a pair of stream sockets.
+        let fork = "require('child_process')
+            .spawn(process.execPath, ['-e', 'process.send(1)'], { stdio: ['ignore', 'ignore', 'ignore', 'ipc'] })
+            .on('message', (m) => console.log(`message ${m}`))";
+        let (_, message) = run(sandbox(), "node", &["-e", fork]).await;
+        assert_eq!(message, "message 1");
+    }
+}
@@ -0,0 +1,186 @@
+use std::{sync::Arc, time::Duration};
+
+use deadpool::managed::{Object, PoolError, WeakPool};
+
+use crate::{
+    connection::ConnectionOptions, error::RunScriptError, messages::StartedJob,
+    wire::HostToWorkerMessageData, Connection, Error, JobRequestData, JobState, JobStatus,
+    PoolConnection, RunScriptAndWaitResult, WireSizes,
+};
+
+/// How often [JobHandle::result] checks whether the job has finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The most connections to check out of the pool while looking for one to a job's worker. Node.js
+/// hands new connections to its workers in turn, so this is plenty unless the worker has exited.
+const MAX_CONNECT_ATTEMPTS: usize = 64;
+
+/// The code of the worker's error for a job that it doesn't have.
+const JOB_NOT_FOUND: &str = "ERR_JOB_NOT_FOUND";
+
+/// A background job started by [Connection::spawn_script].
+///
+/// The handle doesn't hold on to a connection. Each call checks a connection to the job's worker out
+/// of the sidecar's pool, so the handle can be kept and cloned after the connection that started
+/// the job has gone back to the pool. The worker only answers requests that carry the secret token
+/// that it issued for the job, so other handles can't reach the job by guessing its ID.
+///
+/// ```no_run
+/// # use js_sidecar::{JsSidecar, RunScriptArgs};
+/// # async fn example(sidecar: &JsSidecar) -> Result<(), js_sidecar::Error> {
+/// let job = sidecar
+///     .connect()
+///     .await?
+///     .spawn_script(RunScriptArgs {
+///         code: "export default await rebuildIndex()".into(),
+///         ..Default::default()
+///     })
+///     .await?;
+///
+/// // Later, from anywhere that has the handle
+/// println!("{:?}", job.status().await?.state);
+/// let result = job.result().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct JobHandle {
+    job_id: u64,
+    pid: u32,
+    token: String,
+    options: Arc<ConnectionOptions>,
+}
+
+impl JobHandle {
+    pub(crate) fn new(started: StartedJob, options: Arc<ConnectionOptions>) -> Self {
+        JobHandle {
+            job_id: started.job_id,
+            pid: started.pid,
+            token: started.token,
+            options,
+        }
+    }
+
+    /// The job's ID, which is unique within its worker
+    pub fn id(&self) -> u64 {
+        self.job_id
+    }
+
+    /// The process ID of the worker running the job
+    pub fn worker_pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// The job's current status, including the console messages that it has logged so far.
+    pub async fn status(&self) -> Result<JobStatus, Error> {
+        let mut conn = self.connect().await?;
+        self.call(&mut conn, HostToWorkerMessageData::JobStatus, false)
+            .await
+    }
+
+    /// Wait for the job to finish and return its result, as
+    /// [run_script_and_wait](Connection::run_script_and_wait) would. A job that failed or was
+    /// cancelled returns [Error::Script].
+    ///
+    /// The worker forgets the job once its result has been read, so calling this again returns
+    /// [Error::JobNotFound].
+    ///
+    /// The connection goes back to the pool between checks, so waiting on a long job doesn't tie
+    /// one up.
+    pub async fn result(&self) -> Result<RunScriptAndWaitResult, Error> {
+        let status = loop {
+            let mut conn = self.connect().await?;
+            let status = self
+                .call(&mut conn, HostToWorkerMessageData::JobStatus, true)
+                .await?;
+            drop(conn);
+            if status.state != JobState::Running {
+                break status;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        };
+
+        match (status.response, status.error) {
+            (_, Some(error)) => Err(Error::Script(Box::new(RunScriptError {
+                error,
+                logs: status.logs,
+                other: Vec::new(),
+            }))),
+            (Some(response), None) => Ok(RunScriptAndWaitResult {
+                response,
+                logs: status.logs,
+                other: Vec::new(),
+                cpu_profile: None,
+                wire: WireSizes::default(),
+            }),
+            (None, None) => Err(Error::ScriptEndedEarly),
+        }
+    }
+
+    /// Abort the job's `signal`. The job is [cancelled](JobState::Cancelled) once it has cleaned
+    /// up, and [result](Self::result) returns its error. Cancelling a job that has already finished
+    /// does nothing.
+    pub async fn cancel(&self) -> Result<(), Error> {
+        let mut conn = self.connect().await?;
+        self.call(&mut conn, HostToWorkerMessageData::JobCancel, false)
+            .await?;
+        Ok(())
+    }
+
+    /// Send a request about the job to its worker, which answers with the job's status. With
+    /// `remove` set, the worker forgets a job that has finished.
+    async fn call(
+        &self,
+        conn: &mut Connection,
+        message: fn(JobRequestData) -> HostToWorkerMessageData,
+        remove: bool,
+    ) -> Result<JobStatus, Error> {
+        let data = JobRequestData {
+            job_id: self.job_id,
+            token: self.token.clone(),
+            remove,
+        };
+        match conn.call_worker(message(data)).await {
+            Ok((status, _)) => Ok(status),
+            Err(Error::Script(e)) if e.error.code.as_deref() == Some(JOB_NOT_FOUND) => {
+                Err(self.not_found())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Check out a connection to the job's worker. The pool's connections go to whichever worker
+    /// Node.js picked, so this sets aside connections to other workers until it gets one to the
+    /// right worker. Once every connection that the pool can hold has been set aside, the first one is
+    /// closed, so that the pool opens a new connection in its place.
+    async fn connect(&self) -> Result<PoolConnection, Error> {
+        let pool = self
+            .options
+            .pool
+            .get()
+            .and_then(WeakPool::upgrade)
+            .ok_or_else(|| Error::Pool(Box::new(PoolError::Closed)))?;
+
+        // Hold on to the skipped connections until we're done, so that we don't get them again.
+        let mut skipped = Vec::new();
+        for _ in 0..MAX_CONNECT_ATTEMPTS {
+            if skipped.len() >= pool.status().max_size {
+                let _ = Object::take(skipped.remove(0));
+            }
+
+            let conn = pool.get().await.map_err(|e| Error::Pool(Box::new(e)))?;
+            if conn.worker_pid() == Some(self.pid) {
+                return Ok(conn);
+            }
+            skipped.push(conn);
+        }
+        Err(self.not_found())
+    }
+
+    fn not_found(&self) -> Error {
+        Error::JobNotFound {
+            job_id: self.job_id,
+            pid: self.pid,
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::BTreeMap;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde_json::{json, Value};
+
+use crate::Error;
+
+/// The key that marks an object as a tagged value in the extended encoding.
+const TYPE_KEY: &str = "$type";
+
+/// A JavaScript value, including types that can't be represented in JSON.
+///
+/// Values are sent to and from the worker in a tagged JSON encoding when
+/// [RunScriptArgs::extended_values](crate::RunScriptArgs::extended_values) is set. Use
+/// [JsValue::to_tagged] to create globals and arguments, and [JsValue::from_tagged] or the
+/// helpers on [RunResponseData](crate::RunResponseData) to read the results.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsValue {
+    /// `undefined`
+    Undefined,
+    /// `null`
+    Null,
+    /// A boolean
+    Bool(bool),
+    /// A number, including `NaN` and the infinities
+    Number(f64),
+    /// A BigInt, as its decimal representation
+    BigInt(String),
+    /// A string
+    String(String),
+    /// An array
+    Array(Vec<JsValue>),
+    /// A plain object
+    Object(BTreeMap<String, JsValue>),
+    /// A `Date`, as milliseconds since the Unix epoch. This is `NaN` for an invalid date.
+    Date(f64),
+    /// A `Map`, as its entries in insertion order
+    Map(Vec<(JsValue, JsValue)>),
+    /// A `Set`, as its items in insertion order
+    Set(Vec<JsValue>),
+    /// A typed array such as a `Uint8Array`, with the bytes of its contents in the platform's
+    /// byte order.
+    TypedArray {
+        /// The kind of array
+        kind: TypedArrayKind,
+        /// The contents of the array
+        bytes: Vec<u8>,
+    },
+}
+
+/// The type of a [JsValue::TypedArray]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypedArrayKind {
+    /// `Int8Array`
+    Int8,
+    /// `Uint8Array`
+    Uint8,
+    /// `Uint8ClampedArray`
+    Uint8Clamped,
+    /// `Int16Array`
+    Int16,
+    /// `Uint16Array`
+    Uint16,
+    /// `Int32Array`
+    Int32,
+    /// `Uint32Array`
+    Uint32,
+    /// `Float32Array`
+    Float32,
+    /// `Float64Array`
+    Float64,
+    /// `BigInt64Array`
+    BigInt64,
+    /// `BigUint64Array`
+    BigUint64,
+}
+
+impl TypedArrayKind {
+    /// The JavaScript class name of the array type
+    pub fn name(&self) -> &'static str {
+        match self {
+            TypedArrayKind::Int8 => "Int8Array",
+            TypedArrayKind::Uint8 => "Uint8Array",
+            TypedArrayKind::Uint8Clamped => "Uint8ClampedArray",
+            TypedArrayKind::Int16 => "Int16Array",
+            TypedArrayKind::Uint16 => "Uint16Array",
+            TypedArrayKind::Int32 => "Int32Array",
+            TypedArrayKind::Uint32 => "Uint32Array",
+            TypedArrayKind::Float32 => "Float32Array",
+            TypedArrayKind::Float64 => "Float64Array",
+            TypedArrayKind::BigInt64 => "BigInt64Array",
+            TypedArrayKind::BigUint64 => "BigUint64Array",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        let kind = match name {
+            "Int8Array" => TypedArrayKind::Int8,
+            "Uint8Array" => TypedArrayKind::Uint8,
+            "Uint8ClampedArray" => TypedArrayKind::Uint8Clamped,
+            "Int16Array" => TypedArrayKind::Int16,
+            "Uint16Array" => TypedArrayKind::Uint16,
+            "Int32Array" => TypedArrayKind::Int32,
+            "Uint32Array" => TypedArrayKind::Uint32,
+            "Float32Array" => TypedArrayKind::Float32,
+            "Float64Array" => TypedArrayKind::Float64,
+            "BigInt64Array" => TypedArrayKind::BigInt64,
+            "BigUint64Array" => TypedArrayKind::BigUint64,
+            _ => return None,
+        };
+
+        Some(kind)
+    }
+}
+
+impl JsValue {
+    /// Decode a value in the tagged encoding used by the worker.
+    pub fn from_tagged(value: Value) -> Result<JsValue, Error> {
+        let decoded = match value {
+            Value::Null => JsValue::Null,
+            Value::Bool(b) => JsValue::Bool(b),
+            Value::Number(n) => JsValue::Number(n.as_f64().unwrap_or(f64::NAN)),
+            Value::String(s) => JsValue::String(s),
+            Value::Array(items) => JsValue::Array(
+                items
+                    .into_iter()
+                    .map(JsValue::from_tagged)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Object(mut map) => {
+                let Some(tag) = map.remove(TYPE_KEY) else {
+                    return decode_object(map);
+                };
+
+                let tag = tag.as_str().unwrap_or_default().to_string();
+                let value = map.remove("value").unwrap_or(Value::Null);
+                match tag.as_str() {
+                    "undefined" => JsValue::Undefined,
+                    "bigint" => JsValue::BigInt(expect_string(&tag, value)?),
+                    "number" => JsValue::Number(
+                        expect_string(&tag, value)?
+                            .parse()
+                            .map_err(|_| invalid(&tag))?,
+                    ),
+                    "date" => JsValue::Date(value.as_f64().unwrap_or(f64::NAN)),
+                    "map" => JsValue::Map(
+                        expect_array(&tag, value)?
+                            .into_iter()
+                            .map(|entry| {
+                                let [k, v]: [Value; 2] = expect_array(&tag, entry)?
+                                    .try_into()
+                                    .map_err(|_| invalid(&tag))?;
+                                Ok((JsValue::from_tagged(k)?, JsValue::from_tagged(v)?))
+                            })
+                            .collect::<Result<_, Error>>()?,
+                    ),
+                    "set" => JsValue::Set(
+                        expect_array(&tag, value)?
+                            .into_iter()
+                            .map(JsValue::from_tagged)
+                            .collect::<Result<_, _>>()?,
+                    ),
+                    "typedarray" => {
+                        let kind = map
+                            .get("kind")
+                            .and_then(|k| k.as_str())
+                            .and_then(TypedArrayKind::from_name)
+                            .ok_or_else(|| invalid(&tag))?;
+                        let bytes = BASE64
+                            .decode(expect_string(&tag, value)?)
+                            .map_err(|_| invalid(&tag))?;
+                        JsValue::TypedArray { kind, bytes }
+                    }
+                    "object" => match value {
+                        Value::Object(map) => decode_object(map)?,
+                        _ => return Err(invalid(&tag)),
+                    },
+                    _ => return Err(Error::InvalidValue(format!("Unknown value type {tag}"))),
+                }
+            }
+        };
+
+        Ok(decoded)
+    }
+
+    /// Encode the value in the tagged encoding used by the worker.
+    pub fn to_tagged(&self) -> Value {
+        match self {
+            JsValue::Undefined => json!({ TYPE_KEY: "undefined" }),
+            JsValue::Null => Value::Null,
+            JsValue::Bool(b) => Value::Bool(*b),
+            JsValue::Number(n) => serde_json::Number::from_f64(*n)
+                .map(Value::Number)
+                .unwrap_or_else(|| json!({ TYPE_KEY: "number", "value": js_number_string(*n) })),
+            JsValue::BigInt(n) => json!({ TYPE_KEY: "bigint", "value": n }),
+            JsValue::String(s) => Value::String(s.clone()),
+            JsValue::Array(items) => Value::Array(items.iter().map(|v| v.to_tagged()).collect()),
+            JsValue::Object(map) => {
+                let output = map
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.to_tagged()))
+                    .collect::<serde_json::Map<_, _>>();
+                if output.contains_key(TYPE_KEY) {
+                    json!({ TYPE_KEY: "object", "value": output })
+                } else {
+                    Value::Object(output)
+                }
+            }
+            JsValue::Date(ms) => json!({ TYPE_KEY: "date", "value": ms }),
+            JsValue::Map(entries) => json!({
+                TYPE_KEY: "map",
+                "value": entries
+                    .iter()
+                    .map(|(k, v)| json!([k.to_tagged(), v.to_tagged()]))
+                    .collect::<Vec<_>>(),
+            }),
+            JsValue::Set(items) => json!({
+                TYPE_KEY: "set",
+                "value": items.iter().map(|v| v.to_tagged()).collect::<Vec<_>>(),
+            }),
+            JsValue::TypedArray { kind, bytes } => json!({
+                TYPE_KEY: "typedarray",
+                "kind": kind.name(),
+                "value": BASE64.encode(bytes),
+            }),
+        }
+    }
+}
+
+/// Convert plain JSON, which needs no decoding.
+impl From<Value> for JsValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => JsValue::Null,
+            Value::Bool(b) => JsValue::Bool(b),
+            Value::Number(n) => JsValue::Number(n.as_f64().unwrap_or(f64::NAN)),
+            Value::String(s) => JsValue::String(s),
+            Value::Array(items) => JsValue::Array(items.into_iter().map(JsValue::from).collect()),
+            Value::Object(map) => JsValue::Object(
+                map.into_iter()
+                    .map(|(k, v)| (k, JsValue::from(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+fn decode_object(map: serde_json::Map<String, Value>) -> Result<JsValue, Error> {
+    Ok(JsValue::Object(
+        map.into_iter()
+            .map(|(k, v)| Ok((k, JsValue::from_tagged(v)?)))
+            .collect::<Result<_, Error>>()?,
+    ))
+}
+
+fn js_number_string(n: f64) -> &'static str {
+    if n.is_nan() {
+        "NaN"
+    } else if n > 0.0 {
+        "Infinity"
+    } else {
+        "-Infinity"
+    }
+}
+
+fn invalid(tag: &str) -> Error {
+    Error::InvalidValue(format!("Invalid {tag} value"))
+}
+
+fn expect_string(tag: &str, value: Value) -> Result<String, Error> {
+    match value {
+        Value::String(s) => Ok(s),
+        _ => Err(invalid(tag)),
+    }
+}
+
+fn expect_array(tag: &str, value: Value) -> Result<Vec<Value>, Error> {
+    match value {
+        Value::Array(items) => Ok(items),
+        _ => Err(invalid(tag)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let value = JsValue::Object(BTreeMap::from([
+            ("date".to_string(), JsValue::Date(1718000000000.0)),
+            (
+                "big".to_string(),
+                JsValue::BigInt("123456789012345678901234567890".to_string()),
+            ),
+            (
+                "map".to_string(),
+                JsValue::Map(vec![(
+                    JsValue::Number(1.0),
+                    JsValue::Set(vec![JsValue::Undefined, JsValue::Number(f64::INFINITY)]),
+                )]),
+            ),
+            (
+                "bytes".to_string(),
+                JsValue::TypedArray {
+                    kind: TypedArrayKind::Uint8,
+                    bytes: vec![1, 2, 255],
+                },
+            ),
+            (
+                "tricky".to_string(),
+                JsValue::Object(BTreeMap::from([(
+                    "$type".to_string(),
+                    JsValue::String("date".to_string()),
+                )])),
+            ),
+        ]));
+
+        let tagged = value.to_tagged();
+        assert_eq!(
+            tagged["date"],
+            json!({ "$type": "date", "value": 1718000000000.0 })
+        );
+        assert_eq!(tagged["tricky"]["$type"], json!("object"));
+        assert_eq!(JsValue::from_tagged(tagged).unwrap(), value);
+    }
+
+    #[test]
+    fn nan() {
+        let tagged = JsValue::Number(f64::NAN).to_tagged();
+        assert_eq!(tagged, json!({ "$type": "number", "value": "NaN" }));
+        let JsValue::Number(n) = JsValue::from_tagged(tagged).unwrap() else {
+            panic!("Expected number");
+        };
+        assert!(n.is_nan());
+    }
+
+    #[test]
+    fn invalid() {
+        let err = JsValue::from_tagged(json!({ "$type": "wat" })).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid value: Unknown value type wat");
+
+        let err = JsValue::from_tagged(json!({ "$type": "map", "value": [[1]] })).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid value: Invalid map value");
+    }
+}
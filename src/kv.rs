@@ -0,0 +1,192 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::{self, BoxFuture};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::{
+    transport::WriteHalf,
+    wire::{FrameOptions, HostToWorkerMessage, HostToWorkerMessageData},
+};
+
+/// The result of a [KvBackend] operation. An error rejects the script's promise with the error's
+/// message.
+pub type KvResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Storage behind the `kv` global that scripts see when a backend is set with
+/// [JsSidecarBuilder::kv_backend](crate::JsSidecarBuilder::kv_backend).
+///
+/// Scripts call `await kv.get(key)`, `await kv.set(key, value)`, `await kv.delete(key)`, and
+/// `await kv.list(prefix)`, and each call is passed to the backend. Values are anything that can
+/// be converted to JSON. Keys are shared by every script on the sidecar, so scripts that
+/// shouldn't see each other's data need a backend that scopes the keys.
+pub trait KvBackend: Send + Sync + 'static {
+    /// Get the value stored at `key`.
+    fn get(&self, key: String) -> BoxFuture<'_, KvResult<Option<Value>>>;
+    /// Store `value` at `key`, replacing any existing value.
+    fn set(&self, key: String, value: Value) -> BoxFuture<'_, KvResult<()>>;
+    /// Remove `key`, returning true if it existed.
+    fn delete(&self, key: String) -> BoxFuture<'_, KvResult<bool>>;
+    /// The keys that start with `prefix`, in order.
+    fn list(&self, prefix: String) -> BoxFuture<'_, KvResult<Vec<String>>>;
+}
+
+impl std::fmt::Debug for dyn KvBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("KvBackend")
+    }
+}
+
+/// A [KvBackend] that keeps the data in memory, for tests and for state that only needs to last
+/// as long as the process.
+#[derive(Debug, Default)]
+pub struct MemoryKv {
+    data: Mutex<BTreeMap<String, Value>>,
+}
+
+impl MemoryKv {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KvBackend for MemoryKv {
+    fn get(&self, key: String) -> BoxFuture<'_, KvResult<Option<Value>>> {
+        let value = self.data.lock().unwrap().get(&key).cloned();
+        Box::pin(future::ready(Ok(value)))
+    }
+
+    fn set(&self, key: String, value: Value) -> BoxFuture<'_, KvResult<()>> {
+        self.data.lock().unwrap().insert(key, value);
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn delete(&self, key: String) -> BoxFuture<'_, KvResult<bool>> {
+        let existed = self.data.lock().unwrap().remove(&key).is_some();
+        Box::pin(future::ready(Ok(existed)))
+    }
+
+    fn list(&self, prefix: String) -> BoxFuture<'_, KvResult<Vec<String>>> {
+        let keys = self
+            .data
+            .lock()
+            .unwrap()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, _)| key.clone())
+            .collect();
+        Box::pin(future::ready(Ok(keys)))
+    }
+}
+
+/// A call to the `kv` global from a script.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KvRequestData {
+    /// Matches the request to its response. This is unique per connection.
+    pub id: u32,
+    /// What the script asked for
+    #[serde(flatten)]
+    pub operation: KvOperation,
+}
+
+/// An operation on the `kv` global.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum KvOperation {
+    /// `kv.get(key)`
+    Get {
+        /// The key to read
+        key: String,
+    },
+    /// `kv.set(key, value)`
+    Set {
+        /// The key to write
+        key: String,
+        /// The value to store
+        value: Value,
+    },
+    /// `kv.delete(key)`
+    Delete {
+        /// The key to remove
+        key: String,
+    },
+    /// `kv.list(prefix)`
+    List {
+        /// Only keys that start with this are listed
+        prefix: String,
+    },
+}
+
+/// The answer to a [KvRequestData].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KvResponseData {
+    id: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn run_operation(backend: &dyn KvBackend, operation: KvOperation) -> KvResult<Value> {
+    Ok(match operation {
+        KvOperation::Get { key } => backend.get(key).await?.unwrap_or(Value::Null),
+        KvOperation::Set { key, value } => {
+            backend.set(key, value).await?;
+            Value::Null
+        }
+        KvOperation::Delete { key } => Value::Bool(backend.delete(key).await?),
+        KvOperation::List { prefix } => Value::from(backend.list(prefix).await?),
+    })
+}
+
+/// Run a script's KV request against the backend and send the result back to the worker.
+pub(crate) async fn serve(
+    backend: Arc<dyn KvBackend>,
+    request_id: u32,
+    request: KvRequestData,
+    stream: Arc<AsyncMutex<WriteHalf>>,
+    frame: FrameOptions,
+) {
+    let response = match run_operation(backend.as_ref(), request.operation).await {
+        Ok(value) => KvResponseData {
+            id: request.id,
+            value: Some(value),
+            error: None,
+        },
+        Err(e) => KvResponseData {
+            id: request.id,
+            value: None,
+            error: Some(e.to_string()),
+        },
+    };
+
+    let message =
+        HostToWorkerMessage::new(request_id, 0, HostToWorkerMessageData::KvResponse(response));
+    let mut stream = stream.lock().await;
+    if let Err(e) = message.write_to(frame, &mut *stream).await {
+        tracing::warn!(error = ?e, "Failed to send KV response to worker");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_kv_list() {
+        let kv = MemoryKv::new();
+        for key in ["a", "b/1", "b/2", "c"] {
+            kv.set(key.to_string(), Value::Null).await.unwrap();
+        }
+
+        assert_eq!(kv.list("b/".to_string()).await.unwrap(), vec!["b/1", "b/2"]);
+        assert_eq!(kv.list(String::new()).await.unwrap().len(), 4);
+        assert!(kv.delete("a".to_string()).await.unwrap());
+        assert!(!kv.delete("a".to_string()).await.unwrap());
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How much weight each new sample gets in the moving average.
+const EWMA_ALPHA: f64 = 0.2;
+
+/// A worker is considered slow if its average latency is this many times the median across
+/// all workers.
+const SLOW_WORKER_FACTOR: f64 = 1.5;
+
+/// How long after its last run a worker's average is forgotten, since the worker has probably
+/// exited and its process ID may be reused.
+const STALE_LATENCY_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// An exponentially-weighted moving average of run latency.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Ewma {
+    /// The average in microseconds
+    value: f64,
+    samples: u64,
+}
+
+impl Ewma {
+    pub fn record(&mut self, latency: Duration) {
+        let sample = latency.as_secs_f64() * 1_000_000.0;
+        self.value = if self.samples == 0 {
+            sample
+        } else {
+            EWMA_ALPHA * sample + (1.0 - EWMA_ALPHA) * self.value
+        };
+        self.samples += 1;
+    }
+
+    pub fn snapshot(&self) -> Option<LatencyStats> {
+        (self.samples > 0).then(|| LatencyStats {
+            average: Duration::from_secs_f64(self.value / 1_000_000.0),
+            samples: self.samples,
+        })
+    }
+}
+
+/// Recent latency of script runs, from sending the script to receiving its result.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyStats {
+    /// The exponentially-weighted moving average of the latency, favoring recent runs.
+    pub average: Duration,
+    /// How many runs have been measured.
+    pub samples: u64,
+}
+
+/// Latency averages for each worker process, shared by all the connections to the sidecar.
+/// Workers that haven't run anything recently are left out.
+#[derive(Debug)]
+pub(crate) struct WorkerLatencies {
+    workers: Mutex<HashMap<u32, (Ewma, Instant)>>,
+    max_age: Duration,
+}
+
+impl Default for WorkerLatencies {
+    fn default() -> Self {
+        Self::new(STALE_LATENCY_AGE)
+    }
+}
+
+impl WorkerLatencies {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            workers: Mutex::new(HashMap::new()),
+            max_age,
+        }
+    }
+
+    pub fn record(&self, pid: u32, latency: Duration) {
+        let now = Instant::now();
+        let mut workers = self.workers.lock().unwrap();
+        workers.retain(|_, (_, at)| now.duration_since(*at) < self.max_age);
+        let (ewma, at) = workers.entry(pid).or_insert((Ewma::default(), now));
+        ewma.record(latency);
+        *at = now;
+    }
+
+    /// The averages of the workers that have run something recently.
+    fn recent(&self) -> HashMap<u32, Ewma> {
+        let now = Instant::now();
+        self.workers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, (_, at))| now.duration_since(*at) < self.max_age)
+            .map(|(pid, (ewma, _))| (*pid, *ewma))
+            .collect()
+    }
+
+    pub fn snapshot(&self) -> HashMap<u32, LatencyStats> {
+        self.recent()
+            .iter()
+            .filter_map(|(pid, ewma)| Some((*pid, ewma.snapshot()?)))
+            .collect()
+    }
+
+    /// Returns true if the worker's average latency is well above the median of all workers.
+    /// Workers without any measurements are never considered slow.
+    pub fn is_slow(&self, pid: u32) -> bool {
+        let workers = self.recent();
+        let Some(worker) = workers.get(&pid).filter(|w| w.samples > 0) else {
+            return false;
+        };
+
+        let mut values = workers
+            .values()
+            .filter(|w| w.samples > 0)
+            .map(|w| w.value)
+            .collect::<Vec<_>>();
+        if values.len() < 2 {
+            return false;
+        }
+
+        values.sort_by(f64::total_cmp);
+        let median = values[values.len() / 2];
+        worker.value > median * SLOW_WORKER_FACTOR
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ewma() {
+        let mut ewma = Ewma::default();
+        assert!(ewma.snapshot().is_none());
+
+        ewma.record(Duration::from_millis(10));
+        assert_eq!(ewma.snapshot().unwrap().average, Duration::from_millis(10));
+
+        ewma.record(Duration::from_millis(20));
+        let stats = ewma.snapshot().unwrap();
+        assert_eq!(stats.samples, 2);
+        assert_eq!(stats.average.as_micros(), 12_000);
+    }
+
+    #[test]
+    fn slow_workers() {
+        let latencies = WorkerLatencies::default();
+        latencies.record(1, Duration::from_millis(1));
+        assert!(!latencies.is_slow(1), "a single worker is never slow");
+
+        latencies.record(2, Duration::from_millis(1));
+        latencies.record(3, Duration::from_millis(10));
+        assert!(!latencies.is_slow(1));
+        assert!(!latencies.is_slow(2));
+        assert!(latencies.is_slow(3));
+        assert!(!latencies.is_slow(4));
+        assert_eq!(latencies.snapshot().len(), 3);
+    }
+
+    #[test]
+    fn stale_workers() {
+        let latencies = WorkerLatencies::new(Duration::ZERO);
+        latencies.record(1, Duration::from_millis(1));
+        latencies.record(2, Duration::from_millis(10));
+        assert!(latencies.snapshot().is_empty());
+        assert!(!latencies.is_slow(2));
+        assert_eq!(latencies.workers.lock().unwrap().len(), 1);
+    }
+}
@@ -1,12 +1,77 @@
 //! js_sidecar is s Rust crate that makes it easy to JavaScript instead of embedding a JS library directly into the application,
 //! passes JavaScript code to a separate, persistent Node.js process for execution.
 //!
+mod affinity;
+mod approval;
+mod audit;
+pub mod background;
+pub mod blocking;
 #[deny(missing_docs)]
+mod builder;
+mod cgroup;
+mod channel;
+mod checkout;
+mod cluster;
 mod connection;
+mod corpus;
 mod error;
+mod events;
+mod globals;
+mod hardening;
+mod jobs;
+mod js_value;
+mod kv;
+mod latency;
+mod limits;
+mod load;
+mod management;
+mod memory;
 mod messages;
-mod protocol;
+mod prewarm;
+mod process;
+mod registry;
+mod replay;
+mod resolver;
+mod schedule;
+mod shared_memory;
+mod sink;
+mod template;
+mod tenants;
+pub mod test_harness;
+pub mod testing;
+mod transport;
+pub mod versions;
+#[deny(missing_docs)]
+pub mod wire;
 
+pub use affinity::KeyedConnection;
+pub use approval::ScriptVerifier;
+pub use audit::*;
+pub use builder::*;
+pub use cgroup::CgroupLimits;
+pub use channel::{ChannelOverflow, DEFAULT_CHANNEL_SIZE};
+pub use checkout::PoolStatus;
+pub use cluster::{ClusterMemberStatus, SidecarCluster, SidecarClusterBuilder};
 pub use connection::*;
+pub use corpus::*;
+pub use deadpool::managed::QueueMode;
 pub use error::Error;
+pub use events::{SidecarEvent, SidecarEvents};
+pub use globals::FromScriptGlobals;
+pub use hardening::Hardening;
+pub use jobs::JobHandle;
+pub use js_value::{JsValue, TypedArrayKind};
+pub use kv::{KvBackend, KvOperation, KvRequestData, KvResult, MemoryKv};
+pub use latency::LatencyStats;
+pub use management::{SidecarHealth, WorkerStats};
 pub use messages::*;
+pub use prewarm::*;
+pub use registry::{RegisteredScript, ScriptRegistry};
+pub use replay::{RecordedMessage, Recording, ReplayDivergence, ReplayResult};
+pub use resolver::{ModuleResolver, ResolveModuleRequest, ResolveResult};
+pub use schedule::{CronSchedule, ScheduleStore, ScheduleStoreResult, ScheduledScript};
+pub use sink::MessageSink;
+pub use template::ScriptTemplate;
+pub use tenants::{TenantQuota, TenantStats};
+pub use transport::{ReadHalf, Transport, WriteHalf};
+pub use wire::{decode_frame, DecodedFrame, WorkerToHostMessage, WorkerToHostMessageData};
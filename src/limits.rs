@@ -0,0 +1,87 @@
+use crate::Error;
+
+/// Check that every string inside `value` is no longer than `limit` bytes.
+pub(crate) fn check_string_lengths(
+    value: &serde_json::Value,
+    path: &str,
+    limit: usize,
+) -> Result<(), Error> {
+    match value {
+        serde_json::Value::String(s) if s.len() > limit => Err(Error::StringTooLong {
+            path: path.to_string(),
+            length: s.len(),
+            limit,
+        }),
+        serde_json::Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, item)| check_string_lengths(item, &format!("{path}[{i}]"), limit)),
+        serde_json::Value::Object(map) => map
+            .iter()
+            .try_for_each(|(key, item)| check_string_lengths(item, &key_path(path, key), limit)),
+        _ => Ok(()),
+    }
+}
+
+/// The path to the `key` field of the object at `path`, quoting keys that aren't identifiers so
+/// that a key like `a.b` can't be mistaken for a nested field.
+pub(crate) fn key_path(path: &str, key: &str) -> String {
+    let mut chars = key.chars();
+    let identifier = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+    if identifier {
+        format!("{path}.{key}")
+    } else {
+        format!("{path}[{}]", serde_json::Value::from(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn within_limit() {
+        let value = json!({ "a": "abc", "b": ["de", { "c": "f" }] });
+        check_string_lengths(&value, "globals.x", 3).unwrap();
+    }
+
+    #[test]
+    fn reports_nested_path() {
+        let value = json!({ "a": "abc", "b": ["de", { "c": "ffff" }] });
+        let err = check_string_lengths(&value, "globals.x", 3).unwrap_err();
+        let Error::StringTooLong {
+            path,
+            length,
+            limit,
+        } = err
+        else {
+            panic!("Expected StringTooLong, saw {err:?}");
+        };
+
+        assert_eq!(path, "globals.x.b[1].c");
+        assert_eq!(length, 4);
+        assert_eq!(limit, 3);
+    }
+
+    #[test]
+    fn quotes_keys_that_are_not_identifiers() {
+        let value = json!({ "a.b": { "0": "ffff" } });
+        let Err(Error::StringTooLong { path, .. }) = check_string_lengths(&value, "globals", 3)
+        else {
+            panic!("Expected StringTooLong");
+        };
+        assert_eq!(path, r#"globals["a.b"]["0"]"#);
+    }
+
+    #[test]
+    fn counts_utf8_bytes() {
+        // 2 characters, 6 bytes
+        let value = json!("日本");
+        assert!(check_string_lengths(&value, "globals.x", 5).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::WorkerLoadData;
+
+/// How many report intervals can pass without a report before a worker's last report is ignored,
+/// since the worker has probably exited.
+const STALE_REPORT_INTERVALS: u32 = 4;
+
+/// The latest load reported by each worker process, shared by all the connections to the sidecar.
+#[derive(Debug)]
+pub(crate) struct WorkerLoads {
+    workers: Mutex<HashMap<u32, (WorkerLoadData, Instant)>>,
+    max_age: Duration,
+}
+
+impl WorkerLoads {
+    pub fn new(report_interval: Duration) -> Self {
+        Self {
+            workers: Mutex::new(HashMap::new()),
+            max_age: report_interval * STALE_REPORT_INTERVALS,
+        }
+    }
+
+    pub fn record(&self, load: WorkerLoadData) {
+        let now = Instant::now();
+        let mut workers = self.workers.lock().unwrap();
+        workers.retain(|_, (_, at)| now.duration_since(*at) < self.max_age);
+        workers.insert(load.pid, (load, now));
+    }
+
+    /// The latest report from each worker that is still reporting.
+    pub fn snapshot(&self) -> HashMap<u32, WorkerLoadData> {
+        let now = Instant::now();
+        self.workers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, (_, at))| now.duration_since(*at) < self.max_age)
+            .map(|(pid, (load, _))| (*pid, *load))
+            .collect()
+    }
+
+    /// Returns true if the worker is running more scripts than the least busy worker. Workers
+    /// without a recent report are never considered busier.
+    pub fn is_busier(&self, pid: u32) -> bool {
+        let workers = self.snapshot();
+        let Some(load) = workers.get(&pid) else {
+            return false;
+        };
+
+        let least = workers.values().map(|w| w.active_runs).min();
+        least.is_some_and(|least| load.active_runs > least)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(pid: u32, active_runs: u32) -> WorkerLoadData {
+        WorkerLoadData {
+            pid,
+            active_runs,
+            connections: 1,
+            event_loop_delay_ms: 0.0,
+        }
+    }
+
+    #[test]
+    fn busier_workers() {
+        let loads = WorkerLoads::new(Duration::from_secs(60));
+        loads.record(load(1, 2));
+        assert!(!loads.is_busier(1), "a single worker is never busier");
+
+        loads.record(load(2, 0));
+        loads.record(load(3, 0));
+        assert!(loads.is_busier(1));
+        assert!(!loads.is_busier(2));
+        assert!(!loads.is_busier(3));
+        assert!(!loads.is_busier(4));
+
+        loads.record(load(1, 0));
+        assert!(!loads.is_busier(1));
+        assert_eq!(loads.snapshot().len(), 3);
+    }
+
+    #[test]
+    fn stale_reports() {
+        let loads = WorkerLoads::new(Duration::ZERO);
+        loads.record(load(1, 5));
+        loads.record(load(2, 0));
+        assert!(loads.snapshot().is_empty());
+        assert!(!loads.is_busier(1));
+    }
+}
@@ -0,0 +1,86 @@
+use std::net::SocketAddr;
+
+use serde::{de::DeserializeOwned, Deserialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{Error, MemoryUsageData};
+
+/// The state of the Node.js process, from [JsSidecar::health](crate::JsSidecar::health).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SidecarHealth {
+    /// The process ID of the Node.js primary process
+    pub pid: u32,
+    /// The number of worker processes that are running
+    pub workers: usize,
+    /// How long the Node.js process has been running, in milliseconds
+    pub uptime_ms: f64,
+}
+
+/// Statistics for a worker process, from [JsSidecar::worker_stats](crate::JsSidecar::worker_stats).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkerStats {
+    /// The process ID of the worker
+    pub pid: u32,
+    /// How long the worker has been running, in milliseconds
+    pub uptime_ms: f64,
+    /// The number of open connections to the worker
+    pub connections: usize,
+    /// The number of connections with a run context, which holds the state left by earlier runs
+    pub contexts: usize,
+    /// The number of requests that are currently running
+    pub active_requests: usize,
+    /// The number of scripts run since the worker started
+    pub requests_handled: u64,
+    /// The worker's current memory usage
+    pub memory: MemoryUsageData,
+}
+
+/// Make a GET request to the management endpoint and parse the JSON response.
+pub(crate) async fn get_json<T: DeserializeOwned>(
+    addr: SocketAddr,
+    path: &str,
+) -> Result<T, Error> {
+    let mut stream = TcpStream::connect(addr)
+        .await
+        .map_err(|e| Error::Management(format!("Failed to connect to {addr}: {e}")))?;
+
+    let request =
+        format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\nAccept: application/json\r\n\r\n");
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(Error::WriteStream)?;
+
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .await
+        .map_err(Error::ReadStream)?;
+
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| Error::Management("Malformed HTTP response".to_string()))?;
+    let head = String::from_utf8_lossy(&response[..header_end]);
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| Error::Management("Malformed HTTP response".to_string()))?;
+
+    let body = &response[header_end + 4..];
+    if status != 200 {
+        return Err(Error::Management(format!(
+            "{path} returned status {status}: {}",
+            String::from_utf8_lossy(body)
+        )));
+    }
+
+    serde_json::from_slice(body)
+        .map_err(|e| Error::Management(format!("Invalid response from {path}: {e}")))
+}
@@ -0,0 +1,181 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{MemoryUsageData, SidecarEvent};
+
+/// The latest memory usage reported by each worker process, shared by all the connections to the
+/// sidecar, and the watchdog that replaces workers whose heap grows too large.
+#[derive(Debug)]
+pub(crate) struct WorkerMemory {
+    workers: Mutex<HashMap<u32, MemoryUsageData>>,
+    /// Workers that have been told to retire, which may still send a few reports before exiting.
+    retiring: Mutex<HashSet<u32>>,
+    /// The workers started by the sidecar's own Node.js process, which are the only ones that it
+    /// signals. Workers behind a WebSocket or a custom transport can report the PID of a process
+    /// on another machine or in another namespace.
+    children: Mutex<HashSet<u32>>,
+    max_heap_bytes: Option<u64>,
+    events: broadcast::Sender<SidecarEvent>,
+}
+
+impl WorkerMemory {
+    pub fn new(max_heap_bytes: Option<u64>, events: broadcast::Sender<SidecarEvent>) -> Self {
+        Self {
+            workers: Mutex::new(HashMap::new()),
+            retiring: Mutex::new(HashSet::new()),
+            children: Mutex::new(HashSet::new()),
+            max_heap_bytes,
+            events,
+        }
+    }
+
+    pub fn record(&self, usage: MemoryUsageData) {
+        if self.retiring.lock().unwrap().contains(&usage.pid) {
+            return;
+        }
+
+        let over_limit = self.max_heap_bytes.is_some_and(|max| usage.heap_used > max);
+        if !over_limit {
+            self.workers.lock().unwrap().insert(usage.pid, usage);
+            return;
+        }
+
+        if !self.retiring.lock().unwrap().insert(usage.pid) {
+            return;
+        }
+        self.workers.lock().unwrap().remove(&usage.pid);
+
+        tracing::warn!(
+            pid = usage.pid,
+            heap_used = usage.heap_used,
+            "Replacing worker that exceeded the heap limit"
+        );
+
+        // The worker finishes its current requests and exits, and the Node.js primary process
+        // starts a replacement. Zero and negative values would signal whole process groups.
+        let is_child = self.children.lock().unwrap().contains(&usage.pid);
+        if let Ok(pid) = i32::try_from(usage.pid) {
+            if is_child && pid > 0 {
+                nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), nix::sys::signal::SIGUSR2)
+                    .ok();
+            }
+        }
+
+        // An error just means that there are no subscribers right now.
+        self.events
+            .send(SidecarEvent::WorkerRetired {
+                pid: usage.pid,
+                heap_used: usage.heap_used,
+            })
+            .ok();
+    }
+
+    /// Keep track of the workers that the sidecar's Node.js process starts and stops, from its
+    /// events, and forget retired workers once they exit. This is only called for workers on the
+    /// local socket, and the task ends once the sidecar is gone.
+    pub fn track_children(self: &Arc<Self>, mut events: broadcast::Receiver<SidecarEvent>) {
+        let memory = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return,
+                };
+                let Some(memory) = memory.upgrade() else {
+                    return;
+                };
+                match event {
+                    SidecarEvent::WorkerStarted { pid } => {
+                        memory.children.lock().unwrap().insert(pid);
+                    }
+                    SidecarEvent::WorkerExited { pid, .. } => {
+                        memory.children.lock().unwrap().remove(&pid);
+                        // PIDs are reused, so a later worker with the same one has to be tracked.
+                        memory.retiring.lock().unwrap().remove(&pid);
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    pub fn snapshot(&self) -> HashMap<u32, MemoryUsageData> {
+        self.workers.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Above the maximum PID on Linux and macOS, so signals to it go nowhere.
+    const NO_PROCESS: u32 = 1 << 30;
+
+    fn usage(pid: u32, heap_used: u64) -> MemoryUsageData {
+        MemoryUsageData {
+            pid,
+            heap_used,
+            heap_total: heap_used * 2,
+            rss: heap_used * 4,
+            external: 0,
+        }
+    }
+
+    #[test]
+    fn retires_workers_over_the_limit() {
+        let (events, mut receiver) = broadcast::channel(16);
+        let memory = WorkerMemory::new(Some(1000), events);
+
+        memory.record(usage(NO_PROCESS, 500));
+        assert_eq!(memory.snapshot()[&NO_PROCESS].heap_used, 500);
+
+        memory.record(usage(NO_PROCESS, 2000));
+        assert!(memory.snapshot().is_empty());
+        let SidecarEvent::WorkerRetired { pid, heap_used } = receiver.try_recv().unwrap() else {
+            panic!("Expected WorkerRetired event");
+        };
+        assert_eq!(pid, NO_PROCESS);
+        assert_eq!(heap_used, 2000);
+
+        // Reports from a retiring worker are ignored.
+        memory.record(usage(NO_PROCESS, 3000));
+        assert!(memory.snapshot().is_empty());
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn tracks_children() {
+        let (events, _) = broadcast::channel(16);
+        let memory = Arc::new(WorkerMemory::new(Some(1000), events.clone()));
+        memory.track_children(events.subscribe());
+
+        events
+            .send(SidecarEvent::WorkerStarted { pid: NO_PROCESS })
+            .unwrap();
+        tokio::task::yield_now().await;
+        assert!(memory.children.lock().unwrap().contains(&NO_PROCESS));
+
+        memory.record(usage(NO_PROCESS, 2000));
+        assert!(memory.retiring.lock().unwrap().contains(&NO_PROCESS));
+
+        events
+            .send(SidecarEvent::WorkerExited {
+                pid: NO_PROCESS,
+                code: Some(0),
+                signal: None,
+            })
+            .unwrap();
+        tokio::task::yield_now().await;
+        assert!(memory.children.lock().unwrap().is_empty());
+        assert!(memory.retiring.lock().unwrap().is_empty());
+
+        // A new worker that reuses the PID is watched again.
+        memory.record(usage(NO_PROCESS, 500));
+        assert_eq!(memory.snapshot()[&NO_PROCESS].heap_used, 500);
+    }
+}
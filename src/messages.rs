@@ -1,9 +1,13 @@
-use std::{borrow::Cow, collections::HashMap};
+use std::{borrow::Cow, collections::HashMap, path::PathBuf};
 
-use serde::{Deserialize, Serialize};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{Error, JsValue, KvOperation};
 
 /// A function to be injected into the context.
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FunctionDef {
     /// The name of the function
     pub name: Cow<'static, str>,
@@ -14,17 +18,35 @@ pub struct FunctionDef {
 }
 
 /// A ES Module to be importable by the script
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CodeModule {
-    /// The name of the module, as it should appear in import statements.
+    /// The name of the module, as it should appear in import statements. Names that look like
+    /// file paths, such as `lib/format.js`, can also be imported with relative specifiers from
+    /// other modules, such as `./format.js` from `lib/index.js`.
     pub name: Cow<'static, str>,
     /// The JavaScript code of the model.
     pub code: Cow<'static, str>,
 }
 
-/// Data associated with the RunScript message
+/// A WebAssembly module to instantiate in the script's context
 #[derive(Debug, Clone, Serialize)]
-#[serde(rename_all = "camelCase")]
+pub struct WasmModule {
+    /// The name of the global that holds the instance's exports.
+    pub name: Cow<'static, str>,
+    /// The contents of the `.wasm` file. Use [Bytes::from_static] for a module included in the
+    /// binary, or clone the same [Bytes] for each run, to avoid copying it.
+    #[serde(rename = "length", serialize_with = "serialize_length")]
+    pub bytes: Bytes,
+}
+
+/// The bytes follow the JSON in the frame, so only their length goes in the JSON.
+fn serialize_length<S: serde::Serializer>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(bytes.len() as u64)
+}
+
+/// Data associated with the RunScript message
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
 pub struct RunScriptArgs {
     pub name: Cow<'static, str>,
 
@@ -41,7 +63,49 @@ pub struct RunScriptArgs {
     /// Global variables to set in the context.
     pub globals: HashMap<Cow<'static, str>, serde_json::Value>,
 
-    /// How long to wait for the script to complete.
+    /// If true, `code` is a function expression, a function declaration, or the name of a function
+    /// in the context, which is called with [args](Self::args). The function's return value,
+    /// awaited if it is a Promise, becomes the run's return value, and globals are only returned
+    /// if they are listed in [return_keys](Self::return_keys).
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub call: bool,
+
+    /// The arguments to pass to the function in call mode.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<serde_json::Value>,
+
+    /// Wait for any Promises in the returned globals to settle and return their resolved values,
+    /// instead of the empty objects that unresolved Promises serialize to. A rejection fails the
+    /// run with the rejection's error.
+    ///
+    /// The return value is always awaited, whether it is the value of an expression, the result
+    /// of a function in call mode, or the `export default` of a script. So a script can report
+    /// the result of an async IIFE with `export default (async () => { ... })();`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub await_result: bool,
+
+    /// Send values in an extended encoding that preserves Dates, Maps, Sets, BigInts, typed
+    /// arrays, `undefined`, and non-finite numbers, instead of converting them to JSON.
+    ///
+    /// When set, globals and call arguments are decoded from the tagged form produced by
+    /// [JsValue::to_tagged](crate::JsValue::to_tagged), and the globals and return value of the
+    /// response are encoded in the same way. Use [RunResponseData::return_js_value] and
+    /// [RunResponseData::global_js_value] to read them.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub extended_values: bool,
+
+    /// How long to wait for the script to complete. Synchronous code that runs for longer, such as
+    /// an infinite loop, is interrupted. When this passes while the script waits on promises, the
+    /// `signal` global that the script sees is aborted with a `TimeoutError`, and the run fails in
+    /// the same way as one stopped with [Connection::cancel](crate::Connection::cancel).
+    ///
+    /// Either way, the run fails with an error whose [timed_out](ErrorResponseData::timed_out)
+    /// is set, after any console messages that the script logged before it was stopped, and the
+    /// worker throws away the context. Code that blocks the worker outside of the run itself, such
+    /// as a timer callback that never returns, can't be interrupted, so
+    /// [run_script_and_wait](crate::Connection::run_script_and_wait) kills a worker that hasn't
+    /// answered by [kill_after_timeout](crate::JsSidecarBuilder::kill_after_timeout) past the
+    /// timeout.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub timeout_ms: Option<u64>,
 
@@ -49,48 +113,951 @@ pub struct RunScriptArgs {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub functions: Vec<FunctionDef>,
 
-    /// ES Modules to make available for the code to import.
+    /// ES Modules to make available for the code to import. Modules can import each other, either
+    /// by name or with a specifier relative to the importing module's name, and a relative
+    /// specifier can leave out a `.js` or `.mjs` extension or a trailing `/index.js`.
+    ///
+    /// Modules can import each other in a cycle, as long as they don't use each other's exports
+    /// before they are initialized. If they do, the error names the modules in the cycle.
+    ///
+    /// Each run gets new instances of the modules that it passes, so module state doesn't carry
+    /// over between runs. When a later run on the same context passes a module with the same name
+    /// and code again, the code isn't sent again. Compiled code is cached by the worker for every
+    /// context, so the same module is quicker to load in later runs and in other contexts.
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub modules: Vec<CodeModule>,
 
     /// If set, return only these keys from the context. If omitted, the entire global context is returned.
+    ///
+    /// Each key can also be a path that selects part of a global, and the selected value is
+    /// returned under the path itself, so large objects don't have to be sent whole.
+    /// - `user.profile.name` and `items[0]` select properties and array elements.
+    /// - `user["first.last"]` selects a property whose name contains `.` or brackets.
+    /// - `items[*].id` or `items.*.id` selects from every element of an array, giving an array,
+    ///   or from every property of an object, giving an object.
+    /// - `cache.user_*` selects the properties whose names match the glob, as an object.
+    ///
+    /// Paths that don't exist are left out of the globals, and a malformed path fails the run
+    /// before the script starts.
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub return_keys: Vec<String>,
+
+    /// `debug`-style patterns for the console namespaces to forward, such as `["db:*", "-db:verbose"]`.
+    /// A message's namespace comes from a `[namespace]` prefix on the message or from a console
+    /// created with the `logger(namespace)` global. Messages without a namespace are always
+    /// forwarded, and an empty list forwards everything.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub log_namespaces: Vec<String>,
+
+    /// The name of a function in the context that handles the script's console calls for this
+    /// run, instead of forwarding them to the host. Define it with [functions](Self::functions),
+    /// in a module, or in an earlier run on the context.
+    ///
+    /// The handler is called with an entry `{ level, args, namespace }` and a
+    /// `forward(level, args, namespace)` function that sends a message to the host, so it can
+    /// sample messages by dropping some of them, or add structured fields to the arguments before
+    /// forwarding them. Console calls from the handler itself are forwarded as usual.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub console_handler: Option<Cow<'static, str>>,
+
+    /// The maximum size, in UTF-8 bytes, of any single string in the globals or return value.
+    /// Defaults to the sidecar's [max_string_bytes](crate::JsSidecarBuilder::max_string_bytes) setting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_string_bytes: Option<usize>,
+
+    /// Replace unpaired UTF-16 surrogates in returned strings with U+FFFD instead of failing.
+    /// This is always enabled if the sidecar was built with
+    /// [replace_invalid_unicode](crate::JsSidecarBuilder::replace_invalid_unicode).
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub replace_invalid_unicode: bool,
+
+    /// Small pieces of metadata about the run, such as a user ID or feature name. The worker
+    /// includes these in error stacks and crash reports, so that problems can be traced back to
+    /// the part of the application that triggered them.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+
+    /// Wait for a debugger, such as Chrome DevTools or VS Code, to attach to the worker's
+    /// inspector, and then pause at the first statement of the code. In call mode, this pauses
+    /// just before the function is called, so that the debugger can step into it.
+    ///
+    /// The inspector's URL is available from [JsSidecar::inspector_url](crate::JsSidecar::inspector_url)
+    /// once the worker is waiting. The worker can't do anything else while it waits, so this is
+    /// meant for development only.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub debug: bool,
+
+    /// Record a V8 CPU profile of the run. The profile is sent just before the response, and
+    /// [run_script_and_wait](crate::Connection::run_script_and_wait) returns it in
+    /// [cpu_profile](crate::RunScriptAndWaitResult::cpu_profile).
+    ///
+    /// Profiling slows the script down, so timings in the profile are best compared with each
+    /// other rather than with unprofiled runs.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub profile: bool,
+
+    /// Fail the run if any of the globals to be returned can't be sent to the host, instead of
+    /// leaving them out and listing them in [RunResponseData::skipped_globals].
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub strict_globals: bool,
+
+    /// How `setTimeout` and `setInterval` behave in the context. This is applied when the context
+    /// is created, so it only takes effect on the first run on a connection, or on a run with
+    /// [recreate_context](Self::recreate_context) set.
+    #[serde(skip_serializing_if = "TimerMode::is_real")]
+    pub timers: TimerMode,
+
+    /// The most timers that the run can set, counting timers set by other timers. Setting another
+    /// one throws a `RangeError` in the script.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_timers: Option<u32>,
+
+    /// What to do with timers that the script leaves pending when it finishes, such as
+    /// fire-and-forget work that it didn't await.
+    #[serde(skip_serializing_if = "DrainPolicy::is_return")]
+    pub drain: DrainPolicy,
+
+    /// With [DrainPolicy::Wait], the most time to wait for pending timers, in milliseconds.
+    /// Defaults to 1000. The run's [timeout_ms](Self::timeout_ms) still applies while it waits.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_drain_ms: Option<u32>,
+
+    /// Give the script a `write` global that sends strings and bytes to the host in `Output`
+    /// messages as soon as it is called. `write` returns a promise that resolves once the chunk has been sent, which a
+    /// script writing a lot of output can await to keep from getting ahead of the connection.
+    ///
+    /// [Connection::run_script_to_writer](crate::Connection::run_script_to_writer) sets this and
+    /// copies the output to a writer. Calling `write` without it throws.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stream_output: bool,
+
+    /// Give the script an `input` global that yields the chunks of bytes sent with
+    /// [Connection::send_input](crate::Connection::send_input), as `Uint8Array`s, until
+    /// [Connection::end_input](crate::Connection::end_input) is called. `input.text()` yields
+    /// the same chunks decoded as UTF-8.
+    ///
+    /// ```js
+    /// for await (const text of input.text()) {
+    ///   // ...
+    /// }
+    /// ```
+    ///
+    /// Without this, `input` is undefined unless the host has already sent input for the run.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stream_input: bool,
+
+    /// Run the code as an entry in a REPL. This works like [expr](Self::expr) mode, except that
+    /// the return value is a string showing the value the way that Node's REPL does, and globals
+    /// are only returned if they are listed in [return_keys](Self::return_keys). Top-level `let`,
+    /// `const`, and `class` declarations stay in the context for later entries, and are offered
+    /// as completions. [Connection::repl](crate::Connection::repl) sets this.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub repl: bool,
+
+    /// Preview what the script would do, without doing it. Effects that would reach the host are
+    /// recorded in [RunResponseData::effects] instead of being carried out:
+    /// - `kv.set` and `kv.delete` calls. Reads with `kv.get` and `kv.list` still go to the
+    ///   [KvBackend](crate::KvBackend), and see the run's own writes as if they had happened.
+    /// - Output from the `write` global, which isn't sent to the host as it is written.
+    /// - Values sent with `channel.send`. Values that the host sends on the channel still reach the
+    ///   script.
+    ///
+    /// The [ModuleResolver](crate::ModuleResolver) isn't called, so the script can only import the
+    /// modules that the connection's context already has from earlier runs.
+    ///
+    /// The script still runs in the connection's context, so any globals that it changes stay
+    /// changed for later runs. Use a fresh connection, or set
+    /// [recreate_context](Self::recreate_context) on the next run, to leave them behind.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
+
+    /// Make the context deterministic, so that the same code and globals always produce the same
+    /// output:
+    /// - `Math.random` is seeded with [random_seed](Self::random_seed) at the start of each run.
+    /// - `new Date()` and `Date.now()` read a clock that starts at the Unix epoch, and only moves
+    ///   with [Connection::advance_time](crate::Connection::advance_time).
+    /// - Timers are disabled, unless [timers](Self::timers) is [TimerMode::Virtual].
+    /// - `WeakRef` and `FinalizationRegistry`, which depend on garbage collection, are removed.
+    ///
+    /// The time zone and locale still come from the worker process. Like
+    /// [timers](Self::timers), this only takes effect when the context is created.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub deterministic: bool,
+
+    /// The seed for `Math.random` in a [deterministic](Self::deterministic) context. Defaults to 0.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub random_seed: Option<u32>,
+
+    /// When the run starts with a new context, take the one that
+    /// [JsSidecar::prewarm](crate::JsSidecar::prewarm) built with this name on the worker, which
+    /// already has the manifest's globals, functions, and modules. Modules sent with the run that
+    /// have the same code as the prewarmed ones aren't compiled again.
+    ///
+    /// If the worker doesn't have the context ready, such as when the worker started after
+    /// prewarming, the run gets a fresh context as usual, so runs should still send the modules
+    /// and functions that they use. Prewarmed contexts use real timers, so they aren't used with
+    /// other [timers](Self::timers) modes or in [deterministic](Self::deterministic) mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prewarmed_context: Option<String>,
+
+    /// The most console messages that the run can send to the host. Once this or
+    /// [max_log_bytes](Self::max_log_bytes) is reached, the rest of the run's messages are
+    /// dropped, and a warning saying how many were dropped is logged just before the run's
+    /// response. Defaults to the sidecar's
+    /// [max_log_messages](crate::JsSidecarBuilder::max_log_messages) setting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_log_messages: Option<u32>,
+
+    /// The most bytes of console messages that the run can send to the host, measured as they are
+    /// sent. Defaults to the sidecar's [max_log_bytes](crate::JsSidecarBuilder::max_log_bytes)
+    /// setting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_log_bytes: Option<usize>,
+
+    /// Only send console messages at this level or above to the host. Messages below it are
+    /// dropped in the worker, and don't count toward [max_log_messages](Self::max_log_messages).
+    /// Defaults to the sidecar's [min_log_level](crate::JsSidecarBuilder::min_log_level) setting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_log_level: Option<LogLevel>,
+
+    /// WebAssembly modules to instantiate in the context before the code runs. Each module's
+    /// exports are placed in a global with the module's name, so a module named `math` that
+    /// exports `add` is called as `math.add(1, 2)`.
+    ///
+    /// The modules are instantiated again on every run, with no imports, so a module that imports
+    /// anything fails the run. The worker caches compiled modules, so sending the same bytes again
+    /// is cheap apart from the transfer.
+    // The bytes follow the JSON in the payload, so they can't be read back from the JSON alone.
+    #[serde(skip_serializing_if = "Vec::is_empty", skip_deserializing)]
+    pub wasm_modules: Vec<WasmModule>,
+
+    /// How urgent the run is. On the worker, a run doesn't start while a run with a higher
+    /// priority is active, so small latency-sensitive evaluations aren't stuck behind long batch
+    /// jobs. [JsSidecar::run](crate::JsSidecar::run) also gives waiters with a higher priority
+    /// the first connection that frees up in the pool.
+    #[serde(skip_serializing_if = "Priority::is_normal")]
+    pub priority: Priority,
+
+    /// The tenant that the run belongs to, for platforms that run code on behalf of many
+    /// customers. [JsSidecar::run](crate::JsSidecar::run) applies the tenant's
+    /// [quota](crate::JsSidecarBuilder::tenant_quota) and counts the run in
+    /// [JsSidecar::tenant_stats](crate::JsSidecar::tenant_stats). This isn't sent to the worker.
+    #[serde(skip)]
+    pub tenant: Option<String>,
+
+    /// A signature over the script, for a [ScriptVerifier](crate::ScriptVerifier) set with
+    /// [JsSidecarBuilder::script_verifier](crate::JsSidecarBuilder::script_verifier) to check
+    /// before the script runs. This isn't sent to the worker.
+    #[serde(skip)]
+    pub signature: Option<String>,
+
+    /// Values for the script to use, such as API keys, without risking them leaking back through
+    /// the run's results. The script reads them from a frozen `secrets` global, as in
+    /// `secrets.API_KEY`, which isn't returned with the other globals.
+    ///
+    /// Wherever a secret's value appears in the run's console messages, error message and stack,
+    /// returned globals, or return value, the worker replaces it with `[REDACTED]`. Only exact
+    /// copies of a value are caught, so a script that transforms a secret, such as by encoding it,
+    /// can still send it back. The secrets are removed from the context when a later run on it
+    /// doesn't pass them, but globals that a script copied them into are kept, and aren't
+    /// redacted in later runs.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub secrets: HashMap<String, String>,
 }
 
-impl Default for RunScriptArgs {
-    fn default() -> Self {
-        Self {
-            name: Default::default(),
-            code: Default::default(),
-            recreate_context: false,
-            expr: false,
-            globals: Default::default(),
-            timeout_ms: Default::default(),
-            functions: Default::default(),
-            modules: Default::default(),
-            return_keys: Default::default(),
-        }
+/// How `setTimeout` and `setInterval` behave in a script's context. Timers return numeric IDs,
+/// and an error thrown by a timer's callback is sent as an [AsyncErrorData].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimerMode {
+    /// Timers run on the worker's clock.
+    #[default]
+    Real,
+    /// Setting a timer throws an error.
+    Disabled,
+    /// Timers only run when the host moves the context's clock forward with
+    /// [Connection::advance_time](crate::Connection::advance_time). `new Date()` and `Date.now()`
+    /// read the same clock, which starts at the real time when the context is created.
+    Virtual,
+}
+
+impl TimerMode {
+    fn is_real(&self) -> bool {
+        *self == TimerMode::Real
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// What happens to the timers that a script leaves pending when it finishes. Only timers set by
+/// the script are tracked, so promises waiting on other things, such as a `fetch`, aren't waited
+/// for or cancelled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DrainPolicy {
+    /// Respond as soon as the script finishes, and let the timers run afterwards. Console
+    /// messages and errors from the timers are attributed to the run, or to a later run on the
+    /// same connection.
+    #[default]
+    Return,
+    /// Wait for the timers to finish, up to [max_drain_ms](RunScriptArgs::max_drain_ms), and
+    /// return the globals as they are afterwards. Timers that are still pending then, such as an
+    /// interval that is never cleared, keep running.
+    Wait,
+    /// Clear the timers when the script finishes, so that nothing it started keeps running.
+    Cancel,
+}
+
+impl DrainPolicy {
+    fn is_return(&self) -> bool {
+        *self == DrainPolicy::Return
+    }
+}
+
+/// The lane that a run waits in, both for a connection from the pool and on the worker.
+///
+/// A lower-priority run on a worker waits for at most a second for the higher-priority runs to
+/// finish before starting anyway, so a steady stream of urgent runs can't starve the others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Latency-sensitive work, such as small evaluations in a request handler.
+    High,
+    #[default]
+    Normal,
+    /// Batch jobs that can wait for other work.
+    Low,
+}
+
+impl Priority {
+    fn is_normal(&self) -> bool {
+        *self == Priority::Normal
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RunResponseData {
     #[serde(default)]
     pub globals: HashMap<String, serde_json::Value>,
     #[serde(default)]
     pub return_value: Option<serde_json::Value>,
+    /// The request that produced this response, as returned by
+    /// [Connection::run_script](crate::Connection::run_script)
+    #[serde(default)]
+    pub request_id: u32,
+    /// Timing and resource usage of the run, measured inside the worker. This is `None` for
+    /// runs without any code, and from older workers.
+    #[serde(default)]
+    pub stats: Option<RunStats>,
+    /// Globals that were left out of [globals](Self::globals) because they can't be sent to the
+    /// host, such as values with circular references, BigInts without
+    /// [extended_values](RunScriptArgs::extended_values), and objects that throw when read.
+    ///
+    /// Functions and symbols are listed only when they were asked for in
+    /// [return_keys](RunScriptArgs::return_keys), since contexts normally hold plenty of
+    /// functions that nobody expects to get back.
+    #[serde(default)]
+    pub skipped_globals: Vec<SkippedGlobal>,
+    /// What a run with [dry_run](RunScriptArgs::dry_run) set would have done, in order
+    #[serde(default)]
+    pub effects: Vec<Effect>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// An effect of a run with [dry_run](RunScriptArgs::dry_run) set, which was recorded instead of
+/// being carried out
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Effect {
+    /// A call to `kv.set` or `kv.delete`
+    Kv(KvOperation),
+    /// A chunk of output from the `write` global
+    Output {
+        /// The chunk, with strings encoded as UTF-8
+        #[serde(
+            serialize_with = "serialize_base64",
+            deserialize_with = "deserialize_base64"
+        )]
+        data: Vec<u8>,
+    },
+    /// A value sent with `channel.send`
+    Channel {
+        /// The value, as JSON
+        value: serde_json::Value,
+    },
+}
+
+pub(crate) fn serialize_base64<S: serde::Serializer>(
+    data: &[u8],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&BASE64.encode(data))
+}
+
+pub(crate) fn deserialize_base64<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    BASE64.decode(encoded).map_err(serde::de::Error::custom)
+}
+
+/// A global that was left out of a run's response. See [RunResponseData::skipped_globals].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkippedGlobal {
+    /// The name of the global, or the path from [return_keys](RunScriptArgs::return_keys)
+    pub key: String,
+    /// Why it couldn't be sent, such as `data.self is a circular reference`
+    pub reason: String,
+}
+
+/// Measurements of a single run, taken inside the worker so that they exclude time spent
+/// waiting for a connection or sending messages.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunStats {
+    /// Milliseconds spent compiling the script
+    pub compile_ms: f64,
+    /// Milliseconds spent running the script and waiting for its result, including awaiting
+    /// promises and, with [DrainPolicy::Wait], pending timers
+    pub execute_ms: f64,
+    /// The change in the worker's JavaScript heap usage over the run, in bytes. This can be
+    /// negative if garbage collection ran, and includes allocations from any other runs that
+    /// the worker was handling at the same time.
+    pub heap_used_delta: i64,
+    /// False if the run left timers or immediates pending in the worker that weren't there when
+    /// it started.
+    pub macrotasks_drained: bool,
+}
+
+impl RunResponseData {
+    /// Decode the return value from a run with
+    /// [extended_values](RunScriptArgs::extended_values) set.
+    pub fn return_js_value(&self) -> Result<Option<JsValue>, Error> {
+        self.return_value
+            .clone()
+            .map(JsValue::from_tagged)
+            .transpose()
+    }
+
+    /// Decode a global from a run with [extended_values](RunScriptArgs::extended_values) set.
+    pub fn global_js_value(&self, key: &str) -> Result<Option<JsValue>, Error> {
+        self.globals
+            .get(key)
+            .cloned()
+            .map(JsValue::from_tagged)
+            .transpose()
+    }
+}
+
+/// What kind of failure an [ErrorResponseData] is, so that callers can decide whether to retry a
+/// run or show its error to a user without matching on the message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorKind {
+    /// Any other error thrown by the script.
+    #[default]
+    Script,
+    /// The script, or one of its modules or functions, failed to parse.
+    SyntaxError,
+    /// The script used a variable that doesn't exist.
+    ReferenceError,
+    /// The run passed its [timeout](RunScriptArgs::timeout_ms). This is the same as
+    /// [timed_out](ErrorResponseData::timed_out).
+    Timeout,
+    /// The script ran out of memory, such as by building a string longer than V8 allows, or by
+    /// going over the heap limit of a worker thread.
+    OutOfMemory,
+    /// A module that the script imports couldn't be found, fetched, or resolved by the host.
+    ModuleResolution,
+    /// The run was stopped by [Connection::cancel](crate::Connection::cancel).
+    Cancelled,
+    /// The worker failed in a way that isn't the script's fault, such as receiving a damaged
+    /// message. Running the script again may succeed.
+    InternalWorkerError,
+    /// A string in the run's globals or result was over
+    /// [max_string_bytes](crate::JsSidecarBuilder::max_string_bytes). When the host finds the
+    /// string instead, it returns [Error::StringTooLong](crate::Error::StringTooLong), which has
+    /// this kind too.
+    StringTooLong,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ErrorResponseData {
     pub message: String,
     pub stack: Option<String>,
+    /// The [annotations](RunScriptArgs::annotations) of the run that failed.
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+    /// The request that failed, as returned by
+    /// [Connection::run_script](crate::Connection::run_script)
+    #[serde(default)]
+    pub request_id: u32,
+    /// True if the run was stopped because it passed its [timeout](RunScriptArgs::timeout_ms).
+    /// The script may have been interrupted partway through, so the worker throws away the
+    /// context, and the next run on the connection starts with a fresh one.
+    #[serde(default, rename = "timedOut")]
+    pub timed_out: bool,
+    /// What kind of failure this is.
+    #[serde(default)]
+    pub kind: ErrorKind,
+    /// The `name` of the thrown error, such as `TypeError`, if it was an error object.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The `code` of the thrown error, such as `ERR_MODULE_NOT_FOUND`, if it has one.
+    #[serde(default)]
+    pub code: Option<String>,
+    /// The error's `cause`, if it has one. Errors in the chain don't have their own
+    /// annotations or request ID.
+    #[serde(default)]
+    pub cause: Option<Box<ErrorResponseData>>,
+    /// The errors collected by an `AggregateError`, such as one from `Promise.any`. Up to 100
+    /// errors are included, and causes and aggregated errors are followed up to 8 levels deep.
+    #[serde(default)]
+    pub errors: Vec<ErrorResponseData>,
+}
+
+impl ErrorResponseData {
+    /// This error followed by each error in its chain of causes.
+    pub fn chain(&self) -> impl Iterator<Item = &ErrorResponseData> {
+        std::iter::successors(Some(self), |e| e.cause.as_deref())
+    }
 }
 
+/// Sent by the host when it connects, with its [PROTOCOL_VERSION](crate::versions::PROTOCOL_VERSION).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HandshakeData {
+    /// The host's protocol version
+    pub version: u32,
+    /// Ask the worker to add a checksum to every frame that it sends.
+    pub checksums: bool,
+    /// The largest frame the host will read. The worker splits run responses that are larger
+    /// than this into chunks, and replaces other messages that are larger with an error.
+    pub max_frame_bytes: usize,
+    /// The largest run response the host will accept. The worker sends an error instead of a
+    /// larger response.
+    pub max_response_bytes: Option<usize>,
+    /// Add the `kv` global to contexts, because the host has a [KvBackend](crate::KvBackend).
+    pub kv: bool,
+    /// Send imports of unknown modules to the host, because it has a
+    /// [ModuleResolver](crate::ModuleResolver).
+    pub resolve_modules: bool,
+    /// URL prefixes that scripts can import modules from
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub url_imports: Vec<String>,
+    /// How long the worker uses a module fetched for a URL import before fetching it again
+    pub url_import_ttl_ms: u64,
+    /// Compress frames with payloads larger than this many bytes. The host sends compressed
+    /// frames over the same threshold.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compress_frames_over: Option<usize>,
+    /// The directory to pass large payloads through, instead of sending them over the socket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared_memory_dir: Option<PathBuf>,
+    /// Pass payloads larger than this many bytes through the shared memory directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared_memory_over: Option<usize>,
+}
+
+/// Data associated with the AdvanceTime message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdvanceTimeData {
+    /// How far to move the clock, in milliseconds
+    pub ms: u64,
+}
+
+/// Data associated with the Complete message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompleteData {
+    /// The code up to the cursor
+    pub line: String,
+}
+
+/// Completions for the end of a line of code, from [Repl::complete](crate::Repl::complete)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Completions {
+    /// The partial name at the end of the line, which each candidate starts with
+    pub prefix: String,
+    /// The names that can complete the prefix, in sorted order
+    pub candidates: Vec<String>,
+}
+
+/// Data associated with the Introspect message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntrospectData {
+    /// A path of property names, such as `user.profile`, for the value whose properties to list,
+    /// or empty for the globals
+    pub expr: String,
+    /// Only list the names that start with this
+    pub prefix: String,
+}
+
+/// What kind of value a property holds, from [Connection::complete](crate::Connection::complete)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueKind {
+    Undefined,
+    Null,
+    Boolean,
+    Number,
+    BigInt,
+    String,
+    Symbol,
+    /// A function, other than a class
+    Function,
+    Class,
+    Array,
+    /// Any other object
+    Object,
+    /// A property with a getter or setter. The getter isn't run to find out what it returns.
+    Accessor,
+}
+
+/// A completion from [Connection::complete](crate::Connection::complete), with a hint about what
+/// it holds
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct PropertyHint {
+    /// The name of the property or global
+    pub name: String,
+    /// What the property holds
+    pub kind: ValueKind,
+    /// More about the value: the parameters of a function as written in its source, such as
+    /// `(a, b = 1)`, the length of an array, such as `Array(3)`, or the name of an object's
+    /// constructor, such as `Map`. Built-in functions have no parameters to show.
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
+/// Options for [Connection::check_script](crate::Connection::check_script)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CheckScriptOptions {
+    /// The name of the script, as in [RunScriptArgs::name]. Relative imports are resolved
+    /// against it.
+    pub name: Cow<'static, str>,
+
+    /// Check the code as an expression, as in [RunScriptArgs::expr], instead of as a module.
+    pub expr: bool,
+
+    /// Check the code as a function, as in [RunScriptArgs::call], instead of as a module.
+    pub call: bool,
+
+    /// Also look for problems that aren't syntax errors but would fail the run, which are
+    /// returned in [warnings](ScriptCheck::warnings). For now, these are imports that don't match
+    /// any of the [modules](Self::modules), apart from URL imports. An import that a
+    /// [ModuleResolver](crate::ModuleResolver) would provide is still reported.
+    pub lint: bool,
+
+    /// The names of the modules that the code can import, such as those that will be passed in
+    /// [RunScriptArgs::modules] or that the context already has.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub modules: Vec<String>,
+}
+
+/// Data associated with the CheckScript message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckScriptData {
+    pub code: String,
+    #[serde(flatten)]
+    pub options: CheckScriptOptions,
+}
+
+/// The result of [Connection::check_script](crate::Connection::check_script)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct ScriptCheck {
+    /// Errors that keep the code from compiling. V8 stops at the first syntax error, so there is
+    /// at most one of these for now.
+    pub errors: Vec<Diagnostic>,
+    /// Problems found with [lint](CheckScriptOptions::lint)
+    pub warnings: Vec<Diagnostic>,
+}
+
+impl ScriptCheck {
+    /// True if the code compiled
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// A problem found by [Connection::check_script](crate::Connection::check_script)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Diagnostic {
+    /// What is wrong, such as `SyntaxError: Unexpected token ';'`
+    pub message: String,
+    /// The line of the problem, starting at 1, if it is known
+    #[serde(default)]
+    pub line: Option<u32>,
+    /// The column of the problem within its line, starting at 1, if it is known
+    #[serde(default)]
+    pub column: Option<u32>,
+}
+
+/// Data associated with the JobStatus and JobCancel messages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobRequestData {
+    pub job_id: u64,
+    /// The token that the worker gave the job when it started
+    pub token: String,
+    /// Forget the job once it has finished, after returning its status
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub remove: bool,
+}
+
+/// Where a background job from [Connection::spawn_script](crate::Connection::spawn_script) is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+    /// The job was stopped by [JobHandle::cancel](crate::JobHandle::cancel)
+    Cancelled,
+}
+
+/// The worker's answer to a run that started a background job
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StartedJob {
+    pub job_id: u64,
+    pub pid: u32,
+    pub token: String,
+}
+
+/// The status of a background job, from [JobHandle::status](crate::JobHandle::status)
 #[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStatus {
+    pub job_id: u64,
+    /// The process ID of the worker running the job
+    pub pid: u32,
+    pub state: JobState,
+    /// The response of a job that succeeded
+    #[serde(default)]
+    pub response: Option<RunResponseData>,
+    /// The error of a job that failed or was cancelled
+    #[serde(default)]
+    pub error: Option<ErrorResponseData>,
+    /// The job's console messages so far. The worker keeps the first 1000 of them.
+    #[serde(default)]
+    pub logs: Vec<LogResponseData>,
+    /// The number of console messages dropped after the first 1000
+    #[serde(default)]
+    pub dropped_logs: u64,
+}
+
+/// The result of [Connection::advance_time](crate::Connection::advance_time)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdvanceTimeResult {
+    /// The context's clock after advancing, in milliseconds since the Unix epoch
+    pub now: f64,
+    /// How many timer callbacks ran
+    pub timers_run: u32,
+    /// The timers that are still waiting to run
+    pub pending_timers: u32,
+    /// Console messages logged by the timers that ran
+    #[serde(skip)]
+    pub logs: Vec<LogResponseData>,
+}
+
+/// The worker's response to a handshake
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandshakeResponseData {
+    /// The worker's protocol version
+    pub version: u32,
+    /// False if the versions don't match, in which case the worker closes the connection.
+    pub accepted: bool,
+    /// The process ID of the worker
+    pub pid: u32,
+}
+
+/// A message from the worker that was over the size limit, and so was dropped. See
+/// [Error::MessageTooLarge](crate::Error::MessageTooLarge).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageTooLargeData {
+    /// The size of the message, in bytes. A compressed message is only decompressed until it
+    /// passes the limit, so for one of those this is the size when decompression stopped.
+    pub length: u64,
+    /// The limit that it exceeded
+    pub limit: u64,
+}
+
+/// A frame from the worker that couldn't be read. See [Error::ProtocolCorruption](crate::Error::ProtocolCorruption).
+#[derive(Debug, Clone)]
+pub struct ProtocolCorruptionData {
+    /// The request that the frame belonged to, if its header could be trusted
+    pub request_id: Option<u32>,
+    /// What was wrong with the frame
+    pub reason: String,
+    /// The number of bytes thrown away to get to the start of the next frame
+    pub skipped_bytes: u64,
+}
+
+/// A frame from the worker whose message ID wasn't the next one for its request, which means that
+/// frames were lost or repeated on the way. See
+/// [Error::ProtocolSequence](crate::Error::ProtocolSequence).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolSequenceData {
+    /// The request that the frame belonged to
+    pub request_id: u32,
+    /// The message ID that the next frame for the request should have had
+    pub expected: u32,
+    /// The message ID that the frame had
+    pub received: u32,
+}
+
+/// The response to a ping
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PongData {
+    /// The process ID of the worker that handles the connection.
+    #[serde(default)]
+    pub pid: Option<u32>,
+}
+
+/// Memory usage that a worker reports periodically when
+/// [memory_report_interval](crate::JsSidecarBuilder::memory_report_interval) is set. All sizes
+/// are in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryUsageData {
+    /// The process ID of the worker
+    pub pid: u32,
+    /// The size of the JavaScript heap in use
+    pub heap_used: u64,
+    /// The total size allocated for the JavaScript heap
+    pub heap_total: u64,
+    /// The resident set size of the worker process
+    pub rss: u64,
+    /// Memory used by C++ objects bound to JavaScript objects, such as Buffers
+    pub external: u64,
+}
+
+/// How busy a worker is, reported periodically when
+/// [load_report_interval](crate::JsSidecarBuilder::load_report_interval) or
+/// [prefer_idle_workers](crate::JsSidecarBuilder::prefer_idle_workers) is set.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkerLoadData {
+    /// The process ID of the worker
+    pub pid: u32,
+    /// Runs that have started and not yet finished, on all of the worker's connections
+    pub active_runs: u32,
+    /// Open connections to the worker, from this sidecar and any others
+    pub connections: u32,
+    /// The average delay of the worker's event loop since its last report, in milliseconds.
+    /// This grows when scripts run synchronously for a long time.
+    pub event_loop_delay_ms: f64,
+}
+
+/// How an [AsyncErrorData] escaped from a context's code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AsyncErrorSource {
+    /// A promise was rejected without a handler.
+    UnhandledRejection,
+    /// A callback, such as a timer's, threw an error.
+    UncaughtException,
+}
+
+/// An error from a context's code that wasn't part of a run's result, such as one thrown by a timer
+/// callback after the run finished, or a promise rejection that nothing handled. These used to take
+/// down the worker, and are now sent on the connection with the request ID of the run that is
+/// using the context, or that last used it.
+///
+/// An async error that arrives during a run is included in the run's other messages by
+/// [run_script_and_wait](crate::Connection::run_script_and_wait). One that arrives after the run
+/// finished is returned from [receive_message](crate::Connection::receive_message), or, if it
+/// arrives while waiting for a later run, from
+/// [take_stale_messages](crate::Connection::take_stale_messages).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AsyncErrorData {
+    pub source: AsyncErrorSource,
+    pub error: ErrorResponseData,
+}
+
+/// Sent by a worker when a run with [RunScriptArgs::debug] set is waiting for a debugger.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DebuggerWaitingData {
+    /// The process ID of the worker
+    pub pid: u32,
+    /// The WebSocket URL of the worker's inspector
+    pub url: String,
+}
+
+/// A CPU profile of a run with [RunScriptArgs::profile] set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuProfileData {
+    /// The ID of the request that was profiled
+    pub request_id: u32,
+    /// The profile in `.cpuprofile` JSON format. Save it to a file with that extension to open it
+    /// in Chrome DevTools or VS Code.
+    pub profile: String,
+}
+
+/// The severity of a console message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    /// `console.trace`
+    Trace,
+    /// `console.debug`
+    Debug,
+    /// `console.log` and `console.info`
+    Info,
+    /// `console.warn`
+    Warn,
+    /// `console.error`, and `stderr.write`
+    Error,
+}
+
+impl LogLevel {
+    /// Returns true for the levels that Node.js writes to stderr rather than stdout: [Warn](Self::Warn)
+    /// and [Error](Self::Error).
+    pub fn is_stderr(self) -> bool {
+        self >= LogLevel::Warn
+    }
+}
+
+impl From<LogLevel> for tracing::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Trace => tracing::Level::TRACE,
+            LogLevel::Debug => tracing::Level::DEBUG,
+            LogLevel::Info => tracing::Level::INFO,
+            LogLevel::Warn => tracing::Level::WARN,
+            LogLevel::Error => tracing::Level::ERROR,
+        }
+    }
+}
+
+/// A console message logged by a script
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct LogResponseData {
-    pub level: String,
+    /// The console function that logged the message
+    pub level: LogLevel,
+    /// The arguments passed to the console function
     pub message: serde_json::Value,
+    /// Milliseconds since the Unix epoch when the message was logged
+    pub timestamp: u64,
+    /// The request that was running when the message was logged
+    pub request_id: u32,
+    /// The logger name, from a `[namespace]` prefix on the message or a console created with
+    /// the `logger(namespace)` global.
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// How many identical consecutive messages this represents. This is only ever more than 1
+    /// when using [ChannelOverflow::CoalesceLogs](crate::ChannelOverflow::CoalesceLogs).
+    #[serde(default = "default_log_count")]
+    pub count: u32,
+}
+
+fn default_log_count() -> u32 {
+    1
+}
+
+impl LogResponseData {
+    /// The time at which the message was logged.
+    pub fn time(&self) -> std::time::SystemTime {
+        std::time::UNIX_EPOCH + std::time::Duration::from_millis(self.timestamp)
+    }
 }
@@ -0,0 +1,67 @@
+use std::{borrow::Cow, collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{CodeModule, Error, FunctionDef};
+
+/// A list of contexts to set up before the sidecar starts receiving traffic.
+/// See [JsSidecar::prewarm](crate::JsSidecar::prewarm).
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PrewarmManifest {
+    /// The contexts to create
+    pub contexts: Vec<PrewarmContext>,
+}
+
+/// The code and globals for a context that will be used by later runs.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PrewarmContext {
+    /// The name of the context, which runs pass as
+    /// [prewarmed_context](crate::RunScriptArgs::prewarmed_context) to use it. This is also used
+    /// in the [PrewarmReport].
+    pub name: String,
+    /// Global variables to set in the context.
+    #[serde(default)]
+    pub globals: HashMap<Cow<'static, str>, serde_json::Value>,
+    /// Functions to compile and place in the global scope
+    #[serde(default)]
+    pub functions: Vec<FunctionDef>,
+    /// ES Modules to compile
+    #[serde(default)]
+    pub modules: Vec<CodeModule>,
+}
+
+/// The results of [JsSidecar::prewarm](crate::JsSidecar::prewarm).
+#[derive(Debug, Default)]
+pub struct PrewarmReport {
+    /// The readiness of each context in the manifest, in the same order.
+    pub contexts: Vec<ContextReadiness>,
+}
+
+impl PrewarmReport {
+    /// Returns true if every context was built on every worker.
+    pub fn all_ready(&self) -> bool {
+        self.contexts.iter().all(|c| c.is_ready())
+    }
+}
+
+/// How successful prewarming was for a single context.
+#[derive(Debug)]
+pub struct ContextReadiness {
+    /// The name of the context
+    pub name: String,
+    /// How many workers built the context successfully.
+    pub ready_workers: usize,
+    /// How many workers prewarming was attempted on.
+    pub total_workers: usize,
+    /// Errors from the workers that failed to build the context.
+    pub errors: Vec<Error>,
+    /// How long it took to build the context on all the workers.
+    pub elapsed: Duration,
+}
+
+impl ContextReadiness {
+    /// Returns true if the context was built on every worker.
+    pub fn is_ready(&self) -> bool {
+        self.ready_workers == self.total_workers
+    }
+}
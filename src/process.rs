@@ -0,0 +1,189 @@
+use std::{
+    ffi::CString,
+    io,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use tokio::process::Command;
+
+use crate::{hardening::Sandbox, Error};
+
+/// Scheduling settings for the Node.js process, applied between forking it and running Node.js so
+/// that the workers it starts inherit them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProcessSettings {
+    /// The CPUs that the process may run on
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// The niceness of the process, from -20 to 19
+    pub nice: Option<i32>,
+    /// The directory of an existing cgroup to move the process into
+    pub cgroup: Option<PathBuf>,
+    /// File descriptors that the process keeps open, under the same numbers, for Node.js to use
+    pub inherited_fds: Vec<RawFd>,
+}
+
+impl ProcessSettings {
+    /// Set up `command` to apply the settings to the process that it starts, and then to enter
+    /// `sandbox` if there is one.
+    pub fn apply(&self, command: &mut Command, sandbox: Option<Sandbox>) -> Result<(), Error> {
+        if self.cpu_affinity.is_none()
+            && self.nice.is_none()
+            && self.cgroup.is_none()
+            && self.inherited_fds.is_empty()
+            && sandbox.is_none()
+        {
+            return Ok(());
+        }
+
+        // Everything is prepared before forking, since only async-signal-safe calls can be made
+        // in the child.
+        let cpu_set = self.cpu_affinity.as_deref().map(cpu_set).transpose()?;
+        let nice = self.nice;
+        let cgroup_procs = self
+            .cgroup
+            .as_ref()
+            .map(|dir| CString::new(dir.join("cgroup.procs").as_os_str().as_bytes()))
+            .transpose()
+            .map_err(|e| Error::StartWorker(io::Error::other(e)))?;
+        let inherited_fds = self.inherited_fds.clone();
+
+        // SAFETY: the closure only makes system calls, and doesn't allocate or take locks.
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(cgroup_procs) = &cgroup_procs {
+                    join_cgroup(cgroup_procs)?;
+                }
+                if let Some(nice) = nice {
+                    set_nice(nice)?;
+                }
+                if let Some(cpu_set) = &cpu_set {
+                    set_affinity(cpu_set)?;
+                }
+                for fd in &inherited_fds {
+                    keep_open(*fd)?;
+                }
+                if let Some(sandbox) = &sandbox {
+                    sandbox.enter()?;
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+type CpuSet = nix::sched::CpuSet;
+#[cfg(not(target_os = "linux"))]
+type CpuSet = ();
+
+#[cfg(target_os = "linux")]
+fn cpu_set(cpus: &[usize]) -> Result<CpuSet, Error> {
+    let mut set = CpuSet::new();
+    for &cpu in cpus {
+        set.set(cpu).map_err(|_| {
+            Error::StartWorker(io::Error::other(format!("CPU {cpu} is out of range")))
+        })?;
+    }
+    Ok(set)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_set(_cpus: &[usize]) -> Result<CpuSet, Error> {
+    Err(Error::StartWorker(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "CPU affinity is only supported on Linux",
+    )))
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(cpu_set: &CpuSet) -> io::Result<()> {
+    nix::sched::sched_setaffinity(nix::unistd::Pid::from_raw(0), cpu_set).map_err(io::Error::from)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_cpu_set: &CpuSet) -> io::Result<()> {
+    Ok(())
+}
+
+fn set_nice(nice: i32) -> io::Result<()> {
+    // SAFETY: setpriority only reads its arguments.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Create a Unix socket bound to `path` without listening on it, to hand to Node.js. Connecting
+/// to the socket fails until Node.js starts listening.
+pub(crate) fn bind_unix_socket(path: &Path) -> io::Result<OwnedFd> {
+    let bytes = path.as_os_str().as_bytes();
+    // SAFETY: sockaddr_un is plain data, which is valid when zeroed.
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    if bytes.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Socket path {} is too long", path.display()),
+        ));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dest, byte) in addr.sun_path.iter_mut().zip(bytes) {
+        *dest = *byte as libc::c_char;
+    }
+
+    // SAFETY: socket only reads its arguments.
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the kernel just opened the file descriptor.
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    // SAFETY: fcntl only changes the descriptor's flags, and the address outlives the bind.
+    unsafe {
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::bind(
+            fd,
+            (&addr as *const libc::sockaddr_un).cast(),
+            std::mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+        ) == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(socket)
+}
+
+/// Keep a file descriptor open when the current process runs another program.
+fn keep_open(fd: RawFd) -> io::Result<()> {
+    // SAFETY: fcntl only changes the descriptor's flags.
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Move the current process into the cgroup whose `cgroup.procs` file is at `path`. Writing 0
+/// moves the process that writes it.
+fn join_cgroup(path: &CString) -> io::Result<()> {
+    // SAFETY: the path is a valid C string, and the buffer outlives the write.
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+        let result = if written == 1 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        };
+        libc::close(fd);
+        result
+    }
+}
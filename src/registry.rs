@@ -0,0 +1,197 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{corpus::hex_string, Error, RunScriptArgs};
+
+/// A script in a [ScriptRegistry].
+#[derive(Debug, Clone)]
+pub struct RegisteredScript {
+    /// The name that the script was registered under
+    pub name: String,
+    /// The version that the script was registered under
+    pub version: u32,
+    /// The script's code, which runs as an ES module. The script can report its result as its
+    /// default export.
+    pub code: Arc<str>,
+    /// The hex-encoded SHA-256 hash of the code
+    pub hash: String,
+}
+
+impl RegisteredScript {
+    /// The arguments to run the script. Add globals and any other options before running it.
+    pub fn run_args(&self) -> RunScriptArgs {
+        RunScriptArgs {
+            name: Cow::Owned(format!("{}@{}", self.name, self.version)),
+            code: Cow::Owned(self.code.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+/// A catalog of named, versioned scripts, from [JsSidecar::registry](crate::JsSidecar::registry).
+///
+/// Each connection sends the code of a registered script to its worker the first time it runs the
+/// script, and after that only sends a reference to it, so runs of large scripts don't pay to send
+/// the same code again. A version can't be changed once it is registered; register a new version
+/// instead.
+#[derive(Debug, Default)]
+pub struct ScriptRegistry {
+    scripts: RwLock<BTreeMap<(String, u32), RegisteredScript>>,
+}
+
+impl ScriptRegistry {
+    /// Register `code` as version `version` of the script `name`. Registering the same code again
+    /// does nothing, but registering different code under a version that is already registered
+    /// returns [Error::ScriptVersionConflict].
+    pub fn register(
+        &self,
+        name: impl Into<String>,
+        version: u32,
+        code: impl Into<String>,
+    ) -> Result<(), Error> {
+        let name = name.into();
+        let code = code.into();
+        let hash = hex_string(&Sha256::digest(code.as_bytes()));
+
+        let mut scripts = self.scripts.write().unwrap();
+        match scripts.get(&(name.clone(), version)) {
+            Some(existing) if existing.hash == hash => Ok(()),
+            Some(_) => Err(Error::ScriptVersionConflict { name, version }),
+            None => {
+                let script = RegisteredScript {
+                    name: name.clone(),
+                    version,
+                    code: code.into(),
+                    hash,
+                };
+                scripts.insert((name, version), script);
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove a version of a script, returning it if it was registered. Connections that have
+    /// already sent it to their workers keep it until they close.
+    pub fn unregister(&self, name: &str, version: u32) -> Option<RegisteredScript> {
+        self.scripts
+            .write()
+            .unwrap()
+            .remove(&(name.to_string(), version))
+    }
+
+    /// A version of a script.
+    pub fn get(&self, name: &str, version: u32) -> Option<RegisteredScript> {
+        self.scripts
+            .read()
+            .unwrap()
+            .get(&(name.to_string(), version))
+            .cloned()
+    }
+
+    /// The highest registered version of a script.
+    pub fn latest(&self, name: &str) -> Option<RegisteredScript> {
+        self.scripts
+            .read()
+            .unwrap()
+            .range((name.to_string(), 0)..=(name.to_string(), u32::MAX))
+            .next_back()
+            .map(|(_, script)| script.clone())
+    }
+
+    /// Every registered script, ordered by name and then version.
+    pub fn list(&self) -> Vec<RegisteredScript> {
+        self.scripts.read().unwrap().values().cloned().collect()
+    }
+
+    /// The arguments to run a version of a script, with the given globals.
+    pub fn run_args(
+        &self,
+        name: &str,
+        version: u32,
+        globals: HashMap<Cow<'static, str>, serde_json::Value>,
+    ) -> Result<RunScriptArgs, Error> {
+        let script = self
+            .get(name, version)
+            .ok_or_else(|| Error::ScriptNotRegistered {
+                name: name.to_string(),
+                version,
+            })?;
+        Ok(RunScriptArgs {
+            globals,
+            ..script.run_args()
+        })
+    }
+
+    /// The registered script with the given code, if any. This runs before every run, so it
+    /// compares the code directly instead of hashing it, and only for scripts of the same length.
+    pub(crate) fn find(&self, code: &str) -> Option<RegisteredScript> {
+        self.scripts
+            .read()
+            .unwrap()
+            .values()
+            .find(|script| script.code.len() == code.len() && *script.code == *code)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        let registry = ScriptRegistry::default();
+        registry
+            .register("transform", 1, "export default 1")
+            .unwrap();
+        registry
+            .register("transform", 3, "export default 3")
+            .unwrap();
+        registry.register("other", 7, "export default 7").unwrap();
+
+        // Registering the same code again is fine, but changing a version isn't.
+        registry
+            .register("transform", 1, "export default 1")
+            .unwrap();
+        assert!(matches!(
+            registry.register("transform", 1, "export default 2"),
+            Err(Error::ScriptVersionConflict { version: 1, .. })
+        ));
+
+        assert_eq!(registry.latest("transform").unwrap().version, 3);
+        assert_eq!(
+            &*registry.get("transform", 1).unwrap().code,
+            "export default 1"
+        );
+        assert!(registry.get("transform", 2).is_none());
+        assert_eq!(
+            registry
+                .list()
+                .iter()
+                .map(|s| (s.name.as_str(), s.version))
+                .collect::<Vec<_>>(),
+            [("other", 7), ("transform", 1), ("transform", 3)]
+        );
+
+        let args = registry
+            .run_args("transform", 3, [("input".into(), 5.into())].into())
+            .unwrap();
+        assert_eq!(args.name, "transform@3");
+        assert_eq!(args.globals["input"], 5);
+        assert!(matches!(
+            registry.run_args("transform", 2, HashMap::new()),
+            Err(Error::ScriptNotRegistered { version: 2, .. })
+        ));
+
+        assert_eq!(registry.find("export default 3").unwrap().version, 3);
+        assert!(registry.find("export default 4").is_none());
+
+        registry.unregister("transform", 3).unwrap();
+        assert_eq!(registry.latest("transform").unwrap().version, 1);
+    }
+}
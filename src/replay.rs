@@ -0,0 +1,189 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::wire::{WorkerToHostMessage, WorkerToHostMessageData};
+
+/// What a recording has in place of the value of each of a run's
+/// [secrets](crate::RunScriptArgs::secrets), the same text that the worker puts in place of them
+/// in the run's results.
+pub(crate) const REDACTED_SECRET: &str = "[REDACTED]";
+
+/// The messages exchanged with the worker while a [Connection](crate::Connection) was recording,
+/// from [Connection::start_recording](crate::Connection::start_recording). A recording can be
+/// saved as JSON and replayed later with [Connection::replay](crate::Connection::replay).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Recording {
+    /// The [PROTOCOL_VERSION](crate::versions::PROTOCOL_VERSION) of the host that made the
+    /// recording
+    pub protocol_version: u32,
+    /// The SHA-256 hash of the worker code bundled with the host that made the recording, as hex
+    pub worker_hash: String,
+    /// The messages, in the order that they were sent or received
+    pub messages: Vec<RecordedMessage>,
+}
+
+/// A message in a [Recording].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(
+    tag = "direction",
+    rename_all = "lowercase",
+    rename_all_fields = "camelCase"
+)]
+pub enum RecordedMessage {
+    /// A message sent to the worker. This includes the host's answers to the worker's `kv` and
+    /// module resolution requests, so a replay gets the same answers without calling the
+    /// backends again. The values of a run's [secrets](crate::RunScriptArgs::secrets) are
+    /// replaced with `[REDACTED]`, so they don't end up wherever the recording is saved.
+    Sent {
+        request_id: u32,
+        message_type: u32,
+        /// The message's payload, before it was compressed or moved to shared memory
+        #[serde(
+            serialize_with = "serialize_base64",
+            deserialize_with = "deserialize_base64"
+        )]
+        payload: Vec<u8>,
+    },
+    /// A message received from the worker. Only its type is kept, which is enough to find where a
+    /// replay goes differently.
+    Received {
+        request_id: u32,
+        message_type: u32,
+        /// The ID of a `kv` or module resolution request, which the host's answer refers to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        call_id: Option<u32>,
+    },
+}
+
+fn serialize_base64<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&BASE64.encode(data))
+}
+
+fn deserialize_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    BASE64.decode(encoded).map_err(serde::de::Error::custom)
+}
+
+/// The result of [Connection::replay](crate::Connection::replay).
+#[derive(Debug)]
+pub struct ReplayResult {
+    /// The messages that the worker sent during the replay, with the request IDs from the
+    /// recording so that they can be compared to the original run.
+    pub messages: Vec<WorkerToHostMessage>,
+    /// Where the replay first went differently from the recording, if it did. The replay stops
+    /// there.
+    pub divergence: Option<ReplayDivergence>,
+}
+
+/// Where a replay went differently from its [Recording].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayDivergence {
+    /// The index in [Recording::messages] of the message that the worker was expected to send
+    pub index: usize,
+    /// The type of the message that the worker was expected to send
+    pub expected: u32,
+    /// The type of the message that the worker sent instead, or `None` if the connection closed
+    pub received: Option<u32>,
+}
+
+/// The ID that a `kv` or module resolution request from the worker gives to its call, which the
+/// host's answer has to carry.
+pub(crate) fn call_id(data: &WorkerToHostMessageData) -> Option<u32> {
+    match data {
+        WorkerToHostMessageData::KvRequest(request) => Some(request.id),
+        WorkerToHostMessageData::ResolveModule(request) => Some(request.id),
+        _ => None,
+    }
+}
+
+/// Whether a message sent to the worker is the answer to a `kv` or module resolution request:
+/// a KvResponse or ResolveModuleResponse.
+pub(crate) fn answers_call(message_type: u32) -> bool {
+    matches!(message_type, 4 | 7)
+}
+
+/// Records the messages of a connection while recording is on. The recorder is shared by the
+/// connection, the tasks that answer the worker's requests, and the read task.
+#[derive(Debug, Default)]
+pub(crate) struct Recorder {
+    /// Set while a recording is being replayed, so that the read task passes the worker's `kv`
+    /// and module resolution requests on instead of answering them.
+    replaying: AtomicBool,
+    recording: Mutex<Option<RecorderState>>,
+}
+
+#[derive(Debug, Default)]
+struct RecorderState {
+    messages: Vec<RecordedMessage>,
+    /// The requests sent while recording. Messages received for other requests aren't recorded.
+    requests: HashSet<u32>,
+}
+
+impl Recorder {
+    /// Start a new recording, dropping any that was in progress.
+    pub fn start(&self) {
+        *self.recording.lock().unwrap() = Some(RecorderState::default());
+    }
+
+    /// Stop recording and return the messages that were recorded.
+    pub fn stop(&self) -> Option<Vec<RecordedMessage>> {
+        self.recording
+            .lock()
+            .unwrap()
+            .take()
+            .map(|state| state.messages)
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.replaying.load(Ordering::Relaxed)
+    }
+
+    pub fn set_replaying(&self, replaying: bool) {
+        self.replaying.store(replaying, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, request_id: u32, message_type: u32, payload: &[u8]) {
+        let mut recording = self.recording.lock().unwrap();
+        let Some(state) = recording.as_mut() else {
+            return;
+        };
+        state.requests.insert(request_id);
+        state.messages.push(RecordedMessage::Sent {
+            request_id,
+            message_type,
+            payload: payload.to_vec(),
+        });
+    }
+
+    pub fn received(&self, message: &WorkerToHostMessage) {
+        // Memory and load reports are handled by the read task, and never reach the receiver
+        // that a replay waits on.
+        if matches!(
+            message.data,
+            WorkerToHostMessageData::MemoryUsage(_) | WorkerToHostMessageData::WorkerLoad(_)
+        ) {
+            return;
+        }
+
+        let mut recording = self.recording.lock().unwrap();
+        let Some(state) = recording.as_mut() else {
+            return;
+        };
+        if state.requests.contains(&message.request_id) {
+            state.messages.push(RecordedMessage::Received {
+                request_id: message.request_id,
+                message_type: message.data.message_type(),
+                call_id: call_id(&message.data),
+            });
+        }
+    }
+}
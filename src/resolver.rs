@@ -0,0 +1,90 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::{
+    transport::WriteHalf,
+    wire::{FrameOptions, HostToWorkerMessage, HostToWorkerMessageData},
+    CodeModule,
+};
+
+/// The result of a [ModuleResolver] lookup. `Ok(None)` means that the module doesn't exist, and
+/// an error fails the import with the error's message.
+pub type ResolveResult = Result<Option<CodeModule>, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Supplies the code for imports that don't match any of the run's
+/// [modules](crate::RunScriptArgs::modules), when set with
+/// [JsSidecarBuilder::module_resolver](crate::JsSidecarBuilder::module_resolver).
+///
+/// The module that the resolver returns is added to the context under its
+/// [name](CodeModule::name), so later imports of the same specifier, or of that name, don't ask
+/// the resolver again until the context is recreated. Relative imports inside the returned
+/// module are resolved against its name, and can themselves be sent to the resolver.
+pub trait ModuleResolver: Send + Sync + 'static {
+    /// Find the module for `request.specifier`.
+    fn resolve(&self, request: ResolveModuleRequest) -> BoxFuture<'_, ResolveResult>;
+}
+
+impl std::fmt::Debug for dyn ModuleResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ModuleResolver")
+    }
+}
+
+/// An import that the worker couldn't find among the modules that it has.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResolveModuleRequest {
+    /// Matches the request to its response. This is unique per connection.
+    pub(crate) id: u32,
+    /// The specifier from the `import` statement. Relative specifiers are joined to the
+    /// referrer's name first, so `./util.js` imported from `plugins/a/index.js` arrives as
+    /// `plugins/a/util.js`.
+    pub specifier: String,
+    /// The name of the module that contains the import
+    pub referrer: String,
+}
+
+/// The answer to a [ResolveModuleRequest].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResolveModuleResponseData {
+    id: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    module: Option<CodeModule>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Look up a module for the worker and send the result back.
+pub(crate) async fn serve(
+    resolver: Arc<dyn ModuleResolver>,
+    request_id: u32,
+    request: ResolveModuleRequest,
+    stream: Arc<AsyncMutex<WriteHalf>>,
+    frame: FrameOptions,
+) {
+    let id = request.id;
+    let response = match resolver.resolve(request).await {
+        Ok(module) => ResolveModuleResponseData {
+            id,
+            module,
+            error: None,
+        },
+        Err(e) => ResolveModuleResponseData {
+            id,
+            module: None,
+            error: Some(e.to_string()),
+        },
+    };
+
+    let message = HostToWorkerMessage::new(
+        request_id,
+        0,
+        HostToWorkerMessageData::ResolveModuleResponse(response),
+    );
+    let mut stream = stream.lock().await;
+    if let Err(e) = message.write_to(frame, &mut *stream).await {
+        tracing::warn!(error = ?e, "Failed to send module resolution to worker");
+    }
+}
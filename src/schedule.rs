@@ -0,0 +1,583 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use deadpool::managed::Pool;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::{sync::broadcast, task::JoinHandle};
+
+use crate::{
+    connection::ConnectionManager,
+    messages::{deserialize_base64, serialize_base64},
+    Error, RunScriptArgs, SidecarEvent, WasmModule,
+};
+
+/// How many days ahead to look for the next match of a schedule. A leap day can be eight years
+/// away, so this covers every schedule that can ever match.
+const MAX_SEARCH_DAYS: u64 = 366 * 8;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Cron expressions that can be used in place of the fields.
+const MACROS: &[(&str, &str)] = &[
+    ("@yearly", "0 0 1 1 *"),
+    ("@annually", "0 0 1 1 *"),
+    ("@monthly", "0 0 1 * *"),
+    ("@weekly", "0 0 * * 0"),
+    ("@daily", "0 0 * * *"),
+    ("@midnight", "0 0 * * *"),
+    ("@hourly", "0 * * * *"),
+];
+
+/// The result of a [ScheduleStore] operation.
+pub type ScheduleStoreResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// A parsed cron expression, which tells a [scheduled script](crate::JsSidecar::schedule) when to
+/// run. Times are in UTC.
+///
+/// The expression has five fields, for the minute, hour, day of the month, month, and day of the
+/// week, with Sunday as 0 or 7, or six fields with the second first. Each field is `*`, a number,
+/// a range such as `1-5`, or a comma-separated list of these, and `*` and ranges can take a step,
+/// so `*/15` in the minute field runs every 15 minutes. Like cron, when both the day of the month
+/// and the day of the week are set, a day that matches either one runs. `@yearly`, `@monthly`,
+/// `@weekly`, `@daily`, and `@hourly` are also accepted.
+///
+/// ```
+/// # use js_sidecar::CronSchedule;
+/// let weekdays_at_nine: CronSchedule = "0 9 * * 1-5".parse().unwrap();
+/// let every_ten_seconds: CronSchedule = "*/10 * * * * *".parse().unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    seconds: Field,
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+/// The values that match one field of a cron expression, as a bitmask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Field {
+    mask: u64,
+    /// False if the field was `*`, so that it doesn't restrict the day.
+    restricted: bool,
+}
+
+impl Field {
+    fn parse(text: &str, name: &str, min: u32, max: u32) -> Result<Self, Error> {
+        let invalid =
+            |reason: &str| Error::InvalidSchedule(format!("{name} field {text:?} {reason}"));
+        let mut mask = 0u64;
+        for part in text.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => {
+                    let step = step
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|&step| step > 0)
+                        .ok_or_else(|| invalid("has an invalid step"))?;
+                    (range, step)
+                }
+                None => (part, 1),
+            };
+
+            let number = |n: &str| {
+                n.parse::<u32>()
+                    .ok()
+                    .filter(|n| (min..=max).contains(n))
+                    .ok_or_else(|| invalid(&format!("is not a number from {min} to {max}")))
+            };
+            let (start, end) = match range.split_once('-') {
+                _ if range == "*" => (min, max),
+                Some((start, end)) => (number(start)?, number(end)?),
+                // A single number with a step runs from the number to the end of the range.
+                None if step > 1 => (number(range)?, max),
+                None => {
+                    let n = number(range)?;
+                    (n, n)
+                }
+            };
+            if start > end {
+                return Err(invalid("has a range that ends before it starts"));
+            }
+
+            for value in (start..=end).step_by(step as usize) {
+                mask |= 1 << value;
+            }
+        }
+
+        Ok(Field {
+            mask,
+            restricted: !text.starts_with('*'),
+        })
+    }
+
+    fn contains(&self, value: u32) -> bool {
+        self.mask >> value & 1 == 1
+    }
+}
+
+impl CronSchedule {
+    /// Parse a cron expression, returning [Error::InvalidSchedule] if it isn't valid.
+    pub fn parse(expr: &str) -> Result<Self, Error> {
+        let expr = expr.trim();
+        let expanded = MACROS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(expr))
+            .map_or(expr, |(_, fields)| fields);
+        let fields = expanded.split_whitespace().collect::<Vec<_>>();
+        let (seconds, rest) = match fields.len() {
+            5 => ("0", &fields[..]),
+            6 => (fields[0], &fields[1..]),
+            _ => {
+                return Err(Error::InvalidSchedule(format!(
+                    "{expr:?} should have 5 or 6 fields"
+                )))
+            }
+        };
+
+        let mut weekdays = Field::parse(rest[4], "day of week", 0, 7)?;
+        // Sunday can be 0 or 7.
+        if weekdays.contains(7) {
+            weekdays.mask = (weekdays.mask | 1) & !(1 << 7);
+        }
+
+        Ok(CronSchedule {
+            seconds: Field::parse(seconds, "second", 0, 59)?,
+            minutes: Field::parse(rest[0], "minute", 0, 59)?,
+            hours: Field::parse(rest[1], "hour", 0, 23)?,
+            days: Field::parse(rest[2], "day of month", 1, 31)?,
+            months: Field::parse(rest[3], "month", 1, 12)?,
+            weekdays,
+        })
+    }
+
+    /// The first time after `time` that matches the schedule, or `None` if it never does, such as
+    /// for the 30th of February.
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        // Times before 1970 start the search at 1970.
+        let after = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() + 1);
+        let first_day = after / SECONDS_PER_DAY;
+
+        (first_day..first_day + MAX_SEARCH_DAYS)
+            .filter(|&day| self.matches_day(day))
+            .find_map(|day| {
+                let from = if day == first_day {
+                    (after % SECONDS_PER_DAY) as u32
+                } else {
+                    0
+                };
+                self.time_of_day(from)
+                    .map(|seconds| day * SECONDS_PER_DAY + u64::from(seconds))
+            })
+            .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+
+    /// Whether the schedule runs on the day that is `day` days after 1970-01-01.
+    fn matches_day(&self, day: u64) -> bool {
+        let (month, day_of_month) = month_and_day(day);
+        // 1970-01-01 was a Thursday.
+        let weekday = ((day + 4) % 7) as u32;
+        if !self.months.contains(month) {
+            return false;
+        }
+
+        let day_matches = self.days.contains(day_of_month);
+        let weekday_matches = self.weekdays.contains(weekday);
+        if self.days.restricted && self.weekdays.restricted {
+            day_matches || weekday_matches
+        } else {
+            day_matches && weekday_matches
+        }
+    }
+
+    /// The first second of the day, at or after `from`, that matches the schedule.
+    fn time_of_day(&self, from: u32) -> Option<u32> {
+        let (from_hour, from_minute, from_second) = (from / 3600, from / 60 % 60, from % 60);
+        for hour in (from_hour..24).filter(|&h| self.hours.contains(h)) {
+            let first_minute = if hour == from_hour { from_minute } else { 0 };
+            for minute in (first_minute..60).filter(|&m| self.minutes.contains(m)) {
+                let first_second = if hour == from_hour && minute == from_minute {
+                    from_second
+                } else {
+                    0
+                };
+                if let Some(second) = (first_second..60).find(|&s| self.seconds.contains(s)) {
+                    return Some(hour * 3600 + minute * 60 + second);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+/// The month and day of the month of the day that is `days` days after 1970-01-01, from Howard
+/// Hinnant's `civil_from_days` algorithm.
+fn month_and_day(days: u64) -> (u32, u32) {
+    // Shift the epoch to 0000-03-01, so that the leap day comes at the end of each year.
+    let days = days + 719_468;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    (month as u32, day_of_month as u32)
+}
+
+/// A script scheduled with [JsSidecar::schedule](crate::JsSidecar::schedule).
+///
+/// This serializes all of the run's arguments, including the ones that never go to the worker,
+/// such as the [tenant](RunScriptArgs::tenant) and [signature](RunScriptArgs::signature), and the
+/// bytes of the [WebAssembly modules](RunScriptArgs::wasm_modules), so a [ScheduleStore] can save
+/// it as it is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledScript {
+    /// The schedule's ID
+    pub id: u64,
+    /// The cron expression that the script runs on
+    pub cron: String,
+    /// The arguments for each run
+    #[serde(
+        serialize_with = "serialize_args",
+        deserialize_with = "deserialize_args"
+    )]
+    pub args: RunScriptArgs,
+}
+
+/// [RunScriptArgs] with the fields that its own serialization leaves out.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StoredArgs {
+    #[serde(flatten)]
+    args: RunScriptArgs,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    wasm_modules: Vec<StoredWasmModule>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredWasmModule {
+    name: String,
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64"
+    )]
+    bytes: Vec<u8>,
+}
+
+fn serialize_args<S: Serializer>(args: &RunScriptArgs, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut args = args.clone();
+    StoredArgs {
+        tenant: args.tenant.take(),
+        signature: args.signature.take(),
+        wasm_modules: std::mem::take(&mut args.wasm_modules)
+            .into_iter()
+            .map(|module| StoredWasmModule {
+                name: module.name.into_owned(),
+                bytes: module.bytes.to_vec(),
+            })
+            .collect(),
+        args,
+    }
+    .serialize(serializer)
+}
+
+fn deserialize_args<'de, D: Deserializer<'de>>(deserializer: D) -> Result<RunScriptArgs, D::Error> {
+    let stored = StoredArgs::deserialize(deserializer)?;
+    Ok(RunScriptArgs {
+        tenant: stored.tenant,
+        signature: stored.signature,
+        wasm_modules: stored
+            .wasm_modules
+            .into_iter()
+            .map(|module| WasmModule {
+                name: module.name.into(),
+                bytes: Bytes::from(module.bytes),
+            })
+            .collect(),
+        ..stored.args
+    })
+}
+
+/// Storage for scheduled scripts, set with
+/// [JsSidecarBuilder::schedule_store](crate::JsSidecarBuilder::schedule_store), so that they
+/// survive a restart of the application.
+///
+/// The sidecar loads the schedules when it starts, and then saves each one that is added and
+/// removes each one that is cancelled. A store that writes to disk can serialize each
+/// [ScheduledScript] whole. A saved schedule whose cron expression no longer parses is logged and
+/// skipped when the sidecar starts.
+pub trait ScheduleStore: Send + Sync + 'static {
+    /// The schedules that were saved and not removed.
+    fn load(&self) -> BoxFuture<'_, ScheduleStoreResult<Vec<ScheduledScript>>>;
+    /// Save a new schedule.
+    fn save(&self, schedule: ScheduledScript) -> BoxFuture<'_, ScheduleStoreResult<()>>;
+    /// Remove the schedule with ID `id`.
+    fn remove(&self, id: u64) -> BoxFuture<'_, ScheduleStoreResult<()>>;
+}
+
+impl std::fmt::Debug for dyn ScheduleStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ScheduleStore")
+    }
+}
+
+/// Runs the sidecar's scheduled scripts, each in its own task.
+pub(crate) struct Scheduler {
+    pool: Pool<ConnectionManager>,
+    events: broadcast::Sender<SidecarEvent>,
+    store: Option<Arc<dyn ScheduleStore>>,
+    next_id: AtomicU64,
+    tasks: Mutex<HashMap<u64, JoinHandle<()>>>,
+}
+
+impl Scheduler {
+    /// Create the scheduler, and start the schedules in `store`.
+    pub(crate) async fn start(
+        pool: Pool<ConnectionManager>,
+        events: broadcast::Sender<SidecarEvent>,
+        store: Option<Arc<dyn ScheduleStore>>,
+    ) -> Result<Self, Error> {
+        let saved = match &store {
+            Some(store) => store.load().await.map_err(Error::ScheduleStore)?,
+            None => Vec::new(),
+        };
+
+        let scheduler = Scheduler {
+            pool,
+            events,
+            store,
+            next_id: AtomicU64::new(saved.iter().map(|s| s.id + 1).max().unwrap_or(1)),
+            tasks: Mutex::new(HashMap::new()),
+        };
+        for schedule in saved {
+            match CronSchedule::parse(&schedule.cron) {
+                Ok(cron) => scheduler.spawn(schedule.id, cron, schedule.args),
+                Err(e) => {
+                    tracing::warn!(schedule = schedule.id, error = %e, "Skipping saved schedule");
+                }
+            }
+        }
+
+        Ok(scheduler)
+    }
+
+    pub(crate) async fn add(&self, cron: &str, args: RunScriptArgs) -> Result<u64, Error> {
+        let schedule = CronSchedule::parse(cron)?;
+        if schedule.next_after(SystemTime::now()).is_none() {
+            return Err(Error::InvalidSchedule(format!("{cron:?} never runs")));
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Some(store) = &self.store {
+            store
+                .save(ScheduledScript {
+                    id,
+                    cron: cron.to_string(),
+                    args: args.clone(),
+                })
+                .await
+                .map_err(Error::ScheduleStore)?;
+        }
+
+        self.spawn(id, schedule, args);
+        Ok(id)
+    }
+
+    pub(crate) async fn remove(&self, id: u64) -> Result<bool, Error> {
+        let Some(task) = self.tasks.lock().unwrap().remove(&id) else {
+            return Ok(false);
+        };
+        task.abort();
+
+        if let Some(store) = &self.store {
+            store.remove(id).await.map_err(Error::ScheduleStore)?;
+        }
+        Ok(true)
+    }
+
+    /// Stop running the schedules, leaving them in the store.
+    pub(crate) fn stop(&self) {
+        for (_, task) in self.tasks.lock().unwrap().drain() {
+            task.abort();
+        }
+    }
+
+    fn spawn(&self, id: u64, schedule: CronSchedule, args: RunScriptArgs) {
+        let task = tokio::task::spawn(run_schedule(
+            id,
+            schedule,
+            args,
+            self.pool.clone(),
+            self.events.clone(),
+        ));
+        self.tasks.lock().unwrap().insert(id, task);
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Run `args` each time the schedule comes up, and send the results to the event channel. A run
+/// that is still going when the next one is due delays it, and runs that were missed are skipped.
+async fn run_schedule(
+    id: u64,
+    schedule: CronSchedule,
+    args: RunScriptArgs,
+    pool: Pool<ConnectionManager>,
+    events: broadcast::Sender<SidecarEvent>,
+) {
+    let mut after = SystemTime::now();
+    while let Some(due) = schedule.next_after(after) {
+        let wait = due.duration_since(SystemTime::now()).unwrap_or_default();
+        tokio::time::sleep(wait).await;
+        if pool.is_closed() {
+            break;
+        }
+
+        let result = match pool.get().await {
+            Ok(mut conn) => {
+                let result = conn.run_script_and_wait(args.clone()).await;
+                if result.as_ref().is_err_and(Error::is_connection_failure) {
+                    // Don't return the broken connection to the pool.
+                    let _ = deadpool::managed::Object::take(conn);
+                }
+                result
+            }
+            Err(e) => Err(Error::Pool(Box::new(e))),
+        };
+        if let Err(e) = &result {
+            tracing::debug!(schedule = id, error = ?e, "Scheduled script failed");
+        }
+        events
+            .send(SidecarEvent::ScheduledRun {
+                id,
+                due,
+                result: Arc::new(result),
+            })
+            .ok();
+
+        // The timer can fire a little early, so don't run the same time twice.
+        after = due.max(SystemTime::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn next(expr: &str, after: u64) -> Option<u64> {
+        CronSchedule::parse(expr)
+            .unwrap()
+            .next_after(at(after))
+            .map(|t| t.duration_since(UNIX_EPOCH).unwrap().as_secs())
+    }
+
+    // 2023-06-15 12:34:56, a Thursday
+    const THURSDAY: u64 = 1_686_832_496;
+
+    #[test]
+    fn next_after() {
+        // 2024-02-28 23:59:30 to the leap day
+        assert_eq!(next("0 0 * * *", 1_709_164_770), Some(1_709_164_800));
+        assert_eq!(next("@daily", 1_709_164_770), Some(1_709_164_800));
+        // The next leap day is four years later.
+        assert_eq!(next("0 0 29 2 *", 1_709_164_800), Some(1_835_395_200));
+        // Sunday at 9:00, from 0 or 7
+        assert_eq!(next("0 9 * * 0", THURSDAY), Some(1_687_078_800));
+        assert_eq!(next("0 9 * * 7", THURSDAY), Some(1_687_078_800));
+        // The 13th or a Friday, whichever comes first
+        assert_eq!(next("0 0 13 * 5", THURSDAY), Some(1_686_873_600));
+        // With seconds
+        assert_eq!(next("*/15 * * * * *", THURSDAY), Some(THURSDAY + 4));
+        assert_eq!(next("* * * * * *", THURSDAY), Some(THURSDAY + 1));
+        assert_eq!(next("1,2 35 12 * * *", THURSDAY), Some(THURSDAY + 5));
+        assert_eq!(next("0 0 31 2 *", THURSDAY), None);
+    }
+
+    #[test]
+    fn serialize_scheduled_script() {
+        let schedule = ScheduledScript {
+            id: 3,
+            cron: "@hourly".to_string(),
+            args: RunScriptArgs {
+                code: "1 + 1".into(),
+                expr: true,
+                tenant: Some("acme".to_string()),
+                signature: Some("sig".to_string()),
+                wasm_modules: vec![WasmModule {
+                    name: "add".into(),
+                    bytes: Bytes::from_static(b"\0asm"),
+                }],
+                ..Default::default()
+            },
+        };
+
+        let json = serde_json::to_string(&schedule).unwrap();
+        let loaded: ScheduledScript = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.id, 3);
+        assert_eq!(loaded.cron, "@hourly");
+        assert_eq!(loaded.args.code, "1 + 1");
+        assert!(loaded.args.expr);
+        assert_eq!(loaded.args.tenant.as_deref(), Some("acme"));
+        assert_eq!(loaded.args.signature.as_deref(), Some("sig"));
+        assert_eq!(loaded.args.wasm_modules.len(), 1);
+        assert_eq!(loaded.args.wasm_modules[0].name, "add");
+        assert_eq!(&loaded.args.wasm_modules[0].bytes[..], b"\0asm");
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for expr in [
+            "* * *",
+            "* * * * * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+            "@never",
+        ] {
+            assert!(
+                matches!(CronSchedule::parse(expr), Err(Error::InvalidSchedule(_))),
+                "{expr}"
+            );
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tempfile::TempDir;
+
+/// A directory that large payloads are passed through instead of being copied through the
+/// socket, shared with local workers.
+///
+/// Node.js can't receive file descriptors over a socket, so rather than passing memfds, each
+/// payload is written to its own file and the frame carries the file's name. The directory is
+/// created under `/dev/shm` when it exists, so the files stay in memory. The reader of a payload
+/// removes its file, and any files left behind by dropped connections are removed along with the
+/// directory when the sidecar is dropped.
+#[derive(Debug)]
+pub(crate) struct SharedMemory {
+    dir: TempDir,
+    /// Payloads larger than this many bytes are passed through the directory.
+    pub min_bytes: usize,
+    next_id: AtomicU64,
+}
+
+impl SharedMemory {
+    pub fn new(min_bytes: usize) -> io::Result<Self> {
+        let dir = tempfile::Builder::new()
+            .prefix("js-sidecar-shm-")
+            .tempdir_in(Self::parent_dir())?;
+        Ok(Self {
+            dir,
+            min_bytes,
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// The directory that the payload directories are created in.
+    pub fn parent_dir() -> PathBuf {
+        let shm = Path::new("/dev/shm");
+        if shm.is_dir() {
+            shm.to_path_buf()
+        } else {
+            std::env::temp_dir()
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Write a payload to a new file, returning the name to send in its place.
+    pub async fn write(&self, payload: &[u8]) -> io::Result<String> {
+        let name = format!("host-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        tokio::fs::write(self.dir.path().join(&name), payload).await?;
+        Ok(name)
+    }
+
+    /// Read and remove a payload that the worker wrote. Payloads larger than `limit` are removed
+    /// without being read, and their length is returned instead.
+    pub async fn take(&self, name: &str, limit: usize) -> io::Result<Result<Vec<u8>, u64>> {
+        let path = self.segment_path(name)?;
+        let length = tokio::fs::metadata(&path).await?.len();
+        let result = if length > limit as u64 {
+            Err(length)
+        } else {
+            Ok(tokio::fs::read(&path).await?)
+        };
+        tokio::fs::remove_file(&path).await?;
+        Ok(result)
+    }
+
+    /// The path of a payload file, rejecting names that would point outside the directory.
+    fn segment_path(&self, name: &str) -> io::Result<PathBuf> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid shared memory segment name {name:?}"),
+            ));
+        }
+        Ok(self.dir.path().join(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn take_segments() {
+        let shm = SharedMemory::new(0).unwrap();
+        let name = shm.write(b"hello").await.unwrap();
+        assert_eq!(shm.take(&name, 100).await.unwrap(), Ok(b"hello".to_vec()));
+        assert!(
+            shm.take(&name, 100).await.is_err(),
+            "segment should be removed"
+        );
+
+        let name = shm.write(&[0; 200]).await.unwrap();
+        assert_eq!(shm.take(&name, 100).await.unwrap(), Err(200));
+        assert!(!shm.path().join(&name).exists());
+
+        for name in ["../x", ".hidden", "a/b", ""] {
+            let err = shm.take(name, 100).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{name:?}");
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use tokio::sync::{broadcast, mpsc};
+
+use crate::wire::WorkerToHostMessageData;
+
+/// Receives the messages of a run from
+/// [run_script_with_sink](crate::Connection::run_script_with_sink) as they arrive: console
+/// messages, output chunks, channel messages, and finally the run's response or error.
+///
+/// `send` is called inline while the connection waits for the next message, so implementations
+/// should hand the message off rather than doing slow work directly. Closures, unbounded channel
+/// senders, and broadcast senders are sinks, and a broadcast sender lets any number of
+/// subscribers watch the same run.
+pub trait MessageSink: Send {
+    /// Handle a message from the run.
+    fn send(&mut self, message: WorkerToHostMessageData);
+}
+
+impl<F> MessageSink for F
+where
+    F: FnMut(WorkerToHostMessageData) + Send,
+{
+    fn send(&mut self, message: WorkerToHostMessageData) {
+        self(message)
+    }
+}
+
+/// Messages sent after the receiver has closed are dropped.
+impl MessageSink for mpsc::UnboundedSender<WorkerToHostMessageData> {
+    fn send(&mut self, message: WorkerToHostMessageData) {
+        mpsc::UnboundedSender::send(self, message).ok();
+    }
+}
+
+/// Messages sent while there are no subscribers are dropped.
+impl MessageSink for broadcast::Sender<WorkerToHostMessageData> {
+    fn send(&mut self, message: WorkerToHostMessageData) {
+        broadcast::Sender::send(self, message).ok();
+    }
+}
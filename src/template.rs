@@ -0,0 +1,313 @@
+use std::{borrow::Cow, collections::HashMap, iter::Peekable, str::Chars};
+
+use crate::{Error, RunScriptArgs};
+
+/// Functions that turn strings into code. Values passed to a template could end up in their
+/// arguments. `import()` loads a module from any specifier, including a `data:` URL.
+const CODE_FROM_STRINGS: &[&str] = &["eval", "Function", "import"];
+
+/// Functions that run their first argument as code when it is a string.
+const STRING_CALLBACKS: &[&str] = &["setTimeout", "setInterval"];
+
+/// A script whose values are always passed to it as arguments, and never spliced into its code.
+///
+/// The template is the body of an async function, whose parameters are the names given when
+/// the template is created. Each run calls the function with the run's values, so a value that
+/// contains code is only ever treated as data.
+///
+/// ```no_run
+/// # use js_sidecar::{JsSidecar, ScriptTemplate};
+/// # async fn example(sidecar: &JsSidecar) -> Result<(), js_sidecar::Error> {
+/// let greet = ScriptTemplate::new("greet", &["name"], "return `Hello, ${name}!`;")?;
+/// let args = greet.run_args([("name", "'); process.exit(); ('".into())])?;
+/// let result = sidecar.run(args).await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The template has to be a `&'static str`, so that it can't be built with `format!` from values
+/// that should have been parameters. Templates are also checked when they are created, and
+/// rejected if they:
+///
+/// - pass strings to `eval`, `Function`, dynamic `import()`, or the string forms of `setTimeout`
+///   and `setInterval`, which would turn the values back into code. This includes calls inside
+///   the `${}` substitutions of template literals.
+/// - contain a placeholder such as `{{name}}` for one of the parameters, which suggests that the
+///   template was written for substitution.
+#[derive(Debug, Clone)]
+pub struct ScriptTemplate {
+    name: &'static str,
+    params: Vec<&'static str>,
+    code: String,
+}
+
+impl ScriptTemplate {
+    /// Create a template from the body of a function with the given parameters. Returns
+    /// [Error::InvalidTemplate] if a parameter name isn't a valid identifier, or the template
+    /// doesn't pass the checks above.
+    pub fn new(
+        name: &'static str,
+        params: &[&'static str],
+        template: &'static str,
+    ) -> Result<Self, Error> {
+        for (i, param) in params.iter().enumerate() {
+            if !is_identifier(param) {
+                return Err(Error::InvalidTemplate(format!(
+                    "Parameter {param:?} is not a valid identifier"
+                )));
+            }
+            if params[..i].contains(param) {
+                return Err(Error::InvalidTemplate(format!(
+                    "Parameter {param} is listed more than once"
+                )));
+            }
+        }
+
+        lint(template, params).map_err(Error::InvalidTemplate)?;
+
+        Ok(ScriptTemplate {
+            name,
+            params: params.to_vec(),
+            code: format!("async function ({}) {{\n{template}\n}}", params.join(", ")),
+        })
+    }
+
+    /// The names of the template's parameters
+    pub fn params(&self) -> &[&'static str] {
+        &self.params
+    }
+
+    /// The arguments to run the template with the given value for each parameter. Every
+    /// parameter must have a value.
+    pub fn run_args<'a>(
+        &self,
+        values: impl IntoIterator<Item = (&'a str, serde_json::Value)>,
+    ) -> Result<RunScriptArgs, Error> {
+        let mut values = values.into_iter().collect::<HashMap<_, _>>();
+        let args = self
+            .params
+            .iter()
+            .map(|param| {
+                values.remove(param).ok_or_else(|| {
+                    Error::InvalidTemplate(format!("No value for parameter {param}"))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(unknown) = values.keys().next() {
+            return Err(Error::InvalidTemplate(format!(
+                "Template {} has no parameter {unknown}",
+                self.name
+            )));
+        }
+
+        Ok(RunScriptArgs {
+            name: Cow::Borrowed(self.name),
+            code: Cow::Owned(self.code.clone()),
+            call: true,
+            args,
+            ..Default::default()
+        })
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+/// Check the template for code that turns strings into code, and for placeholders.
+fn lint(template: &str, params: &[&str]) -> Result<(), String> {
+    let code = strip_literals(template);
+
+    for param in params {
+        for placeholder in [format!("{{{{{param}}}}}"), format!("{{{{ {param} }}}}")] {
+            if code.contains(&placeholder) {
+                return Err(format!(
+                    "Template contains the placeholder {placeholder}, but parameters are passed \
+                     as arguments, not substituted into the code"
+                ));
+            }
+        }
+    }
+
+    let mut rest = &code[..];
+    while let Some(start) = rest.find(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '$') {
+        rest = &rest[start..];
+        let end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '$'))
+            .unwrap_or(rest.len());
+        let (word, after) = rest.split_at(end);
+        rest = after;
+
+        let Some(call_args) = after.trim_start().strip_prefix('(') else {
+            continue;
+        };
+        if CODE_FROM_STRINGS.contains(&word) {
+            return Err(format!(
+                "Template calls {word}, which could run a parameter as code"
+            ));
+        }
+        if STRING_CALLBACKS.contains(&word) && call_args.trim_start().starts_with(['"', '\'', '`'])
+        {
+            return Err(format!(
+                "Template passes a string to {word}, which runs it as code"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Replace comments with spaces and empty out string literals, keeping their quotes, so that
+/// only code is left. The substitutions in template literals are code, so they are kept.
+fn strip_literals(template: &str) -> String {
+    let mut code = String::with_capacity(template.len());
+    strip_code(&mut template.chars().peekable(), &mut code, false);
+    code
+}
+
+/// Copy code into `code`, without its comments and the contents of its literals. With
+/// `in_substitution`, this stops after the `}` that closes a template literal's `${`.
+fn strip_code(chars: &mut Peekable<Chars>, code: &mut String, in_substitution: bool) {
+    let mut depth = 0usize;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                code.push(c);
+                while let Some(next) = chars.next() {
+                    if next == '\\' {
+                        chars.next();
+                    } else if next == c {
+                        break;
+                    }
+                }
+                code.push(c);
+            }
+            '`' => {
+                code.push(c);
+                strip_template(chars, code);
+            }
+            '{' => {
+                depth += 1;
+                code.push(c);
+            }
+            '}' if in_substitution && depth == 0 => {
+                code.push(c);
+                return;
+            }
+            '}' => {
+                depth = depth.saturating_sub(1);
+                code.push(c);
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.next_if(|&next| next != '\n').is_some() {}
+                code.push(' ');
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                for next in chars.by_ref() {
+                    if last == '*' && next == '/' {
+                        break;
+                    }
+                    last = next;
+                }
+                code.push(' ');
+            }
+            c => code.push(c),
+        }
+    }
+}
+
+/// Empty out the text of a template literal after its opening backtick, keeping the code in its
+/// substitutions, up to and including the closing backtick.
+fn strip_template(chars: &mut Peekable<Chars>, code: &mut String) {
+    while let Some(next) = chars.next() {
+        match next {
+            '\\' => {
+                chars.next();
+            }
+            '`' => break,
+            '$' if chars.peek() == Some(&'{') => {
+                chars.next();
+                code.push_str("${");
+                strip_code(chars, code, true);
+            }
+            _ => {}
+        }
+    }
+    code.push('`');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_args() {
+        let template = ScriptTemplate::new("add", &["a", "b"], "return a + b;").unwrap();
+        let args = template
+            .run_args([("b", 2.into()), ("a", "1".into())])
+            .unwrap();
+        assert!(args.call);
+        assert_eq!(args.args, [serde_json::json!("1"), serde_json::json!(2)]);
+        assert_eq!(args.code, "async function (a, b) {\nreturn a + b;\n}");
+
+        assert!(matches!(
+            template.run_args([("a", 1.into())]),
+            Err(Error::InvalidTemplate(reason)) if reason == "No value for parameter b"
+        ));
+        assert!(matches!(
+            template.run_args([("a", 1.into()), ("b", 2.into()), ("c", 3.into())]),
+            Err(Error::InvalidTemplate(reason)) if reason == "Template add has no parameter c"
+        ));
+    }
+
+    #[test]
+    fn params() {
+        assert!(ScriptTemplate::new("t", &["a-b"], "").is_err());
+        assert!(ScriptTemplate::new("t", &["a", "a"], "").is_err());
+        assert!(ScriptTemplate::new("t", &["$a", "_b2"], "").is_ok());
+    }
+
+    #[test]
+    fn checks() {
+        let check = |template: &'static str| ScriptTemplate::new("t", &["name"], template);
+
+        for rejected in [
+            "return eval('1 + ' + name);",
+            "return new Function('return ' + name)();",
+            "return globalThis.eval (name);",
+            "setTimeout(\"run(\" + name + \")\", 10);",
+            "setInterval(`run(${name})`);",
+            "return `${eval(name)}`;",
+            "return `a ${`b ${ { x: eval(name) }.x }`} c`;",
+            "return await import(name);",
+            "return import (`./${name}.js`);",
+            "return 'Hello ' + {{name}};",
+            "return {{ name }};",
+        ] {
+            assert!(
+                matches!(check(rejected), Err(Error::InvalidTemplate(_))),
+                "{rejected}"
+            );
+        }
+
+        for allowed in [
+            "return `Hello, ${name}!`;",
+            "// eval(name) would be bad\nreturn name;",
+            "/* new Function(name) */ return name;",
+            "return 'eval(name)' + \"{{name}}\";",
+            "setTimeout(() => console.log(name), 10);",
+            "const evaluate = (x) => x; return evaluate(name);",
+            "return { name };",
+            "return `eval(${name}) ${ { name }.name }`;",
+            "return `${'}'} ${name}` + eval.name;",
+        ] {
+            assert!(check(allowed).is_ok(), "{allowed}");
+        }
+    }
+}
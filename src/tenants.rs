@@ -0,0 +1,405 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{Error, RunStats};
+
+/// The window over which [TenantQuota::run_time_per_minute] is measured.
+const RUN_TIME_WINDOW: Duration = Duration::from_secs(60);
+
+/// How long a tenant has to go without runs before it is forgotten, along with its stats.
+const TENANT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Limits on the runs of one tenant, set with
+/// [JsSidecarBuilder::tenant_quota](crate::JsSidecarBuilder::tenant_quota) and
+/// [JsSidecarBuilder::default_tenant_quota](crate::JsSidecarBuilder::default_tenant_quota).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TenantQuota {
+    /// The most runs that the tenant can have going at once. Further runs wait for one of them to
+    /// finish, before they take a connection from the pool, so a busy tenant can't hold every
+    /// connection while others wait.
+    pub max_concurrent_runs: Option<usize>,
+    /// How much wall-clock time the tenant's runs can take over any minute. This is measured by
+    /// the worker's [RunStats] for runs that return them, which includes time spent awaiting
+    /// promises, and otherwise from when the run got its turn until it failed or was dropped.
+    /// The budget refills continuously, and once it is used up, runs fail with
+    /// [Error::TenantQuotaExceeded] until enough of it has refilled.
+    pub run_time_per_minute: Option<Duration>,
+}
+
+/// Counters for one tenant's runs, from [JsSidecar::tenant_stats](crate::JsSidecar::tenant_stats).
+/// A tenant that hasn't run anything for ten minutes is forgotten, and starts again from zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TenantStats {
+    /// Runs that have a connection, or are waiting for one
+    pub active_runs: usize,
+    /// Runs that are waiting for the tenant's other runs to finish
+    pub queued_runs: usize,
+    /// Runs that finished successfully
+    pub completed_runs: u64,
+    /// Runs that failed, including scripts that threw errors, runs that timed out, and runs whose
+    /// callers gave up on them
+    pub failed_runs: u64,
+    /// Runs that were turned away because the tenant used up its run time
+    pub rejected_runs: u64,
+    /// The total wall-clock time of the tenant's runs, measured as for
+    /// [TenantQuota::run_time_per_minute]
+    pub run_time: Duration,
+}
+
+/// Schedules runs by tenant, enforcing each tenant's quota and keeping its statistics.
+#[derive(Debug)]
+pub(crate) struct Tenants {
+    quotas: HashMap<String, TenantQuota>,
+    default_quota: TenantQuota,
+    tenants: Mutex<HashMap<String, Arc<Tenant>>>,
+    /// When idle tenants were last looked for
+    swept_at: Mutex<Instant>,
+}
+
+#[derive(Debug)]
+struct Tenant {
+    name: String,
+    quota: TenantQuota,
+    slots: Option<Arc<Semaphore>>,
+    usage: Mutex<Usage>,
+}
+
+#[derive(Debug)]
+struct Usage {
+    stats: TenantStats,
+    /// The run time left in the budget. This goes negative when a run takes more than was left,
+    /// and the tenant can't run again until it refills past zero.
+    budget: f64,
+    refilled_at: Instant,
+    /// When a run of the tenant last started or finished
+    used_at: Instant,
+}
+
+impl Tenants {
+    pub fn new(quotas: HashMap<String, TenantQuota>, default_quota: Option<TenantQuota>) -> Self {
+        Self {
+            quotas,
+            default_quota: default_quota.unwrap_or_default(),
+            tenants: Mutex::new(HashMap::new()),
+            swept_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait for the tenant to have room for another run, or fail if it has used up its run time.
+    pub async fn acquire(&self, name: &str) -> Result<TenantPermit, Error> {
+        let tenant = self.tenant(name);
+        {
+            let mut usage = tenant.usage.lock().unwrap();
+            if let Some(retry_after) = tenant.refill(&mut usage) {
+                usage.stats.rejected_runs += 1;
+                return Err(Error::TenantQuotaExceeded {
+                    tenant: name.to_string(),
+                    retry_after,
+                });
+            }
+            usage.stats.queued_runs += 1;
+        }
+
+        // Undo the queued count if the caller gives up while waiting.
+        let queued = QueuedGuard(&tenant);
+        let slot = match &tenant.slots {
+            Some(slots) => Some(
+                slots
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("tenant semaphore is never closed"),
+            ),
+            None => None,
+        };
+        drop(queued);
+
+        let started = Instant::now();
+        {
+            let mut usage = tenant.usage.lock().unwrap();
+            usage.stats.active_runs += 1;
+            usage.used_at = started;
+        }
+        Ok(TenantPermit {
+            tenant,
+            started,
+            finished: false,
+            _slot: slot,
+        })
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, TenantStats> {
+        self.tenants
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, tenant)| (name.clone(), tenant.usage.lock().unwrap().stats.clone()))
+            .collect()
+    }
+
+    fn tenant(&self, name: &str) -> Arc<Tenant> {
+        let mut tenants = self.tenants.lock().unwrap();
+        self.evict_idle(&mut tenants);
+        if let Some(tenant) = tenants.get(name) {
+            return tenant.clone();
+        }
+
+        let quota = self.quotas.get(name).unwrap_or(&self.default_quota).clone();
+        let tenant = Arc::new(Tenant {
+            name: name.to_string(),
+            slots: quota
+                .max_concurrent_runs
+                .map(|max| Arc::new(Semaphore::new(max.max(1)))),
+            usage: Mutex::new(Usage {
+                stats: TenantStats::default(),
+                budget: quota.run_time_per_minute.unwrap_or_default().as_secs_f64(),
+                refilled_at: Instant::now(),
+                used_at: Instant::now(),
+            }),
+            quota,
+        });
+        tenants.insert(name.to_string(), tenant.clone());
+        tenant
+    }
+
+    /// Forget the tenants that haven't run anything for a while, so that a stream of one-off
+    /// tenants doesn't grow the map forever. A tenant is kept while it has runs waiting or going,
+    /// which hold on to it, or until its budget has refilled, so that forgetting it doesn't
+    /// restore run time that it has used.
+    ///
+    /// This looks through the tenants at most once per [TENANT_IDLE_TIMEOUT].
+    fn evict_idle(&self, tenants: &mut HashMap<String, Arc<Tenant>>) {
+        let mut swept_at = self.swept_at.lock().unwrap();
+        if swept_at.elapsed() < TENANT_IDLE_TIMEOUT {
+            return;
+        }
+        *swept_at = Instant::now();
+
+        tenants.retain(|_, tenant| {
+            if Arc::strong_count(tenant) > 1 {
+                return true;
+            }
+            let mut usage = tenant.usage.lock().unwrap();
+            let refilled = tenant.refill(&mut usage).is_none()
+                && tenant
+                    .quota
+                    .run_time_per_minute
+                    .is_none_or(|limit| usage.budget >= limit.as_secs_f64());
+            !refilled || usage.used_at.elapsed() < TENANT_IDLE_TIMEOUT
+        });
+    }
+}
+
+impl Tenant {
+    /// Refill the run time budget for the time since it was last refilled. Returns how long to
+    /// wait before running again, if the budget is used up.
+    fn refill(&self, usage: &mut Usage) -> Option<Duration> {
+        let limit = self.quota.run_time_per_minute?.as_secs_f64();
+        let now = Instant::now();
+        let rate = limit / RUN_TIME_WINDOW.as_secs_f64();
+        let elapsed = now.duration_since(usage.refilled_at).as_secs_f64();
+        usage.budget = (usage.budget + elapsed * rate).min(limit);
+        usage.refilled_at = now;
+
+        if usage.budget > 0.0 {
+            None
+        } else if rate > 0.0 {
+            Some(Duration::from_secs_f64(-usage.budget / rate))
+        } else {
+            Some(RUN_TIME_WINDOW)
+        }
+    }
+
+    /// Count a finished run and charge its time to the budget.
+    fn charge(&self, succeeded: bool, run_time: Duration) {
+        let mut usage = self.usage.lock().unwrap();
+        if succeeded {
+            usage.stats.completed_runs += 1;
+        } else {
+            usage.stats.failed_runs += 1;
+        }
+        usage.stats.run_time += run_time;
+        usage.used_at = Instant::now();
+        if self.quota.run_time_per_minute.is_some() {
+            self.refill(&mut usage);
+            usage.budget -= run_time.as_secs_f64();
+            if usage.budget <= 0.0 {
+                tracing::debug!(tenant = %self.name, "Tenant used up its run time");
+            }
+        }
+    }
+}
+
+struct QueuedGuard<'a>(&'a Tenant);
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.usage.lock().unwrap().stats.queued_runs -= 1;
+    }
+}
+
+/// A tenant's room for one run. The run's outcome is recorded with [TenantPermit::finish], and
+/// dropping the permit lets the tenant's next run start. A permit dropped without finishing, such
+/// as when the caller gives up on the run, counts as a failed run.
+#[derive(Debug)]
+pub(crate) struct TenantPermit {
+    tenant: Arc<Tenant>,
+    /// When the run got its turn
+    started: Instant,
+    finished: bool,
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl TenantPermit {
+    /// Count the run, charging the tenant for the run time in its stats, or for the time since
+    /// the run got its turn if it has no stats, such as when it failed or timed out.
+    pub fn finish(mut self, succeeded: bool, stats: Option<&RunStats>) {
+        let run_time = stats
+            .map(|s| Duration::from_secs_f64((s.compile_ms + s.execute_ms).max(0.0) / 1000.0))
+            .unwrap_or_else(|| self.started.elapsed());
+        self.tenant.charge(succeeded, run_time);
+        self.finished = true;
+    }
+}
+
+impl Drop for TenantPermit {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.tenant.charge(false, self.started.elapsed());
+        }
+        self.tenant.usage.lock().unwrap().stats.active_runs -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_stats(ms: f64) -> RunStats {
+        RunStats {
+            compile_ms: 0.0,
+            execute_ms: ms,
+            heap_used_delta: 0,
+            macrotasks_drained: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrency() {
+        let tenants = Tenants::new(
+            [(
+                "a".to_string(),
+                TenantQuota {
+                    max_concurrent_runs: Some(1),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            None,
+        );
+
+        let first = tenants.acquire("a").await.unwrap();
+        // Other tenants aren't limited.
+        let _b = tenants.acquire("b").await.unwrap();
+        let _b2 = tenants.acquire("b").await.unwrap();
+
+        let second = tenants.acquire("a");
+        tokio::pin!(second);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), &mut second)
+                .await
+                .is_err(),
+            "second run should wait"
+        );
+        assert_eq!(tenants.snapshot()["a"].queued_runs, 1);
+        assert_eq!(tenants.snapshot()["b"].active_runs, 2);
+
+        first.finish(true, Some(&run_stats(5.0)));
+        let second = second.await.unwrap();
+        assert_eq!(tenants.snapshot()["a"].active_runs, 1);
+        second.finish(false, None);
+
+        let stats = &tenants.snapshot()["a"];
+        assert_eq!(stats.active_runs, 0);
+        assert_eq!(stats.queued_runs, 0);
+        assert_eq!(stats.completed_runs, 1);
+        assert_eq!(stats.failed_runs, 1);
+        assert!(stats.run_time >= Duration::from_millis(5));
+    }
+
+    #[tokio::test]
+    async fn run_time() {
+        let tenants = Tenants::new(
+            HashMap::new(),
+            Some(TenantQuota {
+                run_time_per_minute: Some(Duration::from_millis(100)),
+                ..Default::default()
+            }),
+        );
+
+        let permit = tenants.acquire("a").await.unwrap();
+        permit.finish(true, Some(&run_stats(250.0)));
+
+        let Err(Error::TenantQuotaExceeded {
+            tenant,
+            retry_after,
+        }) = tenants.acquire("a").await
+        else {
+            panic!("Expected the quota to be exceeded");
+        };
+        assert_eq!(tenant, "a");
+        // 150ms over the budget, which refills at 100ms a minute.
+        assert!(retry_after > Duration::from_secs(80) && retry_after <= Duration::from_secs(90));
+
+        // Each tenant has its own budget.
+        tenants.acquire("b").await.unwrap();
+        let stats = &tenants.snapshot()["a"];
+        assert_eq!(stats.rejected_runs, 1);
+        assert_eq!(stats.active_runs, 0);
+
+        // Runs without stats, such as ones that timed out or were dropped, are charged for the
+        // time since they got their turn.
+        let permit = tenants.acquire("c").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        permit.finish(false, None);
+        let permit = tenants.acquire("c").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        drop(permit);
+        let stats = &tenants.snapshot()["c"];
+        assert_eq!(stats.failed_runs, 2);
+        assert!(stats.run_time >= Duration::from_millis(120));
+        assert!(matches!(
+            tenants.acquire("c").await,
+            Err(Error::TenantQuotaExceeded { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn evict_idle() {
+        let tenants = Tenants::new(HashMap::new(), None);
+        let held = tenants.acquire("busy").await.unwrap();
+        tenants.acquire("idle").await.unwrap().finish(true, None);
+        tenants.acquire("recent").await.unwrap().finish(true, None);
+
+        let long_ago = Instant::now() - TENANT_IDLE_TIMEOUT;
+        for name in ["busy", "idle"] {
+            tenants.tenants.lock().unwrap()[name]
+                .usage
+                .lock()
+                .unwrap()
+                .used_at = long_ago;
+        }
+        *tenants.swept_at.lock().unwrap() = long_ago;
+
+        tenants.acquire("new").await.unwrap().finish(true, None);
+        let mut names = tenants.snapshot().into_keys().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["busy", "new", "recent"]);
+        drop(held);
+    }
+}
@@ -0,0 +1,318 @@
+//! Fixtures and assertions for integration tests that run scripts on a real sidecar.
+//!
+//! Each [TestSidecar] starts its own Node.js process with its sockets and worker script in a
+//! temporary directory, so tests can run in parallel without sharing sockets or leaving files
+//! behind. Runs are [deterministic](RunScriptArgs::deterministic) unless turned off, and the
+//! console messages of every run are kept for the test to check.
+//!
+//! ```no_run
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> Result<(), js_sidecar::Error> {
+//! use js_sidecar::{
+//!     test_harness::{RunAssertions, TestSidecar},
+//!     LogLevel,
+//! };
+//! use serde_json::json;
+//!
+//! let sidecar = TestSidecar::start().await?;
+//! sidecar
+//!     .run_code("console.log('rolling'); globalThis.roll = Math.random();")
+//!     .await?
+//!     .assert_logged(LogLevel::Info, "rolling");
+//! // Loaded from `tests/fixtures/sum.js` in the crate being tested.
+//! sidecar
+//!     .run_fixture("tests/fixtures/sum.js")
+//!     .await?
+//!     .assert_global("total", json!(6));
+//! sidecar.close().await;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde_json::Value;
+use tempfile::TempDir;
+
+use crate::{
+    Error, ErrorKind, ErrorResponseData, JsSidecar, JsSidecarBuilder, LogLevel, LogResponseData,
+    RunScriptAndWaitResult, RunScriptArgs,
+};
+
+/// A sidecar started for one test, which cleans up after itself. It dereferences to the
+/// [JsSidecar], for anything that the harness doesn't wrap.
+pub struct TestSidecar {
+    // Dropping the sidecar only starts stopping Node.js in the background, so without close the
+    // directory can be removed while Node.js is still exiting.
+    sidecar: JsSidecar,
+    dir: TempDir,
+    deterministic: bool,
+    logs: Mutex<Vec<LogResponseData>>,
+}
+
+impl TestSidecar {
+    /// Start a sidecar with one worker.
+    pub async fn start() -> Result<Self, Error> {
+        Self::start_with(JsSidecar::builder()).await
+    }
+
+    /// Start a sidecar from `builder`, with one worker unless it sets
+    /// [num_workers](JsSidecarBuilder::num_workers). The builder's
+    /// [socket_dir](JsSidecarBuilder::socket_dir) and [script_dir](JsSidecarBuilder::script_dir)
+    /// are replaced with the test's own directory.
+    pub async fn start_with(mut builder: JsSidecarBuilder) -> Result<Self, Error> {
+        let dir = tempfile::Builder::new()
+            .prefix("js_sidecar_test")
+            .tempdir()
+            .map_err(Error::StartWorker)?;
+        if builder.num_workers.is_none() {
+            builder = builder.num_workers(1);
+        }
+        let sidecar = builder.runtime_dir(dir.path()).build().await?;
+        Ok(Self {
+            sidecar,
+            dir,
+            deterministic: true,
+            logs: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// The directory that holds the sidecar's sockets and worker script.
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Whether runs are made [deterministic](RunScriptArgs::deterministic). Defaults to true.
+    /// Turn this off for scripts that use real timers.
+    ///
+    /// While this is on, it overrides the `deterministic` field of each run, since a run can't
+    /// tell an explicit `false` from the default. Turn it off to choose for each run instead.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    /// Run a script with [JsSidecar::run], keeping its console messages. The run is made
+    /// deterministic if the harness [is](Self::set_deterministic) or `args` asks for it.
+    pub async fn run(&self, mut args: RunScriptArgs) -> Result<RunScriptAndWaitResult, Error> {
+        args.deterministic |= self.deterministic;
+        let result = self.sidecar.run(args).await;
+        let logs = match &result {
+            Ok(result) => &result.logs[..],
+            Err(Error::Script(e)) => &e.logs[..],
+            Err(_) => &[],
+        };
+        self.logs.lock().unwrap().extend_from_slice(logs);
+        result
+    }
+
+    /// Run `code` as a script.
+    pub async fn run_code(&self, code: impl Into<String>) -> Result<RunScriptAndWaitResult, Error> {
+        self.run(RunScriptArgs {
+            code: code.into().into(),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Run the script in a fixture file, named after the file. Relative paths are relative to
+    /// the directory of the crate being tested.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the file can't be read.
+    #[track_caller]
+    pub fn run_fixture(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> impl std::future::Future<Output = Result<RunScriptAndWaitResult, Error>> + '_ {
+        let path = fixture_path(path.as_ref());
+        let code = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read fixture {}: {e}", path.display()));
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.run(RunScriptArgs {
+            name: name.into(),
+            code: code.into(),
+            ..Default::default()
+        })
+    }
+
+    /// The console messages logged by the runs so far, in order.
+    pub fn logs(&self) -> Vec<LogResponseData> {
+        self.logs.lock().unwrap().clone()
+    }
+
+    /// Return the console messages logged so far, and start collecting them again.
+    pub fn take_logs(&self) -> Vec<LogResponseData> {
+        std::mem::take(&mut self.logs.lock().unwrap())
+    }
+
+    /// Shut down Node.js and then remove the test's directory. Dropping the sidecar does the
+    /// same, but removes the directory without waiting for Node.js to exit.
+    pub async fn close(mut self) {
+        self.sidecar.close().await;
+    }
+}
+
+impl std::ops::Deref for TestSidecar {
+    type Target = JsSidecar;
+
+    fn deref(&self) -> &JsSidecar {
+        &self.sidecar
+    }
+}
+
+/// Resolve a fixture's path against the directory of the crate being tested, which Cargo sets
+/// while running tests.
+fn fixture_path(path: &Path) -> PathBuf {
+    match std::env::var_os("CARGO_MANIFEST_DIR") {
+        Some(dir) if path.is_relative() => Path::new(&dir).join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Assertions on the result of a run, which panic with the whole result when they fail. Each
+/// returns the result, so that they can be chained.
+pub trait RunAssertions {
+    /// Assert that the run returned `expected`.
+    fn assert_returned(&self, expected: Value) -> &Self;
+    /// Assert that the run returned a global `key` equal to `expected`.
+    fn assert_global(&self, key: &str, expected: Value) -> &Self;
+    /// Assert that the run logged a message at `level` whose text contains `text`.
+    fn assert_logged(&self, level: LogLevel, text: &str) -> &Self;
+}
+
+impl RunAssertions for RunScriptAndWaitResult {
+    #[track_caller]
+    fn assert_returned(&self, expected: Value) -> &Self {
+        assert_eq!(
+            self.response.return_value.as_ref(),
+            Some(&expected),
+            "Unexpected return value in {self:#?}"
+        );
+        self
+    }
+
+    #[track_caller]
+    fn assert_global(&self, key: &str, expected: Value) -> &Self {
+        assert_eq!(
+            self.response.globals.get(key),
+            Some(&expected),
+            "Unexpected value for global {key} in {self:#?}"
+        );
+        self
+    }
+
+    #[track_caller]
+    fn assert_logged(&self, level: LogLevel, text: &str) -> &Self {
+        assert!(
+            self.logs
+                .iter()
+                .any(|log| log.level == level && log_text(&log.message).contains(text)),
+            "No {level:?} message containing {text:?} in {:#?}",
+            self.logs
+        );
+        self
+    }
+}
+
+/// The arguments of a console message joined with spaces, as the console would print them.
+fn log_text(message: &Value) -> String {
+    let text = |value: &Value| match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    };
+    match message {
+        Value::Array(args) => args.iter().map(text).collect::<Vec<_>>().join(" "),
+        message => text(message),
+    }
+}
+
+/// Assert that a run failed with a script error of the given kind, and return the error.
+#[track_caller]
+pub fn assert_script_error(
+    result: Result<RunScriptAndWaitResult, Error>,
+    kind: ErrorKind,
+) -> ErrorResponseData {
+    match result {
+        Err(Error::Script(e)) if e.error.kind == kind => e.error,
+        result => panic!("Expected a {kind:?} script error, got {result:#?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn harness() {
+        let mut sidecar = TestSidecar::start().await.unwrap();
+        let dir = sidecar.dir().to_path_buf();
+        assert!(sidecar.socket_path().unwrap().starts_with(&dir));
+
+        sidecar
+            .run_fixture("src/fixtures/scripts/sum.js")
+            .await
+            .unwrap()
+            .assert_global("total", json!(6))
+            .assert_logged(LogLevel::Info, "sum of 3 values");
+
+        // Runs are deterministic, so Math.random gives the same value in every new context.
+        let random = |deterministic| RunScriptArgs {
+            code: "Math.random()".into(),
+            expr: true,
+            recreate_context: true,
+            deterministic,
+            ..Default::default()
+        };
+        let first = sidecar
+            .run(random(false))
+            .await
+            .unwrap()
+            .response
+            .return_value;
+        sidecar
+            .run(random(false))
+            .await
+            .unwrap()
+            .assert_returned(first.clone().unwrap());
+
+        // With the harness's setting off, each run chooses for itself.
+        sidecar.set_deterministic(false);
+        let unseeded = sidecar
+            .run(random(false))
+            .await
+            .unwrap()
+            .response
+            .return_value;
+        assert_ne!(unseeded, first);
+        sidecar
+            .run(random(true))
+            .await
+            .unwrap()
+            .assert_returned(first.unwrap());
+
+        let error = assert_script_error(
+            sidecar
+                .run_code("console.warn('about to fail'); missing()")
+                .await,
+            ErrorKind::ReferenceError,
+        );
+        assert!(error.message.contains("missing"), "{error:?}");
+
+        let logs = sidecar.take_logs();
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[1].level, LogLevel::Warn);
+        assert!(sidecar.logs().is_empty());
+
+        sidecar.close().await;
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,638 @@
+//! An in-memory stand-in for the Node.js workers, for testing code that uses the sidecar without
+//! needing Node.js.
+//!
+//! A [MockWorker] speaks the worker protocol over an in-memory pipe, and answers runs with
+//! responses set up ahead of time instead of running the code.
+//!
+//! ```
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> Result<(), js_sidecar::Error> {
+//! use js_sidecar::{
+//!     testing::{MockResponse, MockWorker},
+//!     LogLevel, RunScriptArgs,
+//! };
+//! use serde_json::json;
+//!
+//! let worker = MockWorker::new();
+//! worker.respond_to(
+//!     "1 + 1",
+//!     MockResponse::value(json!(2)).log(LogLevel::Info, json!(["adding"])),
+//! );
+//!
+//! let sidecar = worker.builder().build().await?;
+//! let value: i32 = sidecar.eval("1 + 1").await?;
+//! assert_eq!(value, 2);
+//! assert_eq!(worker.runs()[0].code, "1 + 1");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{channel::oneshot, future::BoxFuture};
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    transport::{ReadHalf, Transport, WriteHalf},
+    versions::PROTOCOL_VERSION,
+    wire::{
+        HostToWorkerMessage, HostToWorkerMessageData, WorkerToHostMessage, WorkerToHostMessageData,
+        FRAME_HEADER_LENGTH, MAX_PAYLOAD_BYTES,
+    },
+    ErrorKind, ErrorResponseData, HandshakeResponseData, JsSidecar, JsSidecarBuilder, LogLevel,
+    LogResponseData, PongData, RunResponseData,
+};
+
+/// The size of the in-memory pipe between the host and the mock worker.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// A fake worker that answers runs with canned [MockResponse]s. Connect a sidecar to it with
+/// [builder](Self::builder), or pass it to [JsSidecarBuilder::transport].
+///
+/// Runs are answered by the first response set up with [respond_to](Self::respond_to) or
+/// [respond_when](Self::respond_when) that matches them, or else by the
+/// [default response](Self::set_default_response). Pings and the handshake are answered like a
+/// real worker would, and cancelling a run that is still waiting out its
+/// [delay](MockResponse::delay) fails it with [ErrorKind::Cancelled]. Other requests, such as
+/// completions, fail with [ErrorKind::InternalWorkerError].
+///
+/// The worker reports a process ID of 0, so the sidecar never sends it signals.
+#[derive(Clone, Default)]
+pub struct MockWorker {
+    inner: Arc<MockWorkerInner>,
+}
+
+type Matcher = Box<dyn Fn(&MockRun) -> bool + Send + Sync>;
+
+#[derive(Default)]
+struct MockWorkerInner {
+    responses: Mutex<Vec<(Matcher, MockResponse)>>,
+    default_response: Mutex<MockResponse>,
+    runs: Mutex<Vec<MockRun>>,
+}
+
+impl std::fmt::Debug for MockWorker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockWorker")
+            .field("runs", &self.inner.runs.lock().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl MockWorker {
+    /// Create a worker that answers every run with an empty response.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A [JsSidecarBuilder] that connects to this worker.
+    pub fn builder(&self) -> JsSidecarBuilder {
+        JsSidecar::builder().transport(self.clone())
+    }
+
+    /// Answer runs whose code is exactly `code` with `response`.
+    pub fn respond_to(&self, code: impl Into<String>, response: MockResponse) {
+        let code = code.into();
+        self.respond_when(move |run| run.code == code, response);
+    }
+
+    /// Answer runs that `matches` returns true for with `response`.
+    pub fn respond_when(
+        &self,
+        matches: impl Fn(&MockRun) -> bool + Send + Sync + 'static,
+        response: MockResponse,
+    ) {
+        self.inner
+            .responses
+            .lock()
+            .unwrap()
+            .push((Box::new(matches), response));
+    }
+
+    /// Answer runs that no other response matches with `response`.
+    pub fn set_default_response(&self, response: MockResponse) {
+        *self.inner.default_response.lock().unwrap() = response;
+    }
+
+    /// The runs that the worker has received, in order.
+    pub fn runs(&self) -> Vec<MockRun> {
+        self.inner.runs.lock().unwrap().clone()
+    }
+
+    fn response_for(&self, run: &MockRun) -> MockResponse {
+        self.inner.runs.lock().unwrap().push(run.clone());
+        self.inner
+            .responses
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(matches, _)| matches(run))
+            .map(|(_, response)| response.clone())
+            .unwrap_or_else(|| self.inner.default_response.lock().unwrap().clone())
+    }
+}
+
+impl Transport for MockWorker {
+    fn connect(&self) -> BoxFuture<'_, std::io::Result<(ReadHalf, WriteHalf)>> {
+        let (host, worker) = tokio::io::duplex(PIPE_SIZE);
+        let (read, write) = tokio::io::split(worker);
+        tokio::spawn(MockConnection::new(self.clone(), write).serve(read));
+        let (read, write) = tokio::io::split(host);
+        Box::pin(async move { Ok((Box::new(read) as ReadHalf, Box::new(write) as WriteHalf)) })
+    }
+}
+
+/// A run received by a [MockWorker].
+#[derive(Debug, Clone)]
+pub struct MockRun {
+    /// The ID of the request, as returned by [Connection::run_script](crate::Connection::run_script)
+    pub request_id: u32,
+    /// The code of the run
+    pub code: String,
+    /// The whole [RunScriptArgs](crate::RunScriptArgs) of the run, as sent to the worker
+    pub args: Value,
+}
+
+/// How a [MockWorker] answers a run.
+#[derive(Debug, Clone, Default)]
+pub struct MockResponse {
+    logs: Vec<(LogLevel, Value)>,
+    outcome: MockOutcome,
+    delay: Duration,
+}
+
+#[derive(Debug, Clone)]
+enum MockOutcome {
+    Success {
+        return_value: Option<Value>,
+        globals: HashMap<String, Value>,
+    },
+    Error {
+        message: String,
+        kind: ErrorKind,
+    },
+}
+
+impl Default for MockOutcome {
+    fn default() -> Self {
+        MockOutcome::Success {
+            return_value: None,
+            globals: HashMap::new(),
+        }
+    }
+}
+
+impl MockResponse {
+    /// A successful run that returns `value`, as an [expression](crate::RunScriptArgs::expr)
+    /// would.
+    pub fn value(value: Value) -> Self {
+        Self {
+            outcome: MockOutcome::Success {
+                return_value: Some(value),
+                globals: HashMap::new(),
+            },
+            ..Default::default()
+        }
+    }
+
+    /// A run that fails with `message`.
+    pub fn error(message: impl Into<String>, kind: ErrorKind) -> Self {
+        Self {
+            outcome: MockOutcome::Error {
+                message: message.into(),
+                kind,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Return a global from the run. This has no effect on a response that fails.
+    pub fn global(mut self, key: impl Into<String>, value: Value) -> Self {
+        if let MockOutcome::Success { globals, .. } = &mut self.outcome {
+            globals.insert(key.into(), value);
+        }
+        self
+    }
+
+    /// Log a console message before answering. `message` is the list of arguments passed to
+    /// the console function.
+    pub fn log(mut self, level: LogLevel, message: Value) -> Self {
+        self.logs.push((level, message));
+        self
+    }
+
+    /// Wait this long before answering, as if the script took this long to run. A run whose
+    /// [timeout](crate::RunScriptArgs::timeout_ms) is shorter fails with [ErrorKind::Timeout]
+    /// once the timeout passes.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// The worker's end of one connection.
+struct MockConnection<W> {
+    worker: MockWorker,
+    writer: Arc<tokio::sync::Mutex<W>>,
+    /// Set by the handshake when the host asks for checksums.
+    checksums: Arc<std::sync::atomic::AtomicBool>,
+    /// Cancels the runs that are waiting out their delay.
+    pending: Arc<Mutex<HashMap<u32, oneshot::Sender<()>>>>,
+}
+
+impl<W: AsyncWrite + Send + Unpin + 'static> MockConnection<W> {
+    fn new(worker: MockWorker, writer: W) -> Self {
+        Self {
+            worker,
+            writer: Arc::new(tokio::sync::Mutex::new(writer)),
+            checksums: Default::default(),
+            pending: Default::default(),
+        }
+    }
+
+    fn clone_handle(&self) -> Self {
+        Self {
+            worker: self.worker.clone(),
+            writer: self.writer.clone(),
+            checksums: self.checksums.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+
+    /// Answer the host's messages until it closes the connection.
+    async fn serve(self, mut reader: impl AsyncRead + Unpin) {
+        while let Ok(Some(frame)) = read_frame(&mut reader).await {
+            let message = match HostToWorkerMessage::decode(&frame, MAX_PAYLOAD_BYTES) {
+                Ok(message) => message,
+                Err(e) => {
+                    // Like the real worker, drop a frame that can't be read and fail its request.
+                    let request_id = u32::from_le_bytes(frame[8..12].try_into().unwrap());
+                    let message = format!("Message from host could not be read: {e}");
+                    if self
+                        .send_error(request_id, 0, &message, ErrorKind::InternalWorkerError)
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            let request_id = message.request_id;
+            let result = match message.data {
+                HostToWorkerMessageData::Handshake(handshake) => {
+                    self.checksums
+                        .store(handshake.checksums, std::sync::atomic::Ordering::Relaxed);
+                    let response = HandshakeResponseData {
+                        version: PROTOCOL_VERSION,
+                        accepted: handshake.version == PROTOCOL_VERSION,
+                        pid: 0,
+                    };
+                    self.send(request_id, 0, WorkerToHostMessageData::Handshake(response))
+                        .await
+                }
+                HostToWorkerMessageData::Ping => {
+                    let pong = PongData { pid: Some(0) };
+                    self.send(request_id, 0, WorkerToHostMessageData::Pong(pong))
+                        .await
+                }
+                HostToWorkerMessageData::RunScript(run_message) => {
+                    let run = MockRun {
+                        request_id,
+                        code: run_message.args.code.to_string(),
+                        args: serde_json::to_value(&run_message.args).unwrap_or_default(),
+                    };
+                    let response = self.worker.response_for(&run);
+                    let (cancel, cancelled) = oneshot::channel();
+                    self.pending.lock().unwrap().insert(request_id, cancel);
+                    tokio::spawn(self.clone_handle().answer(run, response, cancelled));
+                    Ok(())
+                }
+                HostToWorkerMessageData::Cancel => {
+                    if let Some(cancel) = self.pending.lock().unwrap().remove(&request_id) {
+                        cancel.send(()).ok();
+                    }
+                    Ok(())
+                }
+                data => {
+                    self.send_error(
+                        request_id,
+                        0,
+                        &format!(
+                            "MockWorker doesn't handle message type {}",
+                            data.message_type()
+                        ),
+                        ErrorKind::InternalWorkerError,
+                    )
+                    .await
+                }
+            };
+
+            if result.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Send the response to a run once its delay is over.
+    async fn answer(self, run: MockRun, response: MockResponse, cancelled: oneshot::Receiver<()>) {
+        let timeout = run.args["timeoutMs"].as_u64().map(Duration::from_millis);
+        let delay = match timeout {
+            Some(timeout) => response.delay.min(timeout),
+            None => response.delay,
+        };
+        let cancelled = tokio::select! {
+            _ = tokio::time::sleep(delay) => false,
+            result = cancelled => result.is_ok(),
+        };
+        self.pending.lock().unwrap().remove(&run.request_id);
+
+        let request_id = run.request_id;
+        let result = if cancelled {
+            self.send_error(request_id, 0, "The run was cancelled", ErrorKind::Cancelled)
+                .await
+        } else if timeout.is_some_and(|timeout| response.delay > timeout) {
+            self.send_error(
+                request_id,
+                0,
+                "Script execution timed out",
+                ErrorKind::Timeout,
+            )
+            .await
+        } else {
+            self.send_outcome(request_id, &response).await
+        };
+        result.ok();
+    }
+
+    async fn send_outcome(&self, request_id: u32, response: &MockResponse) -> std::io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        for (message_id, (level, message)) in response.logs.iter().enumerate() {
+            let log = LogResponseData {
+                level: *level,
+                message: message.clone(),
+                timestamp,
+                request_id,
+                namespace: None,
+                count: 1,
+            };
+            self.send(
+                request_id,
+                message_id as u32,
+                WorkerToHostMessageData::Log(log),
+            )
+            .await?;
+        }
+
+        let message_id = response.logs.len() as u32;
+        match &response.outcome {
+            MockOutcome::Success {
+                return_value,
+                globals,
+            } => {
+                let data = RunResponseData {
+                    globals: globals.clone(),
+                    return_value: return_value.clone(),
+                    request_id,
+                    stats: None,
+                    skipped_globals: Vec::new(),
+                    effects: Vec::new(),
+                };
+                self.send(
+                    request_id,
+                    message_id,
+                    WorkerToHostMessageData::RunResponse(data),
+                )
+                .await
+            }
+            MockOutcome::Error { message, kind } => {
+                self.send_error(request_id, message_id, message, *kind)
+                    .await
+            }
+        }
+    }
+
+    async fn send_error(
+        &self,
+        request_id: u32,
+        message_id: u32,
+        message: &str,
+        kind: ErrorKind,
+    ) -> std::io::Result<()> {
+        let error = ErrorResponseData {
+            message: message.to_string(),
+            stack: None,
+            annotations: HashMap::new(),
+            request_id,
+            timed_out: kind == ErrorKind::Timeout,
+            kind,
+            name: None,
+            code: None,
+            cause: None,
+            errors: Vec::new(),
+        };
+        self.send(
+            request_id,
+            message_id,
+            WorkerToHostMessageData::Error(error),
+        )
+        .await
+    }
+
+    /// Send a message. Like the real worker, the messages of each request are numbered from 0.
+    async fn send(
+        &self,
+        request_id: u32,
+        message_id: u32,
+        data: WorkerToHostMessageData,
+    ) -> std::io::Result<()> {
+        let checksum = self.checksums.load(std::sync::atomic::Ordering::Relaxed);
+        let frame = WorkerToHostMessage::new(request_id, message_id, data)
+            .encode(checksum)
+            .map_err(std::io::Error::other)?;
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&frame).await?;
+        writer.flush().await
+    }
+}
+
+/// Read a whole frame from the host, or `None` once the host closes the connection.
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let length = u32::from_le_bytes(length);
+    if length < FRAME_HEADER_LENGTH {
+        return Err(std::io::Error::other("Frame is too short for its header"));
+    }
+
+    let mut frame = vec![0; length as usize + 4];
+    frame[..4].copy_from_slice(&length.to_le_bytes());
+    reader.read_exact(&mut frame[4..]).await?;
+    Ok(Some(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        wire::{decode_frame, DecodedFrame},
+        Error, RunScriptArgs, WasmModule,
+    };
+
+    #[tokio::test]
+    async fn canned_responses() {
+        let worker = MockWorker::new();
+        worker.respond_to(
+            "globalThis.total = 3",
+            MockResponse::default()
+                .global("total", json!(3))
+                .log(LogLevel::Warn, json!(["careful"])),
+        );
+        worker.respond_when(
+            |run| run.code.contains("throw"),
+            MockResponse::error("boom", ErrorKind::Script),
+        );
+        worker.set_default_response(MockResponse::value(json!("default")));
+
+        let mut sidecar = worker
+            .builder()
+            .frame_checksums(true)
+            .build()
+            .await
+            .unwrap();
+        let mut connection = sidecar.connect().await.unwrap();
+        assert_eq!(connection.worker_pid(), Some(0));
+
+        let result = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "globalThis.total = 3".into(),
+                return_keys: vec!["total".to_string()],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.globals["total"], json!(3));
+        assert_eq!(result.logs[0].level, LogLevel::Warn);
+        assert_eq!(result.logs[0].message, json!(["careful"]));
+
+        let err = connection
+            .run_script_and_wait(RunScriptArgs {
+                code: "throw new Error('boom')".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::Script(e) if e.error.message == "boom"),
+            "{err:?}"
+        );
+
+        let value: String = sidecar.eval("anything").await.unwrap();
+        assert_eq!(value, "default");
+
+        let codes = worker
+            .runs()
+            .into_iter()
+            .map(|run| run.code)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            [
+                "globalThis.total = 3",
+                "throw new Error('boom')",
+                "anything"
+            ]
+        );
+        drop(connection);
+        sidecar.close().await;
+    }
+
+    #[tokio::test]
+    async fn delays() {
+        let worker = MockWorker::new();
+        worker.set_default_response(MockResponse::value(json!(1)).delay(Duration::from_secs(60)));
+        let sidecar = worker.builder().build().await.unwrap();
+
+        let err = sidecar
+            .run(RunScriptArgs {
+                code: "slow()".into(),
+                timeout_ms: Some(20),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::Script(e) if e.error.kind == ErrorKind::Timeout),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn wasm_modules() {
+        let worker = MockWorker::new();
+        worker.set_default_response(MockResponse::value(json!(3)));
+        let sidecar = worker.builder().build().await.unwrap();
+
+        let result = sidecar
+            .run(RunScriptArgs {
+                code: "math.add(1, 2)".into(),
+                expr: true,
+                wasm_modules: vec![WasmModule {
+                    name: "math".into(),
+                    bytes: Bytes::from_static(b"\0asm\x01\0\0\0"),
+                }],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.response.return_value, Some(json!(3)));
+
+        let runs = worker.runs();
+        assert_eq!(runs[0].code, "math.add(1, 2)");
+        assert_eq!(
+            runs[0].args["wasmModules"],
+            json!([{ "name": "math", "length": 8 }])
+        );
+    }
+
+    #[tokio::test]
+    async fn damaged_frames() {
+        let worker = MockWorker::new();
+        let (mut read, mut write) = worker.connect().await.unwrap();
+
+        let mut ping = HostToWorkerMessage::new(5, 0, HostToWorkerMessageData::Ping)
+            .encode(true)
+            .unwrap();
+        ping[19] ^= 0x01;
+        write.write_all(&ping).await.unwrap();
+
+        let mut response = vec![0; 4096];
+        let length = read.read(&mut response).await.unwrap();
+        let Ok(DecodedFrame::Message { message, .. }) = decode_frame(&response[..length], 4096)
+        else {
+            panic!("not a message");
+        };
+        assert_eq!(message.request_id, 5);
+        assert!(
+            matches!(&message.data, WorkerToHostMessageData::Error(e)
+                if e.kind == ErrorKind::InternalWorkerError && e.message.contains("Checksum")),
+            "{message:?}"
+        );
+    }
+}
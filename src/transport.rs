@@ -0,0 +1,115 @@
+use std::{io, path::PathBuf, sync::Arc};
+
+use bytes::Bytes;
+use futures::{
+    future::{self, BoxFuture},
+    SinkExt, StreamExt, TryStreamExt,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, BufWriter},
+    net::UnixStream,
+};
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http::HeaderValue, Message};
+use tokio_util::io::{CopyToBytes, SinkWriter, StreamReader};
+
+use crate::Error;
+
+/// The half of a [Transport] connection that the worker's messages are read from.
+pub type ReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+/// The half of a [Transport] connection that messages to the worker are written to.
+pub type WriteHalf = Box<dyn AsyncWrite + Send + Sync + Unpin>;
+
+/// A way to open connections to workers, set with
+/// [JsSidecarBuilder::transport](crate::JsSidecarBuilder::transport), for workers that aren't
+/// reachable through a Unix socket or a WebSocket, such as over vsock to a virtual machine,
+/// through TLS-wrapped TCP, or over an in-memory pipe in tests.
+///
+/// Each connection carries the same bytes that would go over the Unix socket, so the other end
+/// only has to pass them on to a worker's socket or speak the worker protocol itself. Writes are
+/// flushed at the end of each message, so a buffered writer doesn't hold messages back.
+pub trait Transport: Send + Sync + 'static {
+    /// Open a new connection to a worker.
+    fn connect(&self) -> BoxFuture<'_, io::Result<(ReadHalf, WriteHalf)>>;
+}
+
+impl std::fmt::Debug for dyn Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Transport")
+    }
+}
+
+/// How much of a frame is gathered into one WebSocket message.
+const WEBSOCKET_WRITE_BUFFER: usize = 64 * 1024;
+
+/// Where connections to the workers are opened.
+#[derive(Debug, Clone)]
+pub(crate) enum WorkerAddress {
+    /// The Unix socket of a Node.js process started by the sidecar.
+    Socket(PathBuf),
+    /// A WebSocket URL, for workers started with a WebSocket listener or reached through a proxy,
+    /// and the token to send to it.
+    WebSocket { url: String, token: Option<String> },
+    /// A transport supplied by the application.
+    Custom(Arc<dyn Transport>),
+}
+
+impl WorkerAddress {
+    /// Open a new connection to a worker.
+    pub async fn connect(&self) -> Result<(ReadHalf, WriteHalf), Error> {
+        match self {
+            WorkerAddress::Socket(path) => {
+                let stream = UnixStream::connect(path)
+                    .await
+                    .map_err(Error::ConnectWorker)?;
+                let (read, write) = stream.into_split();
+                Ok((Box::new(read), Box::new(write)))
+            }
+            WorkerAddress::WebSocket { url, token } => connect_websocket(url, token.as_deref())
+                .await
+                .map_err(Error::ConnectWorker),
+            WorkerAddress::Custom(transport) => {
+                transport.connect().await.map_err(Error::ConnectWorker)
+            }
+        }
+    }
+}
+
+/// Connect to a WebSocket and present it as a byte stream.
+///
+/// The protocol messages already carry their own lengths, so the WebSocket just carries the same
+/// bytes that would go over the Unix socket. Each message is written as one binary WebSocket
+/// message, but incoming data is not required to line up with WebSocket message boundaries.
+///
+/// Frames are written in several pieces, which the sink would send as separate WebSocket messages,
+/// so writes are buffered until the frame is flushed. Frames larger than the buffer still go out
+/// in pieces.
+async fn connect_websocket(url: &str, token: Option<&str>) -> io::Result<(ReadHalf, WriteHalf)> {
+    let mut request = url.into_client_request().map_err(io::Error::other)?;
+    if let Some(token) = token {
+        let value = HeaderValue::from_str(&format!("Bearer {token}")).map_err(io::Error::other)?;
+        request.headers_mut().insert("Authorization", value);
+    }
+    let (stream, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .map_err(io::Error::other)?;
+    let (sink, stream) = stream.split();
+
+    let sink = sink
+        .sink_map_err(io::Error::other)
+        .with(|bytes: Bytes| future::ready(Ok::<_, io::Error>(Message::binary(bytes))));
+    let writer = BufWriter::with_capacity(
+        WEBSOCKET_WRITE_BUFFER,
+        SinkWriter::new(CopyToBytes::new(sink)),
+    );
+
+    // tungstenite answers pings and closes on its own, so only the data messages are passed on.
+    let reader = StreamReader::new(stream.map_err(io::Error::other).try_filter_map(|message| {
+        future::ready(Ok(match message {
+            Message::Binary(data) => Some(Bytes::from(data)),
+            Message::Text(data) => Some(Bytes::from(data)),
+            _ => None,
+        }))
+    }));
+
+    Ok((Box::new(reader), Box::new(writer)))
+}
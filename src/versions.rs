@@ -0,0 +1,54 @@
+//! The version of the wire protocol spoken between the host and the worker.
+//!
+//! The host sends [PROTOCOL_VERSION] in the handshake when it connects, and the worker closes
+//! the connection if its own version is different. The host and the worker must therefore come
+//! from the same version of the crate. There are no versioned payload types and no conversion
+//! between older and newer payload formats, so a fleet has to upgrade its hosts and workers
+//! together rather than rolling the workers out gradually.
+//!
+//! The tests parse fixtures of the current payloads, so that a change to a payload format is
+//! noticed and comes with a new [PROTOCOL_VERSION].
+
+/// The version of the wire protocol: the message framing, the set of message types, and the
+/// formats of their payloads. This changes whenever any of them do.
+pub const PROTOCOL_VERSION: u32 = 20;
+
+#[cfg(test)]
+mod tests {
+    use crate::{wire::WorkerToHostMessageData, LogLevel};
+
+    fn parse(message_type: u32, fixture: &str) -> WorkerToHostMessageData {
+        WorkerToHostMessageData::parse_data(message_type, 3, fixture.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn log() {
+        let WorkerToHostMessageData::Log(log) =
+            parse(0x1001, include_str!("fixtures/messages/log.json"))
+        else {
+            panic!("Expected log");
+        };
+
+        assert_eq!(log.level, LogLevel::Debug);
+        assert_eq!(log.request_id, 7);
+        assert_eq!(log.timestamp, 1718000000000);
+        assert_eq!(log.namespace.as_deref(), Some("db"));
+    }
+
+    #[test]
+    fn error() {
+        let WorkerToHostMessageData::Error(error) =
+            parse(0x1002, include_str!("fixtures/messages/error.json"))
+        else {
+            panic!("Expected error");
+        };
+        assert_eq!(error.message, "x is not defined");
+        assert_eq!(error.annotations["feature"], "search");
+    }
+
+    #[test]
+    fn invalid_log() {
+        let err = WorkerToHostMessageData::parse_data(0x1001, 0, b"{\"message\": 1}").unwrap_err();
+        assert!(err.to_string().contains("JSON"), "{err}");
+    }
+}
@@ -0,0 +1,2173 @@
+//! The wire protocol between the host and the workers, for implementing a compatible worker in
+//! another runtime, or a proxy that sits between the two. [Connection](crate::Connection) and
+//! [JsSidecar](crate::JsSidecar) take care of all of this for normal use.
+//!
+//! # Frames
+//!
+//! Each message, in either direction, is one frame. Every field of the header is a little-endian
+//! `u32`:
+//!
+//! | Offset | Field |
+//! |--------|-------|
+//! | 0 | The length of the rest of the frame, after this field |
+//! | 4 | [FRAME_MAGIC], so that a reader that loses its place can find the next frame |
+//! | 8 | The request ID |
+//! | 12 | The message ID |
+//! | 16 | The message type, from [to_worker] or [to_host], with the flags below |
+//! | 20 | The payload |
+//!
+//! Frames with [CHECKSUM_FLAG] set in the message type end with the [crc32] of everything
+//! before the checksum, including the length. [COMPRESSED_FLAG] means that the payload is
+//! gzipped, and [SHARED_MEMORY_FLAG] that the payload is the name of a file in the shared memory
+//! directory from the handshake, which holds the real payload. Either side may set these flags
+//! only after the handshake turned them on.
+//!
+//! # Payloads
+//!
+//! Most payloads are JSON, in the format of the type in the matching variant of
+//! [HostToWorkerMessageData] or [WorkerToHostMessageData]. Pings, cancellations, and other
+//! messages without data have an empty payload, and input, output, heap snapshot, and CPU
+//! profile payloads are the raw bytes. [to_worker::RUN_SCRIPT_BINARY] payloads start with the
+//! length of the JSON as a `u32`, followed by the JSON and then the binary data that it refers
+//! to.
+//!
+//! A run response too large for one frame is sent as [to_host::RUN_RESPONSE_CHUNK] frames
+//! followed by a [to_host::RUN_RESPONSE] frame with the rest, and the payloads are joined.
+//!
+//! # Requests and sequences
+//!
+//! The host picks the request ID of each request, and every message that the worker sends about
+//! it has the same request ID. Messages that aren't about a request, such as memory reports, use
+//! request ID 0. The worker numbers the messages of each request from 0, so that the host can
+//! tell when frames were lost, and a response, error, pong, or handshake response ends the
+//! sequence. The next message for the same request starts again from 0. Memory and load reports
+//! always have message ID 0 and are left out of the sequences.
+//!
+//! # Handshake
+//!
+//! The host's first message on a connection is a [to_worker::HANDSHAKE] with its
+//! [PROTOCOL_VERSION], sent without a checksum or compression. The worker answers with a
+//! [to_host::HANDSHAKE_RESPONSE] that says whether it accepted, and closes the connection if
+//! its own protocol version is different. See [crate::versions].
+//!
+//! ```
+//! use js_sidecar::wire::{
+//!     decode_frame, to_worker, DecodedFrame, HostToWorkerMessage, HostToWorkerMessageData,
+//!     WorkerToHostMessage, WorkerToHostMessageData,
+//! };
+//! use js_sidecar::PongData;
+//!
+//! let ping = HostToWorkerMessage::new(1, 0, HostToWorkerMessageData::Ping)
+//!     .encode(false)
+//!     .unwrap();
+//! assert_eq!(ping[16..20], to_worker::PING.to_le_bytes());
+//!
+//! // A worker's answer
+//! let pong = WorkerToHostMessage::new(1, 0, WorkerToHostMessageData::Pong(PongData { pid: Some(42) }))
+//!     .encode(true)
+//!     .unwrap();
+//! let Ok(DecodedFrame::Message { message, .. }) = decode_frame(&pong, 1024) else {
+//!     panic!("not a message");
+//! };
+//! assert!(matches!(message.data, WorkerToHostMessageData::Pong(pong) if pong.pid == Some(42)));
+//! ```
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::{IoSlice, Read},
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use self::to_host::*;
+use crate::{
+    kv::KvRequestData,
+    messages::{
+        AdvanceTimeData, AsyncErrorData, CheckScriptData, CompleteData, CpuProfileData,
+        DebuggerWaitingData, ErrorResponseData, HandshakeData, HandshakeResponseData,
+        IntrospectData, JobRequestData, LogResponseData, MemoryUsageData, MessageTooLargeData,
+        PongData, ProtocolCorruptionData, ProtocolSequenceData, RunResponseData, RunScriptArgs,
+        WasmModule, WorkerLoadData,
+    },
+    prewarm::PrewarmContext,
+    replay::{Recorder, REDACTED_SECRET},
+    resolver::ResolveModuleRequest,
+    shared_memory::SharedMemory,
+    Error,
+};
+pub use crate::{
+    kv::KvResponseData, resolver::ResolveModuleResponseData, versions::PROTOCOL_VERSION,
+};
+
+/// Follows the length at the start of every frame, so that a reader that loses its place can scan
+/// for the start of the next frame.
+pub const FRAME_MAGIC: u32 = 0x4653_4a53;
+
+/// Set in the message type of frames that end with a CRC32 checksum.
+pub const CHECKSUM_FLAG: u32 = 0x8000_0000;
+/// Set in the message type of frames whose payload is gzip-compressed. The checksum, if any,
+/// covers the compressed payload.
+pub const COMPRESSED_FLAG: u32 = 0x4000_0000;
+/// Set in the message type of frames whose payload is the name of a file in the shared memory
+/// directory, which holds the real payload.
+pub const SHARED_MEMORY_FLAG: u32 = 0x2000_0000;
+
+/// The length of the frame header, not counting the length itself: the magic marker, request ID,
+/// message ID, and message type.
+pub const FRAME_HEADER_LENGTH: u32 = 16;
+
+/// The largest payload that an uncompressed frame can carry. A worker stops decompressing a
+/// compressed payload once it passes this, since the host couldn't have sent it uncompressed.
+pub const MAX_PAYLOAD_BYTES: usize = (u32::MAX - FRAME_HEADER_LENGTH) as usize;
+
+/// The types of messages from the host to a worker.
+pub mod to_worker {
+    /// Run a script, with a [RunScriptMessage](super::RunScriptMessage).
+    pub const RUN_SCRIPT: u32 = 0;
+    /// Check that the worker is alive. The worker answers with a pong.
+    pub const PING: u32 = 1;
+    /// Take a heap snapshot, which the worker sends back in chunks.
+    pub const HEAP_SNAPSHOT: u32 = 2;
+    /// The first message on a connection, with a [HandshakeData](crate::HandshakeData).
+    pub const HANDSHAKE: u32 = 3;
+    /// The answer to a worker's KV request.
+    pub const KV_RESPONSE: u32 = 4;
+    /// Move a run's fake clock forward.
+    pub const ADVANCE_TIME: u32 = 5;
+    /// A run with WebAssembly modules, whose bytes follow the JSON.
+    pub const RUN_SCRIPT_BINARY: u32 = 6;
+    /// The answer to a worker's request for a module's code.
+    pub const RESOLVE_MODULE_RESPONSE: u32 = 7;
+    /// Abort the signal of the run with the message's request ID.
+    pub const CANCEL: u32 = 8;
+    /// A chunk of bytes for a run's `input` global.
+    pub const INPUT: u32 = 9;
+    /// The end of a run's `input`.
+    pub const INPUT_END: u32 = 10;
+    /// A value for a run's `channel` global.
+    pub const CHANNEL_MESSAGE: u32 = 11;
+    /// Close the host's side of a run's channel.
+    pub const CHANNEL_CLOSE: u32 = 12;
+    /// Ask for completions of a line of code.
+    pub const COMPLETE: u32 = 13;
+    /// List the properties of a value in the context.
+    pub const INTROSPECT: u32 = 14;
+    /// Compile code without running it.
+    pub const CHECK_SCRIPT: u32 = 15;
+    /// Get the status of a background job.
+    pub const JOB_STATUS: u32 = 16;
+    /// Abort the signal of a background job.
+    pub const JOB_CANCEL: u32 = 17;
+    /// Build a context from a prewarm manifest.
+    pub const PREWARM: u32 = 18;
+}
+
+/// The types of messages from a worker to the host.
+pub mod to_host {
+    /// The result of a run or another request, which ends the request.
+    pub const RUN_RESPONSE: u32 = 0x1000;
+    /// A console message.
+    pub const LOG: u32 = 0x1001;
+    /// An error that ends a request.
+    pub const ERROR: u32 = 0x1002;
+    /// The answer to a ping.
+    pub const PONG: u32 = 0x1003;
+    /// A report of the worker's memory use, sent to every connection.
+    pub const MEMORY_USAGE: u32 = 0x1004;
+    /// A run is waiting for a debugger to attach.
+    pub const DEBUGGER_WAITING: u32 = 0x1005;
+    /// A run's CPU profile, as JSON text.
+    pub const CPU_PROFILE: u32 = 0x1006;
+    /// A piece of a heap snapshot. A run response ends the snapshot.
+    pub const HEAP_SNAPSHOT_CHUNK: u32 = 0x1007;
+    /// The answer to the handshake.
+    pub const HANDSHAKE_RESPONSE: u32 = 0x1008;
+    /// Part of a run response that is too large for one frame. The chunks are followed by a
+    /// normal run response frame with the last part of the data.
+    pub const RUN_RESPONSE_CHUNK: u32 = 0x1009;
+    /// A message that the worker didn't send because it was over the limit from the handshake.
+    pub const MESSAGE_TOO_LARGE: u32 = 0x100a;
+    /// A script called the `kv` global.
+    pub const KV_REQUEST: u32 = 0x100b;
+    /// A script imported a module that the worker asks the host for.
+    pub const RESOLVE_MODULE: u32 = 0x100c;
+    /// A report of how busy the worker is, sent to every connection.
+    pub const WORKER_LOAD: u32 = 0x100d;
+    /// An error from a context's code that wasn't part of a run's result.
+    pub const ASYNC_ERROR: u32 = 0x100e;
+    /// Bytes that a run wrote to its `write` global.
+    pub const OUTPUT: u32 = 0x100f;
+    /// A value that a run passed to `channel.send`.
+    pub const CHANNEL_MESSAGE: u32 = 0x1010;
+}
+
+/// How messages are framed when they are written.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FrameOptions {
+    /// End each frame with a CRC32 checksum.
+    pub checksum: bool,
+    /// Compress payloads larger than this many bytes.
+    pub compress_over: Option<usize>,
+    /// Pass payloads larger than the directory's threshold through shared memory. These are
+    /// never compressed.
+    pub shared_memory: Option<Arc<SharedMemory>>,
+    /// Records each message's payload while the connection is recording.
+    pub recorder: Option<Arc<Recorder>>,
+}
+
+/// Compress `payload` if it is over the threshold, returning the message type with the
+/// compression flag set if it was. Payloads that don't shrink are sent as they are.
+fn compress_payload(
+    message_type: u32,
+    payload: &[u8],
+    compress_over: Option<usize>,
+) -> (u32, Cow<'_, [u8]>) {
+    if compress_over.is_none_or(|threshold| payload.len() <= threshold) {
+        return (message_type, Cow::Borrowed(payload));
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    let compressed =
+        std::io::Write::write_all(&mut encoder, payload).and_then(|_| encoder.finish());
+    match compressed {
+        Ok(compressed) if compressed.len() < payload.len() => {
+            (message_type | COMPRESSED_FLAG, Cow::Owned(compressed))
+        }
+        _ => (message_type, Cow::Borrowed(payload)),
+    }
+}
+
+/// Decompress a payload, stopping once it is larger than `limit` bytes, so that a small frame
+/// can't make the reader inflate an unbounded amount of data. Returns the decompressed payload,
+/// or the length that was reached, one byte over the limit, if it was too large.
+fn decompress_payload(payload: &[u8], limit: usize) -> std::io::Result<Result<Vec<u8>, u64>> {
+    let mut data = Vec::new();
+    GzDecoder::new(payload)
+        .take((limit as u64).saturating_add(1))
+        .read_to_end(&mut data)?;
+    if data.len() <= limit {
+        Ok(Ok(data))
+    } else {
+        Ok(Err(data.len() as u64))
+    }
+}
+
+/// The header that starts a frame, and the checksum that ends it if `checksum` is set. The
+/// length covers everything after the length field, and the checksum covers everything before
+/// the checksum.
+fn frame_header(
+    request_id: u32,
+    message_id: u32,
+    message_type: u32,
+    payload: &[u8],
+    checksum: bool,
+) -> ([u8; 20], Option<[u8; 4]>) {
+    let checksum_length = if checksum { 4 } else { 0 };
+    let length = FRAME_HEADER_LENGTH as usize + payload.len() + checksum_length;
+    let message_type = if checksum {
+        message_type | CHECKSUM_FLAG
+    } else {
+        message_type
+    };
+
+    let mut header = [0u8; 20];
+    header[0..4].copy_from_slice(&(length as u32).to_le_bytes());
+    header[4..8].copy_from_slice(&FRAME_MAGIC.to_le_bytes());
+    header[8..12].copy_from_slice(&request_id.to_le_bytes());
+    header[12..16].copy_from_slice(&message_id.to_le_bytes());
+    header[16..20].copy_from_slice(&message_type.to_le_bytes());
+
+    let crc = checksum.then(|| (!crc32_update(crc32_update(!0, &header), payload)).to_le_bytes());
+    (header, crc)
+}
+
+/// Encode a whole frame, ending it with a checksum if `checksum` is set. The payload is used as it
+/// is, without compression.
+pub fn encode_frame(
+    request_id: u32,
+    message_id: u32,
+    message_type: u32,
+    payload: &[u8],
+    checksum: bool,
+) -> Vec<u8> {
+    let (header, crc) = frame_header(request_id, message_id, message_type, payload, checksum);
+    let mut data = header.to_vec();
+    data.extend_from_slice(payload);
+    data.extend(crc.into_iter().flatten());
+    data
+}
+
+/// Write all of `bufs`, in a single system call when the stream supports vectored writes.
+async fn write_all_vectored(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut bufs: &mut [IoSlice<'_>],
+) -> std::io::Result<()> {
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        let written = stream.write_vectored(bufs).await?;
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut bufs, written);
+    }
+    Ok(())
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// A payload that carries binary data along with its JSON: the length of the JSON as a u32, the
+/// JSON, and then each piece of binary data in order. The JSON gives the length of each piece.
+fn binary_payload<'a>(json: &[u8], binary: impl Iterator<Item = &'a [u8]> + Clone) -> Vec<u8> {
+    let binary_length: usize = binary.clone().map(|b| b.len()).sum();
+    let mut data = Vec::with_capacity(4 + json.len() + binary_length);
+    data.extend_from_slice(&(json.len() as u32).to_le_bytes());
+    data.extend_from_slice(json);
+    for b in binary {
+        data.extend_from_slice(b);
+    }
+    data
+}
+
+/// Split a payload made by [binary_payload] into its JSON and the binary data after it.
+pub(crate) fn split_binary_payload(payload: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let json_length = payload
+        .get(..4)
+        .map(|length| u32::from_le_bytes(length.try_into().unwrap()) as usize);
+    json_length
+        .and_then(|length| Some((payload.get(4..4 + length)?, &payload[4 + length..])))
+        .ok_or_else(|| Error::InvalidValue("Binary payload is shorter than its JSON".to_string()))
+}
+
+/// Read a run back from the payload of a [to_worker::RUN_SCRIPT] or
+/// [to_worker::RUN_SCRIPT_BINARY] message, including the bytes of its WebAssembly modules.
+pub(crate) fn parse_run_script(
+    message_type: u32,
+    payload: &[u8],
+) -> Result<RunScriptMessage, Error> {
+    if message_type != to_worker::RUN_SCRIPT_BINARY {
+        return Ok(serde_json::from_slice(payload)?);
+    }
+
+    #[derive(Deserialize)]
+    struct WasmLength {
+        name: Cow<'static, str>,
+        length: usize,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct WasmLengths {
+        #[serde(default)]
+        wasm_modules: Vec<WasmLength>,
+    }
+
+    let (json, mut binary) = split_binary_payload(payload)?;
+    let mut run: RunScriptMessage = serde_json::from_slice(json)?;
+    let lengths: WasmLengths = serde_json::from_slice(json)?;
+    for module in lengths.wasm_modules {
+        let Some(bytes) = binary.get(..module.length) else {
+            return Err(Error::InvalidValue(format!(
+                "Binary payload is missing the bytes of {}",
+                module.name
+            )));
+        };
+        binary = &binary[module.length..];
+        run.args.wasm_modules.push(WasmModule {
+            name: module.name,
+            bytes: Bytes::copy_from_slice(bytes),
+        });
+    }
+    Ok(run)
+}
+
+/// The CRC32 (IEEE) checksum of `data`, the same one used by zlib, which ends frames with
+/// [CHECKSUM_FLAG] set.
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// Continue a CRC32 over more data, so that a checksum can cover data in several buffers. Start
+/// with `!0`, and invert the result to get the checksum.
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &b| {
+        CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// The arguments of a run, along with the modules that it uses from the worker's context without
+/// sending their code again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunScriptMessage {
+    /// The run's arguments, whose fields are at the top level of the JSON
+    #[serde(flatten)]
+    pub args: RunScriptArgs,
+    /// Modules that the run imports from the context without sending their code again
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cached_modules: Vec<CachedModule>,
+    /// Set when the code is a script from the [ScriptRegistry](crate::ScriptRegistry). The code
+    /// is left out if the worker already has it from an earlier run on the connection.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registered_script: Option<RegisteredScriptRef>,
+    /// Run the script as a background job in its own context, which keeps going after the
+    /// connection closes. The worker responds right away with the job's
+    /// [JobStatus](crate::JobStatus).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub detach: bool,
+}
+
+/// A script from the [ScriptRegistry](crate::ScriptRegistry), which the worker keeps for later
+/// runs on the connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisteredScriptRef {
+    /// The script's name and version, for errors
+    pub name: String,
+    /// The SHA-256 hash of the script's code, as hex
+    pub hash: String,
+}
+
+/// A module that the context already has from an earlier run, with the same code.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedModule {
+    /// The module's name
+    pub name: String,
+    /// The SHA-256 hash of the module's code, as hex
+    pub hash: String,
+}
+
+/// The contents of a message from the host to a worker, by message type.
+#[derive(Debug, Clone)]
+pub enum HostToWorkerMessageData {
+    /// Run a script. This is sent as [to_worker::RUN_SCRIPT_BINARY] when the run has
+    /// WebAssembly modules.
+    RunScript(Box<RunScriptMessage>),
+    /// Check that the worker is alive.
+    Ping,
+    /// Take a heap snapshot of the worker.
+    HeapSnapshot,
+    /// Agree on the protocol version and the connection's settings.
+    Handshake(HandshakeData),
+    /// The answer to a [KvRequest](WorkerToHostMessageData::KvRequest).
+    KvResponse(KvResponseData),
+    /// Move the fake clock of the connection's context forward.
+    AdvanceTime(AdvanceTimeData),
+    /// The answer to a [ResolveModule](WorkerToHostMessageData::ResolveModule) request.
+    ResolveModuleResponse(ResolveModuleResponseData),
+    /// Abort the signal of the run with the message's request ID.
+    Cancel,
+    /// A chunk of bytes for the `input` global of the run with the message's request ID.
+    Input(Vec<u8>),
+    /// End the `input` of the run with the message's request ID.
+    InputEnd,
+    /// A value for the `channel` global of the run with the message's request ID.
+    ChannelMessage(serde_json::Value),
+    /// Close the host's side of the channel of the run with the message's request ID.
+    ChannelClose,
+    /// Ask for completions of a line of code from the connection's context.
+    Complete(CompleteData),
+    /// List the properties of a value in the connection's context.
+    Introspect(IntrospectData),
+    /// Compile code without running it.
+    CheckScript(CheckScriptData),
+    /// Get the status of a background job in the worker.
+    JobStatus(JobRequestData),
+    /// Abort the signal of a background job in the worker.
+    JobCancel(JobRequestData),
+    /// Build a context and keep it ready in the worker for runs that ask for it by name.
+    Prewarm(PrewarmContext),
+    /// A message from a [Recording](crate::Recording), sent again as it was recorded.
+    Replayed {
+        /// The message's type
+        message_type: u32,
+        /// The message's payload, before compression
+        payload: Vec<u8>,
+    },
+}
+
+impl HostToWorkerMessageData {
+    /// The message's type, from [to_worker], without any flags.
+    pub fn message_type(&self) -> u32 {
+        match self {
+            HostToWorkerMessageData::RunScript(d) if !d.args.wasm_modules.is_empty() => {
+                to_worker::RUN_SCRIPT_BINARY
+            }
+            HostToWorkerMessageData::RunScript(_) => to_worker::RUN_SCRIPT,
+            HostToWorkerMessageData::Ping => to_worker::PING,
+            HostToWorkerMessageData::HeapSnapshot => to_worker::HEAP_SNAPSHOT,
+            HostToWorkerMessageData::Handshake(_) => to_worker::HANDSHAKE,
+            HostToWorkerMessageData::KvResponse(_) => to_worker::KV_RESPONSE,
+            HostToWorkerMessageData::AdvanceTime(_) => to_worker::ADVANCE_TIME,
+            HostToWorkerMessageData::ResolveModuleResponse(_) => to_worker::RESOLVE_MODULE_RESPONSE,
+            HostToWorkerMessageData::Cancel => to_worker::CANCEL,
+            HostToWorkerMessageData::Input(_) => to_worker::INPUT,
+            HostToWorkerMessageData::InputEnd => to_worker::INPUT_END,
+            HostToWorkerMessageData::ChannelMessage(_) => to_worker::CHANNEL_MESSAGE,
+            HostToWorkerMessageData::ChannelClose => to_worker::CHANNEL_CLOSE,
+            HostToWorkerMessageData::Complete(_) => to_worker::COMPLETE,
+            HostToWorkerMessageData::Introspect(_) => to_worker::INTROSPECT,
+            HostToWorkerMessageData::CheckScript(_) => to_worker::CHECK_SCRIPT,
+            HostToWorkerMessageData::JobStatus(_) => to_worker::JOB_STATUS,
+            HostToWorkerMessageData::JobCancel(_) => to_worker::JOB_CANCEL,
+            HostToWorkerMessageData::Prewarm(_) => to_worker::PREWARM,
+            HostToWorkerMessageData::Replayed { message_type, .. } => *message_type,
+        }
+    }
+
+    /// The message's payload, before compression.
+    pub fn payload(&self) -> Result<Vec<u8>, Error> {
+        Ok(match self {
+            HostToWorkerMessageData::RunScript(d) if !d.args.wasm_modules.is_empty() => {
+                binary_payload(
+                    &serde_json::to_vec(d)?,
+                    d.args.wasm_modules.iter().map(|m| &m.bytes[..]),
+                )
+            }
+            HostToWorkerMessageData::RunScript(d) => serde_json::to_vec(d)?,
+            HostToWorkerMessageData::Ping
+            | HostToWorkerMessageData::HeapSnapshot
+            | HostToWorkerMessageData::Cancel
+            | HostToWorkerMessageData::InputEnd
+            | HostToWorkerMessageData::ChannelClose => Vec::new(),
+            HostToWorkerMessageData::Input(d) => d.clone(),
+            HostToWorkerMessageData::ChannelMessage(d) => serde_json::to_vec(d)?,
+            HostToWorkerMessageData::Handshake(d) => serde_json::to_vec(d)?,
+            HostToWorkerMessageData::KvResponse(d) => serde_json::to_vec(d)?,
+            HostToWorkerMessageData::AdvanceTime(d) => serde_json::to_vec(d)?,
+            HostToWorkerMessageData::Complete(d) => serde_json::to_vec(d)?,
+            HostToWorkerMessageData::Introspect(d) => serde_json::to_vec(d)?,
+            HostToWorkerMessageData::CheckScript(d) => serde_json::to_vec(d)?,
+            HostToWorkerMessageData::JobStatus(d) | HostToWorkerMessageData::JobCancel(d) => {
+                serde_json::to_vec(d)?
+            }
+            HostToWorkerMessageData::ResolveModuleResponse(d) => serde_json::to_vec(d)?,
+            HostToWorkerMessageData::Prewarm(d) => serde_json::to_vec(d)?,
+            HostToWorkerMessageData::Replayed { payload, .. } => payload.clone(),
+        })
+    }
+
+    /// Parse the payload of a message of type `message_type`, without any flags, and after
+    /// decompressing it. Returns [Error::InvalidMessageType] for types that aren't in [to_worker].
+    pub fn parse_data(message_type: u32, buffer: &[u8]) -> Result<Self, Error> {
+        Ok(match message_type {
+            to_worker::RUN_SCRIPT | to_worker::RUN_SCRIPT_BINARY => {
+                HostToWorkerMessageData::RunScript(Box::new(parse_run_script(
+                    message_type,
+                    buffer,
+                )?))
+            }
+            to_worker::PING => HostToWorkerMessageData::Ping,
+            to_worker::HEAP_SNAPSHOT => HostToWorkerMessageData::HeapSnapshot,
+            to_worker::HANDSHAKE => {
+                HostToWorkerMessageData::Handshake(serde_json::from_slice(buffer)?)
+            }
+            to_worker::KV_RESPONSE => {
+                HostToWorkerMessageData::KvResponse(serde_json::from_slice(buffer)?)
+            }
+            to_worker::ADVANCE_TIME => {
+                HostToWorkerMessageData::AdvanceTime(serde_json::from_slice(buffer)?)
+            }
+            to_worker::RESOLVE_MODULE_RESPONSE => {
+                HostToWorkerMessageData::ResolveModuleResponse(serde_json::from_slice(buffer)?)
+            }
+            to_worker::CANCEL => HostToWorkerMessageData::Cancel,
+            to_worker::INPUT => HostToWorkerMessageData::Input(buffer.to_vec()),
+            to_worker::INPUT_END => HostToWorkerMessageData::InputEnd,
+            to_worker::CHANNEL_MESSAGE => {
+                HostToWorkerMessageData::ChannelMessage(serde_json::from_slice(buffer)?)
+            }
+            to_worker::CHANNEL_CLOSE => HostToWorkerMessageData::ChannelClose,
+            to_worker::COMPLETE => {
+                HostToWorkerMessageData::Complete(serde_json::from_slice(buffer)?)
+            }
+            to_worker::INTROSPECT => {
+                HostToWorkerMessageData::Introspect(serde_json::from_slice(buffer)?)
+            }
+            to_worker::CHECK_SCRIPT => {
+                HostToWorkerMessageData::CheckScript(serde_json::from_slice(buffer)?)
+            }
+            to_worker::JOB_STATUS => {
+                HostToWorkerMessageData::JobStatus(serde_json::from_slice(buffer)?)
+            }
+            to_worker::JOB_CANCEL => {
+                HostToWorkerMessageData::JobCancel(serde_json::from_slice(buffer)?)
+            }
+            to_worker::PREWARM => HostToWorkerMessageData::Prewarm(serde_json::from_slice(buffer)?),
+            code => return Err(Error::InvalidMessageType(code)),
+        })
+    }
+
+    /// A copy of a run with the values of its [secrets](RunScriptArgs::secrets) replaced, for
+    /// recording, or `None` if the message has no secrets.
+    fn redacted(&self) -> Option<Self> {
+        let HostToWorkerMessageData::RunScript(d) = self else {
+            return None;
+        };
+        if d.args.secrets.is_empty() {
+            return None;
+        }
+
+        let mut redacted = d.clone();
+        for value in redacted.args.secrets.values_mut() {
+            *value = REDACTED_SECRET.to_string();
+        }
+        Some(HostToWorkerMessageData::RunScript(redacted))
+    }
+
+    /// Write the message as a frame, returning the number of bytes written.
+    pub(crate) async fn to_buffer(
+        &self,
+        request_id: u32,
+        message_id: u32,
+        frame: FrameOptions,
+        mut stream: impl AsyncWrite + Unpin,
+    ) -> Result<u64, Error> {
+        let message_data = self.payload()?;
+
+        if let Some(recorder) = &frame.recorder {
+            match self.redacted() {
+                Some(redacted) => {
+                    recorder.sent(request_id, self.message_type(), &redacted.payload()?)
+                }
+                None => recorder.sent(request_id, self.message_type(), &message_data),
+            }
+        }
+
+        // The handshake is never compressed or moved to shared memory, so that a worker on
+        // another protocol version can still read it and reply with its version.
+        let is_handshake = matches!(self, HostToWorkerMessageData::Handshake(_));
+        let shared_memory = frame
+            .shared_memory
+            .as_ref()
+            .filter(|shm| !is_handshake && message_data.len() > shm.min_bytes);
+        let (message_type, payload) = match shared_memory {
+            Some(shm) => {
+                let name = shm.write(&message_data).await.map_err(Error::WriteStream)?;
+                (
+                    self.message_type() | SHARED_MEMORY_FLAG,
+                    Cow::Owned(name.into_bytes()),
+                )
+            }
+            None => compress_payload(
+                self.message_type(),
+                &message_data,
+                frame.compress_over.filter(|_| !is_handshake),
+            ),
+        };
+        // The header, payload, and checksum are written straight from their own buffers, rather
+        // than being copied into one.
+        let (header, crc) = frame_header(
+            request_id,
+            message_id,
+            message_type,
+            &payload,
+            frame.checksum,
+        );
+        let crc = crc.as_ref().map_or(&[][..], |crc| &crc[..]);
+        let length = (header.len() + payload.len() + crc.len()) as u64;
+        let mut bufs = [
+            IoSlice::new(&header),
+            IoSlice::new(&payload),
+            IoSlice::new(crc),
+        ];
+        write_all_vectored(&mut stream, &mut bufs)
+            .await
+            .map_err(Error::WriteStream)?;
+        // A no-op for sockets, but needed for transports that buffer, such as WebSockets.
+        stream.flush().await.map_err(Error::WriteStream)?;
+        Ok(length)
+    }
+}
+
+/// The contents of a message from a worker, by message type.
+#[derive(Debug, Clone)]
+pub enum WorkerToHostMessageData {
+    /// The result of a run or another request
+    RunResponse(RunResponseData),
+    /// A console message
+    Log(LogResponseData),
+    /// An error that ended a request
+    Error(ErrorResponseData),
+    /// The answer to a ping
+    Pong(PongData),
+    /// The worker's memory use
+    MemoryUsage(MemoryUsageData),
+    /// A run is waiting for a debugger to attach
+    DebuggerWaiting(DebuggerWaitingData),
+    /// A run's CPU profile
+    CpuProfile(CpuProfileData),
+    /// A piece of a heap snapshot
+    HeapSnapshotChunk(Vec<u8>),
+    /// The answer to the handshake
+    Handshake(HandshakeResponseData),
+    /// A message that was over the size limit, and wasn't sent or was skipped
+    MessageTooLarge(MessageTooLargeData),
+    /// A script called the `kv` global
+    KvRequest(KvRequestData),
+    /// A script imported a module that the worker asks the host for
+    ResolveModule(ResolveModuleRequest),
+    /// How busy the worker is
+    WorkerLoad(WorkerLoadData),
+    /// An error from a context's code that wasn't part of a run's result
+    AsyncError(AsyncErrorData),
+    /// Bytes that a run with [stream_output](RunScriptArgs::stream_output) set passed to the
+    /// `write` global. Large writes are split across several messages.
+    Output(Vec<u8>),
+    /// A value that a run passed to `channel.send`. See [Connection::open_channel](crate::Connection::open_channel).
+    ChannelMessage(serde_json::Value),
+    /// Not sent by the worker, but generated by the reader when a frame can't be read. The
+    /// message's request ID is only meaningful if the corruption's
+    /// [request_id](ProtocolCorruptionData::request_id) is set.
+    Corrupted(ProtocolCorruptionData),
+    /// Not sent by the worker, but generated by the reader when a frame's message ID shows that
+    /// frames for its request were lost or repeated. The frame itself is dropped.
+    OutOfSequence(ProtocolSequenceData),
+}
+
+impl WorkerToHostMessageData {
+    /// The message's type, from [to_host]. The messages that the host makes up itself have types
+    /// at the top of the range, which the worker never sends.
+    pub fn message_type(&self) -> u32 {
+        match self {
+            WorkerToHostMessageData::RunResponse(_) => RUN_RESPONSE,
+            WorkerToHostMessageData::Log(_) => LOG,
+            WorkerToHostMessageData::Error(_) => ERROR,
+            WorkerToHostMessageData::Pong(_) => PONG,
+            WorkerToHostMessageData::MemoryUsage(_) => MEMORY_USAGE,
+            WorkerToHostMessageData::DebuggerWaiting(_) => DEBUGGER_WAITING,
+            WorkerToHostMessageData::CpuProfile(_) => CPU_PROFILE,
+            WorkerToHostMessageData::HeapSnapshotChunk(_) => HEAP_SNAPSHOT_CHUNK,
+            WorkerToHostMessageData::Handshake(_) => HANDSHAKE_RESPONSE,
+            WorkerToHostMessageData::MessageTooLarge(_) => MESSAGE_TOO_LARGE,
+            WorkerToHostMessageData::KvRequest(_) => KV_REQUEST,
+            WorkerToHostMessageData::ResolveModule(_) => RESOLVE_MODULE,
+            WorkerToHostMessageData::WorkerLoad(_) => WORKER_LOAD,
+            WorkerToHostMessageData::AsyncError(_) => ASYNC_ERROR,
+            WorkerToHostMessageData::Output(_) => OUTPUT,
+            WorkerToHostMessageData::ChannelMessage(_) => CHANNEL_MESSAGE,
+            WorkerToHostMessageData::Corrupted(_) => u32::MAX,
+            WorkerToHostMessageData::OutOfSequence(_) => u32::MAX - 1,
+        }
+    }
+
+    /// Returns false for messages that no run sends, such as a pong or a worker's memory report,
+    /// even if their request ID matches a run.
+    pub fn is_run_message(&self) -> bool {
+        !matches!(
+            self,
+            WorkerToHostMessageData::Pong(_)
+                | WorkerToHostMessageData::Handshake(_)
+                | WorkerToHostMessageData::MemoryUsage(_)
+                | WorkerToHostMessageData::WorkerLoad(_)
+                | WorkerToHostMessageData::HeapSnapshotChunk(_)
+        )
+    }
+
+    /// The message's payload, before compression. Returns [Error::InvalidMessageType] for
+    /// [Corrupted](Self::Corrupted) and [OutOfSequence](Self::OutOfSequence), which the worker
+    /// never sends.
+    pub fn payload(&self) -> Result<Vec<u8>, Error> {
+        Ok(match self {
+            WorkerToHostMessageData::RunResponse(d) => serde_json::to_vec(d)?,
+            WorkerToHostMessageData::Log(d) => serde_json::to_vec(d)?,
+            WorkerToHostMessageData::Error(d) => serde_json::to_vec(d)?,
+            WorkerToHostMessageData::Pong(d) => serde_json::to_vec(d)?,
+            WorkerToHostMessageData::MemoryUsage(d) => serde_json::to_vec(d)?,
+            WorkerToHostMessageData::DebuggerWaiting(d) => serde_json::to_vec(d)?,
+            WorkerToHostMessageData::CpuProfile(d) => d.profile.clone().into_bytes(),
+            WorkerToHostMessageData::HeapSnapshotChunk(d) | WorkerToHostMessageData::Output(d) => {
+                d.clone()
+            }
+            WorkerToHostMessageData::Handshake(d) => serde_json::to_vec(d)?,
+            WorkerToHostMessageData::MessageTooLarge(d) => serde_json::to_vec(d)?,
+            WorkerToHostMessageData::KvRequest(d) => serde_json::to_vec(d)?,
+            WorkerToHostMessageData::ResolveModule(d) => serde_json::to_vec(d)?,
+            WorkerToHostMessageData::WorkerLoad(d) => serde_json::to_vec(d)?,
+            WorkerToHostMessageData::AsyncError(d) => serde_json::to_vec(d)?,
+            WorkerToHostMessageData::ChannelMessage(d) => serde_json::to_vec(d)?,
+            WorkerToHostMessageData::Corrupted(_) | WorkerToHostMessageData::OutOfSequence(_) => {
+                return Err(Error::InvalidMessageType(self.message_type()))
+            }
+        })
+    }
+
+    /// Parse the payload of a message of type `message_type`, without any flags, and after
+    /// decompressing it. Returns [Error::InvalidMessageType] for types that aren't in [to_host].
+    pub fn parse_data(message_type: u32, request_id: u32, buffer: &[u8]) -> Result<Self, Error> {
+        match message_type {
+            RUN_RESPONSE => Ok(WorkerToHostMessageData::RunResponse(RunResponseData {
+                // The request ID comes from the message header.
+                request_id,
+                ..serde_json::from_slice(buffer)?
+            })),
+            LOG => Ok(WorkerToHostMessageData::Log(serde_json::from_slice(
+                buffer,
+            )?)),
+            ERROR => Ok(WorkerToHostMessageData::Error(ErrorResponseData {
+                request_id,
+                ..serde_json::from_slice(buffer)?
+            })),
+            PONG => Ok(WorkerToHostMessageData::Pong(if buffer.is_empty() {
+                // Older workers send an empty pong
+                PongData::default()
+            } else {
+                serde_json::from_slice(buffer)?
+            })),
+            MEMORY_USAGE => Ok(WorkerToHostMessageData::MemoryUsage(
+                serde_json::from_slice(buffer)?,
+            )),
+            DEBUGGER_WAITING => Ok(WorkerToHostMessageData::DebuggerWaiting(
+                serde_json::from_slice(buffer)?,
+            )),
+            CPU_PROFILE => Ok(WorkerToHostMessageData::CpuProfile(CpuProfileData {
+                request_id,
+                profile: String::from_utf8_lossy(buffer).into_owned(),
+            })),
+            HEAP_SNAPSHOT_CHUNK => Ok(WorkerToHostMessageData::HeapSnapshotChunk(buffer.to_vec())),
+            HANDSHAKE_RESPONSE => Ok(WorkerToHostMessageData::Handshake(serde_json::from_slice(
+                buffer,
+            )?)),
+            MESSAGE_TOO_LARGE => Ok(WorkerToHostMessageData::MessageTooLarge(
+                serde_json::from_slice(buffer)?,
+            )),
+            KV_REQUEST => Ok(WorkerToHostMessageData::KvRequest(serde_json::from_slice(
+                buffer,
+            )?)),
+            RESOLVE_MODULE => Ok(WorkerToHostMessageData::ResolveModule(
+                serde_json::from_slice(buffer)?,
+            )),
+            WORKER_LOAD => Ok(WorkerToHostMessageData::WorkerLoad(serde_json::from_slice(
+                buffer,
+            )?)),
+            ASYNC_ERROR => {
+                let mut data: AsyncErrorData = serde_json::from_slice(buffer)?;
+                data.error.request_id = request_id;
+                Ok(WorkerToHostMessageData::AsyncError(data))
+            }
+            OUTPUT => Ok(WorkerToHostMessageData::Output(buffer.to_vec())),
+            CHANNEL_MESSAGE => Ok(WorkerToHostMessageData::ChannelMessage(
+                serde_json::from_slice(buffer)?,
+            )),
+            code => Err(Error::InvalidMessageType(code)),
+        }
+    }
+}
+
+/// A message from the host to a worker.
+#[derive(Debug, Clone)]
+pub struct HostToWorkerMessage {
+    /// The request that the message starts or belongs to
+    pub request_id: u32,
+    /// The ID that the host gave the message
+    pub message_id: u32,
+    /// The message's type and contents
+    pub data: HostToWorkerMessageData,
+}
+
+impl HostToWorkerMessage {
+    /// Create a message.
+    pub fn new(request_id: u32, message_id: u32, data: HostToWorkerMessageData) -> Self {
+        HostToWorkerMessage {
+            request_id,
+            message_id,
+            data,
+        }
+    }
+
+    /// Encode the message as a frame, ending it with a checksum if `checksum` is set. The payload
+    /// isn't compressed.
+    pub fn encode(&self, checksum: bool) -> Result<Vec<u8>, Error> {
+        Ok(encode_frame(
+            self.request_id,
+            self.message_id,
+            self.data.message_type(),
+            &self.data.payload()?,
+            checksum,
+        ))
+    }
+
+    /// Decode a whole frame, as a worker reads it. The checksum is checked if the frame has one,
+    /// and a compressed payload is decompressed. A damaged frame returns
+    /// [Error::ProtocolCorruption], as does one whose payload was passed through shared memory,
+    /// since that can't be read without the directory, and one whose compressed payload inflates
+    /// to more than `max_frame_bytes`, which is only decompressed up to that point. A worker
+    /// passes [MAX_PAYLOAD_BYTES] to accept every payload that the host could send.
+    pub fn decode(frame: &[u8], max_frame_bytes: usize) -> Result<Self, Error> {
+        if frame.len() < 20 || !is_frame_start(frame) {
+            return Err(missing_magic(frame.len() as u64));
+        }
+        let length = read_u32(frame, 0);
+        if length as usize + 4 != frame.len() {
+            return Err(Error::ProtocolCorruption(ProtocolCorruptionData {
+                request_id: None,
+                reason: format!(
+                    "Frame length {length} doesn't match the {} bytes after it",
+                    frame.len() - 4
+                ),
+                skipped_bytes: frame.len() as u64,
+            }));
+        }
+
+        let header: [u8; 8] = frame[..8].try_into().unwrap();
+        let fields: [u8; 12] = frame[8..20].try_into().unwrap();
+        let raw = RawFrame::parse(&fields);
+        let mut payload = frame[20..].to_vec();
+        verify_checksum(&header, &fields, &mut payload)?;
+        let unpacked = if raw.message_type & SHARED_MEMORY_FLAG != 0 {
+            Some(Err(
+                "Frame uses shared memory, which can't be decoded from a buffer".to_string(),
+            ))
+        } else {
+            decompress_frame(raw.message_type, &payload, max_frame_bytes).map(|unpacked| {
+                match unpacked {
+                    Ok(Err(_)) => Err(format!(
+                        "Frame decompresses to more than {max_frame_bytes} bytes"
+                    )),
+                    unpacked => unpacked,
+                }
+            })
+        };
+        let raw = unpack_frame(raw, unpacked, length, max_frame_bytes, &mut payload)?;
+        let data = HostToWorkerMessageData::parse_data(raw.message_type, &payload)?;
+        Ok(HostToWorkerMessage::new(
+            raw.request_id,
+            raw.message_id,
+            data,
+        ))
+    }
+
+    /// Write the message as a frame, returning the number of bytes written.
+    pub(crate) async fn write_to(
+        &self,
+        frame: FrameOptions,
+        stream: impl AsyncWrite + Unpin,
+    ) -> Result<u64, Error> {
+        self.data
+            .to_buffer(self.request_id, self.message_id, frame, stream)
+            .await
+    }
+}
+
+/// A message from a worker to the host.
+#[derive(Debug, Clone)]
+pub struct WorkerToHostMessage {
+    /// The request that the message belongs to, or 0 for messages that aren't part of a request.
+    pub request_id: u32,
+    /// The ID that the worker gave the message.
+    pub message_id: u32,
+    /// The message's type and contents
+    pub data: WorkerToHostMessageData,
+    /// The number of bytes that brought the message over the connection, including the frame
+    /// headers and every chunk of a response that was sent in chunks. A payload passed through
+    /// shared memory only counts the name of its segment. This is 0 for messages that the host
+    /// makes up itself, such as [Corrupted](WorkerToHostMessageData::Corrupted).
+    pub wire_bytes: u64,
+}
+
+impl WorkerToHostMessage {
+    /// Create a message, as a worker would send it.
+    pub fn new(request_id: u32, message_id: u32, data: WorkerToHostMessageData) -> Self {
+        WorkerToHostMessage {
+            request_id,
+            message_id,
+            data,
+            wire_bytes: 0,
+        }
+    }
+
+    /// Encode the message as a frame, as a worker sends it, ending it with a checksum if
+    /// `checksum` is set. The payload isn't compressed, and run responses aren't split into
+    /// chunks.
+    pub fn encode(&self, checksum: bool) -> Result<Vec<u8>, Error> {
+        Ok(encode_frame(
+            self.request_id,
+            self.message_id,
+            self.data.message_type(),
+            &self.data.payload()?,
+            checksum,
+        ))
+    }
+}
+
+/// Limits on the size of messages from the worker.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FrameLimits {
+    /// The largest frame that will be read. Larger frames are skipped without being buffered.
+    pub max_frame_bytes: usize,
+    /// The largest response that will be reassembled from chunks.
+    pub max_response_bytes: Option<usize>,
+}
+
+impl FrameLimits {
+    /// Returns the limit if a response of `length` bytes is over it.
+    fn response_too_large(&self, length: u64) -> Option<u64> {
+        self.max_response_bytes
+            .map(|limit| limit as u64)
+            .filter(|&limit| length > limit)
+    }
+}
+
+/// A frame whose header and checksum have been checked. Its payload is left in the reader's
+/// buffer, and hasn't been parsed.
+struct RawFrame {
+    request_id: u32,
+    message_id: u32,
+    message_type: u32,
+}
+
+/// Read buffers that grow past this size for a large frame are released before the next frame,
+/// so that one large message doesn't hold on to memory for the life of the connection.
+const MAX_RETAINED_READ_BUFFER: usize = 1024 * 1024;
+
+/// A run response that the worker is sending in chunks.
+#[derive(Default)]
+struct PartialResponse {
+    data: Vec<u8>,
+    /// The total length seen so far. Once this passes the limit, `data` is thrown away and only
+    /// the length is kept, to report in the error.
+    length: u64,
+    /// The size of the chunks' frames.
+    wire_bytes: u64,
+}
+
+/// Reads messages from the worker, recovering from corrupted frames.
+///
+/// When a frame header doesn't start with the magic marker, the reader scans forward byte by byte
+/// until it finds one and resumes from there. Frames with a bad checksum or a payload that can't be
+/// parsed are skipped whole. In each case the read returns [Error::ProtocolCorruption] and the next
+/// read continues with the following frame.
+///
+/// Frames over the size limit are skipped and returned as
+/// [MessageTooLarge](WorkerToHostMessageData::MessageTooLarge) messages, and chunked run responses
+/// are put back together before being returned.
+pub(crate) struct FrameReader<R> {
+    stream: BufReader<R>,
+    limits: FrameLimits,
+    shared_memory: Option<Arc<SharedMemory>>,
+    /// The length and magic marker of the next frame, when resyncing has already read them.
+    next_header: Option<[u8; 8]>,
+    /// The payload of the last frame read. This is reused from frame to frame.
+    buffer: Vec<u8>,
+    /// The size of the last frame read, including its length.
+    frame_bytes: u64,
+    partial_responses: HashMap<u32, PartialResponse>,
+    /// The message ID expected next for each request whose sequence hasn't ended.
+    sequences: HashMap<u32, u32>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(stream: R, limits: FrameLimits, shared_memory: Option<Arc<SharedMemory>>) -> Self {
+        FrameReader {
+            stream: BufReader::new(stream),
+            limits,
+            shared_memory,
+            next_header: None,
+            buffer: Vec::new(),
+            frame_bytes: 0,
+            partial_responses: HashMap::new(),
+            sequences: HashMap::new(),
+        }
+    }
+
+    pub async fn read(&mut self) -> Result<WorkerToHostMessage, Error> {
+        loop {
+            let RawFrame {
+                request_id,
+                message_id,
+                message_type,
+            } = self.read_frame().await?;
+
+            if message_type == RUN_RESPONSE_CHUNK {
+                let partial = self.partial_responses.entry(request_id).or_default();
+                partial.length += self.buffer.len() as u64;
+                partial.wire_bytes += self.frame_bytes;
+                if self.limits.response_too_large(partial.length).is_none() {
+                    partial.data.extend_from_slice(&self.buffer);
+                } else {
+                    partial.data = Vec::new();
+                }
+                continue;
+            }
+
+            let assembled;
+            let mut payload = &self.buffer[..];
+            let mut wire_bytes = self.frame_bytes;
+            if message_type == RUN_RESPONSE {
+                if let Some(mut partial) = self.partial_responses.remove(&request_id) {
+                    partial.length += payload.len() as u64;
+                    wire_bytes += partial.wire_bytes;
+                    if let Some(limit) = self.limits.response_too_large(partial.length) {
+                        return Ok(WorkerToHostMessage {
+                            request_id,
+                            message_id,
+                            data: WorkerToHostMessageData::MessageTooLarge(MessageTooLargeData {
+                                length: partial.length,
+                                limit,
+                            }),
+                            wire_bytes,
+                        });
+                    }
+
+                    partial.data.extend_from_slice(payload);
+                    assembled = partial.data;
+                    payload = &assembled;
+                }
+            }
+
+            let data = parse_payload(request_id, message_type, payload)?;
+
+            return Ok(WorkerToHostMessage {
+                request_id,
+                message_id,
+                data,
+                wire_bytes,
+            });
+        }
+    }
+
+    /// Read the next frame, leaving its payload in `self.buffer`.
+    async fn read_frame(&mut self) -> Result<RawFrame, Error> {
+        let mut header = match self.next_header.take() {
+            Some(header) => header,
+            None => {
+                let mut header = [0u8; 8];
+                self.stream
+                    .read_exact(&mut header)
+                    .await
+                    .map_err(Error::ReadStream)?;
+                header
+            }
+        };
+
+        if !is_frame_start(&header) {
+            let mut skipped_bytes = 0;
+            while !is_frame_start(&header) {
+                header.copy_within(1.., 0);
+                header[7] = self.stream.read_u8().await.map_err(Error::ReadStream)?;
+                skipped_bytes += 1;
+            }
+
+            self.next_header = Some(header);
+            return Err(missing_magic(skipped_bytes));
+        }
+
+        let length = read_u32(&header, 0);
+        self.frame_bytes = length as u64 + 4;
+        if length as usize > self.limits.max_frame_bytes {
+            return self.skip_frame(length).await;
+        }
+
+        let mut fields = [0u8; 12];
+        self.stream
+            .read_exact(&mut fields)
+            .await
+            .map_err(Error::ReadStream)?;
+        let raw = RawFrame::parse(&fields);
+
+        if self.buffer.capacity() > MAX_RETAINED_READ_BUFFER {
+            self.buffer = Vec::new();
+        }
+        self.buffer.clear();
+        // The buffer grows as the payload arrives, rather than being allocated up front, so that
+        // a damaged length doesn't allocate memory for data that never comes.
+        let payload_length = (length - FRAME_HEADER_LENGTH) as u64;
+        let read = (&mut self.stream)
+            .take(payload_length)
+            .read_to_end(&mut self.buffer)
+            .await
+            .map_err(Error::ReadStream)?;
+        if (read as u64) < payload_length {
+            return Err(Error::ReadStream(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        verify_checksum(&header, &fields, &mut self.buffer)?;
+        self.check_sequence(&raw)?;
+
+        let limit = self.limits.max_frame_bytes;
+        let unpacked = if raw.message_type & SHARED_MEMORY_FLAG != 0 {
+            Some(Self::take_shared(self.shared_memory.clone(), &self.buffer, limit).await)
+        } else {
+            decompress_frame(raw.message_type, &self.buffer, limit)
+        };
+        unpack_frame(raw, unpacked, length, limit, &mut self.buffer)
+    }
+
+    /// Read the payload of a frame that was passed through shared memory, given the frame's
+    /// payload with the segment name.
+    ///
+    /// This doesn't borrow the reader, since holding that across the await would need the stream
+    /// to be Sync.
+    async fn take_shared(
+        shared_memory: Option<Arc<SharedMemory>>,
+        name: &[u8],
+        limit: usize,
+    ) -> Result<Result<Vec<u8>, u64>, String> {
+        let Some(shared_memory) = shared_memory else {
+            return Err("Frame uses shared memory, but none was set up".to_string());
+        };
+        let name = String::from_utf8_lossy(name);
+        shared_memory
+            .take(&name, limit)
+            .await
+            .map_err(|e| format!("Failed to read shared memory segment {name}: {e}"))
+    }
+
+    /// Skip over a frame that is too large to read, returning a
+    /// [MessageTooLarge](WorkerToHostMessageData::MessageTooLarge) message in its place.
+    async fn skip_frame(&mut self, length: u32) -> Result<RawFrame, Error> {
+        let mut fields = [0u8; 12];
+        self.stream
+            .read_exact(&mut fields)
+            .await
+            .map_err(Error::ReadStream)?;
+        let raw = RawFrame::parse(&fields);
+
+        let mut rest = (&mut self.stream).take((length - FRAME_HEADER_LENGTH) as u64);
+        tokio::io::copy(&mut rest, &mut tokio::io::sink())
+            .await
+            .map_err(Error::ReadStream)?;
+        self.check_sequence(&raw)?;
+
+        let too_large = MessageTooLargeData {
+            length: length as u64,
+            limit: self.limits.max_frame_bytes as u64,
+        };
+        self.buffer = serde_json::to_vec(&too_large)?;
+        Ok(RawFrame {
+            message_type: MESSAGE_TOO_LARGE,
+            ..raw
+        })
+    }
+
+    /// Check that a frame has the next message ID for its request. The worker numbers the frames
+    /// of each request from 0, and starts again after a frame that ends the request, so a gap or
+    /// a repeat means that frames were lost or duplicated. The sequence continues from the
+    /// frame's ID afterwards, so one lost frame is only reported once.
+    fn check_sequence(&mut self, raw: &RawFrame) -> Result<(), Error> {
+        let message_type =
+            raw.message_type & !(CHECKSUM_FLAG | COMPRESSED_FLAG | SHARED_MEMORY_FLAG);
+        if matches!(message_type, MEMORY_USAGE | WORKER_LOAD) {
+            // Not part of any request.
+            return Ok(());
+        }
+
+        let expected = self.sequences.get(&raw.request_id).copied().unwrap_or(0);
+        if matches!(
+            message_type,
+            RUN_RESPONSE | ERROR | PONG | HANDSHAKE_RESPONSE
+        ) {
+            self.sequences.remove(&raw.request_id);
+        } else {
+            self.sequences
+                .insert(raw.request_id, raw.message_id.wrapping_add(1));
+        }
+
+        if raw.message_id == expected {
+            return Ok(());
+        }
+
+        // A response put together from chunks around the gap would be garbled.
+        self.partial_responses.remove(&raw.request_id);
+        Err(Error::ProtocolSequence(ProtocolSequenceData {
+            request_id: raw.request_id,
+            expected,
+            received: raw.message_id,
+        }))
+    }
+}
+
+impl RawFrame {
+    /// Parse the request ID, message ID, and message type that follow the magic marker.
+    fn parse(fields: &[u8; 12]) -> Self {
+        RawFrame {
+            request_id: read_u32(fields, 0),
+            message_id: read_u32(fields, 4),
+            message_type: read_u32(fields, 8),
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// True if `header` is a frame length followed by the magic marker. Lengths too short to hold
+/// the rest of the header can't start a frame.
+fn is_frame_start(header: &[u8]) -> bool {
+    header.len() >= 8
+        && read_u32(header, 4) == FRAME_MAGIC
+        && read_u32(header, 0) >= FRAME_HEADER_LENGTH
+}
+
+fn missing_magic(skipped_bytes: u64) -> Error {
+    Error::ProtocolCorruption(ProtocolCorruptionData {
+        request_id: None,
+        reason: "Frame did not start with the magic marker".to_string(),
+        skipped_bytes,
+    })
+}
+
+/// If the frame has a checksum, check it and remove it from the end of `payload`. `header` is the
+/// frame's length and magic marker, and `fields` the rest of its header.
+fn verify_checksum(
+    header: &[u8; 8],
+    fields: &[u8; 12],
+    payload: &mut Vec<u8>,
+) -> Result<(), Error> {
+    if read_u32(fields, 8) & CHECKSUM_FLAG == 0 {
+        return Ok(());
+    }
+
+    let corruption = |reason: String| {
+        Error::ProtocolCorruption(ProtocolCorruptionData {
+            request_id: None,
+            reason,
+            skipped_bytes: read_u32(header, 0) as u64 + 4,
+        })
+    };
+
+    if payload.len() < 4 {
+        return Err(corruption(
+            "Frame is too short for its checksum".to_string(),
+        ));
+    }
+    let payload_end = payload.len() - 4;
+    let expected = read_u32(payload, payload_end);
+    let crc = crc32_update(crc32_update(!0, header), fields);
+    let actual = !crc32_update(crc, &payload[..payload_end]);
+    if actual != expected {
+        return Err(corruption(format!(
+            "Checksum mismatch: expected {expected:08x}, got {actual:08x}"
+        )));
+    }
+    payload.truncate(payload_end);
+    Ok(())
+}
+
+/// Decompress the payload of a compressed frame, or return `None` if the frame isn't compressed.
+fn decompress_frame(
+    message_type: u32,
+    payload: &[u8],
+    limit: usize,
+) -> Option<Result<Result<Vec<u8>, u64>, String>> {
+    (message_type & COMPRESSED_FLAG != 0).then(|| {
+        decompress_payload(payload, limit).map_err(|e| format!("Failed to decompress frame: {e}"))
+    })
+}
+
+/// Replace the payload in `buffer` with its unpacked form, if it was compressed or in shared
+/// memory, and clear the flags from the message type. An unpacked payload over `limit` is
+/// replaced with a [MessageTooLarge](WorkerToHostMessageData::MessageTooLarge) message.
+fn unpack_frame(
+    raw: RawFrame,
+    unpacked: Option<Result<Result<Vec<u8>, u64>, String>>,
+    length: u32,
+    limit: usize,
+    buffer: &mut Vec<u8>,
+) -> Result<RawFrame, Error> {
+    let RawFrame {
+        request_id,
+        message_id,
+        message_type,
+    } = raw;
+    match unpacked {
+        Some(Ok(Ok(unpacked))) => *buffer = unpacked,
+        Some(Ok(Err(unpacked_length))) => {
+            let too_large = MessageTooLargeData {
+                length: unpacked_length + FRAME_HEADER_LENGTH as u64,
+                limit: limit as u64,
+            };
+            *buffer = serde_json::to_vec(&too_large)?;
+            return Ok(RawFrame {
+                request_id,
+                message_id,
+                message_type: MESSAGE_TOO_LARGE,
+            });
+        }
+        Some(Err(reason)) => {
+            return Err(Error::ProtocolCorruption(ProtocolCorruptionData {
+                request_id: Some(request_id),
+                reason,
+                skipped_bytes: length as u64 + 4,
+            }));
+        }
+        None => {}
+    }
+
+    Ok(RawFrame {
+        request_id,
+        message_id,
+        message_type: message_type & !(CHECKSUM_FLAG | COMPRESSED_FLAG | SHARED_MEMORY_FLAG),
+    })
+}
+
+/// Parse a payload, reporting a payload that doesn't match its message type as corruption.
+fn parse_payload(
+    request_id: u32,
+    message_type: u32,
+    payload: &[u8],
+) -> Result<WorkerToHostMessageData, Error> {
+    WorkerToHostMessageData::parse_data(message_type, request_id, payload).map_err(|e| {
+        Error::ProtocolCorruption(ProtocolCorruptionData {
+            request_id: Some(request_id),
+            reason: format!("Failed to parse message type {message_type:#x}: {e}"),
+            skipped_bytes: payload.len() as u64,
+        })
+    })
+}
+
+/// The result of [decode_frame].
+#[derive(Debug)]
+pub enum DecodedFrame {
+    /// The data ends before the frame does.
+    Incomplete,
+    /// A whole message, which took up `length` bytes of the data.
+    Message {
+        /// The decoded message
+        message: WorkerToHostMessage,
+        /// The frame's length, including its header and checksum
+        length: usize,
+    },
+    /// Part of a run response that was too large for one frame, which took up `length` bytes of
+    /// the data. The parts are joined, in order, with the payload of the run response frame for
+    /// the same request that follows them. That frame holds only the end of the response, so
+    /// it fails to decode on its own.
+    ResponseChunk {
+        /// The request that the response belongs to
+        request_id: u32,
+        /// This part of the response's payload
+        data: Vec<u8>,
+        /// The frame's length, including its header and checksum
+        length: usize,
+    },
+}
+
+/// Decode the frame at the start of `data`, with the same checks as the reader that connections
+/// use, but without reading from a stream. Any input returns a result rather than panicking, and
+/// the memory used is bounded by the length of `data`, which makes this suitable for fuzzing.
+///
+/// A damaged frame returns [Error::ProtocolCorruption], whose
+/// [skipped_bytes](ProtocolCorruptionData::skipped_bytes) is how far to move ahead in the data to
+/// get past it. Frames longer than `max_frame_bytes` return a
+/// [MessageTooLarge](WorkerToHostMessageData::MessageTooLarge) message. Frames whose payload was
+/// passed through shared memory can't be read without the directory, and return corruption.
+/// Message IDs aren't checked against the request's sequence, since that depends on the frames
+/// before this one.
+pub fn decode_frame(data: &[u8], max_frame_bytes: usize) -> Result<DecodedFrame, Error> {
+    if data.len() < 8 {
+        return Ok(DecodedFrame::Incomplete);
+    }
+    if !is_frame_start(data) {
+        // Skip to the next possible frame start. The last 7 bytes might be the start of a frame
+        // whose header hasn't all arrived yet.
+        let skipped_bytes = (1..data.len() - 7)
+            .find(|&start| is_frame_start(&data[start..]))
+            .unwrap_or(data.len() - 7);
+        return Err(missing_magic(skipped_bytes as u64));
+    }
+
+    let length = read_u32(data, 0);
+    let frame_length = length as usize + 4;
+    let Some(frame) = data.get(..frame_length) else {
+        return Ok(DecodedFrame::Incomplete);
+    };
+    let header: [u8; 8] = frame[..8].try_into().unwrap();
+    let fields: [u8; 12] = frame[8..20].try_into().unwrap();
+    let raw = RawFrame::parse(&fields);
+
+    let (raw, payload) = if length as usize > max_frame_bytes {
+        let too_large = MessageTooLargeData {
+            length: length as u64,
+            limit: max_frame_bytes as u64,
+        };
+        let raw = RawFrame {
+            message_type: MESSAGE_TOO_LARGE,
+            ..raw
+        };
+        (raw, serde_json::to_vec(&too_large)?)
+    } else {
+        let mut payload = frame[20..].to_vec();
+        verify_checksum(&header, &fields, &mut payload)?;
+        let unpacked = if raw.message_type & SHARED_MEMORY_FLAG != 0 {
+            Some(Err(
+                "Frame uses shared memory, which can't be decoded from a buffer".to_string(),
+            ))
+        } else {
+            decompress_frame(raw.message_type, &payload, max_frame_bytes)
+        };
+        let raw = unpack_frame(raw, unpacked, length, max_frame_bytes, &mut payload)?;
+        (raw, payload)
+    };
+
+    if raw.message_type == RUN_RESPONSE_CHUNK {
+        return Ok(DecodedFrame::ResponseChunk {
+            request_id: raw.request_id,
+            data: payload,
+            length: frame_length,
+        });
+    }
+
+    let data = parse_payload(raw.request_id, raw.message_type, &payload).map_err(|e| match e {
+        Error::ProtocolCorruption(corruption) => {
+            Error::ProtocolCorruption(ProtocolCorruptionData {
+                skipped_bytes: frame_length as u64,
+                ..corruption
+            })
+        }
+        e => e,
+    })?;
+    Ok(DecodedFrame::Message {
+        message: WorkerToHostMessage {
+            request_id: raw.request_id,
+            message_id: raw.message_id,
+            data,
+            wire_bytes: frame_length as u64,
+        },
+        length: frame_length,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pong_frame(request_id: u32, checksum: bool) -> Vec<u8> {
+        encode_frame(request_id, 0, 0x1003, b"{\"pid\":5}", checksum)
+    }
+
+    const NO_LIMITS: FrameLimits = FrameLimits {
+        max_frame_bytes: usize::MAX,
+        max_response_bytes: None,
+    };
+
+    async fn read_all(data: Vec<u8>) -> Vec<Result<WorkerToHostMessage, Error>> {
+        read_all_with_limits(data, NO_LIMITS).await
+    }
+
+    async fn read_all_with_limits(
+        data: Vec<u8>,
+        limits: FrameLimits,
+    ) -> Vec<Result<WorkerToHostMessage, Error>> {
+        let mut reader = FrameReader::new(data.as_slice(), limits, None);
+        let mut results = Vec::new();
+        loop {
+            match reader.read().await {
+                Err(Error::ReadStream(_)) => return results,
+                result => results.push(result),
+            }
+        }
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[tokio::test]
+    async fn vectored_write() {
+        let message = HostToWorkerMessage::new(
+            3,
+            4,
+            HostToWorkerMessageData::AdvanceTime(AdvanceTimeData { ms: 10 }),
+        );
+        let mut written = Vec::new();
+        let frame = FrameOptions {
+            checksum: true,
+            ..Default::default()
+        };
+        message.write_to(frame, &mut written).await.unwrap();
+        assert_eq!(written, encode_frame(3, 4, 5, br#"{"ms":10}"#, true));
+    }
+
+    #[test]
+    fn run_script_payload_round_trip() {
+        let run = HostToWorkerMessageData::RunScript(Box::new(RunScriptMessage {
+            args: RunScriptArgs {
+                code: "math.add(1, 2)".into(),
+                wasm_modules: vec![
+                    WasmModule {
+                        name: "math".into(),
+                        bytes: Bytes::from_static(b"\0asm1"),
+                    },
+                    WasmModule {
+                        name: "text".into(),
+                        bytes: Bytes::from_static(b"\0asm22"),
+                    },
+                ],
+                secrets: [("KEY".to_string(), "value".to_string())].into(),
+                ..Default::default()
+            },
+            cached_modules: Vec::new(),
+            registered_script: None,
+            detach: false,
+        }));
+
+        let args = parse_run_script(run.message_type(), &run.payload().unwrap())
+            .unwrap()
+            .args;
+        assert_eq!(args.code, "math.add(1, 2)");
+        assert_eq!(args.wasm_modules.len(), 2);
+        assert_eq!(args.wasm_modules[1].name, "text");
+        assert_eq!(&args.wasm_modules[1].bytes[..], b"\0asm22");
+        assert_eq!(args.secrets["KEY"], "value");
+
+        let redacted = run.redacted().unwrap().payload().unwrap();
+        let args = parse_run_script(run.message_type(), &redacted)
+            .unwrap()
+            .args;
+        assert_eq!(args.secrets["KEY"], REDACTED_SECRET);
+        assert_eq!(&args.wasm_modules[0].bytes[..], b"\0asm1");
+
+        let truncated = &redacted[..redacted.len() - 1];
+        assert!(parse_run_script(run.message_type(), truncated).is_err());
+    }
+
+    #[test]
+    fn decode_host_messages() {
+        let run = HostToWorkerMessage::new(
+            7,
+            2,
+            HostToWorkerMessageData::RunScript(Box::new(RunScriptMessage {
+                args: RunScriptArgs {
+                    code: "math.add(1, 2)".into(),
+                    wasm_modules: vec![WasmModule {
+                        name: "math".into(),
+                        bytes: Bytes::from_static(b"\0asm1"),
+                    }],
+                    ..Default::default()
+                },
+                cached_modules: Vec::new(),
+                registered_script: None,
+                detach: true,
+            })),
+        );
+        let frame = run.encode(true).unwrap();
+        let decoded = HostToWorkerMessage::decode(&frame, usize::MAX).unwrap();
+        assert_eq!((decoded.request_id, decoded.message_id), (7, 2));
+        let HostToWorkerMessageData::RunScript(decoded) = decoded.data else {
+            panic!("not a run");
+        };
+        assert_eq!(decoded.args.code, "math.add(1, 2)");
+        assert_eq!(&decoded.args.wasm_modules[0].bytes[..], b"\0asm1");
+        assert!(decoded.detach);
+
+        let mut damaged = frame.clone();
+        damaged[24] ^= 1;
+        assert!(matches!(
+            HostToWorkerMessage::decode(&damaged, usize::MAX),
+            Err(Error::ProtocolCorruption(_))
+        ));
+        assert!(matches!(
+            HostToWorkerMessage::decode(&frame[..frame.len() - 1], usize::MAX),
+            Err(Error::ProtocolCorruption(_))
+        ));
+
+        let cancel = HostToWorkerMessage::new(7, 3, HostToWorkerMessageData::Cancel);
+        let decoded =
+            HostToWorkerMessage::decode(&cancel.encode(false).unwrap(), usize::MAX).unwrap();
+        assert!(matches!(decoded.data, HostToWorkerMessageData::Cancel));
+
+        let unknown = encode_frame(1, 0, 0x0fff, b"", false);
+        assert!(matches!(
+            HostToWorkerMessage::decode(&unknown, usize::MAX),
+            Err(Error::InvalidMessageType(0x0fff))
+        ));
+
+        // A compressed payload is only decompressed up to the limit.
+        let run = HostToWorkerMessageData::RunScript(Box::new(RunScriptMessage {
+            args: RunScriptArgs {
+                code: "1;".repeat(1000).into(),
+                ..Default::default()
+            },
+            cached_modules: Vec::new(),
+            registered_script: None,
+            detach: false,
+        }));
+        let payload = run.payload().unwrap();
+        let (message_type, compressed) = compress_payload(run.message_type(), &payload, Some(0));
+        assert_ne!(message_type & COMPRESSED_FLAG, 0);
+        let frame = encode_frame(8, 0, message_type, &compressed, false);
+        assert!(HostToWorkerMessage::decode(&frame, payload.len()).is_ok());
+        assert!(matches!(
+            HostToWorkerMessage::decode(&frame, payload.len() - 1),
+            Err(Error::ProtocolCorruption(_))
+        ));
+    }
+
+    #[test]
+    fn encode_worker_messages() {
+        let log = WorkerToHostMessage::new(
+            4,
+            0,
+            WorkerToHostMessageData::Log(LogResponseData {
+                level: crate::LogLevel::Warn,
+                message: serde_json::json!(["careful"]),
+                timestamp: 10,
+                request_id: 4,
+                namespace: None,
+                count: 1,
+            }),
+        );
+        let Ok(DecodedFrame::Message { message, .. }) =
+            decode_frame(&log.encode(true).unwrap(), 1024)
+        else {
+            panic!("not a message");
+        };
+        let WorkerToHostMessageData::Log(decoded) = message.data else {
+            panic!("not a log");
+        };
+        assert_eq!(decoded.level, crate::LogLevel::Warn);
+        assert_eq!(decoded.message, serde_json::json!(["careful"]));
+
+        let output =
+            WorkerToHostMessage::new(4, 1, WorkerToHostMessageData::Output(b"hi".to_vec()));
+        let Ok(DecodedFrame::Message { message, .. }) =
+            decode_frame(&output.encode(false).unwrap(), 1024)
+        else {
+            panic!("not a message");
+        };
+        assert!(matches!(message.data, WorkerToHostMessageData::Output(data) if data == b"hi"));
+
+        let sequence = WorkerToHostMessage::new(
+            4,
+            2,
+            WorkerToHostMessageData::OutOfSequence(ProtocolSequenceData {
+                request_id: 4,
+                expected: 1,
+                received: 2,
+            }),
+        );
+        assert!(matches!(
+            sequence.encode(false),
+            Err(Error::InvalidMessageType(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn release_large_read_buffer() {
+        let mut data = encode_frame(1, 0, 0x1007, &vec![0; MAX_RETAINED_READ_BUFFER * 2], false);
+        data.extend(pong_frame(2, true));
+        data.extend(pong_frame(3, true));
+
+        let mut reader = FrameReader::new(data.as_slice(), NO_LIMITS, None);
+        reader.read().await.unwrap();
+        assert_eq!(reader.read().await.unwrap().request_id, 2);
+        assert!(reader.buffer.capacity() < MAX_RETAINED_READ_BUFFER);
+        let capacity = reader.buffer.capacity();
+        assert_eq!(reader.read().await.unwrap().request_id, 3);
+        assert_eq!(reader.buffer.capacity(), capacity);
+    }
+
+    #[tokio::test]
+    async fn resync_after_garbage() {
+        let mut data = pong_frame(1, false);
+        data.extend_from_slice(b"garbage");
+        data.extend(pong_frame(2, true));
+
+        let results = read_all(data).await;
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().request_id, 1);
+        let Err(Error::ProtocolCorruption(corruption)) = &results[1] else {
+            panic!("expected corruption, got {:?}", results[1]);
+        };
+        assert_eq!(corruption.skipped_bytes, 7);
+        assert_eq!(results[2].as_ref().unwrap().request_id, 2);
+    }
+
+    #[tokio::test]
+    async fn bad_checksum() {
+        let mut damaged = pong_frame(1, true);
+        damaged[22] ^= 0xff;
+        let mut data = damaged;
+        data.extend(pong_frame(2, true));
+
+        let results = read_all(data).await;
+        assert_eq!(results.len(), 2);
+        let Err(Error::ProtocolCorruption(corruption)) = &results[0] else {
+            panic!("expected corruption, got {:?}", results[0]);
+        };
+        assert!(corruption.reason.contains("Checksum"), "{corruption:?}");
+        assert_eq!(corruption.request_id, None);
+        assert_eq!(results[1].as_ref().unwrap().request_id, 2);
+    }
+
+    #[tokio::test]
+    async fn unparseable_payload() {
+        let mut data = encode_frame(1, 0, 0x1003, b"{not json", false);
+        data.extend(encode_frame(1, 0, 0x9999, b"", false));
+        data.extend(pong_frame(2, false));
+
+        let results = read_all(data).await;
+        assert_eq!(results.len(), 3);
+        for result in &results[..2] {
+            let Err(Error::ProtocolCorruption(corruption)) = result else {
+                panic!("expected corruption, got {result:?}");
+            };
+            assert_eq!(corruption.request_id, Some(1));
+        }
+        assert_eq!(results[2].as_ref().unwrap().request_id, 2);
+    }
+
+    #[tokio::test]
+    async fn skip_oversized_frame() {
+        let mut data = encode_frame(1, 0, RUN_RESPONSE_CHUNK, b"", false);
+        data.extend(encode_frame(1, 1, 0x1001, &[b'x'; 1000], false));
+        data.extend(pong_frame(2, false));
+
+        let limits = FrameLimits {
+            max_frame_bytes: 100,
+            max_response_bytes: None,
+        };
+        let results = read_all_with_limits(data, limits).await;
+        assert_eq!(results.len(), 2);
+        let message = results[0].as_ref().unwrap();
+        assert_eq!(message.request_id, 1);
+        assert_eq!(message.message_id, 1);
+        let WorkerToHostMessageData::MessageTooLarge(too_large) = &message.data else {
+            panic!("expected MessageTooLarge, got {message:?}");
+        };
+        assert_eq!(too_large.length, 1016);
+        assert_eq!(too_large.limit, 100);
+        assert_eq!(results[1].as_ref().unwrap().request_id, 2);
+    }
+
+    #[tokio::test]
+    async fn message_sequences() {
+        let log = |request_id, message_id| {
+            encode_frame(
+                request_id,
+                message_id,
+                LOG,
+                br#"{"level":"info","message":["x"],"timestamp":0,"requestId":1}"#,
+                false,
+            )
+        };
+        let frames = [
+            log(1, 0),
+            log(2, 0),
+            // A lost frame
+            log(1, 2),
+            log(1, 3),
+            encode_frame(1, 4, RUN_RESPONSE, b"{}", false),
+            // A new sequence after the run finished
+            log(1, 0),
+            // A repeated frame
+            log(2, 0),
+            log(2, 1),
+        ];
+
+        let results = read_all(frames.concat()).await;
+        let summary = results
+            .iter()
+            .map(|result| match result {
+                Ok(message) => Ok((message.request_id, message.message_id)),
+                Err(Error::ProtocolSequence(e)) => Err((e.request_id, e.expected, e.received)),
+                Err(e) => panic!("unexpected error {e:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                Ok((1, 0)),
+                Ok((2, 0)),
+                Err((1, 1, 2)),
+                Ok((1, 3)),
+                Ok((1, 4)),
+                Ok((1, 0)),
+                Err((2, 1, 0)),
+                Ok((2, 1)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn compressed_frames() {
+        let response = format!(r#"{{"returnValue":"{}"}}"#, "a".repeat(1000));
+        let (message_type, payload) =
+            compress_payload(RUN_RESPONSE, response.as_bytes(), Some(100));
+        assert_eq!(message_type, RUN_RESPONSE | COMPRESSED_FLAG);
+        assert!(payload.len() < response.len());
+
+        // Small payloads are left alone.
+        let (message_type, small) = compress_payload(0x1003, b"{\"pid\":5}", Some(100));
+        assert_eq!(message_type, 0x1003);
+        assert_eq!(&small[..], b"{\"pid\":5}");
+
+        let mut data = encode_frame(1, 0, message_type | COMPRESSED_FLAG, b"not gzip", true);
+        data.extend(encode_frame(
+            2,
+            0,
+            RUN_RESPONSE | COMPRESSED_FLAG,
+            &payload,
+            true,
+        ));
+        let results = read_all(data).await;
+        assert_eq!(results.len(), 2);
+        let Err(Error::ProtocolCorruption(corruption)) = &results[0] else {
+            panic!("expected corruption, got {:?}", results[0]);
+        };
+        assert_eq!(corruption.request_id, Some(1));
+        let message = results[1].as_ref().unwrap();
+        let WorkerToHostMessageData::RunResponse(run) = &message.data else {
+            panic!("expected RunResponse, got {message:?}");
+        };
+        assert_eq!(run.return_value, Some(serde_json::json!("a".repeat(1000))));
+
+        // The frame limit also applies to the decompressed payload.
+        let limits = FrameLimits {
+            max_frame_bytes: 500,
+            max_response_bytes: None,
+        };
+        let data = encode_frame(2, 0, RUN_RESPONSE | COMPRESSED_FLAG, &payload, false);
+        let results = read_all_with_limits(data, limits).await;
+        let message = results[0].as_ref().unwrap();
+        let WorkerToHostMessageData::MessageTooLarge(too_large) = &message.data else {
+            panic!("expected MessageTooLarge, got {message:?}");
+        };
+        // Decompression stops at the limit, so the length is only known to be over it.
+        assert_eq!(too_large.length, 501 + 16);
+        assert_eq!(too_large.limit, 500);
+    }
+
+    #[tokio::test]
+    async fn reassemble_chunked_response() {
+        let response = br#"{"returnValue":"abcdefghij"}"#;
+        let mut data = Vec::new();
+        for (i, chunk) in response[..20].chunks(8).enumerate() {
+            data.extend(encode_frame(1, i as u32, RUN_RESPONSE_CHUNK, chunk, true));
+        }
+        // Messages for other requests can arrive in between.
+        data.extend(pong_frame(2, true));
+        data.extend(encode_frame(1, 3, RUN_RESPONSE, &response[20..], true));
+
+        let results = read_all(data).await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap().request_id, 2);
+        let message = results[1].as_ref().unwrap();
+        let WorkerToHostMessageData::RunResponse(response) = &message.data else {
+            panic!("expected RunResponse, got {message:?}");
+        };
+        assert_eq!(response.request_id, 1);
+        assert_eq!(response.return_value, Some(serde_json::json!("abcdefghij")));
+
+        let limits = FrameLimits {
+            max_frame_bytes: usize::MAX,
+            max_response_bytes: Some(20),
+        };
+        let mut data = encode_frame(1, 0, RUN_RESPONSE_CHUNK, &[b' '; 30], false);
+        data.extend(encode_frame(1, 1, RUN_RESPONSE, b"{}", false));
+        let results = read_all_with_limits(data, limits).await;
+        assert_eq!(results.len(), 1);
+        let message = results[0].as_ref().unwrap();
+        assert!(
+            matches!(
+                &message.data,
+                WorkerToHostMessageData::MessageTooLarge(MessageTooLargeData {
+                    length: 32,
+                    limit: 20
+                })
+            ),
+            "{message:?}"
+        );
+    }
+
+    /// Frames of each kind that the reader handles, for the property tests to damage.
+    fn sample_frames(rng: &mut fastrand::Rng) -> Vec<u8> {
+        let response = format!(r#"{{"returnValue":"{}"}}"#, "b".repeat(200));
+        let (compressed_type, compressed) =
+            compress_payload(RUN_RESPONSE, response.as_bytes(), Some(10));
+        let frames = [
+            pong_frame(1, rng.bool()),
+            encode_frame(2, 0, RUN_RESPONSE_CHUNK, b"{\"returnValue\"", rng.bool()),
+            encode_frame(6, 0, RUN_RESPONSE, b"{\"returnValue\":5}", rng.bool()),
+            encode_frame(3, 0, compressed_type, &compressed, rng.bool()),
+            encode_frame(
+                0,
+                0,
+                0x1001,
+                br#"{"level":"info","message":["x"],"timestamp":0,"requestId":0}"#,
+                rng.bool(),
+            ),
+        ];
+        frames.concat()
+    }
+
+    /// Decode every frame in `data`, skipping past errors the way a reader resynchronizes.
+    fn decode_all(mut data: &[u8], max_frame_bytes: usize) -> Vec<Result<DecodedFrame, Error>> {
+        let mut results = Vec::new();
+        loop {
+            let result = decode_frame(data, max_frame_bytes);
+            let advance = match &result {
+                Ok(DecodedFrame::Incomplete) => return results,
+                Ok(DecodedFrame::Message { length, .. })
+                | Ok(DecodedFrame::ResponseChunk { length, .. }) => *length,
+                Err(Error::ProtocolCorruption(e)) => e.skipped_bytes as usize,
+                Err(e) => panic!("unexpected error {e:?}"),
+            };
+            assert!(advance > 0 && advance <= data.len(), "{result:?}");
+            data = &data[advance..];
+            results.push(result);
+        }
+    }
+
+    fn request_ids(results: &[Result<DecodedFrame, Error>]) -> Vec<u32> {
+        results
+            .iter()
+            .filter_map(|result| match result {
+                Ok(DecodedFrame::Message { message, .. }) => Some(message.request_id),
+                Ok(DecodedFrame::ResponseChunk { request_id, .. }) => Some(*request_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decode_valid_frames() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        for _ in 0..50 {
+            let data = sample_frames(&mut rng);
+            let results = decode_all(&data, 1 << 20);
+            assert_eq!(request_ids(&results), [1, 2, 6, 3, 0]);
+            assert!(results.iter().all(|result| result.is_ok()));
+
+            // Every truncation ends with a frame that hasn't all arrived.
+            let end = rng.usize(..data.len());
+            let results = decode_all(&data[..end], 1 << 20);
+            assert!(results.iter().all(|result| result.is_ok()), "{results:?}");
+        }
+
+        let Ok(DecodedFrame::Message { message, .. }) = decode_frame(&pong_frame(1, false), 8)
+        else {
+            panic!("expected a message");
+        };
+        assert!(matches!(
+            message.data,
+            WorkerToHostMessageData::MessageTooLarge(MessageTooLargeData {
+                length: 25,
+                limit: 8
+            })
+        ));
+    }
+
+    #[test]
+    fn decode_random_bytes() {
+        let mut rng = fastrand::Rng::with_seed(2);
+        for _ in 0..2000 {
+            let length = rng.usize(..200);
+            let mut data: Vec<u8> = std::iter::repeat_with(|| rng.u8(..)).take(length).collect();
+            // Plant frame headers with random lengths and types, so that the checks past the
+            // magic marker are reached too.
+            if data.len() > 24 && rng.bool() {
+                let start = rng.usize(..data.len() - 24);
+                data[start..start + 4].copy_from_slice(&rng.u32(16..100).to_le_bytes());
+                data[start + 4..start + 8].copy_from_slice(&FRAME_MAGIC.to_le_bytes());
+                let flags = rng.u32(..) & (CHECKSUM_FLAG | COMPRESSED_FLAG | SHARED_MEMORY_FLAG);
+                let message_type = 0x1000 + rng.u32(..10);
+                data[start + 16..start + 20].copy_from_slice(&(message_type | flags).to_le_bytes());
+            }
+            decode_all(&data, 64);
+        }
+    }
+
+    #[test]
+    fn decode_damaged_frames() {
+        let mut rng = fastrand::Rng::with_seed(3);
+        let frame = encode_frame(4, 0, RUN_RESPONSE, br#"{"returnValue":[1,2,3]}"#, true);
+        for _ in 0..500 {
+            let mut data = frame.clone();
+            let i = rng.usize(..data.len());
+            data[i] ^= 1 << rng.u8(..8);
+            data.extend(pong_frame(5, true));
+
+            // A checksum catches any single flipped bit, so the damaged frame never decodes.
+            // The frame after it does, unless the damaged length runs over it.
+            let results = decode_all(&data, 1 << 20);
+            let ids = request_ids(&results);
+            if i >= 4 {
+                assert_eq!(ids, [5], "byte {i}: {results:?}");
+            } else {
+                assert!(ids.iter().all(|&id| id == 5), "byte {i}: {results:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn decode_garbage_between_frames() {
+        let mut rng = fastrand::Rng::with_seed(4);
+        for _ in 0..200 {
+            let mut data = Vec::new();
+            let mut expected = Vec::new();
+            for request_id in 1..5 {
+                let length = rng.usize(..40);
+                data.extend(std::iter::repeat_with(|| rng.u8(..)).take(length));
+                data.extend(pong_frame(request_id, true));
+                expected.push(request_id);
+            }
+            // Garbage can contain something that looks like a frame start, and swallow the frame
+            // after it, but can never produce a frame of its own.
+            let ids = request_ids(&decode_all(&data, 1 << 20));
+            assert!(ids.iter().all(|id| expected.contains(id)), "{ids:?}");
+            assert_eq!(ids.last(), Some(&4));
+        }
+    }
+
+    #[tokio::test]
+    async fn read_random_bytes() {
+        let limits = FrameLimits {
+            max_frame_bytes: 64,
+            max_response_bytes: Some(64),
+        };
+        let mut rng = fastrand::Rng::with_seed(5);
+        for _ in 0..500 {
+            let mut data = sample_frames(&mut rng);
+            for _ in 0..rng.usize(1..4) {
+                let i = rng.usize(..data.len());
+                data[i] = rng.u8(..);
+            }
+            read_all_with_limits(data, limits).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn huge_claimed_length() {
+        // A length just under the limit, followed by a few bytes, doesn't allocate the whole
+        // length before finding that the stream ends.
+        let mut data = encode_frame(1, 0, 0x1003, b"{}", false);
+        data[..4].copy_from_slice(&(u32::MAX - 1).to_le_bytes());
+        let mut reader = FrameReader::new(data.as_slice(), NO_LIMITS, None);
+        assert!(matches!(reader.read().await, Err(Error::ReadStream(_))));
+        assert!(reader.buffer.capacity() < 1 << 20);
+
+        assert!(matches!(
+            decode_frame(&data, usize::MAX),
+            Ok(DecodedFrame::Incomplete)
+        ));
+    }
+}
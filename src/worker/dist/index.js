@@ -1,2 +1,6750 @@
-import F from"node:cluster";import Q from"node:os";import $ from"node:fs";import{parseArgs as Z}from"node:util";import q from"node:net";import{EventEmitter as tt}from"node:events";import*as R from"node:vm";var k=(o=>(o[o.RunScript=0]="RunScript",o[o.Ping=1]="Ping",o))(k||{}),z=(o=>(o[o.RunResponse=4096]="RunResponse",o[o.Log=4097]="Log",o[o.Error=4098]="Error",o[o.Pong=4099]="Pong",o))(z||{});const et=!!process.env.DEBUG_JS_SIDECAR_WORKER;function p(...o){et&&console.log(...o)}const j=12,P=0,K=4,B=8;class it extends tt{socket;buffer;expectedLength;id;cache=new Map;constructor(t){super(),this.socket=t,this.buffer=Buffer.alloc(0),this.expectedLength=null,this.id=0,this.socket.on("data",e=>this.handleData(e))}handleData(t){for(this.buffer=Buffer.concat([this.buffer,t]);this.buffer.length>0;){if(this.expectedLength===null){if(this.buffer.length<4)return;this.expectedLength=this.buffer.readUInt32LE(0),this.buffer=this.buffer.subarray(4)}if(this.buffer.length<this.expectedLength)return;const e=this.buffer.readUInt32LE(P),i=this.buffer.readUInt32LE(K),s=this.buffer.readUInt32LE(B),n=this.buffer.subarray(12,this.expectedLength);this.buffer=this.buffer.subarray(this.expectedLength),this.expectedLength=null;const r={id:i,reqId:e,type:s,data:n};this.emit("message",r)}}sendMessage(t,e,i){p("Sending message",t,e,i),i instanceof Buffer||(i=Buffer.from(i));let s=this.id++;const n=Buffer.allocUnsafe(j+4);return n.writeUInt32LE(i.length+j),n.writeUInt32LE(t,P+4),n.writeUInt32LE(s,K+4),n.writeUInt32LE(e,B+4),this.socket.write(Buffer.concat([n,i])),s}log(t,e,i){let s=JSON.stringify({level:e,message:i});this.sendMessage(t,z.Log,s)}respond(t,e){this.sendMessage(t,z.RunResponse,JSON.stringify(e))}error(t,e){let i={message:e.message,stack:e.stack},s=JSON.stringify(i);this.sendMessage(t,z.Error,s)}}const T=typeof performance=="object"&&performance&&typeof performance.now=="function"?performance:Date,H=new Set,I=typeof process=="object"&&process?process:{},Y=(o,t,e,i)=>{typeof I.emitWarning=="function"?I.emitWarning(o,t,e,i):console.error(`[${e}] ${t}: ${o}`)};let W=globalThis.AbortController,V=globalThis.AbortSignal;if(typeof W>"u"){V=class{onabort;_onabort=[];reason;aborted=!1;addEventListener(i,s){this._onabort.push(s)}},W=class{constructor(){t()}signal=new V;abort(i){if(!this.signal.aborted){this.signal.reason=i,this.signal.aborted=!0;for(const s of this.signal._onabort)s(i);this.signal.onabort?.(i)}}};let o=I.env?.LRU_CACHE_IGNORE_AC_WARNING!=="1";const t=()=>{o&&(o=!1,Y("AbortController is not defined. If using lru-cache in node 14, load an AbortController polyfill from the `node-abort-controller` package. A minimal polyfill is provided for use by LRUCache.fetch(), but it should not be relied upon in other contexts (eg, passing it to other APIs that use AbortController/AbortSignal might have undesirable effects). You may disable this with LRU_CACHE_IGNORE_AC_WARNING=1 in the env.","NO_ABORT_CONTROLLER","ENOTSUP",t))}}const st=o=>!H.has(o),A=o=>o&&o===Math.floor(o)&&o>0&&isFinite(o),X=o=>A(o)?o<=Math.pow(2,8)?Uint8Array:o<=Math.pow(2,16)?Uint16Array:o<=Math.pow(2,32)?Uint32Array:o<=Number.MAX_SAFE_INTEGER?D:null:null;class D extends Array{constructor(t){super(t),this.fill(0)}}class x{heap;length;static#a=!1;static create(t){const e=X(t);if(!e)return[];x.#a=!0;const i=new x(t,e);return x.#a=!1,i}constructor(t,e){if(!x.#a)throw new TypeError("instantiate Stack using Stack.create(n)");this.heap=new e(t),this.length=0}push(t){this.heap[this.length++]=t}pop(){return this.heap[--this.length]}}class M{#a;#f;#g;#w;#R;#z;ttl;ttlResolution;ttlAutopurge;updateAgeOnGet;updateAgeOnHas;allowStale;noDisposeOnSet;noUpdateTTL;maxEntrySize;sizeCalculation;noDeleteOnFetchRejection;noDeleteOnStaleGet;allowStaleOnFetchAbort;allowStaleOnFetchRejection;ignoreFetchAbort;#n;#m;#s;#i;#t;#l;#u;#h;#r;#S;#o;#b;#y;#d;#_;#O;#c;static unsafeExposeInternals(t){return{starts:t.#y,ttls:t.#d,sizes:t.#b,keyMap:t.#s,keyList:t.#i,valList:t.#t,next:t.#l,prev:t.#u,get head(){return t.#h},get tail(){return t.#r},free:t.#S,isBackgroundFetch:e=>t.#e(e),backgroundFetch:(e,i,s,n)=>t.#W(e,i,s,n),moveToTail:e=>t.#C(e),indexes:e=>t.#E(e),rindexes:e=>t.#A(e),isStale:e=>t.#p(e)}}get max(){return this.#a}get maxSize(){return this.#f}get calculatedSize(){return this.#m}get size(){return this.#n}get fetchMethod(){return this.#R}get memoMethod(){return this.#z}get dispose(){return this.#g}get disposeAfter(){return this.#w}constructor(t){const{max:e=0,ttl:i,ttlResolution:s=1,ttlAutopurge:n,updateAgeOnGet:r,updateAgeOnHas:a,allowStale:h,dispose:l,disposeAfter:w,noDisposeOnSet:c,noUpdateTTL:d,maxSize:u=0,maxEntrySize:v=0,sizeCalculation:g,fetchMethod:b,memoMethod:f,noDeleteOnFetchRejection:S,noDeleteOnStaleGet:_,allowStaleOnFetchRejection:m,allowStaleOnFetchAbort:y,ignoreFetchAbort:C}=t;if(e!==0&&!A(e))throw new TypeError("max option must be a nonnegative integer");const E=e?X(e):Array;if(!E)throw new Error("invalid max value: "+e);if(this.#a=e,this.#f=u,this.maxEntrySize=v||this.#f,this.sizeCalculation=g,this.sizeCalculation){if(!this.#f&&!this.maxEntrySize)throw new TypeError("cannot set sizeCalculation without setting maxSize or maxEntrySize");if(typeof this.sizeCalculation!="function")throw new TypeError("sizeCalculation set to non-function")}if(f!==void 0&&typeof f!="function")throw new TypeError("memoMethod must be a function if defined");if(this.#z=f,b!==void 0&&typeof b!="function")throw new TypeError("fetchMethod must be a function if specified");if(this.#R=b,this.#O=!!b,this.#s=new Map,this.#i=new Array(e).fill(void 0),this.#t=new Array(e).fill(void 0),this.#l=new E(e),this.#u=new E(e),this.#h=0,this.#r=0,this.#S=x.create(e),this.#n=0,this.#m=0,typeof l=="function"&&(this.#g=l),typeof w=="function"?(this.#w=w,this.#o=[]):(this.#w=void 0,this.#o=void 0),this.#_=!!this.#g,this.#c=!!this.#w,this.noDisposeOnSet=!!c,this.noUpdateTTL=!!d,this.noDeleteOnFetchRejection=!!S,this.allowStaleOnFetchRejection=!!m,this.allowStaleOnFetchAbort=!!y,this.ignoreFetchAbort=!!C,this.maxEntrySize!==0){if(this.#f!==0&&!A(this.#f))throw new TypeError("maxSize must be a positive integer if specified");if(!A(this.maxEntrySize))throw new TypeError("maxEntrySize must be a positive integer if specified");this.#$()}if(this.allowStale=!!h,this.noDeleteOnStaleGet=!!_,this.updateAgeOnGet=!!r,this.updateAgeOnHas=!!a,this.ttlResolution=A(s)||s===0?s:1,this.ttlAutopurge=!!n,this.ttl=i||0,this.ttl){if(!A(this.ttl))throw new TypeError("ttl must be a positive integer if specified");this.#U()}if(this.#a===0&&this.ttl===0&&this.#f===0)throw new TypeError("At least one of max, maxSize, or ttl is required");if(!this.ttlAutopurge&&!this.#a&&!this.#f){const L="LRU_CACHE_UNBOUNDED";st(L)&&(H.add(L),Y("TTL caching without ttlAutopurge, max, or maxSize can result in unbounded memory consumption.","UnboundedCacheWarning",L,M))}}getRemainingTTL(t){return this.#s.has(t)?1/0:0}#U(){const t=new D(this.#a),e=new D(this.#a);this.#d=t,this.#y=e,this.#I=(n,r,a=T.now())=>{if(e[n]=r!==0?a:0,t[n]=r,r!==0&&this.ttlAutopurge){const h=setTimeout(()=>{this.#p(n)&&this.#v(this.#i[n],"expire")},r+1);h.unref&&h.unref()}},this.#T=n=>{e[n]=t[n]!==0?T.now():0},this.#F=(n,r)=>{if(t[r]){const a=t[r],h=e[r];if(!a||!h)return;n.ttl=a,n.start=h,n.now=i||s();const l=n.now-h;n.remainingTTL=a-l}};let i=0;const s=()=>{const n=T.now();if(this.ttlResolution>0){i=n;const r=setTimeout(()=>i=0,this.ttlResolution);r.unref&&r.unref()}return n};this.getRemainingTTL=n=>{const r=this.#s.get(n);if(r===void 0)return 0;const a=t[r],h=e[r];if(!a||!h)return 1/0;const l=(i||s())-h;return a-l},this.#p=n=>{const r=e[n],a=t[n];return!!a&&!!r&&(i||s())-r>a}}#T=()=>{};#F=()=>{};#I=()=>{};#p=()=>!1;#$(){const t=new D(this.#a);this.#m=0,this.#b=t,this.#x=e=>{this.#m-=t[e],t[e]=0},this.#G=(e,i,s,n)=>{if(this.#e(i))return 0;if(!A(s))if(n){if(typeof n!="function")throw new TypeError("sizeCalculation must be a function");if(s=n(i,e),!A(s))throw new TypeError("sizeCalculation return invalid (expect positive integer)")}else throw new TypeError("invalid size value (must be positive integer). When maxSize or maxEntrySize is used, sizeCalculation or size must be set.");return s},this.#L=(e,i,s)=>{if(t[e]=i,this.#f){const n=this.#f-t[e];for(;this.#m>n;)this.#D(!0)}this.#m+=t[e],s&&(s.entrySize=i,s.totalCalculatedSize=this.#m)}}#x=t=>{};#L=(t,e,i)=>{};#G=(t,e,i,s)=>{if(i||s)throw new TypeError("cannot set size without setting maxSize or maxEntrySize on cache");return 0};*#E({allowStale:t=this.allowStale}={}){if(this.#n)for(let e=this.#r;!(!this.#k(e)||((t||!this.#p(e))&&(yield e),e===this.#h));)e=this.#u[e]}*#A({allowStale:t=this.allowStale}={}){if(this.#n)for(let e=this.#h;!(!this.#k(e)||((t||!this.#p(e))&&(yield e),e===this.#r));)e=this.#l[e]}#k(t){return t!==void 0&&this.#s.get(this.#i[t])===t}*entries(){for(const t of this.#E())this.#t[t]!==void 0&&this.#i[t]!==void 0&&!this.#e(this.#t[t])&&(yield[this.#i[t],this.#t[t]])}*rentries(){for(const t of this.#A())this.#t[t]!==void 0&&this.#i[t]!==void 0&&!this.#e(this.#t[t])&&(yield[this.#i[t],this.#t[t]])}*keys(){for(const t of this.#E()){const e=this.#i[t];e!==void 0&&!this.#e(this.#t[t])&&(yield e)}}*rkeys(){for(const t of this.#A()){const e=this.#i[t];e!==void 0&&!this.#e(this.#t[t])&&(yield e)}}*values(){for(const t of this.#E())this.#t[t]!==void 0&&!this.#e(this.#t[t])&&(yield this.#t[t])}*rvalues(){for(const t of this.#A())this.#t[t]!==void 0&&!this.#e(this.#t[t])&&(yield this.#t[t])}[Symbol.iterator](){return this.entries()}[Symbol.toStringTag]="LRUCache";find(t,e={}){for(const i of this.#E()){const s=this.#t[i],n=this.#e(s)?s.__staleWhileFetching:s;if(n!==void 0&&t(n,this.#i[i],this))return this.get(this.#i[i],e)}}forEach(t,e=this){for(const i of this.#E()){const s=this.#t[i],n=this.#e(s)?s.__staleWhileFetching:s;n!==void 0&&t.call(e,n,this.#i[i],this)}}rforEach(t,e=this){for(const i of this.#A()){const s=this.#t[i],n=this.#e(s)?s.__staleWhileFetching:s;n!==void 0&&t.call(e,n,this.#i[i],this)}}purgeStale(){let t=!1;for(const e of this.#A({allowStale:!0}))this.#p(e)&&(this.#v(this.#i[e],"expire"),t=!0);return t}info(t){const e=this.#s.get(t);if(e===void 0)return;const i=this.#t[e],s=this.#e(i)?i.__staleWhileFetching:i;if(s===void 0)return;const n={value:s};if(this.#d&&this.#y){const r=this.#d[e],a=this.#y[e];if(r&&a){const h=r-(T.now()-a);n.ttl=h,n.start=Date.now()}}return this.#b&&(n.size=this.#b[e]),n}dump(){const t=[];for(const e of this.#E({allowStale:!0})){const i=this.#i[e],s=this.#t[e],n=this.#e(s)?s.__staleWhileFetching:s;if(n===void 0||i===void 0)continue;const r={value:n};if(this.#d&&this.#y){r.ttl=this.#d[e];const a=T.now()-this.#y[e];r.start=Math.floor(Date.now()-a)}this.#b&&(r.size=this.#b[e]),t.unshift([i,r])}return t}load(t){this.clear();for(const[e,i]of t){if(i.start){const s=Date.now()-i.start;i.start=T.now()-s}this.set(e,i.value,i)}}set(t,e,i={}){if(e===void 0)return this.delete(t),this;const{ttl:s=this.ttl,start:n,noDisposeOnSet:r=this.noDisposeOnSet,sizeCalculation:a=this.sizeCalculation,status:h}=i;let{noUpdateTTL:l=this.noUpdateTTL}=i;const w=this.#G(t,e,i.size||0,a);if(this.maxEntrySize&&w>this.maxEntrySize)return h&&(h.set="miss",h.maxEntrySizeExceeded=!0),this.#v(t,"set"),this;let c=this.#n===0?void 0:this.#s.get(t);if(c===void 0)c=this.#n===0?this.#r:this.#S.length!==0?this.#S.pop():this.#n===this.#a?this.#D(!1):this.#n,this.#i[c]=t,this.#t[c]=e,this.#s.set(t,c),this.#l[this.#r]=c,this.#u[c]=this.#r,this.#r=c,this.#n++,this.#L(c,w,h),h&&(h.set="add"),l=!1;else{this.#C(c);const d=this.#t[c];if(e!==d){if(this.#O&&this.#e(d)){d.__abortController.abort(new Error("replaced"));const{__staleWhileFetching:u}=d;u!==void 0&&!r&&(this.#_&&this.#g?.(u,t,"set"),this.#c&&this.#o?.push([u,t,"set"]))}else r||(this.#_&&this.#g?.(d,t,"set"),this.#c&&this.#o?.push([d,t,"set"]));if(this.#x(c),this.#L(c,w,h),this.#t[c]=e,h){h.set="replace";const u=d&&this.#e(d)?d.__staleWhileFetching:d;u!==void 0&&(h.oldValue=u)}}else h&&(h.set="update")}if(s!==0&&!this.#d&&this.#U(),this.#d&&(l||this.#I(c,s,n),h&&this.#F(h,c)),!r&&this.#c&&this.#o){const d=this.#o;let u;for(;u=d?.shift();)this.#w?.(...u)}return this}pop(){try{for(;this.#n;){const t=this.#t[this.#h];if(this.#D(!0),this.#e(t)){if(t.__staleWhileFetching)return t.__staleWhileFetching}else if(t!==void 0)return t}}finally{if(this.#c&&this.#o){const t=this.#o;let e;for(;e=t?.shift();)this.#w?.(...e)}}}#D(t){const e=this.#h,i=this.#i[e],s=this.#t[e];return this.#O&&this.#e(s)?s.__abortController.abort(new Error("evicted")):(this.#_||this.#c)&&(this.#_&&this.#g?.(s,i,"evict"),this.#c&&this.#o?.push([s,i,"evict"])),this.#x(e),t&&(this.#i[e]=void 0,this.#t[e]=void 0,this.#S.push(e)),this.#n===1?(this.#h=this.#r=0,this.#S.length=0):this.#h=this.#l[e],this.#s.delete(i),this.#n--,e}has(t,e={}){const{updateAgeOnHas:i=this.updateAgeOnHas,status:s}=e,n=this.#s.get(t);if(n!==void 0){const r=this.#t[n];if(this.#e(r)&&r.__staleWhileFetching===void 0)return!1;if(this.#p(n))s&&(s.has="stale",this.#F(s,n));else return i&&this.#T(n),s&&(s.has="hit",this.#F(s,n)),!0}else s&&(s.has="miss");return!1}peek(t,e={}){const{allowStale:i=this.allowStale}=e,s=this.#s.get(t);if(s===void 0||!i&&this.#p(s))return;const n=this.#t[s];return this.#e(n)?n.__staleWhileFetching:n}#W(t,e,i,s){const n=e===void 0?void 0:this.#t[e];if(this.#e(n))return n;const r=new W,{signal:a}=i;a?.addEventListener("abort",()=>r.abort(a.reason),{signal:r.signal});const h={signal:r.signal,options:i,context:s},l=(g,b=!1)=>{const{aborted:f}=r.signal,S=i.ignoreFetchAbort&&g!==void 0;if(i.status&&(f&&!b?(i.status.fetchAborted=!0,i.status.fetchError=r.signal.reason,S&&(i.status.fetchAbortIgnored=!0)):i.status.fetchResolved=!0),f&&!S&&!b)return c(r.signal.reason);const _=u;return this.#t[e]===u&&(g===void 0?_.__staleWhileFetching?this.#t[e]=_.__staleWhileFetching:this.#v(t,"fetch"):(i.status&&(i.status.fetchUpdated=!0),this.set(t,g,h.options))),g},w=g=>(i.status&&(i.status.fetchRejected=!0,i.status.fetchError=g),c(g)),c=g=>{const{aborted:b}=r.signal,f=b&&i.allowStaleOnFetchAbort,S=f||i.allowStaleOnFetchRejection,_=S||i.noDeleteOnFetchRejection,m=u;if(this.#t[e]===u&&(!_||m.__staleWhileFetching===void 0?this.#v(t,"fetch"):f||(this.#t[e]=m.__staleWhileFetching)),S)return i.status&&m.__staleWhileFetching!==void 0&&(i.status.returnedStale=!0),m.__staleWhileFetching;if(m.__returned===m)throw g},d=(g,b)=>{const f=this.#R?.(t,n,h);f&&f instanceof Promise&&f.then(S=>g(S===void 0?void 0:S),b),r.signal.addEventListener("abort",()=>{(!i.ignoreFetchAbort||i.allowStaleOnFetchAbort)&&(g(void 0),i.allowStaleOnFetchAbort&&(g=S=>l(S,!0)))})};i.status&&(i.status.fetchDispatched=!0);const u=new Promise(d).then(l,w),v=Object.assign(u,{__abortController:r,__staleWhileFetching:n,__returned:void 0});return e===void 0?(this.set(t,v,{...h.options,status:void 0}),e=this.#s.get(t)):this.#t[e]=v,v}#e(t){if(!this.#O)return!1;const e=t;return!!e&&e instanceof Promise&&e.hasOwnProperty("__staleWhileFetching")&&e.__abortController instanceof W}async fetch(t,e={}){const{allowStale:i=this.allowStale,updateAgeOnGet:s=this.updateAgeOnGet,noDeleteOnStaleGet:n=this.noDeleteOnStaleGet,ttl:r=this.ttl,noDisposeOnSet:a=this.noDisposeOnSet,size:h=0,sizeCalculation:l=this.sizeCalculation,noUpdateTTL:w=this.noUpdateTTL,noDeleteOnFetchRejection:c=this.noDeleteOnFetchRejection,allowStaleOnFetchRejection:d=this.allowStaleOnFetchRejection,ignoreFetchAbort:u=this.ignoreFetchAbort,allowStaleOnFetchAbort:v=this.allowStaleOnFetchAbort,context:g,forceRefresh:b=!1,status:f,signal:S}=e;if(!this.#O)return f&&(f.fetch="get"),this.get(t,{allowStale:i,updateAgeOnGet:s,noDeleteOnStaleGet:n,status:f});const _={allowStale:i,updateAgeOnGet:s,noDeleteOnStaleGet:n,ttl:r,noDisposeOnSet:a,size:h,sizeCalculation:l,noUpdateTTL:w,noDeleteOnFetchRejection:c,allowStaleOnFetchRejection:d,allowStaleOnFetchAbort:v,ignoreFetchAbort:u,status:f,signal:S};let m=this.#s.get(t);if(m===void 0){f&&(f.fetch="miss");const y=this.#W(t,m,_,g);return y.__returned=y}else{const y=this.#t[m];if(this.#e(y)){const N=i&&y.__staleWhileFetching!==void 0;return f&&(f.fetch="inflight",N&&(f.returnedStale=!0)),N?y.__staleWhileFetching:y.__returned=y}const C=this.#p(m);if(!b&&!C)return f&&(f.fetch="hit"),this.#C(m),s&&this.#T(m),f&&this.#F(f,m),y;const E=this.#W(t,m,_,g),U=E.__staleWhileFetching!==void 0&&i;return f&&(f.fetch=C?"stale":"refresh",U&&C&&(f.returnedStale=!0)),U?E.__staleWhileFetching:E.__returned=E}}async forceFetch(t,e={}){const i=await this.fetch(t,e);if(i===void 0)throw new Error("fetch() returned undefined");return i}memo(t,e={}){const i=this.#z;if(!i)throw new Error("no memoMethod provided to constructor");const{context:s,forceRefresh:n,...r}=e,a=this.get(t,r);if(!n&&a!==void 0)return a;const h=i(t,a,{options:r,context:s});return this.set(t,h,r),h}get(t,e={}){const{allowStale:i=this.allowStale,updateAgeOnGet:s=this.updateAgeOnGet,noDeleteOnStaleGet:n=this.noDeleteOnStaleGet,status:r}=e,a=this.#s.get(t);if(a!==void 0){const h=this.#t[a],l=this.#e(h);return r&&this.#F(r,a),this.#p(a)?(r&&(r.get="stale"),l?(r&&i&&h.__staleWhileFetching!==void 0&&(r.returnedStale=!0),i?h.__staleWhileFetching:void 0):(n||this.#v(t,"expire"),r&&i&&(r.returnedStale=!0),i?h:void 0)):(r&&(r.get="hit"),l?h.__staleWhileFetching:(this.#C(a),s&&this.#T(a),h))}else r&&(r.get="miss")}#M(t,e){this.#u[e]=t,this.#l[t]=e}#C(t){t!==this.#r&&(t===this.#h?this.#h=this.#l[t]:this.#M(this.#u[t],this.#l[t]),this.#M(this.#r,t),this.#r=t)}delete(t){return this.#v(t,"delete")}#v(t,e){let i=!1;if(this.#n!==0){const s=this.#s.get(t);if(s!==void 0)if(i=!0,this.#n===1)this.#N(e);else{this.#x(s);const n=this.#t[s];if(this.#e(n)?n.__abortController.abort(new Error("deleted")):(this.#_||this.#c)&&(this.#_&&this.#g?.(n,t,e),this.#c&&this.#o?.push([n,t,e])),this.#s.delete(t),this.#i[s]=void 0,this.#t[s]=void 0,s===this.#r)this.#r=this.#u[s];else if(s===this.#h)this.#h=this.#l[s];else{const r=this.#u[s];this.#l[r]=this.#l[s];const a=this.#l[s];this.#u[a]=this.#u[s]}this.#n--,this.#S.push(s)}}if(this.#c&&this.#o?.length){const s=this.#o;let n;for(;n=s?.shift();)this.#w?.(...n)}return i}clear(){return this.#N("delete")}#N(t){for(const e of this.#A({allowStale:!0})){const i=this.#t[e];if(this.#e(i))i.__abortController.abort(new Error("deleted"));else{const s=this.#i[e];this.#_&&this.#g?.(i,s,t),this.#c&&this.#o?.push([i,s,t])}}if(this.#s.clear(),this.#t.fill(void 0),this.#i.fill(void 0),this.#d&&this.#y&&(this.#d.fill(0),this.#y.fill(0)),this.#b&&this.#b.fill(0),this.#h=0,this.#r=0,this.#S.length=0,this.#m=0,this.#n=0,this.#c&&this.#o){const e=this.#o;let i;for(;i=e?.shift();)this.#w?.(...i)}}}const O=new M({max:128});function G(o,t,e){return[o?"esm":"cjs",t,...e||[]].join("\0")}const J=Symbol("runCtx");function nt(o,t){let e=t.recreateContext?void 0:o.protocol.cache.get(J);if(e){if(t.globals)for(const[i,s]of Object.entries(t.globals))e.context[i]=s}else{const i={log:(...n)=>o.log(n,"info"),info:(...n)=>o.log(n,"info"),warn:(...n)=>o.log(n,"warn"),error:(...n)=>o.log(n,"error")},s=R.createContext({...t.globals,console:i});e={modules:{},context:s},o.protocol.cache.set(J,e)}for(const i of t.functions??[]){let s=G(!1,i.code,i.params),n=O.get(s),r=R.compileFunction(i.code,i.params,{parsingContext:e.context,cachedData:n,produceCachedData:!n});e.context[i.name]=r}for(const i of t.modules??[]){const s=G(!0,i.code);let n=O.get(s),r=new R.SourceTextModule(i.code,{identifier:i.name,context:e.context,cachedData:n});if(!n){let a=r.createCachedData();O.set(s,a)}e.modules[i.name]=r}return e}async function rt(o,t){let e=process.hrtime.bigint(),i=nt(t,o),s;if(!o.code)return{};const n=G(!o.expr,o.code);if(o.expr){let h=O.get(n),l=new R.Script(o.code,{filename:o.name||"<script>",cachedData:h});h||O.set(n,l.createCachedData()),s=l.runInContext(i.context,{timeout:o.timeoutMs??void 0}),typeof s?.then=="function"&&(s=await s)}else{async function h(c,d){const u=i.modules[c];if(u)return u;throw new Error(`Module not found: ${c}, referenced from ${d.identifier}`)}let l=O.get(n),w=new R.SourceTextModule(o.code,{identifier:o.name||"<script>",context:i.context,cachedData:l});if(!l){let c=w.createCachedData();c&&O.set(n,c)}await w.link(h),await w.evaluate()}const r=o.returnKeys?Object.fromEntries(o.returnKeys.map(h=>[h,i.context[h]])):i.context;let a=Number(process.hrtime.bigint()-e)/1e3;return p(`Evaluated in ${a}us`),{globals:r,returnValue:s}}function ot(o){p(`Worker ${process.pid} started`);const t=q.createServer(),e=()=>{p(`Worker ${process.pid} is shutting down`),t.close(()=>process.exit(0))};process.on("message",s=>{p(`Worker ${process.pid} received message: ${s}`),s=="shutdown"&&(p(`Worker ${process.pid} received shutdown message`),e())}),F.worker?.send("ready"),process.on("SIGTERM",e),process.on("SIGINT",e);function i(s){let n=new it(s);n.on("message",r=>ht(n,r))}t.on("error",s=>{console.error(s),process.exit(1)}),t.listen(o,()=>{p(`Worker ${process.pid} is listening on ${o}`),t.on("connection",i)})}function ht(o,{id:t,reqId:e,type:i,data:s}){if(i===k.Ping){o.sendMessage(e,z.Pong,Buffer.alloc(0));return}let n=process.hrtime.bigint(),r=!1;const a={protocol:o,reqId:e,id:t,log(h,l="info"){p(`${e}[${l}]:`,h),o.log(e,l,h)},respond(h){r=!0,o.respond(e,h)},error(h){p(`${e}: `,h.message),o.error(e,h)}};at(a,i,s).then(h=>{(h!=null||!r)&&a.respond(h??null);let l=Number(process.hrtime.bigint()-n)/1e3;p(`handle: ${l}us`)}).catch(h=>{p("Failed to handle request:"),a.error(h)})}async function at(o,t,e){switch(t){case k.RunScript:return rt(JSON.parse(e.toString()),o)}}if(F.isPrimary){let o=function(){if(n)return;let a=F.fork({SOCKET_PATH:s});a.on("message",h=>{h==="ready"&&n&&a.send("shutdown")})};const t=process.argv[1],{values:e}=Z({options:{workers:{type:"string",default:Q.cpus().length.toString()},socket:{type:"string"}}}),i=parseInt(e.workers??"1",10),s=e.socket;let n=!1;if(!s)throw new Error("No socket path provided");process.on("exit",()=>{try{$.unlinkSync(s)}catch{}});const r=()=>{p("shutting down"),n&&process.exit(1),n=!0;for(let a of Object.values(F.workers??{}))a?.send("shutdown",()=>{})};process.on("SIGTERM",r),process.on("SIGINT",r),F.on("online",a=>{p("online",a.process.pid,n),n&&a.kill("SIGKILL")}),F.on("exit",(a,h,l)=>{if(p("exit",a.process.pid,h,l,n,s),!n&&!$.existsSync(t)&&(p(`${s} script is gone, shutting down`),r()),n){const w=Object.values(F.workers??{}).map(c=>c?.process.pid);p(s,"remaining workers:",w),w.length==0&&process.exit(0);return}p(l?`Worker ${a.process.pid} died with signal ${l}. Restarting...`:`Worker ${a.process.pid} died with code ${h}. Restarting...`),o()}),p(`Primary ${process.pid} is running, starting ${i} workers and connecting to ${s}`);for(let a=0;a<i;a++)o()}else ot(process.env.SOCKET_PATH);
+import { parseArgs } from 'node:util';
+import fs from 'node:fs';
+import os from 'node:os';
+import cluster from 'node:cluster';
+import net from 'node:net';
+import { EventEmitter } from 'node:events';
+import { gunzipSync, gzipSync, constants } from 'node:zlib';
+import { getHeapStatistics } from 'node:v8';
+import { monitorEventLoopDelay } from 'node:perf_hooks';
+import { Worker, parentPort, workerData, isMainThread } from 'node:worker_threads';
+import { AsyncLocalStorage } from 'node:async_hooks';
+import * as vm from 'node:vm';
+import { inspect, types } from 'node:util';
+import { getHeapSnapshot } from 'node:v8';
+import inspector from 'node:inspector';
+import { createHash } from 'node:crypto';
+import path from 'node:path';
+import http from 'node:http';
+import crypto from 'node:crypto';
+
+const { LRUCache } = (() => {
+/**
+ * @module LRUCache
+ */
+const perf = typeof performance === 'object' &&
+    performance &&
+    typeof performance.now === 'function'
+    ? performance
+    : Date;
+const warned = new Set();
+/* c8 ignore start */
+const PROCESS = (typeof process === 'object' && !!process ? process : {});
+/* c8 ignore start */
+const emitWarning = (msg, type, code, fn) => {
+    typeof PROCESS.emitWarning === 'function'
+        ? PROCESS.emitWarning(msg, type, code, fn)
+        : console.error(`[${code}] ${type}: ${msg}`);
+};
+let AC = globalThis.AbortController;
+let AS = globalThis.AbortSignal;
+/* c8 ignore start */
+if (typeof AC === 'undefined') {
+    //@ts-ignore
+    AS = class AbortSignal {
+        onabort;
+        _onabort = [];
+        reason;
+        aborted = false;
+        addEventListener(_, fn) {
+            this._onabort.push(fn);
+        }
+    };
+    //@ts-ignore
+    AC = class AbortController {
+        constructor() {
+            warnACPolyfill();
+        }
+        signal = new AS();
+        abort(reason) {
+            if (this.signal.aborted)
+                return;
+            //@ts-ignore
+            this.signal.reason = reason;
+            //@ts-ignore
+            this.signal.aborted = true;
+            //@ts-ignore
+            for (const fn of this.signal._onabort) {
+                fn(reason);
+            }
+            this.signal.onabort?.(reason);
+        }
+    };
+    let printACPolyfillWarning = PROCESS.env?.LRU_CACHE_IGNORE_AC_WARNING !== '1';
+    const warnACPolyfill = () => {
+        if (!printACPolyfillWarning)
+            return;
+        printACPolyfillWarning = false;
+        emitWarning('AbortController is not defined. If using lru-cache in ' +
+            'node 14, load an AbortController polyfill from the ' +
+            '`node-abort-controller` package. A minimal polyfill is ' +
+            'provided for use by LRUCache.fetch(), but it should not be ' +
+            'relied upon in other contexts (eg, passing it to other APIs that ' +
+            'use AbortController/AbortSignal might have undesirable effects). ' +
+            'You may disable this with LRU_CACHE_IGNORE_AC_WARNING=1 in the env.', 'NO_ABORT_CONTROLLER', 'ENOTSUP', warnACPolyfill);
+    };
+}
+/* c8 ignore stop */
+const shouldWarn = (code) => !warned.has(code);
+const TYPE = Symbol('type');
+const isPosInt = (n) => n && n === Math.floor(n) && n > 0 && isFinite(n);
+/* c8 ignore start */
+// This is a little bit ridiculous, tbh.
+// The maximum array length is 2^32-1 or thereabouts on most JS impls.
+// And well before that point, you're caching the entire world, I mean,
+// that's ~32GB of just integers for the next/prev links, plus whatever
+// else to hold that many keys and values.  Just filling the memory with
+// zeroes at init time is brutal when you get that big.
+// But why not be complete?
+// Maybe in the future, these limits will have expanded.
+const getUintArray = (max) => !isPosInt(max)
+    ? null
+    : max <= Math.pow(2, 8)
+        ? Uint8Array
+        : max <= Math.pow(2, 16)
+            ? Uint16Array
+            : max <= Math.pow(2, 32)
+                ? Uint32Array
+                : max <= Number.MAX_SAFE_INTEGER
+                    ? ZeroArray
+                    : null;
+/* c8 ignore stop */
+class ZeroArray extends Array {
+    constructor(size) {
+        super(size);
+        this.fill(0);
+    }
+}
+class Stack {
+    heap;
+    length;
+    // private constructor
+    static #constructing = false;
+    static create(max) {
+        const HeapCls = getUintArray(max);
+        if (!HeapCls)
+            return [];
+        Stack.#constructing = true;
+        const s = new Stack(max, HeapCls);
+        Stack.#constructing = false;
+        return s;
+    }
+    constructor(max, HeapCls) {
+        /* c8 ignore start */
+        if (!Stack.#constructing) {
+            throw new TypeError('instantiate Stack using Stack.create(n)');
+        }
+        /* c8 ignore stop */
+        this.heap = new HeapCls(max);
+        this.length = 0;
+    }
+    push(n) {
+        this.heap[this.length++] = n;
+    }
+    pop() {
+        return this.heap[--this.length];
+    }
+}
+/**
+ * Default export, the thing you're using this module to get.
+ *
+ * All properties from the options object (with the exception of
+ * {@link OptionsBase.max} and {@link OptionsBase.maxSize}) are added as
+ * normal public members. (`max` and `maxBase` are read-only getters.)
+ * Changing any of these will alter the defaults for subsequent method calls,
+ * but is otherwise safe.
+ */
+class LRUCache {
+    // properties coming in from the options of these, only max and maxSize
+    // really *need* to be protected. The rest can be modified, as they just
+    // set defaults for various methods.
+    #max;
+    #maxSize;
+    #dispose;
+    #disposeAfter;
+    #fetchMethod;
+    /**
+     * {@link LRUCache.OptionsBase.ttl}
+     */
+    ttl;
+    /**
+     * {@link LRUCache.OptionsBase.ttlResolution}
+     */
+    ttlResolution;
+    /**
+     * {@link LRUCache.OptionsBase.ttlAutopurge}
+     */
+    ttlAutopurge;
+    /**
+     * {@link LRUCache.OptionsBase.updateAgeOnGet}
+     */
+    updateAgeOnGet;
+    /**
+     * {@link LRUCache.OptionsBase.updateAgeOnHas}
+     */
+    updateAgeOnHas;
+    /**
+     * {@link LRUCache.OptionsBase.allowStale}
+     */
+    allowStale;
+    /**
+     * {@link LRUCache.OptionsBase.noDisposeOnSet}
+     */
+    noDisposeOnSet;
+    /**
+     * {@link LRUCache.OptionsBase.noUpdateTTL}
+     */
+    noUpdateTTL;
+    /**
+     * {@link LRUCache.OptionsBase.maxEntrySize}
+     */
+    maxEntrySize;
+    /**
+     * {@link LRUCache.OptionsBase.sizeCalculation}
+     */
+    sizeCalculation;
+    /**
+     * {@link LRUCache.OptionsBase.noDeleteOnFetchRejection}
+     */
+    noDeleteOnFetchRejection;
+    /**
+     * {@link LRUCache.OptionsBase.noDeleteOnStaleGet}
+     */
+    noDeleteOnStaleGet;
+    /**
+     * {@link LRUCache.OptionsBase.allowStaleOnFetchAbort}
+     */
+    allowStaleOnFetchAbort;
+    /**
+     * {@link LRUCache.OptionsBase.allowStaleOnFetchRejection}
+     */
+    allowStaleOnFetchRejection;
+    /**
+     * {@link LRUCache.OptionsBase.ignoreFetchAbort}
+     */
+    ignoreFetchAbort;
+    // computed properties
+    #size;
+    #calculatedSize;
+    #keyMap;
+    #keyList;
+    #valList;
+    #next;
+    #prev;
+    #head;
+    #tail;
+    #free;
+    #disposed;
+    #sizes;
+    #starts;
+    #ttls;
+    #hasDispose;
+    #hasFetchMethod;
+    #hasDisposeAfter;
+    /**
+     * Do not call this method unless you need to inspect the
+     * inner workings of the cache.  If anything returned by this
+     * object is modified in any way, strange breakage may occur.
+     *
+     * These fields are private for a reason!
+     *
+     * @internal
+     */
+    static unsafeExposeInternals(c) {
+        return {
+            // properties
+            starts: c.#starts,
+            ttls: c.#ttls,
+            sizes: c.#sizes,
+            keyMap: c.#keyMap,
+            keyList: c.#keyList,
+            valList: c.#valList,
+            next: c.#next,
+            prev: c.#prev,
+            get head() {
+                return c.#head;
+            },
+            get tail() {
+                return c.#tail;
+            },
+            free: c.#free,
+            // methods
+            isBackgroundFetch: (p) => c.#isBackgroundFetch(p),
+            backgroundFetch: (k, index, options, context) => c.#backgroundFetch(k, index, options, context),
+            moveToTail: (index) => c.#moveToTail(index),
+            indexes: (options) => c.#indexes(options),
+            rindexes: (options) => c.#rindexes(options),
+            isStale: (index) => c.#isStale(index),
+        };
+    }
+    // Protected read-only members
+    /**
+     * {@link LRUCache.OptionsBase.max} (read-only)
+     */
+    get max() {
+        return this.#max;
+    }
+    /**
+     * {@link LRUCache.OptionsBase.maxSize} (read-only)
+     */
+    get maxSize() {
+        return this.#maxSize;
+    }
+    /**
+     * The total computed size of items in the cache (read-only)
+     */
+    get calculatedSize() {
+        return this.#calculatedSize;
+    }
+    /**
+     * The number of items stored in the cache (read-only)
+     */
+    get size() {
+        return this.#size;
+    }
+    /**
+     * {@link LRUCache.OptionsBase.fetchMethod} (read-only)
+     */
+    get fetchMethod() {
+        return this.#fetchMethod;
+    }
+    /**
+     * {@link LRUCache.OptionsBase.dispose} (read-only)
+     */
+    get dispose() {
+        return this.#dispose;
+    }
+    /**
+     * {@link LRUCache.OptionsBase.disposeAfter} (read-only)
+     */
+    get disposeAfter() {
+        return this.#disposeAfter;
+    }
+    constructor(options) {
+        const { max = 0, ttl, ttlResolution = 1, ttlAutopurge, updateAgeOnGet, updateAgeOnHas, allowStale, dispose, disposeAfter, noDisposeOnSet, noUpdateTTL, maxSize = 0, maxEntrySize = 0, sizeCalculation, fetchMethod, noDeleteOnFetchRejection, noDeleteOnStaleGet, allowStaleOnFetchRejection, allowStaleOnFetchAbort, ignoreFetchAbort, } = options;
+        if (max !== 0 && !isPosInt(max)) {
+            throw new TypeError('max option must be a nonnegative integer');
+        }
+        const UintArray = max ? getUintArray(max) : Array;
+        if (!UintArray) {
+            throw new Error('invalid max value: ' + max);
+        }
+        this.#max = max;
+        this.#maxSize = maxSize;
+        this.maxEntrySize = maxEntrySize || this.#maxSize;
+        this.sizeCalculation = sizeCalculation;
+        if (this.sizeCalculation) {
+            if (!this.#maxSize && !this.maxEntrySize) {
+                throw new TypeError('cannot set sizeCalculation without setting maxSize or maxEntrySize');
+            }
+            if (typeof this.sizeCalculation !== 'function') {
+                throw new TypeError('sizeCalculation set to non-function');
+            }
+        }
+        if (fetchMethod !== undefined &&
+            typeof fetchMethod !== 'function') {
+            throw new TypeError('fetchMethod must be a function if specified');
+        }
+        this.#fetchMethod = fetchMethod;
+        this.#hasFetchMethod = !!fetchMethod;
+        this.#keyMap = new Map();
+        this.#keyList = new Array(max).fill(undefined);
+        this.#valList = new Array(max).fill(undefined);
+        this.#next = new UintArray(max);
+        this.#prev = new UintArray(max);
+        this.#head = 0;
+        this.#tail = 0;
+        this.#free = Stack.create(max);
+        this.#size = 0;
+        this.#calculatedSize = 0;
+        if (typeof dispose === 'function') {
+            this.#dispose = dispose;
+        }
+        if (typeof disposeAfter === 'function') {
+            this.#disposeAfter = disposeAfter;
+            this.#disposed = [];
+        }
+        else {
+            this.#disposeAfter = undefined;
+            this.#disposed = undefined;
+        }
+        this.#hasDispose = !!this.#dispose;
+        this.#hasDisposeAfter = !!this.#disposeAfter;
+        this.noDisposeOnSet = !!noDisposeOnSet;
+        this.noUpdateTTL = !!noUpdateTTL;
+        this.noDeleteOnFetchRejection = !!noDeleteOnFetchRejection;
+        this.allowStaleOnFetchRejection = !!allowStaleOnFetchRejection;
+        this.allowStaleOnFetchAbort = !!allowStaleOnFetchAbort;
+        this.ignoreFetchAbort = !!ignoreFetchAbort;
+        // NB: maxEntrySize is set to maxSize if it's set
+        if (this.maxEntrySize !== 0) {
+            if (this.#maxSize !== 0) {
+                if (!isPosInt(this.#maxSize)) {
+                    throw new TypeError('maxSize must be a positive integer if specified');
+                }
+            }
+            if (!isPosInt(this.maxEntrySize)) {
+                throw new TypeError('maxEntrySize must be a positive integer if specified');
+            }
+            this.#initializeSizeTracking();
+        }
+        this.allowStale = !!allowStale;
+        this.noDeleteOnStaleGet = !!noDeleteOnStaleGet;
+        this.updateAgeOnGet = !!updateAgeOnGet;
+        this.updateAgeOnHas = !!updateAgeOnHas;
+        this.ttlResolution =
+            isPosInt(ttlResolution) || ttlResolution === 0
+                ? ttlResolution
+                : 1;
+        this.ttlAutopurge = !!ttlAutopurge;
+        this.ttl = ttl || 0;
+        if (this.ttl) {
+            if (!isPosInt(this.ttl)) {
+                throw new TypeError('ttl must be a positive integer if specified');
+            }
+            this.#initializeTTLTracking();
+        }
+        // do not allow completely unbounded caches
+        if (this.#max === 0 && this.ttl === 0 && this.#maxSize === 0) {
+            throw new TypeError('At least one of max, maxSize, or ttl is required');
+        }
+        if (!this.ttlAutopurge && !this.#max && !this.#maxSize) {
+            const code = 'LRU_CACHE_UNBOUNDED';
+            if (shouldWarn(code)) {
+                warned.add(code);
+                const msg = 'TTL caching without ttlAutopurge, max, or maxSize can ' +
+                    'result in unbounded memory consumption.';
+                emitWarning(msg, 'UnboundedCacheWarning', code, LRUCache);
+            }
+        }
+    }
+    /**
+     * Return the remaining TTL time for a given entry key
+     */
+    getRemainingTTL(key) {
+        return this.#keyMap.has(key) ? Infinity : 0;
+    }
+    #initializeTTLTracking() {
+        const ttls = new ZeroArray(this.#max);
+        const starts = new ZeroArray(this.#max);
+        this.#ttls = ttls;
+        this.#starts = starts;
+        this.#setItemTTL = (index, ttl, start = perf.now()) => {
+            starts[index] = ttl !== 0 ? start : 0;
+            ttls[index] = ttl;
+            if (ttl !== 0 && this.ttlAutopurge) {
+                const t = setTimeout(() => {
+                    if (this.#isStale(index)) {
+                        this.delete(this.#keyList[index]);
+                    }
+                }, ttl + 1);
+                // unref() not supported on all platforms
+                /* c8 ignore start */
+                if (t.unref) {
+                    t.unref();
+                }
+                /* c8 ignore stop */
+            }
+        };
+        this.#updateItemAge = index => {
+            starts[index] = ttls[index] !== 0 ? perf.now() : 0;
+        };
+        this.#statusTTL = (status, index) => {
+            if (ttls[index]) {
+                const ttl = ttls[index];
+                const start = starts[index];
+                /* c8 ignore next */
+                if (!ttl || !start)
+                    return;
+                status.ttl = ttl;
+                status.start = start;
+                status.now = cachedNow || getNow();
+                const age = status.now - start;
+                status.remainingTTL = ttl - age;
+            }
+        };
+        // debounce calls to perf.now() to 1s so we're not hitting
+        // that costly call repeatedly.
+        let cachedNow = 0;
+        const getNow = () => {
+            const n = perf.now();
+            if (this.ttlResolution > 0) {
+                cachedNow = n;
+                const t = setTimeout(() => (cachedNow = 0), this.ttlResolution);
+                // not available on all platforms
+                /* c8 ignore start */
+                if (t.unref) {
+                    t.unref();
+                }
+                /* c8 ignore stop */
+            }
+            return n;
+        };
+        this.getRemainingTTL = key => {
+            const index = this.#keyMap.get(key);
+            if (index === undefined) {
+                return 0;
+            }
+            const ttl = ttls[index];
+            const start = starts[index];
+            if (!ttl || !start) {
+                return Infinity;
+            }
+            const age = (cachedNow || getNow()) - start;
+            return ttl - age;
+        };
+        this.#isStale = index => {
+            const s = starts[index];
+            const t = ttls[index];
+            return !!t && !!s && (cachedNow || getNow()) - s > t;
+        };
+    }
+    // conditionally set private methods related to TTL
+    #updateItemAge = () => { };
+    #statusTTL = () => { };
+    #setItemTTL = () => { };
+    /* c8 ignore stop */
+    #isStale = () => false;
+    #initializeSizeTracking() {
+        const sizes = new ZeroArray(this.#max);
+        this.#calculatedSize = 0;
+        this.#sizes = sizes;
+        this.#removeItemSize = index => {
+            this.#calculatedSize -= sizes[index];
+            sizes[index] = 0;
+        };
+        this.#requireSize = (k, v, size, sizeCalculation) => {
+            // provisionally accept background fetches.
+            // actual value size will be checked when they return.
+            if (this.#isBackgroundFetch(v)) {
+                return 0;
+            }
+            if (!isPosInt(size)) {
+                if (sizeCalculation) {
+                    if (typeof sizeCalculation !== 'function') {
+                        throw new TypeError('sizeCalculation must be a function');
+                    }
+                    size = sizeCalculation(v, k);
+                    if (!isPosInt(size)) {
+                        throw new TypeError('sizeCalculation return invalid (expect positive integer)');
+                    }
+                }
+                else {
+                    throw new TypeError('invalid size value (must be positive integer). ' +
+                        'When maxSize or maxEntrySize is used, sizeCalculation ' +
+                        'or size must be set.');
+                }
+            }
+            return size;
+        };
+        this.#addItemSize = (index, size, status) => {
+            sizes[index] = size;
+            if (this.#maxSize) {
+                const maxSize = this.#maxSize - sizes[index];
+                while (this.#calculatedSize > maxSize) {
+                    this.#evict(true);
+                }
+            }
+            this.#calculatedSize += sizes[index];
+            if (status) {
+                status.entrySize = size;
+                status.totalCalculatedSize = this.#calculatedSize;
+            }
+        };
+    }
+    #removeItemSize = _i => { };
+    #addItemSize = (_i, _s, _st) => { };
+    #requireSize = (_k, _v, size, sizeCalculation) => {
+        if (size || sizeCalculation) {
+            throw new TypeError('cannot set size without setting maxSize or maxEntrySize on cache');
+        }
+        return 0;
+    };
+    *#indexes({ allowStale = this.allowStale } = {}) {
+        if (this.#size) {
+            for (let i = this.#tail; true;) {
+                if (!this.#isValidIndex(i)) {
+                    break;
+                }
+                if (allowStale || !this.#isStale(i)) {
+                    yield i;
+                }
+                if (i === this.#head) {
+                    break;
+                }
+                else {
+                    i = this.#prev[i];
+                }
+            }
+        }
+    }
+    *#rindexes({ allowStale = this.allowStale } = {}) {
+        if (this.#size) {
+            for (let i = this.#head; true;) {
+                if (!this.#isValidIndex(i)) {
+                    break;
+                }
+                if (allowStale || !this.#isStale(i)) {
+                    yield i;
+                }
+                if (i === this.#tail) {
+                    break;
+                }
+                else {
+                    i = this.#next[i];
+                }
+            }
+        }
+    }
+    #isValidIndex(index) {
+        return (index !== undefined &&
+            this.#keyMap.get(this.#keyList[index]) === index);
+    }
+    /**
+     * Return a generator yielding `[key, value]` pairs,
+     * in order from most recently used to least recently used.
+     */
+    *entries() {
+        for (const i of this.#indexes()) {
+            if (this.#valList[i] !== undefined &&
+                this.#keyList[i] !== undefined &&
+                !this.#isBackgroundFetch(this.#valList[i])) {
+                yield [this.#keyList[i], this.#valList[i]];
+            }
+        }
+    }
+    /**
+     * Inverse order version of {@link LRUCache.entries}
+     *
+     * Return a generator yielding `[key, value]` pairs,
+     * in order from least recently used to most recently used.
+     */
+    *rentries() {
+        for (const i of this.#rindexes()) {
+            if (this.#valList[i] !== undefined &&
+                this.#keyList[i] !== undefined &&
+                !this.#isBackgroundFetch(this.#valList[i])) {
+                yield [this.#keyList[i], this.#valList[i]];
+            }
+        }
+    }
+    /**
+     * Return a generator yielding the keys in the cache,
+     * in order from most recently used to least recently used.
+     */
+    *keys() {
+        for (const i of this.#indexes()) {
+            const k = this.#keyList[i];
+            if (k !== undefined &&
+                !this.#isBackgroundFetch(this.#valList[i])) {
+                yield k;
+            }
+        }
+    }
+    /**
+     * Inverse order version of {@link LRUCache.keys}
+     *
+     * Return a generator yielding the keys in the cache,
+     * in order from least recently used to most recently used.
+     */
+    *rkeys() {
+        for (const i of this.#rindexes()) {
+            const k = this.#keyList[i];
+            if (k !== undefined &&
+                !this.#isBackgroundFetch(this.#valList[i])) {
+                yield k;
+            }
+        }
+    }
+    /**
+     * Return a generator yielding the values in the cache,
+     * in order from most recently used to least recently used.
+     */
+    *values() {
+        for (const i of this.#indexes()) {
+            const v = this.#valList[i];
+            if (v !== undefined &&
+                !this.#isBackgroundFetch(this.#valList[i])) {
+                yield this.#valList[i];
+            }
+        }
+    }
+    /**
+     * Inverse order version of {@link LRUCache.values}
+     *
+     * Return a generator yielding the values in the cache,
+     * in order from least recently used to most recently used.
+     */
+    *rvalues() {
+        for (const i of this.#rindexes()) {
+            const v = this.#valList[i];
+            if (v !== undefined &&
+                !this.#isBackgroundFetch(this.#valList[i])) {
+                yield this.#valList[i];
+            }
+        }
+    }
+    /**
+     * Iterating over the cache itself yields the same results as
+     * {@link LRUCache.entries}
+     */
+    [Symbol.iterator]() {
+        return this.entries();
+    }
+    /**
+     * A String value that is used in the creation of the default string description of an object.
+     * Called by the built-in method Object.prototype.toString.
+     */
+    [Symbol.toStringTag] = 'LRUCache';
+    /**
+     * Find a value for which the supplied fn method returns a truthy value,
+     * similar to Array.find().  fn is called as fn(value, key, cache).
+     */
+    find(fn, getOptions = {}) {
+        for (const i of this.#indexes()) {
+            const v = this.#valList[i];
+            const value = this.#isBackgroundFetch(v)
+                ? v.__staleWhileFetching
+                : v;
+            if (value === undefined)
+                continue;
+            if (fn(value, this.#keyList[i], this)) {
+                return this.get(this.#keyList[i], getOptions);
+            }
+        }
+    }
+    /**
+     * Call the supplied function on each item in the cache, in order from
+     * most recently used to least recently used.  fn is called as
+     * fn(value, key, cache).  Does not update age or recenty of use.
+     * Does not iterate over stale values.
+     */
+    forEach(fn, thisp = this) {
+        for (const i of this.#indexes()) {
+            const v = this.#valList[i];
+            const value = this.#isBackgroundFetch(v)
+                ? v.__staleWhileFetching
+                : v;
+            if (value === undefined)
+                continue;
+            fn.call(thisp, value, this.#keyList[i], this);
+        }
+    }
+    /**
+     * The same as {@link LRUCache.forEach} but items are iterated over in
+     * reverse order.  (ie, less recently used items are iterated over first.)
+     */
+    rforEach(fn, thisp = this) {
+        for (const i of this.#rindexes()) {
+            const v = this.#valList[i];
+            const value = this.#isBackgroundFetch(v)
+                ? v.__staleWhileFetching
+                : v;
+            if (value === undefined)
+                continue;
+            fn.call(thisp, value, this.#keyList[i], this);
+        }
+    }
+    /**
+     * Delete any stale entries. Returns true if anything was removed,
+     * false otherwise.
+     */
+    purgeStale() {
+        let deleted = false;
+        for (const i of this.#rindexes({ allowStale: true })) {
+            if (this.#isStale(i)) {
+                this.delete(this.#keyList[i]);
+                deleted = true;
+            }
+        }
+        return deleted;
+    }
+    /**
+     * Get the extended info about a given entry, to get its value, size, and
+     * TTL info simultaneously. Like {@link LRUCache#dump}, but just for a
+     * single key. Always returns stale values, if their info is found in the
+     * cache, so be sure to check for expired TTLs if relevant.
+     */
+    info(key) {
+        const i = this.#keyMap.get(key);
+        if (i === undefined)
+            return undefined;
+        const v = this.#valList[i];
+        const value = this.#isBackgroundFetch(v)
+            ? v.__staleWhileFetching
+            : v;
+        if (value === undefined)
+            return undefined;
+        const entry = { value };
+        if (this.#ttls && this.#starts) {
+            const ttl = this.#ttls[i];
+            const start = this.#starts[i];
+            if (ttl && start) {
+                const remain = ttl - (perf.now() - start);
+                entry.ttl = remain;
+                entry.start = Date.now();
+            }
+        }
+        if (this.#sizes) {
+            entry.size = this.#sizes[i];
+        }
+        return entry;
+    }
+    /**
+     * Return an array of [key, {@link LRUCache.Entry}] tuples which can be
+     * passed to cache.load()
+     */
+    dump() {
+        const arr = [];
+        for (const i of this.#indexes({ allowStale: true })) {
+            const key = this.#keyList[i];
+            const v = this.#valList[i];
+            const value = this.#isBackgroundFetch(v)
+                ? v.__staleWhileFetching
+                : v;
+            if (value === undefined || key === undefined)
+                continue;
+            const entry = { value };
+            if (this.#ttls && this.#starts) {
+                entry.ttl = this.#ttls[i];
+                // always dump the start relative to a portable timestamp
+                // it's ok for this to be a bit slow, it's a rare operation.
+                const age = perf.now() - this.#starts[i];
+                entry.start = Math.floor(Date.now() - age);
+            }
+            if (this.#sizes) {
+                entry.size = this.#sizes[i];
+            }
+            arr.unshift([key, entry]);
+        }
+        return arr;
+    }
+    /**
+     * Reset the cache and load in the items in entries in the order listed.
+     * Note that the shape of the resulting cache may be different if the
+     * same options are not used in both caches.
+     */
+    load(arr) {
+        this.clear();
+        for (const [key, entry] of arr) {
+            if (entry.start) {
+                // entry.start is a portable timestamp, but we may be using
+                // node's performance.now(), so calculate the offset, so that
+                // we get the intended remaining TTL, no matter how long it's
+                // been on ice.
+                //
+                // it's ok for this to be a bit slow, it's a rare operation.
+                const age = Date.now() - entry.start;
+                entry.start = perf.now() - age;
+            }
+            this.set(key, entry.value, entry);
+        }
+    }
+    /**
+     * Add a value to the cache.
+     *
+     * Note: if `undefined` is specified as a value, this is an alias for
+     * {@link LRUCache#delete}
+     */
+    set(k, v, setOptions = {}) {
+        if (v === undefined) {
+            this.delete(k);
+            return this;
+        }
+        const { ttl = this.ttl, start, noDisposeOnSet = this.noDisposeOnSet, sizeCalculation = this.sizeCalculation, status, } = setOptions;
+        let { noUpdateTTL = this.noUpdateTTL } = setOptions;
+        const size = this.#requireSize(k, v, setOptions.size || 0, sizeCalculation);
+        // if the item doesn't fit, don't do anything
+        // NB: maxEntrySize set to maxSize by default
+        if (this.maxEntrySize && size > this.maxEntrySize) {
+            if (status) {
+                status.set = 'miss';
+                status.maxEntrySizeExceeded = true;
+            }
+            // have to delete, in case something is there already.
+            this.delete(k);
+            return this;
+        }
+        let index = this.#size === 0 ? undefined : this.#keyMap.get(k);
+        if (index === undefined) {
+            // addition
+            index = (this.#size === 0
+                ? this.#tail
+                : this.#free.length !== 0
+                    ? this.#free.pop()
+                    : this.#size === this.#max
+                        ? this.#evict(false)
+                        : this.#size);
+            this.#keyList[index] = k;
+            this.#valList[index] = v;
+            this.#keyMap.set(k, index);
+            this.#next[this.#tail] = index;
+            this.#prev[index] = this.#tail;
+            this.#tail = index;
+            this.#size++;
+            this.#addItemSize(index, size, status);
+            if (status)
+                status.set = 'add';
+            noUpdateTTL = false;
+        }
+        else {
+            // update
+            this.#moveToTail(index);
+            const oldVal = this.#valList[index];
+            if (v !== oldVal) {
+                if (this.#hasFetchMethod && this.#isBackgroundFetch(oldVal)) {
+                    oldVal.__abortController.abort(new Error('replaced'));
+                    const { __staleWhileFetching: s } = oldVal;
+                    if (s !== undefined && !noDisposeOnSet) {
+                        if (this.#hasDispose) {
+                            this.#dispose?.(s, k, 'set');
+                        }
+                        if (this.#hasDisposeAfter) {
+                            this.#disposed?.push([s, k, 'set']);
+                        }
+                    }
+                }
+                else if (!noDisposeOnSet) {
+                    if (this.#hasDispose) {
+                        this.#dispose?.(oldVal, k, 'set');
+                    }
+                    if (this.#hasDisposeAfter) {
+                        this.#disposed?.push([oldVal, k, 'set']);
+                    }
+                }
+                this.#removeItemSize(index);
+                this.#addItemSize(index, size, status);
+                this.#valList[index] = v;
+                if (status) {
+                    status.set = 'replace';
+                    const oldValue = oldVal && this.#isBackgroundFetch(oldVal)
+                        ? oldVal.__staleWhileFetching
+                        : oldVal;
+                    if (oldValue !== undefined)
+                        status.oldValue = oldValue;
+                }
+            }
+            else if (status) {
+                status.set = 'update';
+            }
+        }
+        if (ttl !== 0 && !this.#ttls) {
+            this.#initializeTTLTracking();
+        }
+        if (this.#ttls) {
+            if (!noUpdateTTL) {
+                this.#setItemTTL(index, ttl, start);
+            }
+            if (status)
+                this.#statusTTL(status, index);
+        }
+        if (!noDisposeOnSet && this.#hasDisposeAfter && this.#disposed) {
+            const dt = this.#disposed;
+            let task;
+            while ((task = dt?.shift())) {
+                this.#disposeAfter?.(...task);
+            }
+        }
+        return this;
+    }
+    /**
+     * Evict the least recently used item, returning its value or
+     * `undefined` if cache is empty.
+     */
+    pop() {
+        try {
+            while (this.#size) {
+                const val = this.#valList[this.#head];
+                this.#evict(true);
+                if (this.#isBackgroundFetch(val)) {
+                    if (val.__staleWhileFetching) {
+                        return val.__staleWhileFetching;
+                    }
+                }
+                else if (val !== undefined) {
+                    return val;
+                }
+            }
+        }
+        finally {
+            if (this.#hasDisposeAfter && this.#disposed) {
+                const dt = this.#disposed;
+                let task;
+                while ((task = dt?.shift())) {
+                    this.#disposeAfter?.(...task);
+                }
+            }
+        }
+    }
+    #evict(free) {
+        const head = this.#head;
+        const k = this.#keyList[head];
+        const v = this.#valList[head];
+        if (this.#hasFetchMethod && this.#isBackgroundFetch(v)) {
+            v.__abortController.abort(new Error('evicted'));
+        }
+        else if (this.#hasDispose || this.#hasDisposeAfter) {
+            if (this.#hasDispose) {
+                this.#dispose?.(v, k, 'evict');
+            }
+            if (this.#hasDisposeAfter) {
+                this.#disposed?.push([v, k, 'evict']);
+            }
+        }
+        this.#removeItemSize(head);
+        // if we aren't about to use the index, then null these out
+        if (free) {
+            this.#keyList[head] = undefined;
+            this.#valList[head] = undefined;
+            this.#free.push(head);
+        }
+        if (this.#size === 1) {
+            this.#head = this.#tail = 0;
+            this.#free.length = 0;
+        }
+        else {
+            this.#head = this.#next[head];
+        }
+        this.#keyMap.delete(k);
+        this.#size--;
+        return head;
+    }
+    /**
+     * Check if a key is in the cache, without updating the recency of use.
+     * Will return false if the item is stale, even though it is technically
+     * in the cache.
+     *
+     * Will not update item age unless
+     * {@link LRUCache.OptionsBase.updateAgeOnHas} is set.
+     */
+    has(k, hasOptions = {}) {
+        const { updateAgeOnHas = this.updateAgeOnHas, status } = hasOptions;
+        const index = this.#keyMap.get(k);
+        if (index !== undefined) {
+            const v = this.#valList[index];
+            if (this.#isBackgroundFetch(v) &&
+                v.__staleWhileFetching === undefined) {
+                return false;
+            }
+            if (!this.#isStale(index)) {
+                if (updateAgeOnHas) {
+                    this.#updateItemAge(index);
+                }
+                if (status) {
+                    status.has = 'hit';
+                    this.#statusTTL(status, index);
+                }
+                return true;
+            }
+            else if (status) {
+                status.has = 'stale';
+                this.#statusTTL(status, index);
+            }
+        }
+        else if (status) {
+            status.has = 'miss';
+        }
+        return false;
+    }
+    /**
+     * Like {@link LRUCache#get} but doesn't update recency or delete stale
+     * items.
+     *
+     * Returns `undefined` if the item is stale, unless
+     * {@link LRUCache.OptionsBase.allowStale} is set.
+     */
+    peek(k, peekOptions = {}) {
+        const { allowStale = this.allowStale } = peekOptions;
+        const index = this.#keyMap.get(k);
+        if (index === undefined ||
+            (!allowStale && this.#isStale(index))) {
+            return;
+        }
+        const v = this.#valList[index];
+        // either stale and allowed, or forcing a refresh of non-stale value
+        return this.#isBackgroundFetch(v) ? v.__staleWhileFetching : v;
+    }
+    #backgroundFetch(k, index, options, context) {
+        const v = index === undefined ? undefined : this.#valList[index];
+        if (this.#isBackgroundFetch(v)) {
+            return v;
+        }
+        const ac = new AC();
+        const { signal } = options;
+        // when/if our AC signals, then stop listening to theirs.
+        signal?.addEventListener('abort', () => ac.abort(signal.reason), {
+            signal: ac.signal,
+        });
+        const fetchOpts = {
+            signal: ac.signal,
+            options,
+            context,
+        };
+        const cb = (v, updateCache = false) => {
+            const { aborted } = ac.signal;
+            const ignoreAbort = options.ignoreFetchAbort && v !== undefined;
+            if (options.status) {
+                if (aborted && !updateCache) {
+                    options.status.fetchAborted = true;
+                    options.status.fetchError = ac.signal.reason;
+                    if (ignoreAbort)
+                        options.status.fetchAbortIgnored = true;
+                }
+                else {
+                    options.status.fetchResolved = true;
+                }
+            }
+            if (aborted && !ignoreAbort && !updateCache) {
+                return fetchFail(ac.signal.reason);
+            }
+            // either we didn't abort, and are still here, or we did, and ignored
+            const bf = p;
+            if (this.#valList[index] === p) {
+                if (v === undefined) {
+                    if (bf.__staleWhileFetching) {
+                        this.#valList[index] = bf.__staleWhileFetching;
+                    }
+                    else {
+                        this.delete(k);
+                    }
+                }
+                else {
+                    if (options.status)
+                        options.status.fetchUpdated = true;
+                    this.set(k, v, fetchOpts.options);
+                }
+            }
+            return v;
+        };
+        const eb = (er) => {
+            if (options.status) {
+                options.status.fetchRejected = true;
+                options.status.fetchError = er;
+            }
+            return fetchFail(er);
+        };
+        const fetchFail = (er) => {
+            const { aborted } = ac.signal;
+            const allowStaleAborted = aborted && options.allowStaleOnFetchAbort;
+            const allowStale = allowStaleAborted || options.allowStaleOnFetchRejection;
+            const noDelete = allowStale || options.noDeleteOnFetchRejection;
+            const bf = p;
+            if (this.#valList[index] === p) {
+                // if we allow stale on fetch rejections, then we need to ensure that
+                // the stale value is not removed from the cache when the fetch fails.
+                const del = !noDelete || bf.__staleWhileFetching === undefined;
+                if (del) {
+                    this.delete(k);
+                }
+                else if (!allowStaleAborted) {
+                    // still replace the *promise* with the stale value,
+                    // since we are done with the promise at this point.
+                    // leave it untouched if we're still waiting for an
+                    // aborted background fetch that hasn't yet returned.
+                    this.#valList[index] = bf.__staleWhileFetching;
+                }
+            }
+            if (allowStale) {
+                if (options.status && bf.__staleWhileFetching !== undefined) {
+                    options.status.returnedStale = true;
+                }
+                return bf.__staleWhileFetching;
+            }
+            else if (bf.__returned === bf) {
+                throw er;
+            }
+        };
+        const pcall = (res, rej) => {
+            const fmp = this.#fetchMethod?.(k, v, fetchOpts);
+            if (fmp && fmp instanceof Promise) {
+                fmp.then(v => res(v === undefined ? undefined : v), rej);
+            }
+            // ignored, we go until we finish, regardless.
+            // defer check until we are actually aborting,
+            // so fetchMethod can override.
+            ac.signal.addEventListener('abort', () => {
+                if (!options.ignoreFetchAbort ||
+                    options.allowStaleOnFetchAbort) {
+                    res(undefined);
+                    // when it eventually resolves, update the cache.
+                    if (options.allowStaleOnFetchAbort) {
+                        res = v => cb(v, true);
+                    }
+                }
+            });
+        };
+        if (options.status)
+            options.status.fetchDispatched = true;
+        const p = new Promise(pcall).then(cb, eb);
+        const bf = Object.assign(p, {
+            __abortController: ac,
+            __staleWhileFetching: v,
+            __returned: undefined,
+        });
+        if (index === undefined) {
+            // internal, don't expose status.
+            this.set(k, bf, { ...fetchOpts.options, status: undefined });
+            index = this.#keyMap.get(k);
+        }
+        else {
+            this.#valList[index] = bf;
+        }
+        return bf;
+    }
+    #isBackgroundFetch(p) {
+        if (!this.#hasFetchMethod)
+            return false;
+        const b = p;
+        return (!!b &&
+            b instanceof Promise &&
+            b.hasOwnProperty('__staleWhileFetching') &&
+            b.__abortController instanceof AC);
+    }
+    async fetch(k, fetchOptions = {}) {
+        const {
+        // get options
+        allowStale = this.allowStale, updateAgeOnGet = this.updateAgeOnGet, noDeleteOnStaleGet = this.noDeleteOnStaleGet,
+        // set options
+        ttl = this.ttl, noDisposeOnSet = this.noDisposeOnSet, size = 0, sizeCalculation = this.sizeCalculation, noUpdateTTL = this.noUpdateTTL,
+        // fetch exclusive options
+        noDeleteOnFetchRejection = this.noDeleteOnFetchRejection, allowStaleOnFetchRejection = this.allowStaleOnFetchRejection, ignoreFetchAbort = this.ignoreFetchAbort, allowStaleOnFetchAbort = this.allowStaleOnFetchAbort, context, forceRefresh = false, status, signal, } = fetchOptions;
+        if (!this.#hasFetchMethod) {
+            if (status)
+                status.fetch = 'get';
+            return this.get(k, {
+                allowStale,
+                updateAgeOnGet,
+                noDeleteOnStaleGet,
+                status,
+            });
+        }
+        const options = {
+            allowStale,
+            updateAgeOnGet,
+            noDeleteOnStaleGet,
+            ttl,
+            noDisposeOnSet,
+            size,
+            sizeCalculation,
+            noUpdateTTL,
+            noDeleteOnFetchRejection,
+            allowStaleOnFetchRejection,
+            allowStaleOnFetchAbort,
+            ignoreFetchAbort,
+            status,
+            signal,
+        };
+        let index = this.#keyMap.get(k);
+        if (index === undefined) {
+            if (status)
+                status.fetch = 'miss';
+            const p = this.#backgroundFetch(k, index, options, context);
+            return (p.__returned = p);
+        }
+        else {
+            // in cache, maybe already fetching
+            const v = this.#valList[index];
+            if (this.#isBackgroundFetch(v)) {
+                const stale = allowStale && v.__staleWhileFetching !== undefined;
+                if (status) {
+                    status.fetch = 'inflight';
+                    if (stale)
+                        status.returnedStale = true;
+                }
+                return stale ? v.__staleWhileFetching : (v.__returned = v);
+            }
+            // if we force a refresh, that means do NOT serve the cached value,
+            // unless we are already in the process of refreshing the cache.
+            const isStale = this.#isStale(index);
+            if (!forceRefresh && !isStale) {
+                if (status)
+                    status.fetch = 'hit';
+                this.#moveToTail(index);
+                if (updateAgeOnGet) {
+                    this.#updateItemAge(index);
+                }
+                if (status)
+                    this.#statusTTL(status, index);
+                return v;
+            }
+            // ok, it is stale or a forced refresh, and not already fetching.
+            // refresh the cache.
+            const p = this.#backgroundFetch(k, index, options, context);
+            const hasStale = p.__staleWhileFetching !== undefined;
+            const staleVal = hasStale && allowStale;
+            if (status) {
+                status.fetch = isStale ? 'stale' : 'refresh';
+                if (staleVal && isStale)
+                    status.returnedStale = true;
+            }
+            return staleVal ? p.__staleWhileFetching : (p.__returned = p);
+        }
+    }
+    /**
+     * Return a value from the cache. Will update the recency of the cache
+     * entry found.
+     *
+     * If the key is not found, get() will return `undefined`.
+     */
+    get(k, getOptions = {}) {
+        const { allowStale = this.allowStale, updateAgeOnGet = this.updateAgeOnGet, noDeleteOnStaleGet = this.noDeleteOnStaleGet, status, } = getOptions;
+        const index = this.#keyMap.get(k);
+        if (index !== undefined) {
+            const value = this.#valList[index];
+            const fetching = this.#isBackgroundFetch(value);
+            if (status)
+                this.#statusTTL(status, index);
+            if (this.#isStale(index)) {
+                if (status)
+                    status.get = 'stale';
+                // delete only if not an in-flight background fetch
+                if (!fetching) {
+                    if (!noDeleteOnStaleGet) {
+                        this.delete(k);
+                    }
+                    if (status && allowStale)
+                        status.returnedStale = true;
+                    return allowStale ? value : undefined;
+                }
+                else {
+                    if (status &&
+                        allowStale &&
+                        value.__staleWhileFetching !== undefined) {
+                        status.returnedStale = true;
+                    }
+                    return allowStale ? value.__staleWhileFetching : undefined;
+                }
+            }
+            else {
+                if (status)
+                    status.get = 'hit';
+                // if we're currently fetching it, we don't actually have it yet
+                // it's not stale, which means this isn't a staleWhileRefetching.
+                // If it's not stale, and fetching, AND has a __staleWhileFetching
+                // value, then that means the user fetched with {forceRefresh:true},
+                // so it's safe to return that value.
+                if (fetching) {
+                    return value.__staleWhileFetching;
+                }
+                this.#moveToTail(index);
+                if (updateAgeOnGet) {
+                    this.#updateItemAge(index);
+                }
+                return value;
+            }
+        }
+        else if (status) {
+            status.get = 'miss';
+        }
+    }
+    #connect(p, n) {
+        this.#prev[n] = p;
+        this.#next[p] = n;
+    }
+    #moveToTail(index) {
+        // if tail already, nothing to do
+        // if head, move head to next[index]
+        // else
+        //   move next[prev[index]] to next[index] (head has no prev)
+        //   move prev[next[index]] to prev[index]
+        // prev[index] = tail
+        // next[tail] = index
+        // tail = index
+        if (index !== this.#tail) {
+            if (index === this.#head) {
+                this.#head = this.#next[index];
+            }
+            else {
+                this.#connect(this.#prev[index], this.#next[index]);
+            }
+            this.#connect(this.#tail, index);
+            this.#tail = index;
+        }
+    }
+    /**
+     * Deletes a key out of the cache.
+     * Returns true if the key was deleted, false otherwise.
+     */
+    delete(k) {
+        let deleted = false;
+        if (this.#size !== 0) {
+            const index = this.#keyMap.get(k);
+            if (index !== undefined) {
+                deleted = true;
+                if (this.#size === 1) {
+                    this.clear();
+                }
+                else {
+                    this.#removeItemSize(index);
+                    const v = this.#valList[index];
+                    if (this.#isBackgroundFetch(v)) {
+                        v.__abortController.abort(new Error('deleted'));
+                    }
+                    else if (this.#hasDispose || this.#hasDisposeAfter) {
+                        if (this.#hasDispose) {
+                            this.#dispose?.(v, k, 'delete');
+                        }
+                        if (this.#hasDisposeAfter) {
+                            this.#disposed?.push([v, k, 'delete']);
+                        }
+                    }
+                    this.#keyMap.delete(k);
+                    this.#keyList[index] = undefined;
+                    this.#valList[index] = undefined;
+                    if (index === this.#tail) {
+                        this.#tail = this.#prev[index];
+                    }
+                    else if (index === this.#head) {
+                        this.#head = this.#next[index];
+                    }
+                    else {
+                        const pi = this.#prev[index];
+                        this.#next[pi] = this.#next[index];
+                        const ni = this.#next[index];
+                        this.#prev[ni] = this.#prev[index];
+                    }
+                    this.#size--;
+                    this.#free.push(index);
+                }
+            }
+        }
+        if (this.#hasDisposeAfter && this.#disposed?.length) {
+            const dt = this.#disposed;
+            let task;
+            while ((task = dt?.shift())) {
+                this.#disposeAfter?.(...task);
+            }
+        }
+        return deleted;
+    }
+    /**
+     * Clear the cache entirely, throwing away all values.
+     */
+    clear() {
+        for (const index of this.#rindexes({ allowStale: true })) {
+            const v = this.#valList[index];
+            if (this.#isBackgroundFetch(v)) {
+                v.__abortController.abort(new Error('deleted'));
+            }
+            else {
+                const k = this.#keyList[index];
+                if (this.#hasDispose) {
+                    this.#dispose?.(v, k, 'delete');
+                }
+                if (this.#hasDisposeAfter) {
+                    this.#disposed?.push([v, k, 'delete']);
+                }
+            }
+        }
+        this.#keyMap.clear();
+        this.#valList.fill(undefined);
+        this.#keyList.fill(undefined);
+        if (this.#ttls && this.#starts) {
+            this.#ttls.fill(0);
+            this.#starts.fill(0);
+        }
+        if (this.#sizes) {
+            this.#sizes.fill(0);
+        }
+        this.#head = 0;
+        this.#tail = 0;
+        this.#free.length = 0;
+        this.#calculatedSize = 0;
+        this.#size = 0;
+        if (this.#hasDisposeAfter && this.#disposed) {
+            const dt = this.#disposed;
+            let task;
+            while ((task = dt?.shift())) {
+                this.#disposeAfter?.(...task);
+            }
+        }
+    }
+}
+
+return { LRUCache };
+})();
+
+// src/api_types.ts
+// Types that are used when communicating with the host, together for easy reference.
+
+// Message types
+// Host-to-worker
+var HostToWorkerMessage = /* @__PURE__ */ ((HostToWorkerMessage) => {
+  HostToWorkerMessage[HostToWorkerMessage["RunScript"] = 0] = "RunScript";
+  HostToWorkerMessage[HostToWorkerMessage["Ping"] = 1] = "Ping";
+  HostToWorkerMessage[HostToWorkerMessage["HeapSnapshot"] = 2] = "HeapSnapshot";
+  HostToWorkerMessage[HostToWorkerMessage["Handshake"] = 3] = "Handshake";
+  HostToWorkerMessage[HostToWorkerMessage["KvResponse"] = 4] = "KvResponse";
+  HostToWorkerMessage[HostToWorkerMessage["AdvanceTime"] = 5] = "AdvanceTime";
+  HostToWorkerMessage[HostToWorkerMessage["RunScriptBinary"] = 6] = "RunScriptBinary";
+  HostToWorkerMessage[HostToWorkerMessage["ResolveModuleResponse"] = 7] = "ResolveModuleResponse";
+  HostToWorkerMessage[HostToWorkerMessage["Cancel"] = 8] = "Cancel";
+  HostToWorkerMessage[HostToWorkerMessage["Input"] = 9] = "Input";
+  HostToWorkerMessage[HostToWorkerMessage["InputEnd"] = 10] = "InputEnd";
+  HostToWorkerMessage[HostToWorkerMessage["ChannelMessage"] = 11] = "ChannelMessage";
+  HostToWorkerMessage[HostToWorkerMessage["ChannelClose"] = 12] = "ChannelClose";
+  /** Ask for completions of the end of a line of code, from the globals and lexical declarations
+   * of the connection's context. */
+  HostToWorkerMessage[HostToWorkerMessage["Complete"] = 13] = "Complete";
+  /** List the properties of a value in the connection's context, or its globals, with hints
+   * about what each one holds. */
+  HostToWorkerMessage[HostToWorkerMessage["Introspect"] = 14] = "Introspect";
+  /** Compile code without running it, and report its syntax errors. */
+  HostToWorkerMessage[HostToWorkerMessage["CheckScript"] = 15] = "CheckScript";
+  /** Get the status of a background job started by a RunScript with `detach` set. */
+  HostToWorkerMessage[HostToWorkerMessage["JobStatus"] = 16] = "JobStatus";
+  /** Abort the signal of a background job. */
+  HostToWorkerMessage[HostToWorkerMessage["JobCancel"] = 17] = "JobCancel";
+  /** Build a context from a prewarm manifest, and keep one ready for runs that ask for it by
+   * name. */
+  HostToWorkerMessage[HostToWorkerMessage["Prewarm"] = 18] = "Prewarm";
+  return HostToWorkerMessage;
+})(HostToWorkerMessage || {});
+
+// Worker-to-host
+var WorkerToHostMessage = /* @__PURE__ */ ((WorkerToHostMessage) => {
+  WorkerToHostMessage[WorkerToHostMessage["RunResponse"] = 0x1000] = "RunResponse";
+  WorkerToHostMessage[WorkerToHostMessage["Log"] = 0x1001] = "Log";
+  WorkerToHostMessage[WorkerToHostMessage["Error"] = 0x1002] = "Error";
+  WorkerToHostMessage[WorkerToHostMessage["Pong"] = 0x1003] = "Pong";
+  WorkerToHostMessage[WorkerToHostMessage["MemoryUsage"] = 0x1004] = "MemoryUsage";
+  WorkerToHostMessage[WorkerToHostMessage["DebuggerWaiting"] = 0x1005] = "DebuggerWaiting";
+  WorkerToHostMessage[WorkerToHostMessage["CpuProfile"] = 0x1006] = "CpuProfile";
+  WorkerToHostMessage[WorkerToHostMessage["HeapSnapshotChunk"] = 0x1007] = "HeapSnapshotChunk";
+  WorkerToHostMessage[WorkerToHostMessage["Handshake"] = 0x1008] = "Handshake";
+  WorkerToHostMessage[WorkerToHostMessage["RunResponseChunk"] = 0x1009] = "RunResponseChunk";
+  WorkerToHostMessage[WorkerToHostMessage["MessageTooLarge"] = 0x100a] = "MessageTooLarge";
+  WorkerToHostMessage[WorkerToHostMessage["KvRequest"] = 0x100b] = "KvRequest";
+  WorkerToHostMessage[WorkerToHostMessage["ResolveModule"] = 0x100c] = "ResolveModule";
+  WorkerToHostMessage[WorkerToHostMessage["WorkerLoad"] = 0x100d] = "WorkerLoad";
+  WorkerToHostMessage[WorkerToHostMessage["AsyncError"] = 0x100e] = "AsyncError";
+  WorkerToHostMessage[WorkerToHostMessage["Output"] = 0x100f] = "Output";
+  WorkerToHostMessage[WorkerToHostMessage["ChannelMessage"] = 0x1010] = "ChannelMessage";
+  return WorkerToHostMessage;
+})(WorkerToHostMessage || {});
+
+/** The version of the wire protocol: the message framing and the set of message types. This must
+ * match `PROTOCOL_VERSION` in the Rust crate, and changes whenever either of them changes. */
+const PROTOCOL_VERSION = 20;
+
+/** A function to be injected into the context. */
+
+
+/** A ES Module to be importable by the script */
+
+
+/** A WebAssembly module to instantiate in the context */
+
+
+/** Data associated with the RunScript message */
+
+
+/** How `setTimeout` and `setInterval` behave in a context
+ *
+ * - `real`: timers run on the worker's clock
+ * - `disabled`: setting a timer throws
+ * - `virtual`: timers run when the host sends AdvanceTime, and `Date` reads the same clock
+ */
+
+
+/** Data associated with the Complete message */
+
+
+/** The result of Complete, sent as the return value of a RunResponse */
+
+
+/** Data associated with the Introspect message */
+
+
+/** What a property holds. `accessor` is a property with a getter or setter, which isn't run to
+ * find out. */
+
+
+/** A property from Introspect, sent in an array as the return value of a RunResponse */
+
+
+/** Data associated with the CheckScript message */
+
+
+/** A problem found by CheckScript. Lines and columns start at 1. */
+
+
+/** The result of CheckScript, sent as the return value of a RunResponse */
+
+
+/** Data associated with the AdvanceTime message */
+
+
+/** The result of AdvanceTime, sent as the return value of a RunResponse */
+
+
+
+
+/** An effect of a dry run, which was recorded instead of reaching the host */
+
+
+/** A global that was left out of a response */
+
+
+/** Measurements of a single run, taken inside the worker. */
+
+
+
+
+/** Data associated with the MemoryUsage message, in bytes */
+
+
+
+
+
+
+/** Data associated with the DebuggerWaiting message */
+
+
+/** Returned from the management endpoint's `/health` route. */
+
+
+/** Returned for each worker from the management endpoint's `/workers` route. */
+
+
+/** Sent by the primary process to the host over the events socket, one JSON object per line. */
+
+
+/** Data associated with the Handshake message from the host */
+
+
+/** A call to the `kv` global */
+
+
+/** Data associated with the KvResponse message */
+
+
+/** Data associated with the ResolveModule message */
+
+
+/** Data associated with the ResolveModuleResponse message */
+
+
+/** Data associated with the MessageTooLarge message */
+
+
+/** Data associated with the Handshake response */
+
+// src/annotations.ts
+/** Requests currently being handled by this worker, so that a crash can be attributed to them. */
+const activeRequests = new Set();
+
+/** Format annotations as `key=value` pairs for diagnostic output. */
+function formatAnnotations(annotations) {
+  if (!annotations) {
+    return '';
+  }
+
+  return Object.entries(annotations)
+    .map(([key, value]) => `${key}=${value}`)
+    .join(' ');
+}
+
+/** Add the run's annotations to the end of an error's stack trace. */
+function annotateStack(stack, annotations) {
+  const formatted = formatAnnotations(annotations);
+  if (!stack || !formatted) {
+    return stack;
+  }
+
+  return `${stack}\n    [annotations: ${formatted}]`;
+}
+
+/** Describe the requests that were running when the worker crashed. */
+function crashReport(e, requests) {
+  const error = e instanceof Error ? (e.stack ?? e.message) : String(e);
+  const lines = [`Worker ${process.pid} crashed: ${error}`];
+  for (const request of requests) {
+    const formatted = formatAnnotations(request.annotations);
+    lines.push(`  running request ${request.reqId}${formatted ? ` [${formatted}]` : ''}`);
+  }
+
+  return lines.join('\n');
+}
+
+// src/debug.ts
+const enabled = !!process.env.DEBUG_JS_SIDECAR_WORKER;
+
+function debug(...args) {
+  if (enabled) {
+    console.log(...args);
+  }
+}
+
+// src/errors.ts
+/** Set on errors from loading a script's imports. This is the code Node.js uses for the same
+ * failure. */
+const MODULE_NOT_FOUND = 'ERR_MODULE_NOT_FOUND';
+/** Set on errors from the worker itself, rather than from the script. */
+const INTERNAL_ERROR = 'ERR_SIDECAR_INTERNAL';
+/** Set on errors for strings over the run's size limit. */
+const STRING_TOO_LONG = 'ERR_STRING_TOO_LONG';
+
+/** How deep a chain of causes and aggregated errors is sent to the host. */
+const MAX_ERROR_DEPTH = 8;
+/** How many of the errors in an AggregateError are sent to the host. */
+const MAX_AGGREGATED_ERRORS = 100;
+/** How many errors in all are described for one thrown value, so that wide trees of aggregated
+ * errors stay small. */
+const MAX_DESCRIBED_ERRORS = 500;
+
+/** Messages of the RangeErrors that V8 throws when it can't allocate memory. */
+const OUT_OF_MEMORY_MESSAGES = ['Array buffer allocation failed', 'Invalid string length'];
+
+/** An error for an import that couldn't be loaded. */
+function moduleError(message, options) {
+  return Object.assign(new Error(message, options), { code: MODULE_NOT_FOUND });
+}
+
+/** An error for a failure in the worker that isn't the script's fault. */
+function internalError(message) {
+  return Object.assign(new Error(message), { code: INTERNAL_ERROR });
+}
+
+/** An error for a string over the run's size limit. */
+function stringTooLongError(message) {
+  return Object.assign(new Error(message), { code: STRING_TOO_LONG });
+}
+
+/** Classify a thrown value, so that the host doesn't have to match on the message to decide what
+ * to do about it. `signal` is the run's signal, whose reason is what a cancelled run fails with.
+ *
+ * Errors thrown inside a context come from that context's realm, so they are checked by name
+ * rather than with `instanceof`. */
+function errorKind(e, signal) {
+  if (isTimeout(e)) {
+    return 'timeout';
+  }
+  if (signal?.aborted && e === signal.reason) {
+    return 'cancelled';
+  }
+
+  switch (e?.code) {
+    case MODULE_NOT_FOUND:
+      return 'moduleResolution';
+    case INTERNAL_ERROR:
+      return 'internalWorkerError';
+    case STRING_TOO_LONG:
+      return 'stringTooLong';
+    case 'ERR_WORKER_OUT_OF_MEMORY':
+      return 'outOfMemory';
+  }
+
+  switch (e?.name) {
+    case 'SyntaxError':
+      return 'syntaxError';
+    case 'ReferenceError':
+      return 'referenceError';
+    case 'RangeError':
+      if (OUT_OF_MEMORY_MESSAGES.includes(e.message)) {
+        return 'outOfMemory';
+      }
+  }
+  return 'script';
+}
+
+/** Describe a thrown value for the host, along with the chain of errors that caused it and, for an
+ * AggregateError, the errors that it collects. Scripts can throw values that aren't errors, and
+ * those are described by their string form. */
+function describeError(
+  e,
+  kind = errorKind(e),
+  walk = { visited: new Set(), remaining: MAX_DESCRIBED_ERRORS },
+  depth = 0
+) {
+  walk.remaining -= 1;
+  const response = {
+    message: typeof e?.message === 'string' ? e.message : String(e),
+    stack: typeof e?.stack === 'string' ? e.stack : undefined,
+    kind,
+    name: typeof e?.name === 'string' ? e.name : undefined,
+    code: typeof e?.code === 'string' ? e.code : undefined,
+  };
+
+  // Causes can form a cycle, and the same error can show up in many places, so each error's own
+  // causes and aggregated errors are only described the first time it is reached.
+  if (
+    typeof e !== 'object' ||
+    e === null ||
+    walk.visited.has(e) ||
+    depth >= MAX_ERROR_DEPTH ||
+    walk.remaining <= 0
+  ) {
+    return response;
+  }
+
+  walk.visited.add(e);
+  if (e.cause !== undefined) {
+    response.cause = describeError(e.cause, errorKind(e.cause), walk, depth + 1);
+  }
+  if (Array.isArray(e.errors)) {
+    response.errors = [];
+    for (const item of e.errors.slice(0, MAX_AGGREGATED_ERRORS)) {
+      if (walk.remaining <= 0) {
+        break;
+      }
+      response.errors.push(describeError(item, errorKind(item), walk, depth + 1));
+    }
+  }
+  return response;
+}
+
+// src/protocol.ts
+/** The byte stream that a Protocol runs over: a Unix socket, or a WebSocket connection. */
+
+
+/** Follows the length at the start of every frame, so that a reader that loses its place can
+ * scan for the start of the next frame. */
+const FRAME_MAGIC = 0x46534a53;
+const FRAME_MAGIC_BYTES = Buffer.from([0x53, 0x4a, 0x53, 0x46]);
+
+/** Set in the message type of frames that end with a CRC32 checksum. */
+const CHECKSUM_FLAG = 0x80000000;
+/** Set in the message type of frames whose payload is gzip-compressed. The checksum, if any,
+ * covers the compressed payload. */
+const COMPRESSED_FLAG = 0x40000000;
+/** Set in the message type of frames whose payload is the name of a file in the shared memory
+ * directory, which holds the real payload. */
+const SHARED_MEMORY_FLAG = 0x20000000;
+const FLAGS = CHECKSUM_FLAG | COMPRESSED_FLAG | SHARED_MEMORY_FLAG;
+
+/** Message types that end a request's sequence of message IDs. The next message for the same
+ * request, such as an async error after a run finished, starts a new sequence. */
+const ENDS_SEQUENCE = new Set([
+  WorkerToHostMessage.RunResponse,
+  WorkerToHostMessage.Error,
+  WorkerToHostMessage.Pong,
+  WorkerToHostMessage.Handshake,
+]);
+
+/** Message types that aren't part of any request, and so are left out of the sequences. */
+const UNSEQUENCED = new Set([WorkerToHostMessage.MemoryUsage, WorkerToHostMessage.WorkerLoad]);
+
+// Header *without* the length field
+const MSG_HEADER_LENGTH = 16;
+/** The largest payload that an uncompressed frame can carry. */
+const MAX_PAYLOAD_BYTES = 0xffffffff - MSG_HEADER_LENGTH;
+
+// Offsets from the start of the frame.
+const MAGIC_OFFSET = 4;
+const REQ_ID_OFFSET = 8;
+const MSG_ID_OFFSET = 12;
+const MSG_TYPE_OFFSET = 16;
+const DATA_OFFSET = 20;
+
+const CRC32_TABLE = new Uint32Array(256);
+for (let i = 0; i < 256; i++) {
+  let c = i;
+  for (let bit = 0; bit < 8; bit++) {
+    c = c & 1 ? 0xedb88320 ^ (c >>> 1) : c >>> 1;
+  }
+  CRC32_TABLE[i] = c >>> 0;
+}
+
+/** Split a payload that has binary data after its JSON. */
+function splitBinaryPayload(data) {
+  if (data.length < 4) {
+    throw internalError('Binary payload is too short');
+  }
+  const jsonLength = data.readUInt32LE(0);
+  if (4 + jsonLength > data.length) {
+    throw internalError('Binary payload is shorter than its JSON');
+  }
+  return {
+    json: JSON.parse(data.subarray(4, 4 + jsonLength).toString()),
+    binary: data.subarray(4 + jsonLength),
+  };
+}
+
+/** The CRC32 (IEEE) checksum of `data`, the same one used by zlib. */
+function crc32(data) {
+  let crc = 0xffffffff;
+  for (let i = 0; i < data.length; i++) {
+    crc = CRC32_TABLE[(crc ^ data[i]) & 0xff] ^ (crc >>> 8);
+  }
+  return (crc ^ 0xffffffff) >>> 0;
+}
+
+/** A simple protocol in which each message has an ID, a type, and some data
+ *
+ *  Format
+ *
+ *  0: length of the rest of the frame
+ *  4: magic marker
+ *  8: request ID, links the message to a particular run
+ *  12: message ID, unique per message within a request
+ *  16: message type, with the high bit set if the frame ends with a checksum, the next bit
+ *      set if the payload is gzip-compressed, and the one after that set if the payload is the
+ *      name of a file in the shared memory directory
+ *  ... type-specific data follows
+ *  CRC32 of everything before it, if the checksum bit is set
+ *
+ *  Frames that don't start with the magic marker are skipped until the next marker, and frames
+ *  whose checksum doesn't match are dropped.
+ * */
+class Protocol extends EventEmitter {
+  socket;
+  buffer;
+  /** The ID of the next message for each request, for requests whose sequence hasn't ended.
+   * Message IDs count up from 0 within each request, so that the host can tell when a frame is
+   * lost or repeated. */
+  sequences = new Map();
+  /** Add a checksum to each frame sent. The host asks for this in its handshake. */
+  checksums = false;
+  /** Bytes dropped while looking for the start of a frame, since the last complete frame. */
+  skippedBytes = 0;
+  /** The largest frame the host will read, from the handshake. */
+  maxFrameBytes = null;
+  /** How far to decompress a compressed frame from the host before dropping it. */
+  maxPayloadBytes = MAX_PAYLOAD_BYTES;
+  /** The largest response the host will accept, from the handshake. */
+  maxResponseBytes = null;
+  /** The directory to pass large payloads through instead of the socket, from the handshake. */
+  sharedMemoryDir = null;
+  /** Pass payloads larger than this many bytes through the shared memory directory, from the
+   * handshake. */
+  sharedMemoryOver = null;
+  nextSegmentId = 0;
+  /** Compress payloads larger than this many bytes, from the handshake. */
+  compressOver = null;
+  /** The host has a KV backend, from the handshake. */
+  kvEnabled = false;
+  /** The host has a module resolver, from the handshake. */
+  resolveModulesEnabled = false;
+  /** URL prefixes that scripts can import modules from, from the handshake. */
+  urlImports = [];
+  /** How long to cache modules fetched for URL imports, from the handshake. */
+  urlImportTtlMs = 0;
+  /** KV calls and module lookups waiting for the host to answer, by ID. */
+  hostCalls = new Map();
+  nextHostCallId = 0;
+
+  cache = new Map();
+
+  constructor(socket) {
+    super();
+    this.socket = socket;
+    this.buffer = Buffer.alloc(0);
+    this.socket.on('data', (data) => this.handleData(data));
+    this.socket.on('close', () => {
+      for (const call of this.hostCalls.values()) {
+        call.reject(new Error('Connection to the host closed'));
+      }
+      this.hostCalls.clear();
+    });
+  }
+
+  handleData(data) {
+    this.buffer = Buffer.concat([this.buffer, data]);
+
+    while (this.buffer.length >= 8) {
+      const length = this.buffer.readUInt32LE(0);
+      if (this.buffer.readUInt32LE(MAGIC_OFFSET) !== FRAME_MAGIC || length < MSG_HEADER_LENGTH) {
+        this.resync();
+        continue;
+      }
+
+      // Not enough data for full message
+      if (this.buffer.length < length + 4) {
+        return;
+      }
+
+      const frame = this.buffer.subarray(0, length + 4);
+      this.buffer = this.buffer.subarray(length + 4);
+
+      if (this.skippedBytes > 0) {
+        debug(`Skipped ${this.skippedBytes} bytes of corrupted data`);
+        this.skippedBytes = 0;
+      }
+
+      const reqId = frame.readUInt32LE(REQ_ID_OFFSET);
+      const id = frame.readUInt32LE(MSG_ID_OFFSET);
+      const typeField = frame.readUInt32LE(MSG_TYPE_OFFSET);
+      let data = frame.subarray(DATA_OFFSET);
+
+      if ((typeField & CHECKSUM_FLAG) !== 0) {
+        const end = frame.length - 4;
+        if (end < DATA_OFFSET || crc32(frame.subarray(0, end)) !== frame.readUInt32LE(end)) {
+          debug(`Dropping message ${reqId}:${id} with a bad checksum`);
+          // The request ID may be damaged too, but if it isn't then this saves the host from
+          // waiting for a response that will never come.
+          this.error(reqId, internalError('Message from host failed its checksum'));
+          continue;
+        }
+        data = frame.subarray(DATA_OFFSET, end);
+      }
+
+      if ((typeField & SHARED_MEMORY_FLAG) !== 0) {
+        try {
+          data = this.takeSegment(data.toString());
+        } catch (e) {
+          debug(`Dropping message ${reqId}:${id} whose shared memory couldn't be read`, e);
+          this.error(
+            reqId,
+            internalError('Message from host could not be read from shared memory')
+          );
+          continue;
+        }
+      } else if ((typeField & COMPRESSED_FLAG) !== 0) {
+        try {
+          // Stop where an uncompressed frame would have to, rather than inflating without bound.
+          data = gunzipSync(data, { maxOutputLength: this.maxPayloadBytes });
+        } catch (e) {
+          debug(`Dropping message ${reqId}:${id} that failed to decompress`, e);
+          const message =
+            e?.code === 'ERR_BUFFER_TOO_LARGE'
+              ? `Message from host is larger than ${this.maxPayloadBytes} bytes once decompressed`
+              : 'Message from host could not be decompressed';
+          this.error(reqId, internalError(message));
+          continue;
+        }
+      }
+
+      const message = {
+        id,
+        reqId,
+        type: typeField & ~FLAGS,
+        data,
+      };
+
+      // Emit the received message
+      this.emit('message', message);
+    }
+  }
+
+  /** Drop data from the front of the buffer up to the next frame marker, keeping any partial
+   * frame header at the end in case the rest of it hasn't arrived yet. */
+  resync() {
+    const marker = this.buffer.indexOf(FRAME_MAGIC_BYTES, MAGIC_OFFSET + 1);
+    const skip = marker === -1 ? Math.max(this.buffer.length - 7, 1) : marker - MAGIC_OFFSET;
+    this.skippedBytes += skip;
+    this.buffer = this.buffer.subarray(skip);
+  }
+
+  /** Send a message. `callback` is called once the message has been written out. */
+  sendMessage(
+    reqId,
+    type,
+    message,
+    callback
+  ) {
+    debug('Sending message', reqId, type, message);
+    if (!(message instanceof Buffer)) {
+      message = Buffer.from(message);
+    }
+
+    const checksumLength = this.checksums ? 4 : 0;
+    const length = message.length + MSG_HEADER_LENGTH + checksumLength;
+    if (
+      this.maxFrameBytes !== null &&
+      length > this.maxFrameBytes &&
+      type !== WorkerToHostMessage.MessageTooLarge
+    ) {
+      return this.tooLarge(reqId, length, this.maxFrameBytes, callback);
+    }
+
+    let compressed = false;
+    let shared = false;
+    if (this.sharedMemoryOver !== null && message.length > this.sharedMemoryOver) {
+      message = Buffer.from(this.writeSegment(message));
+      shared = true;
+    } else if (this.compressOver !== null && message.length > this.compressOver) {
+      const gzipped = gzipSync(message, { level: constants.Z_BEST_SPEED });
+      // Payloads that don't get smaller go out as they are.
+      if (gzipped.length < message.length) {
+        message = gzipped;
+        compressed = true;
+      }
+    }
+
+    let flags = 0;
+    if (this.checksums) {
+      flags |= CHECKSUM_FLAG;
+    }
+    if (compressed) {
+      flags |= COMPRESSED_FLAG;
+    }
+    if (shared) {
+      flags |= SHARED_MEMORY_FLAG;
+    }
+
+    const id = this.nextMessageId(reqId, type);
+    const header = Buffer.allocUnsafe(DATA_OFFSET);
+    header.writeUInt32LE(message.length + MSG_HEADER_LENGTH + checksumLength);
+    header.writeUInt32LE(FRAME_MAGIC, MAGIC_OFFSET);
+    header.writeUInt32LE(reqId, REQ_ID_OFFSET);
+    header.writeUInt32LE(id, MSG_ID_OFFSET);
+    header.writeUInt32LE((type | flags) >>> 0, MSG_TYPE_OFFSET);
+
+    const frame = [header, message];
+    if (this.checksums) {
+      const checksum = Buffer.allocUnsafe(4);
+      checksum.writeUInt32LE(crc32(Buffer.concat(frame)));
+      frame.push(checksum);
+    }
+
+    this.socket.write(Buffer.concat(frame), callback);
+    return id;
+  }
+
+  /** Take the ID for the next message of a request. */
+  nextMessageId(reqId, type) {
+    if (UNSEQUENCED.has(type)) {
+      return 0;
+    }
+    const id = this.sequences.get(reqId) ?? 0;
+    this.advanceSequence(reqId, id, type);
+    return id;
+  }
+
+  advanceSequence(reqId, id, type) {
+    if (ENDS_SEQUENCE.has(type)) {
+      this.sequences.delete(reqId);
+    } else if (!UNSEQUENCED.has(type)) {
+      this.sequences.set(reqId, id + 1);
+    }
+  }
+
+  /** Keep the sequences up to date with a frame that another Protocol sent over this one's
+   * socket, as a connection's worker thread does, so that messages sent from here follow on. */
+  forwarded(frame) {
+    const reqId = frame.readUInt32LE(REQ_ID_OFFSET);
+    const id = frame.readUInt32LE(MSG_ID_OFFSET);
+    const type = frame.readUInt32LE(MSG_TYPE_OFFSET) & ~FLAGS;
+    this.advanceSequence(reqId, id, type);
+  }
+
+  /** Write a payload to a new file in the shared memory directory, returning its name. */
+  writeSegment(data) {
+    const name = `worker-${process.pid}-${this.nextSegmentId++}`;
+    fs.writeFileSync(path.join(this.sharedMemoryDir, name), data);
+    return name;
+  }
+
+  /** Read and remove a payload that the host wrote to the shared memory directory. */
+  takeSegment(name) {
+    if (!this.sharedMemoryDir) {
+      throw new Error('Frame uses shared memory, but none was set up');
+    }
+    if (!name || name.startsWith('.') || /[/\\]/.test(name)) {
+      throw new Error(`Invalid shared memory segment name ${JSON.stringify(name)}`);
+    }
+
+    const file = path.join(this.sharedMemoryDir, name);
+    try {
+      return fs.readFileSync(file);
+    } finally {
+      fs.rmSync(file, { force: true });
+    }
+  }
+
+  /** Send a console message, unless it is over the run's `budget`. */
+  log(
+    reqId,
+    level,
+    message,
+    namespace,
+    budget
+  ) {
+    let log = { level, message, timestamp: Date.now(), requestId: reqId, namespace };
+    let data = JSON.stringify(log);
+    if (budget && !budget.take(Buffer.byteLength(data))) {
+      return;
+    }
+    this.sendMessage(reqId, WorkerToHostMessage.Log, data);
+  }
+
+  respond(reqId, data) {
+    const message = Buffer.from(JSON.stringify(data));
+    if (this.maxResponseBytes !== null && message.length > this.maxResponseBytes) {
+      this.tooLarge(reqId, message.length, this.maxResponseBytes);
+      return;
+    }
+
+    // Split responses that are too large for one frame into chunks, with the last chunk sent as
+    // the response itself.
+    const chunkSize =
+      this.maxFrameBytes === null
+        ? message.length
+        : Math.max(this.maxFrameBytes - MSG_HEADER_LENGTH - 4, 1);
+    let offset = 0;
+    while (message.length - offset > chunkSize) {
+      const chunk = message.subarray(offset, offset + chunkSize);
+      this.sendMessage(reqId, WorkerToHostMessage.RunResponseChunk, chunk);
+      offset += chunkSize;
+    }
+    this.sendMessage(reqId, WorkerToHostMessage.RunResponse, message.subarray(offset));
+  }
+
+  /** Send output from a run's `write` global, split into frames that fit the size limit.
+   * Resolves once the last frame has been written out. */
+  output(reqId, data) {
+    if (!data.length) {
+      return Promise.resolve();
+    }
+
+    const chunkSize =
+      this.maxFrameBytes === null
+        ? data.length
+        : Math.max(this.maxFrameBytes - MSG_HEADER_LENGTH - 4, 1);
+    return new Promise((resolve) => {
+      for (let offset = 0; offset < data.length; offset += chunkSize) {
+        const chunk = data.subarray(offset, offset + chunkSize);
+        const last = offset + chunkSize >= data.length;
+        this.sendMessage(reqId, WorkerToHostMessage.Output, chunk, last ? resolve : undefined);
+      }
+    });
+  }
+
+  /** Send a value from a run's `channel` global, resolving once it has been written out. */
+  channelMessage(reqId, json) {
+    return new Promise((resolve) => {
+      this.sendMessage(reqId, WorkerToHostMessage.ChannelMessage, json, resolve);
+    });
+  }
+
+  /** Send a request to the host, and wait for the response with the same ID. */
+  callHost(reqId, type, request) {
+    const id = this.nextHostCallId++;
+    return new Promise((resolve, reject) => {
+      this.hostCalls.set(id, { resolve, reject });
+      this.sendMessage(reqId, type, JSON.stringify({ id, ...request }));
+    });
+  }
+
+  settleHostCall(id, value, error) {
+    const call = this.hostCalls.get(id);
+    if (!call) {
+      debug(`Received a response to unknown host call ${id}`);
+      return;
+    }
+
+    this.hostCalls.delete(id);
+    if (error !== undefined) {
+      call.reject(new Error(error));
+    } else {
+      call.resolve(value);
+    }
+  }
+
+  /** Send a KV call for the `kv` global to the host, and wait for the answer. */
+  kvRequest(reqId, request) {
+    if (!this.kvEnabled) {
+      return Promise.reject(new Error('The host has no KV backend'));
+    }
+    return this.callHost(reqId, WorkerToHostMessage.KvRequest, request);
+  }
+
+  /** Handle the host's answer to a KV call. */
+  kvResponse(data) {
+    const { id, value, error } = JSON.parse(data.toString()) ;
+    this.settleHostCall(id, value ?? null, error);
+  }
+
+  /** Ask the host's module resolver for the module that `specifier` refers to. Resolves to null
+   * if the host doesn't know it. */
+  resolveModule(reqId, specifier, referrer) {
+    if (!this.resolveModulesEnabled) {
+      return Promise.reject(new Error('The host has no module resolver'));
+    }
+    return this.callHost(reqId, WorkerToHostMessage.ResolveModule, { specifier, referrer });
+  }
+
+  /** Handle the host's answer to a module lookup. */
+  moduleResolved(data) {
+    const { id, module, error } = JSON.parse(data.toString()) ;
+    this.settleHostCall(id, module ?? null, error);
+  }
+
+  /** Tell the host that a message was dropped for being over its size limit. */
+  tooLarge(reqId, length, limit, callback) {
+    debug(`Dropping ${length} byte message for request ${reqId}, over the limit of ${limit}`);
+    const data = { length, limit };
+    return this.sendMessage(
+      reqId,
+      WorkerToHostMessage.MessageTooLarge,
+      JSON.stringify(data),
+      callback
+    );
+  }
+
+  error(reqId, e, annotations, kind = errorKind(e)) {
+    const described = describeError(e, kind);
+    let message = {
+      ...described,
+      stack: annotateStack(described.stack, annotations),
+      annotations,
+      timedOut: kind === 'timeout' || undefined,
+    };
+
+    let data = JSON.stringify(message);
+    this.sendMessage(reqId, WorkerToHostMessage.Error, data);
+  }
+
+  /** Report an error that escaped from a context's async code, such as a timer callback. */
+  asyncError(reqId, e, source, annotations) {
+    const described = describeError(e);
+    const message = {
+      source,
+      error: { ...described, stack: annotateStack(described.stack, annotations), annotations },
+    };
+    this.sendMessage(reqId, WorkerToHostMessage.AsyncError, JSON.stringify(message));
+  }
+
+  /** Answer the host's handshake, and close the connection if it speaks a different protocol
+   * version. Returns true if the versions match. `respond` is false when a new worker thread takes
+   * over a connection that already finished its handshake. */
+  handshake(reqId, data, respond = true) {
+    const handshake = JSON.parse(data.toString()) ;
+    const { version, checksums, maxFrameBytes, maxResponseBytes, kv, resolveModules } = handshake;
+    const accepted = version === PROTOCOL_VERSION;
+    if (respond) {
+      const response = { version: PROTOCOL_VERSION, accepted, pid: process.pid };
+      this.sendMessage(reqId, WorkerToHostMessage.Handshake, JSON.stringify(response));
+    }
+    // The response goes out without a checksum or compression, since the host can read frames
+    // either way.
+    this.checksums = accepted && Boolean(checksums);
+    this.compressOver = accepted ? (handshake.compressFramesOver ?? null) : null;
+    this.sharedMemoryDir = accepted ? (handshake.sharedMemoryDir ?? null) : null;
+    this.sharedMemoryOver = this.sharedMemoryDir ? (handshake.sharedMemoryOver ?? null) : null;
+    this.maxFrameBytes = maxFrameBytes ?? null;
+    this.maxResponseBytes = maxResponseBytes ?? null;
+    this.kvEnabled = accepted && Boolean(kv);
+    this.resolveModulesEnabled = accepted && Boolean(resolveModules);
+    this.urlImports = accepted ? (handshake.urlImports ?? []) : [];
+    this.urlImportTtlMs = handshake.urlImportTtlMs ?? 0;
+
+    if (!accepted && respond) {
+      debug(`Rejecting host with protocol version ${version}, expected ${PROTOCOL_VERSION}`);
+      this.socket.end();
+    }
+    return accepted;
+  }
+}
+
+// src/log_filter.ts
+/** Matches log namespaces against a `debug`-style list of patterns.
+ *
+ *  Each pattern is a namespace name, which may contain `*` wildcards. Patterns starting with `-`
+ *  disable matching namespaces, and take precedence over the enabling patterns.
+ *
+ *  e.g. `['db:*', '-db:verbose']` enables `db:query` and `db:pool`, but not `db:verbose`.
+ * */
+class NamespaceFilter {
+  enabled = [];
+  skipped = [];
+
+  constructor(patterns) {
+    for (const pattern of patterns) {
+      if (!pattern) {
+        continue;
+      }
+
+      if (pattern.startsWith('-')) {
+        this.skipped.push(patternToRegex(pattern.slice(1)));
+      } else {
+        this.enabled.push(patternToRegex(pattern));
+      }
+    }
+  }
+
+  isEnabled(namespace) {
+    if (this.skipped.some((r) => r.test(namespace))) {
+      return false;
+    }
+
+    // Only negative patterns means that everything else is enabled.
+    if (!this.enabled.length) {
+      return true;
+    }
+
+    return this.enabled.some((r) => r.test(namespace));
+  }
+}
+
+function patternToRegex(pattern) {
+  const escaped = pattern.replace(/[.+?^${}()|[\]\\]/g, '\\$&').replace(/\*/g, '.*?');
+  return new RegExp(`^${escaped}$`);
+}
+
+const PREFIX_REGEX = /^\[([^\]\s]+)\]/;
+
+/** Get the namespace from a log message that starts with a `[namespace]` prefix. */
+function extractNamespace(args) {
+  if (typeof args[0] !== 'string') {
+    return undefined;
+  }
+
+  return PREFIX_REGEX.exec(args[0])?.[1];
+}
+
+// src/validate.ts
+/** Check all the strings inside `value` against the limits, and ensure that they can be
+ * represented as UTF-8 by the host. Returns the value with any invalid strings replaced if
+ * `replaceInvalidUnicode` is set, copying objects as needed so that the original is not modified.
+ * */
+function validateStrings(value, path, limits) {
+  return validate(value, path, limits, new Map());
+}
+
+/** `fixed` maps each object that has been checked to its output, so that an object reached
+ * through more than one reference is only checked once, and every reference gets the same copy. */
+function validate(value, path, limits, fixed) {
+  if (typeof value === 'string') {
+    return validateString(value, path, limits);
+  }
+
+  if (value === null || typeof value !== 'object') {
+    return value;
+  }
+  if (fixed.has(value)) {
+    return fixed.get(value);
+  }
+
+  // A circular reference to an object that is still being checked keeps the original.
+  fixed.set(value, value);
+  const output = validateObject(value, path, limits, fixed);
+  fixed.set(value, output);
+  return output;
+}
+
+function validateObject(value, path, limits, fixed) {
+  if (Array.isArray(value)) {
+    let output = value;
+    for (let i = 0; i < value.length; i++) {
+      const item = validate(value[i], `${path}[${i}]`, limits, fixed);
+      if (item !== value[i]) {
+        if (output === value) {
+          output = value.slice();
+        }
+        output[i] = item;
+      }
+    }
+    return output;
+  }
+
+  let output = value;
+  for (const key of Object.keys(value)) {
+    const item = validate(value[key], keyPath(path, key), limits, fixed);
+    if (item !== value[key]) {
+      if (output === value) {
+        output = { ...value };
+      }
+      output[key] = item;
+    }
+  }
+  return output;
+}
+
+function keyPath(path, key) {
+  return /^[A-Za-z_$][A-Za-z0-9_$]*$/.test(key)
+    ? `${path}.${key}`
+    : `${path}[${JSON.stringify(key)}]`;
+}
+function validateString(value, path, limits) {
+  if (!value.isWellFormed()) {
+    if (!limits.replaceInvalidUnicode) {
+      throw new Error(`String at ${path} contains an unpaired UTF-16 surrogate`);
+    }
+
+    value = value.toWellFormed();
+  }
+
+  // Each UTF-16 code unit is at most 3 bytes in UTF-8, so skip measuring short strings.
+  if (limits.maxStringBytes !== undefined && value.length * 3 > limits.maxStringBytes) {
+    const bytes = Buffer.byteLength(value, 'utf8');
+    if (bytes > limits.maxStringBytes) {
+      throw stringTooLongError(
+        `String at ${path} is ${bytes} bytes, exceeding the limit of ${limits.maxStringBytes}`
+      );
+    }
+  }
+
+  return value;
+}
+
+// src/structured.ts
+/** Values that JSON can't represent are encoded as objects with this key naming their type. */
+const TYPE_KEY = '$type';
+
+const TYPED_ARRAYS = {
+  Int8Array,
+  Uint8Array,
+  Uint8ClampedArray,
+  Int16Array,
+  Uint16Array,
+  Int32Array,
+  Uint32Array,
+  Float32Array,
+  Float64Array,
+  BigInt64Array,
+  BigUint64Array,
+};
+
+/** The constructors that decoded values are built with. Values for a script are built with its
+ * context's constructors, so that `instanceof` checks in the script work. */
+const WORKER_REALM = { Object, Array, Date, Map, Set, ArrayBuffer, ...TYPED_ARRAYS };
+
+const realmScript = new vm.Script(`({ ${Object.keys(WORKER_REALM).join(', ')} })`);
+
+/** The constructors of a context, read before its code has had a chance to replace them. */
+function contextRealm(context) {
+  return realmScript.runInContext(context);
+}
+
+/** Encode a value as JSON-compatible data, using tagged objects for Dates, Maps, Sets, BigInts,
+ * typed arrays, `undefined`, and non-finite numbers so that they survive the trip to the host.
+ *
+ * The `util.types` checks are used instead of `instanceof` since values from a script come from
+ * a different realm. */
+function encodeValue(value, path = 'value', seen = new Set()) {
+  switch (typeof value) {
+    case 'undefined':
+      return { [TYPE_KEY]: 'undefined' };
+    case 'bigint':
+      return { [TYPE_KEY]: 'bigint', value: value.toString() };
+    case 'number':
+      return Number.isFinite(value) ? value : { [TYPE_KEY]: 'number', value: String(value) };
+    case 'function':
+    case 'symbol':
+      return undefined;
+    case 'object':
+      break;
+    default:
+      return value;
+  }
+
+  if (value === null) {
+    return null;
+  }
+
+  if (seen.has(value)) {
+    throw new Error(`Value at ${path} contains a circular reference`);
+  }
+  seen.add(value);
+
+  try {
+    if (types.isDate(value)) {
+      return { [TYPE_KEY]: 'date', value: value.getTime() };
+    }
+
+    if (types.isMap(value)) {
+      return {
+        [TYPE_KEY]: 'map',
+        value: Array.from(value.entries(), ([k, v], i) => [
+          encodeValue(k, `${path}.<key ${i}>`, seen),
+          encodeValue(v, `${path}.<value ${i}>`, seen),
+        ]),
+      };
+    }
+
+    if (types.isSet(value)) {
+      return {
+        [TYPE_KEY]: 'set',
+        value: Array.from(value, (v, i) => encodeValue(v, `${path}.<item ${i}>`, seen)),
+      };
+    }
+
+    if (types.isTypedArray(value)) {
+      const bytes = Buffer.from(value.buffer, value.byteOffset, value.byteLength);
+      return {
+        [TYPE_KEY]: 'typedarray',
+        kind: Object.prototype.toString.call(value).slice(8, -1),
+        value: bytes.toString('base64'),
+      };
+    }
+
+    if (Array.isArray(value)) {
+      return value.map((item, i) => {
+        const encoded = encodeValue(item, `${path}[${i}]`, seen);
+        // Match JSON, which turns functions in arrays into null.
+        return encoded === undefined ? null : encoded;
+      });
+    }
+
+    const output = {};
+    for (const key of Object.keys(value)) {
+      const encoded = encodeValue(value[key], `${path}.${key}`, seen);
+      if (encoded !== undefined) {
+        output[key] = encoded;
+      }
+    }
+
+    if (TYPE_KEY in output) {
+      // Escape objects that look like tagged values.
+      return { [TYPE_KEY]: 'object', value: output };
+    }
+
+    return output;
+  } finally {
+    seen.delete(value);
+  }
+}
+
+/** Decode a value from the host, turning tagged objects back into the values they represent.
+ * Objects are built with the constructors from `realm`. */
+function decodeValue(value, realm = WORKER_REALM) {
+  if (value === null || typeof value !== 'object') {
+    return value;
+  }
+
+  if (Array.isArray(value)) {
+    return realm.Array.from(value, (item) => decodeValue(item, realm));
+  }
+
+  switch (value[TYPE_KEY]) {
+    case undefined:
+      break;
+    case 'undefined':
+      return undefined;
+    case 'bigint':
+      return BigInt(value.value);
+    case 'number':
+      return Number(value.value);
+    case 'date':
+      return new realm.Date(value.value);
+    case 'map':
+      return new realm.Map(
+        value.value.map(([k, v]) => [decodeValue(k, realm), decodeValue(v, realm)])
+      );
+    case 'set':
+      return new realm.Set(value.value.map((item) => decodeValue(item, realm)));
+    case 'typedarray': {
+      if (!Object.hasOwn(TYPED_ARRAYS, value.kind)) {
+        throw new Error(`Unknown typed array kind ${value.kind}`);
+      }
+      const bytes = Buffer.from(value.value, 'base64');
+      // Copy into a new buffer to get the alignment that the array type needs.
+      const buffer = new realm.ArrayBuffer(bytes.byteLength);
+      new Uint8Array(buffer).set(bytes);
+      return new realm[value.kind](buffer);
+    }
+    case 'object':
+      return decodeObject(value.value, realm);
+    default:
+      throw new Error(`Unknown value type ${value[TYPE_KEY]}`);
+  }
+
+  return decodeObject(value, realm);
+}
+
+/** Decode each property of `value`. Keys are defined rather than assigned, so that a key such as
+ * `__proto__` is an ordinary property instead of setting the prototype. */
+function decodeObject(value, realm = WORKER_REALM) {
+  const output = new realm.Object();
+  for (const [key, item] of Object.entries(value)) {
+    Object.defineProperty(output, key, {
+      value: decodeValue(item, realm),
+      writable: true,
+      enumerable: true,
+      configurable: true,
+    });
+  }
+  return output;
+}
+
+// src/inspector.ts
+/** Set once a debugger has attached, until the debug run finishes. A debugger that stays attached
+ * doesn't announce itself again, so other debug runs in the meantime just pause at their
+ * `debugger` statement instead of waiting. */
+let debuggerAttached = false;
+
+/** Open the inspector if needed, tell the host where to connect, and block until a debugger
+ * attaches. This stops the whole worker, including runs on other connections. */
+async function waitForDebugger(ctx) {
+  if (!inspector.url()) {
+    inspector.open(0, '127.0.0.1', false);
+  }
+
+  const url = inspector.url() ?? '';
+  if (debuggerAttached) {
+    return;
+  }
+
+  debug(`Request ${ctx.reqId} is waiting for a debugger at ${url}`);
+  const message = { pid: process.pid, url };
+  // Make sure the host gets the URL before the worker blocks.
+  await new Promise((resolve) =>
+    ctx.protocol.sendMessage(
+      ctx.reqId,
+      WorkerToHostMessage.DebuggerWaiting,
+      JSON.stringify(message),
+      resolve
+    )
+  );
+
+  inspector.waitForDebugger();
+  debuggerAttached = true;
+}
+
+/** Called when a debug run finishes, so that the next one waits for a debugger again rather than
+ * running straight through once the debugger has gone. */
+function debugRunFinished() {
+  debuggerAttached = false;
+}
+
+/** Prefix code with a `debugger` statement, on the same line to keep the line numbers intact. */
+function withBreakpoint(code) {
+  return `debugger; ${code}`;
+}
+
+function post(session, method, params) {
+  return new Promise((resolve, reject) =>
+    session.post(method, params, (err, result) => (err ? reject(err) : resolve(result)))
+  );
+}
+
+/** Finer than V8's default of 1ms, since most runs are short. */
+const PROFILE_SAMPLING_INTERVAL_US = 100;
+
+/** Start recording a CPU profile. The returned function stops the profiler and returns the
+ * profile as `.cpuprofile` JSON. */
+async function startProfiling() {
+  const session = new inspector.Session();
+  session.connect();
+  try {
+    await post(session, 'Profiler.enable');
+    await post(session, 'Profiler.setSamplingInterval', { interval: PROFILE_SAMPLING_INTERVAL_US });
+    await post(session, 'Profiler.start');
+  } catch (e) {
+    session.disconnect();
+    throw e;
+  }
+
+  return async () => {
+    try {
+      const { profile } = await post(session, 'Profiler.stop');
+      return JSON.stringify(profile);
+    } finally {
+      session.disconnect();
+    }
+  };
+}
+
+/** Take a heap snapshot of the whole worker and send it to the host in chunks. Each chunk waits
+ * for the previous one to be written, so that a large snapshot isn't buffered in memory. */
+async function sendHeapSnapshot(ctx) {
+  let size = 0;
+  for await (const chunk of getHeapSnapshot()) {
+    size += chunk.length;
+    await new Promise((resolve) =>
+      ctx.protocol.sendMessage(ctx.reqId, WorkerToHostMessage.HeapSnapshotChunk, chunk, resolve)
+    );
+  }
+  debug(`Request ${ctx.reqId} sent a ${size} byte heap snapshot`);
+}
+
+// src/select.ts
+/** Path selectors for `returnKeys`, which pick values out of the context.
+ *
+ *  A selector is a list of property names separated by `.`, with `[n]` to index into an array and
+ *  `["name"]` for names containing other characters. `*`, or `[*]`, selects every element of an
+ *  array, as an array, or every property of an object, as an object. A `*` inside a name matches
+ *  any characters, and selects the matching properties as an object.
+ *
+ *  e.g. `user.profile.name`, `items[*].id`, `cache.user_*`
+ * */
+
+
+
+
+
+
+
+
+
+
+
+const PLAIN_KEY = /^[^.[\]*]+$/;
+
+function nameSegment(name, selector) {
+  if (!name) {
+    throw new Error(`Invalid return key ${JSON.stringify(selector)}: empty property name`);
+  }
+
+  if (name === '*') {
+    return { kind: 'wildcard' };
+  }
+
+  if (name.includes('*')) {
+    const escaped = name.replace(/[.+?^${}()|[\]\\]/g, '\\$&').replace(/\*/g, '.*');
+    return { kind: 'glob', pattern: new RegExp(`^${escaped}$`) };
+  }
+
+  return { kind: 'key', key: name };
+}
+
+/** Parse a selector into its segments. Throws if the selector is malformed. */
+function parseSelector(selector) {
+  if (PLAIN_KEY.test(selector)) {
+    return [{ kind: 'key', key: selector }];
+  }
+
+  const segments = [];
+  let pos = 0;
+  // Names are allowed at the start and after a `.`.
+  let expectName = true;
+  while (pos < selector.length) {
+    const c = selector[pos];
+    if (c === '[') {
+      const end = selector.indexOf(']', pos);
+      if (end === -1) {
+        throw new Error(`Invalid return key ${JSON.stringify(selector)}: unclosed [`);
+      }
+
+      const inner = selector.slice(pos + 1, end);
+      if (inner === '*') {
+        segments.push({ kind: 'wildcard' });
+      } else if (/^\d+$/.test(inner)) {
+        segments.push({ kind: 'index', index: parseInt(inner, 10) });
+      } else if (/^(".*"|'.*')$/.test(inner)) {
+        segments.push({ kind: 'key', key: inner.slice(1, -1) });
+      } else {
+        throw new Error(`Invalid return key ${JSON.stringify(selector)}: bad index [${inner}]`);
+      }
+
+      pos = end + 1;
+      expectName = false;
+    } else if (c === '.' && !expectName) {
+      pos += 1;
+      expectName = true;
+    } else if (expectName) {
+      const match = /^[^.[\]]*/.exec(selector.slice(pos));
+      const name = match ? match[0] : '';
+      segments.push(nameSegment(name, selector));
+      pos += name.length;
+      expectName = false;
+    } else {
+      throw new Error(`Invalid return key ${JSON.stringify(selector)}: unexpected ${c}`);
+    }
+  }
+
+  if (expectName) {
+    throw new Error(`Invalid return key ${JSON.stringify(selector)}: ends with .`);
+  }
+
+  return segments;
+}
+
+function selectSegments(value, segments, i) {
+  if (i === segments.length) {
+    return value;
+  }
+
+  if (value === null || (typeof value !== 'object' && typeof value !== 'function')) {
+    return undefined;
+  }
+
+  const segment = segments[i];
+  switch (segment.kind) {
+    case 'key':
+      return selectSegments(value[segment.key], segments, i + 1);
+    case 'index':
+      if (!Array.isArray(value)) {
+        return undefined;
+      }
+      return selectSegments(value[segment.index], segments, i + 1);
+    case 'wildcard':
+      if (Array.isArray(value)) {
+        return value.map((v) => selectSegments(v, segments, i + 1));
+      }
+      return Object.fromEntries(
+        Object.entries(value).map(([k, v]) => [k, selectSegments(v, segments, i + 1)])
+      );
+    case 'glob':
+      return Object.fromEntries(
+        Object.entries(value)
+          .filter(([k]) => segment.pattern.test(k))
+          .map(([k, v]) => [k, selectSegments(v, segments, i + 1)])
+      );
+  }
+}
+
+/** Evaluate each selector against `root`, returning the results keyed by the selectors. Selectors
+ * whose path doesn't exist are left out. */
+function selectPaths(root, selectors) {
+  const result = {};
+  for (const selector of selectors) {
+    const value = selectSegments(root, parseSelector(selector), 0);
+    if (value !== undefined) {
+      result[selector] = value;
+    }
+  }
+  return result;
+}
+
+// src/serializable.ts
+function errorMessage(e) {
+  return e instanceof Error ? e.message : String(e);
+}
+
+/** Why `value` can't be converted to JSON, or undefined if it can. Functions and symbols inside
+ * objects are left out by `JSON.stringify` as usual, and aren't reported. */
+function unserializableReason(
+  value,
+  path,
+  ancestors = new Set()
+) {
+  if (typeof value === 'bigint') {
+    return `${path} is a BigInt`;
+  }
+
+  if (value === null || typeof value !== 'object') {
+    return undefined;
+  }
+
+  if (ancestors.has(value)) {
+    return `${path} is a circular reference`;
+  }
+
+  ancestors.add(value);
+  try {
+    if (typeof value.toJSON === 'function') {
+      let converted;
+      try {
+        converted = value.toJSON();
+      } catch (e) {
+        return `${path}.toJSON() threw: ${errorMessage(e)}`;
+      }
+      return unserializableReason(converted, path, ancestors);
+    }
+
+    if (Array.isArray(value)) {
+      for (let i = 0; i < value.length; i++) {
+        const reason = unserializableReason(value[i], `${path}[${i}]`, ancestors);
+        if (reason) {
+          return reason;
+        }
+      }
+      return undefined;
+    }
+
+    for (const key of Object.keys(value)) {
+      const reason = unserializableReason(value[key], `${path}.${key}`, ancestors);
+      if (reason) {
+        return reason;
+      }
+    }
+    return undefined;
+  } catch (e) {
+    // Proxies and host objects can throw when they are read.
+    return `reading ${path} threw: ${errorMessage(e)}`;
+  } finally {
+    ancestors.delete(value);
+  }
+}
+
+/** Why a global that was asked for by name holds nothing that can be sent. */
+function unsendableReason(value, key) {
+  switch (typeof value) {
+    case 'function':
+      return `${key} is a function`;
+    case 'symbol':
+      return `${key} is a symbol`;
+  }
+}
+
+/** Split the globals into the ones that can be sent to the host and the ones that can't.
+ *
+ * Functions and symbols are only reported if `requested` is set, meaning that the globals were
+ * picked by `returnKeys`, since a context normally holds plenty of functions that nobody expects
+ * to get back. They are still left out either way.
+ *
+ * With `extended` set, the globals are encoded for `extendedValues` along the way. */
+function serializableGlobals(
+  globals,
+  requested,
+  extended
+) {
+  const output = {};
+  const skipped = [];
+
+  for (const key of Object.keys(globals)) {
+    let value;
+    let reason;
+    try {
+      value = globals[key];
+      reason = requested ? unsendableReason(value, key) : undefined;
+      if (!reason && extended) {
+        value = encodeValue(value, `globals.${key}`);
+      } else if (!reason) {
+        reason = unserializableReason(value, key);
+      }
+    } catch (e) {
+      reason = errorMessage(e);
+    }
+
+    if (reason) {
+      skipped.push({ key, reason });
+    } else if (value !== undefined) {
+      output[key] = value;
+    }
+  }
+
+  return { globals: output, skipped };
+}
+
+// src/kv.ts
+function checkKey(key, name) {
+  if (typeof key !== 'string') {
+    throw new TypeError(`kv.${name}: key must be a string`);
+  }
+}
+
+/** The `kv` global, which stores values on the host. `currentRequest` returns the request that
+ * the calling code belongs to, whose connection carries the call. */
+function createKv(currentRequest) {
+  const call = (request) => {
+    const ctx = currentRequest();
+    const host = (request) => ctx.protocol.kvRequest(ctx.reqId, request);
+    return ctx.dryRun ? ctx.dryRun.kv(request, host) : host(request);
+  };
+
+  return Object.freeze({
+    async get(key) {
+      checkKey(key, 'get');
+      return call({ op: 'get', key });
+    },
+    async set(key, value) {
+      checkKey(key, 'set');
+      // Round-trip through JSON so that the error shows up here, rather than on the host.
+      const json = JSON.stringify(value);
+      if (json === undefined) {
+        throw new TypeError(`kv.set: the value for ${key} can't be converted to JSON`);
+      }
+      await call({ op: 'set', key, value: JSON.parse(json) });
+    },
+    async delete(key) {
+      checkKey(key, 'delete');
+      return call({ op: 'delete', key });
+    },
+    async list(prefix = '') {
+      checkKey(prefix, 'list');
+      return call({ op: 'list', prefix });
+    },
+  });
+}
+
+// src/timers.ts
+/** The most timers that one `advance` runs, so that a short interval can't keep the worker busy
+ * indefinitely. */
+const MAX_TIMERS_PER_ADVANCE = 100_000;
+
+/** The `Date` global for a context on virtual time. `new Date()`, `Date()`, and `Date.now()` read
+ * the virtual clock, and everything else behaves like the context's own `Date`. */
+function virtualDate(ContextDate, now) {
+  // A function rather than a class, since `Date` can also be called without `new`.
+  function VirtualDate(...args) {
+    if (!new.target) {
+      return new ContextDate(now()).toString();
+    }
+    return Reflect.construct(ContextDate, args.length === 0 ? [now()] : args, new.target);
+  }
+
+  Object.setPrototypeOf(VirtualDate, ContextDate);
+  VirtualDate.prototype = ContextDate.prototype;
+  Object.defineProperty(VirtualDate, 'now', {
+    value: () => now(),
+    writable: true,
+    configurable: true,
+  });
+  return VirtualDate ;
+}
+
+/** The timers of one context. Timers set by the context's code get numeric IDs, like in a
+ * browser, so that clearing them works the same way in every mode. Each timer belongs to the run
+ * that set it, as given by `owner`, so that a run can wait for or cancel just its own timers.
+ *
+ * In `real` mode, timers run on the worker's own clock. In `virtual` mode, they only run when
+ * the host calls `advance`. In `disabled` mode, setting a timer throws. */
+class ContextTimers {
+  mode;
+  /** The virtual clock, in milliseconds since the epoch. */
+  now;
+  /** The most timers that can be set during the current run, or null for no limit. */
+  maxTimers = null;
+  /** How many timers have been set during the current run. */
+  created = 0;
+  /** The most timers that one `advance` runs. */
+  maxTimersPerAdvance = MAX_TIMERS_PER_ADVANCE;
+
+  realTimers = new Map();
+  virtualTimers = new Map();
+  nextId = 1;
+  nextSeq = 0;
+  /** Called when the last real timer of a run runs or is cleared, with the run that each one
+   * waits for, or undefined to wait for every run. */
+  idleWaiters = new Map();
+  /** Called with errors thrown by timer callbacks. */
+  reportError;
+  /** The run that is setting a timer. */
+  owner;
+
+  constructor(mode, reportError, now = Date.now(), owner = () => undefined) {
+    this.mode = mode;
+    this.now = now;
+    this.reportError = reportError;
+    this.owner = owner;
+  }
+
+  /** Reset the count of timers for a new run. */
+  startRun(maxTimers) {
+    this.maxTimers = maxTimers ?? null;
+    this.created = 0;
+  }
+
+  /** The number of timers that haven't run or been cleared. */
+  get pending() {
+    return this.realTimers.size + this.virtualTimers.size;
+  }
+
+  /** The timer functions to place in the context. */
+  globals() {
+    return {
+      setTimeout: (callback, delay, ...args) =>
+        this.set(callback, delay, args, false),
+      setInterval: (callback, delay, ...args) =>
+        this.set(callback, delay, args, true),
+      clearTimeout: (id) => this.clear(id),
+      clearInterval: (id) => this.clear(id),
+    };
+  }
+
+  set(callback, delay, args, repeat) {
+    if (this.mode === 'disabled') {
+      throw new Error('Timers are disabled in this context');
+    }
+
+    if (typeof callback !== 'function') {
+      throw new TypeError('The timer callback must be a function');
+    }
+
+    if (this.maxTimers !== null && this.created >= this.maxTimers) {
+      throw new RangeError(`Too many timers: a run can set at most ${this.maxTimers}`);
+    }
+    this.created += 1;
+
+    // Like Node.js, treat delays below 1ms, or that aren't numbers, as 1ms.
+    const ms = Math.max(Number(delay) || 0, 1);
+    const id = this.nextId++;
+    const owner = this.owner();
+    const run = () => {
+      try {
+        callback(...args);
+      } catch (e) {
+        this.reportError(e);
+      }
+    };
+
+    if (this.mode === 'virtual') {
+      this.virtualTimers.set(id, {
+        id,
+        due: this.now + ms,
+        seq: this.nextSeq++,
+        interval: repeat ? ms : null,
+        callback: run,
+        owner,
+      });
+    } else if (repeat) {
+      this.realTimers.set(id, { handle: setInterval(run, ms), owner });
+    } else {
+      const handle = setTimeout(() => {
+        this.realTimers.delete(id);
+        run();
+        this.checkIdle();
+      }, ms);
+      this.realTimers.set(id, { handle, owner });
+    }
+
+    return id;
+  }
+
+  clear(id) {
+    if (typeof id !== 'number') {
+      return;
+    }
+
+    const timer = this.realTimers.get(id);
+    if (timer) {
+      clearTimeout(timer.handle);
+      this.realTimers.delete(id);
+      this.checkIdle();
+    }
+    this.virtualTimers.delete(id);
+  }
+
+  /** Resolves once no real timers set by `owner`, or by any run if it is undefined, are waiting
+   * to run. Virtual timers only run when the host advances the clock, so they aren't waited
+   * for. */
+  idle(owner) {
+    if (!this.hasRealTimers(owner)) {
+      return Promise.resolve();
+    }
+    return new Promise((resolve) => this.idleWaiters.set(resolve, owner));
+  }
+
+  hasRealTimers(owner) {
+    for (const timer of this.realTimers.values()) {
+      if (owner === undefined || timer.owner === owner) {
+        return true;
+      }
+    }
+    return false;
+  }
+
+  checkIdle() {
+    for (const [resolve, owner] of this.idleWaiters) {
+      if (!this.hasRealTimers(owner)) {
+        this.idleWaiters.delete(resolve);
+        resolve();
+      }
+    }
+  }
+
+  /** The virtual timer that is due next, if it is due by `limit`. */
+  nextDue(limit) {
+    let next;
+    for (const timer of this.virtualTimers.values()) {
+      if (
+        timer.due <= limit &&
+        (!next || timer.due < next.due || (timer.due === next.due && timer.seq < next.seq))
+      ) {
+        next = timer;
+      }
+    }
+    return next;
+  }
+
+  /** Move the virtual clock forward by `ms`, running each timer that comes due along the way. */
+  async advance(ms) {
+    if (this.mode !== 'virtual') {
+      throw new Error('The context is not using virtual time');
+    }
+
+    const target = this.now + ms;
+    let timersRun = 0;
+    for (;;) {
+      const timer = this.nextDue(target);
+      if (!timer) {
+        break;
+      }
+      if (timersRun >= this.maxTimersPerAdvance) {
+        throw new RangeError(
+          `Advancing the clock ran ${timersRun} timers without reaching the end, so an interval ` +
+            'may be too short for the time advanced'
+        );
+      }
+
+      this.now = timer.due;
+      if (timer.interval === null) {
+        this.virtualTimers.delete(timer.id);
+      } else {
+        timer.due += timer.interval;
+        timer.seq = this.nextSeq++;
+      }
+
+      timer.callback();
+      timersRun += 1;
+      // Let promises started by the timer settle before the clock moves on.
+      await new Promise((resolve) => setImmediate(resolve));
+    }
+
+    this.now = target;
+    return { now: this.now, timersRun, pendingTimers: this.pending };
+  }
+
+  /** Cancel the timers set by `owner`, when a run cancels the work it left pending. */
+  cancel(owner) {
+    for (const [id, timer] of this.realTimers) {
+      if (timer.owner === owner) {
+        clearTimeout(timer.handle);
+        this.realTimers.delete(id);
+      }
+    }
+    for (const [id, timer] of this.virtualTimers) {
+      if (timer.owner === owner) {
+        this.virtualTimers.delete(id);
+      }
+    }
+    this.checkIdle();
+  }
+
+  /** Cancel every timer, when the context is thrown away. */
+  dispose() {
+    for (const timer of this.realTimers.values()) {
+      clearTimeout(timer.handle);
+    }
+    this.realTimers.clear();
+    this.virtualTimers.clear();
+    this.checkIdle();
+  }
+}
+
+// src/deterministic.ts
+/** A `Math.random` replacement that produces the same sequence for the same seed (mulberry32). */
+function seededRandom(seed) {
+  let state = seed >>> 0;
+  return () => {
+    state = (state + 0x6d2b79f5) >>> 0;
+    let t = state;
+    t = Math.imul(t ^ (t >>> 15), t | 1);
+    t ^= t + Math.imul(t ^ (t >>> 7), t | 61);
+    return ((t ^ (t >>> 14)) >>> 0) / 4294967296;
+  };
+}
+
+/** Remove the globals whose behavior depends on the garbage collector. */
+function removeNondeterministicGlobals(context) {
+  vm.runInContext('delete globalThis.WeakRef; delete globalThis.FinalizationRegistry;', context);
+}
+
+// src/log_budget.ts
+/** Limits on the console messages that one run can send to the host. Once either limit is
+ * reached, the rest of the run's messages are dropped, and a summary is sent with the response. */
+class LogBudget {
+  maxMessages;
+  maxBytes;
+  messages = 0;
+  bytes = 0;
+  droppedMessages = 0;
+  droppedBytes = 0;
+
+  constructor(maxMessages, maxBytes) {
+    this.maxMessages = maxMessages ?? null;
+    this.maxBytes = maxBytes ?? null;
+  }
+
+  /** Returns true if a message of `bytes` bytes can be sent, and counts it. */
+  take(bytes) {
+    const full =
+      this.droppedMessages > 0 ||
+      (this.maxMessages !== null && this.messages >= this.maxMessages) ||
+      (this.maxBytes !== null && this.bytes + bytes > this.maxBytes);
+    if (full) {
+      this.droppedMessages += 1;
+      this.droppedBytes += bytes;
+      return false;
+    }
+
+    this.messages += 1;
+    this.bytes += bytes;
+    return true;
+  }
+
+  /** A description of the dropped messages, or null if nothing was dropped. */
+  summary() {
+    if (this.droppedMessages === 0) {
+      return null;
+    }
+
+    const limits = [];
+    if (this.maxMessages !== null) {
+      limits.push(`${this.maxMessages} messages`);
+    }
+    if (this.maxBytes !== null) {
+      limits.push(`${this.maxBytes} bytes`);
+    }
+    return (
+      `Dropped ${this.droppedMessages} console messages (${this.droppedBytes} bytes) ` +
+      `over the run's limit of ${limits.join(' or ')}`
+    );
+  }
+}
+
+// src/wasm.ts
+/** Compiled modules, by the hash of their bytes. */
+const moduleCache = new LRUCache({
+  max: 32,
+});
+
+function compile(bytes) {
+  const key = createHash('sha256').update(bytes).digest('hex');
+  let mod = moduleCache.get(key);
+  if (!mod) {
+    mod = new WebAssembly.Module(bytes);
+    moduleCache.set(key, mod);
+  }
+  return mod;
+}
+
+/** Fill in the bytes of each module from the binary data that followed the arguments. */
+function attachWasmBytes(modules, binary) {
+  let offset = 0;
+  for (const mod of modules) {
+    if (offset + mod.length > binary.length) {
+      throw new Error(`WASM module ${mod.name} is missing some of its bytes`);
+    }
+    mod.bytes = binary.subarray(offset, offset + mod.length);
+    offset += mod.length;
+  }
+}
+
+/** Instantiate a module in the context, returning its exports. */
+function instantiateWasm(mod, context) {
+  if (!mod.bytes) {
+    throw new Error(`WASM module ${mod.name} has no bytes`);
+  }
+
+  let compiled;
+  try {
+    compiled = compile(mod.bytes);
+  } catch (e) {
+    const message = e instanceof Error ? e.message : String(e);
+    throw new Error(`Failed to compile WASM module ${mod.name}: ${message}`);
+  }
+
+  const imports = WebAssembly.Module.imports(compiled);
+  if (imports.length) {
+    const names = imports.map((i) => `${i.module}.${i.name}`).join(', ');
+    throw new Error(`WASM module ${mod.name} needs imports, which aren't supported: ${names}`);
+  }
+
+  // Use the context's own WebAssembly, so that the exports belong to the context.
+  const ContextWebAssembly = vm.runInContext('WebAssembly', context);
+  return new ContextWebAssembly.Instance(compiled, {}).exports;
+}
+
+// src/url_imports.ts
+/** How long to wait for a module's source before failing the import. */
+const FETCH_TIMEOUT_MS = 30_000;
+
+/** How many redirects to follow when fetching a module. */
+const MAX_REDIRECTS = 5;
+
+/** Returns true if `specifier` is an absolute HTTP or HTTPS URL. */
+function isUrl(specifier) {
+  return /^https?:\/\//i.test(specifier);
+}
+
+/** The URL that `specifier` imports, or null if it isn't a URL import. Paths imported by a module
+ * that came from a URL are resolved against that module's URL, and bare names are left for the
+ * other kinds of modules. */
+function importUrl(specifier, referrer) {
+  if (isUrl(specifier)) {
+    return new URL(specifier).href;
+  }
+
+  const isPath =
+    specifier.startsWith('/') || specifier.startsWith('./') || specifier.startsWith('../');
+  if (isPath && isUrl(referrer)) {
+    return new URL(specifier, referrer).href;
+  }
+  return null;
+}
+
+/** Returns true if `url` is under one of the allowed prefixes from the handshake. The URLs are
+ * parsed, so the origin has to match exactly and the path is compared after `..` segments are
+ * resolved. A prefix whose path doesn't end with a slash still only matches whole segments. */
+function isAllowed(url, prefixes) {
+  let parsed;
+  try {
+    parsed = new URL(url);
+  } catch {
+    return false;
+  }
+  if (parsed.username || parsed.password) {
+    return false;
+  }
+
+  return prefixes.some((prefix) => {
+    let allowed;
+    try {
+      allowed = new URL(prefix);
+    } catch {
+      return false;
+    }
+    if (parsed.origin !== allowed.origin) {
+      return false;
+    }
+    const path = allowed.pathname;
+    return (
+      parsed.pathname === path ||
+      parsed.pathname.startsWith(path.endsWith('/') ? path : `${path}/`)
+    );
+  });
+}
+
+/** Fetch a module's source, following redirects only to URLs that are also allowed. */
+async function fetchSource(url, prefixes) {
+  const signal = AbortSignal.timeout(FETCH_TIMEOUT_MS);
+  const hops = [url];
+  for (;;) {
+    const response = await fetch(url, { signal, redirect: 'manual' });
+    const location = response.headers.get('location');
+    if (response.status >= 300 && response.status < 400 && location) {
+      if (hops.length > MAX_REDIRECTS) {
+        throw moduleError(`Too many redirects fetching ${hops[0]}`);
+      }
+      url = new URL(location, url).href;
+      if (!isAllowed(url, prefixes)) {
+        throw moduleError(`Import of ${hops[0]} redirected to ${url}, which is not allowed`);
+      }
+      hops.push(url);
+      continue;
+    }
+
+    if (!response.ok) {
+      throw moduleError(`Failed to fetch ${url}: ${response.status} ${response.statusText}`);
+    }
+    return { url, hops, code: await response.text() };
+  }
+}
+
+/** The source of modules imported from URLs, shared by every connection to the worker. */
+class UrlModuleCache {
+  entries = new LRUCache({ max: 256 });
+  /** Fetches in progress, so that concurrent imports of a URL only fetch it once. */
+  pending = new Map();
+  fetchSource;
+
+  constructor(fetch = fetchSource) {
+    this.fetchSource = fetch;
+  }
+
+  /** Get the module at `url`, fetching it if the cached copy is older than `ttlMs`. Redirects
+   * are only followed to URLs under `prefixes`. The cache is shared by connections with
+   * different prefixes, so callers check the redirects of a cached module themselves. */
+  get(url, ttlMs, prefixes) {
+    const entry = this.entries.get(url);
+    if (entry && Date.now() - entry.fetchedAt < ttlMs) {
+      return Promise.resolve(entry.module);
+    }
+
+    let pending = this.pending.get(url);
+    if (!pending) {
+      pending = this.fetchSource(url, prefixes)
+        .then((module) => {
+          this.entries.set(url, { module, fetchedAt: Date.now() });
+          return module;
+        })
+        .finally(() => this.pending.delete(url));
+      this.pending.set(url, pending);
+    }
+    return pending;
+  }
+}
+
+const urlModules = new UrlModuleCache();
+
+// src/module_graph.ts
+/** Suffixes tried, in order, when a specifier doesn't match a module name exactly. */
+const SUFFIXES = ['', '.js', '.mjs', '/index.js', '/index.mjs'];
+
+/** The name that a module is stored under, with `./` and `..` segments resolved. Modules from
+ * URLs are stored under their URL. */
+function moduleName(name) {
+  if (isUrl(name)) {
+    return name;
+  }
+  return path.posix.normalize(name).replace(/^\.\//, '');
+}
+
+/** Join a relative specifier to the name of the importing module, as if the names were file
+ * paths. Other specifiers are returned unchanged. */
+function joinSpecifier(specifier, referrer) {
+  const relative = specifier.startsWith('./') || specifier.startsWith('../');
+  if (!relative) {
+    return specifier;
+  }
+  return moduleName(path.posix.join(path.posix.dirname(referrer), specifier));
+}
+
+/** Find the module that `specifier` refers to. Relative specifiers are resolved against the name
+ * of the importing module. */
+function resolveSpecifier(
+  specifier,
+  referrer,
+  modules
+) {
+  const base = joinSpecifier(specifier, referrer);
+  for (const suffix of SUFFIXES) {
+    const name = moduleName(base + suffix);
+    if (Object.hasOwn(modules, name)) {
+      return name;
+    }
+  }
+  return undefined;
+}
+
+/** Find an import cycle reachable from `root`, to explain errors from modules that used each
+ * other's exports too early. Returns the modules along the cycle, starting and ending with the
+ * same one, or null if there is no cycle. */
+function findCycle(root, dependencies) {
+  const done = new Set();
+  const stack = [];
+
+  const visit = (name) => {
+    const index = stack.indexOf(name);
+    if (index !== -1) {
+      return [...stack.slice(index), name];
+    }
+    if (done.has(name)) {
+      return null;
+    }
+
+    stack.push(name);
+    for (const dep of dependencies(name)) {
+      const cycle = visit(dep);
+      if (cycle) {
+        return cycle;
+      }
+    }
+    stack.pop();
+    done.add(name);
+    return null;
+  };
+
+  return visit(root);
+}
+
+// src/secrets.ts
+/** What a secret's value is replaced with in anything sent back to the host. */
+const REDACTED = '[REDACTED]';
+
+/** Replaces the values of a run's secrets in logs, errors, and responses. */
+class Redactor {
+  /** Longest first, so that a secret containing another is replaced whole. */
+  values;
+
+  constructor(secrets) {
+    this.values = [...new Set(Object.values(secrets))]
+      .filter((value) => value.length > 0)
+      .sort((a, b) => b.length - a.length);
+  }
+
+  redact(text) {
+    for (const value of this.values) {
+      if (text.includes(value)) {
+        text = text.replaceAll(value, REDACTED);
+      }
+    }
+    return text;
+  }
+
+  /** Redact every string in a value, in the same way that `JSON.stringify` would visit it. */
+  redactValue(value, seen = new Set()) {
+    if (typeof value === 'string') {
+      return this.redact(value);
+    }
+    if (value === null || typeof value !== 'object') {
+      return value;
+    }
+    if (typeof value.toJSON === 'function') {
+      return this.redactValue(value.toJSON(), seen);
+    }
+    if (seen.has(value) || ArrayBuffer.isView(value)) {
+      return value;
+    }
+
+    seen.add(value);
+    if (Array.isArray(value)) {
+      return value.map((item) => this.redactValue(item, seen));
+    }
+    return Object.fromEntries(
+      Object.entries(value).map(([key, item]) => [this.redact(key), this.redactValue(item, seen)])
+    );
+  }
+
+  /** A copy of a thrown value with the secrets removed from its message and stack, and from its
+   * causes and aggregated errors. The name and code are kept, since the host uses them to tell
+   * errors apart. */
+  redactError(e, seen = new Set()) {
+    const message = typeof e?.message === 'string' ? e.message : String(e);
+    const redacted = new Error(this.redact(message));
+    redacted.stack = typeof e?.stack === 'string' ? this.redact(e.stack) : undefined;
+    if (typeof e?.name === 'string') {
+      redacted.name = e.name;
+    }
+    if (typeof e?.code === 'string') {
+      Object.assign(redacted, { code: e.code });
+    }
+
+    if (typeof e === 'object' && e !== null && !seen.has(e)) {
+      seen.add(e);
+      if (e.cause !== undefined) {
+        redacted.cause = this.redactError(e.cause, seen);
+      }
+      if (Array.isArray(e.errors)) {
+        const errors = e.errors.map((item) => this.redactError(item, seen));
+        Object.assign(redacted, { errors });
+      }
+    }
+    return redacted;
+  }
+}
+
+// src/abort.ts
+/** How long a run has to finish cleaning up after its signal aborts, before it fails anyway. */
+const ABORT_GRACE_MS = 1000;
+
+/** The reason given to a run's signal when the host cancels it. */
+function cancelledError() {
+  return new DOMException('The run was cancelled by the host', 'AbortError');
+}
+
+/** The reason given to a run's signal when its timeout passes. */
+function timeoutError(ms) {
+  return new DOMException(`The run timed out after ${ms}ms`, 'TimeoutError');
+}
+
+/** True if the run failed because it passed its timeout, whether that interrupted synchronous code
+ * or aborted the run's signal while it waited on promises. */
+function isTimeout(e) {
+  return (
+    e?.code === 'ERR_SCRIPT_EXECUTION_TIMEOUT' ||
+    (e instanceof DOMException && e.name === 'TimeoutError')
+  );
+}
+
+/** Abort the controller once `ms` have passed, returning a function that stops the timer. */
+function abortAfter(controller, ms) {
+  const timer = setTimeout(() => controller.abort(timeoutError(ms)), ms);
+  return () => clearTimeout(timer);
+}
+
+/** Wait for a run to finish, or fail with the signal's reason if it aborts first. The listeners on
+ * the signal run as soon as it aborts, and the run then has `graceMs` to settle, so that
+ * cooperative code can clean up and log what it did. A run that ignores the signal keeps going in
+ * the background, but its result is thrown away. */
+function untilAborted(
+  promise,
+  signal,
+  graceMs = ABORT_GRACE_MS
+) {
+  return new Promise((resolve, reject) => {
+    let grace;
+    const onAbort = () => {
+      grace = setTimeout(() => reject(signal.reason), graceMs);
+    };
+
+    if (signal.aborted) {
+      onAbort();
+    } else {
+      signal.addEventListener('abort', onAbort, { once: true });
+    }
+
+    promise
+      .then(
+        (value) => (signal.aborted ? reject(signal.reason) : resolve(value)),
+        (e) => reject(signal.aborted ? signal.reason : e)
+      )
+      .finally(() => {
+        clearTimeout(grace);
+        signal.removeEventListener('abort', onAbort);
+      });
+  });
+}
+
+// src/queue.ts
+/** Values that arrive from the host for a run, waiting for the script to read them. Reading waits
+ * for the next value, and stops with the signal's reason if the run is cancelled or times out.
+ * Values are only kept until they are read, so each one is read once. */
+class AsyncQueue {
+  values = [];
+  ended = false;
+  signal;
+  /** Wakes up the reader waiting for the next value. */
+  wake = null;
+
+  constructor(signal) {
+    this.signal = signal;
+    signal?.addEventListener('abort', () => this.notify(), { once: true });
+  }
+
+  push(value) {
+    if (!this.ended) {
+      this.values.push(value);
+      this.notify();
+    }
+  }
+
+  end() {
+    this.ended = true;
+    this.notify();
+  }
+
+  notify() {
+    this.wake?.();
+    this.wake = null;
+  }
+
+  /** The next value, or undefined once the queue has ended and every value has been read. */
+  async next() {
+    for (;;) {
+      this.signal?.throwIfAborted();
+      if (this.values.length) {
+        return this.values.shift();
+      } else if (this.ended) {
+        return undefined;
+      }
+      await new Promise((resolve) => (this.wake = resolve));
+    }
+  }
+
+  async *[Symbol.asyncIterator]() {
+    for (;;) {
+      const value = await this.next();
+      if (value === undefined) {
+        return;
+      }
+      yield value;
+    }
+  }
+}
+
+// src/input.ts
+/** The `input` global of a run with `streamInput` set, which yields the chunks that the host sends
+ * with Input messages until it sends InputEnd. */
+class InputStream extends AsyncQueue {
+  /** The chunks decoded as UTF-8, for scripts that read text. Contexts have no `TextDecoder` of
+   * their own, and a character can be split between two chunks. */
+  async *text() {
+    const decoder = new TextDecoder();
+    for await (const chunk of this) {
+      const text = decoder.decode(chunk, { stream: true });
+      if (text) {
+        yield text;
+      }
+    }
+    const rest = decoder.decode();
+    if (rest) {
+      yield rest;
+    }
+  }
+}
+
+// src/channel.ts
+/** The `channel` global, which exchanges JSON values with the host while the run is going. Values
+ * from the host are read with `receive()` or `for await`, which finish once the host closes its
+ * side. Values sent after the run finishes are ignored by the host. */
+class ScriptChannel {
+  ctx;
+  incoming;
+
+  constructor(ctx) {
+    this.ctx = ctx;
+    this.incoming = new AsyncQueue(ctx.abort?.signal);
+  }
+
+  /** Send a value to the host, resolving once it has been written out. */
+  send(value) {
+    const { ctx } = this;
+    const json = JSON.stringify(ctx.redactor ? ctx.redactor.redactValue(value) : value);
+    if (json === undefined) {
+      throw new TypeError("channel.send: the value can't be converted to JSON");
+    }
+    if (ctx.dryRun) {
+      ctx.dryRun.channel(JSON.parse(json));
+      return Promise.resolve();
+    }
+    return ctx.protocol.channelMessage(ctx.reqId, json);
+  }
+
+  /** The next value from the host, or undefined once the host has closed the channel. */
+  receive() {
+    return this.incoming.next();
+  }
+
+  [Symbol.asyncIterator]() {
+    return this.incoming[Symbol.asyncIterator]();
+  }
+}
+
+// src/dry_run.ts
+/** Marks a key that the dry run deleted. */
+const DELETED = Symbol('deleted');
+
+/** The effects of a run with `dryRun` set, which are recorded instead of reaching the host. Reads
+ * from `kv` still go to the host, with the run's own writes laid over what it returns, so that the
+ * script sees the same values as it would in a real run. Values sent on the channel are recorded
+ * too, while values from the host can still be received. */
+class DryRun {
+  effects = [];
+  /** The values that the run wrote to `kv`, by key */
+  writes = new Map();
+
+  async kv(request, host) {
+    switch (request.op) {
+      case 'get': {
+        if (!this.writes.has(request.key)) {
+          return host(request);
+        }
+        const value = this.writes.get(request.key);
+        return value === DELETED ? null : value;
+      }
+      case 'set':
+        this.effects.push({ type: 'kv', ...request });
+        this.writes.set(request.key, request.value);
+        return null;
+      case 'delete': {
+        const existed = this.writes.has(request.key)
+          ? this.writes.get(request.key) !== DELETED
+          : (await host({ op: 'get', key: request.key })) !== null;
+        this.effects.push({ type: 'kv', ...request });
+        this.writes.set(request.key, DELETED);
+        return existed;
+      }
+      case 'list': {
+        const keys = new Set(await host(request));
+        for (const [key, value] of this.writes) {
+          if (!key.startsWith(request.prefix)) {
+            continue;
+          }
+          if (value === DELETED) {
+            keys.delete(key);
+          } else {
+            keys.add(key);
+          }
+        }
+        return [...keys].sort();
+      }
+    }
+  }
+
+  output(data) {
+    this.effects.push({ type: 'output', data: data.toString('base64') });
+  }
+
+  channel(value) {
+    this.effects.push({ type: 'channel', value });
+  }
+}
+
+// src/complete.ts
+const IDENTIFIER = /^[A-Za-z_$][\w$]*$/;
+
+/** A member path and the partial name after it, at the end of a line, such as `user.profile.na`.
+ * Either part can be missing, as in `user.` or `na`. */
+const TRAILING_PATH = /(?:([A-Za-z_$][\w$]*(?:\.[A-Za-z_$][\w$]*)*)\.)?([A-Za-z_$][\w$]*)?$/;
+
+/** A whole member path, such as `user.profile`. */
+const PROPERTY_PATH = /^[A-Za-z_$][\w$]*(?:\.[A-Za-z_$][\w$]*)*$/;
+
+/** Top-level `let`, `const`, and `class` declarations. These don't become properties of the
+ * global object, but scripts run later in the same context can still see them. */
+const LEXICAL_DECLARATION = /(?:^|[;}])\s*(?:let|const|class)\s+([A-Za-z_$][\w$]*)/gm;
+
+/** The names that a script may have declared with `let`, `const`, or `class`. Declarations inside
+ * blocks are matched too, and are weeded out when completing. */
+function declaredNames(code) {
+  return Array.from(code.matchAll(LEXICAL_DECLARATION), (match) => match[1]);
+}
+
+/** Every property name of a value, including inherited ones, that can be written after a dot.
+ * Listing the properties of a Proxy would run its traps, so the names stop at the first one. */
+function propertyNames(value) {
+  const names = new Set();
+  for (
+    let obj = Object(value);
+    obj && !types.isProxy(obj);
+    obj = Object.getPrototypeOf(obj)
+  ) {
+    for (const name of Object.getOwnPropertyNames(obj)) {
+      if (IDENTIFIER.test(name)) {
+        names.add(name);
+      }
+    }
+  }
+  return names;
+}
+
+/** The global names of the context, including lexical declarations that are visible at its top
+ * level. */
+function globalNames(context, lexicalNames) {
+  const names = propertyNames(vm.runInContext('globalThis', context));
+  for (const name of lexicalNames) {
+    if (isDeclared(name, context)) {
+      names.add(name);
+    }
+  }
+  return names;
+}
+
+/** Where a property is defined, which may be on the object's prototype chain. Like
+ * `propertyNames`, this stops at the first Proxy. */
+function findProperty(value, name) {
+  for (
+    let obj = Object(value);
+    obj && !types.isProxy(obj);
+    obj = Object.getPrototypeOf(obj)
+  ) {
+    const descriptor = Object.getOwnPropertyDescriptor(obj, name);
+    if (descriptor) {
+      return descriptor;
+    }
+  }
+  return undefined;
+}
+
+/** Follow a path of property names from the context's globals. Each property is read from its
+ * descriptor, so that no getter or Proxy trap runs, and a path that goes through one throws. */
+function resolvePath(path, context, lexicalNames) {
+  const [first, ...rest] = path.split('.');
+  let value;
+  const descriptor = findProperty(vm.runInContext('globalThis', context), first);
+  if (descriptor) {
+    value = readProperty(descriptor, first);
+  } else if (lexicalNames.has(first)) {
+    // Reading a lexical declaration can't run any code.
+    value = vm.runInContext(first, context);
+  } else {
+    throw new ReferenceError(`${first} is not defined`);
+  }
+
+  for (const name of rest) {
+    if (value === null || value === undefined) {
+      throw new TypeError(`Cannot read properties of ${value} (reading '${name}')`);
+    }
+    if (types.isProxy(value)) {
+      throw new TypeError(`Can't read ${name} from a Proxy without running its traps`);
+    }
+    const descriptor = findProperty(value, name);
+    value = descriptor && readProperty(descriptor, name);
+  }
+  return value;
+}
+
+/** The value of a property, unless reading it would run a getter. */
+function readProperty(descriptor, name) {
+  if (descriptor.get) {
+    throw new TypeError(`Can't read ${name} without running its getter`);
+  }
+  return descriptor.value;
+}
+
+/** The parameters of a function, as written in its source, such as `(a, b = 1)`. Built-in
+ * functions have no source to read them from. */
+function parameters(source) {
+  if (source.endsWith('{ [native code] }')) {
+    return undefined;
+  }
+  const single = /^(?:async\s+)?([A-Za-z_$][\w$]*)\s*=>/.exec(source);
+  if (single) {
+    return `(${single[1]})`;
+  }
+  const list = /^[^(]*\(([^)]*)\)/.exec(source);
+  return list ? `(${list[1].replace(/\s+/g, ' ').trim()})` : undefined;
+}
+
+/** What kind of value a property holds, without running its getter. */
+function describe(descriptor) {
+  if (descriptor.get || descriptor.set) {
+    return { kind: 'accessor' };
+  }
+
+  const { value } = descriptor;
+  if (value === null) {
+    return { kind: 'null' };
+  }
+  // Looking into a Proxy would run its traps.
+  if (types.isProxy(value)) {
+    return { kind: typeof value === 'function' ? 'function' : 'object' };
+  }
+  if (typeof value === 'function') {
+    const source = Function.prototype.toString.call(value);
+    if (/^class\b/.test(source)) {
+      return { kind: 'class' };
+    }
+    return { kind: 'function', detail: parameters(source) };
+  }
+  if (Array.isArray(value)) {
+    return { kind: 'array', detail: `Array(${value.length})` };
+  }
+  if (typeof value === 'object') {
+    const proto = Object.getPrototypeOf(value);
+    const constructor = proto && Object.getOwnPropertyDescriptor(proto, 'constructor')?.value;
+    return {
+      kind: 'object',
+      detail: typeof constructor === 'function' ? constructor.name : undefined,
+    };
+  }
+  return { kind: typeof value };
+}
+
+/** Whether a lexical declaration is visible at the top level of the context. Names that were only
+ * declared in a block, or are still uninitialized, are not. */
+function isDeclared(name, context) {
+  try {
+    return vm.runInContext(`typeof ${name} !== 'undefined'`, context) === true;
+  } catch (e) {
+    return false;
+  }
+}
+
+/** Complete the name at the end of `line` from the globals in the context, or from the properties
+ * of the value before the last dot. Only simple member paths are followed, reading each property
+ * from its descriptor, so completing never runs a getter, a Proxy trap or any other code. */
+function completeLine(line, context, lexicalNames) {
+  const match = TRAILING_PATH.exec(line);
+  const [matched, path, prefix = ''] = match;
+  const result = { prefix, candidates: [] };
+  // A name after something other than a path, as in `'text'.len` or `1.5`, can't be completed.
+  if (line[match.index - 1] === '.' || (!matched && /[\w$.]$/.test(line))) {
+    return result;
+  }
+
+  let names;
+  if (path) {
+    let target;
+    try {
+      target = resolvePath(path, context, lexicalNames);
+    } catch (e) {
+      return result;
+    }
+    if (target === null || target === undefined) {
+      return result;
+    }
+    names = propertyNames(target);
+  } else {
+    names = globalNames(context, lexicalNames);
+  }
+
+  result.candidates = [...names].filter((name) => name.startsWith(prefix)).sort();
+  return result;
+}
+
+/** The properties of the value at `path` that start with `prefix`, or the globals when `path` is
+ * empty, with hints about what each one holds. As with `completeLine`, only a simple path of
+ * names is followed, so this never runs any code in the context. Unlike `completeLine`, an error
+ * from following the path, such as one that goes through a getter, is thrown. */
+function introspect(path, prefix, context, lexicalNames) {
+  let target;
+  let names;
+  if (path) {
+    if (!PROPERTY_PATH.test(path)) {
+      throw new TypeError(`Not a path of property names: ${path}`);
+    }
+    target = resolvePath(path, context, lexicalNames);
+    if (target === null || target === undefined) {
+      return [];
+    }
+    names = propertyNames(target);
+  } else {
+    target = vm.runInContext('globalThis', context);
+    names = globalNames(context, lexicalNames);
+  }
+
+  return [...names]
+    .filter((name) => name.startsWith(prefix))
+    .sort()
+    .map((name) => {
+      // Lexical declarations aren't properties of the global object, but reading one can't run
+      // any code.
+      const descriptor = findProperty(target, name) ?? {
+        value: vm.runInContext(name, context),
+      };
+      return { name, ...describe(descriptor) };
+    });
+}
+
+// src/check.ts
+/** Module syntax that a script can't contain. Each match is blanked out, so that the rest of a
+ * module can be compiled as a script to find where its error is. */
+const IMPORT_DECLARATION = /^[ \t]*import\s*(?:[\w$*{}\s,]+?\s*from\s*)?(['"])[^'"\n]*\1/gm;
+const EXPORT_FROM = /^[ \t]*export\s*\*[^'"\n]*?from\s*(['"])[^'"\n]*\1/gm;
+const EXPORT_LIST = /^[ \t]*export\s*\{[^}]*\}(?:\s*from\s*(['"])[^'"\n]*\1)?/gm;
+const EXPORT_DEFAULT = /^([ \t]*)export default\b/gm;
+const EXPORT_KEYWORD = /^([ \t]*)export(?=\s)/gm;
+
+/** Replace everything but line breaks with spaces, so that positions after it don't move. */
+function blank(text) {
+  return text.replace(/[^\n]/g, ' ');
+}
+
+/** The 1-based line and column of an offset in the code. */
+function position(code, index) {
+  const before = code.slice(0, index);
+  const lineStart = before.lastIndexOf('\n') + 1;
+  return { line: before.split('\n').length, column: index - lineStart + 1 };
+}
+
+/** A syntax error from compiling a script. V8 starts the stack with a `name:line` header, the line
+ * of code, and carets under the error, which give its position. */
+function syntaxError(e) {
+  const message = e instanceof Error ? `${e.name}: ${e.message}` : String(e);
+  const [header, , carets] = e?.stack?.split('\n') ?? [];
+  const line = /:(\d+)$/.exec(header ?? '');
+  if (!line || !carets?.includes('^')) {
+    return { message };
+  }
+  return { message, line: Number(line[1]), column: carets.indexOf('^') + 1 };
+}
+
+/** Move an error that was found in the code wrapped around the script, past its end, to the end
+ * of the script. */
+function withinCode(error, code) {
+  const lines = code.split('\n');
+  if (error.line && error.line > lines.length) {
+    error.line = lines.length;
+    error.column = lines[lines.length - 1].length + 1;
+  }
+  return error;
+}
+
+/** Find where a module's syntax error is. V8 doesn't report positions for modules, so this
+ * compiles the module as the body of an async function instead, with its imports and exports
+ * blanked out. The position is only used if that fails with the same error. */
+function locateModuleError(code, name, error) {
+  const body = code
+    .replace(IMPORT_DECLARATION, blank)
+    .replace(EXPORT_FROM, blank)
+    .replace(EXPORT_LIST, blank)
+    // `export default class {}` needs to stay an expression.
+    .replace(EXPORT_DEFAULT, (_, indent) => `${indent}0,${' '.repeat(12)}`)
+    .replace(EXPORT_KEYWORD, (_, indent) => `${indent}${' '.repeat(6)}`)
+    .replace(/\bimport\.meta\b/g, '({})       ');
+  try {
+    new vm.Script(`(async function () {"use strict";\n${body}\n})`, {
+      filename: name,
+      lineOffset: -1,
+    });
+  } catch (e) {
+    const located = syntaxError(e);
+    if (located.message === error.message) {
+      return withinCode(located, code);
+    }
+  }
+  return error;
+}
+
+/** Imports that don't match any of the modules that the code can import. */
+function unresolvedImports(code, name, specifiers, modules) {
+  const available = Object.fromEntries(modules.map((name) => [moduleName(name), true]));
+  const referrer = moduleName(name);
+  return specifiers
+    .filter((specifier) => !isUrl(specifier) && !resolveSpecifier(specifier, referrer, available))
+    .map((specifier) => {
+      const found = [`'${specifier}'`, `"${specifier}"`]
+        .map((quoted) => code.indexOf(quoted))
+        .filter((index) => index !== -1);
+      return {
+        message: `Module not found: ${specifier}`,
+        ...(found.length ? position(code, Math.min(...found)) : {}),
+      };
+    });
+}
+
+/** Compile code without running it, and report what would keep it from running. */
+function checkScript(args) {
+  const name = args.name || '<script>';
+  const result = { errors: [], warnings: [] };
+
+  if (args.expr || args.call) {
+    // Call mode wraps the code in parentheses, like when it runs.
+    const code = args.call ? `(${args.code}\n)` : args.code;
+    try {
+      new vm.Script(code, { filename: name });
+    } catch (e) {
+      const error = syntaxError(e);
+      if (args.call && error.line === 1 && error.column) {
+        error.column -= 1;
+      }
+      result.errors.push(withinCode(error, args.code));
+    }
+    return result;
+  }
+
+  let mod;
+  try {
+    mod = new vm.SourceTextModule(args.code, { identifier: name });
+  } catch (e) {
+    result.errors.push(locateModuleError(args.code, name, syntaxError(e)));
+    return result;
+  }
+
+  if (args.lint) {
+    result.warnings = unresolvedImports(
+      args.code,
+      name,
+      mod.dependencySpecifiers,
+      args.modules ?? []
+    );
+  }
+  return result;
+}
+
+// src/context_pool.ts
+/** Values that are expensive to build, such as fresh vm contexts, built ahead of time. `take`
+ * returns a ready value if there is one, and the pool builds replacements one at a time between
+ * other work, so that building them doesn't hold up the request that took one. */
+class ContextPool {
+  ready = [];
+  size;
+  build;
+  refilling = false;
+
+  /** `ready` holds values that were already built. */
+  constructor(size, build, ready = []) {
+    this.size = size;
+    this.build = build;
+    this.ready = ready;
+    this.refill();
+  }
+
+  /** Take a ready value, or return undefined if the pool is empty. */
+  take() {
+    const value = this.ready.pop();
+    this.refill();
+    return value;
+  }
+
+  refill() {
+    if (this.refilling || this.ready.length >= this.size) {
+      return;
+    }
+
+    this.refilling = true;
+    setImmediate(() => {
+      this.refilling = false;
+      this.ready.push(this.build());
+      this.refill();
+    }).unref();
+  }
+}
+
+// src/run_script.ts
+const codeCache = new LRUCache({
+  max: 128,
+});
+
+function contentHash(code) {
+  return createHash('sha256').update(code).digest('hex');
+}
+
+/** The key for compiled code in the cache. Keys are hashed, so that the cache doesn't hold on to
+ * a second copy of large code. */
+function codeCacheKey(mode, code, params) {
+  return contentHash([mode, code, ...(params || [])].join('\0'));
+}
+
+const RUN_CTX_KEY = Symbol('runCtx');
+/** The code of the registered scripts sent on the connection, by hash. Unlike the context, these
+ * are kept for as long as the connection is open. */
+const REGISTERED_SCRIPTS_KEY = Symbol('registeredScripts');
+
+/** Call mode places a function that makes the call under this key for a moment, so that the call
+ * runs inside a script and the run's timeout can interrupt it. */
+const CALL_KEY = Symbol.for('js_sidecar.call');
+const callScript = new vm.Script('globalThis[Symbol.for("js_sidecar.call")]()');
+
+/** How long the `wait` drain policy waits for timers, unless the run says otherwise. */
+const DEFAULT_MAX_DRAIN_MS = 1000;
+
+/** The request that started the code that is currently running. Several runs can be in flight on
+ * the same context, so this follows each run through its async operations. */
+const currentRequest = new AsyncLocalStorage();
+
+
+
+/** The contexts, by the `Object.prototype` of their realm. Errors and promises created by a
+ * context's code inherit from it, which ties them back to the context. */
+const realms = new WeakMap();
+
+/** The context that created an object, if any. */
+function realmOf(value) {
+  if ((typeof value !== 'object' && typeof value !== 'function') || value === null) {
+    return undefined;
+  }
+
+  let proto = Object.getPrototypeOf(value);
+  while (proto !== null && Object.getPrototypeOf(proto) !== null) {
+    proto = Object.getPrototypeOf(proto);
+  }
+  return proto ? realms.get(proto) : undefined;
+}
+
+/** Send an error that escaped from a context's code to the request that is using the context, or
+ * that last used it. The context is found from the realm of the rejected promise or the error.
+ * Returns false if the error didn't come from any context. */
+function reportAsyncError(e, source, promise) {
+  const run = realmOf(promise) ?? realmOf(e);
+  if (!run) {
+    return false;
+  }
+
+  (currentRequest.getStore() ?? run.current).asyncError(e, source);
+  return true;
+}
+
+/** The console levels, from least to most severe. */
+const LOG_LEVELS = ['trace', 'debug', 'info', 'warn', 'error'];
+
+/** Returns true if the request only forwards messages more severe than `level`. */
+function belowMinLevel(request, level) {
+  return (
+    request.minLogLevel !== undefined &&
+    LOG_LEVELS.indexOf(level) < LOG_LEVELS.indexOf(request.minLogLevel)
+  );
+}
+
+function forwardLog(run, args, level, namespace) {
+  namespace ??= extractNamespace(args);
+  if (namespace && run.logFilter && !run.logFilter.isEnabled(namespace)) {
+    return;
+  }
+
+  const request = currentRequest.getStore() ?? run.current;
+  if (belowMinLevel(request, level)) {
+    return;
+  }
+  if (request.consoleHandler && !request.inConsoleHandler) {
+    callConsoleHandler(run, request, { level, args, namespace });
+    return;
+  }
+  request.log(args, level, namespace);
+}
+
+/** Pass a console call to the function that the run named as its console handler, along with a
+ * function that forwards a message to the host. Console calls from the handler itself are
+ * forwarded as they are. */
+function callConsoleHandler(run, request, entry) {
+  const name = request.consoleHandler;
+  const handler = run.context[name];
+  if (typeof handler !== 'function') {
+    throw new TypeError(`Console handler ${name} is not a function`);
+  }
+
+  const forward = (level, args, namespace) => {
+    if (!LOG_LEVELS.includes(level)) {
+      throw new TypeError(`Unknown log level ${level}`);
+    }
+    if (belowMinLevel(request, level)) {
+      return;
+    }
+    request.log(Array.isArray(args) ? args : [args], level, namespace ?? undefined);
+  };
+  request.inConsoleHandler = true;
+  try {
+    handler(entry, forward);
+  } finally {
+    request.inConsoleHandler = false;
+  }
+}
+
+function createConsole(run, namespace) {
+  return {
+    trace: (...args) => forwardLog(run, args, 'trace', namespace),
+    debug: (...args) => forwardLog(run, args, 'debug', namespace),
+    log: (...args) => forwardLog(run, args, 'info', namespace),
+    info: (...args) => forwardLog(run, args, 'info', namespace),
+    warn: (...args) => forwardLog(run, args, 'warn', namespace),
+    error: (...args) => forwardLog(run, args, 'error', namespace),
+  };
+}
+
+/** Define a global that reads a property of the current run. Like `kv`, it isn't enumerable, but a
+ * global of the same name from the host or the script replaces it. */
+function defineRunGlobal(context, name, get) {
+  if (Object.hasOwn(context, name)) {
+    return;
+  }
+
+  Object.defineProperty(context, name, {
+    get,
+    set: (value) => {
+      Object.defineProperty(context, name, {
+        value,
+        writable: true,
+        enumerable: true,
+        configurable: true,
+      });
+    },
+    configurable: true,
+  });
+}
+
+/** Returns true if the connection has a run context from an earlier run. */
+function hasContext(protocol) {
+  return protocol.cache.has(RUN_CTX_KEY);
+}
+
+/** Globals that a context defines for itself, which take precedence over the run's globals. */
+const CONTEXT_GLOBALS = new Set([
+  'console',
+  'logger',
+  'kv',
+  'write',
+  'stderr',
+  'setTimeout',
+  'setInterval',
+  'clearTimeout',
+  'clearInterval',
+]);
+
+/** Fresh contexts with the default settings, so that a run that recreates its context doesn't
+ * wait for one to be built. Set up by `startContextPool`. */
+let contextPool = null;
+
+/** Keep `size` fresh contexts ready for runs that create a context with real timers and without
+ * deterministic mode. */
+function startContextPool(size) {
+  contextPool = size > 0 ? new ContextPool(size, () => buildContext('real', false)) : null;
+}
+
+/** Contexts built from a prewarm manifest, by name, for runs with `prewarmedContext` set. */
+const prewarmedContexts = new Map();
+
+/** Build the context described by `args`, throwing if its functions or modules fail to compile,
+ * and keep one ready for runs that ask for it by name. The pool builds a replacement after each
+ * one is taken. Prewarming a name again replaces its contexts. */
+function prewarmContext(args) {
+  const build = () => buildPrewarmedContext(args);
+  const pool = new ContextPool(1, build, [build()]);
+
+  const previous = prewarmedContexts.get(args.name);
+  for (const runCtx of previous?.ready ?? []) {
+    runCtx.timers.dispose();
+  }
+  prewarmedContexts.set(args.name, pool);
+}
+
+function buildPrewarmedContext(args) {
+  const runCtx = buildContext('real', false, args.globals);
+  compileFunctions(runCtx, args.functions ?? []);
+  for (const modArgs of args.modules ?? []) {
+    addModule(runCtx, modArgs);
+  }
+  return runCtx;
+}
+
+/** Take a ready context for a run that creates one, from the prewarmed contexts if the run asks
+ * for them, or else from the pool of fresh contexts. Ready contexts all use real timers, so
+ * they aren't used for deterministic runs or other timer modes. */
+function takeReadyContext(args, timerMode) {
+  if (timerMode !== 'real' || args.deterministic) {
+    return undefined;
+  }
+
+  const prewarmed = args.prewarmedContext
+    ? prewarmedContexts.get(args.prewarmedContext)?.take()
+    : undefined;
+  return prewarmed ?? contextPool?.take();
+}
+
+/** Build a context with the given timer mode, in deterministic mode if `deterministic` is set,
+ * holding `globals`. */
+function buildContext(timerMode, deterministic, globals = {}) {
+  const context = vm.createContext({ ...globals });
+  const newCtx = {
+    modules: {},
+    moduleSources: new Map(),
+    hostModules: new Map(),
+    pendingHostModules: new Map(),
+    context,
+    realm: contextRealm(context),
+    // Set when a run takes the context.
+    current: undefined,
+    logFilter: null,
+    timers: new ContextTimers(
+      timerMode,
+      (e) => (currentRequest.getStore() ?? newCtx.current).asyncError(e, 'uncaughtException'),
+      deterministic ? 0 : Date.now(),
+      () => currentRequest.getStore() ?? newCtx.current
+    ),
+    seedRandom: null,
+    lexicalNames: new Set(),
+  };
+  realms.set(vm.runInContext('Object.prototype', newCtx.context), newCtx);
+
+  newCtx.context.console = createConsole(newCtx);
+  newCtx.context.logger = (namespace) => createConsole(newCtx, namespace);
+  // The signal of whichever run is using the context, for code to pass to cancellable APIs or
+  // to listen to for cleanup, and its input and channel.
+  const request = () => currentRequest.getStore() ?? newCtx.current;
+  defineRunGlobal(newCtx.context, 'signal', () => request().abort?.signal);
+  defineRunGlobal(newCtx.context, 'input', () => request().input);
+  defineRunGlobal(newCtx.context, 'channel', () => {
+    const ctx = request();
+    return (ctx.channel ??= new ScriptChannel(ctx));
+  });
+  // Not enumerable, like the built-in globals, so that they aren't sent back.
+  const { timers } = newCtx;
+  const globalFunctions = {
+    ...timers.globals(),
+    write: (chunk) => writeOutput(currentRequest.getStore() ?? newCtx.current, chunk),
+    stderr: { write: (chunk) => writeStderr(newCtx, chunk) },
+  };
+  for (const [name, fn] of Object.entries(globalFunctions)) {
+    Object.defineProperty(newCtx.context, name, {
+      value: fn,
+      writable: true,
+      configurable: true,
+    });
+  }
+  if (deterministic) {
+    removeNondeterministicGlobals(newCtx.context);
+    const ContextMath = vm.runInContext('Math', newCtx.context);
+    newCtx.seedRandom = (seed) => {
+      ContextMath.random = seededRandom(seed);
+    };
+  }
+  if (timers.mode === 'virtual' || deterministic) {
+    const ContextDate = vm.runInContext('Date', newCtx.context);
+    Object.defineProperty(newCtx.context, 'Date', {
+      value: virtualDate(ContextDate, () => timers.now),
+      writable: true,
+      configurable: true,
+    });
+  }
+  return newCtx;
+}
+
+/** Set the run's globals in the context, decoding them with the context's constructors if they
+ * use the extended encoding. Names in `skip` are left alone. */
+function setGlobals(runCtx, args, skip) {
+  const globals =
+    args.extendedValues && args.globals ? decodeObject(args.globals, runCtx.realm) : args.globals;
+  for (const [key, value] of Object.entries(globals ?? {})) {
+    if (!skip?.has(key)) {
+      runCtx.context[key] = value;
+    }
+  }
+}
+
+function createContext(ctx, args) {
+  let runCtx = ctx.protocol.cache.get(RUN_CTX_KEY);
+  if (runCtx && args.recreateContext) {
+    runCtx.timers.dispose();
+    runCtx = undefined;
+  }
+
+  if (!runCtx) {
+    // Deterministic contexts only allow virtual timers, whose clock starts at the epoch.
+    let timerMode = args.timers ?? 'real';
+    if (args.deterministic && timerMode === 'real') {
+      timerMode = 'disabled';
+    }
+
+    const pooled = takeReadyContext(args, timerMode);
+    if (pooled) {
+      runCtx = pooled;
+      setGlobals(runCtx, args, CONTEXT_GLOBALS);
+    } else if (args.extendedValues) {
+      // Extended values are decoded with the context's constructors, so it has to exist first.
+      runCtx = buildContext(timerMode, !!args.deterministic);
+      setGlobals(runCtx, args, CONTEXT_GLOBALS);
+    } else {
+      runCtx = buildContext(timerMode, !!args.deterministic, args.globals);
+    }
+
+    if (ctx.protocol.kvEnabled) {
+      // Not enumerable, so that it isn't sent back with the other globals.
+      const run = runCtx;
+      Object.defineProperty(run.context, 'kv', {
+        value: createKv(() => currentRequest.getStore() ?? run.current),
+      });
+    }
+
+    // Save the context for reuse later.
+    ctx.protocol.cache.set(RUN_CTX_KEY, runCtx);
+  } else {
+    setGlobals(runCtx, args);
+  }
+
+  runCtx.current = ctx;
+  runCtx.timers.startRun(args.maxTimers);
+  runCtx.seedRandom?.(args.randomSeed ?? 0);
+  runCtx.logFilter = args.logNamespaces?.length ? new NamespaceFilter(args.logNamespaces) : null;
+
+  compileFunctions(runCtx, args.functions ?? []);
+
+  // Each run gets new instances of the modules that it passes, which are linked and evaluated
+  // afresh, so no state carries over from an earlier run's instance. Only the compiled code is
+  // shared, through the code cache.
+  for (const { name, hash } of args.cachedModules ?? []) {
+    const source = runCtx.moduleSources.get(moduleName(name));
+    if (source?.hash !== hash) {
+      throw moduleError(
+        `Module ${name} was sent without its code, but the context doesn't have it`
+      );
+    }
+    runCtx.modules[moduleName(name)] = compileModule(source.module, runCtx.context);
+  }
+
+  for (const modArgs of args.modules ?? []) {
+    addModule(runCtx, modArgs);
+  }
+
+  for (const wasm of args.wasmModules ?? []) {
+    runCtx.context[wasm.name] = instantiateWasm(wasm, runCtx.context);
+  }
+
+  return runCtx;
+}
+
+/** Compile `functions` and place them in the context's global scope. */
+function compileFunctions(runCtx, functions) {
+  for (const fn of functions) {
+    let cacheKey = codeCacheKey('function', fn.code, fn.params);
+    let cachedData = codeCache.get(cacheKey);
+    let compiled = vm.compileFunction(fn.code, fn.params, {
+      parsingContext: runCtx.context,
+      cachedData,
+      produceCachedData: !cachedData,
+    });
+
+    if (!cachedData && compiled.cachedData) {
+      codeCache.set(cacheKey, compiled.cachedData);
+    }
+
+    runCtx.context[fn.name] = compiled;
+  }
+}
+
+/** Compile a module that the host sent into the context, keeping its code so that later runs can
+ * refer to it by hash instead of sending it again. */
+function addModule(runCtx, modArgs) {
+  const name = moduleName(modArgs.name);
+  runCtx.modules[name] = compileModule(modArgs, runCtx.context);
+  runCtx.moduleSources.set(name, { module: modArgs, hash: contentHash(modArgs.code) });
+}
+
+function compileModule(modArgs, context) {
+  const cacheKey = codeCacheKey('module', modArgs.code);
+  let cachedData = codeCache.get(cacheKey);
+  let mod = new vm.SourceTextModule(modArgs.code, {
+    identifier: modArgs.name,
+    context,
+    cachedData,
+  });
+
+  if (!cachedData) {
+    let data = mod.createCachedData();
+    codeCache.set(cacheKey, data);
+  }
+
+  return mod;
+}
+
+/** Ask the host's resolver for a module that the context doesn't have, and add it to the context.
+ * Returns the module's name, or undefined if the host doesn't know it. Fails in a dry run, which
+ * can only use the modules that the context already has. */
+function resolveFromHost(
+  run,
+  ctx,
+  specifier,
+  referrer
+) {
+  // The resolver is code on the host that can do anything, so a dry run doesn't call it.
+  if (ctx.dryRun) {
+    return Promise.reject(
+      moduleError(`Can't resolve module ${specifier} from ${referrer} in a dry run`)
+    );
+  }
+
+  const key = joinSpecifier(specifier, referrer);
+  let pending = run.pendingHostModules.get(key);
+  if (pending) {
+    return pending;
+  }
+
+  pending = ctx.protocol
+    .resolveModule(ctx.reqId, key, referrer)
+    .then(
+      (module) => {
+        if (!module) {
+          return undefined;
+        }
+
+        // The host can return a module that the context already has, under another specifier.
+        const name = moduleName(module.name);
+        run.modules[name] ??= compileModule(module, run.context);
+        run.hostModules.set(key, name);
+        return name;
+      },
+      (e) => {
+        throw moduleError(`Failed to resolve module ${specifier} from ${referrer}: ${e.message}`);
+      }
+    )
+    .finally(() => run.pendingHostModules.delete(key));
+  run.pendingHostModules.set(key, pending);
+  return pending;
+}
+
+/** Throw away the connection's context, so that the next run starts with a fresh one. This is done
+ * when a run is interrupted or abandoned, since its code may have left the context half-updated,
+ * or still be running in it. */
+function discardContext(protocol) {
+  const run = protocol.cache.get(RUN_CTX_KEY);
+  if (run) {
+    run.timers.dispose();
+    protocol.cache.delete(RUN_CTX_KEY);
+  }
+}
+
+/** Fetch a module for a URL import, or take it from the cache, and add it to the context.
+ * Returns the module's name, which is the URL that it came from after any redirects, so that its
+ * own relative imports resolve against that. */
+async function importFromUrl(run, ctx, url, referrer) {
+  const prefixes = ctx.protocol.urlImports;
+  if (!isAllowed(url, prefixes)) {
+    throw moduleError(`Import of ${url} from ${referrer} is not allowed`);
+  }
+
+  const fetched = await urlModules.get(url, ctx.protocol.urlImportTtlMs, prefixes);
+  // A cached module may have been fetched for a connection that allows more of its redirects.
+  const blocked = fetched.hops.find((hop) => !isAllowed(hop, prefixes));
+  if (blocked) {
+    throw moduleError(`Import of ${url} redirected to ${blocked}, which is not allowed`);
+  }
+
+  run.modules[fetched.url] ??= compileModule(
+    { name: fetched.url, code: fetched.code },
+    run.context
+  );
+  run.modules[url] ??= run.modules[fetched.url];
+  return fetched.url;
+}
+
+/** The code of a registered script. The host only sends the code the first time that the
+ * connection runs the script, so it is kept for the runs after that. */
+function registeredCode(script, code, protocol) {
+  let scripts = protocol.cache.get(REGISTERED_SCRIPTS_KEY);
+  if (!scripts) {
+    scripts = new Map();
+    protocol.cache.set(REGISTERED_SCRIPTS_KEY, scripts);
+  }
+
+  if (code) {
+    scripts.set(script.hash, code);
+    return code;
+  }
+
+  const kept = scripts.get(script.hash);
+  if (kept === undefined) {
+    throw internalError(
+      `Script ${script.name} was sent without its code, but the connection doesn't have it`
+    );
+  }
+  return kept;
+}
+
+/** The number of timers and immediates waiting to run in this worker. */
+function pendingMacrotasks() {
+  return process
+    .getActiveResourcesInfo()
+    .filter((type) => type === 'Timeout' || type === 'Immediate').length;
+}
+
+function elapsedMs(from, to) {
+  return Number(to - from) / 1e6;
+}
+
+/** Send a chunk passed to the `write` global to the host. Strings are sent as UTF-8. The returned
+ * promise resolves once the chunk has been written out, so a script that awaits each write doesn't
+ * get ahead of the connection. */
+function writeOutput(ctx, chunk) {
+  if (!ctx.streamOutput) {
+    throw new Error('Output streaming is not enabled for this run');
+  }
+
+  let data;
+  if (typeof chunk === 'string') {
+    data = Buffer.from(ctx.redactor ? ctx.redactor.redact(chunk) : chunk);
+  } else if (ArrayBuffer.isView(chunk)) {
+    data = Buffer.from(chunk.buffer, chunk.byteOffset, chunk.byteLength);
+  } else if (types.isArrayBuffer(chunk)) {
+    data = Buffer.from(chunk);
+  } else {
+    throw new TypeError('write() takes a string, an ArrayBuffer, or a typed array');
+  }
+  if (ctx.dryRun) {
+    ctx.dryRun.output(data);
+    return Promise.resolve();
+  }
+  return ctx.output(data);
+}
+
+/** Send a chunk passed to `stderr.write` to the host as an error-level console message, like
+ * `console.error`. Unlike the output from `write`, this doesn't need the run to stream its output.
+ * A single trailing newline is left out, since each chunk is already a separate message. */
+function writeStderr(run, chunk) {
+  let text;
+  if (typeof chunk === 'string') {
+    text = chunk;
+  } else if (ArrayBuffer.isView(chunk)) {
+    text = Buffer.from(chunk.buffer, chunk.byteOffset, chunk.byteLength).toString('utf8');
+  } else if (types.isArrayBuffer(chunk)) {
+    text = Buffer.from(chunk).toString('utf8');
+  } else {
+    throw new TypeError('stderr.write() takes a string, an ArrayBuffer, or a typed array');
+  }
+  forwardLog(run, [text.endsWith('\n') ? text.slice(0, -1) : text], 'error');
+  return true;
+}
+
+/** Handle the timers that a script left pending, according to the run's drain policy. Only the
+ * run's own timers are affected, and not ones left by earlier runs in the context. */
+async function drainTimers(run, args, ctx) {
+  if (args.drain === 'cancel') {
+    run.timers.cancel(ctx);
+  } else if (args.drain === 'wait') {
+    let cap;
+    await Promise.race([
+      run.timers.idle(ctx),
+      new Promise((resolve) => {
+        cap = setTimeout(resolve, args.maxDrainMs ?? DEFAULT_MAX_DRAIN_MS);
+      }),
+    ]);
+    clearTimeout(cap);
+    // Let promises started by the last timers settle.
+    await new Promise((resolve) => setImmediate(resolve));
+  }
+}
+
+/** Resolve any promises in the top level of the globals. */
+async function awaitGlobals(globals) {
+  const entries = await Promise.all(
+    Object.entries(globals).map(async ([key, value]) => [key, await value])
+  );
+  return Object.fromEntries(entries);
+}
+
+/** Advance the virtual clock of the connection's context. Console output from the timers that
+ * run is attributed to this request. */
+function advanceTime(args, ctx) {
+  const run = ctx.protocol.cache.get(RUN_CTX_KEY);
+  if (!run) {
+    return Promise.reject(new Error('The connection has no context to advance'));
+  }
+
+  run.current = ctx;
+  return currentRequest.run(ctx, () => run.timers.advance(args.ms));
+}
+
+/** Complete the end of a line of code from the connection's context. A connection with no
+ * context yet has nothing to offer. */
+function complete(args, ctx) {
+  const run = ctx.protocol.cache.get(RUN_CTX_KEY);
+  if (!run) {
+    return { prefix: '', candidates: [] };
+  }
+  return completeLine(args.line, run.context, run.lexicalNames);
+}
+
+/** List the properties of a value in the connection's context, or its globals. */
+function introspectContext(args, ctx) {
+  const run = ctx.protocol.cache.get(RUN_CTX_KEY);
+  if (!run) {
+    return [];
+  }
+  return introspect(args.expr, args.prefix, run.context, run.lexicalNames);
+}
+
+function runScript(args, ctx) {
+  const abort = (ctx.abort ??= new AbortController());
+  ctx.streamOutput = Boolean(args.streamOutput);
+  if (args.dryRun) {
+    ctx.dryRun = new DryRun();
+  }
+  if (args.streamInput) {
+    ctx.input ??= new InputStream(abort.signal);
+  }
+  const stopTimeout = args.timeoutMs ? abortAfter(abort, args.timeoutMs) : undefined;
+  return currentRequest
+    .run(ctx, () =>
+      untilAborted(args.profile ? executeWithProfile(args, ctx) : execute(args, ctx), abort.signal)
+    )
+    .catch((e) => {
+      if (abort.signal.aborted || isTimeout(e)) {
+        discardContext(ctx.protocol);
+      }
+      throw e;
+    })
+    .finally(() => {
+      stopTimeout?.();
+      // The host stops reading output once the run finishes.
+      ctx.streamOutput = false;
+      if (args.debug) {
+        debugRunFinished();
+      }
+    });
+}
+
+/** Run the script under the CPU profiler, and send the profile before the response. */
+async function executeWithProfile(args, ctx) {
+  const stopProfiling = await startProfiling();
+  try {
+    return await execute(args, ctx);
+  } finally {
+    try {
+      const profile = await stopProfiling();
+      ctx.protocol.sendMessage(ctx.reqId, WorkerToHostMessage.CpuProfile, profile);
+    } catch (e) {
+      debug(`Request ${ctx.reqId} failed to collect a CPU profile`, e);
+    }
+  }
+}
+
+/** The `secrets` globals set by runs, so that they can be told apart from a script's own global
+ * with the same name. */
+const secretsGlobals = new WeakSet();
+
+/** Expose the run's secrets to the script as a frozen `secrets` global. It isn't enumerable, so it
+ * isn't sent back with the other globals, and secrets from an earlier run on the context are
+ * removed. */
+function setSecrets(context, secrets) {
+  if (secrets && Object.keys(secrets).length) {
+    const value = Object.freeze({ ...secrets });
+    secretsGlobals.add(value);
+    Object.defineProperty(context, 'secrets', { value, configurable: true });
+  } else if (secretsGlobals.has(context.secrets)) {
+    delete context.secrets;
+  }
+}
+
+async function execute(args, ctx) {
+  ctx.annotations = args.annotations;
+  ctx.consoleHandler = args.consoleHandler;
+  ctx.minLogLevel = args.minLogLevel;
+  if (args.secrets && Object.keys(args.secrets).length) {
+    ctx.redactor = new Redactor(args.secrets);
+  }
+  if (args.maxLogMessages !== undefined || args.maxLogBytes !== undefined) {
+    ctx.logBudget = new LogBudget(args.maxLogMessages, args.maxLogBytes);
+  }
+  if (args.registeredScript) {
+    args = { ...args, code: registeredCode(args.registeredScript, args.code, ctx.protocol) };
+  }
+
+  // Call and REPL modes return their result directly, so only return globals that were asked for.
+  const returnKeys = args.call || args.repl ? (args.returnKeys ?? []) : args.returnKeys;
+  // Check the selectors up front, so that a typo fails before the script has done anything.
+  returnKeys?.forEach(parseSelector);
+
+  if (args.debug && args.code) {
+    await waitForDebugger(ctx);
+    if (!args.call) {
+      args = { ...args, code: withBreakpoint(args.code) };
+    }
+  }
+
+  const macrotasksBefore = pendingMacrotasks();
+  const heapBefore = getHeapStatistics().used_heap_size;
+  let start = process.hrtime.bigint();
+  let run = createContext(ctx, args);
+  setSecrets(run.context, args.secrets);
+  if (args.extendedValues && args.args) {
+    const { realm } = run;
+    args = { ...args, args: args.args.map((arg) => decodeValue(arg, realm)) };
+  }
+  // Set once the code is compiled, to separate compile time from execution time.
+  let compiled = start;
+
+  let retVal;
+
+  if (!args.code) {
+    // The user sent no code, this was only to update the context for future runs.
+    return {};
+  }
+
+  const cacheKey = codeCacheKey(
+    args.call ? 'call' : args.expr || args.repl ? 'script' : 'module',
+    args.code
+  );
+  if (args.call) {
+    // Wrap the code in parentheses so that function declarations become expressions.
+    let code = `(${args.code}\n)`;
+    let cacheData = codeCache.get(cacheKey);
+    let script = new vm.Script(code, {
+      filename: args.name || '<script>',
+      cachedData: cacheData,
+    });
+
+    if (!cacheData) {
+      codeCache.set(cacheKey, script.createCachedData());
+    }
+
+    compiled = process.hrtime.bigint();
+    let fn = script.runInContext(run.context, {
+      timeout: args.timeoutMs ?? undefined,
+    });
+
+    if (typeof fn !== 'function') {
+      throw new Error(`Call mode code evaluated to ${typeof fn}, not a function`);
+    }
+
+    run.context[CALL_KEY] = () => {
+      if (args.debug) {
+        // Pause just before the call, so that the debugger can step into the function.
+        debugger;
+      }
+      return fn(...(args.args ?? []));
+    };
+    let result;
+    try {
+      result = callScript.runInContext(run.context, {
+        timeout: args.timeoutMs ?? undefined,
+      });
+    } finally {
+      delete run.context[CALL_KEY];
+    }
+    retVal = await result;
+  } else if (args.expr || args.repl) {
+    let cacheData = codeCache.get(cacheKey);
+    let script = new vm.Script(args.code, {
+      filename: args.name || '<script>',
+      cachedData: cacheData,
+    });
+
+    if (!cacheData) {
+      codeCache.set(cacheKey, script.createCachedData());
+    }
+
+    compiled = process.hrtime.bigint();
+    retVal = script.runInContext(run.context, {
+      timeout: args.timeoutMs ?? undefined,
+    });
+
+    if (typeof retVal?.then === 'function') {
+      retVal = await retVal;
+    }
+    for (const name of declaredNames(args.code)) {
+      run.lexicalNames.add(name);
+    }
+  } else {
+    const resolve = (specifier, referencingModule) => {
+      const referrer = moduleName(referencingModule.identifier);
+      const url = importUrl(specifier, referrer);
+      if (url) {
+        return Object.hasOwn(run.modules, url) ? url : undefined;
+      }
+      return (
+        resolveSpecifier(specifier, referrer, run.modules) ??
+        run.hostModules.get(joinSpecifier(specifier, referrer))
+      );
+    };
+
+    async function doLink(specifier, referencingModule) {
+      let name = resolve(specifier, referencingModule);
+      if (!name) {
+        const referrer = moduleName(referencingModule.identifier);
+        const url = importUrl(specifier, referrer);
+        if (url) {
+          name = await importFromUrl(run, ctx, url, referrer);
+        } else if (ctx.protocol.resolveModulesEnabled) {
+          name = await resolveFromHost(run, ctx, specifier, referrer);
+        }
+      }
+      if (name) {
+        return run.modules[name];
+      }
+
+      throw moduleError(
+        `Module not found: ${specifier}, referenced from ${referencingModule.identifier}`
+      );
+    }
+
+    let cachedData = codeCache.get(cacheKey);
+    let mod = new vm.SourceTextModule(args.code, {
+      identifier: args.name || '<script>',
+      context: run.context,
+      cachedData,
+    });
+
+    if (!cachedData) {
+      let data = mod.createCachedData();
+      if (data) {
+        codeCache.set(cacheKey, data);
+      }
+    }
+
+    await mod.link(doLink);
+    compiled = process.hrtime.bigint();
+    try {
+      await mod.evaluate({ timeout: args.timeoutMs ?? undefined });
+    } catch (e) {
+      // Modules in a cycle can use each other's exports before they are initialized, which
+      // throws a ReferenceError that doesn't say why. The error comes from the context, so it
+      // isn't an instance of this realm's ReferenceError.
+      const script = mod;
+      const byName = (name) => (name === '' ? script : run.modules[name]);
+      const cycle =
+        (e )?.name === 'ReferenceError' &&
+        findCycle('', (name) => {
+          const from = byName(name) ;
+          return from.dependencySpecifiers.flatMap((specifier) => resolve(specifier, from) ?? []);
+        });
+      if (cycle) {
+        const names = cycle.map((name) => byName(name).identifier).join(' -> ');
+        const message = (e ).message;
+        throw new Error(`Import cycle between modules ${names}: ${message}`, { cause: e });
+      }
+      throw e;
+    }
+
+    // A script can report its result, such as the promise from an async IIFE, as its default export.
+    const namespace = mod.namespace;
+    retVal = await namespace.default;
+  }
+
+  await drainTimers(run, args, ctx);
+
+  let outputGlobals = returnKeys ? selectPaths(run.context, returnKeys) : run.context;
+  if (args.awaitResult) {
+    outputGlobals = await awaitGlobals(outputGlobals);
+  }
+
+  const executed = process.hrtime.bigint();
+  const stats = {
+    compileMs: elapsedMs(start, compiled),
+    executeMs: elapsedMs(compiled, executed),
+    heapUsedDelta: getHeapStatistics().used_heap_size - heapBefore,
+    macrotasksDrained: pendingMacrotasks() <= macrotasksBefore,
+  };
+  const { globals: sendableGlobals, skipped } = serializableGlobals(
+    outputGlobals,
+    returnKeys !== undefined,
+    Boolean(args.extendedValues)
+  );
+  if (skipped.length && args.strictGlobals) {
+    const { key, reason } = skipped[0];
+    throw new Error(`Global ${key} can't be sent to the host: ${reason}`);
+  }
+  if (args.repl) {
+    retVal = inspect(retVal);
+  } else if (args.extendedValues) {
+    retVal = encodeValue(retVal, 'returnValue');
+  }
+  let elapsed = Number(process.hrtime.bigint() - start) / 1e3;
+  debug(`Evaluated in ${elapsed}us`);
+
+  const limits = {
+    maxStringBytes: args.maxStringBytes,
+    replaceInvalidUnicode: args.replaceInvalidUnicode,
+  };
+  return {
+    globals: validateStrings(sendableGlobals, 'globals', limits),
+    returnValue: validateStrings(retVal, 'returnValue', limits),
+    stats,
+    skippedGlobals: skipped.length ? skipped : undefined,
+    effects: ctx.dryRun?.effects,
+  };
+}
+
+// src/websocket.ts
+const HANDSHAKE_GUID = '258EAFA5-E914-47DA-95CA-C5AB0DC85B11';
+
+/** The largest frame payload accepted from a client. Larger frames close the connection. */
+const MAX_FRAME_BYTES = 256 * 1024 * 1024;
+
+/** The initial size of a connection's receive buffer, which grows to fit larger frames. */
+const INITIAL_BUFFER_BYTES = 64 * 1024;
+
+/** Close codes from RFC 6455 */
+const CLOSE_PROTOCOL_ERROR = 1002;
+const CLOSE_TOO_LARGE = 1009;
+
+var Opcode = /* @__PURE__ */ ((Opcode) => {
+  Opcode[Opcode["Continuation"] = 0x0] = "Continuation";
+  Opcode[Opcode["Text"] = 0x1] = "Text";
+  Opcode[Opcode["Binary"] = 0x2] = "Binary";
+  Opcode[Opcode["Close"] = 0x8] = "Close";
+  Opcode[Opcode["Ping"] = 0x9] = "Ping";
+  Opcode[Opcode["Pong"] = 0xa] = "Pong";
+  return Opcode;
+})(Opcode || {});
+
+
+
+/** Parse one frame from the start of `buffer`, or return null if it doesn't contain a whole
+ * frame yet. Clients must mask their frames, and payloads can't be over `maxPayload` bytes. */
+function parseFrame(
+  buffer,
+  maxPayload = MAX_FRAME_BYTES
+) {
+  if (buffer.length < 2) {
+    return null;
+  }
+
+  const opcode = buffer[0] & 0x0f;
+  if ((buffer[1] & 0x80) === 0) {
+    return { error: 'Client frames must be masked', status: CLOSE_PROTOCOL_ERROR };
+  }
+  let payloadLength = buffer[1] & 0x7f;
+  let offset = 2;
+
+  if (payloadLength === 126) {
+    if (buffer.length < offset + 2) {
+      return null;
+    }
+    payloadLength = buffer.readUInt16BE(offset);
+    offset += 2;
+  } else if (payloadLength === 127) {
+    if (buffer.length < offset + 8) {
+      return null;
+    }
+    payloadLength = Number(buffer.readBigUInt64BE(offset));
+    offset += 8;
+  }
+
+  if (payloadLength > maxPayload) {
+    return { error: `Frame of ${payloadLength} bytes is too large`, status: CLOSE_TOO_LARGE };
+  }
+
+  if (buffer.length < offset + 4) {
+    return null;
+  }
+  const mask = buffer.subarray(offset, offset + 4);
+  offset += 4;
+
+  if (buffer.length < offset + payloadLength) {
+    return null;
+  }
+
+  const payload = Buffer.from(buffer.subarray(offset, offset + payloadLength));
+  for (let i = 0; i < payload.length; i++) {
+    payload[i] ^= mask[i % 4];
+  }
+
+  return { opcode, payload, length: offset + payloadLength };
+}
+
+/** Encode an unmasked frame, as sent by a server. */
+function encodeFrame(opcode, payload) {
+  let header;
+  if (payload.length < 126) {
+    header = Buffer.alloc(2);
+    header[1] = payload.length;
+  } else if (payload.length < 65536) {
+    header = Buffer.alloc(4);
+    header[1] = 126;
+    header.writeUInt16BE(payload.length, 2);
+  } else {
+    header = Buffer.alloc(10);
+    header[1] = 127;
+    header.writeBigUInt64BE(BigInt(payload.length), 2);
+  }
+
+  header[0] = 0x80 | opcode;
+  return Buffer.concat([header, payload]);
+}
+
+/** The server side of a WebSocket connection, presenting the payloads of the data frames as a
+ * stream of bytes so that it can be used in place of a socket by the Protocol. */
+class WebSocketConnection extends EventEmitter {
+  socket;
+  /** Received data that doesn't make up a whole frame yet, in the first `buffered` bytes */
+  buffer;
+  buffered;
+  closed;
+
+  constructor(socket) {
+    super();
+    this.socket = socket;
+    this.buffer = Buffer.allocUnsafe(INITIAL_BUFFER_BYTES);
+    this.buffered = 0;
+    this.closed = false;
+    socket.on('data', (data) => this.handleData(data));
+    socket.on('close', () => this.handleClose());
+    socket.on('error', (e) => {
+      debug('WebSocket error', e);
+      socket.destroy();
+    });
+  }
+
+  handleData(data) {
+    // Grow the buffer by doubling, so that a large frame arriving in many chunks isn't copied
+    // again for each one. Frames over the limit are refused before the buffer gets that large.
+    const needed = this.buffered + data.length;
+    if (needed > this.buffer.length) {
+      const grown = Buffer.allocUnsafe(Math.max(needed, this.buffer.length * 2));
+      this.buffer.copy(grown, 0, 0, this.buffered);
+      this.buffer = grown;
+    }
+    data.copy(this.buffer, this.buffered);
+    this.buffered = needed;
+
+    let offset = 0;
+    while (!this.closed) {
+      const frame = parseFrame(this.buffer.subarray(offset, this.buffered));
+      if (!frame) {
+        break;
+      }
+      if ('error' in frame) {
+        debug(`Closing WebSocket: ${frame.error}`);
+        this.fail(frame.status);
+        return;
+      }
+      offset += frame.length;
+      this.handleFrame(frame);
+    }
+
+    this.buffer.copyWithin(0, offset, this.buffered);
+    this.buffered -= offset;
+  }
+
+  handleFrame({ opcode, payload }) {
+    switch (opcode) {
+      case Opcode.Continuation:
+      case Opcode.Text:
+      case Opcode.Binary:
+        // Message boundaries don't matter since the protocol has its own framing.
+        this.emit('data', payload);
+        break;
+      case Opcode.Ping:
+        this.socket.write(encodeFrame(Opcode.Pong, payload));
+        break;
+      case Opcode.Pong:
+        break;
+      case Opcode.Close:
+        if (!this.closed) {
+          this.socket.end(encodeFrame(Opcode.Close, payload.subarray(0, 2)));
+        }
+        this.handleClose();
+        break;
+    }
+  }
+
+  handleClose() {
+    if (!this.closed) {
+      this.closed = true;
+      this.emit('close');
+    }
+  }
+
+  write(data, callback) {
+    if (!this.closed) {
+      this.socket.write(encodeFrame(Opcode.Binary, data), callback);
+    }
+  }
+
+  /** Close the connection with an error status. */
+  fail(status) {
+    if (!this.closed) {
+      const payload = Buffer.alloc(2);
+      payload.writeUInt16BE(status);
+      this.socket.end(encodeFrame(Opcode.Close, payload));
+      this.handleClose();
+    }
+  }
+
+  /** Send a normal closure frame and close the socket. */
+  end() {
+    if (!this.closed) {
+      const status = Buffer.alloc(2);
+      status.writeUInt16BE(1000);
+      this.socket.end(encodeFrame(Opcode.Close, status));
+    }
+  }
+}
+
+/** Split a `host:port` address, as formatted by Rust's `SocketAddr`, into its parts. */
+function parseAddress(address) {
+  const separator = address.lastIndexOf(':');
+  return {
+    host: address.slice(0, separator).replace(/^\[(.*)\]$/, '$1'),
+    port: parseInt(address.slice(separator + 1), 10),
+  };
+}
+
+/** Check the headers of an upgrade request, returning the HTTP status to refuse it with, or null
+ * to accept it. Requests from browsers, which always send an `Origin`, are refused so that a web
+ * page can't reach the workers, and when `token` is set the request must carry it as a bearer
+ * token. */
+function checkUpgrade(headers, token) {
+  if (
+    headers.upgrade?.toLowerCase() !== 'websocket' ||
+    typeof headers['sec-websocket-key'] !== 'string'
+  ) {
+    return '400 Bad Request';
+  }
+  if (headers.origin !== undefined) {
+    return '403 Forbidden';
+  }
+  if (token) {
+    const expected = Buffer.from(`Bearer ${token}`);
+    const given = Buffer.from(headers.authorization ?? '');
+    if (given.length !== expected.length || !crypto.timingSafeEqual(given, expected)) {
+      return '401 Unauthorized';
+    }
+  }
+  return null;
+}
+
+/** Start an HTTP server at `address` that accepts WebSocket connections and passes them to
+ * `accept`. When `token` is set, connections must send it in an `Authorization: Bearer` header. */
+function listenWebSocket(
+  address,
+  accept,
+  token = process.env.WEBSOCKET_TOKEN || undefined
+) {
+  const { host, port } = parseAddress(address);
+
+  const server = http.createServer((_req, res) => {
+    res.writeHead(426, { Connection: 'Upgrade', Upgrade: 'websocket' });
+    res.end();
+  });
+
+  server.on('upgrade', (req, socket, head) => {
+    const refused = checkUpgrade(req.headers, token);
+    if (refused) {
+      socket.end(`HTTP/1.1 ${refused}\r\n\r\n`);
+      return;
+    }
+
+    const key = req.headers['sec-websocket-key'];
+
+    const accepted = crypto
+      .createHash('sha1')
+      .update(key + HANDSHAKE_GUID)
+      .digest('base64');
+    socket.write(
+      'HTTP/1.1 101 Switching Protocols\r\n' +
+        'Upgrade: websocket\r\n' +
+        'Connection: Upgrade\r\n' +
+        `Sec-WebSocket-Accept: ${accepted}\r\n\r\n`
+    );
+
+    const conn = new WebSocketConnection(socket);
+    accept(conn);
+    if (head.length > 0) {
+      conn.handleData(head);
+    }
+  });
+
+  server.on('error', (e) => {
+    console.error(e);
+    process.exit(1);
+  });
+
+  server.listen(port, host, () => {
+    debug(`Worker ${process.pid} is listening for WebSockets on ${address}`);
+  });
+
+  return server;
+}
+
+// src/threads.ts
+/** How the worker keeps a script that stops responding from blocking other connections. */
+
+
+/** Passed to a connection's thread when it starts. */
+
+
+
+
+
+/** Sent from a connection's thread to the main thread. */
+
+
+
+
+
+const THREAD_KEY = Symbol('thread');
+
+/** The transport of a Protocol inside a connection's thread, which hands each frame to the main
+ * thread to write to the socket. */
+class ThreadTransport extends EventEmitter  {
+  port;
+
+  constructor(port) {
+    super();
+    this.port = port;
+  }
+
+  write(data, callback) {
+    const message = { type: 'write', data };
+    this.port.postMessage(message);
+    callback?.();
+  }
+
+  end() {
+    const message = { type: 'end' };
+    this.port.postMessage(message);
+  }
+}
+
+/** A Buffer over the bytes of a Uint8Array that came through `postMessage`. */
+function toBuffer(data) {
+  return Buffer.from(data.buffer, data.byteOffset, data.byteLength);
+}
+
+/** Runs the requests for one connection in a worker thread. The main thread reads the frames from
+ * the socket and passes them on, and the thread writes its frames back through the main thread.
+ *
+ * A run that goes past its timeout by more than the abort grace period, such as one stuck in an
+ * infinite loop in a timer callback, has its thread terminated. The run fails as timed out, other
+ * runs on the connection fail, and a new thread takes over the connection with a fresh context. */
+class ThreadedConnection {
+  protocol;
+  socket;
+  onRun;
+  thread;
+  /** Runs that the thread hasn't finished yet, with the timer that terminates the thread if the run
+   * goes too far past its timeout. The timer starts once the run leaves its lane, since a run can
+   * wait there behind the thread's other runs. */
+  pending = new Map();
+  handshake = null;
+  /** The thread has started a run, and so has a context. */
+  hasContext = false;
+  closed = false;
+
+  constructor(protocol, socket, onRun) {
+    this.protocol = protocol;
+    this.socket = socket;
+    this.onRun = onRun;
+    this.start();
+    protocol.on('message', (message) => this.forward(message));
+    socket.on('close', () => {
+      this.closed = true;
+      this.clearPending();
+      this.thread.terminate();
+    });
+  }
+
+  start() {
+    const data = { handshake: this.handshake };
+    const thread = new Worker(process.argv[1], { workerData: data });
+    thread.on('message', (message) => {
+      // Messages from a thread that has been replaced are about runs that have already failed.
+      if (thread !== this.thread) {
+        return;
+      }
+
+      if (message.type === 'write') {
+        const frame = toBuffer(message.data);
+        this.protocol.forwarded(frame);
+        this.socket.write(frame);
+      } else if (message.type === 'end') {
+        this.socket.end();
+      } else if (message.type === 'started') {
+        this.started(message.reqId, message.timeoutMs);
+      } else {
+        clearTimeout(this.pending.get(message.reqId));
+        this.pending.delete(message.reqId);
+      }
+    });
+    thread.on('error', (e) => {
+      console.error(e);
+      this.replace(thread, e);
+    });
+    thread.on('exit', (code) => {
+      this.replace(thread, internalError(`The worker thread exited with code ${code}`));
+    });
+    this.thread = thread;
+    this.hasContext = false;
+  }
+
+  forward(message) {
+    const { reqId, type, data } = message;
+    if (type === HostToWorkerMessage.Handshake) {
+      this.handshake = data;
+    } else if (
+      type === HostToWorkerMessage.RunScript ||
+      type === HostToWorkerMessage.RunScriptBinary
+    ) {
+      this.onRun();
+      this.hasContext = true;
+      this.pending.set(reqId, undefined);
+    }
+
+    this.thread.postMessage(message);
+  }
+
+  /** Start the timer for a run that the thread has started. */
+  started(reqId, timeoutMs) {
+    if (timeoutMs == null || !this.pending.has(reqId)) {
+      return;
+    }
+
+    const timer = setTimeout(() => this.timedOut(reqId, timeoutMs), timeoutMs + ABORT_GRACE_MS);
+    this.pending.set(reqId, timer);
+  }
+
+  /** Terminate a thread that is stuck in a run past its timeout, and start a new one. */
+  timedOut(reqId, timeoutMs) {
+    debug(`${reqId}: terminating the worker thread of a run that timed out`);
+    this.pending.delete(reqId);
+    this.protocol.error(reqId, timeoutError(timeoutMs));
+    this.replace(this.thread, internalError('Another run on the connection timed out'));
+  }
+
+  /** Fail the runs of a thread that has stopped, and start a new thread for the connection. */
+  replace(thread, reason) {
+    if (thread !== this.thread || this.closed) {
+      return;
+    }
+
+    for (const reqId of this.pending.keys()) {
+      this.protocol.error(reqId, reason);
+    }
+    this.clearPending();
+    thread.removeAllListeners('exit');
+    thread.terminate();
+    this.start();
+  }
+
+  clearPending() {
+    for (const timer of this.pending.values()) {
+      clearTimeout(timer);
+    }
+    this.pending.clear();
+  }
+}
+
+/** Run the requests on a connection in a worker thread. `onRun` is called for each run request. */
+function runInThread(protocol, socket, onRun) {
+  protocol.cache.set(THREAD_KEY, new ThreadedConnection(protocol, socket, onRun));
+}
+
+/** The state of a connection's thread, if it runs in one. */
+function threadStats(protocol) {
+  const connection = protocol.cache.get(THREAD_KEY) ;
+  return connection
+    ? { hasContext: connection.hasContext, activeRequests: connection.pending.size }
+    : null;
+}
+
+// src/management.ts
+/** How long the primary waits for each worker to report its stats. */
+const STATS_TIMEOUT_MS = 1000;
+
+
+
+
+
+function isStatsMessage(msg) {
+  return typeof msg === 'object' && msg?.type === 'stats';
+}
+
+let nextStatsId = 0;
+
+/** Ask a worker for its stats over the cluster IPC channel. Resolves to null if the worker doesn't
+ * answer in time. */
+function requestStats(worker) {
+  const id = nextStatsId++;
+  return new Promise((resolve) => {
+    const timeout = setTimeout(() => finish(null), STATS_TIMEOUT_MS);
+    const listener = (msg) => {
+      if (isStatsMessage(msg) && msg.id === id && msg.stats) {
+        finish((msg ).stats);
+      }
+    };
+
+    function finish(stats) {
+      clearTimeout(timeout);
+      worker.off('message', listener);
+      resolve(stats);
+    }
+
+    worker.on('message', listener);
+    const request = { type: 'stats', id };
+    worker.send(request, () => {});
+  });
+}
+
+async function collectWorkerStats() {
+  const workers = Object.values(cluster.workers ?? {}) ;
+  const stats = await Promise.all(workers.map(requestStats));
+  return stats.filter((s) => s !== null) ;
+}
+
+function health() {
+  return {
+    pid: process.pid,
+    workers: Object.keys(cluster.workers ?? {}).length,
+    uptimeMs: process.uptime() * 1000,
+  };
+}
+
+/** Start the management HTTP server in the primary process. */
+function startManagementServer(address) {
+  const { host, port } = parseAddress(address);
+
+  const server = http.createServer((req, res) => {
+    const respond = (status, body) => {
+      const data = JSON.stringify(body);
+      res.writeHead(status, {
+        'Content-Type': 'application/json',
+        'Content-Length': Buffer.byteLength(data),
+      });
+      res.end(data);
+    };
+
+    if (req.method !== 'GET') {
+      respond(405, { error: 'Method not allowed' });
+      return;
+    }
+
+    switch (req.url) {
+      case '/health':
+        respond(200, health());
+        break;
+      case '/workers':
+        collectWorkerStats()
+          .then((stats) => respond(200, stats))
+          .catch((e) => respond(500, { error: String(e) }));
+        break;
+      default:
+        respond(404, { error: 'Not found' });
+    }
+  });
+
+  server.on('error', (e) => {
+    console.error('Management server failed', e);
+  });
+
+  server.listen(port, host, () => {
+    debug(`Management server listening on ${address}`);
+  });
+
+  return server;
+}
+
+/** The stats for the current worker process. */
+function workerStats(connections, requestsHandled) {
+  const { heapUsed, heapTotal, rss, external } = process.memoryUsage();
+  let contexts = 0;
+  for (const protocol of connections) {
+    if (hasContext(protocol) || threadStats(protocol)?.hasContext) {
+      contexts += 1;
+    }
+  }
+
+  return {
+    pid: process.pid,
+    uptimeMs: process.uptime() * 1000,
+    connections: connections.size,
+    contexts,
+    activeRequests: activeRunCount(connections),
+    requestsHandled,
+    memory: { pid: process.pid, heapUsed, heapTotal, rss, external },
+  };
+}
+
+/** The requests running in the current worker process, including those in connection threads. */
+function activeRunCount(connections) {
+  let count = activeRequests.size;
+  for (const protocol of connections) {
+    count += threadStats(protocol)?.activeRequests ?? 0;
+  }
+  return count;
+}
+
+/** Answer stats requests from the primary process. */
+function handleStatsRequests(getStats) {
+  process.on('message', (msg) => {
+    if (isStatsMessage(msg) && !msg.stats) {
+      const request = msg ;
+      const response = { type: 'stats', id: request.id, stats: getStats() };
+      process.send?.(response);
+    }
+  });
+}
+
+// src/lanes.ts
+const RANKS = { high: 0, normal: 1, low: 2 };
+
+/** The longest that a run waits for higher-priority runs before starting anyway, so that a steady
+ * stream of high-priority work can't starve the other lanes. */
+const MAX_LANE_WAIT_MS = 1000;
+
+/** Queues for each run priority. A run waits while any run with a higher priority is active on
+ * the worker, so that small latency-sensitive runs aren't stuck behind batch jobs that are
+ * awaiting timers or I/O. Runs in the same lane don't wait for each other. */
+class Lanes {
+  active = [0, 0, 0];
+  waiting = [[], [], []];
+  maxWaitMs;
+
+  constructor(maxWaitMs = MAX_LANE_WAIT_MS) {
+    this.maxWaitMs = maxWaitMs;
+  }
+
+  /** Wait for the run's turn, returning a function to call when it finishes. */
+  async enter(priority = 'normal') {
+    const rank = RANKS[priority] ?? RANKS.normal;
+    if (this.blocked(rank)) {
+      await new Promise((resolve) => {
+        const start = () => {
+          clearTimeout(timer);
+          this.active[rank] += 1;
+          resolve();
+        };
+
+        const timer = setTimeout(() => {
+          const queue = this.waiting[rank];
+          queue.splice(queue.indexOf(start), 1);
+          this.active[rank] += 1;
+          resolve();
+        }, this.maxWaitMs);
+
+        this.waiting[rank].push(start);
+      });
+    } else {
+      this.active[rank] += 1;
+    }
+
+    let released = false;
+    return () => {
+      if (!released) {
+        released = true;
+        this.active[rank] -= 1;
+        this.wake();
+      }
+    };
+  }
+
+  /** True if a run in the lane has to wait for higher-priority runs. */
+  blocked(rank) {
+    return this.active.slice(0, rank).some((n) => n > 0);
+  }
+
+  /** Start the waiting runs of each lane that is no longer blocked. */
+  wake() {
+    for (let rank = 0; rank < this.waiting.length; rank++) {
+      if (this.blocked(rank)) {
+        return;
+      }
+
+      const queue = this.waiting[rank];
+      this.waiting[rank] = [];
+      for (const start of queue) {
+        start();
+      }
+    }
+  }
+}
+
+// src/jobs.ts
+/** The most console messages that a job keeps. Later ones are counted but dropped. */
+const MAX_JOB_LOGS = 1000;
+
+/** The code of the error for a job that the worker doesn't have, because it never existed, was
+ * already removed, or ran in a worker that has since exited. */
+const JOB_NOT_FOUND = 'ERR_JOB_NOT_FOUND';
+
+/** The most finished jobs that a worker keeps for the host to collect. The oldest ones are
+ * forgotten first. */
+const MAX_FINISHED_JOBS = 100;
+
+/** The jobs in this worker process, by ID. Maps iterate in insertion order, so the first finished
+ * job is the oldest one. */
+const jobs = new Map();
+let nextJobId = 1;
+
+/** Start a run with `detach` set as a background job, and return its status along with the
+ * job's token. The job gets its own context, and its console messages and result are kept in the
+ * worker until the host asks for them with a JobStatus message that has the token, from this
+ * connection or any other one to the same worker.
+ *
+ * `run` runs the script outside of the worker's priority lanes, since a job can run for much
+ * longer than the request that started it.
+ *
+ * The job can't use anything that needs the connection it was started from, since that may close
+ * while the job is still running, so `kv` and module resolution by the host are disabled, and its
+ * output and channel messages go nowhere. */
+function startJob(args, ctx, run) {
+  const jobId = nextJobId++;
+  const job = {
+    status: { jobId, pid: process.pid, state: 'running', logs: [], droppedLogs: 0 },
+    token: crypto.randomBytes(16),
+    abort: new AbortController(),
+  };
+  jobs.set(jobId, job);
+
+  const protocol = Object.create(ctx.protocol, {
+    cache: { value: new Map() },
+    kvEnabled: { value: false },
+    resolveModulesEnabled: { value: false },
+    sendMessage: { value: () => {} },
+  });
+
+  const jobCtx = {
+    protocol,
+    reqId: ctx.reqId,
+    id: ctx.id,
+    abort: job.abort,
+    log(message, level = 'info', namespace) {
+      if (jobCtx.redactor) {
+        message = jobCtx.redactor.redactValue(message);
+      }
+      debug(`job ${jobId}[${level}]:`, message);
+      const { logs } = job.status;
+      if (logs.length < MAX_JOB_LOGS) {
+        const log = {
+          level,
+          message,
+          timestamp: Date.now(),
+          requestId: ctx.reqId,
+          namespace,
+        };
+        logs.push(log);
+      } else {
+        job.status.droppedLogs += 1;
+      }
+    },
+    respond() {},
+    error() {},
+    async output() {},
+    asyncError(e, source) {
+      jobCtx.log(`${source}: ${describeError(e).message}`, 'error');
+    },
+  };
+
+  activeRequests.add(jobCtx);
+  run(args, jobCtx)
+    .then((response) => {
+      finish(job, 'succeeded', {
+        response: jobCtx.redactor ? jobCtx.redactor.redactValue(response) : response,
+      });
+    })
+    .catch((e) => {
+      const kind = errorKind(e, job.abort.signal);
+      if (jobCtx.redactor) {
+        e = jobCtx.redactor.redactError(e);
+      }
+      finish(job, kind === 'cancelled' ? 'cancelled' : 'failed', {
+        error: { ...describeError(e, kind), timedOut: kind === 'timeout' || undefined },
+      });
+    })
+    .finally(() => activeRequests.delete(jobCtx));
+
+  debug(`job ${jobId}: started by request ${ctx.reqId}`);
+  return { ...job.status, token: job.token.toString('hex') };
+}
+
+function finish(job, state, outcome) {
+  // Re-insert the job so that the finished jobs stay in the order they finished.
+  jobs.delete(job.status.jobId);
+  Object.assign(job.status, outcome, { state });
+  jobs.set(job.status.jobId, job);
+  debug(`job ${job.status.jobId}: ${state}`);
+
+  const finished = [...jobs.values()].filter((j) => j.status.state !== 'running');
+  for (const old of finished.slice(0, Math.max(finished.length - MAX_FINISHED_JOBS, 0))) {
+    jobs.delete(old.status.jobId);
+  }
+}
+
+function findJob({ jobId, token }) {
+  const job = jobs.get(jobId);
+  const given = Buffer.from(token ?? '', 'hex');
+  // A wrong token gets the same error as a missing job, so that it doesn't tell which IDs exist.
+  if (!job || given.length !== job.token.length || !crypto.timingSafeEqual(given, job.token)) {
+    throw Object.assign(new Error(`No job ${jobId} in worker ${process.pid}`), {
+      code: JOB_NOT_FOUND,
+    });
+  }
+  return job;
+}
+
+/** The status of a job, forgetting it afterward if it has finished and `remove` is set. */
+function jobStatus(request) {
+  const { jobId, remove } = request;
+  const job = findJob(request);
+  if (remove && job.status.state !== 'running') {
+    jobs.delete(jobId);
+  }
+  return { ...job.status };
+}
+
+/** Abort the signal of a job. The job is cancelled once it has cleaned up. */
+function cancelJob(request) {
+  const { jobId } = request;
+  const job = findJob(request);
+  if (job.status.state === 'running') {
+    debug(`job ${jobId}: cancelled by the host`);
+    job.abort.abort(cancelledError());
+  }
+  return { ...job.status };
+}
+
+// src/worker.ts
+function runWorker(
+  socketPath,
+  websocketAddress,
+  isolation = 'process'
+) {
+  debug(`Worker ${process.pid} started`);
+  startContextPool(parseInt(process.env.CONTEXT_POOL_SIZE ?? '0', 10));
+  const websocketServer = websocketAddress ? listenWebSocket(websocketAddress, accept) : null;
+  const shutdown = () => {
+    debug(`Worker ${process.pid} is shutting down`);
+    websocketServer?.close();
+    // The primary has stopped passing on connections, so exit once the host closes these.
+    const exitWhenClosed = () => {
+      if (sockets.size === 0) {
+        process.exit(0);
+      }
+      setTimeout(exitWhenClosed, 10);
+    };
+    exitWhenClosed();
+  };
+
+  process.on('message', (msg, handle) => {
+    debug(`Worker ${process.pid} received message: ${msg}`);
+    if (msg == 'shutdown') {
+      debug(`Worker ${process.pid} received shutdown message`);
+      shutdown();
+    } else if (msg === 'connection' && handle instanceof net.Socket) {
+      acceptSocket(handle);
+    }
+  });
+
+  // Tell the primary that we are now listening to messages. This prevents a race condition
+  // where shutdown triggers while this worker is starting up, and so the shutdown messages
+  // arrives before we are listening for them.
+  cluster.worker?.send('ready');
+
+  process.on('SIGTERM', shutdown);
+  process.on('SIGINT', shutdown);
+
+  // The host sends SIGUSR2 to replace a worker that is using too much memory. Stop taking new
+  // connections and exit once the current requests finish, and the primary will start a new worker.
+  process.on('SIGUSR2', () => {
+    debug(`Worker ${process.pid} is retiring`);
+    websocketServer?.close();
+    cluster.worker?.send('retiring');
+    const exitWhenIdle = () => {
+      if (activeRunCount(connections) === 0) {
+        process.exit(0);
+      }
+      setTimeout(exitWhenIdle, 10);
+    };
+    exitWhenIdle();
+  });
+
+  const connections = new Set();
+  /** The connections that the primary passed on from the host's socket. */
+  const sockets = new Set();
+  handleStatsRequests(() => workerStats(connections, requestsHandled));
+
+  const reportInterval = parseInt(process.env.MEMORY_REPORT_INTERVAL ?? '0', 10);
+  if (reportInterval > 0) {
+    setInterval(() => reportMemoryUsage(connections), reportInterval).unref();
+  }
+
+  const loadInterval = parseInt(process.env.LOAD_REPORT_INTERVAL ?? '0', 10);
+  if (loadInterval > 0) {
+    const eventLoopDelay = monitorEventLoopDelay({ resolution: 10 });
+    eventLoopDelay.enable();
+    setInterval(() => reportLoad(connections, eventLoopDelay), loadInterval).unref();
+  }
+
+  const crash = (e) => {
+    const report = crashReport(e, activeRequests);
+    console.error(report);
+    if (!process.send) {
+      process.exit(1);
+    }
+
+    // Let the primary pass the report on to the host before exiting.
+    const message = { type: 'crash', report };
+    process.send?.(message, () => process.exit(1));
+  };
+  handleAsyncErrors(crash);
+
+  function accept(socket) {
+    let protocol = new Protocol(socket);
+    connections.add(protocol);
+    socket.on('close', () => connections.delete(protocol));
+    if (isolation === 'thread') {
+      runInThread(protocol, socket, () => (requestsHandled += 1));
+    } else {
+      protocol.on('message', (message) => handleRawMessage(protocol, message));
+    }
+  }
+
+  function acceptSocket(socket) {
+    sockets.add(socket);
+    socket.on('close', () => sockets.delete(socket));
+    // The host can close a connection while a response is still being written to it.
+    socket.on('error', (e) => debug('Socket error', e));
+    accept(socket);
+  }
+
+  debug(`Worker ${process.pid} is taking connections on ${socketPath}`);
+}
+
+/** Handle the requests of one connection inside a worker thread started by `runInThread`. */
+function runThread() {
+  const port = parentPort;
+  const protocol = new Protocol(new ThreadTransport(port));
+  // The main thread replaces a thread that exits, failing the runs that were in it.
+  handleAsyncErrors((e) => {
+    console.error(crashReport(e, activeRequests));
+    process.exit(1);
+  });
+  startContextPool(parseInt(process.env.CONTEXT_POOL_SIZE ?? '0', 10));
+  const { handshake } = workerData ;
+  if (handshake) {
+    protocol.handshake(0, toBuffer(handshake), false);
+  }
+
+  port.on('message', (message) => {
+    const { reqId } = message;
+    handleRawMessage(protocol, { ...message, data: toBuffer(message.data) })?.finally(() => {
+      const finished = { type: 'finished', reqId };
+      port.postMessage(finished);
+    });
+  });
+}
+
+let requestsHandled = 0;
+const lanes = new Lanes();
+
+/** Send errors that escape from a context's code to the host, and treat any others as a crash. */
+function handleAsyncErrors(crash) {
+  process.on('uncaughtException', (e) => {
+    if (!reportAsyncError(e, 'uncaughtException')) {
+      crash(e);
+    }
+  });
+  process.on('unhandledRejection', (reason, promise) => {
+    if (!reportAsyncError(reason, 'unhandledRejection', promise)) {
+      crash(reason);
+    }
+  });
+}
+
+function reportMemoryUsage(connections) {
+  const { heapUsed, heapTotal, rss, external } = process.memoryUsage();
+  const usage = { pid: process.pid, heapUsed, heapTotal, rss, external };
+  const data = JSON.stringify(usage);
+  for (const protocol of connections) {
+    protocol.sendMessage(0, WorkerToHostMessage.MemoryUsage, data);
+  }
+}
+
+function reportLoad(connections, eventLoopDelay) {
+  const load = {
+    pid: process.pid,
+    activeRuns: activeRunCount(connections),
+    connections: connections.size,
+    // The histogram is in nanoseconds, and its mean is NaN when it has no samples.
+    eventLoopDelayMs: (eventLoopDelay.mean || 0) / 1e6,
+  };
+  eventLoopDelay.reset();
+
+  const data = JSON.stringify(load);
+  for (const protocol of connections) {
+    protocol.sendMessage(0, WorkerToHostMessage.WorkerLoad, data);
+  }
+}
+
+/** Handle a message from the host, returning a promise that settles once the request is done, for
+ * messages that start a request. */
+function handleRawMessage(protocol, { id, reqId, type, data }) {
+  if (type === HostToWorkerMessage.Ping) {
+    protocol.sendMessage(reqId, WorkerToHostMessage.Pong, JSON.stringify({ pid: process.pid }));
+    return;
+  }
+
+  if (type === HostToWorkerMessage.Handshake) {
+    protocol.handshake(reqId, data);
+    return;
+  }
+
+  if (type === HostToWorkerMessage.KvResponse) {
+    protocol.kvResponse(data);
+    return;
+  }
+
+  if (type === HostToWorkerMessage.ResolveModuleResponse) {
+    protocol.moduleResolved(data);
+    return;
+  }
+
+  if (type === HostToWorkerMessage.Cancel) {
+    cancelRequest(protocol, reqId);
+    return;
+  }
+
+  if (type === HostToWorkerMessage.Input || type === HostToWorkerMessage.InputEnd) {
+    sendInput(protocol, reqId, type === HostToWorkerMessage.Input ? data : null);
+    return;
+  }
+
+  if (
+    type === HostToWorkerMessage.ChannelMessage ||
+    type === HostToWorkerMessage.ChannelClose
+  ) {
+    sendToChannel(protocol, reqId, type === HostToWorkerMessage.ChannelMessage ? data : null);
+    return;
+  }
+
+  requestsHandled += 1;
+  let start = process.hrtime.bigint();
+
+  let sentResponse = false;
+  const context = {
+    protocol,
+    reqId,
+    id,
+    abort: new AbortController(),
+    log(message, level = 'info', namespace) {
+      if (context.redactor) {
+        message = context.redactor.redactValue(message);
+      }
+      debug(`${reqId}[${level}]:`, message);
+      protocol.log(reqId, level, message, namespace, context.logBudget);
+    },
+    respond(data) {
+      sentResponse = true;
+      sendLogSummary();
+      protocol.respond(reqId, context.redactor ? context.redactor.redactValue(data) : data);
+    },
+    error(e) {
+      const kind = errorKind(e, context.abort?.signal);
+      if (context.redactor) {
+        e = context.redactor.redactError(e);
+      }
+      debug(`${reqId}: `, e.message, formatAnnotations(context.annotations));
+      sendLogSummary();
+      protocol.error(reqId, e, context.annotations, kind);
+    },
+    output(data) {
+      return protocol.output(reqId, data);
+    },
+    asyncError(e, source) {
+      if (context.redactor) {
+        e = context.redactor.redactError(e);
+      }
+      debug(`${reqId}: ${source}`, e, formatAnnotations(context.annotations));
+      protocol.asyncError(reqId, e, source, context.annotations);
+    },
+  };
+
+  /** Tell the host about console messages that were dropped, just before the run finishes. */
+  const sendLogSummary = () => {
+    const summary = context.logBudget?.summary();
+    if (summary) {
+      protocol.log(reqId, 'warn', summary);
+    }
+  };
+
+  activeRequests.add(context);
+  return handleMessage(context, type, data)
+    .then((response) => {
+      if (response != undefined || !sentResponse) {
+        context.respond(response ?? null);
+      }
+
+      let elapsed = Number(process.hrtime.bigint() - start) / 1e3;
+      debug(`handle: ${elapsed}us`, formatAnnotations(context.annotations));
+    })
+    .catch((e) => {
+      debug('Failed to handle request:');
+      context.error(e);
+    })
+    .finally(() => activeRequests.delete(context));
+}
+
+async function handleMessage(
+  ctx,
+  type,
+  data
+) {
+  switch (type) {
+    case HostToWorkerMessage.RunScript: {
+      return startRun(parseRequest(data), ctx);
+    }
+    case HostToWorkerMessage.RunScriptBinary: {
+      const { json, binary } = splitBinaryPayload(data);
+      attachWasmBytes(json.wasmModules ?? [], binary);
+      return startRun(json, ctx);
+    }
+    case HostToWorkerMessage.HeapSnapshot: {
+      await sendHeapSnapshot(ctx);
+      return {};
+    }
+    case HostToWorkerMessage.AdvanceTime: {
+      return { returnValue: await advanceTime(parseRequest(data), ctx) };
+    }
+    case HostToWorkerMessage.Complete: {
+      return { returnValue: complete(parseRequest(data), ctx) };
+    }
+    case HostToWorkerMessage.Introspect: {
+      return { returnValue: introspectContext(parseRequest(data), ctx) };
+    }
+    case HostToWorkerMessage.CheckScript: {
+      return { returnValue: checkScript(parseRequest(data)) };
+    }
+    case HostToWorkerMessage.JobStatus: {
+      return { returnValue: jobStatus(parseRequest(data)) };
+    }
+    case HostToWorkerMessage.JobCancel: {
+      return { returnValue: cancelJob(parseRequest(data)) };
+    }
+    case HostToWorkerMessage.Prewarm: {
+      prewarmContext(parseRequest(data));
+      return {};
+    }
+  }
+}
+
+/** Parse the JSON payload of a request. A payload that doesn't parse is the host's fault, so it
+ * isn't reported as a syntax error in the script. */
+function parseRequest(data) {
+  try {
+    return JSON.parse(data.toString());
+  } catch (e) {
+    throw internalError(`Request payload is not valid JSON: ${(e ).message}`);
+  }
+}
+
+/** The request on the connection with the given ID, if it is still running. */
+function findRequest(protocol, reqId) {
+  for (const request of activeRequests) {
+    if (request.protocol === protocol && request.reqId === reqId) {
+      return request;
+    }
+  }
+  return undefined;
+}
+
+/** Abort the signal of a request on the connection, if it is still running. */
+function cancelRequest(protocol, reqId) {
+  const request = findRequest(protocol, reqId);
+  if (request) {
+    debug(`${reqId}: cancelled by the host`);
+    request.abort?.abort(cancelledError());
+  }
+}
+
+/** Pass a chunk of input to a request on the connection, or end its input if `data` is null.
+ * Input can arrive while the run waits for its lane, before it has started. */
+function sendInput(protocol, reqId, data) {
+  const request = findRequest(protocol, reqId);
+  if (!request) {
+    debug(`${reqId}: dropping input for a request that isn't running`);
+    return;
+  }
+
+  request.input ??= new InputStream(request.abort?.signal);
+  if (data) {
+    request.input.push(data);
+  } else {
+    request.input.end();
+  }
+}
+
+/** Pass a value from the host to a request's channel, or close the channel if `data` is null. */
+function sendToChannel(protocol, reqId, data) {
+  const request = findRequest(protocol, reqId);
+  if (!request) {
+    debug(`${reqId}: dropping a channel message for a request that isn't running`);
+    return;
+  }
+
+  request.channel ??= new ScriptChannel(request);
+  if (data) {
+    request.channel.incoming.push(JSON.parse(data.toString()));
+  } else {
+    request.channel.incoming.end();
+  }
+}
+
+/** Run a script, or start it as a background job and respond with the job's status if `detach` is
+ * set. */
+function startRun(args, ctx) {
+  if (!args.detach) {
+    return runInLane(args, ctx);
+  }
+
+  // A connection's thread exits when the connection closes, which would take its jobs with it.
+  if (parentPort) {
+    throw new Error('Background jobs are not supported with thread isolation');
+  }
+  return { returnValue: startJob(args, ctx, runScript) };
+}
+
+async function runInLane(args, ctx) {
+  const release = await lanes.enter(args.priority);
+  try {
+    // The run may have been cancelled while it waited for its turn.
+    ctx.abort?.signal.throwIfAborted();
+    if (parentPort) {
+      // The main thread times the run from here.
+      const started = { type: 'started', reqId: ctx.reqId, timeoutMs: args.timeoutMs ?? null };
+      parentPort.postMessage(started);
+    }
+    return await runScript(args, ctx);
+  } finally {
+    release();
+  }
+}
+
+// src/events.ts
+/** Sent from a worker to the primary over the cluster IPC channel before it exits from a crash. */
+
+
+function isCrashMessage(msg) {
+  return typeof msg === 'object' && msg?.type === 'crash';
+}
+
+/** Send events over the socket that the host passed as file descriptor `fd`, returning a function
+ * that sends an event. Failures are ignored since the events are only informational. */
+function eventSender(fd) {
+  if (fd === undefined) {
+    return () => {};
+  }
+
+  const socket = new net.Socket({ fd, readable: false, writable: true });
+  socket.on('error', (e) => {
+    debug('Failed to send events', e);
+  });
+  // Don't keep the primary alive just for this.
+  socket.unref();
+
+  return (event) => {
+    if (!socket.destroyed) {
+      socket.write(JSON.stringify(event) + '\n');
+    }
+  };
+}
+
+// src/dispatch.ts
+/** Hands the connections on the host's socket out to the workers in turn.
+ *
+ * The host creates the socket and passes it to the primary as a file descriptor. The primary
+ * listens on it itself rather than leaving that to the cluster module, which closes a listening
+ * socket when its last worker exits, and a socket that came from the host can't be opened again. */
+class ConnectionDispatcher {
+  /** The workers that are taking connections. */
+  workers = [];
+  next = 0;
+  /** Connections that arrived while no worker was taking them, such as while the only worker is
+   * being replaced. */
+  waiting = [];
+  listening = false;
+
+  constructor(fd) {
+    this.fd = fd;
+    this.server = net.createServer({ pauseOnConnect: true }, (socket) => this.dispatch(socket));
+    this.server.on('error', (e) => {
+      console.error(e);
+      process.exit(1);
+    });
+  }
+
+  /** Start sending connections to `worker`. The socket only starts listening once the first
+   * worker is ready, so that the host can't connect before then. */
+  add(worker) {
+    this.workers.push(worker);
+    if (!this.listening) {
+      this.listening = true;
+      this.server.listen({ fd: this.fd });
+    }
+
+    for (const socket of this.waiting.splice(0)) {
+      this.dispatch(socket);
+    }
+  }
+
+  /** Stop sending connections to `worker`, because it is retiring or has exited. */
+  remove(worker) {
+    const index = this.workers.indexOf(worker);
+    if (index !== -1) {
+      this.workers.splice(index, 1);
+    }
+  }
+
+  dispatch(socket) {
+    if (this.workers.length === 0) {
+      this.waiting.push(socket);
+      return;
+    }
+
+    const worker = this.workers[this.next++ % this.workers.length];
+    worker.send('connection', socket, (e) => {
+      if (e) {
+        debug(`Failed to pass a connection to worker ${worker.process.pid}`, e);
+        this.remove(worker);
+        this.dispatch(socket);
+      }
+    });
+  }
+
+  close() {
+    this.server.close();
+    for (const socket of this.waiting.splice(0)) {
+      socket.destroy();
+    }
+  }
+}
+
+// src/index.ts
+/** A file descriptor number from the command line or the environment. */
+function parseFd(value) {
+  return value ? parseInt(value, 10) : undefined;
+}
+
+if (!isMainThread) {
+  // A connection thread in a worker started with `--isolation thread`.
+  runThread();
+} else if (cluster.isPrimary) {
+  const filename = process.argv[1];
+
+  // Parse command line arguments
+  const { values } = parseArgs({
+    options: {
+      workers: {
+        type: 'string',
+        default: os.cpus().length.toString(),
+      },
+      socket: {
+        type: 'string',
+      },
+      'socket-fd': {
+        type: 'string',
+      },
+      'memory-report-interval': {
+        type: 'string',
+      },
+      'load-report-interval': {
+        type: 'string',
+      },
+      'context-pool-size': {
+        type: 'string',
+      },
+      websocket: {
+        type: 'string',
+      },
+      management: {
+        type: 'string',
+      },
+      'events-fd': {
+        type: 'string',
+      },
+      isolation: {
+        type: 'string',
+        default: 'process',
+      },
+    },
+  });
+
+  const numWorkers = parseInt(values.workers ?? '1', 10);
+  const socketPath = values.socket;
+  let shuttingDown = false;
+
+  if (!socketPath) {
+    throw new Error('No socket path provided');
+  }
+  const socketFd = parseFd(values['socket-fd']);
+  if (socketFd === undefined) {
+    throw new Error('No socket file descriptor provided');
+  }
+  const dispatcher = new ConnectionDispatcher(socketFd);
+
+  const managementServer = values.management ? startManagementServer(values.management) : null;
+  const sendEvent = eventSender(parseFd(values['events-fd']));
+
+  process.on('exit', () => {
+    // Make sure to clean up the socket file when the process exits
+    try {
+      fs.unlinkSync(socketPath);
+    } catch (e) {}
+  });
+
+  function forkWorker() {
+    if (shuttingDown) {
+      return;
+    }
+
+    let worker = cluster.fork({
+      SOCKET_PATH: socketPath,
+      MEMORY_REPORT_INTERVAL: values['memory-report-interval'] ?? '0',
+      LOAD_REPORT_INTERVAL: values['load-report-interval'] ?? '0',
+      CONTEXT_POOL_SIZE: values['context-pool-size'] ?? '0',
+      WEBSOCKET_ADDRESS: values.websocket ?? '',
+      ISOLATION: values.isolation,
+    });
+
+    worker.on('message', (msg) => {
+      if (msg === 'ready' && shuttingDown) {
+        // We started shutting down between when this worker was forked and when it
+        // started listening to messages, so tell it again.
+        worker.send('shutdown');
+      } else if (msg === 'ready') {
+        dispatcher.add(worker);
+      } else if (msg === 'retiring') {
+        dispatcher.remove(worker);
+      } else if (isCrashMessage(msg)) {
+        const pid = worker.process.pid ?? 0;
+        sendEvent({ type: 'workerCrashed', pid, report: (msg ).report });
+      }
+    });
+  }
+
+  const shutdown = () => {
+    debug('shutting down');
+    if (shuttingDown) {
+      // Double SIGINT means the shutdown is taking longer than the user wants, so just quit now.
+      process.exit(1);
+    }
+
+    shuttingDown = true;
+    managementServer?.close();
+    dispatcher.close();
+    for (let worker of Object.values(cluster.workers ?? {})) {
+      worker?.send('shutdown', () => {});
+    }
+  };
+
+  process.on('SIGTERM', shutdown);
+  process.on('SIGINT', shutdown);
+
+  cluster.on('online', (worker) => {
+    debug('online', worker.process.pid, shuttingDown);
+    sendEvent({ type: 'workerStarted', pid: worker.process.pid ?? 0 });
+    if (shuttingDown) {
+      worker.kill('SIGKILL');
+    }
+  });
+
+  cluster.on('exit', (worker, code, signal) => {
+    debug('exit', worker.process.pid, code, signal, shuttingDown, socketPath);
+    dispatcher.remove(worker);
+    sendEvent({
+      type: 'workerExited',
+      pid: worker.process.pid ?? 0,
+      code: code ?? null,
+      signal: signal ?? null,
+    });
+    if (!shuttingDown && !fs.existsSync(filename)) {
+      // This happens when the Rust side shuts down somewhat uncleanly.
+      debug(`${socketPath} script is gone, shutting down`);
+      shutdown();
+    }
+
+    if (shuttingDown) {
+      const remainingWorkers = Object.values(cluster.workers ?? {}).map((w) => w?.process.pid);
+      debug(socketPath, 'remaining workers:', remainingWorkers);
+      if (remainingWorkers.length == 0) {
+        process.exit(0);
+      }
+      return;
+    }
+
+    if (signal) {
+      debug(`Worker ${worker.process.pid} died with signal ${signal}. Restarting...`);
+    } else {
+      debug(`Worker ${worker.process.pid} died with code ${code}. Restarting...`);
+    }
+    forkWorker();
+  });
+
+  debug(
+    `Primary ${process.pid} is running, starting ${numWorkers} workers and connecting to ${socketPath}`
+  );
+
+  for (let i = 0; i < numWorkers; i++) {
+    forkWorker();
+  }
+} else {
+  runWorker(
+    process.env.SOCKET_PATH ,
+    process.env.WEBSOCKET_ADDRESS || undefined,
+    process.env.ISOLATION === 'thread' ? 'thread' : 'process'
+  );
+}
 //# sourceMappingURL=index.js.map
@@ -0,0 +1,62 @@
+import { describe, it, expect } from 'vitest';
+import { abortAfter, cancelledError, isTimeout, timeoutError, untilAborted } from './abort';
+
+describe('untilAborted', () => {
+  it('settles with the promise when the signal is not aborted', async () => {
+    const controller = new AbortController();
+    await expect(untilAborted(Promise.resolve(5), controller.signal)).resolves.toBe(5);
+    await expect(
+      untilAborted(Promise.reject(new Error('failed')), controller.signal)
+    ).rejects.toThrow('failed');
+  });
+
+  it('fails with the reason once the promise settles after an abort', async () => {
+    const controller = new AbortController();
+    const cleanedUp: string[] = [];
+    const work = new Promise<string>((resolve) => {
+      controller.signal.addEventListener('abort', () => {
+        cleanedUp.push('listener');
+        setTimeout(() => resolve('partial'), 5);
+      });
+    });
+
+    const result = untilAborted(work, controller.signal, 1000);
+    controller.abort(cancelledError());
+    await expect(result).rejects.toMatchObject({ name: 'AbortError' });
+    expect(cleanedUp).toEqual(['listener']);
+  });
+
+  it('fails after the grace period when the promise never settles', async () => {
+    const controller = new AbortController();
+    const result = untilAborted(new Promise(() => {}), controller.signal, 10);
+    controller.abort(cancelledError());
+    await expect(result).rejects.toThrow('cancelled by the host');
+  });
+
+  it('aborts with a TimeoutError', async () => {
+    const controller = new AbortController();
+    abortAfter(controller, 5);
+    const result = untilAborted(new Promise(() => {}), controller.signal, 10);
+    await expect(result).rejects.toMatchObject({ name: 'TimeoutError' });
+  });
+
+  it('stops the timeout', async () => {
+    const controller = new AbortController();
+    abortAfter(controller, 5)();
+    await new Promise((resolve) => setTimeout(resolve, 20));
+    expect(controller.signal.aborted).toBe(false);
+  });
+});
+
+describe('isTimeout', () => {
+  it('recognizes both kinds of timeout', () => {
+    const interrupted = Object.assign(new Error('Script execution timed out after 100ms'), {
+      code: 'ERR_SCRIPT_EXECUTION_TIMEOUT',
+    });
+    expect(isTimeout(interrupted)).toBe(true);
+    expect(isTimeout(timeoutError(100))).toBe(true);
+    expect(isTimeout(cancelledError())).toBe(false);
+    expect(isTimeout(new Error('failed'))).toBe(false);
+    expect(isTimeout('not an error')).toBe(false);
+  });
+});
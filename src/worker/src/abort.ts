@@ -0,0 +1,60 @@
+/** How long a run has to finish cleaning up after its signal aborts, before it fails anyway. */
+export const ABORT_GRACE_MS = 1000;
+
+/** The reason given to a run's signal when the host cancels it. */
+export function cancelledError() {
+  return new DOMException('The run was cancelled by the host', 'AbortError');
+}
+
+/** The reason given to a run's signal when its timeout passes. */
+export function timeoutError(ms: number) {
+  return new DOMException(`The run timed out after ${ms}ms`, 'TimeoutError');
+}
+
+/** True if the run failed because it passed its timeout, whether that interrupted synchronous code
+ * or aborted the run's signal while it waited on promises. */
+export function isTimeout(e: any) {
+  return (
+    e?.code === 'ERR_SCRIPT_EXECUTION_TIMEOUT' ||
+    (e instanceof DOMException && e.name === 'TimeoutError')
+  );
+}
+
+/** Abort the controller once `ms` have passed, returning a function that stops the timer. */
+export function abortAfter(controller: AbortController, ms: number): () => void {
+  const timer = setTimeout(() => controller.abort(timeoutError(ms)), ms);
+  return () => clearTimeout(timer);
+}
+
+/** Wait for a run to finish, or fail with the signal's reason if it aborts first. The listeners on
+ * the signal run as soon as it aborts, and the run then has `graceMs` to settle, so that
+ * cooperative code can clean up and log what it did. A run that ignores the signal keeps going in
+ * the background, but its result is thrown away. */
+export function untilAborted<T>(
+  promise: Promise<T>,
+  signal: AbortSignal,
+  graceMs = ABORT_GRACE_MS
+): Promise<T> {
+  return new Promise<T>((resolve, reject) => {
+    let grace: NodeJS.Timeout | undefined;
+    const onAbort = () => {
+      grace = setTimeout(() => reject(signal.reason), graceMs);
+    };
+
+    if (signal.aborted) {
+      onAbort();
+    } else {
+      signal.addEventListener('abort', onAbort, { once: true });
+    }
+
+    promise
+      .then(
+        (value) => (signal.aborted ? reject(signal.reason) : resolve(value)),
+        (e) => reject(signal.aborted ? signal.reason : e)
+      )
+      .finally(() => {
+        clearTimeout(grace);
+        signal.removeEventListener('abort', onAbort);
+      });
+  });
+}
@@ -0,0 +1,29 @@
+import { describe, it, expect } from 'vitest';
+import { annotateStack, crashReport, formatAnnotations } from './annotations';
+import type { MessageContext } from './types';
+
+describe('annotations', () => {
+  it('formats key-value pairs', () => {
+    expect(formatAnnotations({ user: '12', feature: 'search' })).toBe('user=12 feature=search');
+    expect(formatAnnotations(undefined)).toBe('');
+  });
+
+  it('appends annotations to a stack', () => {
+    expect(annotateStack('Error: x\n    at y', { user: '12' })).toBe(
+      'Error: x\n    at y\n    [annotations: user=12]'
+    );
+    expect(annotateStack('Error: x', {})).toBe('Error: x');
+    expect(annotateStack(undefined, { user: '12' })).toBeUndefined();
+  });
+
+  it('lists running requests in a crash report', () => {
+    const requests = [
+      { reqId: 3, annotations: { feature: 'search' } },
+      { reqId: 4 },
+    ] as MessageContext[];
+    const report = crashReport(new Error('boom'), requests);
+    expect(report).toContain('Error: boom');
+    expect(report).toContain('running request 3 [feature=search]');
+    expect(report).toContain('running request 4');
+  });
+});
@@ -0,0 +1,39 @@
+import type { MessageContext } from './types.js';
+
+export type Annotations = Record<string, string>;
+
+/** Requests currently being handled by this worker, so that a crash can be attributed to them. */
+export const activeRequests = new Set<MessageContext>();
+
+/** Format annotations as `key=value` pairs for diagnostic output. */
+export function formatAnnotations(annotations: Annotations | undefined): string {
+  if (!annotations) {
+    return '';
+  }
+
+  return Object.entries(annotations)
+    .map(([key, value]) => `${key}=${value}`)
+    .join(' ');
+}
+
+/** Add the run's annotations to the end of an error's stack trace. */
+export function annotateStack(stack: string | undefined, annotations: Annotations | undefined) {
+  const formatted = formatAnnotations(annotations);
+  if (!stack || !formatted) {
+    return stack;
+  }
+
+  return `${stack}\n    [annotations: ${formatted}]`;
+}
+
+/** Describe the requests that were running when the worker crashed. */
+export function crashReport(e: unknown, requests: Iterable<MessageContext>): string {
+  const error = e instanceof Error ? (e.stack ?? e.message) : String(e);
+  const lines = [`Worker ${process.pid} crashed: ${error}`];
+  for (const request of requests) {
+    const formatted = formatAnnotations(request.annotations);
+    lines.push(`  running request ${request.reqId}${formatted ? ` [${formatted}]` : ''}`);
+  }
+
+  return lines.join('\n');
+}
@@ -7,6 +7,45 @@ export enum HostToWorkerMessage {
   RunScript = 0,
   /** Host checking connection integrity */
   Ping = 1,
+  /** Take a heap snapshot of the worker and send it back in HeapSnapshotChunk messages. */
+  HeapSnapshot = 2,
+  /** Sent by the host when it connects, to check that both sides speak the same protocol. */
+  Handshake = 3,
+  /** The answer to a KvRequest. */
+  KvResponse = 4,
+  /** Advance the virtual clock of the connection's context, running the timers that come due. */
+  AdvanceTime = 5,
+  /** RunScript with binary data, such as WASM modules, after the arguments. The payload is the
+   * length of the JSON arguments as a u32, the JSON, and then the binary data. */
+  RunScriptBinary = 6,
+  /** The answer to a ResolveModule request. */
+  ResolveModuleResponse = 7,
+  /** Abort the signal of the run with the message's request ID. The run fails once it has
+   * cleaned up. */
+  Cancel = 8,
+  /** A chunk of bytes for the `input` global of the run with the message's request ID. */
+  Input = 9,
+  /** Ends the `input` of the run with the message's request ID. */
+  InputEnd = 10,
+  /** A JSON value for the `channel` global of the run with the message's request ID. */
+  ChannelMessage = 11,
+  /** Closes the host's side of the channel of the run with the message's request ID. */
+  ChannelClose = 12,
+  /** Ask for completions of the end of a line of code, from the globals and lexical declarations
+   * of the connection's context. */
+  Complete = 13,
+  /** List the properties of a value in the connection's context, or its globals, with hints
+   * about what each one holds. */
+  Introspect = 14,
+  /** Compile code without running it, and report its syntax errors. */
+  CheckScript = 15,
+  /** Get the status of a background job started by a RunScript with `detach` set. */
+  JobStatus = 16,
+  /** Abort the signal of a background job. */
+  JobCancel = 17,
+  /** Build a context from a prewarm manifest, and keep one ready for runs that ask for it by
+   * name. */
+  Prewarm = 18,
 }
 
 // Worker-to-host
@@ -15,8 +54,43 @@ export enum WorkerToHostMessage {
   Log = 0x1001,
   Error = 0x1002,
   Pong = 0x1003,
+  /** Sent periodically on each connection when memory reporting is enabled. */
+  MemoryUsage = 0x1004,
+  /** A run with `debug` set is waiting for a debugger to attach. */
+  DebuggerWaiting = 0x1005,
+  /** The V8 CPU profile of a run with `profile` set, sent just before its response. The body is
+   * the profile itself, in `.cpuprofile` format. */
+  CpuProfile = 0x1006,
+  /** Part of a heap snapshot. The chunks are followed by an empty RunResponse. */
+  HeapSnapshotChunk = 0x1007,
+  /** The response to a Handshake. */
+  Handshake = 0x1008,
+  /** Part of a RunResponse that is too large for one frame. The chunks are followed by a
+   * RunResponse holding the rest of the data. */
+  RunResponseChunk = 0x1009,
+  /** Sent in place of a message that is over the size limits from the handshake. */
+  MessageTooLarge = 0x100a,
+  /** A script called the `kv` global. The host answers with a KvResponse. */
+  KvRequest = 0x100b,
+  /** An import didn't match any module in the context. The host answers with a
+   * ResolveModuleResponse. */
+  ResolveModule = 0x100c,
+  /** Sent periodically on each connection when load reporting is enabled. */
+  WorkerLoad = 0x100d,
+  /** A context's code threw from a callback or left a promise rejection unhandled, outside of
+   * the run that it belongs to. Tagged with the request that last used the context. */
+  AsyncError = 0x100e,
+  /** Bytes that a run with `streamOutput` set passed to the `write` global. Output larger than a
+   * frame is split across several messages. */
+  Output = 0x100f,
+  /** A JSON value that a run passed to `channel.send`. */
+  ChannelMessage = 0x1010,
 }
 
+/** The version of the wire protocol: the message framing and the set of message types. This must
+ * match `PROTOCOL_VERSION` in the Rust crate, and changes whenever either of them changes. */
+export const PROTOCOL_VERSION = 20;
+
 /** A function to be injected into the context. */
 export interface FunctionDef {
   name: string;
@@ -30,6 +104,32 @@ export interface CodeModule {
   code: string;
 }
 
+/** A module that the context already has from an earlier run, sent without its code */
+export interface CachedModule {
+  name: string;
+  /** The SHA-256 hash of the module's code, as hex */
+  hash: string;
+}
+
+/** A script from the host's registry. The host sends its code the first time the connection
+ * runs it, and leaves the code out after that. */
+export interface RegisteredScript {
+  /** The script's name and version, for errors */
+  name: string;
+  /** The SHA-256 hash of the script's code, as hex */
+  hash: string;
+}
+
+/** A WebAssembly module to instantiate in the context */
+export interface WasmModule {
+  /** The name of the global for the module's exports */
+  name: string;
+  /** The length of the module in the binary data of the message */
+  length: number;
+  /** The module itself, taken from the binary data */
+  bytes?: Buffer;
+}
+
 /** Data associated with the RunScript message */
 export interface RunScriptArgs {
   name: string;
@@ -48,7 +148,21 @@ export interface RunScriptArgs {
   /** Global variables to set in the context. */
   globals?: object;
 
-  /** How long to wait for the script to complete. */
+  /** If true, `code` is a function, or the name of one, which is called with `args`.
+   * The function's return value, awaited if it is a Promise, is the run's return value. */
+  call?: boolean;
+
+  /** Arguments for the function in call mode. */
+  args?: any[];
+
+  /** Wait for promises in the returned globals to resolve, and return their values. */
+  awaitResult?: boolean;
+
+  /** Use the tagged encoding from `structured.ts` for globals, arguments, and return values, to
+   * preserve types that JSON can't represent. */
+  extendedValues?: boolean;
+
+  /** How long to wait for the script to complete. The run's `signal` aborts when this passes. */
   timeoutMs?: number;
 
   /** Functions to compile and place in the global scope */
@@ -57,21 +171,490 @@ export interface RunScriptArgs {
   /** ES Modules to make available for the code to import. */
   modules?: CodeModule[];
 
-  /** If set, return only these keys from the context. If omitted, the entire global context is returned. */
+  /** Modules from an earlier run that the context should still have, with the same code. The host
+   * sends these instead of sending the same code again. */
+  cachedModules?: CachedModule[];
+
+  /** Set when the code is a script from the host's registry. */
+  registeredScript?: RegisteredScript;
+
+  /** Run the script as a background job that keeps going after the connection closes. The run
+   * responds right away with the job's status, and runs in its own context. */
+  detach?: boolean;
+
+  /** If set, return only these keys from the context. If omitted, the entire global context is returned.
+   * Each key can be a path selector such as `user.profile.name` or `items[*].id`, whose result is
+   * returned under the selector itself. See `select.ts` for the syntax. */
   returnKeys?: string[];
+
+  /** `debug`-style patterns for the console namespaces to forward, e.g. `['db:*', '-db:verbose']`.
+   * Messages without a namespace are always forwarded. */
+  logNamespaces?: string[];
+
+  /** The name of a global function that handles the run's console calls instead of forwarding
+   * them. It is called with `{ level, args, namespace }` and a `forward(level, args, namespace)`
+   * function. */
+  consoleHandler?: string;
+
+  /** Only send console messages at this level or above to the host. */
+  minLogLevel?: LogLevel;
+
+  /** The maximum size, in UTF-8 bytes, of any single string in the globals or return value. */
+  maxStringBytes?: number;
+
+  /** Replace unpaired UTF-16 surrogates in returned strings with U+FFFD instead of failing. */
+  replaceInvalidUnicode?: boolean;
+
+  /** Small metadata about the run, such as a user ID or feature name, which is included in
+   * error stacks and crash reports. */
+  annotations?: Record<string, string>;
+
+  /** Wait for a debugger to attach to the worker's inspector, and pause at the start of the code. */
+  debug?: boolean;
+
+  /** Record a CPU profile of the run and send it in a CpuProfile message. */
+  profile?: boolean;
+
+  /** Fail the run if any of the globals can't be sent, instead of leaving them out and listing
+   * them in `skippedGlobals`. */
+  strictGlobals?: boolean;
+
+  /** How timers behave in the context. This only applies when the context is created. */
+  timers?: TimerMode;
+
+  /** The most timers that the run can set, counting timers set by other timers. */
+  maxTimers?: number;
+
+  /** What to do with timers that are still pending when the script finishes. Defaults to
+   * `return`. */
+  drain?: DrainPolicy;
+
+  /** With the `wait` drain policy, the most time to wait for timers, in milliseconds. Defaults
+   * to 1000. */
+  maxDrainMs?: number;
+
+  /** Let the script send output to the host with the `write` global, in Output messages. */
+  streamOutput?: boolean;
+
+  /** Give the script an `input` global that yields the chunks that the host sends in Input
+   * messages. */
+  streamInput?: boolean;
+
+  /** Run the code as an entry in a REPL: like `expr` mode, but the return value is rendered as
+   * text in the way that Node's REPL shows it, top-level `let` and `const` declarations are
+   * remembered for completions, and globals are only returned if they are in `returnKeys`. */
+  repl?: boolean;
+
+  /** Record the run's effects on the host, instead of carrying them out, and return them in the
+   * response's `effects`. `kv` writes, `write` output and `channel.send` values are recorded,
+   * while `kv` reads still go to the host, with the run's own writes laid over them. Modules that
+   * the context doesn't already have can't be resolved by the host. */
+  dryRun?: boolean;
+
+  /** Make the context deterministic: `Math.random` is seeded at the start of each run, `Date`
+   * reads a virtual clock that starts at the epoch, real timers are disabled, and `WeakRef` and
+   * `FinalizationRegistry` are removed. This only applies when the context is created. */
+  deterministic?: boolean;
+
+  /** The seed for `Math.random` in a deterministic context. Defaults to 0. */
+  randomSeed?: number;
+
+  /** When the run creates a context, take the one built by Prewarm with this name, if the worker
+   * has one ready. */
+  prewarmedContext?: string;
+
+  /** The most console messages that the run can send. Later messages are dropped, and a summary
+   * of what was dropped is logged at the end of the run. */
+  maxLogMessages?: number;
+
+  /** The most bytes of console messages, as sent to the host, that the run can send. */
+  maxLogBytes?: number;
+
+  /** WebAssembly modules to instantiate, with no imports, before the code runs. */
+  wasmModules?: WasmModule[];
+
+  /** The run's lane on the worker. Defaults to `normal`. */
+  priority?: Priority;
+
+  /** Values exposed to the script in a frozen `secrets` global, which are replaced with
+   * `[REDACTED]` wherever they appear in the run's logs, error, and response. */
+  secrets?: Record<string, string>;
+}
+
+/** How `setTimeout` and `setInterval` behave in a context
+ *
+ * - `real`: timers run on the worker's clock
+ * - `disabled`: setting a timer throws
+ * - `virtual`: timers run when the host sends AdvanceTime, and `Date` reads the same clock
+ */
+export type TimerMode = 'real' | 'disabled' | 'virtual';
+
+/** What happens to the timers that a script leaves pending when it finishes
+ *
+ * - `return`: respond right away, and let the timers run later
+ * - `wait`: wait for the timers, up to `maxDrainMs`, and respond with the globals they left
+ * - `cancel`: clear the timers, and respond right away
+ */
+export type DrainPolicy = 'return' | 'wait' | 'cancel';
+
+/** The lane that a run waits in on the worker. A run doesn't start while a run with a higher
+ * priority is active, for up to a second. */
+export type Priority = 'high' | 'normal' | 'low';
+
+/** Data associated with the Prewarm message */
+export interface PrewarmContext {
+  name: string;
+  globals?: Record<string, unknown>;
+  functions?: FunctionDef[];
+  modules?: CodeModule[];
+}
+
+/** Data associated with the CheckScript message */
+export interface CheckScript {
+  code: string;
+  /** The name of the script, which relative imports are resolved against */
+  name?: string;
+  /** Check the code as an expression, as with `expr` in RunScriptArgs */
+  expr?: boolean;
+  /** Check the code as a function, as with `call` in RunScriptArgs */
+  call?: boolean;
+  /** Also look for problems that would fail the run, such as imports of missing modules */
+  lint?: boolean;
+  /** The names of the modules that the code can import, for `lint` */
+  modules?: string[];
+}
+
+/** A problem found by CheckScript. Lines and columns start at 1. */
+export interface Diagnostic {
+  message: string;
+  line?: number;
+  column?: number;
+}
+
+/** The result of CheckScript, sent as the return value of a RunResponse */
+export interface ScriptCheck {
+  /** Errors that keep the code from compiling */
+  errors: Diagnostic[];
+  /** Problems found by `lint` */
+  warnings: Diagnostic[];
+}
+
+/** Data associated with the JobStatus and JobCancel messages */
+export interface JobRequest {
+  jobId: number;
+  /** The token that the worker gave the job when it started */
+  token: string;
+  /** Forget the job once it has finished, after returning its status. */
+  remove?: boolean;
+}
+
+export type JobState = 'running' | 'succeeded' | 'failed' | 'cancelled';
+
+/** The status of a background job, sent as the return value of a RunResponse */
+export interface JobStatus {
+  jobId: number;
+  /** The process ID of the worker running the job */
+  pid: number;
+  state: JobState;
+  /** The response of a job that succeeded */
+  response?: RunResponse;
+  /** The error of a job that failed or was cancelled */
+  error?: ErrorResponse;
+  /** The job's console messages so far, up to a limit */
+  logs: LogMessage[];
+  /** The number of console messages dropped after the limit */
+  droppedLogs: number;
+}
+
+/** Data associated with the AdvanceTime message */
+export interface AdvanceTime {
+  /** How far to move the clock, in milliseconds */
+  ms: number;
+}
+
+/** Data associated with the Complete message */
+export interface Complete {
+  /** The code up to the cursor */
+  line: string;
+}
+
+/** The result of Complete, sent as the return value of a RunResponse */
+export interface Completions {
+  /** The partial name at the end of the line, which each candidate starts with */
+  prefix: string;
+  /** The names that can complete the prefix, in sorted order */
+  candidates: string[];
+}
+
+/** Data associated with the Introspect message */
+export interface Introspect {
+  /** A path of property names, such as `user.profile`, for the value whose properties to list,
+   * or empty for the globals */
+  expr: string;
+  /** Only list the names that start with this */
+  prefix: string;
+}
+
+/** What a property holds. `accessor` is a property with a getter or setter, which isn't run to
+ * find out. */
+export type ValueKind =
+  | 'undefined'
+  | 'null'
+  | 'boolean'
+  | 'number'
+  | 'bigint'
+  | 'string'
+  | 'symbol'
+  | 'function'
+  | 'class'
+  | 'array'
+  | 'object'
+  | 'accessor';
+
+/** A property from Introspect, sent in an array as the return value of a RunResponse */
+export interface PropertyHint {
+  name: string;
+  kind: ValueKind;
+  /** The parameters of a function, the length of an array, or the constructor of an object */
+  detail?: string;
+}
+
+/** The result of AdvanceTime, sent as the return value of a RunResponse */
+export interface AdvanceTimeResult {
+  /** The virtual clock after advancing, in milliseconds since the epoch */
+  now: number;
+  /** How many timer callbacks ran */
+  timersRun: number;
+  /** Timers that are still waiting */
+  pendingTimers: number;
 }
 
 export interface RunResponse {
   globals?: object;
   returnValue?: any;
+  stats?: RunStats;
+  /** Globals that were left out because they can't be sent to the host */
+  skippedGlobals?: SkippedGlobal[];
+  /** What a run with `dryRun` set would have done, in order */
+  effects?: Effect[];
 }
 
+/** An effect of a dry run, which was recorded instead of reaching the host */
+export type Effect =
+  | { type: 'kv'; op: 'set'; key: string; value: any }
+  | { type: 'kv'; op: 'delete'; key: string }
+  /** Output from the `write` global, in base64 */
+  | { type: 'output'; data: string }
+  /** A value sent with `channel.send` */
+  | { type: 'channel'; value: any };
+
+/** A global that was left out of a response */
+export interface SkippedGlobal {
+  key: string;
+  /** Why the global can't be sent, such as a circular reference */
+  reason: string;
+}
+
+/** Measurements of a single run, taken inside the worker. */
+export interface RunStats {
+  /** Time spent compiling the script and its functions and modules */
+  compileMs: number;
+  /** Time spent running the script and waiting for its result and timers */
+  executeMs: number;
+  /** The change in the worker's heap usage over the run, in bytes. This can be negative if
+   * garbage collection ran. */
+  heapUsedDelta: number;
+  /** False if the run left timers or immediates pending that weren't there when it started */
+  macrotasksDrained: boolean;
+}
+
+/** What kind of failure an error is, so the host can decide whether to retry it or show it to a
+ * user without matching on the message. */
+export type ErrorKind =
+  | 'script'
+  | 'syntaxError'
+  | 'referenceError'
+  | 'timeout'
+  | 'outOfMemory'
+  | 'moduleResolution'
+  | 'cancelled'
+  | 'internalWorkerError'
+  | 'stringTooLong';
+
 export interface ErrorResponse {
   message: string;
   stack?: string;
+  annotations?: Record<string, string>;
+  /** The run passed its timeout, and the worker threw away its context. */
+  timedOut?: boolean;
+  kind: ErrorKind;
+  /** The `name` of the thrown error, such as `TypeError` */
+  name?: string;
+  /** The `code` of the thrown error, if it has one */
+  code?: string;
+  /** The error's `cause`, if it has one */
+  cause?: ErrorResponse;
+  /** The errors collected by an AggregateError */
+  errors?: ErrorResponse[];
 }
 
+/** How an error escaped from a context's code. */
+export type AsyncErrorSource = 'unhandledRejection' | 'uncaughtException';
+
+/** Data associated with the AsyncError message */
+export interface AsyncError {
+  source: AsyncErrorSource;
+  error: ErrorResponse;
+}
+
+/** Data associated with the MemoryUsage message, in bytes */
+export interface MemoryUsage {
+  pid: number;
+  heapUsed: number;
+  heapTotal: number;
+  rss: number;
+  external: number;
+}
+
+/** Data associated with the WorkerLoad message */
+export interface WorkerLoad {
+  pid: number;
+  /** Runs that have started and not yet finished */
+  activeRuns: number;
+  /** Open connections to the worker */
+  connections: number;
+  /** The average event loop delay since the last report, in milliseconds */
+  eventLoopDelayMs: number;
+}
+
+export type LogLevel = 'trace' | 'debug' | 'info' | 'warn' | 'error';
+
 export interface LogMessage {
-  level: string;
+  level: LogLevel;
   message: string | object;
+  /** Milliseconds since the Unix epoch when the message was logged. */
+  timestamp: number;
+  /** The request that was running when the message was logged. */
+  requestId: number;
+  /** The logger name, from a `[namespace]` message prefix or a `logger(namespace)` console. */
+  namespace?: string;
+}
+
+/** Data associated with the DebuggerWaiting message */
+export interface DebuggerWaiting {
+  pid: number;
+  /** The inspector's WebSocket URL, for Chrome DevTools or VS Code to connect to */
+  url: string;
+}
+
+/** Returned from the management endpoint's `/health` route. */
+export interface SidecarHealth {
+  /** The process ID of the Node.js primary process */
+  pid: number;
+  /** The number of worker processes that are running */
+  workers: number;
+  uptimeMs: number;
+}
+
+/** Returned for each worker from the management endpoint's `/workers` route. */
+export interface WorkerStats {
+  pid: number;
+  uptimeMs: number;
+  /** Open connections from the host */
+  connections: number;
+  /** Connections with a run context, which holds the globals and modules of earlier runs */
+  contexts: number;
+  /** Requests that are currently running */
+  activeRequests: number;
+  /** Scripts run since the worker started */
+  requestsHandled: number;
+  memory: MemoryUsage;
+}
+
+/** Sent by the primary process to the host over the events socket, one JSON object per line. */
+export type SidecarEvent =
+  | { type: 'workerStarted'; pid: number }
+  | { type: 'workerExited'; pid: number; code: number | null; signal: string | null }
+  /** A worker hit an uncaught exception or unhandled rejection and is exiting. */
+  | { type: 'workerCrashed'; pid: number; report: string };
+
+/** Data associated with the Handshake message from the host */
+export interface Handshake {
+  /** The host's protocol version */
+  version: number;
+  /** Add a checksum to every frame sent to the host. */
+  checksums?: boolean;
+  /** The largest frame the host will read. Larger responses are split into chunks, and other
+   * larger messages are replaced with MessageTooLarge. */
+  maxFrameBytes?: number;
+  /** The largest response the host will accept. */
+  maxResponseBytes?: number | null;
+  /** The host has a KV backend, so contexts get the `kv` global. */
+  kv?: boolean;
+  /** The host has a module resolver, so imports of unknown modules are sent to it. */
+  resolveModules?: boolean;
+  /** URL prefixes that scripts can import modules from */
+  urlImports?: string[];
+  /** How long to use a module fetched for a URL import before fetching it again */
+  urlImportTtlMs?: number;
+  /** Compress frames with payloads larger than this many bytes. */
+  compressFramesOver?: number;
+  /** The directory to pass large payloads through instead of the socket */
+  sharedMemoryDir?: string;
+  /** Pass payloads larger than this many bytes through the shared memory directory. */
+  sharedMemoryOver?: number;
+}
+
+/** A call to the `kv` global */
+export type KvRequest =
+  | { op: 'get'; key: string }
+  | { op: 'set'; key: string; value: any }
+  | { op: 'delete'; key: string }
+  | { op: 'list'; prefix: string };
+
+/** Data associated with the KvResponse message */
+export interface KvResponse {
+  /** The ID from the KvRequest */
+  id: number;
+  /** The result of the operation */
+  value?: any;
+  /** Set if the host's backend failed */
+  error?: string;
+}
+
+/** Data associated with the ResolveModule message */
+export interface ResolveModuleRequest {
+  /** Matches the request to its response */
+  id: number;
+  /** The imported specifier, joined to the referrer's name if it is relative */
+  specifier: string;
+  /** The name of the importing module */
+  referrer: string;
+}
+
+/** Data associated with the ResolveModuleResponse message */
+export interface ResolveModuleResponse {
+  /** The ID from the ResolveModule request */
+  id: number;
+  /** The module's name and code, or missing if the host doesn't know the module */
+  module?: CodeModule;
+  /** Set if the host's resolver failed */
+  error?: string;
+}
+
+/** Data associated with the MessageTooLarge message */
+export interface MessageTooLarge {
+  /** The size of the message that was dropped, in bytes */
+  length: number;
+  /** The limit that it exceeded */
+  limit: number;
+}
+
+/** Data associated with the Handshake response */
+export interface HandshakeResponse {
+  /** The worker's protocol version */
+  version: number;
+  /** False if the versions don't match. The worker closes the connection after sending this. */
+  accepted: boolean;
+  pid: number;
 }
@@ -0,0 +1,41 @@
+import { describe, it, expect } from 'vitest';
+import type { MessageContext } from './types.js';
+import { ScriptChannel } from './channel';
+import { Redactor } from './secrets';
+
+describe('ScriptChannel', () => {
+  const createMessageContext = (sent: string[]) =>
+    ({
+      reqId: 1,
+      protocol: {
+        channelMessage: async (_reqId: number, json: string) => {
+          sent.push(json);
+        },
+      },
+      redactor: new Redactor({ KEY: 'hunter2' }),
+    }) as unknown as MessageContext;
+
+  it('sends values as redacted JSON', async () => {
+    const sent: string[] = [];
+    const channel = new ScriptChannel(createMessageContext(sent));
+    await channel.send({ password: 'hunter2', n: 1 });
+    expect(sent).toEqual(['{"password":"[REDACTED]","n":1}']);
+    expect(() => channel.send(undefined)).toThrow("can't be converted");
+  });
+
+  it('receives values until the host closes it', async () => {
+    const channel = new ScriptChannel(createMessageContext([]));
+    channel.incoming.push({ a: 1 });
+    expect(await channel.receive()).toEqual({ a: 1 });
+
+    channel.incoming.push(null);
+    channel.incoming.push(2);
+    channel.incoming.end();
+    const received: unknown[] = [];
+    for await (const value of channel) {
+      received.push(value);
+    }
+    expect(received).toEqual([null, 2]);
+    expect(await channel.receive()).toBeUndefined();
+  });
+});
@@ -0,0 +1,38 @@
+import type { MessageContext } from './types.js';
+import { AsyncQueue } from './queue.js';
+
+/** The `channel` global, which exchanges JSON values with the host while the run is going. Values
+ * from the host are read with `receive()` or `for await`, which finish once the host closes its
+ * side. Values sent after the run finishes are ignored by the host. */
+export class ScriptChannel {
+  ctx: MessageContext;
+  incoming: AsyncQueue<unknown>;
+
+  constructor(ctx: MessageContext) {
+    this.ctx = ctx;
+    this.incoming = new AsyncQueue(ctx.abort?.signal);
+  }
+
+  /** Send a value to the host, resolving once it has been written out. */
+  send(value: unknown): Promise<void> {
+    const { ctx } = this;
+    const json = JSON.stringify(ctx.redactor ? ctx.redactor.redactValue(value) : value);
+    if (json === undefined) {
+      throw new TypeError("channel.send: the value can't be converted to JSON");
+    }
+    if (ctx.dryRun) {
+      ctx.dryRun.channel(JSON.parse(json));
+      return Promise.resolve();
+    }
+    return ctx.protocol.channelMessage(ctx.reqId, json);
+  }
+
+  /** The next value from the host, or undefined once the host has closed the channel. */
+  receive(): Promise<unknown> {
+    return this.incoming.next();
+  }
+
+  [Symbol.asyncIterator]() {
+    return this.incoming[Symbol.asyncIterator]();
+  }
+}
@@ -0,0 +1,43 @@
+import { describe, it, expect } from 'vitest';
+import { checkScript } from './check';
+
+describe('checkScript', () => {
+  it('reports the position of syntax errors', () => {
+    expect(checkScript({ code: 'let x = ;', expr: true }).errors).toEqual([
+      { message: "SyntaxError: Unexpected token ';'", line: 1, column: 9 },
+    ]);
+    expect(checkScript({ code: 'function (a) { return a +; }', call: true }).errors).toEqual([
+      { message: "SyntaxError: Unexpected token ';'", line: 1, column: 26 },
+    ]);
+    // The parser only fails at the parenthesis that closes the wrapped code.
+    expect(checkScript({ code: '(a) => a +', call: true }).errors).toEqual([
+      { message: "SyntaxError: Unexpected token ')'", line: 1, column: 11 },
+    ]);
+  });
+
+  it('locates errors in modules', () => {
+    const code = [
+      'import { a } from "./lib";',
+      'export default class {}',
+      'export const b = 1;',
+      'const c = ;',
+    ].join('\n');
+    expect(checkScript({ code, name: 'main.js' }).errors).toEqual([
+      { message: "SyntaxError: Unexpected token ';'", line: 4, column: 11 },
+    ]);
+  });
+
+  it('accepts valid code without running it', () => {
+    expect(checkScript({ code: 'throw new Error("ran")' })).toEqual({ errors: [], warnings: [] });
+    expect(checkScript({ code: 'await 1; export default 2;' }).errors).toEqual([]);
+  });
+
+  it('warns about missing modules when linting', () => {
+    const code = 'import { a } from "./lib";\nimport "other";\nconsole.log(a);';
+    const args = { code, name: 'main.js', modules: ['lib.js'] };
+    expect(checkScript(args).warnings).toEqual([]);
+    expect(checkScript({ ...args, lint: true }).warnings).toEqual([
+      { message: 'Module not found: other', line: 2, column: 8 },
+    ]);
+  });
+});
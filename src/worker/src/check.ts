@@ -0,0 +1,134 @@
+import * as vm from 'vm';
+import type { CheckScript, Diagnostic, ScriptCheck } from './api_types.js';
+import { moduleName, resolveSpecifier } from './module_graph.js';
+import { isUrl } from './url_imports.js';
+
+/** Module syntax that a script can't contain. Each match is blanked out, so that the rest of a
+ * module can be compiled as a script to find where its error is. */
+const IMPORT_DECLARATION = /^[ \t]*import\s*(?:[\w$*{}\s,]+?\s*from\s*)?(['"])[^'"\n]*\1/gm;
+const EXPORT_FROM = /^[ \t]*export\s*\*[^'"\n]*?from\s*(['"])[^'"\n]*\1/gm;
+const EXPORT_LIST = /^[ \t]*export\s*\{[^}]*\}(?:\s*from\s*(['"])[^'"\n]*\1)?/gm;
+const EXPORT_DEFAULT = /^([ \t]*)export default\b/gm;
+const EXPORT_KEYWORD = /^([ \t]*)export(?=\s)/gm;
+
+/** Replace everything but line breaks with spaces, so that positions after it don't move. */
+function blank(text: string) {
+  return text.replace(/[^\n]/g, ' ');
+}
+
+/** The 1-based line and column of an offset in the code. */
+function position(code: string, index: number) {
+  const before = code.slice(0, index);
+  const lineStart = before.lastIndexOf('\n') + 1;
+  return { line: before.split('\n').length, column: index - lineStart + 1 };
+}
+
+/** A syntax error from compiling a script. V8 starts the stack with a `name:line` header, the line
+ * of code, and carets under the error, which give its position. */
+function syntaxError(e: unknown): Diagnostic {
+  const message = e instanceof Error ? `${e.name}: ${e.message}` : String(e);
+  const [header, , carets] = (e as Error)?.stack?.split('\n') ?? [];
+  const line = /:(\d+)$/.exec(header ?? '');
+  if (!line || !carets?.includes('^')) {
+    return { message };
+  }
+  return { message, line: Number(line[1]), column: carets.indexOf('^') + 1 };
+}
+
+/** Move an error that was found in the code wrapped around the script, past its end, to the end
+ * of the script. */
+function withinCode(error: Diagnostic, code: string) {
+  const lines = code.split('\n');
+  if (error.line && error.line > lines.length) {
+    error.line = lines.length;
+    error.column = lines[lines.length - 1].length + 1;
+  }
+  return error;
+}
+
+/** Find where a module's syntax error is. V8 doesn't report positions for modules, so this
+ * compiles the module as the body of an async function instead, with its imports and exports
+ * blanked out. The position is only used if that fails with the same error. */
+function locateModuleError(code: string, name: string, error: Diagnostic): Diagnostic {
+  const body = code
+    .replace(IMPORT_DECLARATION, blank)
+    .replace(EXPORT_FROM, blank)
+    .replace(EXPORT_LIST, blank)
+    // `export default class {}` needs to stay an expression.
+    .replace(EXPORT_DEFAULT, (_, indent) => `${indent}0,${' '.repeat(12)}`)
+    .replace(EXPORT_KEYWORD, (_, indent) => `${indent}${' '.repeat(6)}`)
+    .replace(/\bimport\.meta\b/g, '({})       ');
+  try {
+    new vm.Script(`(async function () {"use strict";\n${body}\n})`, {
+      filename: name,
+      lineOffset: -1,
+    });
+  } catch (e) {
+    const located = syntaxError(e);
+    if (located.message === error.message) {
+      return withinCode(located, code);
+    }
+  }
+  return error;
+}
+
+/** Imports that don't match any of the modules that the code can import. */
+function unresolvedImports(
+  code: string,
+  name: string,
+  specifiers: readonly string[],
+  modules: string[]
+) {
+  const available = Object.fromEntries(modules.map((name) => [moduleName(name), true]));
+  const referrer = moduleName(name);
+  return specifiers
+    .filter((specifier) => !isUrl(specifier) && !resolveSpecifier(specifier, referrer, available))
+    .map((specifier): Diagnostic => {
+      const found = [`'${specifier}'`, `"${specifier}"`]
+        .map((quoted) => code.indexOf(quoted))
+        .filter((index) => index !== -1);
+      return {
+        message: `Module not found: ${specifier}`,
+        ...(found.length ? position(code, Math.min(...found)) : {}),
+      };
+    });
+}
+
+/** Compile code without running it, and report what would keep it from running. */
+export function checkScript(args: CheckScript): ScriptCheck {
+  const name = args.name || '<script>';
+  const result: ScriptCheck = { errors: [], warnings: [] };
+
+  if (args.expr || args.call) {
+    // Call mode wraps the code in parentheses, like when it runs.
+    const code = args.call ? `(${args.code}\n)` : args.code;
+    try {
+      new vm.Script(code, { filename: name });
+    } catch (e) {
+      const error = syntaxError(e);
+      if (args.call && error.line === 1 && error.column) {
+        error.column -= 1;
+      }
+      result.errors.push(withinCode(error, args.code));
+    }
+    return result;
+  }
+
+  let mod: vm.SourceTextModule;
+  try {
+    mod = new vm.SourceTextModule(args.code, { identifier: name });
+  } catch (e) {
+    result.errors.push(locateModuleError(args.code, name, syntaxError(e)));
+    return result;
+  }
+
+  if (args.lint) {
+    result.warnings = unresolvedImports(
+      args.code,
+      name,
+      mod.dependencySpecifiers,
+      args.modules ?? []
+    );
+  }
+  return result;
+}
@@ -0,0 +1,110 @@
+import { describe, it, expect } from 'vitest';
+import * as vm from 'vm';
+import { completeLine, declaredNames, introspect } from './complete';
+
+describe('completeLine', () => {
+  const context = vm.createContext({ user: { profile: { name: 'a', nickname: 'b' } } });
+  const code = 'let counter = 1; const config = {}; { let hidden = 2; }';
+  vm.runInContext(code, context);
+  const lexicalNames = new Set(declaredNames(code));
+
+  it('completes globals and lexical declarations', () => {
+    const { prefix, candidates } = completeLine('co', context, lexicalNames);
+    expect(prefix).toBe('co');
+    expect(candidates).toEqual(expect.arrayContaining(['config', 'constructor', 'counter']));
+    expect(completeLine('x = us', context, lexicalNames).candidates).toEqual(['user']);
+    expect(lexicalNames).toContain('hidden');
+    expect(completeLine('hid', context, lexicalNames).candidates).toEqual([]);
+  });
+
+  it('completes properties of a path', () => {
+    expect(completeLine('user.profile.n', context, lexicalNames)).toEqual({
+      prefix: 'n',
+      candidates: ['name', 'nickname'],
+    });
+    expect(completeLine('user.', context, lexicalNames).candidates).toContain('profile');
+    expect(completeLine('user.profile.name.toUp', context, lexicalNames).candidates).toEqual([
+      'toUpperCase',
+    ]);
+  });
+
+  it('does not run getters or Proxy traps', () => {
+    const context = vm.createContext({});
+    vm.runInContext(
+      `var ran = [];
+      var lazy = { get profile() { ran.push('getter'); return { name: 'a' }; } };
+      var trap = () => { ran.push('trap'); return undefined; };
+      var wrapped = new Proxy({ name: 'a' }, {
+        get: trap, getPrototypeOf: trap, ownKeys: trap, getOwnPropertyDescriptor: trap,
+      });`,
+      context
+    );
+    expect(completeLine('lazy.profile.', context, new Set()).candidates).toEqual([]);
+    expect(completeLine('wrapped.', context, new Set()).candidates).toEqual([]);
+    expect(completeLine('wrapped.name.', context, new Set()).candidates).toEqual([]);
+    expect(introspect('', 'wrapped', context, new Set())).toEqual([
+      { name: 'wrapped', kind: 'object' },
+    ]);
+    expect(() => introspect('lazy.profile', '', context, new Set())).toThrow(
+      "Can't read profile without running its getter"
+    );
+    expect(() => introspect('wrapped.name', '', context, new Set())).toThrow(
+      "Can't read name from a Proxy"
+    );
+    expect(vm.runInContext('ran', context)).toEqual([]);
+  });
+
+  it('ignores what it cannot complete', () => {
+    expect(completeLine("'text'.len", context, lexicalNames).candidates).toEqual([]);
+    expect(completeLine('1.5', context, lexicalNames).candidates).toEqual([]);
+    expect(completeLine('missing.x', context, lexicalNames).candidates).toEqual([]);
+  });
+});
+
+describe('introspect', () => {
+  const context = vm.createContext({});
+  const code = `
+    const items = [1, 2];
+    let handler = async (req, res) => res;
+    class Store {}
+    var store = new Map();
+    var config = { get secret() { throw new Error('ran the getter'); }, count: 1 };`;
+  vm.runInContext(code, context);
+  const lexicalNames = new Set(declaredNames(code));
+
+  it('describes globals and lexical declarations', () => {
+    expect(introspect('', 'items', context, lexicalNames)).toEqual([
+      { name: 'items', kind: 'array', detail: 'Array(2)' },
+    ]);
+    expect(introspect('', 'handler', context, lexicalNames)).toEqual([
+      { name: 'handler', kind: 'function', detail: '(req, res)' },
+    ]);
+    expect(introspect('', 'Sto', context, lexicalNames)).toEqual([
+      { name: 'Store', kind: 'class' },
+    ]);
+    expect(introspect('', 'store', context, lexicalNames)).toEqual([
+      { name: 'store', kind: 'object', detail: 'Map' },
+    ]);
+  });
+
+  it('describes properties without running getters', () => {
+    expect(introspect('config', 'co', context, lexicalNames)).toEqual([
+      { name: 'constructor', kind: 'function' },
+      { name: 'count', kind: 'number' },
+    ]);
+    expect(introspect('config', 's', context, lexicalNames)).toEqual([
+      { name: 'secret', kind: 'accessor' },
+    ]);
+    expect(introspect('store', 'se', context, lexicalNames)).toEqual([
+      { name: 'set', kind: 'function', detail: undefined },
+    ]);
+    expect(() => introspect('missing', '', context, lexicalNames)).toThrow();
+  });
+
+  it('only evaluates paths of property names', () => {
+    expect(() => introspect('(globalThis.ran = true)', '', context, lexicalNames)).toThrow(
+      'Not a path of property names'
+    );
+    expect(vm.runInContext('globalThis.ran', context)).toBeUndefined();
+  });
+});
@@ -0,0 +1,234 @@
+import { types } from 'node:util';
+import * as vm from 'vm';
+import type { Completions, PropertyHint, ValueKind } from './api_types.js';
+
+const IDENTIFIER = /^[A-Za-z_$][\w$]*$/;
+
+/** A member path and the partial name after it, at the end of a line, such as `user.profile.na`.
+ * Either part can be missing, as in `user.` or `na`. */
+const TRAILING_PATH = /(?:([A-Za-z_$][\w$]*(?:\.[A-Za-z_$][\w$]*)*)\.)?([A-Za-z_$][\w$]*)?$/;
+
+/** A whole member path, such as `user.profile`. */
+const PROPERTY_PATH = /^[A-Za-z_$][\w$]*(?:\.[A-Za-z_$][\w$]*)*$/;
+
+/** Top-level `let`, `const`, and `class` declarations. These don't become properties of the
+ * global object, but scripts run later in the same context can still see them. */
+const LEXICAL_DECLARATION = /(?:^|[;}])\s*(?:let|const|class)\s+([A-Za-z_$][\w$]*)/gm;
+
+/** The names that a script may have declared with `let`, `const`, or `class`. Declarations inside
+ * blocks are matched too, and are weeded out when completing. */
+export function declaredNames(code: string) {
+  return Array.from(code.matchAll(LEXICAL_DECLARATION), (match) => match[1]);
+}
+
+/** Every property name of a value, including inherited ones, that can be written after a dot.
+ * Listing the properties of a Proxy would run its traps, so the names stop at the first one. */
+function propertyNames(value: unknown) {
+  const names = new Set<string>();
+  for (
+    let obj: object | null = Object(value);
+    obj && !types.isProxy(obj);
+    obj = Object.getPrototypeOf(obj)
+  ) {
+    for (const name of Object.getOwnPropertyNames(obj)) {
+      if (IDENTIFIER.test(name)) {
+        names.add(name);
+      }
+    }
+  }
+  return names;
+}
+
+/** The global names of the context, including lexical declarations that are visible at its top
+ * level. */
+function globalNames(context: vm.Context, lexicalNames: Set<string>) {
+  const names = propertyNames(vm.runInContext('globalThis', context));
+  for (const name of lexicalNames) {
+    if (isDeclared(name, context)) {
+      names.add(name);
+    }
+  }
+  return names;
+}
+
+/** Where a property is defined, which may be on the object's prototype chain. Like
+ * `propertyNames`, this stops at the first Proxy. */
+function findProperty(value: unknown, name: string) {
+  for (
+    let obj: object | null = Object(value);
+    obj && !types.isProxy(obj);
+    obj = Object.getPrototypeOf(obj)
+  ) {
+    const descriptor = Object.getOwnPropertyDescriptor(obj, name);
+    if (descriptor) {
+      return descriptor;
+    }
+  }
+  return undefined;
+}
+
+/** Follow a path of property names from the context's globals. Each property is read from its
+ * descriptor, so that no getter or Proxy trap runs, and a path that goes through one throws. */
+function resolvePath(path: string, context: vm.Context, lexicalNames: Set<string>): unknown {
+  const [first, ...rest] = path.split('.');
+  let value: unknown;
+  const descriptor = findProperty(vm.runInContext('globalThis', context), first);
+  if (descriptor) {
+    value = readProperty(descriptor, first);
+  } else if (lexicalNames.has(first)) {
+    // Reading a lexical declaration can't run any code.
+    value = vm.runInContext(first, context);
+  } else {
+    throw new ReferenceError(`${first} is not defined`);
+  }
+
+  for (const name of rest) {
+    if (value === null || value === undefined) {
+      throw new TypeError(`Cannot read properties of ${value} (reading '${name}')`);
+    }
+    if (types.isProxy(value)) {
+      throw new TypeError(`Can't read ${name} from a Proxy without running its traps`);
+    }
+    const descriptor = findProperty(value, name);
+    value = descriptor && readProperty(descriptor, name);
+  }
+  return value;
+}
+
+/** The value of a property, unless reading it would run a getter. */
+function readProperty(descriptor: PropertyDescriptor, name: string) {
+  if (descriptor.get) {
+    throw new TypeError(`Can't read ${name} without running its getter`);
+  }
+  return descriptor.value;
+}
+
+/** The parameters of a function, as written in its source, such as `(a, b = 1)`. Built-in
+ * functions have no source to read them from. */
+function parameters(source: string) {
+  if (source.endsWith('{ [native code] }')) {
+    return undefined;
+  }
+  const single = /^(?:async\s+)?([A-Za-z_$][\w$]*)\s*=>/.exec(source);
+  if (single) {
+    return `(${single[1]})`;
+  }
+  const list = /^[^(]*\(([^)]*)\)/.exec(source);
+  return list ? `(${list[1].replace(/\s+/g, ' ').trim()})` : undefined;
+}
+
+/** What kind of value a property holds, without running its getter. */
+function describe(descriptor: PropertyDescriptor): Omit<PropertyHint, 'name'> {
+  if (descriptor.get || descriptor.set) {
+    return { kind: 'accessor' };
+  }
+
+  const { value } = descriptor;
+  if (value === null) {
+    return { kind: 'null' };
+  }
+  // Looking into a Proxy would run its traps.
+  if (types.isProxy(value)) {
+    return { kind: typeof value === 'function' ? 'function' : 'object' };
+  }
+  if (typeof value === 'function') {
+    const source = Function.prototype.toString.call(value);
+    if (/^class\b/.test(source)) {
+      return { kind: 'class' };
+    }
+    return { kind: 'function', detail: parameters(source) };
+  }
+  if (Array.isArray(value)) {
+    return { kind: 'array', detail: `Array(${value.length})` };
+  }
+  if (typeof value === 'object') {
+    const proto = Object.getPrototypeOf(value);
+    const constructor = proto && Object.getOwnPropertyDescriptor(proto, 'constructor')?.value;
+    return {
+      kind: 'object',
+      detail: typeof constructor === 'function' ? constructor.name : undefined,
+    };
+  }
+  return { kind: typeof value as ValueKind };
+}
+
+/** Whether a lexical declaration is visible at the top level of the context. Names that were only
+ * declared in a block, or are still uninitialized, are not. */
+function isDeclared(name: string, context: vm.Context) {
+  try {
+    return vm.runInContext(`typeof ${name} !== 'undefined'`, context) === true;
+  } catch (e) {
+    return false;
+  }
+}
+
+/** Complete the name at the end of `line` from the globals in the context, or from the properties
+ * of the value before the last dot. Only simple member paths are followed, reading each property
+ * from its descriptor, so completing never runs a getter, a Proxy trap or any other code. */
+export function completeLine(line: string, context: vm.Context, lexicalNames: Set<string>) {
+  const match = TRAILING_PATH.exec(line)!;
+  const [matched, path, prefix = ''] = match;
+  const result: Completions = { prefix, candidates: [] };
+  // A name after something other than a path, as in `'text'.len` or `1.5`, can't be completed.
+  if (line[match.index - 1] === '.' || (!matched && /[\w$.]$/.test(line))) {
+    return result;
+  }
+
+  let names: Set<string>;
+  if (path) {
+    let target: unknown;
+    try {
+      target = resolvePath(path, context, lexicalNames);
+    } catch (e) {
+      return result;
+    }
+    if (target === null || target === undefined) {
+      return result;
+    }
+    names = propertyNames(target);
+  } else {
+    names = globalNames(context, lexicalNames);
+  }
+
+  result.candidates = [...names].filter((name) => name.startsWith(prefix)).sort();
+  return result;
+}
+
+/** The properties of the value at `path` that start with `prefix`, or the globals when `path` is
+ * empty, with hints about what each one holds. As with `completeLine`, only a simple path of
+ * names is followed, so this never runs any code in the context. Unlike `completeLine`, an error
+ * from following the path, such as one that goes through a getter, is thrown. */
+export function introspect(
+  path: string,
+  prefix: string,
+  context: vm.Context,
+  lexicalNames: Set<string>
+): PropertyHint[] {
+  let target: unknown;
+  let names: Set<string>;
+  if (path) {
+    if (!PROPERTY_PATH.test(path)) {
+      throw new TypeError(`Not a path of property names: ${path}`);
+    }
+    target = resolvePath(path, context, lexicalNames);
+    if (target === null || target === undefined) {
+      return [];
+    }
+    names = propertyNames(target);
+  } else {
+    target = vm.runInContext('globalThis', context);
+    names = globalNames(context, lexicalNames);
+  }
+
+  return [...names]
+    .filter((name) => name.startsWith(prefix))
+    .sort()
+    .map((name) => {
+      // Lexical declarations aren't properties of the global object, but reading one can't run
+      // any code.
+      const descriptor = findProperty(target, name) ?? {
+        value: vm.runInContext(name, context),
+      };
+      return { name, ...describe(descriptor) };
+    });
+}
@@ -0,0 +1,50 @@
+import { describe, it, expect } from 'vitest';
+import { ContextPool } from './context_pool';
+
+/** Let pending immediates run. */
+const settle = () => new Promise((resolve) => setImmediate(resolve));
+
+describe('ContextPool', () => {
+  it('builds values ahead of time', async () => {
+    let built = 0;
+    const pool = new ContextPool(2, () => ++built);
+    expect(pool.take()).toBeUndefined();
+
+    await settle();
+    await settle();
+    expect(pool.ready).toEqual([1, 2]);
+    expect(pool.take()).toBe(2);
+    expect(built).toBe(2);
+  });
+
+  it('starts with values that were already built', async () => {
+    let built = 0;
+    const pool = new ContextPool(1, () => ++built, [0]);
+    await settle();
+    expect(built).toBe(0);
+    expect(pool.take()).toBe(0);
+
+    await settle();
+    expect(pool.ready).toEqual([1]);
+  });
+
+  it('refills one value at a time', async () => {
+    let built = 0;
+    const pool = new ContextPool(3, () => ++built);
+    for (let i = 0; i < 3; i++) {
+      await settle();
+    }
+    expect(built).toBe(3);
+
+    pool.take();
+    pool.take();
+    expect(built).toBe(3);
+    await settle();
+    expect(built).toBe(4);
+    await settle();
+    expect(built).toBe(5);
+    await settle();
+    expect(built).toBe(5);
+    expect(pool.ready.length).toBe(3);
+  });
+});
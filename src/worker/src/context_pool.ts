@@ -0,0 +1,37 @@
+/** Values that are expensive to build, such as fresh vm contexts, built ahead of time. `take`
+ * returns a ready value if there is one, and the pool builds replacements one at a time between
+ * other work, so that building them doesn't hold up the request that took one. */
+export class ContextPool<T> {
+  ready: T[] = [];
+  size: number;
+  build: () => T;
+  refilling = false;
+
+  /** `ready` holds values that were already built. */
+  constructor(size: number, build: () => T, ready: T[] = []) {
+    this.size = size;
+    this.build = build;
+    this.ready = ready;
+    this.refill();
+  }
+
+  /** Take a ready value, or return undefined if the pool is empty. */
+  take(): T | undefined {
+    const value = this.ready.pop();
+    this.refill();
+    return value;
+  }
+
+  refill() {
+    if (this.refilling || this.ready.length >= this.size) {
+      return;
+    }
+
+    this.refilling = true;
+    setImmediate(() => {
+      this.refilling = false;
+      this.ready.push(this.build());
+      this.refill();
+    }).unref();
+  }
+}
@@ -0,0 +1,17 @@
+import { describe, it, expect } from 'vitest';
+import { seededRandom } from './deterministic';
+
+describe('seededRandom', () => {
+  it('repeats the sequence for the same seed', () => {
+    const a = seededRandom(42);
+    const b = seededRandom(42);
+    const c = seededRandom(43);
+    const first = [a(), a(), a()];
+    expect([b(), b(), b()]).toEqual(first);
+    expect(c()).not.toBe(first[0]);
+    for (const value of first) {
+      expect(value).toBeGreaterThanOrEqual(0);
+      expect(value).toBeLessThan(1);
+    }
+  });
+});
@@ -0,0 +1,18 @@
+import * as vm from 'vm';
+
+/** A `Math.random` replacement that produces the same sequence for the same seed (mulberry32). */
+export function seededRandom(seed: number) {
+  let state = seed >>> 0;
+  return () => {
+    state = (state + 0x6d2b79f5) >>> 0;
+    let t = state;
+    t = Math.imul(t ^ (t >>> 15), t | 1);
+    t ^= t + Math.imul(t ^ (t >>> 7), t | 61);
+    return ((t ^ (t >>> 14)) >>> 0) / 4294967296;
+  };
+}
+
+/** Remove the globals whose behavior depends on the garbage collector. */
+export function removeNondeterministicGlobals(context: vm.Context) {
+  vm.runInContext('delete globalThis.WeakRef; delete globalThis.FinalizationRegistry;', context);
+}
@@ -0,0 +1,74 @@
+import type { Worker } from 'node:cluster';
+import net from 'node:net';
+import { debug } from './debug.js';
+
+/** Hands the connections on the host's socket out to the workers in turn.
+ *
+ * The host creates the socket and passes it to the primary as a file descriptor. The primary
+ * listens on it itself rather than leaving that to the cluster module, which closes a listening
+ * socket when its last worker exits, and a socket that came from the host can't be opened again. */
+export class ConnectionDispatcher {
+  server: net.Server;
+  fd: number;
+  /** The workers that are taking connections. */
+  workers: Worker[] = [];
+  next = 0;
+  /** Connections that arrived while no worker was taking them, such as while the only worker is
+   * being replaced. */
+  waiting: net.Socket[] = [];
+  listening = false;
+
+  constructor(fd: number) {
+    this.fd = fd;
+    this.server = net.createServer({ pauseOnConnect: true }, (socket) => this.dispatch(socket));
+    this.server.on('error', (e) => {
+      console.error(e);
+      process.exit(1);
+    });
+  }
+
+  /** Start sending connections to `worker`. The socket only starts listening once the first
+   * worker is ready, so that the host can't connect before then. */
+  add(worker: Worker) {
+    this.workers.push(worker);
+    if (!this.listening) {
+      this.listening = true;
+      this.server.listen({ fd: this.fd });
+    }
+
+    for (const socket of this.waiting.splice(0)) {
+      this.dispatch(socket);
+    }
+  }
+
+  /** Stop sending connections to `worker`, because it is retiring or has exited. */
+  remove(worker: Worker) {
+    const index = this.workers.indexOf(worker);
+    if (index !== -1) {
+      this.workers.splice(index, 1);
+    }
+  }
+
+  dispatch(socket: net.Socket) {
+    if (this.workers.length === 0) {
+      this.waiting.push(socket);
+      return;
+    }
+
+    const worker = this.workers[this.next++ % this.workers.length];
+    worker.send('connection', socket, (e) => {
+      if (e) {
+        debug(`Failed to pass a connection to worker ${worker.process.pid}`, e);
+        this.remove(worker);
+        this.dispatch(socket);
+      }
+    });
+  }
+
+  close() {
+    this.server.close();
+    for (const socket of this.waiting.splice(0)) {
+      socket.destroy();
+    }
+  }
+}
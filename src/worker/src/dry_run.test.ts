@@ -0,0 +1,49 @@
+import { describe, it, expect } from 'vitest';
+import type { KvRequest } from './api_types';
+import { DryRun } from './dry_run';
+
+describe('DryRun', () => {
+  const stored: Record<string, unknown> = { 'a/1': 1, 'a/2': 2 };
+  const host = async (request: KvRequest) => {
+    switch (request.op) {
+      case 'get':
+        return stored[request.key] ?? null;
+      case 'list':
+        return Object.keys(stored).filter((key) => key.startsWith(request.prefix));
+      default:
+        throw new Error(`The dry run sent a ${request.op} to the host`);
+    }
+  };
+
+  it('records kv writes and lays them over reads', async () => {
+    const dryRun = new DryRun();
+    expect(await dryRun.kv({ op: 'get', key: 'a/1' }, host)).toBe(1);
+    await dryRun.kv({ op: 'set', key: 'a/1', value: 10 }, host);
+    await dryRun.kv({ op: 'set', key: 'a/3', value: 3 }, host);
+    expect(await dryRun.kv({ op: 'get', key: 'a/1' }, host)).toBe(10);
+    expect(await dryRun.kv({ op: 'delete', key: 'a/2' }, host)).toBe(true);
+    expect(await dryRun.kv({ op: 'delete', key: 'a/2' }, host)).toBe(false);
+    expect(await dryRun.kv({ op: 'get', key: 'a/2' }, host)).toBe(null);
+    expect(await dryRun.kv({ op: 'list', prefix: 'a/' }, host)).toEqual(['a/1', 'a/3']);
+
+    expect(dryRun.effects).toEqual([
+      { type: 'kv', op: 'set', key: 'a/1', value: 10 },
+      { type: 'kv', op: 'set', key: 'a/3', value: 3 },
+      { type: 'kv', op: 'delete', key: 'a/2' },
+      { type: 'kv', op: 'delete', key: 'a/2' },
+    ]);
+    expect(stored).toEqual({ 'a/1': 1, 'a/2': 2 });
+  });
+
+  it('records output', () => {
+    const dryRun = new DryRun();
+    dryRun.output(Buffer.from('hi'));
+    expect(dryRun.effects).toEqual([{ type: 'output', data: 'aGk=' }]);
+  });
+
+  it('records channel messages', () => {
+    const dryRun = new DryRun();
+    dryRun.channel({ a: 1 });
+    expect(dryRun.effects).toEqual([{ type: 'channel', value: { a: 1 } }]);
+  });
+});
@@ -0,0 +1,60 @@
+import type { Effect, KvRequest } from './api_types.js';
+
+/** Marks a key that the dry run deleted. */
+const DELETED = Symbol('deleted');
+
+/** The effects of a run with `dryRun` set, which are recorded instead of reaching the host. Reads
+ * from `kv` still go to the host, with the run's own writes laid over what it returns, so that the
+ * script sees the same values as it would in a real run. Values sent on the channel are recorded
+ * too, while values from the host can still be received. */
+export class DryRun {
+  effects: Effect[] = [];
+  /** The values that the run wrote to `kv`, by key */
+  writes = new Map<string, unknown>();
+
+  async kv(request: KvRequest, host: (request: KvRequest) => Promise<any>): Promise<any> {
+    switch (request.op) {
+      case 'get': {
+        if (!this.writes.has(request.key)) {
+          return host(request);
+        }
+        const value = this.writes.get(request.key);
+        return value === DELETED ? null : value;
+      }
+      case 'set':
+        this.effects.push({ type: 'kv', ...request });
+        this.writes.set(request.key, request.value);
+        return null;
+      case 'delete': {
+        const existed = this.writes.has(request.key)
+          ? this.writes.get(request.key) !== DELETED
+          : (await host({ op: 'get', key: request.key })) !== null;
+        this.effects.push({ type: 'kv', ...request });
+        this.writes.set(request.key, DELETED);
+        return existed;
+      }
+      case 'list': {
+        const keys = new Set<string>(await host(request));
+        for (const [key, value] of this.writes) {
+          if (!key.startsWith(request.prefix)) {
+            continue;
+          }
+          if (value === DELETED) {
+            keys.delete(key);
+          } else {
+            keys.add(key);
+          }
+        }
+        return [...keys].sort();
+      }
+    }
+  }
+
+  output(data: Buffer) {
+    this.effects.push({ type: 'output', data: data.toString('base64') });
+  }
+
+  channel(value: unknown) {
+    this.effects.push({ type: 'channel', value });
+  }
+}
@@ -0,0 +1,111 @@
+import { describe, it, expect } from 'vitest';
+import * as vm from 'node:vm';
+import {
+  describeError,
+  errorKind,
+  internalError,
+  moduleError,
+  stringTooLongError,
+} from './errors';
+import { cancelledError, timeoutError } from './abort';
+
+describe('errorKind', () => {
+  it('classifies errors from a context', () => {
+    const thrown = (code: string) => {
+      try {
+        vm.runInNewContext(code);
+      } catch (e) {
+        return e;
+      }
+    };
+
+    expect(errorKind(thrown('let ('))).toBe('syntaxError');
+    expect(errorKind(thrown('missing.value'))).toBe('referenceError');
+    expect(errorKind(thrown('null.value'))).toBe('script');
+    expect(errorKind(thrown('throw "a string"'))).toBe('script');
+    expect(errorKind(thrown('"x".repeat(2 ** 30)'))).toBe('outOfMemory');
+  });
+
+  it('classifies errors from the worker', () => {
+    expect(errorKind(timeoutError(100))).toBe('timeout');
+    expect(errorKind(moduleError('Module not found: a, referenced from b'))).toBe(
+      'moduleResolution'
+    );
+    expect(errorKind(internalError('Message from host failed its checksum'))).toBe(
+      'internalWorkerError'
+    );
+    expect(errorKind(stringTooLongError('String at returnValue is 4 bytes'))).toBe(
+      'stringTooLong'
+    );
+  });
+
+  it('only treats the reason of an aborted signal as a cancellation', () => {
+    const controller = new AbortController();
+    const reason = cancelledError();
+    expect(errorKind(reason, controller.signal)).toBe('script');
+    controller.abort(reason);
+    expect(errorKind(reason, controller.signal)).toBe('cancelled');
+    expect(errorKind(cancelledError(), controller.signal)).toBe('script');
+  });
+});
+
+describe('describeError', () => {
+  it('includes causes and aggregated errors', () => {
+    const root = new AggregateError([new TypeError('first'), 'second'], 'all failed', {
+      cause: moduleError('Module not found: a'),
+    });
+    const e = new Error('outer', { cause: root });
+
+    const described = describeError(e);
+    expect(described).toMatchObject({
+      message: 'outer',
+      kind: 'script',
+      cause: {
+        message: 'all failed',
+        name: 'AggregateError',
+        cause: { message: 'Module not found: a', kind: 'moduleResolution' },
+        errors: [
+          { message: 'first', name: 'TypeError' },
+          { message: 'second', kind: 'script' },
+        ],
+      },
+    });
+    expect(described.cause?.errors?.[1].stack).toBeUndefined();
+  });
+
+  it('stops at a cycle of causes', () => {
+    const a: Error = new Error('a');
+    const b = new Error('b', { cause: a });
+    a.cause = b;
+
+    const described = describeError(a);
+    expect(described.cause?.message).toBe('b');
+    expect(described.cause?.cause?.message).toBe('a');
+    expect(described.cause?.cause?.cause).toBeUndefined();
+  });
+
+  it('describes errors shared between branches once', () => {
+    let e: unknown = new Error('leaf');
+    for (let i = 0; i < 8; i++) {
+      e = new AggregateError(Array(10).fill(e), `level ${i}`);
+    }
+
+    const count = (d: any): number =>
+      1 +
+      (d.cause ? count(d.cause) : 0) +
+      (d.errors ?? []).reduce((n: number, item: any) => n + count(item), 0);
+    expect(count(describeError(e))).toBe(81);
+  });
+
+  it('stops after a budget of errors', () => {
+    const errors = Array.from(
+      { length: 100 },
+      (_, i) => new AggregateError(Array.from({ length: 100 }, () => new Error(`${i}`)))
+    );
+    const wide = new AggregateError(errors, 'wide');
+
+    const described = describeError(wide);
+    const total = described.errors!.reduce((n, item) => n + 1 + (item.errors?.length ?? 0), 1);
+    expect(total).toBe(500);
+  });
+});
@@ -0,0 +1,127 @@
+import type { ErrorKind, ErrorResponse } from './api_types.js';
+import { isTimeout } from './abort.js';
+
+/** Set on errors from loading a script's imports. This is the code Node.js uses for the same
+ * failure. */
+const MODULE_NOT_FOUND = 'ERR_MODULE_NOT_FOUND';
+/** Set on errors from the worker itself, rather than from the script. */
+const INTERNAL_ERROR = 'ERR_SIDECAR_INTERNAL';
+/** Set on errors for strings over the run's size limit. */
+const STRING_TOO_LONG = 'ERR_STRING_TOO_LONG';
+
+/** How deep a chain of causes and aggregated errors is sent to the host. */
+const MAX_ERROR_DEPTH = 8;
+/** How many of the errors in an AggregateError are sent to the host. */
+const MAX_AGGREGATED_ERRORS = 100;
+/** How many errors in all are described for one thrown value, so that wide trees of aggregated
+ * errors stay small. */
+const MAX_DESCRIBED_ERRORS = 500;
+
+/** Messages of the RangeErrors that V8 throws when it can't allocate memory. */
+const OUT_OF_MEMORY_MESSAGES = ['Array buffer allocation failed', 'Invalid string length'];
+
+/** An error for an import that couldn't be loaded. */
+export function moduleError(message: string, options?: ErrorOptions) {
+  return Object.assign(new Error(message, options), { code: MODULE_NOT_FOUND });
+}
+
+/** An error for a failure in the worker that isn't the script's fault. */
+export function internalError(message: string) {
+  return Object.assign(new Error(message), { code: INTERNAL_ERROR });
+}
+
+/** An error for a string over the run's size limit. */
+export function stringTooLongError(message: string) {
+  return Object.assign(new Error(message), { code: STRING_TOO_LONG });
+}
+
+/** Classify a thrown value, so that the host doesn't have to match on the message to decide what
+ * to do about it. `signal` is the run's signal, whose reason is what a cancelled run fails with.
+ *
+ * Errors thrown inside a context come from that context's realm, so they are checked by name
+ * rather than with `instanceof`. */
+export function errorKind(e: any, signal?: AbortSignal): ErrorKind {
+  if (isTimeout(e)) {
+    return 'timeout';
+  }
+  if (signal?.aborted && e === signal.reason) {
+    return 'cancelled';
+  }
+
+  switch (e?.code) {
+    case MODULE_NOT_FOUND:
+      return 'moduleResolution';
+    case INTERNAL_ERROR:
+      return 'internalWorkerError';
+    case STRING_TOO_LONG:
+      return 'stringTooLong';
+    case 'ERR_WORKER_OUT_OF_MEMORY':
+      return 'outOfMemory';
+  }
+
+  switch (e?.name) {
+    case 'SyntaxError':
+      return 'syntaxError';
+    case 'ReferenceError':
+      return 'referenceError';
+    case 'RangeError':
+      if (OUT_OF_MEMORY_MESSAGES.includes(e.message)) {
+        return 'outOfMemory';
+      }
+  }
+  return 'script';
+}
+
+/** Tracks a walk through a thrown value and the errors that it refers to. */
+interface ErrorWalk {
+  /** The errors that have been described */
+  visited: Set<unknown>;
+  /** How many more errors can be described */
+  remaining: number;
+}
+
+/** Describe a thrown value for the host, along with the chain of errors that caused it and, for an
+ * AggregateError, the errors that it collects. Scripts can throw values that aren't errors, and
+ * those are described by their string form. */
+export function describeError(
+  e: any,
+  kind = errorKind(e),
+  walk: ErrorWalk = { visited: new Set(), remaining: MAX_DESCRIBED_ERRORS },
+  depth = 0
+): ErrorResponse {
+  walk.remaining -= 1;
+  const response: ErrorResponse = {
+    message: typeof e?.message === 'string' ? e.message : String(e),
+    stack: typeof e?.stack === 'string' ? e.stack : undefined,
+    kind,
+    name: typeof e?.name === 'string' ? e.name : undefined,
+    code: typeof e?.code === 'string' ? e.code : undefined,
+  };
+
+  // Causes can form a cycle, and the same error can show up in many places, so each error's own
+  // causes and aggregated errors are only described the first time it is reached.
+  if (
+    typeof e !== 'object' ||
+    e === null ||
+    walk.visited.has(e) ||
+    depth >= MAX_ERROR_DEPTH ||
+    walk.remaining <= 0
+  ) {
+    return response;
+  }
+
+  walk.visited.add(e);
+  if (e.cause !== undefined) {
+    response.cause = describeError(e.cause, errorKind(e.cause), walk, depth + 1);
+  }
+  if (Array.isArray(e.errors)) {
+    response.errors = [];
+    for (const item of e.errors.slice(0, MAX_AGGREGATED_ERRORS)) {
+      if (walk.remaining <= 0) {
+        break;
+      }
+      response.errors.push(describeError(item, errorKind(item), walk, depth + 1));
+    }
+  }
+  return response;
+}
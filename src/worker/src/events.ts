@@ -0,0 +1,34 @@
+import net from 'node:net';
+import type { SidecarEvent } from './api_types.js';
+import { debug } from './debug.js';
+
+/** Sent from a worker to the primary over the cluster IPC channel before it exits from a crash. */
+export interface CrashMessage {
+  type: 'crash';
+  report: string;
+}
+
+export function isCrashMessage(msg: any): boolean {
+  return typeof msg === 'object' && msg?.type === 'crash';
+}
+
+/** Send events over the socket that the host passed as file descriptor `fd`, returning a function
+ * that sends an event. Failures are ignored since the events are only informational. */
+export function eventSender(fd: number | undefined): (event: SidecarEvent) => void {
+  if (fd === undefined) {
+    return () => {};
+  }
+
+  const socket = new net.Socket({ fd, readable: false, writable: true });
+  socket.on('error', (e) => {
+    debug('Failed to send events', e);
+  });
+  // Don't keep the primary alive just for this.
+  socket.unref();
+
+  return (event) => {
+    if (!socket.destroyed) {
+      socket.write(JSON.stringify(event) + '\n');
+    }
+  };
+}
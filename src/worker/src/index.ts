@@ -2,11 +2,23 @@ import cluster from 'node:cluster';
 import os from 'node:os';
 import fs from 'node:fs';
 import { parseArgs } from 'node:util';
+import { isMainThread } from 'node:worker_threads';
 
-import { runWorker } from './worker.js';
+import { runThread, runWorker } from './worker.js';
+import { startManagementServer } from './management.js';
+import { eventSender, isCrashMessage, type CrashMessage } from './events.js';
+import { ConnectionDispatcher } from './dispatch.js';
 import { debug } from './debug.js';
 
-if (cluster.isPrimary) {
+/** A file descriptor number from the command line or the environment. */
+function parseFd(value: string | undefined) {
+  return value ? parseInt(value, 10) : undefined;
+}
+
+if (!isMainThread) {
+  // A connection thread in a worker started with `--isolation thread`.
+  runThread();
+} else if (cluster.isPrimary) {
   const filename = process.argv[1];
 
   // Parse command line arguments
@@ -19,6 +31,31 @@ if (cluster.isPrimary) {
       socket: {
         type: 'string',
       },
+      'socket-fd': {
+        type: 'string',
+      },
+      'memory-report-interval': {
+        type: 'string',
+      },
+      'load-report-interval': {
+        type: 'string',
+      },
+      'context-pool-size': {
+        type: 'string',
+      },
+      websocket: {
+        type: 'string',
+      },
+      management: {
+        type: 'string',
+      },
+      'events-fd': {
+        type: 'string',
+      },
+      isolation: {
+        type: 'string',
+        default: 'process',
+      },
     },
   });
 
@@ -29,6 +66,14 @@ if (cluster.isPrimary) {
   if (!socketPath) {
     throw new Error('No socket path provided');
   }
+  const socketFd = parseFd(values['socket-fd']);
+  if (socketFd === undefined) {
+    throw new Error('No socket file descriptor provided');
+  }
+  const dispatcher = new ConnectionDispatcher(socketFd);
+
+  const managementServer = values.management ? startManagementServer(values.management) : null;
+  const sendEvent = eventSender(parseFd(values['events-fd']));
 
   process.on('exit', () => {
     // Make sure to clean up the socket file when the process exits
@@ -44,6 +89,11 @@ if (cluster.isPrimary) {
 
     let worker = cluster.fork({
       SOCKET_PATH: socketPath,
+      MEMORY_REPORT_INTERVAL: values['memory-report-interval'] ?? '0',
+      LOAD_REPORT_INTERVAL: values['load-report-interval'] ?? '0',
+      CONTEXT_POOL_SIZE: values['context-pool-size'] ?? '0',
+      WEBSOCKET_ADDRESS: values.websocket ?? '',
+      ISOLATION: values.isolation,
     });
 
     worker.on('message', (msg) => {
@@ -51,6 +101,13 @@ if (cluster.isPrimary) {
         // We started shutting down between when this worker was forked and when it
         // started listening to messages, so tell it again.
         worker.send('shutdown');
+      } else if (msg === 'ready') {
+        dispatcher.add(worker);
+      } else if (msg === 'retiring') {
+        dispatcher.remove(worker);
+      } else if (isCrashMessage(msg)) {
+        const pid = worker.process.pid ?? 0;
+        sendEvent({ type: 'workerCrashed', pid, report: (msg as CrashMessage).report });
       }
     });
   }
@@ -63,6 +120,8 @@ if (cluster.isPrimary) {
     }
 
     shuttingDown = true;
+    managementServer?.close();
+    dispatcher.close();
     for (let worker of Object.values(cluster.workers ?? {})) {
       worker?.send('shutdown', () => {});
     }
@@ -73,6 +132,7 @@ if (cluster.isPrimary) {
 
   cluster.on('online', (worker) => {
     debug('online', worker.process.pid, shuttingDown);
+    sendEvent({ type: 'workerStarted', pid: worker.process.pid ?? 0 });
     if (shuttingDown) {
       worker.kill('SIGKILL');
     }
@@ -80,6 +140,13 @@ if (cluster.isPrimary) {
 
   cluster.on('exit', (worker, code, signal) => {
     debug('exit', worker.process.pid, code, signal, shuttingDown, socketPath);
+    dispatcher.remove(worker);
+    sendEvent({
+      type: 'workerExited',
+      pid: worker.process.pid ?? 0,
+      code: code ?? null,
+      signal: signal ?? null,
+    });
     if (!shuttingDown && !fs.existsSync(filename)) {
       // This happens when the Rust side shuts down somewhat uncleanly.
       debug(`${socketPath} script is gone, shutting down`);
@@ -111,5 +178,9 @@ if (cluster.isPrimary) {
     forkWorker();
   }
 } else {
-  runWorker(process.env.SOCKET_PATH as string);
+  runWorker(
+    process.env.SOCKET_PATH as string,
+    process.env.WEBSOCKET_ADDRESS || undefined,
+    process.env.ISOLATION === 'thread' ? 'thread' : 'process'
+  );
 }
@@ -0,0 +1,49 @@
+import { describe, it, expect } from 'vitest';
+import { InputStream } from './input';
+
+describe('InputStream', () => {
+  it('yields chunks as they arrive until the end', async () => {
+    const input = new InputStream();
+    input.push(Buffer.from('a'));
+    const read = (async () => {
+      const chunks: string[] = [];
+      for await (const chunk of input) {
+        chunks.push(Buffer.from(chunk).toString());
+      }
+      return chunks;
+    })();
+
+    await new Promise((resolve) => setTimeout(resolve, 5));
+    input.push(Buffer.from('b'));
+    input.end();
+    input.push(Buffer.from('ignored'));
+    expect(await read).toEqual(['a', 'b']);
+  });
+
+  it('decodes text split across chunks', async () => {
+    const input = new InputStream();
+    const euro = Buffer.from('€');
+    input.push(euro.subarray(0, 1));
+    input.push(euro.subarray(1));
+    input.push(Buffer.from('!'));
+    input.end();
+
+    const text: string[] = [];
+    for await (const chunk of input.text()) {
+      text.push(chunk);
+    }
+    expect(text).toEqual(['€', '!']);
+  });
+
+  it('stops when the signal aborts', async () => {
+    const controller = new AbortController();
+    const input = new InputStream(controller.signal);
+    const read = (async () => {
+      for await (const _ of input) {
+      }
+    })();
+
+    controller.abort(new Error('cancelled'));
+    await expect(read).rejects.toThrow('cancelled');
+  });
+});
@@ -0,0 +1,21 @@
+import { AsyncQueue } from './queue.js';
+
+/** The `input` global of a run with `streamInput` set, which yields the chunks that the host sends
+ * with Input messages until it sends InputEnd. */
+export class InputStream extends AsyncQueue<Uint8Array> {
+  /** The chunks decoded as UTF-8, for scripts that read text. Contexts have no `TextDecoder` of
+   * their own, and a character can be split between two chunks. */
+  async *text(): AsyncGenerator<string> {
+    const decoder = new TextDecoder();
+    for await (const chunk of this) {
+      const text = decoder.decode(chunk, { stream: true });
+      if (text) {
+        yield text;
+      }
+    }
+    const rest = decoder.decode();
+    if (rest) {
+      yield rest;
+    }
+  }
+}
@@ -0,0 +1,95 @@
+import inspector from 'node:inspector';
+import { getHeapSnapshot } from 'node:v8';
+import { WorkerToHostMessage, type DebuggerWaiting } from './api_types.js';
+import type { MessageContext } from './types.js';
+import { debug } from './debug.js';
+
+/** Set once a debugger has attached, until the debug run finishes. A debugger that stays attached
+ * doesn't announce itself again, so other debug runs in the meantime just pause at their
+ * `debugger` statement instead of waiting. */
+let debuggerAttached = false;
+
+/** Open the inspector if needed, tell the host where to connect, and block until a debugger
+ * attaches. This stops the whole worker, including runs on other connections. */
+export async function waitForDebugger(ctx: MessageContext) {
+  if (!inspector.url()) {
+    inspector.open(0, '127.0.0.1', false);
+  }
+
+  const url = inspector.url() ?? '';
+  if (debuggerAttached) {
+    return;
+  }
+
+  debug(`Request ${ctx.reqId} is waiting for a debugger at ${url}`);
+  const message: DebuggerWaiting = { pid: process.pid, url };
+  // Make sure the host gets the URL before the worker blocks.
+  await new Promise<void>((resolve) =>
+    ctx.protocol.sendMessage(
+      ctx.reqId,
+      WorkerToHostMessage.DebuggerWaiting,
+      JSON.stringify(message),
+      resolve
+    )
+  );
+
+  inspector.waitForDebugger();
+  debuggerAttached = true;
+}
+
+/** Called when a debug run finishes, so that the next one waits for a debugger again rather than
+ * running straight through once the debugger has gone. */
+export function debugRunFinished() {
+  debuggerAttached = false;
+}
+
+/** Prefix code with a `debugger` statement, on the same line to keep the line numbers intact. */
+export function withBreakpoint(code: string) {
+  return `debugger; ${code}`;
+}
+
+function post(session: inspector.Session, method: string, params?: object): Promise<any> {
+  return new Promise((resolve, reject) =>
+    session.post(method, params, (err, result) => (err ? reject(err) : resolve(result)))
+  );
+}
+
+/** Finer than V8's default of 1ms, since most runs are short. */
+const PROFILE_SAMPLING_INTERVAL_US = 100;
+
+/** Start recording a CPU profile. The returned function stops the profiler and returns the
+ * profile as `.cpuprofile` JSON. */
+export async function startProfiling(): Promise<() => Promise<string>> {
+  const session = new inspector.Session();
+  session.connect();
+  try {
+    await post(session, 'Profiler.enable');
+    await post(session, 'Profiler.setSamplingInterval', { interval: PROFILE_SAMPLING_INTERVAL_US });
+    await post(session, 'Profiler.start');
+  } catch (e) {
+    session.disconnect();
+    throw e;
+  }
+
+  return async () => {
+    try {
+      const { profile } = await post(session, 'Profiler.stop');
+      return JSON.stringify(profile);
+    } finally {
+      session.disconnect();
+    }
+  };
+}
+
+/** Take a heap snapshot of the whole worker and send it to the host in chunks. Each chunk waits
+ * for the previous one to be written, so that a large snapshot isn't buffered in memory. */
+export async function sendHeapSnapshot(ctx: MessageContext) {
+  let size = 0;
+  for await (const chunk of getHeapSnapshot()) {
+    size += chunk.length;
+    await new Promise<void>((resolve) =>
+      ctx.protocol.sendMessage(ctx.reqId, WorkerToHostMessage.HeapSnapshotChunk, chunk, resolve)
+    );
+  }
+  debug(`Request ${ctx.reqId} sent a ${size} byte heap snapshot`);
+}
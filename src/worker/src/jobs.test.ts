@@ -0,0 +1,66 @@
+import { describe, it, expect } from 'vitest';
+import { cancelJob, jobStatus, startJob } from './jobs';
+import { runScript } from './run_script';
+import type { MessageContext } from './types';
+
+/** Let pending promise callbacks run. */
+const settle = () => new Promise((resolve) => setTimeout(resolve, 10));
+
+describe('jobs', () => {
+  const createMessageContext = (): MessageContext => ({
+    protocol: {
+      cache: new Map(),
+    } as any,
+    reqId: 1,
+    id: 1,
+    log: () => {},
+    respond: () => {},
+    error: () => {},
+    output: async () => {},
+    asyncError: () => {},
+  });
+
+  it('keeps the result and logs of a job in its own context', async () => {
+    const ctx = createMessageContext();
+    const started = startJob(
+      {
+        name: 'job.js',
+        code: `console.log('working'); export default await Promise.resolve(42);`,
+      },
+      ctx,
+      runScript
+    );
+    expect(started.state).toBe('running');
+
+    await settle();
+    const { jobId, token } = started;
+    expect(() => jobStatus({ jobId, token: '00'.repeat(16) })).toThrow(/No job/);
+    const status = jobStatus({ jobId, token });
+    expect(status.state).toBe('succeeded');
+    expect(status.response?.returnValue).toBe(42);
+    expect(status.logs.map((log) => log.message)).toEqual([['working']]);
+    // The job didn't touch the context of the connection that started it.
+    expect(ctx.protocol.cache.size).toBe(0);
+
+    jobStatus({ jobId, token, remove: true });
+    expect(() => jobStatus({ jobId, token })).toThrow(/No job/);
+  });
+
+  it('cancels a job', async () => {
+    const started = startJob(
+      {
+        name: 'job.js',
+        code: `await new Promise((resolve) => signal.addEventListener('abort', resolve)); throw signal.reason;`,
+      },
+      createMessageContext(),
+      runScript
+    );
+
+    const { jobId, token } = started;
+    expect(cancelJob({ jobId, token }).state).toBe('running');
+    await settle();
+    const status = jobStatus({ jobId, token, remove: true });
+    expect(status.state).toBe('cancelled');
+    expect(status.error?.kind).toBe('cancelled');
+  });
+});
@@ -0,0 +1,171 @@
+import type { Protocol } from './protocol.js';
+import type { MessageContext } from './types.js';
+import type {
+  JobRequest,
+  JobState,
+  JobStatus,
+  LogMessage,
+  RunResponse,
+  RunScriptArgs,
+} from './api_types.js';
+import { activeRequests } from './annotations.js';
+import { cancelledError } from './abort.js';
+import { debug } from './debug.js';
+import { describeError, errorKind } from './errors.js';
+import crypto from 'node:crypto';
+
+/** The most console messages that a job keeps. Later ones are counted but dropped. */
+export const MAX_JOB_LOGS = 1000;
+
+/** The code of the error for a job that the worker doesn't have, because it never existed, was
+ * already removed, or ran in a worker that has since exited. */
+const JOB_NOT_FOUND = 'ERR_JOB_NOT_FOUND';
+
+/** The most finished jobs that a worker keeps for the host to collect. The oldest ones are
+ * forgotten first. */
+export const MAX_FINISHED_JOBS = 100;
+
+interface Job {
+  status: JobStatus;
+  /** The secret that requests about the job have to present, so that a job can't be looked up by
+   * guessing its ID. */
+  token: Buffer;
+  abort: AbortController;
+}
+
+/** The jobs in this worker process, by ID. Maps iterate in insertion order, so the first finished
+ * job is the oldest one. */
+const jobs = new Map<number, Job>();
+let nextJobId = 1;
+
+/** Start a run with `detach` set as a background job, and return its status along with the
+ * job's token. The job gets its own context, and its console messages and result are kept in the
+ * worker until the host asks for them with a JobStatus message that has the token, from this
+ * connection or any other one to the same worker.
+ *
+ * `run` runs the script outside of the worker's priority lanes, since a job can run for much
+ * longer than the request that started it.
+ *
+ * The job can't use anything that needs the connection it was started from, since that may close
+ * while the job is still running, so `kv` and module resolution by the host are disabled, and its
+ * output and channel messages go nowhere. */
+export function startJob(
+  args: RunScriptArgs,
+  ctx: MessageContext,
+  run: (args: RunScriptArgs, ctx: MessageContext) => Promise<RunResponse>
+): JobStatus & { token: string } {
+  const jobId = nextJobId++;
+  const job: Job = {
+    status: { jobId, pid: process.pid, state: 'running', logs: [], droppedLogs: 0 },
+    token: crypto.randomBytes(16),
+    abort: new AbortController(),
+  };
+  jobs.set(jobId, job);
+
+  const protocol: Protocol = Object.create(ctx.protocol, {
+    cache: { value: new Map() },
+    kvEnabled: { value: false },
+    resolveModulesEnabled: { value: false },
+    sendMessage: { value: () => {} },
+  });
+
+  const jobCtx: MessageContext = {
+    protocol,
+    reqId: ctx.reqId,
+    id: ctx.id,
+    abort: job.abort,
+    log(message: any, level = 'info', namespace?: string) {
+      if (jobCtx.redactor) {
+        message = jobCtx.redactor.redactValue(message);
+      }
+      debug(`job ${jobId}[${level}]:`, message);
+      const { logs } = job.status;
+      if (logs.length < MAX_JOB_LOGS) {
+        const log: LogMessage = {
+          level,
+          message,
+          timestamp: Date.now(),
+          requestId: ctx.reqId,
+          namespace,
+        };
+        logs.push(log);
+      } else {
+        job.status.droppedLogs += 1;
+      }
+    },
+    respond() {},
+    error() {},
+    async output() {},
+    asyncError(e: unknown, source) {
+      jobCtx.log(`${source}: ${describeError(e).message}`, 'error');
+    },
+  };
+
+  activeRequests.add(jobCtx);
+  run(args, jobCtx)
+    .then((response) => {
+      finish(job, 'succeeded', {
+        response: jobCtx.redactor ? jobCtx.redactor.redactValue(response) : response,
+      });
+    })
+    .catch((e) => {
+      const kind = errorKind(e, job.abort.signal);
+      if (jobCtx.redactor) {
+        e = jobCtx.redactor.redactError(e);
+      }
+      finish(job, kind === 'cancelled' ? 'cancelled' : 'failed', {
+        error: { ...describeError(e, kind), timedOut: kind === 'timeout' || undefined },
+      });
+    })
+    .finally(() => activeRequests.delete(jobCtx));
+
+  debug(`job ${jobId}: started by request ${ctx.reqId}`);
+  return { ...job.status, token: job.token.toString('hex') };
+}
+
+function finish(job: Job, state: JobState, outcome: Partial<JobStatus>) {
+  // Re-insert the job so that the finished jobs stay in the order they finished.
+  jobs.delete(job.status.jobId);
+  Object.assign(job.status, outcome, { state });
+  jobs.set(job.status.jobId, job);
+  debug(`job ${job.status.jobId}: ${state}`);
+
+  const finished = [...jobs.values()].filter((j) => j.status.state !== 'running');
+  for (const old of finished.slice(0, Math.max(finished.length - MAX_FINISHED_JOBS, 0))) {
+    jobs.delete(old.status.jobId);
+  }
+}
+
+function findJob({ jobId, token }: JobRequest) {
+  const job = jobs.get(jobId);
+  const given = Buffer.from(token ?? '', 'hex');
+  // A wrong token gets the same error as a missing job, so that it doesn't tell which IDs exist.
+  if (!job || given.length !== job.token.length || !crypto.timingSafeEqual(given, job.token)) {
+    throw Object.assign(new Error(`No job ${jobId} in worker ${process.pid}`), {
+      code: JOB_NOT_FOUND,
+    });
+  }
+  return job;
+}
+
+/** The status of a job, forgetting it afterward if it has finished and `remove` is set. */
+export function jobStatus(request: JobRequest): JobStatus {
+  const { jobId, remove } = request;
+  const job = findJob(request);
+  if (remove && job.status.state !== 'running') {
+    jobs.delete(jobId);
+  }
+  return { ...job.status };
+}
+
+/** Abort the signal of a job. The job is cancelled once it has cleaned up. */
+export function cancelJob(request: JobRequest): JobStatus {
+  const { jobId } = request;
+  const job = findJob(request);
+  if (job.status.state === 'running') {
+    debug(`job ${jobId}: cancelled by the host`);
+    job.abort.abort(cancelledError());
+  }
+  return { ...job.status };
+}
+
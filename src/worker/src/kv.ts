@@ -0,0 +1,42 @@
+import type { KvRequest } from './api_types.js';
+import type { MessageContext } from './types.js';
+
+function checkKey(key: unknown, name: string) {
+  if (typeof key !== 'string') {
+    throw new TypeError(`kv.${name}: key must be a string`);
+  }
+}
+
+/** The `kv` global, which stores values on the host. `currentRequest` returns the request that
+ * the calling code belongs to, whose connection carries the call. */
+export function createKv(currentRequest: () => MessageContext) {
+  const call = (request: KvRequest) => {
+    const ctx = currentRequest();
+    const host = (request: KvRequest) => ctx.protocol.kvRequest(ctx.reqId, request);
+    return ctx.dryRun ? ctx.dryRun.kv(request, host) : host(request);
+  };
+
+  return Object.freeze({
+    async get(key: string): Promise<any> {
+      checkKey(key, 'get');
+      return call({ op: 'get', key });
+    },
+    async set(key: string, value: any): Promise<void> {
+      checkKey(key, 'set');
+      // Round-trip through JSON so that the error shows up here, rather than on the host.
+      const json = JSON.stringify(value);
+      if (json === undefined) {
+        throw new TypeError(`kv.set: the value for ${key} can't be converted to JSON`);
+      }
+      await call({ op: 'set', key, value: JSON.parse(json) });
+    },
+    async delete(key: string): Promise<boolean> {
+      checkKey(key, 'delete');
+      return call({ op: 'delete', key });
+    },
+    async list(prefix = ''): Promise<string[]> {
+      checkKey(prefix, 'list');
+      return call({ op: 'list', prefix });
+    },
+  });
+}
@@ -0,0 +1,62 @@
+import { describe, it, expect } from 'vitest';
+import { Lanes } from './lanes';
+
+/** Let pending promise callbacks run. */
+const settle = () => new Promise((resolve) => setImmediate(resolve));
+
+describe('Lanes', () => {
+  it('runs the same lane concurrently', async () => {
+    const lanes = new Lanes();
+    const first = await lanes.enter('low');
+    const second = await lanes.enter('low');
+    expect(lanes.active).toEqual([0, 0, 2]);
+    first();
+    second();
+    expect(lanes.active).toEqual([0, 0, 0]);
+  });
+
+  it('holds lower lanes while a higher-priority run is active', async () => {
+    const lanes = new Lanes();
+    const high = await lanes.enter('high');
+
+    const started: string[] = [];
+    const normal = lanes.enter().then((release) => {
+      started.push('normal');
+      return release;
+    });
+    const low = lanes.enter('low').then((release) => {
+      started.push('low');
+      return release;
+    });
+
+    // High-priority runs never wait.
+    const secondHigh = await lanes.enter('high');
+    await settle();
+    expect(started).toEqual([]);
+
+    high();
+    secondHigh();
+    await settle();
+    expect(started).toEqual(['normal']);
+
+    (await normal)();
+    await settle();
+    expect(started).toEqual(['normal', 'low']);
+    (await low)();
+    expect(lanes.active).toEqual([0, 0, 0]);
+  });
+
+  it('starts waiting runs after the maximum wait', async () => {
+    const lanes = new Lanes(10);
+    const high = await lanes.enter('high');
+    const release = await lanes.enter('low');
+    expect(lanes.active).toEqual([1, 0, 1]);
+    expect(lanes.waiting[2]).toEqual([]);
+
+    release();
+    high();
+    // Releasing twice has no effect.
+    high();
+    expect(lanes.active).toEqual([0, 0, 0]);
+  });
+});
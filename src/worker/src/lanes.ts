@@ -0,0 +1,74 @@
+import type { Priority } from './api_types.js';
+
+const RANKS: Record<Priority, number> = { high: 0, normal: 1, low: 2 };
+
+/** The longest that a run waits for higher-priority runs before starting anyway, so that a steady
+ * stream of high-priority work can't starve the other lanes. */
+export const MAX_LANE_WAIT_MS = 1000;
+
+/** Queues for each run priority. A run waits while any run with a higher priority is active on
+ * the worker, so that small latency-sensitive runs aren't stuck behind batch jobs that are
+ * awaiting timers or I/O. Runs in the same lane don't wait for each other. */
+export class Lanes {
+  active = [0, 0, 0];
+  waiting: Array<Array<() => void>> = [[], [], []];
+  maxWaitMs: number;
+
+  constructor(maxWaitMs = MAX_LANE_WAIT_MS) {
+    this.maxWaitMs = maxWaitMs;
+  }
+
+  /** Wait for the run's turn, returning a function to call when it finishes. */
+  async enter(priority: Priority = 'normal'): Promise<() => void> {
+    const rank = RANKS[priority] ?? RANKS.normal;
+    if (this.blocked(rank)) {
+      await new Promise<void>((resolve) => {
+        const start = () => {
+          clearTimeout(timer);
+          this.active[rank] += 1;
+          resolve();
+        };
+
+        const timer = setTimeout(() => {
+          const queue = this.waiting[rank];
+          queue.splice(queue.indexOf(start), 1);
+          this.active[rank] += 1;
+          resolve();
+        }, this.maxWaitMs);
+
+        this.waiting[rank].push(start);
+      });
+    } else {
+      this.active[rank] += 1;
+    }
+
+    let released = false;
+    return () => {
+      if (!released) {
+        released = true;
+        this.active[rank] -= 1;
+        this.wake();
+      }
+    };
+  }
+
+  /** True if a run in the lane has to wait for higher-priority runs. */
+  blocked(rank: number) {
+    return this.active.slice(0, rank).some((n) => n > 0);
+  }
+
+  /** Start the waiting runs of each lane that is no longer blocked. */
+  wake() {
+    for (let rank = 0; rank < this.waiting.length; rank++) {
+      if (this.blocked(rank)) {
+        return;
+      }
+
+      const queue = this.waiting[rank];
+      this.waiting[rank] = [];
+      for (const start of queue) {
+        start();
+      }
+    }
+  }
+}
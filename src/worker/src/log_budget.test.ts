@@ -0,0 +1,34 @@
+import { describe, it, expect } from 'vitest';
+import { LogBudget } from './log_budget';
+
+describe('LogBudget', () => {
+  it('allows everything without limits', () => {
+    const budget = new LogBudget();
+    for (let i = 0; i < 100; i++) {
+      expect(budget.take(1000)).toBe(true);
+    }
+    expect(budget.summary()).toBe(null);
+  });
+
+  it('drops messages over the count limit', () => {
+    const budget = new LogBudget(2);
+    expect([budget.take(1), budget.take(1), budget.take(1), budget.take(5)]).toEqual([
+      true,
+      true,
+      false,
+      false,
+    ]);
+    expect(budget.summary()).toBe(
+      "Dropped 2 console messages (6 bytes) over the run's limit of 2 messages"
+    );
+  });
+
+  it('stops at the first message over the byte limit', () => {
+    const budget = new LogBudget(undefined, 10);
+    expect(budget.take(6)).toBe(true);
+    expect(budget.take(6)).toBe(false);
+    // Smaller messages after the limit are dropped too, so the output is a prefix of the logs.
+    expect(budget.take(1)).toBe(false);
+    expect(budget.droppedMessages).toBe(2);
+  });
+});
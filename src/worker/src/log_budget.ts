@@ -0,0 +1,51 @@
+/** Limits on the console messages that one run can send to the host. Once either limit is
+ * reached, the rest of the run's messages are dropped, and a summary is sent with the response. */
+export class LogBudget {
+  maxMessages: number | null;
+  maxBytes: number | null;
+  messages = 0;
+  bytes = 0;
+  droppedMessages = 0;
+  droppedBytes = 0;
+
+  constructor(maxMessages?: number, maxBytes?: number) {
+    this.maxMessages = maxMessages ?? null;
+    this.maxBytes = maxBytes ?? null;
+  }
+
+  /** Returns true if a message of `bytes` bytes can be sent, and counts it. */
+  take(bytes: number) {
+    const full =
+      this.droppedMessages > 0 ||
+      (this.maxMessages !== null && this.messages >= this.maxMessages) ||
+      (this.maxBytes !== null && this.bytes + bytes > this.maxBytes);
+    if (full) {
+      this.droppedMessages += 1;
+      this.droppedBytes += bytes;
+      return false;
+    }
+
+    this.messages += 1;
+    this.bytes += bytes;
+    return true;
+  }
+
+  /** A description of the dropped messages, or null if nothing was dropped. */
+  summary() {
+    if (this.droppedMessages === 0) {
+      return null;
+    }
+
+    const limits = [];
+    if (this.maxMessages !== null) {
+      limits.push(`${this.maxMessages} messages`);
+    }
+    if (this.maxBytes !== null) {
+      limits.push(`${this.maxBytes} bytes`);
+    }
+    return (
+      `Dropped ${this.droppedMessages} console messages (${this.droppedBytes} bytes) ` +
+      `over the run's limit of ${limits.join(' or ')}`
+    );
+  }
+}
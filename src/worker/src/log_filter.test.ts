@@ -0,0 +1,37 @@
+import { describe, it, expect } from 'vitest';
+import { NamespaceFilter, extractNamespace } from './log_filter';
+
+describe('NamespaceFilter', () => {
+  it('enables matching namespaces', () => {
+    const filter = new NamespaceFilter(['db', 'http:*']);
+    expect(filter.isEnabled('db')).toBe(true);
+    expect(filter.isEnabled('http:request')).toBe(true);
+    expect(filter.isEnabled('dbx')).toBe(false);
+    expect(filter.isEnabled('cache')).toBe(false);
+  });
+
+  it('skipped namespaces take precedence', () => {
+    const filter = new NamespaceFilter(['db:*', '-db:verbose']);
+    expect(filter.isEnabled('db:query')).toBe(true);
+    expect(filter.isEnabled('db:verbose')).toBe(false);
+  });
+
+  it('only negative patterns enables everything else', () => {
+    const filter = new NamespaceFilter(['-noisy']);
+    expect(filter.isEnabled('db')).toBe(true);
+    expect(filter.isEnabled('noisy')).toBe(false);
+  });
+});
+
+describe('extractNamespace', () => {
+  it('reads a bracketed prefix', () => {
+    expect(extractNamespace(['[db] connected', 5])).toBe('db');
+    expect(extractNamespace(['[db:pool]'])).toBe('db:pool');
+  });
+
+  it('ignores messages without a prefix', () => {
+    expect(extractNamespace(['connected [db]'])).toBeUndefined();
+    expect(extractNamespace([{ a: 1 }])).toBeUndefined();
+    expect(extractNamespace([])).toBeUndefined();
+  });
+});
@@ -0,0 +1,54 @@
+/** Matches log namespaces against a `debug`-style list of patterns.
+ *
+ *  Each pattern is a namespace name, which may contain `*` wildcards. Patterns starting with `-`
+ *  disable matching namespaces, and take precedence over the enabling patterns.
+ *
+ *  e.g. `['db:*', '-db:verbose']` enables `db:query` and `db:pool`, but not `db:verbose`.
+ * */
+export class NamespaceFilter {
+  enabled: RegExp[] = [];
+  skipped: RegExp[] = [];
+
+  constructor(patterns: string[]) {
+    for (const pattern of patterns) {
+      if (!pattern) {
+        continue;
+      }
+
+      if (pattern.startsWith('-')) {
+        this.skipped.push(patternToRegex(pattern.slice(1)));
+      } else {
+        this.enabled.push(patternToRegex(pattern));
+      }
+    }
+  }
+
+  isEnabled(namespace: string): boolean {
+    if (this.skipped.some((r) => r.test(namespace))) {
+      return false;
+    }
+
+    // Only negative patterns means that everything else is enabled.
+    if (!this.enabled.length) {
+      return true;
+    }
+
+    return this.enabled.some((r) => r.test(namespace));
+  }
+}
+
+function patternToRegex(pattern: string) {
+  const escaped = pattern.replace(/[.+?^${}()|[\]\\]/g, '\\$&').replace(/\*/g, '.*?');
+  return new RegExp(`^${escaped}$`);
+}
+
+const PREFIX_REGEX = /^\[([^\]\s]+)\]/;
+
+/** Get the namespace from a log message that starts with a `[namespace]` prefix. */
+export function extractNamespace(args: any[]): string | undefined {
+  if (typeof args[0] !== 'string') {
+    return undefined;
+  }
+
+  return PREFIX_REGEX.exec(args[0])?.[1];
+}
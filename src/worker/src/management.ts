@@ -0,0 +1,153 @@
+import cluster from 'node:cluster';
+import type { Worker } from 'node:cluster';
+import http from 'node:http';
+import type { Protocol } from './protocol.js';
+import type { SidecarHealth, WorkerStats } from './api_types.js';
+import { activeRequests } from './annotations.js';
+import { hasContext } from './run_script.js';
+import { threadStats } from './threads.js';
+import { parseAddress } from './websocket.js';
+import { debug } from './debug.js';
+
+/** How long the primary waits for each worker to report its stats. */
+const STATS_TIMEOUT_MS = 1000;
+
+interface StatsRequest {
+  type: 'stats';
+  id: number;
+}
+
+interface StatsResponse {
+  type: 'stats';
+  id: number;
+  stats: WorkerStats;
+}
+
+function isStatsMessage(msg: any): boolean {
+  return typeof msg === 'object' && msg?.type === 'stats';
+}
+
+let nextStatsId = 0;
+
+/** Ask a worker for its stats over the cluster IPC channel. Resolves to null if the worker doesn't
+ * answer in time. */
+function requestStats(worker: Worker): Promise<WorkerStats | null> {
+  const id = nextStatsId++;
+  return new Promise((resolve) => {
+    const timeout = setTimeout(() => finish(null), STATS_TIMEOUT_MS);
+    const listener = (msg: any) => {
+      if (isStatsMessage(msg) && msg.id === id && msg.stats) {
+        finish((msg as StatsResponse).stats);
+      }
+    };
+
+    function finish(stats: WorkerStats | null) {
+      clearTimeout(timeout);
+      worker.off('message', listener);
+      resolve(stats);
+    }
+
+    worker.on('message', listener);
+    const request: StatsRequest = { type: 'stats', id };
+    worker.send(request, () => {});
+  });
+}
+
+async function collectWorkerStats() {
+  const workers = Object.values(cluster.workers ?? {}) as Worker[];
+  const stats = await Promise.all(workers.map(requestStats));
+  return stats.filter((s) => s !== null) as WorkerStats[];
+}
+
+function health(): SidecarHealth {
+  return {
+    pid: process.pid,
+    workers: Object.keys(cluster.workers ?? {}).length,
+    uptimeMs: process.uptime() * 1000,
+  };
+}
+
+/** Start the management HTTP server in the primary process. */
+export function startManagementServer(address: string) {
+  const { host, port } = parseAddress(address);
+
+  const server = http.createServer((req, res) => {
+    const respond = (status: number, body: unknown) => {
+      const data = JSON.stringify(body);
+      res.writeHead(status, {
+        'Content-Type': 'application/json',
+        'Content-Length': Buffer.byteLength(data),
+      });
+      res.end(data);
+    };
+
+    if (req.method !== 'GET') {
+      respond(405, { error: 'Method not allowed' });
+      return;
+    }
+
+    switch (req.url) {
+      case '/health':
+        respond(200, health());
+        break;
+      case '/workers':
+        collectWorkerStats()
+          .then((stats) => respond(200, stats))
+          .catch((e) => respond(500, { error: String(e) }));
+        break;
+      default:
+        respond(404, { error: 'Not found' });
+    }
+  });
+
+  server.on('error', (e) => {
+    console.error('Management server failed', e);
+  });
+
+  server.listen(port, host, () => {
+    debug(`Management server listening on ${address}`);
+  });
+
+  return server;
+}
+
+/** The stats for the current worker process. */
+export function workerStats(connections: Set<Protocol>, requestsHandled: number): WorkerStats {
+  const { heapUsed, heapTotal, rss, external } = process.memoryUsage();
+  let contexts = 0;
+  for (const protocol of connections) {
+    if (hasContext(protocol) || threadStats(protocol)?.hasContext) {
+      contexts += 1;
+    }
+  }
+
+  return {
+    pid: process.pid,
+    uptimeMs: process.uptime() * 1000,
+    connections: connections.size,
+    contexts,
+    activeRequests: activeRunCount(connections),
+    requestsHandled,
+    memory: { pid: process.pid, heapUsed, heapTotal, rss, external },
+  };
+}
+
+/** The requests running in the current worker process, including those in connection threads. */
+export function activeRunCount(connections: Set<Protocol>) {
+  let count = activeRequests.size;
+  for (const protocol of connections) {
+    count += threadStats(protocol)?.activeRequests ?? 0;
+  }
+  return count;
+}
+
+/** Answer stats requests from the primary process. */
+export function handleStatsRequests(getStats: () => WorkerStats) {
+  process.on('message', (msg: any) => {
+    if (isStatsMessage(msg) && !msg.stats) {
+      const request = msg as StatsRequest;
+      const response: StatsResponse = { type: 'stats', id: request.id, stats: getStats() };
+      process.send?.(response);
+    }
+  });
+}
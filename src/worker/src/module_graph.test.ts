@@ -0,0 +1,47 @@
+import { describe, it, expect } from 'vitest';
+import { findCycle, joinSpecifier, moduleName, resolveSpecifier } from './module_graph';
+
+describe('resolveSpecifier', () => {
+  const modules = { lib: 1, 'lib/a.js': 1, 'lib/b.mjs': 1, 'util/index.js': 1, 'c.js': 1 };
+
+  it('resolves bare names exactly', () => {
+    expect(resolveSpecifier('lib', '<script>', modules)).toBe('lib');
+    expect(resolveSpecifier('missing', '<script>', modules)).toBe(undefined);
+  });
+
+  it('resolves relative specifiers against the importing module', () => {
+    expect(resolveSpecifier('./a.js', 'lib/b.mjs', modules)).toBe('lib/a.js');
+    expect(resolveSpecifier('./b', 'lib/a.js', modules)).toBe('lib/b.mjs');
+    expect(resolveSpecifier('../c.js', 'lib/a.js', modules)).toBe('c.js');
+    expect(resolveSpecifier('./util', '<script>', modules)).toBe('util/index.js');
+    expect(resolveSpecifier('./lib/a', 'main.js', modules)).toBe('lib/a.js');
+  });
+
+  it('normalizes names', () => {
+    expect(moduleName('./lib/../c.js')).toBe('c.js');
+  });
+
+  it('joins relative specifiers without looking for a module', () => {
+    expect(joinSpecifier('./util', 'plugins/a/index.js')).toBe('plugins/a/util');
+    expect(joinSpecifier('../b/index.js', 'plugins/a/index.js')).toBe('plugins/b/index.js');
+    expect(joinSpecifier('plugins/a', 'main.js')).toBe('plugins/a');
+  });
+});
+
+describe('findCycle', () => {
+  const graph = (edges: Record<string, string[]>) => (name: string) => edges[name] ?? [];
+
+  it('allows shared dependencies', () => {
+    expect(findCycle('main', graph({ main: ['a', 'b'], a: ['c'], b: ['c'] }))).toBe(null);
+  });
+
+  it('finds cycles', () => {
+    expect(findCycle('main', graph({ main: ['a'], a: ['b'], b: ['c'], c: ['a'] }))).toEqual([
+      'a',
+      'b',
+      'c',
+      'a',
+    ]);
+    expect(findCycle('a', graph({ a: ['a'] }))).toEqual(['a', 'a']);
+  });
+});
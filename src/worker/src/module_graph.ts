@@ -0,0 +1,72 @@
+import path from 'node:path';
+import { isUrl } from './url_imports.js';
+
+/** Suffixes tried, in order, when a specifier doesn't match a module name exactly. */
+const SUFFIXES = ['', '.js', '.mjs', '/index.js', '/index.mjs'];
+
+/** The name that a module is stored under, with `./` and `..` segments resolved. Modules from
+ * URLs are stored under their URL. */
+export function moduleName(name: string) {
+  if (isUrl(name)) {
+    return name;
+  }
+  return path.posix.normalize(name).replace(/^\.\//, '');
+}
+
+/** Join a relative specifier to the name of the importing module, as if the names were file
+ * paths. Other specifiers are returned unchanged. */
+export function joinSpecifier(specifier: string, referrer: string) {
+  const relative = specifier.startsWith('./') || specifier.startsWith('../');
+  if (!relative) {
+    return specifier;
+  }
+  return moduleName(path.posix.join(path.posix.dirname(referrer), specifier));
+}
+
+/** Find the module that `specifier` refers to. Relative specifiers are resolved against the name
+ * of the importing module. */
+export function resolveSpecifier(
+  specifier: string,
+  referrer: string,
+  modules: Record<string, unknown>
+): string | undefined {
+  const base = joinSpecifier(specifier, referrer);
+  for (const suffix of SUFFIXES) {
+    const name = moduleName(base + suffix);
+    if (Object.hasOwn(modules, name)) {
+      return name;
+    }
+  }
+  return undefined;
+}
+
+/** Find an import cycle reachable from `root`, to explain errors from modules that used each
+ * other's exports too early. Returns the modules along the cycle, starting and ending with the
+ * same one, or null if there is no cycle. */
+export function findCycle(root: string, dependencies: (name: string) => string[]): string[] | null {
+  const done = new Set<string>();
+  const stack: string[] = [];
+
+  const visit = (name: string): string[] | null => {
+    const index = stack.indexOf(name);
+    if (index !== -1) {
+      return [...stack.slice(index), name];
+    }
+    if (done.has(name)) {
+      return null;
+    }
+
+    stack.push(name);
+    for (const dep of dependencies(name)) {
+      const cycle = visit(dep);
+      if (cycle) {
+        return cycle;
+      }
+    }
+    stack.pop();
+    done.add(name);
+    return null;
+  };
+
+  return visit(root);
+}
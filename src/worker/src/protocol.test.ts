@@ -1,7 +1,28 @@
 import { describe, it, expect, beforeEach, vi } from 'vitest';
 import net from 'net';
-import { Protocol } from './protocol';
-import { HostToWorkerMessage, WorkerToHostMessage } from './api_types';
+import fs from 'node:fs';
+import os from 'node:os';
+import path from 'node:path';
+import { gunzipSync, gzipSync } from 'node:zlib';
+import { Protocol, crc32 } from './protocol';
+import { HostToWorkerMessage, PROTOCOL_VERSION, WorkerToHostMessage } from './api_types';
+
+/** Build a frame the way the host does. */
+function frame(reqId: number, id: number, type: number, data = Buffer.alloc(0), checksum = false) {
+  const header = Buffer.alloc(20);
+  header.writeUInt32LE(16 + data.length + (checksum ? 4 : 0), 0); // Length
+  header.writeUInt32LE(0x46534a53, 4); // Magic
+  header.writeUInt32LE(reqId, 8);
+  header.writeUInt32LE(id, 12);
+  header.writeUInt32LE(checksum ? (type | 0x80000000) >>> 0 : type, 16);
+  const parts = [header, data];
+  if (checksum) {
+    const crc = Buffer.alloc(4);
+    crc.writeUInt32LE(crc32(Buffer.concat(parts)));
+    parts.push(crc);
+  }
+  return Buffer.concat(parts);
+}
 
 describe('Protocol', () => {
   let mockSocket: net.Socket;
@@ -11,27 +32,22 @@ describe('Protocol', () => {
     mockSocket = {
       on: vi.fn(),
       write: vi.fn(),
+      end: vi.fn(),
     } as unknown as net.Socket;
     protocol = new Protocol(mockSocket);
   });
 
   it('constructor initializes correctly', () => {
     expect(protocol.buffer).toHaveLength(0);
-    expect(protocol.expectedLength).toBeNull();
-    expect(protocol.id).toBe(0);
+    expect(protocol.checksums).toBe(false);
+    expect(protocol.sequences.size).toBe(0);
   });
 
   it('handleData processes complete message', () => {
     const messageListener = vi.fn();
     protocol.on('message', messageListener);
 
-    const message = Buffer.alloc(16);
-    message.writeUInt32LE(12, 0); // Length
-    message.writeUInt32LE(1, 4); // Request ID
-    message.writeUInt32LE(2, 8); // Message ID
-    message.writeUInt32LE(HostToWorkerMessage.RunScript, 12); // Message Type
-
-    protocol.handleData(message);
+    protocol.handleData(frame(1, 2, HostToWorkerMessage.RunScript));
 
     expect(messageListener).toHaveBeenCalledWith({
       id: 2,
@@ -45,14 +61,9 @@ describe('Protocol', () => {
     const messageListener = vi.fn();
     protocol.on('message', messageListener);
 
-    const part1 = Buffer.alloc(6);
-    part1.writeUInt32LE(12, 0); // Length
-    part1.writeUInt16LE(1, 4); // Partial Request ID
-
-    const part2 = Buffer.alloc(10);
-    part2.writeUInt16LE(0, 0); // Rest of Request ID
-    part2.writeUInt32LE(2, 2); // Message ID
-    part2.writeUInt32LE(HostToWorkerMessage.RunScript, 6); // Message Type
+    const message = frame(1, 2, HostToWorkerMessage.RunScript);
+    const part1 = message.subarray(0, 10);
+    const part2 = message.subarray(10);
 
     protocol.handleData(part1);
     expect(messageListener).not.toHaveBeenCalled();
@@ -76,23 +87,204 @@ describe('Protocol', () => {
     expect(mockSocket.write).toHaveBeenCalledWith(expect.any(Buffer));
 
     const writtenBuffer = (mockSocket.write as any).mock.calls[0][0] as Buffer;
-    expect(writtenBuffer.readUInt32LE(0)).toBe(message.length + 12); // Total length
-    expect(writtenBuffer.readUInt32LE(4)).toBe(reqId);
-    expect(writtenBuffer.readUInt32LE(8)).toBe(0); // First message ID
-    expect(writtenBuffer.readUInt32LE(12)).toBe(type);
-    expect(writtenBuffer.subarray(16).toString()).toBe(message);
+    expect(writtenBuffer).toEqual(frame(reqId, 0, type, Buffer.from(message)));
+  });
+
+  it('sendMessage adds a checksum when enabled', () => {
+    protocol.checksums = true;
+    protocol.sendMessage(1, WorkerToHostMessage.RunResponse, 'test message');
+
+    const writtenBuffer = (mockSocket.write as any).mock.calls[0][0] as Buffer;
+    expect(writtenBuffer).toEqual(
+      frame(1, 0, WorkerToHostMessage.RunResponse, Buffer.from('test message'), true)
+    );
+  });
+
+  it('numbers the messages of each request', () => {
+    const ids = () =>
+      (mockSocket.write as any).mock.calls.map((call: [Buffer]) => [
+        call[0].readUInt32LE(8),
+        call[0].readUInt32LE(12),
+      ]);
+
+    protocol.log(1, 'info', ['a']);
+    protocol.log(2, 'info', ['b']);
+    protocol.log(1, 'info', ['c']);
+    protocol.sendMessage(0, WorkerToHostMessage.MemoryUsage, '{}');
+    protocol.respond(1, {});
+    // A message after the run finished starts a new sequence.
+    protocol.log(1, 'info', ['d']);
+    expect(ids()).toEqual([
+      [1, 0],
+      [2, 0],
+      [1, 1],
+      [0, 0],
+      [1, 2],
+      [1, 0],
+    ]);
+
+    // Frames sent by a connection's worker thread advance the sequence too.
+    protocol.forwarded(frame(2, 5, WorkerToHostMessage.Log));
+    protocol.log(2, 'info', ['e']);
+    expect(ids().at(-1)).toEqual([2, 6]);
+  });
+
+  it('handleData skips garbage to the next frame', () => {
+    const messageListener = vi.fn();
+    protocol.on('message', messageListener);
+
+    const garbage = Buffer.from('not a frame at all');
+    protocol.handleData(Buffer.concat([garbage, frame(1, 2, HostToWorkerMessage.Ping)]));
+    protocol.handleData(frame(3, 4, HostToWorkerMessage.Ping));
+
+    expect(messageListener).toHaveBeenCalledTimes(2);
+    expect(messageListener.mock.calls[0][0]).toMatchObject({ reqId: 1, id: 2 });
+    expect(messageListener.mock.calls[1][0]).toMatchObject({ reqId: 3, id: 4 });
+  });
+
+  it('handleData keeps a partial frame while resyncing', () => {
+    const messageListener = vi.fn();
+    protocol.on('message', messageListener);
+
+    const message = frame(1, 2, HostToWorkerMessage.Ping);
+    protocol.handleData(Buffer.concat([Buffer.from('garbage'), message.subarray(0, 6)]));
+    protocol.handleData(message.subarray(6));
+
+    expect(messageListener).toHaveBeenCalledTimes(1);
+    expect(messageListener.mock.calls[0][0]).toMatchObject({ reqId: 1, id: 2 });
+  });
+
+  it('handleData checks checksums', () => {
+    const messageListener = vi.fn();
+    protocol.on('message', messageListener);
+
+    const data = Buffer.from('{"code":"1"}');
+    protocol.handleData(frame(1, 2, HostToWorkerMessage.RunScript, data, true));
+    expect(messageListener).toHaveBeenCalledWith({
+      id: 2,
+      reqId: 1,
+      type: HostToWorkerMessage.RunScript,
+      data,
+    });
+
+    const corrupted = frame(3, 4, HostToWorkerMessage.RunScript, data, true);
+    corrupted[22] ^= 0xff;
+    protocol.handleData(corrupted);
+    expect(messageListener).toHaveBeenCalledTimes(1);
+
+    const sent = (mockSocket.write as any).mock.calls[0][0] as Buffer;
+    expect(sent.readUInt32LE(8)).toBe(3);
+    expect(sent.readUInt32LE(16)).toBe(WorkerToHostMessage.Error);
+  });
+
+  it('compresses large frames after the handshake asks for it', () => {
+    const handshake = { version: PROTOCOL_VERSION, compressFramesOver: 100 };
+    protocol.handshake(0, Buffer.from(JSON.stringify(handshake)));
+    expect(protocol.compressOver).toBe(100);
+
+    const large = JSON.stringify({ returnValue: 'a'.repeat(1000) });
+    protocol.sendMessage(1, WorkerToHostMessage.RunResponse, large);
+    protocol.sendMessage(2, WorkerToHostMessage.RunResponse, '{"returnValue":1}');
+
+    const compressed = (mockSocket.write as any).mock.calls[1][0] as Buffer;
+    expect(compressed.readUInt32LE(16)).toBe((WorkerToHostMessage.RunResponse | 0x40000000) >>> 0);
+    expect(compressed.readUInt32LE(0)).toBe(compressed.length - 4);
+    expect(compressed.length).toBeLessThan(large.length);
+    expect(gunzipSync(compressed.subarray(20)).toString()).toBe(large);
+
+    const small = (mockSocket.write as any).mock.calls[2][0] as Buffer;
+    expect(small).toEqual(
+      frame(2, 0, WorkerToHostMessage.RunResponse, Buffer.from('{"returnValue":1}'))
+    );
+  });
+
+  it('handleData decompresses frames', () => {
+    const messageListener = vi.fn();
+    protocol.on('message', messageListener);
+
+    const data = Buffer.from(JSON.stringify({ code: 'return 1;'.repeat(100) }));
+    const type = (HostToWorkerMessage.RunScript | 0x40000000) >>> 0;
+    protocol.handleData(frame(1, 2, type, gzipSync(data), true));
+    expect(messageListener).toHaveBeenCalledWith({
+      id: 2,
+      reqId: 1,
+      type: HostToWorkerMessage.RunScript,
+      data,
+    });
+
+    protocol.handleData(frame(3, 4, type, Buffer.from('not gzip')));
+    expect(messageListener).toHaveBeenCalledTimes(1);
+    const sent = (mockSocket.write as any).mock.calls[0][0] as Buffer;
+    expect(sent.readUInt32LE(8)).toBe(3);
+    expect(sent.readUInt32LE(16)).toBe(WorkerToHostMessage.Error);
+  });
+
+  it('handleData stops decompressing at the frame limit', () => {
+    const messageListener = vi.fn();
+    protocol.on('message', messageListener);
+    protocol.maxPayloadBytes = 100;
+
+    const type = (HostToWorkerMessage.RunScript | 0x40000000) >>> 0;
+    protocol.handleData(frame(1, 2, type, gzipSync(Buffer.alloc(1_000_000))));
+    expect(messageListener).not.toHaveBeenCalled();
+    const sent = (mockSocket.write as any).mock.calls[0][0] as Buffer;
+    expect(sent.readUInt32LE(8)).toBe(1);
+    expect(sent.readUInt32LE(16)).toBe(WorkerToHostMessage.Error);
+    expect(sent.subarray(20).toString()).toContain('larger than 100 bytes once decompressed');
+  });
+
+  it('passes large payloads through shared memory', () => {
+    const dir = fs.mkdtempSync(path.join(os.tmpdir(), 'protocol-test-'));
+    try {
+      const handshake = { version: PROTOCOL_VERSION, sharedMemoryDir: dir, sharedMemoryOver: 100 };
+      protocol.handshake(0, Buffer.from(JSON.stringify(handshake)));
+      const messageListener = vi.fn();
+      protocol.on('message', messageListener);
+
+      const large = JSON.stringify({ returnValue: 'a'.repeat(1000) });
+      protocol.sendMessage(1, WorkerToHostMessage.RunResponse, large);
+      const sent = (mockSocket.write as any).mock.calls[1][0] as Buffer;
+      expect(sent.readUInt32LE(16)).toBe((WorkerToHostMessage.RunResponse | 0x20000000) >>> 0);
+      const name = sent.subarray(20).toString();
+      expect(fs.readFileSync(path.join(dir, name)).toString()).toBe(large);
+
+      const script = Buffer.from(JSON.stringify({ code: 'return 1;'.repeat(100) }));
+      fs.writeFileSync(path.join(dir, 'host-0'), script);
+      const type = (HostToWorkerMessage.RunScript | 0x20000000) >>> 0;
+      protocol.handleData(frame(2, 3, type, Buffer.from('host-0')));
+      expect(messageListener).toHaveBeenCalledWith({
+        id: 3,
+        reqId: 2,
+        type: HostToWorkerMessage.RunScript,
+        data: script,
+      });
+      expect(fs.existsSync(path.join(dir, 'host-0'))).toBe(false);
+
+      // Names that point outside the directory are rejected.
+      protocol.handleData(frame(4, 5, type, Buffer.from('../host-0')));
+      expect(messageListener).toHaveBeenCalledTimes(1);
+      const error = (mockSocket.write as any).mock.calls[2][0] as Buffer;
+      expect(error.readUInt32LE(8)).toBe(4);
+      expect(error.readUInt32LE(16)).toBe(WorkerToHostMessage.Error);
+    } finally {
+      fs.rmSync(dir, { recursive: true, force: true });
+    }
   });
 
   it('log sends correct log message', () => {
     const sendMessageSpy = vi.spyOn(protocol, 'sendMessage');
 
-    protocol.log(1, 'info', 'test log');
+    protocol.log(1, 'info', 'test log', 'db');
 
-    expect(sendMessageSpy).toHaveBeenCalledWith(
-      1,
-      WorkerToHostMessage.Log,
-      JSON.stringify({ level: 'info', message: 'test log' })
-    );
+    expect(sendMessageSpy).toHaveBeenCalledWith(1, WorkerToHostMessage.Log, expect.any(String));
+    const data = JSON.parse(sendMessageSpy.mock.calls[0][2] as string);
+    expect(data).toEqual({
+      level: 'info',
+      message: 'test log',
+      timestamp: expect.any(Number),
+      requestId: 1,
+      namespace: 'db',
+    });
   });
 
   it('respond sends correct response', () => {
@@ -103,10 +295,48 @@ describe('Protocol', () => {
     expect(sendMessageSpy).toHaveBeenCalledWith(
       1,
       WorkerToHostMessage.RunResponse,
-      JSON.stringify({ globals: { a: 'b' }, returnValue: 5 })
+      Buffer.from(JSON.stringify({ globals: { a: 'b' }, returnValue: 5 }))
     );
   });
 
+  it('respond splits large responses into chunks', () => {
+    const sendMessageSpy = vi.spyOn(protocol, 'sendMessage');
+    protocol.maxFrameBytes = 30;
+
+    const response = { returnValue: 'x'.repeat(20) };
+    protocol.respond(1, response);
+
+    const calls = sendMessageSpy.mock.calls;
+    // 38 bytes of JSON in 10 byte chunks
+    expect(calls.map((c) => c[1])).toEqual([
+      WorkerToHostMessage.RunResponseChunk,
+      WorkerToHostMessage.RunResponseChunk,
+      WorkerToHostMessage.RunResponseChunk,
+      WorkerToHostMessage.RunResponse,
+    ]);
+    expect(calls.every((c) => (c[2] as Buffer).length <= 10)).toBe(true);
+    const data = Buffer.concat(calls.map((c) => c[2] as Buffer));
+    expect(JSON.parse(data.toString())).toEqual(response);
+  });
+
+  it('respond sends an error for responses over the limit', () => {
+    protocol.maxResponseBytes = 10;
+    protocol.respond(1, { returnValue: 'x'.repeat(20) });
+
+    const sent = (mockSocket.write as any).mock.calls[0][0] as Buffer;
+    expect(sent.readUInt32LE(16)).toBe(WorkerToHostMessage.MessageTooLarge);
+    expect(JSON.parse(sent.subarray(20).toString())).toEqual({ length: 38, limit: 10 });
+  });
+
+  it('sendMessage replaces messages over the frame limit', () => {
+    protocol.maxFrameBytes = 30;
+    protocol.log(1, 'info', 'x'.repeat(100));
+
+    const sent = (mockSocket.write as any).mock.calls[0][0] as Buffer;
+    expect(sent.readUInt32LE(8)).toBe(1);
+    expect(sent.readUInt32LE(16)).toBe(WorkerToHostMessage.MessageTooLarge);
+  });
+
   it('error sends correct error message', () => {
     const sendMessageSpy = vi.spyOn(protocol, 'sendMessage');
     const error = new Error('Test error');
@@ -116,7 +346,93 @@ describe('Protocol', () => {
     expect(sendMessageSpy).toHaveBeenCalledWith(
       1,
       WorkerToHostMessage.Error,
-      JSON.stringify({ message: 'Test error', stack: error.stack })
+      JSON.stringify({ message: 'Test error', stack: error.stack, kind: 'script', name: 'Error' })
     );
   });
+
+  it('error handles thrown values that are not errors', () => {
+    const sendMessageSpy = vi.spyOn(protocol, 'sendMessage');
+
+    protocol.error(1, 'just a string' as any);
+
+    expect(sendMessageSpy).toHaveBeenCalledWith(
+      1,
+      WorkerToHostMessage.Error,
+      JSON.stringify({ message: 'just a string', kind: 'script' })
+    );
+  });
+
+  it('accepts a handshake with the same version', () => {
+    const data = Buffer.from(JSON.stringify({ version: PROTOCOL_VERSION, checksums: true }));
+    expect(protocol.handshake(3, data)).toBe(true);
+    expect(protocol.checksums).toBe(true);
+
+    const sent = (mockSocket.write as any).mock.calls[0][0] as Buffer;
+    expect(sent.readUInt32LE(8)).toBe(3);
+    expect(sent.readUInt32LE(16)).toBe(WorkerToHostMessage.Handshake);
+    expect(JSON.parse(sent.subarray(20).toString())).toEqual({
+      version: PROTOCOL_VERSION,
+      accepted: true,
+      pid: process.pid,
+    });
+    expect(mockSocket.end).not.toHaveBeenCalled();
+  });
+
+  it('rejects a handshake with a different version and closes', () => {
+    const data = Buffer.from(JSON.stringify({ version: PROTOCOL_VERSION + 1 }));
+    expect(protocol.handshake(0, data)).toBe(false);
+
+    const sent = (mockSocket.write as any).mock.calls[0][0] as Buffer;
+    expect(JSON.parse(sent.subarray(20).toString()).accepted).toBe(false);
+    expect(mockSocket.end).toHaveBeenCalled();
+  });
+
+  it('round-trips KV calls to the host', async () => {
+    protocol.handshake(0, Buffer.from(JSON.stringify({ version: PROTOCOL_VERSION, kv: true })));
+    expect(protocol.kvEnabled).toBe(true);
+
+    const get = protocol.kvRequest(5, { op: 'get', key: 'a' });
+    const set = protocol.kvRequest(5, { op: 'set', key: 'a', value: 1 });
+
+    const sent = (mockSocket.write as any).mock.calls[1][0] as Buffer;
+    expect(sent.readUInt32LE(8)).toBe(5);
+    expect(sent.readUInt32LE(16)).toBe(WorkerToHostMessage.KvRequest);
+    expect(JSON.parse(sent.subarray(20).toString())).toEqual({ id: 0, op: 'get', key: 'a' });
+
+    protocol.kvResponse(Buffer.from(JSON.stringify({ id: 1, error: 'backend failed' })));
+    protocol.kvResponse(Buffer.from(JSON.stringify({ id: 0, value: { b: 2 } })));
+    await expect(get).resolves.toEqual({ b: 2 });
+    await expect(set).rejects.toThrow('backend failed');
+    expect(protocol.hostCalls.size).toBe(0);
+  });
+
+  it('rejects KV calls when the host has no backend', async () => {
+    await expect(protocol.kvRequest(0, { op: 'list', prefix: '' })).rejects.toThrow(
+      'no KV backend'
+    );
+    expect(mockSocket.write).not.toHaveBeenCalled();
+  });
+
+  it('asks the host to resolve modules', async () => {
+    const handshake = { version: PROTOCOL_VERSION, resolveModules: true };
+    protocol.handshake(0, Buffer.from(JSON.stringify(handshake)));
+    expect(protocol.resolveModulesEnabled).toBe(true);
+
+    const found = protocol.resolveModule(3, 'plugins/a', 'main.js');
+    const missing = protocol.resolveModule(3, 'plugins/b', 'main.js');
+
+    const sent = (mockSocket.write as any).mock.calls[1][0] as Buffer;
+    expect(sent.readUInt32LE(16)).toBe(WorkerToHostMessage.ResolveModule);
+    expect(JSON.parse(sent.subarray(20).toString())).toEqual({
+      id: 0,
+      specifier: 'plugins/a',
+      referrer: 'main.js',
+    });
+
+    const module = { name: 'plugins/a/index.js', code: 'export default 1;' };
+    protocol.moduleResolved(Buffer.from(JSON.stringify({ id: 0, module })));
+    protocol.moduleResolved(Buffer.from(JSON.stringify({ id: 1 })));
+    await expect(found).resolves.toEqual(module);
+    await expect(missing).resolves.toBe(null);
+  });
 });
@@ -1,7 +1,30 @@
-import net from 'node:net';
 import { EventEmitter } from 'node:events';
-import { HostToWorkerMessage, WorkerToHostMessage, type RunResponse } from './api_types.js';
+import fs from 'node:fs';
+import path from 'node:path';
+import { gunzipSync, gzipSync, constants as zlibConstants } from 'node:zlib';
+import {
+  HostToWorkerMessage,
+  PROTOCOL_VERSION,
+  WorkerToHostMessage,
+  type Handshake,
+  type CodeModule,
+  type HandshakeResponse,
+  type KvRequest,
+  type KvResponse,
+  type LogLevel,
+  type LogMessage,
+  type ErrorResponse,
+  type AsyncError,
+  type AsyncErrorSource,
+  type ErrorKind,
+  type MessageTooLarge,
+  type ResolveModuleResponse,
+  type RunResponse,
+} from './api_types.js';
+import { annotateStack, type Annotations } from './annotations.js';
 import { debug } from './debug.js';
+import { describeError, errorKind, internalError } from './errors.js';
+import type { LogBudget } from './log_budget.js';
 
 export interface IncomingMessage {
   id: number;
@@ -10,72 +33,226 @@ export interface IncomingMessage {
   data: Buffer;
 }
 
+/** The byte stream that a Protocol runs over: a Unix socket, or a WebSocket connection. */
+export interface Transport {
+  on(event: 'data', listener: (data: Buffer) => void): unknown;
+  on(event: 'close', listener: () => void): unknown;
+  write(data: Buffer, callback?: () => void): unknown;
+  /** Close the connection once everything written so far has been sent. */
+  end(): unknown;
+}
+
+/** Follows the length at the start of every frame, so that a reader that loses its place can
+ * scan for the start of the next frame. */
+const FRAME_MAGIC = 0x46534a53;
+const FRAME_MAGIC_BYTES = Buffer.from([0x53, 0x4a, 0x53, 0x46]);
+
+/** Set in the message type of frames that end with a CRC32 checksum. */
+const CHECKSUM_FLAG = 0x80000000;
+/** Set in the message type of frames whose payload is gzip-compressed. The checksum, if any,
+ * covers the compressed payload. */
+const COMPRESSED_FLAG = 0x40000000;
+/** Set in the message type of frames whose payload is the name of a file in the shared memory
+ * directory, which holds the real payload. */
+const SHARED_MEMORY_FLAG = 0x20000000;
+const FLAGS = CHECKSUM_FLAG | COMPRESSED_FLAG | SHARED_MEMORY_FLAG;
+
+/** Message types that end a request's sequence of message IDs. The next message for the same
+ * request, such as an async error after a run finished, starts a new sequence. */
+const ENDS_SEQUENCE = new Set([
+  WorkerToHostMessage.RunResponse,
+  WorkerToHostMessage.Error,
+  WorkerToHostMessage.Pong,
+  WorkerToHostMessage.Handshake,
+]);
+
+/** Message types that aren't part of any request, and so are left out of the sequences. */
+const UNSEQUENCED = new Set([WorkerToHostMessage.MemoryUsage, WorkerToHostMessage.WorkerLoad]);
+
 // Header *without* the length field
-const MSG_HEADER_LENGTH = 12;
+const MSG_HEADER_LENGTH = 16;
+/** The largest payload that an uncompressed frame can carry. */
+const MAX_PAYLOAD_BYTES = 0xffffffff - MSG_HEADER_LENGTH;
+
+// Offsets from the start of the frame.
+const MAGIC_OFFSET = 4;
+const REQ_ID_OFFSET = 8;
+const MSG_ID_OFFSET = 12;
+const MSG_TYPE_OFFSET = 16;
+const DATA_OFFSET = 20;
+
+const CRC32_TABLE = new Uint32Array(256);
+for (let i = 0; i < 256; i++) {
+  let c = i;
+  for (let bit = 0; bit < 8; bit++) {
+    c = c & 1 ? 0xedb88320 ^ (c >>> 1) : c >>> 1;
+  }
+  CRC32_TABLE[i] = c >>> 0;
+}
+
+/** Split a payload that has binary data after its JSON. */
+export function splitBinaryPayload(data: Buffer) {
+  if (data.length < 4) {
+    throw internalError('Binary payload is too short');
+  }
+  const jsonLength = data.readUInt32LE(0);
+  if (4 + jsonLength > data.length) {
+    throw internalError('Binary payload is shorter than its JSON');
+  }
+  return {
+    json: JSON.parse(data.subarray(4, 4 + jsonLength).toString()),
+    binary: data.subarray(4 + jsonLength),
+  };
+}
 
-// Offsets from just after the length field.
-const REQ_ID_OFFSET = 0;
-const MSG_ID_OFFSET = 4;
-const MSG_TYPE_OFFSET = 8;
+/** The CRC32 (IEEE) checksum of `data`, the same one used by zlib. */
+export function crc32(data: Buffer) {
+  let crc = 0xffffffff;
+  for (let i = 0; i < data.length; i++) {
+    crc = CRC32_TABLE[(crc ^ data[i]) & 0xff] ^ (crc >>> 8);
+  }
+  return (crc ^ 0xffffffff) >>> 0;
+}
 
 /** A simple protocol in which each message has an ID, a type, and some data
  *
  *  Format
  *
- *  0: length
- *  4: request ID, links the message to a particular run
- *  8: message ID, unique per message within a request
- *  12: message type
+ *  0: length of the rest of the frame
+ *  4: magic marker
+ *  8: request ID, links the message to a particular run
+ *  12: message ID, unique per message within a request
+ *  16: message type, with the high bit set if the frame ends with a checksum, the next bit
+ *      set if the payload is gzip-compressed, and the one after that set if the payload is the
+ *      name of a file in the shared memory directory
  *  ... type-specific data follows
+ *  CRC32 of everything before it, if the checksum bit is set
+ *
+ *  Frames that don't start with the magic marker are skipped until the next marker, and frames
+ *  whose checksum doesn't match are dropped.
  * */
 export class Protocol extends EventEmitter<{ message: [IncomingMessage] }> {
-  socket: net.Socket;
+  socket: Transport;
   buffer: Buffer;
-  expectedLength: number | null;
-  id: number;
+  /** The ID of the next message for each request, for requests whose sequence hasn't ended.
+   * Message IDs count up from 0 within each request, so that the host can tell when a frame is
+   * lost or repeated. */
+  sequences = new Map<number, number>();
+  /** Add a checksum to each frame sent. The host asks for this in its handshake. */
+  checksums = false;
+  /** Bytes dropped while looking for the start of a frame, since the last complete frame. */
+  skippedBytes = 0;
+  /** The largest frame the host will read, from the handshake. */
+  maxFrameBytes: number | null = null;
+  /** How far to decompress a compressed frame from the host before dropping it. */
+  maxPayloadBytes = MAX_PAYLOAD_BYTES;
+  /** The largest response the host will accept, from the handshake. */
+  maxResponseBytes: number | null = null;
+  /** The directory to pass large payloads through instead of the socket, from the handshake. */
+  sharedMemoryDir: string | null = null;
+  /** Pass payloads larger than this many bytes through the shared memory directory, from the
+   * handshake. */
+  sharedMemoryOver: number | null = null;
+  nextSegmentId = 0;
+  /** Compress payloads larger than this many bytes, from the handshake. */
+  compressOver: number | null = null;
+  /** The host has a KV backend, from the handshake. */
+  kvEnabled = false;
+  /** The host has a module resolver, from the handshake. */
+  resolveModulesEnabled = false;
+  /** URL prefixes that scripts can import modules from, from the handshake. */
+  urlImports: string[] = [];
+  /** How long to cache modules fetched for URL imports, from the handshake. */
+  urlImportTtlMs = 0;
+  /** KV calls and module lookups waiting for the host to answer, by ID. */
+  hostCalls = new Map<number, { resolve: (value: any) => void; reject: (e: Error) => void }>();
+  nextHostCallId = 0;
 
   cache: Map<any, any> = new Map();
 
-  constructor(socket: net.Socket) {
+  constructor(socket: Transport) {
     super();
     this.socket = socket;
     this.buffer = Buffer.alloc(0);
-    this.expectedLength = null;
-    this.id = 0;
     this.socket.on('data', (data) => this.handleData(data));
+    this.socket.on('close', () => {
+      for (const call of this.hostCalls.values()) {
+        call.reject(new Error('Connection to the host closed'));
+      }
+      this.hostCalls.clear();
+    });
   }
 
   handleData(data: Buffer) {
     this.buffer = Buffer.concat([this.buffer, data]);
 
-    while (this.buffer.length > 0) {
-      if (this.expectedLength === null) {
-        if (this.buffer.length < 4) {
-          // Not enough data yet to read length
-          return;
-        }
-        this.expectedLength = this.buffer.readUInt32LE(0);
-        this.buffer = this.buffer.subarray(4);
+    while (this.buffer.length >= 8) {
+      const length = this.buffer.readUInt32LE(0);
+      if (this.buffer.readUInt32LE(MAGIC_OFFSET) !== FRAME_MAGIC || length < MSG_HEADER_LENGTH) {
+        this.resync();
+        continue;
       }
 
       // Not enough data for full message
-      if (this.buffer.length < this.expectedLength) {
+      if (this.buffer.length < length + 4) {
         return;
       }
 
-      const reqId = this.buffer.readUInt32LE(REQ_ID_OFFSET);
-      const id = this.buffer.readUInt32LE(MSG_ID_OFFSET);
-      const type = this.buffer.readUInt32LE(MSG_TYPE_OFFSET);
-      const data = this.buffer.subarray(12, this.expectedLength);
+      const frame = this.buffer.subarray(0, length + 4);
+      this.buffer = this.buffer.subarray(length + 4);
+
+      if (this.skippedBytes > 0) {
+        debug(`Skipped ${this.skippedBytes} bytes of corrupted data`);
+        this.skippedBytes = 0;
+      }
+
+      const reqId = frame.readUInt32LE(REQ_ID_OFFSET);
+      const id = frame.readUInt32LE(MSG_ID_OFFSET);
+      const typeField = frame.readUInt32LE(MSG_TYPE_OFFSET);
+      let data = frame.subarray(DATA_OFFSET);
+
+      if ((typeField & CHECKSUM_FLAG) !== 0) {
+        const end = frame.length - 4;
+        if (end < DATA_OFFSET || crc32(frame.subarray(0, end)) !== frame.readUInt32LE(end)) {
+          debug(`Dropping message ${reqId}:${id} with a bad checksum`);
+          // The request ID may be damaged too, but if it isn't then this saves the host from
+          // waiting for a response that will never come.
+          this.error(reqId, internalError('Message from host failed its checksum'));
+          continue;
+        }
+        data = frame.subarray(DATA_OFFSET, end);
+      }
 
-      // Remove the message from the pending buffer
-      this.buffer = this.buffer.subarray(this.expectedLength);
-      this.expectedLength = null;
+      if ((typeField & SHARED_MEMORY_FLAG) !== 0) {
+        try {
+          data = this.takeSegment(data.toString());
+        } catch (e) {
+          debug(`Dropping message ${reqId}:${id} whose shared memory couldn't be read`, e);
+          this.error(
+            reqId,
+            internalError('Message from host could not be read from shared memory')
+          );
+          continue;
+        }
+      } else if ((typeField & COMPRESSED_FLAG) !== 0) {
+        try {
+          // Stop where an uncompressed frame would have to, rather than inflating without bound.
+          data = gunzipSync(data, { maxOutputLength: this.maxPayloadBytes });
+        } catch (e) {
+          debug(`Dropping message ${reqId}:${id} that failed to decompress`, e);
+          const message =
+            (e as any)?.code === 'ERR_BUFFER_TOO_LARGE'
+              ? `Message from host is larger than ${this.maxPayloadBytes} bytes once decompressed`
+              : 'Message from host could not be decompressed';
+          this.error(reqId, internalError(message));
+          continue;
+        }
+      }
 
       const message = {
         id,
         reqId,
-        type,
+        type: typeField & ~FLAGS,
         data,
       };
 
@@ -84,36 +261,313 @@ export class Protocol extends EventEmitter<{ message: [IncomingMessage] }> {
     }
   }
 
-  sendMessage(reqId: number, type: WorkerToHostMessage, message: string | Buffer) {
+  /** Drop data from the front of the buffer up to the next frame marker, keeping any partial
+   * frame header at the end in case the rest of it hasn't arrived yet. */
+  resync() {
+    const marker = this.buffer.indexOf(FRAME_MAGIC_BYTES, MAGIC_OFFSET + 1);
+    const skip = marker === -1 ? Math.max(this.buffer.length - 7, 1) : marker - MAGIC_OFFSET;
+    this.skippedBytes += skip;
+    this.buffer = this.buffer.subarray(skip);
+  }
+
+  /** Send a message. `callback` is called once the message has been written out. */
+  sendMessage(
+    reqId: number,
+    type: WorkerToHostMessage,
+    message: string | Buffer,
+    callback?: () => void
+  ) {
     debug('Sending message', reqId, type, message);
     if (!(message instanceof Buffer)) {
       message = Buffer.from(message);
     }
 
-    let id = this.id++;
-    const header = Buffer.allocUnsafe(MSG_HEADER_LENGTH + 4);
-    header.writeUInt32LE(message.length + MSG_HEADER_LENGTH);
-    header.writeUInt32LE(reqId, REQ_ID_OFFSET + 4);
-    header.writeUInt32LE(id, MSG_ID_OFFSET + 4);
-    header.writeUInt32LE(type, MSG_TYPE_OFFSET + 4);
+    const checksumLength = this.checksums ? 4 : 0;
+    const length = message.length + MSG_HEADER_LENGTH + checksumLength;
+    if (
+      this.maxFrameBytes !== null &&
+      length > this.maxFrameBytes &&
+      type !== WorkerToHostMessage.MessageTooLarge
+    ) {
+      return this.tooLarge(reqId, length, this.maxFrameBytes, callback);
+    }
+
+    let compressed = false;
+    let shared = false;
+    if (this.sharedMemoryOver !== null && message.length > this.sharedMemoryOver) {
+      message = Buffer.from(this.writeSegment(message));
+      shared = true;
+    } else if (this.compressOver !== null && message.length > this.compressOver) {
+      const gzipped = gzipSync(message, { level: zlibConstants.Z_BEST_SPEED });
+      // Payloads that don't get smaller go out as they are.
+      if (gzipped.length < message.length) {
+        message = gzipped;
+        compressed = true;
+      }
+    }
+
+    let flags = 0;
+    if (this.checksums) {
+      flags |= CHECKSUM_FLAG;
+    }
+    if (compressed) {
+      flags |= COMPRESSED_FLAG;
+    }
+    if (shared) {
+      flags |= SHARED_MEMORY_FLAG;
+    }
+
+    const id = this.nextMessageId(reqId, type);
+    const header = Buffer.allocUnsafe(DATA_OFFSET);
+    header.writeUInt32LE(message.length + MSG_HEADER_LENGTH + checksumLength);
+    header.writeUInt32LE(FRAME_MAGIC, MAGIC_OFFSET);
+    header.writeUInt32LE(reqId, REQ_ID_OFFSET);
+    header.writeUInt32LE(id, MSG_ID_OFFSET);
+    header.writeUInt32LE((type | flags) >>> 0, MSG_TYPE_OFFSET);
+
+    const frame = [header, message];
+    if (this.checksums) {
+      const checksum = Buffer.allocUnsafe(4);
+      checksum.writeUInt32LE(crc32(Buffer.concat(frame)));
+      frame.push(checksum);
+    }
 
-    this.socket.write(Buffer.concat([header, message]));
+    this.socket.write(Buffer.concat(frame), callback);
     return id;
   }
 
-  log(reqId: number, level: string, message: string | object) {
-    let data = JSON.stringify({ level, message });
+  /** Take the ID for the next message of a request. */
+  nextMessageId(reqId: number, type: WorkerToHostMessage) {
+    if (UNSEQUENCED.has(type)) {
+      return 0;
+    }
+    const id = this.sequences.get(reqId) ?? 0;
+    this.advanceSequence(reqId, id, type);
+    return id;
+  }
+
+  advanceSequence(reqId: number, id: number, type: WorkerToHostMessage) {
+    if (ENDS_SEQUENCE.has(type)) {
+      this.sequences.delete(reqId);
+    } else if (!UNSEQUENCED.has(type)) {
+      this.sequences.set(reqId, id + 1);
+    }
+  }
+
+  /** Keep the sequences up to date with a frame that another Protocol sent over this one's
+   * socket, as a connection's worker thread does, so that messages sent from here follow on. */
+  forwarded(frame: Buffer) {
+    const reqId = frame.readUInt32LE(REQ_ID_OFFSET);
+    const id = frame.readUInt32LE(MSG_ID_OFFSET);
+    const type = frame.readUInt32LE(MSG_TYPE_OFFSET) & ~FLAGS;
+    this.advanceSequence(reqId, id, type);
+  }
+
+  /** Write a payload to a new file in the shared memory directory, returning its name. */
+  writeSegment(data: Buffer) {
+    const name = `worker-${process.pid}-${this.nextSegmentId++}`;
+    fs.writeFileSync(path.join(this.sharedMemoryDir!, name), data);
+    return name;
+  }
+
+  /** Read and remove a payload that the host wrote to the shared memory directory. */
+  takeSegment(name: string) {
+    if (!this.sharedMemoryDir) {
+      throw new Error('Frame uses shared memory, but none was set up');
+    }
+    if (!name || name.startsWith('.') || /[/\\]/.test(name)) {
+      throw new Error(`Invalid shared memory segment name ${JSON.stringify(name)}`);
+    }
+
+    const file = path.join(this.sharedMemoryDir, name);
+    try {
+      return fs.readFileSync(file);
+    } finally {
+      fs.rmSync(file, { force: true });
+    }
+  }
+
+  /** Send a console message, unless it is over the run's `budget`. */
+  log(
+    reqId: number,
+    level: LogLevel,
+    message: string | object,
+    namespace?: string,
+    budget?: LogBudget
+  ) {
+    let log: LogMessage = { level, message, timestamp: Date.now(), requestId: reqId, namespace };
+    let data = JSON.stringify(log);
+    if (budget && !budget.take(Buffer.byteLength(data))) {
+      return;
+    }
     this.sendMessage(reqId, WorkerToHostMessage.Log, data);
   }
 
   respond(reqId: number, data: RunResponse) {
-    this.sendMessage(reqId, WorkerToHostMessage.RunResponse, JSON.stringify(data));
+    const message = Buffer.from(JSON.stringify(data));
+    if (this.maxResponseBytes !== null && message.length > this.maxResponseBytes) {
+      this.tooLarge(reqId, message.length, this.maxResponseBytes);
+      return;
+    }
+
+    // Split responses that are too large for one frame into chunks, with the last chunk sent as
+    // the response itself.
+    const chunkSize =
+      this.maxFrameBytes === null
+        ? message.length
+        : Math.max(this.maxFrameBytes - MSG_HEADER_LENGTH - 4, 1);
+    let offset = 0;
+    while (message.length - offset > chunkSize) {
+      const chunk = message.subarray(offset, offset + chunkSize);
+      this.sendMessage(reqId, WorkerToHostMessage.RunResponseChunk, chunk);
+      offset += chunkSize;
+    }
+    this.sendMessage(reqId, WorkerToHostMessage.RunResponse, message.subarray(offset));
+  }
+
+  /** Send output from a run's `write` global, split into frames that fit the size limit.
+   * Resolves once the last frame has been written out. */
+  output(reqId: number, data: Buffer): Promise<void> {
+    if (!data.length) {
+      return Promise.resolve();
+    }
+
+    const chunkSize =
+      this.maxFrameBytes === null
+        ? data.length
+        : Math.max(this.maxFrameBytes - MSG_HEADER_LENGTH - 4, 1);
+    return new Promise((resolve) => {
+      for (let offset = 0; offset < data.length; offset += chunkSize) {
+        const chunk = data.subarray(offset, offset + chunkSize);
+        const last = offset + chunkSize >= data.length;
+        this.sendMessage(reqId, WorkerToHostMessage.Output, chunk, last ? resolve : undefined);
+      }
+    });
+  }
+
+  /** Send a value from a run's `channel` global, resolving once it has been written out. */
+  channelMessage(reqId: number, json: string): Promise<void> {
+    return new Promise((resolve) => {
+      this.sendMessage(reqId, WorkerToHostMessage.ChannelMessage, json, resolve);
+    });
+  }
+
+  /** Send a request to the host, and wait for the response with the same ID. */
+  callHost(reqId: number, type: WorkerToHostMessage, request: object): Promise<any> {
+    const id = this.nextHostCallId++;
+    return new Promise((resolve, reject) => {
+      this.hostCalls.set(id, { resolve, reject });
+      this.sendMessage(reqId, type, JSON.stringify({ id, ...request }));
+    });
   }
 
-  error(reqId: number, e: Error) {
-    let message = { message: e.message, stack: e.stack };
+  settleHostCall(id: number, value: any, error: string | undefined) {
+    const call = this.hostCalls.get(id);
+    if (!call) {
+      debug(`Received a response to unknown host call ${id}`);
+      return;
+    }
+
+    this.hostCalls.delete(id);
+    if (error !== undefined) {
+      call.reject(new Error(error));
+    } else {
+      call.resolve(value);
+    }
+  }
+
+  /** Send a KV call for the `kv` global to the host, and wait for the answer. */
+  kvRequest(reqId: number, request: KvRequest): Promise<any> {
+    if (!this.kvEnabled) {
+      return Promise.reject(new Error('The host has no KV backend'));
+    }
+    return this.callHost(reqId, WorkerToHostMessage.KvRequest, request);
+  }
+
+  /** Handle the host's answer to a KV call. */
+  kvResponse(data: Buffer) {
+    const { id, value, error } = JSON.parse(data.toString()) as KvResponse;
+    this.settleHostCall(id, value ?? null, error);
+  }
+
+  /** Ask the host's module resolver for the module that `specifier` refers to. Resolves to null
+   * if the host doesn't know it. */
+  resolveModule(reqId: number, specifier: string, referrer: string): Promise<CodeModule | null> {
+    if (!this.resolveModulesEnabled) {
+      return Promise.reject(new Error('The host has no module resolver'));
+    }
+    return this.callHost(reqId, WorkerToHostMessage.ResolveModule, { specifier, referrer });
+  }
+
+  /** Handle the host's answer to a module lookup. */
+  moduleResolved(data: Buffer) {
+    const { id, module, error } = JSON.parse(data.toString()) as ResolveModuleResponse;
+    this.settleHostCall(id, module ?? null, error);
+  }
+
+  /** Tell the host that a message was dropped for being over its size limit. */
+  tooLarge(reqId: number, length: number, limit: number, callback?: () => void) {
+    debug(`Dropping ${length} byte message for request ${reqId}, over the limit of ${limit}`);
+    const data: MessageTooLarge = { length, limit };
+    return this.sendMessage(
+      reqId,
+      WorkerToHostMessage.MessageTooLarge,
+      JSON.stringify(data),
+      callback
+    );
+  }
+
+  error(reqId: number, e: Error, annotations?: Annotations, kind: ErrorKind = errorKind(e)) {
+    const described = describeError(e, kind);
+    let message: ErrorResponse = {
+      ...described,
+      stack: annotateStack(described.stack, annotations),
+      annotations,
+      timedOut: kind === 'timeout' || undefined,
+    };
 
     let data = JSON.stringify(message);
     this.sendMessage(reqId, WorkerToHostMessage.Error, data);
   }
+
+  /** Report an error that escaped from a context's async code, such as a timer callback. */
+  asyncError(reqId: number, e: unknown, source: AsyncErrorSource, annotations?: Annotations) {
+    const described = describeError(e);
+    const message: AsyncError = {
+      source,
+      error: { ...described, stack: annotateStack(described.stack, annotations), annotations },
+    };
+    this.sendMessage(reqId, WorkerToHostMessage.AsyncError, JSON.stringify(message));
+  }
+
+  /** Answer the host's handshake, and close the connection if it speaks a different protocol
+   * version. Returns true if the versions match. `respond` is false when a new worker thread takes
+   * over a connection that already finished its handshake. */
+  handshake(reqId: number, data: Buffer, respond = true) {
+    const handshake = JSON.parse(data.toString()) as Handshake;
+    const { version, checksums, maxFrameBytes, maxResponseBytes, kv, resolveModules } = handshake;
+    const accepted = version === PROTOCOL_VERSION;
+    if (respond) {
+      const response: HandshakeResponse = { version: PROTOCOL_VERSION, accepted, pid: process.pid };
+      this.sendMessage(reqId, WorkerToHostMessage.Handshake, JSON.stringify(response));
+    }
+    // The response goes out without a checksum or compression, since the host can read frames
+    // either way.
+    this.checksums = accepted && Boolean(checksums);
+    this.compressOver = accepted ? (handshake.compressFramesOver ?? null) : null;
+    this.sharedMemoryDir = accepted ? (handshake.sharedMemoryDir ?? null) : null;
+    this.sharedMemoryOver = this.sharedMemoryDir ? (handshake.sharedMemoryOver ?? null) : null;
+    this.maxFrameBytes = maxFrameBytes ?? null;
+    this.maxResponseBytes = maxResponseBytes ?? null;
+    this.kvEnabled = accepted && Boolean(kv);
+    this.resolveModulesEnabled = accepted && Boolean(resolveModules);
+    this.urlImports = accepted ? (handshake.urlImports ?? []) : [];
+    this.urlImportTtlMs = handshake.urlImportTtlMs ?? 0;
+
+    if (!accepted && respond) {
+      debug(`Rejecting host with protocol version ${version}, expected ${PROTOCOL_VERSION}`);
+      this.socket.end();
+    }
+    return accepted;
+  }
 }
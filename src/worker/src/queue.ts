@@ -0,0 +1,55 @@
+/** Values that arrive from the host for a run, waiting for the script to read them. Reading waits
+ * for the next value, and stops with the signal's reason if the run is cancelled or times out.
+ * Values are only kept until they are read, so each one is read once. */
+export class AsyncQueue<T> {
+  values: T[] = [];
+  ended = false;
+  signal: AbortSignal | undefined;
+  /** Wakes up the reader waiting for the next value. */
+  wake: (() => void) | null = null;
+
+  constructor(signal?: AbortSignal) {
+    this.signal = signal;
+    signal?.addEventListener('abort', () => this.notify(), { once: true });
+  }
+
+  push(value: T) {
+    if (!this.ended) {
+      this.values.push(value);
+      this.notify();
+    }
+  }
+
+  end() {
+    this.ended = true;
+    this.notify();
+  }
+
+  notify() {
+    this.wake?.();
+    this.wake = null;
+  }
+
+  /** The next value, or undefined once the queue has ended and every value has been read. */
+  async next(): Promise<T | undefined> {
+    for (;;) {
+      this.signal?.throwIfAborted();
+      if (this.values.length) {
+        return this.values.shift();
+      } else if (this.ended) {
+        return undefined;
+      }
+      await new Promise<void>((resolve) => (this.wake = resolve));
+    }
+  }
+
+  async *[Symbol.asyncIterator](): AsyncGenerator<T> {
+    for (;;) {
+      const value = await this.next();
+      if (value === undefined) {
+        return;
+      }
+      yield value;
+    }
+  }
+}
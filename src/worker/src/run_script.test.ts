@@ -1,7 +1,8 @@
 import { describe, it, expect } from 'vitest';
+import { createHash } from 'node:crypto';
 import type { MessageContext } from './types.js';
 import { runScript } from './run_script';
-import type { RunScriptArgs } from './api_types.js';
+import { WorkerToHostMessage, type RunScriptArgs } from './api_types.js';
 
 describe('runScript', () => {
   const createMessageContext = (): MessageContext => ({
@@ -13,6 +14,8 @@ describe('runScript', () => {
     log: () => {},
     respond: () => {},
     error: () => {},
+    output: async () => {},
+    asyncError: () => {},
   });
 
   it('should run a simple expression and return its value', async () => {
@@ -269,6 +272,47 @@ describe('runScript', () => {
     expect(result2.globals?.output).toBe(20);
   });
 
+  it('evaluates a new instance of modules in each run', async () => {
+    const ctx = createMessageContext();
+    const counter = { name: 'counter', code: 'let n = 0; export const next = () => ++n;' };
+    const args: RunScriptArgs = {
+      name: 'run',
+      code: `import { next } from 'counter'; export default next();`,
+      modules: [counter],
+    };
+
+    expect((await runScript(args, ctx)).returnValue).toBe(1);
+    expect((await runScript(args, ctx)).returnValue).toBe(1);
+
+    const hash = createHash('sha256').update(counter.code).digest('hex');
+    const cached = { ...args, modules: [], cachedModules: [{ name: 'counter', hash }] };
+    expect((await runScript(cached, ctx)).returnValue).toBe(1);
+
+    const changed = { ...args, modules: [{ ...counter, code: counter.code.replace('0', '5') }] };
+    expect((await runScript(changed, ctx)).returnValue).toBe(6);
+    await expect(runScript(cached, ctx)).rejects.toThrow(
+      "Module counter was sent without its code, but the context doesn't have it"
+    );
+  });
+
+  it('keeps the code of registered scripts for later runs', async () => {
+    const ctx = createMessageContext();
+    const code = 'export default input * 2;';
+    const registeredScript = {
+      name: 'double@1',
+      hash: createHash('sha256').update(code).digest('hex'),
+    };
+    const args: RunScriptArgs = { name: 'double@1', code, registeredScript, globals: { input: 2 } };
+
+    expect((await runScript(args, ctx)).returnValue).toBe(4);
+    const withoutCode = { ...args, code: '', globals: { input: 5 } };
+    expect((await runScript(withoutCode, ctx)).returnValue).toBe(10);
+
+    await expect(runScript(withoutCode, createMessageContext())).rejects.toThrow(
+      "Script double@1 was sent without its code, but the connection doesn't have it"
+    );
+  });
+
   it('allows two runs with the same name', async () => {
     const ctx = createMessageContext();
     const args: RunScriptArgs = {
@@ -317,4 +361,320 @@ describe('runScript', () => {
     const result2 = await runScript(args2, ctx);
     expect(result2.globals?.output).toBe(40);
   });
+
+  it('filters console messages by namespace', async () => {
+    const logs: any[] = [];
+    const ctx = {
+      ...createMessageContext(),
+      log: (message: any, level?: string, namespace?: string) =>
+        logs.push({ message, level, namespace }),
+    };
+
+    const args: RunScriptArgs = {
+      name: 'test-log-namespaces',
+      code: `
+        console.log('[db] query');
+        console.log('[http] request');
+        logger('db').warn('from logger');
+        logger('cache').info('skipped');
+        console.log('no namespace');
+      `,
+      logNamespaces: ['db'],
+    };
+
+    await runScript(args, ctx);
+    expect(logs).toEqual([
+      { message: ['[db] query'], level: 'info', namespace: 'db' },
+      { message: ['from logger'], level: 'warn', namespace: 'db' },
+      { message: ['no namespace'], level: 'info', namespace: undefined },
+    ]);
+  });
+
+  it('captures each console level', async () => {
+    const levels: any[] = [];
+    const ctx = {
+      ...createMessageContext(),
+      log: (_message: any, level?: string) => levels.push(level),
+    };
+
+    const args: RunScriptArgs = {
+      name: 'test-log-levels',
+      code: `
+        console.trace('a');
+        console.debug('b');
+        console.log('c');
+        console.info('d');
+        console.warn('e');
+        console.error('f');
+      `,
+    };
+
+    await runScript(args, ctx);
+    expect(levels).toEqual(['trace', 'debug', 'info', 'info', 'warn', 'error']);
+  });
+
+  it('sends stderr writes as errors, and skips messages below the minimum level', async () => {
+    const logs: any[] = [];
+    const ctx = {
+      ...createMessageContext(),
+      log: (message: any, level?: string) => logs.push([level, ...message]),
+    };
+
+    const args: RunScriptArgs = {
+      name: 'test-min-log-level',
+      minLogLevel: 'warn',
+      code: `
+        console.info('dropped');
+        console.warn('kept');
+        stderr.write('written\\n');
+      `,
+    };
+
+    await runScript(args, ctx);
+    expect(logs).toEqual([
+      ['warn', 'kept'],
+      ['error', 'written'],
+    ]);
+  });
+
+  it('passes console calls to the console handler', async () => {
+    const logs: any[] = [];
+    const ctx = {
+      ...createMessageContext(),
+      log: (message: any, level?: string) => logs.push([level, ...message]),
+    };
+
+    const args: RunScriptArgs = {
+      name: 'test-console-handler',
+      consoleHandler: 'sampled',
+      functions: [
+        {
+          name: 'sampled',
+          params: ['entry', 'forward'],
+          code: `
+            if (entry.level === 'debug') return;
+            console.log('handled');
+            forward(entry.level, [...entry.args, { source: 'script' }]);
+          `,
+        },
+      ],
+      code: `
+        console.debug('dropped');
+        console.warn('kept');
+      `,
+    };
+
+    await runScript(args, ctx);
+    expect(logs).toEqual([
+      ['info', 'handled'],
+      ['warn', 'kept', { source: 'script' }],
+    ]);
+  });
+
+  it('calls a function with arguments', async () => {
+    const result = await runScript(
+      {
+        name: 'test-call',
+        code: 'async (a, b) => a + b',
+        call: true,
+        args: [2, 3],
+        globals: { unrelated: 1 },
+      },
+      createMessageContext()
+    );
+
+    expect(result.returnValue).toBe(5);
+    expect(result.globals).toEqual({});
+  });
+
+  it('calls a function declaration or a named function', async () => {
+    const ctx = createMessageContext();
+    const declared = await runScript(
+      { name: 'test-call-decl', code: 'function double(x) { return x * 2; }', call: true, args: [4] },
+      ctx
+    );
+    expect(declared.returnValue).toBe(8);
+
+    const named = await runScript(
+      {
+        name: 'test-call-named',
+        code: 'triple',
+        call: true,
+        args: [4],
+        functions: [{ name: 'triple', params: ['x'], code: 'return x * 3;' }],
+      },
+      ctx
+    );
+    expect(named.returnValue).toBe(12);
+  });
+
+  it('returns the default export of a script', async () => {
+    const result = await runScript(
+      {
+        name: 'test-default-export',
+        code: `export default (async () => {
+          await new Promise((resolve) => setTimeout(resolve, 1));
+          return 'done';
+        })();`,
+        globals: { setTimeout },
+      },
+      createMessageContext()
+    );
+
+    expect(result.returnValue).toBe('done');
+  });
+
+  it('awaits promises in globals with awaitResult', async () => {
+    const result = await runScript(
+      {
+        name: 'test-await-result',
+        code: `globalThis.output = Promise.resolve(5);`,
+        returnKeys: ['output'],
+        awaitResult: true,
+      },
+      createMessageContext()
+    );
+
+    expect(result.globals).toEqual({ output: 5 });
+  });
+
+  it('rejects when an awaited global rejects', async () => {
+    await expect(
+      runScript(
+        {
+          name: 'test-await-reject',
+          code: `globalThis.output = Promise.reject(new Error('failed'));`,
+          returnKeys: ['output'],
+          awaitResult: true,
+        },
+        createMessageContext()
+      )
+    ).rejects.toThrow('failed');
+  });
+
+  it('rejects call mode code that is not a function', async () => {
+    await expect(
+      runScript({ name: 'test-call-bad', code: '5', call: true }, createMessageContext())
+    ).rejects.toThrow('not a function');
+  });
+
+  it('attributes logs to the right request when runs overlap', async () => {
+    const protocol = { cache: new Map() } as any;
+    const logs: [number, any][] = [];
+    const context = (reqId: number): MessageContext => ({
+      ...createMessageContext(),
+      protocol,
+      reqId,
+      log: (message) => logs.push([reqId, message]),
+    });
+
+    await Promise.all([
+      runScript({ name: 'first', code: `await null; console.log('first');` }, context(1)),
+      runScript({ name: 'second', code: `console.log('second');` }, context(2)),
+    ]);
+
+    expect(logs.sort()).toEqual([
+      [1, ['first']],
+      [2, ['second']],
+    ]);
+  });
+
+  it('reports run stats', async () => {
+    const result = await runScript(
+      { name: 'test-stats', code: 'globalThis.data = new Array(100000).fill(1)', returnKeys: [] },
+      createMessageContext()
+    );
+
+    expect(result.stats?.compileMs).toBeGreaterThanOrEqual(0);
+    expect(result.stats?.executeMs).toBeGreaterThan(0);
+    expect(result.stats?.heapUsedDelta).toBeGreaterThan(100000);
+    expect(result.stats?.macrotasksDrained).toBe(true);
+  });
+
+  it('reports macrotasks left pending', async () => {
+    const result = await runScript(
+      {
+        name: 'test-pending',
+        code: 'setTimeout(() => {}, 20)',
+        expr: true,
+        globals: { setTimeout },
+      },
+      createMessageContext()
+    );
+
+    expect(result.stats?.macrotasksDrained).toBe(false);
+  });
+
+  it('streams output from the write global', async () => {
+    const chunks: string[] = [];
+    const ctx = createMessageContext();
+    ctx.output = async (data) => {
+      chunks.push(data.toString());
+    };
+
+    await runScript(
+      {
+        name: 'test-output',
+        code: `
+          await write('a');
+          write(new Uint8Array([98]));
+          write(new Uint8Array([99]).buffer);
+        `,
+        streamOutput: true,
+      },
+      ctx
+    );
+    expect(chunks).toEqual(['a', 'b', 'c']);
+
+    await expect(
+      runScript({ name: 'test-output', code: "write('a')" }, createMessageContext())
+    ).rejects.toThrow('not enabled');
+    await expect(
+      runScript({ name: 'test-output', code: 'write(5)', streamOutput: true }, ctx)
+    ).rejects.toThrow('takes a string');
+  });
+
+  it('reads streamed input from the input global', async () => {
+    const ctx = createMessageContext();
+    const result = runScript(
+      {
+        name: 'test-input',
+        code: `
+          globalThis.lines = [];
+          for await (const text of input.text()) lines.push(text);
+        `,
+        streamInput: true,
+        returnKeys: ['lines'],
+      },
+      ctx
+    );
+    ctx.input!.push(Buffer.from('a'));
+    ctx.input!.push(Buffer.from('b'));
+    ctx.input!.end();
+    expect((await result).globals).toEqual({ lines: ['a', 'b'] });
+  });
+
+  it('sends a CPU profile before responding', async () => {
+    const sent: { type: number; data: string }[] = [];
+    const ctx = createMessageContext();
+    ctx.protocol.sendMessage = ((_reqId: number, type: number, data: string) =>
+      sent.push({ type, data })) as any;
+
+    const result = await runScript(
+      {
+        name: 'test-profile',
+        code: 'let x = 0; for (let i = 0; i < 100000; i++) { x += i; } x',
+        expr: true,
+        profile: true,
+      },
+      ctx
+    );
+
+    expect(result.returnValue).toBe(4999950000);
+    expect(sent.length).toBe(1);
+    expect(sent[0].type).toBe(WorkerToHostMessage.CpuProfile);
+    const profile = JSON.parse(sent[0].data);
+    expect(profile.nodes.length).toBeGreaterThan(0);
+    expect(profile.endTime).toBeGreaterThan(profile.startTime);
+  });
 });
@@ -1,56 +1,467 @@
 import * as vm from 'vm';
+import { AsyncLocalStorage } from 'node:async_hooks';
+import { createHash } from 'node:crypto';
+import { inspect, types } from 'node:util';
 import type { MessageContext } from './types.js';
-import type { RunResponse, RunScriptArgs } from './api_types.js';
+import type { Protocol } from './protocol.js';
+import {
+  WorkerToHostMessage,
+  type AdvanceTime,
+  type AdvanceTimeResult,
+  type AsyncErrorSource,
+  type CodeModule,
+  type Complete,
+  type Completions,
+  type FunctionDef,
+  type Introspect,
+  type PropertyHint,
+  type LogLevel,
+  type PrewarmContext,
+  type RegisteredScript,
+  type RunResponse,
+  type RunScriptArgs,
+  type RunStats,
+  type TimerMode,
+} from './api_types.js';
+import { getHeapStatistics } from 'node:v8';
 import { debug } from './debug.js';
 import { LRUCache } from 'lru-cache';
+import { NamespaceFilter, extractNamespace } from './log_filter.js';
+import { validateStrings, type StringLimits } from './validate.js';
+import { contextRealm, decodeObject, decodeValue, encodeValue, type Realm } from './structured.js';
+import {
+  debugRunFinished,
+  startProfiling,
+  waitForDebugger,
+  withBreakpoint,
+} from './inspector.js';
+import { parseSelector, selectPaths } from './select.js';
+import { serializableGlobals } from './serializable.js';
+import { createKv } from './kv.js';
+import { ContextTimers, virtualDate } from './timers.js';
+import { removeNondeterministicGlobals, seededRandom } from './deterministic.js';
+import { LogBudget } from './log_budget.js';
+import { Redactor } from './secrets.js';
+import { abortAfter, isTimeout, untilAborted } from './abort.js';
+import { instantiateWasm } from './wasm.js';
+import { findCycle, joinSpecifier, moduleName, resolveSpecifier } from './module_graph.js';
+import { importUrl, isAllowed, urlModules } from './url_imports.js';
+import { internalError, moduleError } from './errors.js';
+import { ContextPool } from './context_pool.js';
+import { InputStream } from './input.js';
+import { ScriptChannel } from './channel.js';
+import { DryRun } from './dry_run.js';
+import { completeLine, declaredNames, introspect } from './complete.js';
 
 const codeCache = new LRUCache<string, Buffer>({
   max: 128,
 });
 
-function codeCacheKey(esm: boolean, code: string, params?: string[]) {
-  const startKey = esm ? 'esm' : 'cjs';
-  return [startKey, code, ...(params || [])].join('\0');
+function contentHash(code: string) {
+  return createHash('sha256').update(code).digest('hex');
+}
+
+/** How a piece of code is compiled. Call mode wraps the code before compiling it, so the same code
+ * compiles differently in each mode. */
+type CompileMode = 'module' | 'script' | 'call' | 'function';
+
+/** The key for compiled code in the cache. Keys are hashed, so that the cache doesn't hold on to
+ * a second copy of large code. */
+function codeCacheKey(mode: CompileMode, code: string, params?: string[]) {
+  return contentHash([mode, code, ...(params || [])].join('\0'));
 }
 
 const RUN_CTX_KEY = Symbol('runCtx');
+/** The code of the registered scripts sent on the connection, by hash. Unlike the context, these
+ * are kept for as long as the connection is open. */
+const REGISTERED_SCRIPTS_KEY = Symbol('registeredScripts');
+
+/** Call mode places a function that makes the call under this key for a moment, so that the call
+ * runs inside a script and the run's timeout can interrupt it. */
+const CALL_KEY = Symbol.for('js_sidecar.call');
+const callScript = new vm.Script('globalThis[Symbol.for("js_sidecar.call")]()');
+
+/** How long the `wait` drain policy waits for timers, unless the run says otherwise. */
+const DEFAULT_MAX_DRAIN_MS = 1000;
+
+/** The request that started the code that is currently running. Several runs can be in flight on
+ * the same context, so this follows each run through its async operations. */
+const currentRequest = new AsyncLocalStorage<MessageContext>();
 
 interface RunContext {
   modules: Record<string, vm.Module>;
+  /** Each module sent by the host, with the hash of its code, by name. */
+  moduleSources: Map<string, { module: CodeModule; hash: string }>;
+  /** The names of modules that the host's resolver returned, by the specifier they were imported
+   * with, joined to the importing module's name. */
+  hostModules: Map<string, string>;
+  /** Lookups from the host's resolver in progress, so that concurrent imports of the same
+   * specifier only ask once. */
+  pendingHostModules: Map<string, Promise<string | undefined>>;
   context: vm.Context;
+  /** The context's own constructors, which values from the host are decoded with. */
+  realm: Realm;
+  /** The most recent request to use this context. Console output from code that isn't part of
+   * any request, such as a timer set by an earlier run, is attributed to it. */
+  current: MessageContext;
+  /** Filter for namespaced console messages, set per run. */
+  logFilter: NamespaceFilter | null;
+  timers: ContextTimers;
+  /** Set in deterministic mode, to reseed `Math.random` at the start of each run. */
+  seedRandom: ((seed: number) => void) | null;
+  /** Names that expressions and REPL entries may have declared with `let`, `const`, or `class`.
+   * The context doesn't list these anywhere, so completions need them from here. */
+  lexicalNames: Set<string>;
+}
+
+/** The contexts, by the `Object.prototype` of their realm. Errors and promises created by a
+ * context's code inherit from it, which ties them back to the context. */
+const realms = new WeakMap<object, RunContext>();
+
+/** The context that created an object, if any. */
+function realmOf(value: unknown): RunContext | undefined {
+  if ((typeof value !== 'object' && typeof value !== 'function') || value === null) {
+    return undefined;
+  }
+
+  let proto = Object.getPrototypeOf(value);
+  while (proto !== null && Object.getPrototypeOf(proto) !== null) {
+    proto = Object.getPrototypeOf(proto);
+  }
+  return proto ? realms.get(proto) : undefined;
+}
+
+/** Send an error that escaped from a context's code to the request that is using the context, or
+ * that last used it. The context is found from the realm of the rejected promise or the error.
+ * Returns false if the error didn't come from any context. */
+export function reportAsyncError(e: unknown, source: AsyncErrorSource, promise?: Promise<unknown>) {
+  const run = realmOf(promise) ?? realmOf(e);
+  if (!run) {
+    return false;
+  }
+
+  (currentRequest.getStore() ?? run.current).asyncError(e, source);
+  return true;
+}
+
+/** The console levels, from least to most severe. */
+const LOG_LEVELS: string[] = ['trace', 'debug', 'info', 'warn', 'error'];
+
+/** Returns true if the request only forwards messages more severe than `level`. */
+function belowMinLevel(request: MessageContext, level: LogLevel) {
+  return (
+    request.minLogLevel !== undefined &&
+    LOG_LEVELS.indexOf(level) < LOG_LEVELS.indexOf(request.minLogLevel)
+  );
+}
+
+function forwardLog(run: RunContext, args: any[], level: LogLevel, namespace?: string) {
+  namespace ??= extractNamespace(args);
+  if (namespace && run.logFilter && !run.logFilter.isEnabled(namespace)) {
+    return;
+  }
+
+  const request = currentRequest.getStore() ?? run.current;
+  if (belowMinLevel(request, level)) {
+    return;
+  }
+  if (request.consoleHandler && !request.inConsoleHandler) {
+    callConsoleHandler(run, request, { level, args, namespace });
+    return;
+  }
+  request.log(args, level, namespace);
+}
+
+/** Pass a console call to the function that the run named as its console handler, along with a
+ * function that forwards a message to the host. Console calls from the handler itself are
+ * forwarded as they are. */
+function callConsoleHandler(
+  run: RunContext,
+  request: MessageContext,
+  entry: { level: LogLevel; args: any[]; namespace?: string }
+) {
+  const name = request.consoleHandler!;
+  const handler = run.context[name];
+  if (typeof handler !== 'function') {
+    throw new TypeError(`Console handler ${name} is not a function`);
+  }
+
+  const forward = (level: LogLevel, args: unknown, namespace?: string) => {
+    if (!LOG_LEVELS.includes(level)) {
+      throw new TypeError(`Unknown log level ${level}`);
+    }
+    if (belowMinLevel(request, level)) {
+      return;
+    }
+    request.log(Array.isArray(args) ? args : [args], level, namespace ?? undefined);
+  };
+  request.inConsoleHandler = true;
+  try {
+    handler(entry, forward);
+  } finally {
+    request.inConsoleHandler = false;
+  }
+}
+
+function createConsole(run: RunContext, namespace?: string) {
+  return {
+    trace: (...args: any[]) => forwardLog(run, args, 'trace', namespace),
+    debug: (...args: any[]) => forwardLog(run, args, 'debug', namespace),
+    log: (...args: any[]) => forwardLog(run, args, 'info', namespace),
+    info: (...args: any[]) => forwardLog(run, args, 'info', namespace),
+    warn: (...args: any[]) => forwardLog(run, args, 'warn', namespace),
+    error: (...args: any[]) => forwardLog(run, args, 'error', namespace),
+  };
+}
+
+/** Define a global that reads a property of the current run. Like `kv`, it isn't enumerable, but a
+ * global of the same name from the host or the script replaces it. */
+function defineRunGlobal(context: vm.Context, name: string, get: () => unknown) {
+  if (Object.hasOwn(context, name)) {
+    return;
+  }
+
+  Object.defineProperty(context, name, {
+    get,
+    set: (value) => {
+      Object.defineProperty(context, name, {
+        value,
+        writable: true,
+        enumerable: true,
+        configurable: true,
+      });
+    },
+    configurable: true,
+  });
+}
+
+/** Returns true if the connection has a run context from an earlier run. */
+export function hasContext(protocol: Protocol) {
+  return protocol.cache.has(RUN_CTX_KEY);
+}
+
+/** Globals that a context defines for itself, which take precedence over the run's globals. */
+const CONTEXT_GLOBALS = new Set([
+  'console',
+  'logger',
+  'kv',
+  'write',
+  'stderr',
+  'setTimeout',
+  'setInterval',
+  'clearTimeout',
+  'clearInterval',
+]);
+
+/** Fresh contexts with the default settings, so that a run that recreates its context doesn't
+ * wait for one to be built. Set up by `startContextPool`. */
+let contextPool: ContextPool<RunContext> | null = null;
+
+/** Keep `size` fresh contexts ready for runs that create a context with real timers and without
+ * deterministic mode. */
+export function startContextPool(size: number) {
+  contextPool = size > 0 ? new ContextPool(size, () => buildContext('real', false)) : null;
+}
+
+/** Contexts built from a prewarm manifest, by name, for runs with `prewarmedContext` set. */
+const prewarmedContexts = new Map<string, ContextPool<RunContext>>();
+
+/** Build the context described by `args`, throwing if its functions or modules fail to compile,
+ * and keep one ready for runs that ask for it by name. The pool builds a replacement after each
+ * one is taken. Prewarming a name again replaces its contexts. */
+export function prewarmContext(args: PrewarmContext) {
+  const build = () => buildPrewarmedContext(args);
+  const pool = new ContextPool(1, build, [build()]);
+
+  const previous = prewarmedContexts.get(args.name);
+  for (const runCtx of previous?.ready ?? []) {
+    runCtx.timers.dispose();
+  }
+  prewarmedContexts.set(args.name, pool);
+}
+
+function buildPrewarmedContext(args: PrewarmContext): RunContext {
+  const runCtx = buildContext('real', false, args.globals);
+  compileFunctions(runCtx, args.functions ?? []);
+  for (const modArgs of args.modules ?? []) {
+    addModule(runCtx, modArgs);
+  }
+  return runCtx;
+}
+
+/** Take a ready context for a run that creates one, from the prewarmed contexts if the run asks
+ * for them, or else from the pool of fresh contexts. Ready contexts all use real timers, so
+ * they aren't used for deterministic runs or other timer modes. */
+function takeReadyContext(args: RunScriptArgs, timerMode: TimerMode): RunContext | undefined {
+  if (timerMode !== 'real' || args.deterministic) {
+    return undefined;
+  }
+
+  const prewarmed = args.prewarmedContext
+    ? prewarmedContexts.get(args.prewarmedContext)?.take()
+    : undefined;
+  return prewarmed ?? contextPool?.take();
+}
+
+/** Build a context with the given timer mode, in deterministic mode if `deterministic` is set,
+ * holding `globals`. */
+function buildContext(
+  timerMode: TimerMode,
+  deterministic: boolean,
+  globals: Record<string, unknown> = {}
+): RunContext {
+  const context = vm.createContext({ ...globals });
+  const newCtx: RunContext = {
+    modules: {},
+    moduleSources: new Map(),
+    hostModules: new Map(),
+    pendingHostModules: new Map(),
+    context,
+    realm: contextRealm(context),
+    // Set when a run takes the context.
+    current: undefined as unknown as MessageContext,
+    logFilter: null,
+    timers: new ContextTimers(
+      timerMode,
+      (e) => (currentRequest.getStore() ?? newCtx.current).asyncError(e, 'uncaughtException'),
+      deterministic ? 0 : Date.now(),
+      () => currentRequest.getStore() ?? newCtx.current
+    ),
+    seedRandom: null,
+    lexicalNames: new Set(),
+  };
+  realms.set(vm.runInContext('Object.prototype', newCtx.context), newCtx);
+
+  newCtx.context.console = createConsole(newCtx);
+  newCtx.context.logger = (namespace: string) => createConsole(newCtx, namespace);
+  // The signal of whichever run is using the context, for code to pass to cancellable APIs or
+  // to listen to for cleanup, and its input and channel.
+  const request = () => currentRequest.getStore() ?? newCtx.current;
+  defineRunGlobal(newCtx.context, 'signal', () => request().abort?.signal);
+  defineRunGlobal(newCtx.context, 'input', () => request().input);
+  defineRunGlobal(newCtx.context, 'channel', () => {
+    const ctx = request();
+    return (ctx.channel ??= new ScriptChannel(ctx));
+  });
+  // Not enumerable, like the built-in globals, so that they aren't sent back.
+  const { timers } = newCtx;
+  const globalFunctions = {
+    ...timers.globals(),
+    write: (chunk: unknown) => writeOutput(currentRequest.getStore() ?? newCtx.current, chunk),
+    stderr: { write: (chunk: unknown) => writeStderr(newCtx, chunk) },
+  };
+  for (const [name, fn] of Object.entries(globalFunctions)) {
+    Object.defineProperty(newCtx.context, name, {
+      value: fn,
+      writable: true,
+      configurable: true,
+    });
+  }
+  if (deterministic) {
+    removeNondeterministicGlobals(newCtx.context);
+    const ContextMath = vm.runInContext('Math', newCtx.context);
+    newCtx.seedRandom = (seed) => {
+      ContextMath.random = seededRandom(seed);
+    };
+  }
+  if (timers.mode === 'virtual' || deterministic) {
+    const ContextDate = vm.runInContext('Date', newCtx.context);
+    Object.defineProperty(newCtx.context, 'Date', {
+      value: virtualDate(ContextDate, () => timers.now),
+      writable: true,
+      configurable: true,
+    });
+  }
+  return newCtx;
+}
+
+/** Set the run's globals in the context, decoding them with the context's constructors if they
+ * use the extended encoding. Names in `skip` are left alone. */
+function setGlobals(runCtx: RunContext, args: RunScriptArgs, skip?: Set<string>) {
+  const globals =
+    args.extendedValues && args.globals ? decodeObject(args.globals, runCtx.realm) : args.globals;
+  for (const [key, value] of Object.entries(globals ?? {})) {
+    if (!skip?.has(key)) {
+      runCtx.context[key] = value;
+    }
+  }
 }
 
 function createContext(ctx: MessageContext, args: RunScriptArgs): RunContext {
-  let runCtx: RunContext = args.recreateContext ? undefined : ctx.protocol.cache.get(RUN_CTX_KEY);
+  let runCtx: RunContext = ctx.protocol.cache.get(RUN_CTX_KEY);
+  if (runCtx && args.recreateContext) {
+    runCtx.timers.dispose();
+    runCtx = undefined;
+  }
 
   if (!runCtx) {
-    const scriptConsole = {
-      log: (...args: any[]) => ctx.log(args, 'info'),
-      info: (...args: any[]) => ctx.log(args, 'info'),
-      warn: (...args: any[]) => ctx.log(args, 'warn'),
-      error: (...args: any[]) => ctx.log(args, 'error'),
-    };
+    // Deterministic contexts only allow virtual timers, whose clock starts at the epoch.
+    let timerMode = args.timers ?? 'real';
+    if (args.deterministic && timerMode === 'real') {
+      timerMode = 'disabled';
+    }
 
-    const jsCtx = vm.createContext({
-      ...args.globals,
-      console: scriptConsole,
-    });
+    const pooled = takeReadyContext(args, timerMode);
+    if (pooled) {
+      runCtx = pooled;
+      setGlobals(runCtx, args, CONTEXT_GLOBALS);
+    } else if (args.extendedValues) {
+      // Extended values are decoded with the context's constructors, so it has to exist first.
+      runCtx = buildContext(timerMode, !!args.deterministic);
+      setGlobals(runCtx, args, CONTEXT_GLOBALS);
+    } else {
+      runCtx = buildContext(timerMode, !!args.deterministic, args.globals);
+    }
 
-    runCtx = {
-      modules: {},
-      context: jsCtx,
-    };
+    if (ctx.protocol.kvEnabled) {
+      // Not enumerable, so that it isn't sent back with the other globals.
+      const run = runCtx;
+      Object.defineProperty(run.context, 'kv', {
+        value: createKv(() => currentRequest.getStore() ?? run.current),
+      });
+    }
 
     // Save the context for reuse later.
     ctx.protocol.cache.set(RUN_CTX_KEY, runCtx);
-  } else if (args.globals) {
-    for (const [key, value] of Object.entries(args.globals)) {
-      runCtx.context[key] = value;
+  } else {
+    setGlobals(runCtx, args);
+  }
+
+  runCtx.current = ctx;
+  runCtx.timers.startRun(args.maxTimers);
+  runCtx.seedRandom?.(args.randomSeed ?? 0);
+  runCtx.logFilter = args.logNamespaces?.length ? new NamespaceFilter(args.logNamespaces) : null;
+
+  compileFunctions(runCtx, args.functions ?? []);
+
+  // Each run gets new instances of the modules that it passes, which are linked and evaluated
+  // afresh, so no state carries over from an earlier run's instance. Only the compiled code is
+  // shared, through the code cache.
+  for (const { name, hash } of args.cachedModules ?? []) {
+    const source = runCtx.moduleSources.get(moduleName(name));
+    if (source?.hash !== hash) {
+      throw moduleError(
+        `Module ${name} was sent without its code, but the context doesn't have it`
+      );
     }
+    runCtx.modules[moduleName(name)] = compileModule(source.module, runCtx.context);
   }
 
-  for (const fn of args.functions ?? []) {
-    let cacheKey = codeCacheKey(false, fn.code, fn.params);
+  for (const modArgs of args.modules ?? []) {
+    addModule(runCtx, modArgs);
+  }
+
+  for (const wasm of args.wasmModules ?? []) {
+    runCtx.context[wasm.name] = instantiateWasm(wasm, runCtx.context);
+  }
+
+  return runCtx;
+}
+
+/** Compile `functions` and place them in the context's global scope. */
+function compileFunctions(runCtx: RunContext, functions: FunctionDef[]) {
+  for (const fn of functions) {
+    let cacheKey = codeCacheKey('function', fn.code, fn.params);
     let cachedData = codeCache.get(cacheKey);
     let compiled = vm.compileFunction(fn.code, fn.params, {
       parsingContext: runCtx.context,
@@ -58,32 +469,353 @@ function createContext(ctx: MessageContext, args: RunScriptArgs): RunContext {
       produceCachedData: !cachedData,
     });
 
+    if (!cachedData && compiled.cachedData) {
+      codeCache.set(cacheKey, compiled.cachedData);
+    }
+
     runCtx.context[fn.name] = compiled;
   }
+}
 
-  for (const modArgs of args.modules ?? []) {
-    const cacheKey = codeCacheKey(true, modArgs.code);
-    let cachedData = codeCache.get(cacheKey);
-    let mod = new vm.SourceTextModule(modArgs.code, {
-      identifier: modArgs.name,
-      context: runCtx.context,
-      cachedData,
+/** Compile a module that the host sent into the context, keeping its code so that later runs can
+ * refer to it by hash instead of sending it again. */
+function addModule(runCtx: RunContext, modArgs: CodeModule) {
+  const name = moduleName(modArgs.name);
+  runCtx.modules[name] = compileModule(modArgs, runCtx.context);
+  runCtx.moduleSources.set(name, { module: modArgs, hash: contentHash(modArgs.code) });
+}
+
+function compileModule(modArgs: CodeModule, context: vm.Context) {
+  const cacheKey = codeCacheKey('module', modArgs.code);
+  let cachedData = codeCache.get(cacheKey);
+  let mod = new vm.SourceTextModule(modArgs.code, {
+    identifier: modArgs.name,
+    context,
+    cachedData,
+  });
+
+  if (!cachedData) {
+    let data = mod.createCachedData();
+    codeCache.set(cacheKey, data);
+  }
+
+  return mod;
+}
+
+/** Ask the host's resolver for a module that the context doesn't have, and add it to the context.
+ * Returns the module's name, or undefined if the host doesn't know it. Fails in a dry run, which
+ * can only use the modules that the context already has. */
+function resolveFromHost(
+  run: RunContext,
+  ctx: MessageContext,
+  specifier: string,
+  referrer: string
+): Promise<string | undefined> {
+  // The resolver is code on the host that can do anything, so a dry run doesn't call it.
+  if (ctx.dryRun) {
+    return Promise.reject(
+      moduleError(`Can't resolve module ${specifier} from ${referrer} in a dry run`)
+    );
+  }
+
+  const key = joinSpecifier(specifier, referrer);
+  let pending = run.pendingHostModules.get(key);
+  if (pending) {
+    return pending;
+  }
+
+  pending = ctx.protocol
+    .resolveModule(ctx.reqId, key, referrer)
+    .then(
+      (module) => {
+        if (!module) {
+          return undefined;
+        }
+
+        // The host can return a module that the context already has, under another specifier.
+        const name = moduleName(module.name);
+        run.modules[name] ??= compileModule(module, run.context);
+        run.hostModules.set(key, name);
+        return name;
+      },
+      (e) => {
+        throw moduleError(`Failed to resolve module ${specifier} from ${referrer}: ${e.message}`);
+      }
+    )
+    .finally(() => run.pendingHostModules.delete(key));
+  run.pendingHostModules.set(key, pending);
+  return pending;
+}
+
+/** Throw away the connection's context, so that the next run starts with a fresh one. This is done
+ * when a run is interrupted or abandoned, since its code may have left the context half-updated,
+ * or still be running in it. */
+function discardContext(protocol: Protocol) {
+  const run: RunContext | undefined = protocol.cache.get(RUN_CTX_KEY);
+  if (run) {
+    run.timers.dispose();
+    protocol.cache.delete(RUN_CTX_KEY);
+  }
+}
+
+/** Fetch a module for a URL import, or take it from the cache, and add it to the context.
+ * Returns the module's name, which is the URL that it came from after any redirects, so that its
+ * own relative imports resolve against that. */
+async function importFromUrl(run: RunContext, ctx: MessageContext, url: string, referrer: string) {
+  const prefixes = ctx.protocol.urlImports;
+  if (!isAllowed(url, prefixes)) {
+    throw moduleError(`Import of ${url} from ${referrer} is not allowed`);
+  }
+
+  const fetched = await urlModules.get(url, ctx.protocol.urlImportTtlMs, prefixes);
+  // A cached module may have been fetched for a connection that allows more of its redirects.
+  const blocked = fetched.hops.find((hop) => !isAllowed(hop, prefixes));
+  if (blocked) {
+    throw moduleError(`Import of ${url} redirected to ${blocked}, which is not allowed`);
+  }
+
+  run.modules[fetched.url] ??= compileModule(
+    { name: fetched.url, code: fetched.code },
+    run.context
+  );
+  run.modules[url] ??= run.modules[fetched.url];
+  return fetched.url;
+}
+
+/** The code of a registered script. The host only sends the code the first time that the
+ * connection runs the script, so it is kept for the runs after that. */
+function registeredCode(script: RegisteredScript, code: string | undefined, protocol: Protocol) {
+  let scripts: Map<string, string> | undefined = protocol.cache.get(REGISTERED_SCRIPTS_KEY);
+  if (!scripts) {
+    scripts = new Map();
+    protocol.cache.set(REGISTERED_SCRIPTS_KEY, scripts);
+  }
+
+  if (code) {
+    scripts.set(script.hash, code);
+    return code;
+  }
+
+  const kept = scripts.get(script.hash);
+  if (kept === undefined) {
+    throw internalError(
+      `Script ${script.name} was sent without its code, but the connection doesn't have it`
+    );
+  }
+  return kept;
+}
+
+/** The number of timers and immediates waiting to run in this worker. */
+function pendingMacrotasks() {
+  return process
+    .getActiveResourcesInfo()
+    .filter((type) => type === 'Timeout' || type === 'Immediate').length;
+}
+
+function elapsedMs(from: bigint, to: bigint) {
+  return Number(to - from) / 1e6;
+}
+
+/** Send a chunk passed to the `write` global to the host. Strings are sent as UTF-8. The returned
+ * promise resolves once the chunk has been written out, so a script that awaits each write doesn't
+ * get ahead of the connection. */
+function writeOutput(ctx: MessageContext, chunk: unknown): Promise<void> {
+  if (!ctx.streamOutput) {
+    throw new Error('Output streaming is not enabled for this run');
+  }
+
+  let data: Buffer;
+  if (typeof chunk === 'string') {
+    data = Buffer.from(ctx.redactor ? ctx.redactor.redact(chunk) : chunk);
+  } else if (ArrayBuffer.isView(chunk)) {
+    data = Buffer.from(chunk.buffer, chunk.byteOffset, chunk.byteLength);
+  } else if (types.isArrayBuffer(chunk)) {
+    data = Buffer.from(chunk);
+  } else {
+    throw new TypeError('write() takes a string, an ArrayBuffer, or a typed array');
+  }
+  if (ctx.dryRun) {
+    ctx.dryRun.output(data);
+    return Promise.resolve();
+  }
+  return ctx.output(data);
+}
+
+/** Send a chunk passed to `stderr.write` to the host as an error-level console message, like
+ * `console.error`. Unlike the output from `write`, this doesn't need the run to stream its output.
+ * A single trailing newline is left out, since each chunk is already a separate message. */
+function writeStderr(run: RunContext, chunk: unknown) {
+  let text: string;
+  if (typeof chunk === 'string') {
+    text = chunk;
+  } else if (ArrayBuffer.isView(chunk)) {
+    text = Buffer.from(chunk.buffer, chunk.byteOffset, chunk.byteLength).toString('utf8');
+  } else if (types.isArrayBuffer(chunk)) {
+    text = Buffer.from(chunk).toString('utf8');
+  } else {
+    throw new TypeError('stderr.write() takes a string, an ArrayBuffer, or a typed array');
+  }
+  forwardLog(run, [text.endsWith('\n') ? text.slice(0, -1) : text], 'error');
+  return true;
+}
+
+/** Handle the timers that a script left pending, according to the run's drain policy. Only the
+ * run's own timers are affected, and not ones left by earlier runs in the context. */
+async function drainTimers(run: RunContext, args: RunScriptArgs, ctx: MessageContext) {
+  if (args.drain === 'cancel') {
+    run.timers.cancel(ctx);
+  } else if (args.drain === 'wait') {
+    let cap: NodeJS.Timeout | undefined;
+    await Promise.race([
+      run.timers.idle(ctx),
+      new Promise((resolve) => {
+        cap = setTimeout(resolve, args.maxDrainMs ?? DEFAULT_MAX_DRAIN_MS);
+      }),
+    ]);
+    clearTimeout(cap);
+    // Let promises started by the last timers settle.
+    await new Promise((resolve) => setImmediate(resolve));
+  }
+}
+
+/** Resolve any promises in the top level of the globals. */
+async function awaitGlobals(globals: Record<string, any>) {
+  const entries = await Promise.all(
+    Object.entries(globals).map(async ([key, value]) => [key, await value])
+  );
+  return Object.fromEntries(entries);
+}
+
+/** Advance the virtual clock of the connection's context. Console output from the timers that
+ * run is attributed to this request. */
+export function advanceTime(args: AdvanceTime, ctx: MessageContext): Promise<AdvanceTimeResult> {
+  const run: RunContext | undefined = ctx.protocol.cache.get(RUN_CTX_KEY);
+  if (!run) {
+    return Promise.reject(new Error('The connection has no context to advance'));
+  }
+
+  run.current = ctx;
+  return currentRequest.run(ctx, () => run.timers.advance(args.ms));
+}
+
+/** Complete the end of a line of code from the connection's context. A connection with no
+ * context yet has nothing to offer. */
+export function complete(args: Complete, ctx: MessageContext): Completions {
+  const run: RunContext | undefined = ctx.protocol.cache.get(RUN_CTX_KEY);
+  if (!run) {
+    return { prefix: '', candidates: [] };
+  }
+  return completeLine(args.line, run.context, run.lexicalNames);
+}
+
+/** List the properties of a value in the connection's context, or its globals. */
+export function introspectContext(args: Introspect, ctx: MessageContext): PropertyHint[] {
+  const run: RunContext | undefined = ctx.protocol.cache.get(RUN_CTX_KEY);
+  if (!run) {
+    return [];
+  }
+  return introspect(args.expr, args.prefix, run.context, run.lexicalNames);
+}
+
+export function runScript(args: RunScriptArgs, ctx: MessageContext): Promise<RunResponse> {
+  const abort = (ctx.abort ??= new AbortController());
+  ctx.streamOutput = Boolean(args.streamOutput);
+  if (args.dryRun) {
+    ctx.dryRun = new DryRun();
+  }
+  if (args.streamInput) {
+    ctx.input ??= new InputStream(abort.signal);
+  }
+  const stopTimeout = args.timeoutMs ? abortAfter(abort, args.timeoutMs) : undefined;
+  return currentRequest
+    .run(ctx, () =>
+      untilAborted(args.profile ? executeWithProfile(args, ctx) : execute(args, ctx), abort.signal)
+    )
+    .catch((e) => {
+      if (abort.signal.aborted || isTimeout(e)) {
+        discardContext(ctx.protocol);
+      }
+      throw e;
+    })
+    .finally(() => {
+      stopTimeout?.();
+      // The host stops reading output once the run finishes.
+      ctx.streamOutput = false;
+      if (args.debug) {
+        debugRunFinished();
+      }
     });
+}
 
-    if (!cachedData) {
-      let data = mod.createCachedData();
-      codeCache.set(cacheKey, data);
+/** Run the script under the CPU profiler, and send the profile before the response. */
+async function executeWithProfile(args: RunScriptArgs, ctx: MessageContext): Promise<RunResponse> {
+  const stopProfiling = await startProfiling();
+  try {
+    return await execute(args, ctx);
+  } finally {
+    try {
+      const profile = await stopProfiling();
+      ctx.protocol.sendMessage(ctx.reqId, WorkerToHostMessage.CpuProfile, profile);
+    } catch (e) {
+      debug(`Request ${ctx.reqId} failed to collect a CPU profile`, e);
     }
-
-    runCtx.modules[modArgs.name] = mod;
   }
+}
 
-  return runCtx;
+/** The `secrets` globals set by runs, so that they can be told apart from a script's own global
+ * with the same name. */
+const secretsGlobals = new WeakSet<object>();
+
+/** Expose the run's secrets to the script as a frozen `secrets` global. It isn't enumerable, so it
+ * isn't sent back with the other globals, and secrets from an earlier run on the context are
+ * removed. */
+function setSecrets(context: vm.Context, secrets: Record<string, string> | undefined) {
+  if (secrets && Object.keys(secrets).length) {
+    const value = Object.freeze({ ...secrets });
+    secretsGlobals.add(value);
+    Object.defineProperty(context, 'secrets', { value, configurable: true });
+  } else if (secretsGlobals.has(context.secrets)) {
+    delete context.secrets;
+  }
 }
 
-export async function runScript(args: RunScriptArgs, ctx: MessageContext): Promise<RunResponse> {
+async function execute(args: RunScriptArgs, ctx: MessageContext): Promise<RunResponse> {
+  ctx.annotations = args.annotations;
+  ctx.consoleHandler = args.consoleHandler;
+  ctx.minLogLevel = args.minLogLevel;
+  if (args.secrets && Object.keys(args.secrets).length) {
+    ctx.redactor = new Redactor(args.secrets);
+  }
+  if (args.maxLogMessages !== undefined || args.maxLogBytes !== undefined) {
+    ctx.logBudget = new LogBudget(args.maxLogMessages, args.maxLogBytes);
+  }
+  if (args.registeredScript) {
+    args = { ...args, code: registeredCode(args.registeredScript, args.code, ctx.protocol) };
+  }
+
+  // Call and REPL modes return their result directly, so only return globals that were asked for.
+  const returnKeys = args.call || args.repl ? (args.returnKeys ?? []) : args.returnKeys;
+  // Check the selectors up front, so that a typo fails before the script has done anything.
+  returnKeys?.forEach(parseSelector);
+
+  if (args.debug && args.code) {
+    await waitForDebugger(ctx);
+    if (!args.call) {
+      args = { ...args, code: withBreakpoint(args.code) };
+    }
+  }
+
+  const macrotasksBefore = pendingMacrotasks();
+  const heapBefore = getHeapStatistics().used_heap_size;
   let start = process.hrtime.bigint();
   let run = createContext(ctx, args);
+  setSecrets(run.context, args.secrets);
+  if (args.extendedValues && args.args) {
+    const { realm } = run;
+    args = { ...args, args: args.args.map((arg) => decodeValue(arg, realm)) };
+  }
+  // Set once the code is compiled, to separate compile time from execution time.
+  let compiled = start;
 
   let retVal;
 
@@ -92,8 +824,49 @@ export async function runScript(args: RunScriptArgs, ctx: MessageContext): Promi
     return {};
   }
 
-  const cacheKey = codeCacheKey(!args.expr, args.code);
-  if (args.expr) {
+  const cacheKey = codeCacheKey(
+    args.call ? 'call' : args.expr || args.repl ? 'script' : 'module',
+    args.code
+  );
+  if (args.call) {
+    // Wrap the code in parentheses so that function declarations become expressions.
+    let code = `(${args.code}\n)`;
+    let cacheData = codeCache.get(cacheKey);
+    let script = new vm.Script(code, {
+      filename: args.name || '<script>',
+      cachedData: cacheData,
+    });
+
+    if (!cacheData) {
+      codeCache.set(cacheKey, script.createCachedData());
+    }
+
+    compiled = process.hrtime.bigint();
+    let fn = script.runInContext(run.context, {
+      timeout: args.timeoutMs ?? undefined,
+    });
+
+    if (typeof fn !== 'function') {
+      throw new Error(`Call mode code evaluated to ${typeof fn}, not a function`);
+    }
+
+    run.context[CALL_KEY] = () => {
+      if (args.debug) {
+        // Pause just before the call, so that the debugger can step into the function.
+        debugger;
+      }
+      return fn(...(args.args ?? []));
+    };
+    let result;
+    try {
+      result = callScript.runInContext(run.context, {
+        timeout: args.timeoutMs ?? undefined,
+      });
+    } finally {
+      delete run.context[CALL_KEY];
+    }
+    retVal = await result;
+  } else if (args.expr || args.repl) {
     let cacheData = codeCache.get(cacheKey);
     let script = new vm.Script(args.code, {
       filename: args.name || '<script>',
@@ -104,6 +877,7 @@ export async function runScript(args: RunScriptArgs, ctx: MessageContext): Promi
       codeCache.set(cacheKey, script.createCachedData());
     }
 
+    compiled = process.hrtime.bigint();
     retVal = script.runInContext(run.context, {
       timeout: args.timeoutMs ?? undefined,
     });
@@ -111,14 +885,38 @@ export async function runScript(args: RunScriptArgs, ctx: MessageContext): Promi
     if (typeof retVal?.then === 'function') {
       retVal = await retVal;
     }
+    for (const name of declaredNames(args.code)) {
+      run.lexicalNames.add(name);
+    }
   } else {
+    const resolve = (specifier: string, referencingModule: vm.Module) => {
+      const referrer = moduleName(referencingModule.identifier);
+      const url = importUrl(specifier, referrer);
+      if (url) {
+        return Object.hasOwn(run.modules, url) ? url : undefined;
+      }
+      return (
+        resolveSpecifier(specifier, referrer, run.modules) ??
+        run.hostModules.get(joinSpecifier(specifier, referrer))
+      );
+    };
+
     async function doLink(specifier: string, referencingModule: vm.Module) {
-      const mod = run.modules[specifier];
-      if (mod) {
-        return mod;
+      let name = resolve(specifier, referencingModule);
+      if (!name) {
+        const referrer = moduleName(referencingModule.identifier);
+        const url = importUrl(specifier, referrer);
+        if (url) {
+          name = await importFromUrl(run, ctx, url, referrer);
+        } else if (ctx.protocol.resolveModulesEnabled) {
+          name = await resolveFromHost(run, ctx, specifier, referrer);
+        }
+      }
+      if (name) {
+        return run.modules[name];
       }
 
-      throw new Error(
+      throw moduleError(
         `Module not found: ${specifier}, referenced from ${referencingModule.identifier}`
       );
     }
@@ -138,16 +936,74 @@ export async function runScript(args: RunScriptArgs, ctx: MessageContext): Promi
     }
 
     await mod.link(doLink);
-    await mod.evaluate();
+    compiled = process.hrtime.bigint();
+    try {
+      await mod.evaluate({ timeout: args.timeoutMs ?? undefined });
+    } catch (e) {
+      // Modules in a cycle can use each other's exports before they are initialized, which
+      // throws a ReferenceError that doesn't say why. The error comes from the context, so it
+      // isn't an instance of this realm's ReferenceError.
+      const script = mod;
+      const byName = (name: string) => (name === '' ? script : run.modules[name]);
+      const cycle =
+        (e as any)?.name === 'ReferenceError' &&
+        findCycle('', (name) => {
+          const from = byName(name) as vm.SourceTextModule;
+          return from.dependencySpecifiers.flatMap((specifier) => resolve(specifier, from) ?? []);
+        });
+      if (cycle) {
+        const names = cycle.map((name) => byName(name).identifier).join(' -> ');
+        const message = (e as any).message;
+        throw new Error(`Import cycle between modules ${names}: ${message}`, { cause: e });
+      }
+      throw e;
+    }
+
+    // A script can report its result, such as the promise from an async IIFE, as its default export.
+    const namespace: any = mod.namespace;
+    retVal = await namespace.default;
   }
 
-  const outputGlobals = args.returnKeys
-    ? Object.fromEntries(args.returnKeys.map((key) => [key, run.context[key]]))
-    : run.context;
+  await drainTimers(run, args, ctx);
+
+  let outputGlobals = returnKeys ? selectPaths(run.context, returnKeys) : run.context;
+  if (args.awaitResult) {
+    outputGlobals = await awaitGlobals(outputGlobals);
+  }
+
+  const executed = process.hrtime.bigint();
+  const stats: RunStats = {
+    compileMs: elapsedMs(start, compiled),
+    executeMs: elapsedMs(compiled, executed),
+    heapUsedDelta: getHeapStatistics().used_heap_size - heapBefore,
+    macrotasksDrained: pendingMacrotasks() <= macrotasksBefore,
+  };
+  const { globals: sendableGlobals, skipped } = serializableGlobals(
+    outputGlobals,
+    returnKeys !== undefined,
+    Boolean(args.extendedValues)
+  );
+  if (skipped.length && args.strictGlobals) {
+    const { key, reason } = skipped[0];
+    throw new Error(`Global ${key} can't be sent to the host: ${reason}`);
+  }
+  if (args.repl) {
+    retVal = inspect(retVal);
+  } else if (args.extendedValues) {
+    retVal = encodeValue(retVal, 'returnValue');
+  }
   let elapsed = Number(process.hrtime.bigint() - start) / 1e3;
   debug(`Evaluated in ${elapsed}us`);
+
+  const limits: StringLimits = {
+    maxStringBytes: args.maxStringBytes,
+    replaceInvalidUnicode: args.replaceInvalidUnicode,
+  };
   return {
-    globals: outputGlobals,
-    returnValue: retVal,
+    globals: validateStrings(sendableGlobals, 'globals', limits),
+    returnValue: validateStrings(retVal, 'returnValue', limits),
+    stats,
+    skippedGlobals: skipped.length ? skipped : undefined,
+    effects: ctx.dryRun?.effects,
   };
 }
@@ -0,0 +1,56 @@
+import { describe, it, expect } from 'vitest';
+import { Redactor } from './secrets';
+
+describe('Redactor', () => {
+  const redactor = new Redactor({ key: 'sk-123', longer: 'sk-123456', empty: '' });
+
+  it('redacts strings', () => {
+    expect(redactor.redact('key sk-123456 and sk-123, sk-123')).toBe(
+      'key [REDACTED] and [REDACTED], [REDACTED]'
+    );
+    expect(redactor.redact('nothing here')).toBe('nothing here');
+  });
+
+  it('redacts nested values', () => {
+    const value = {
+      list: ['sk-123', 1, null],
+      'sk-123': { date: new Date(0) },
+      nested: { deep: 'Bearer sk-123' },
+    };
+    expect(redactor.redactValue(value)).toEqual({
+      list: ['[REDACTED]', 1, null],
+      '[REDACTED]': { date: '1970-01-01T00:00:00.000Z' },
+      nested: { deep: 'Bearer [REDACTED]' },
+    });
+  });
+
+  it('handles circular references', () => {
+    const value: any = { secret: 'sk-123' };
+    value.self = value;
+    const redacted = redactor.redactValue(value);
+    expect(redacted.secret).toBe('[REDACTED]');
+  });
+
+  it('redacts errors', () => {
+    const e = redactor.redactError(new Error('bad key sk-123'));
+    expect(e.message).toBe('bad key [REDACTED]');
+    expect(e.stack).toContain('bad key [REDACTED]');
+    expect(e.stack).not.toContain('sk-123');
+    expect(redactor.redactError('thrown sk-123').message).toBe('thrown [REDACTED]');
+  });
+
+  it('redacts causes and aggregated errors', () => {
+    const e = new AggregateError([new Error('inner sk-123')], 'outer', {
+      cause: new Error('cause sk-123'),
+    });
+    const redacted = redactor.redactError(e) as any;
+    expect(redacted.cause.message).toBe('cause [REDACTED]');
+    expect(redacted.errors[0].message).toBe('inner [REDACTED]');
+  });
+
+  it('keeps the name and code of errors', () => {
+    const e = redactor.redactError(Object.assign(new TypeError('sk-123'), { code: 'ERR_X' }));
+    expect(e.name).toBe('TypeError');
+    expect((e as any).code).toBe('ERR_X');
+  });
+});
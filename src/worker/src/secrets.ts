@@ -0,0 +1,74 @@
+/** What a secret's value is replaced with in anything sent back to the host. */
+export const REDACTED = '[REDACTED]';
+
+/** Replaces the values of a run's secrets in logs, errors, and responses. */
+export class Redactor {
+  /** Longest first, so that a secret containing another is replaced whole. */
+  values: string[];
+
+  constructor(secrets: Record<string, string>) {
+    this.values = [...new Set(Object.values(secrets))]
+      .filter((value) => value.length > 0)
+      .sort((a, b) => b.length - a.length);
+  }
+
+  redact(text: string) {
+    for (const value of this.values) {
+      if (text.includes(value)) {
+        text = text.replaceAll(value, REDACTED);
+      }
+    }
+    return text;
+  }
+
+  /** Redact every string in a value, in the same way that `JSON.stringify` would visit it. */
+  redactValue(value: any, seen = new Set<object>()): any {
+    if (typeof value === 'string') {
+      return this.redact(value);
+    }
+    if (value === null || typeof value !== 'object') {
+      return value;
+    }
+    if (typeof value.toJSON === 'function') {
+      return this.redactValue(value.toJSON(), seen);
+    }
+    if (seen.has(value) || ArrayBuffer.isView(value)) {
+      return value;
+    }
+
+    seen.add(value);
+    if (Array.isArray(value)) {
+      return value.map((item) => this.redactValue(item, seen));
+    }
+    return Object.fromEntries(
+      Object.entries(value).map(([key, item]) => [this.redact(key), this.redactValue(item, seen)])
+    );
+  }
+
+  /** A copy of a thrown value with the secrets removed from its message and stack, and from its
+   * causes and aggregated errors. The name and code are kept, since the host uses them to tell
+   * errors apart. */
+  redactError(e: any, seen = new Set<unknown>()): Error {
+    const message = typeof e?.message === 'string' ? e.message : String(e);
+    const redacted = new Error(this.redact(message));
+    redacted.stack = typeof e?.stack === 'string' ? this.redact(e.stack) : undefined;
+    if (typeof e?.name === 'string') {
+      redacted.name = e.name;
+    }
+    if (typeof e?.code === 'string') {
+      Object.assign(redacted, { code: e.code });
+    }
+
+    if (typeof e === 'object' && e !== null && !seen.has(e)) {
+      seen.add(e);
+      if (e.cause !== undefined) {
+        redacted.cause = this.redactError(e.cause, seen);
+      }
+      if (Array.isArray(e.errors)) {
+        const errors = e.errors.map((item: unknown) => this.redactError(item, seen));
+        Object.assign(redacted, { errors });
+      }
+    }
+    return redacted;
+  }
+}
@@ -0,0 +1,57 @@
+import { describe, it, expect } from 'vitest';
+import { parseSelector, selectPaths } from './select';
+
+const context = {
+  user: { profile: { name: 'Bob', 'first.last': 'Bob Smith' } },
+  items: [
+    { id: 1, tags: ['a', 'b'] },
+    { id: 2, tags: ['c'] },
+  ],
+  cache: { user_1: 'x', user_2: 'y', session: 'z' },
+  plain: 5,
+};
+
+describe('selectPaths', () => {
+  it('selects plain keys', () => {
+    expect(selectPaths(context, ['plain', 'missing'])).toEqual({ plain: 5 });
+  });
+
+  it('selects nested properties and indexes', () => {
+    expect(
+      selectPaths(context, ['user.profile.name', 'items[1].id', 'user.profile["first.last"]'])
+    ).toEqual({
+      'user.profile.name': 'Bob',
+      'items[1].id': 2,
+      'user.profile["first.last"]': 'Bob Smith',
+    });
+  });
+
+  it('maps wildcards over arrays and objects', () => {
+    expect(selectPaths(context, ['items[*].id', 'items.*.tags[0]', 'user.*.name'])).toEqual({
+      'items[*].id': [1, 2],
+      'items.*.tags[0]': ['a', 'c'],
+      'user.*.name': { profile: 'Bob' },
+    });
+  });
+
+  it('matches globs against property names', () => {
+    expect(selectPaths(context, ['cache.user_*'])).toEqual({
+      'cache.user_*': { user_1: 'x', user_2: 'y' },
+    });
+  });
+
+  it('leaves out paths that do not exist', () => {
+    expect(selectPaths(context, ['plain.x', 'items[5].id', 'user.profile.name.first'])).toEqual(
+      {}
+    );
+  });
+});
+
+describe('parseSelector', () => {
+  it('rejects malformed selectors', () => {
+    expect(() => parseSelector('items[')).toThrow('unclosed');
+    expect(() => parseSelector('items[x]')).toThrow('bad index');
+    expect(() => parseSelector('user.')).toThrow('ends with');
+    expect(() => parseSelector('user..name')).toThrow('empty property name');
+  });
+});
@@ -0,0 +1,148 @@
+/** Path selectors for `returnKeys`, which pick values out of the context.
+ *
+ *  A selector is a list of property names separated by `.`, with `[n]` to index into an array and
+ *  `["name"]` for names containing other characters. `*`, or `[*]`, selects every element of an
+ *  array, as an array, or every property of an object, as an object. A `*` inside a name matches
+ *  any characters, and selects the matching properties as an object.
+ *
+ *  e.g. `user.profile.name`, `items[*].id`, `cache.user_*`
+ * */
+
+interface KeySegment {
+  kind: 'key';
+  key: string;
+}
+
+interface IndexSegment {
+  kind: 'index';
+  index: number;
+}
+
+interface WildcardSegment {
+  kind: 'wildcard';
+}
+
+interface GlobSegment {
+  kind: 'glob';
+  pattern: RegExp;
+}
+
+type Segment = KeySegment | IndexSegment | WildcardSegment | GlobSegment;
+
+const PLAIN_KEY = /^[^.[\]*]+$/;
+
+function nameSegment(name: string, selector: string): Segment {
+  if (!name) {
+    throw new Error(`Invalid return key ${JSON.stringify(selector)}: empty property name`);
+  }
+
+  if (name === '*') {
+    return { kind: 'wildcard' };
+  }
+
+  if (name.includes('*')) {
+    const escaped = name.replace(/[.+?^${}()|[\]\\]/g, '\\$&').replace(/\*/g, '.*');
+    return { kind: 'glob', pattern: new RegExp(`^${escaped}$`) };
+  }
+
+  return { kind: 'key', key: name };
+}
+
+/** Parse a selector into its segments. Throws if the selector is malformed. */
+export function parseSelector(selector: string): Segment[] {
+  if (PLAIN_KEY.test(selector)) {
+    return [{ kind: 'key', key: selector }];
+  }
+
+  const segments: Segment[] = [];
+  let pos = 0;
+  // Names are allowed at the start and after a `.`.
+  let expectName = true;
+  while (pos < selector.length) {
+    const c = selector[pos];
+    if (c === '[') {
+      const end = selector.indexOf(']', pos);
+      if (end === -1) {
+        throw new Error(`Invalid return key ${JSON.stringify(selector)}: unclosed [`);
+      }
+
+      const inner = selector.slice(pos + 1, end);
+      if (inner === '*') {
+        segments.push({ kind: 'wildcard' });
+      } else if (/^\d+$/.test(inner)) {
+        segments.push({ kind: 'index', index: parseInt(inner, 10) });
+      } else if (/^(".*"|'.*')$/.test(inner)) {
+        segments.push({ kind: 'key', key: inner.slice(1, -1) });
+      } else {
+        throw new Error(`Invalid return key ${JSON.stringify(selector)}: bad index [${inner}]`);
+      }
+
+      pos = end + 1;
+      expectName = false;
+    } else if (c === '.' && !expectName) {
+      pos += 1;
+      expectName = true;
+    } else if (expectName) {
+      const match = /^[^.[\]]*/.exec(selector.slice(pos));
+      const name = match ? match[0] : '';
+      segments.push(nameSegment(name, selector));
+      pos += name.length;
+      expectName = false;
+    } else {
+      throw new Error(`Invalid return key ${JSON.stringify(selector)}: unexpected ${c}`);
+    }
+  }
+
+  if (expectName) {
+    throw new Error(`Invalid return key ${JSON.stringify(selector)}: ends with .`);
+  }
+
+  return segments;
+}
+
+function selectSegments(value: any, segments: Segment[], i: number): any {
+  if (i === segments.length) {
+    return value;
+  }
+
+  if (value === null || (typeof value !== 'object' && typeof value !== 'function')) {
+    return undefined;
+  }
+
+  const segment = segments[i];
+  switch (segment.kind) {
+    case 'key':
+      return selectSegments(value[segment.key], segments, i + 1);
+    case 'index':
+      if (!Array.isArray(value)) {
+        return undefined;
+      }
+      return selectSegments(value[segment.index], segments, i + 1);
+    case 'wildcard':
+      if (Array.isArray(value)) {
+        return value.map((v) => selectSegments(v, segments, i + 1));
+      }
+      return Object.fromEntries(
+        Object.entries(value).map(([k, v]) => [k, selectSegments(v, segments, i + 1)])
+      );
+    case 'glob':
+      return Object.fromEntries(
+        Object.entries(value)
+          .filter(([k]) => segment.pattern.test(k))
+          .map(([k, v]) => [k, selectSegments(v, segments, i + 1)])
+      );
+  }
+}
+
+/** Evaluate each selector against `root`, returning the results keyed by the selectors. Selectors
+ * whose path doesn't exist are left out. */
+export function selectPaths(root: any, selectors: string[]): Record<string, any> {
+  const result: Record<string, any> = {};
+  for (const selector of selectors) {
+    const value = selectSegments(root, parseSelector(selector), 0);
+    if (value !== undefined) {
+      result[selector] = value;
+    }
+  }
+  return result;
+}
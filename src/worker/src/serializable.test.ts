@@ -0,0 +1,59 @@
+import { describe, it, expect } from 'vitest';
+import { serializableGlobals, unserializableReason } from './serializable';
+
+describe('unserializableReason', () => {
+  it('accepts plain data', () => {
+    const shared = { x: 1 };
+    expect(unserializableReason({ a: [shared, shared], d: new Date(0), f: () => 1 }, 'v')).toBe(
+      undefined
+    );
+  });
+
+  it('reports circular references and BigInts', () => {
+    const value: any = { a: {} };
+    value.a.b = value;
+    expect(unserializableReason(value, 'v')).toBe('v.a.b is a circular reference');
+    expect(unserializableReason({ list: [1, 2n] }, 'v')).toBe('v.list[1] is a BigInt');
+  });
+
+  it('reports values that throw when read', () => {
+    const value = {
+      get bad() {
+        throw new Error('no access');
+      },
+    };
+    expect(unserializableReason(value, 'v')).toBe('reading v threw: no access');
+    const toJSON = () => {
+      throw new Error('nope');
+    };
+    expect(unserializableReason({ toJSON }, 'v')).toBe('v.toJSON() threw: nope');
+  });
+});
+
+describe('serializableGlobals', () => {
+  it('skips globals that cannot be sent', () => {
+    const circular: any = {};
+    circular.self = circular;
+    const result = serializableGlobals({ a: 1, circular, f: () => 1 }, false, false);
+    expect(Object.keys(result.globals)).toEqual(['a', 'f']);
+    expect(result.skipped).toEqual([
+      { key: 'circular', reason: 'circular.self is a circular reference' },
+    ]);
+  });
+
+  it('reports functions only when they were requested', () => {
+    const result = serializableGlobals({ a: 1, f: () => 1 }, true, false);
+    expect(result.globals).toEqual({ a: 1 });
+    expect(result.skipped).toEqual([{ key: 'f', reason: 'f is a function' }]);
+  });
+
+  it('encodes extended values', () => {
+    const circular: any = {};
+    circular.self = circular;
+    const result = serializableGlobals({ a: 1n, f: () => 1, circular }, false, true);
+    expect(result.globals).toEqual({ a: { $type: 'bigint', value: '1' } });
+    expect(result.skipped).toEqual([
+      { key: 'circular', reason: 'Value at globals.circular.self contains a circular reference' },
+    ]);
+  });
+});
@@ -0,0 +1,112 @@
+import type { SkippedGlobal } from './api_types.js';
+import { encodeValue } from './structured.js';
+
+function errorMessage(e: unknown) {
+  return e instanceof Error ? e.message : String(e);
+}
+
+/** Why `value` can't be converted to JSON, or undefined if it can. Functions and symbols inside
+ * objects are left out by `JSON.stringify` as usual, and aren't reported. */
+export function unserializableReason(
+  value: any,
+  path: string,
+  ancestors = new Set<object>()
+): string | undefined {
+  if (typeof value === 'bigint') {
+    return `${path} is a BigInt`;
+  }
+
+  if (value === null || typeof value !== 'object') {
+    return undefined;
+  }
+
+  if (ancestors.has(value)) {
+    return `${path} is a circular reference`;
+  }
+
+  ancestors.add(value);
+  try {
+    if (typeof value.toJSON === 'function') {
+      let converted;
+      try {
+        converted = value.toJSON();
+      } catch (e) {
+        return `${path}.toJSON() threw: ${errorMessage(e)}`;
+      }
+      return unserializableReason(converted, path, ancestors);
+    }
+
+    if (Array.isArray(value)) {
+      for (let i = 0; i < value.length; i++) {
+        const reason = unserializableReason(value[i], `${path}[${i}]`, ancestors);
+        if (reason) {
+          return reason;
+        }
+      }
+      return undefined;
+    }
+
+    for (const key of Object.keys(value)) {
+      const reason = unserializableReason(value[key], `${path}.${key}`, ancestors);
+      if (reason) {
+        return reason;
+      }
+    }
+    return undefined;
+  } catch (e) {
+    // Proxies and host objects can throw when they are read.
+    return `reading ${path} threw: ${errorMessage(e)}`;
+  } finally {
+    ancestors.delete(value);
+  }
+}
+
+/** Why a global that was asked for by name holds nothing that can be sent. */
+function unsendableReason(value: any, key: string) {
+  switch (typeof value) {
+    case 'function':
+      return `${key} is a function`;
+    case 'symbol':
+      return `${key} is a symbol`;
+  }
+}
+
+/** Split the globals into the ones that can be sent to the host and the ones that can't.
+ *
+ * Functions and symbols are only reported if `requested` is set, meaning that the globals were
+ * picked by `returnKeys`, since a context normally holds plenty of functions that nobody expects
+ * to get back. They are still left out either way.
+ *
+ * With `extended` set, the globals are encoded for `extendedValues` along the way. */
+export function serializableGlobals(
+  globals: Record<string, any>,
+  requested: boolean,
+  extended: boolean
+): { globals: Record<string, any>; skipped: SkippedGlobal[] } {
+  const output: Record<string, any> = {};
+  const skipped: SkippedGlobal[] = [];
+
+  for (const key of Object.keys(globals)) {
+    let value;
+    let reason;
+    try {
+      value = globals[key];
+      reason = requested ? unsendableReason(value, key) : undefined;
+      if (!reason && extended) {
+        value = encodeValue(value, `globals.${key}`);
+      } else if (!reason) {
+        reason = unserializableReason(value, key);
+      }
+    } catch (e) {
+      reason = errorMessage(e);
+    }
+
+    if (reason) {
+      skipped.push({ key, reason });
+    } else if (value !== undefined) {
+      output[key] = value;
+    }
+  }
+
+  return { globals: output, skipped };
+}
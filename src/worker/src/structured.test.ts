@@ -0,0 +1,76 @@
+import { describe, it, expect } from 'vitest';
+import vm from 'node:vm';
+import { contextRealm, decodeValue, encodeValue } from './structured';
+
+describe('structured values', () => {
+  it('round trips extended types', () => {
+    const value = {
+      date: new Date(1718000000000),
+      map: new Map<any, any>([
+        ['a', 1],
+        [2, new Set([3n])],
+      ]),
+      bytes: new Uint16Array([1, 2, 65535]),
+      missing: undefined,
+      nan: NaN,
+      nested: [Infinity, 'x'],
+    };
+
+    const encoded = JSON.parse(JSON.stringify(encodeValue(value)));
+    expect(encoded.date).toEqual({ $type: 'date', value: 1718000000000 });
+    expect(encoded.bytes.kind).toBe('Uint16Array');
+
+    const decoded = decodeValue(encoded);
+    expect(decoded.date.getTime()).toBe(1718000000000);
+    expect(decoded.map.get('a')).toBe(1);
+    expect(decoded.map.get(2).has(3n)).toBe(true);
+    expect(Array.from(decoded.bytes)).toEqual([1, 2, 65535]);
+    expect('missing' in decoded).toBe(true);
+    expect(decoded.missing).toBeUndefined();
+    expect(Number.isNaN(decoded.nan)).toBe(true);
+    expect(decoded.nested).toEqual([Infinity, 'x']);
+  });
+
+  it('escapes objects that look like tagged values', () => {
+    const value = { $type: 'date', value: 5 };
+    const encoded = encodeValue(value);
+    expect(encoded).toEqual({ $type: 'object', value: { $type: 'date', value: 5 } });
+    expect(decodeValue(encoded)).toEqual(value);
+  });
+
+  it('encodes values from another realm', () => {
+    const value = vm.runInNewContext('({ d: new Date(5), s: new Set([1]) })');
+    expect(encodeValue(value)).toEqual({
+      d: { $type: 'date', value: 5 },
+      s: { $type: 'set', value: [1] },
+    });
+  });
+
+  it("decodes values with a context's constructors", () => {
+    const context = vm.createContext({});
+    // Parsed from JSON, so that `__proto__` is an ordinary key, as it is in a message from the host.
+    const value = JSON.parse(
+      '{"d":{"$type":"date","value":5},"m":{"$type":"map","value":[["a",[1]]]},' +
+        '"b":{"$type":"typedarray","kind":"Uint8Array","value":"AQI="},' +
+        '"__proto__":{"polluted":true}}'
+    );
+    context.value = decodeValue(value, contextRealm(context));
+    expect(
+      vm.runInContext(
+        `[value.d instanceof Date, value.m instanceof Map, value.m.get('a') instanceof Array,
+          value.b instanceof Uint8Array, Object.getPrototypeOf(value) === Object.prototype]`,
+        context
+      )
+    ).toEqual([true, true, true, true, true]);
+    expect(Object.hasOwn(context.value, '__proto__')).toBe(true);
+    expect(context.value.polluted).toBeUndefined();
+  });
+
+  it('rejects circular references', () => {
+    const value: any = { a: {} };
+    value.a.b = value;
+    expect(() => encodeValue(value, 'globals.x')).toThrow(
+      'Value at globals.x.a.b contains a circular reference'
+    );
+  });
+});
@@ -0,0 +1,184 @@
+import { types } from 'node:util';
+import * as vm from 'vm';
+
+/** Values that JSON can't represent are encoded as objects with this key naming their type. */
+const TYPE_KEY = '$type';
+
+const TYPED_ARRAYS: Record<string, any> = {
+  Int8Array,
+  Uint8Array,
+  Uint8ClampedArray,
+  Int16Array,
+  Uint16Array,
+  Int32Array,
+  Uint32Array,
+  Float32Array,
+  Float64Array,
+  BigInt64Array,
+  BigUint64Array,
+};
+
+/** The constructors that decoded values are built with. Values for a script are built with its
+ * context's constructors, so that `instanceof` checks in the script work. */
+export type Realm = Record<'Object' | 'Array' | 'Date' | 'Map' | 'Set' | 'ArrayBuffer', any> &
+  Record<string, any>;
+
+const WORKER_REALM: Realm = { Object, Array, Date, Map, Set, ArrayBuffer, ...TYPED_ARRAYS };
+
+const realmScript = new vm.Script(`({ ${Object.keys(WORKER_REALM).join(', ')} })`);
+
+/** The constructors of a context, read before its code has had a chance to replace them. */
+export function contextRealm(context: vm.Context): Realm {
+  return realmScript.runInContext(context);
+}
+
+/** Encode a value as JSON-compatible data, using tagged objects for Dates, Maps, Sets, BigInts,
+ * typed arrays, `undefined`, and non-finite numbers so that they survive the trip to the host.
+ *
+ * The `util.types` checks are used instead of `instanceof` since values from a script come from
+ * a different realm. */
+export function encodeValue(value: any, path = 'value', seen = new Set<object>()): any {
+  switch (typeof value) {
+    case 'undefined':
+      return { [TYPE_KEY]: 'undefined' };
+    case 'bigint':
+      return { [TYPE_KEY]: 'bigint', value: value.toString() };
+    case 'number':
+      return Number.isFinite(value) ? value : { [TYPE_KEY]: 'number', value: String(value) };
+    case 'function':
+    case 'symbol':
+      return undefined;
+    case 'object':
+      break;
+    default:
+      return value;
+  }
+
+  if (value === null) {
+    return null;
+  }
+
+  if (seen.has(value)) {
+    throw new Error(`Value at ${path} contains a circular reference`);
+  }
+  seen.add(value);
+
+  try {
+    if (types.isDate(value)) {
+      return { [TYPE_KEY]: 'date', value: value.getTime() };
+    }
+
+    if (types.isMap(value)) {
+      return {
+        [TYPE_KEY]: 'map',
+        value: Array.from(value.entries(), ([k, v], i) => [
+          encodeValue(k, `${path}.<key ${i}>`, seen),
+          encodeValue(v, `${path}.<value ${i}>`, seen),
+        ]),
+      };
+    }
+
+    if (types.isSet(value)) {
+      return {
+        [TYPE_KEY]: 'set',
+        value: Array.from(value, (v, i) => encodeValue(v, `${path}.<item ${i}>`, seen)),
+      };
+    }
+
+    if (types.isTypedArray(value)) {
+      const bytes = Buffer.from(value.buffer, value.byteOffset, value.byteLength);
+      return {
+        [TYPE_KEY]: 'typedarray',
+        kind: Object.prototype.toString.call(value).slice(8, -1),
+        value: bytes.toString('base64'),
+      };
+    }
+
+    if (Array.isArray(value)) {
+      return value.map((item, i) => {
+        const encoded = encodeValue(item, `${path}[${i}]`, seen);
+        // Match JSON, which turns functions in arrays into null.
+        return encoded === undefined ? null : encoded;
+      });
+    }
+
+    const output: Record<string, any> = {};
+    for (const key of Object.keys(value)) {
+      const encoded = encodeValue(value[key], `${path}.${key}`, seen);
+      if (encoded !== undefined) {
+        output[key] = encoded;
+      }
+    }
+
+    if (TYPE_KEY in output) {
+      // Escape objects that look like tagged values.
+      return { [TYPE_KEY]: 'object', value: output };
+    }
+
+    return output;
+  } finally {
+    seen.delete(value);
+  }
+}
+
+/** Decode a value from the host, turning tagged objects back into the values they represent.
+ * Objects are built with the constructors from `realm`. */
+export function decodeValue(value: any, realm: Realm = WORKER_REALM): any {
+  if (value === null || typeof value !== 'object') {
+    return value;
+  }
+
+  if (Array.isArray(value)) {
+    return realm.Array.from(value, (item: any) => decodeValue(item, realm));
+  }
+
+  switch (value[TYPE_KEY]) {
+    case undefined:
+      break;
+    case 'undefined':
+      return undefined;
+    case 'bigint':
+      return BigInt(value.value);
+    case 'number':
+      return Number(value.value);
+    case 'date':
+      return new realm.Date(value.value);
+    case 'map':
+      return new realm.Map(
+        value.value.map(([k, v]: [any, any]) => [decodeValue(k, realm), decodeValue(v, realm)])
+      );
+    case 'set':
+      return new realm.Set(value.value.map((item: any) => decodeValue(item, realm)));
+    case 'typedarray': {
+      if (!Object.hasOwn(TYPED_ARRAYS, value.kind)) {
+        throw new Error(`Unknown typed array kind ${value.kind}`);
+      }
+      const bytes = Buffer.from(value.value, 'base64');
+      // Copy into a new buffer to get the alignment that the array type needs.
+      const buffer = new realm.ArrayBuffer(bytes.byteLength);
+      new Uint8Array(buffer).set(bytes);
+      return new realm[value.kind](buffer);
+    }
+    case 'object':
+      return decodeObject(value.value, realm);
+    default:
+      throw new Error(`Unknown value type ${value[TYPE_KEY]}`);
+  }
+
+  return decodeObject(value, realm);
+}
+
+/** Decode each property of `value`. Keys are defined rather than assigned, so that a key such as
+ * `__proto__` is an ordinary property instead of setting the prototype. */
+export function decodeObject(value: Record<string, any>, realm: Realm = WORKER_REALM) {
+  const output: Record<string, any> = new realm.Object();
+  for (const [key, item] of Object.entries(value)) {
+    Object.defineProperty(output, key, {
+      value: decodeValue(item, realm),
+      writable: true,
+      enumerable: true,
+      configurable: true,
+    });
+  }
+  return output;
+}
@@ -0,0 +1,189 @@
+import { EventEmitter } from 'node:events';
+import { Worker, type MessagePort } from 'node:worker_threads';
+import type { IncomingMessage, Protocol, Transport } from './protocol.js';
+import { HostToWorkerMessage } from './api_types.js';
+import { ABORT_GRACE_MS, timeoutError } from './abort.js';
+import { debug } from './debug.js';
+import { internalError } from './errors.js';
+
+/** How the worker keeps a script that stops responding from blocking other connections. */
+export type Isolation = 'process' | 'thread';
+
+/** Passed to a connection's thread when it starts. */
+export interface ThreadData {
+  /** The handshake that the host sent on the connection, which a replacement thread applies
+   * without answering. */
+  handshake: Uint8Array | null;
+}
+
+/** Sent from a connection's thread to the main thread. */
+export type ThreadMessage =
+  | { type: 'write'; data: Uint8Array }
+  | { type: 'end' }
+  | { type: 'started'; reqId: number; timeoutMs: number | null }
+  | { type: 'finished'; reqId: number };
+
+const THREAD_KEY = Symbol('thread');
+
+/** The transport of a Protocol inside a connection's thread, which hands each frame to the main
+ * thread to write to the socket. */
+export class ThreadTransport extends EventEmitter implements Transport {
+  port: MessagePort;
+
+  constructor(port: MessagePort) {
+    super();
+    this.port = port;
+  }
+
+  write(data: Buffer, callback?: () => void) {
+    const message: ThreadMessage = { type: 'write', data };
+    this.port.postMessage(message);
+    callback?.();
+  }
+
+  end() {
+    const message: ThreadMessage = { type: 'end' };
+    this.port.postMessage(message);
+  }
+}
+
+/** A Buffer over the bytes of a Uint8Array that came through `postMessage`. */
+export function toBuffer(data: Uint8Array) {
+  return Buffer.from(data.buffer, data.byteOffset, data.byteLength);
+}
+
+/** Runs the requests for one connection in a worker thread. The main thread reads the frames from
+ * the socket and passes them on, and the thread writes its frames back through the main thread.
+ *
+ * A run that goes past its timeout by more than the abort grace period, such as one stuck in an
+ * infinite loop in a timer callback, has its thread terminated. The run fails as timed out, other
+ * runs on the connection fail, and a new thread takes over the connection with a fresh context. */
+class ThreadedConnection {
+  protocol: Protocol;
+  socket: Transport;
+  onRun: () => void;
+  thread!: Worker;
+  /** Runs that the thread hasn't finished yet, with the timer that terminates the thread if the run
+   * goes too far past its timeout. The timer starts once the run leaves its lane, since a run can
+   * wait there behind the thread's other runs. */
+  pending = new Map<number, NodeJS.Timeout | undefined>();
+  handshake: Uint8Array | null = null;
+  /** The thread has started a run, and so has a context. */
+  hasContext = false;
+  closed = false;
+
+  constructor(protocol: Protocol, socket: Transport, onRun: () => void) {
+    this.protocol = protocol;
+    this.socket = socket;
+    this.onRun = onRun;
+    this.start();
+    protocol.on('message', (message) => this.forward(message));
+    socket.on('close', () => {
+      this.closed = true;
+      this.clearPending();
+      this.thread.terminate();
+    });
+  }
+
+  start() {
+    const data: ThreadData = { handshake: this.handshake };
+    const thread = new Worker(process.argv[1], { workerData: data });
+    thread.on('message', (message: ThreadMessage) => {
+      // Messages from a thread that has been replaced are about runs that have already failed.
+      if (thread !== this.thread) {
+        return;
+      }
+
+      if (message.type === 'write') {
+        const frame = toBuffer(message.data);
+        this.protocol.forwarded(frame);
+        this.socket.write(frame);
+      } else if (message.type === 'end') {
+        this.socket.end();
+      } else if (message.type === 'started') {
+        this.started(message.reqId, message.timeoutMs);
+      } else {
+        clearTimeout(this.pending.get(message.reqId));
+        this.pending.delete(message.reqId);
+      }
+    });
+    thread.on('error', (e) => {
+      console.error(e);
+      this.replace(thread, e);
+    });
+    thread.on('exit', (code) => {
+      this.replace(thread, internalError(`The worker thread exited with code ${code}`));
+    });
+    this.thread = thread;
+    this.hasContext = false;
+  }
+
+  forward(message: IncomingMessage) {
+    const { reqId, type, data } = message;
+    if (type === HostToWorkerMessage.Handshake) {
+      this.handshake = data;
+    } else if (
+      type === HostToWorkerMessage.RunScript ||
+      type === HostToWorkerMessage.RunScriptBinary
+    ) {
+      this.onRun();
+      this.hasContext = true;
+      this.pending.set(reqId, undefined);
+    }
+
+    this.thread.postMessage(message);
+  }
+
+  /** Start the timer for a run that the thread has started. */
+  started(reqId: number, timeoutMs: number | null) {
+    if (timeoutMs == null || !this.pending.has(reqId)) {
+      return;
+    }
+
+    const timer = setTimeout(() => this.timedOut(reqId, timeoutMs), timeoutMs + ABORT_GRACE_MS);
+    this.pending.set(reqId, timer);
+  }
+
+  /** Terminate a thread that is stuck in a run past its timeout, and start a new one. */
+  timedOut(reqId: number, timeoutMs: number) {
+    debug(`${reqId}: terminating the worker thread of a run that timed out`);
+    this.pending.delete(reqId);
+    this.protocol.error(reqId, timeoutError(timeoutMs));
+    this.replace(this.thread, internalError('Another run on the connection timed out'));
+  }
+
+  /** Fail the runs of a thread that has stopped, and start a new thread for the connection. */
+  replace(thread: Worker, reason: Error) {
+    if (thread !== this.thread || this.closed) {
+      return;
+    }
+
+    for (const reqId of this.pending.keys()) {
+      this.protocol.error(reqId, reason);
+    }
+    this.clearPending();
+    thread.removeAllListeners('exit');
+    thread.terminate();
+    this.start();
+  }
+
+  clearPending() {
+    for (const timer of this.pending.values()) {
+      clearTimeout(timer);
+    }
+    this.pending.clear();
+  }
+}
+
+/** Run the requests on a connection in a worker thread. `onRun` is called for each run request. */
+export function runInThread(protocol: Protocol, socket: Transport, onRun: () => void) {
+  protocol.cache.set(THREAD_KEY, new ThreadedConnection(protocol, socket, onRun));
+}
+
+/** The state of a connection's thread, if it runs in one. */
+export function threadStats(protocol: Protocol) {
+  const connection = protocol.cache.get(THREAD_KEY) as ThreadedConnection | undefined;
+  return connection
+    ? { hasContext: connection.hasContext, activeRequests: connection.pending.size }
+    : null;
+}
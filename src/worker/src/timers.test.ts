@@ -0,0 +1,134 @@
+import { describe, it, expect } from 'vitest';
+import { ContextTimers, virtualDate } from './timers';
+
+describe('ContextTimers', () => {
+  it('runs virtual timers in order as the clock advances', async () => {
+    const timers = new ContextTimers('virtual', () => {});
+    const start = timers.now;
+    const { setTimeout, setInterval, clearInterval } = timers.globals();
+    const calls: string[] = [];
+
+    setTimeout(() => calls.push('b'), 20);
+    setTimeout(() => calls.push('a'), 10);
+    const interval = setInterval(() => calls.push(`i${timers.now - start}`), 15);
+
+    expect(await timers.advance(5)).toEqual({ now: start + 5, timersRun: 0, pendingTimers: 3 });
+    expect(await timers.advance(25)).toEqual({ now: start + 30, timersRun: 4, pendingTimers: 1 });
+    expect(calls).toEqual(['a', 'i15', 'b', 'i30']);
+
+    clearInterval(interval);
+    expect(await timers.advance(100)).toMatchObject({ timersRun: 0, pendingTimers: 0 });
+  });
+
+  it('runs timers set by other timers in the same advance', async () => {
+    const timers = new ContextTimers('virtual', () => {});
+    const { setTimeout } = timers.globals();
+    const calls: number[] = [];
+    setTimeout(() => {
+      calls.push(1);
+      setTimeout(() => calls.push(2), 5);
+    }, 5);
+
+    expect((await timers.advance(10)).timersRun).toBe(2);
+    expect(calls).toEqual([1, 2]);
+  });
+
+  it('reports errors from callbacks', async () => {
+    const errors: unknown[] = [];
+    const timers = new ContextTimers('virtual', (e) => errors.push(e));
+    timers.globals().setTimeout(() => {
+      throw new Error('boom');
+    }, 1);
+
+    await timers.advance(1);
+    expect(errors).toHaveLength(1);
+  });
+
+  it('limits the timers per run', () => {
+    const timers = new ContextTimers('real', () => {});
+    const { setTimeout, clearTimeout } = timers.globals();
+    timers.startRun(2);
+    clearTimeout(setTimeout(() => {}, 1000));
+    clearTimeout(setTimeout(() => {}, 1000));
+    expect(() => setTimeout(() => {}, 1000)).toThrow('at most 2');
+
+    timers.startRun(undefined);
+    clearTimeout(setTimeout(() => {}, 1000));
+    expect(timers.pending).toBe(0);
+  });
+
+  it('waits for real timers to finish', async () => {
+    const timers = new ContextTimers('real', () => {});
+    const { setTimeout, setInterval, clearInterval } = timers.globals();
+    const calls: string[] = [];
+    setTimeout(() => {
+      calls.push('a');
+      setTimeout(() => calls.push('b'), 5);
+    }, 5);
+    const interval = setInterval(() => {
+      calls.push('i');
+      clearInterval(interval);
+    }, 1);
+
+    await timers.idle();
+    expect(calls.sort()).toEqual(['a', 'b', 'i']);
+    expect(timers.pending).toBe(0);
+
+    setInterval(() => {}, 1000);
+    const idle = timers.idle();
+    timers.dispose();
+    await idle;
+  });
+
+  it('waits for and cancels the timers of one run', async () => {
+    let owner = 'a';
+    const timers = new ContextTimers('real', () => {}, Date.now(), () => owner);
+    const { setTimeout } = timers.globals();
+    const calls: string[] = [];
+    setTimeout(() => calls.push('a'), 20);
+    owner = 'b';
+    setTimeout(() => calls.push('cancelled'), 10);
+    timers.cancel('b');
+    expect(timers.pending).toBe(1);
+
+    setTimeout(() => calls.push('b'), 5);
+    await timers.idle('b');
+    expect(calls).toEqual(['b']);
+    await timers.idle();
+    expect(calls).toEqual(['b', 'a']);
+  });
+
+  it('limits the timers that one advance runs', async () => {
+    const timers = new ContextTimers('virtual', () => {});
+    timers.maxTimersPerAdvance = 10;
+    const start = timers.now;
+    let calls = 0;
+    timers.globals().setInterval(() => calls++, 1);
+
+    await expect(timers.advance(1000)).rejects.toThrow('ran 10 timers');
+    expect(calls).toBe(10);
+    expect(timers.now).toBe(start + 10);
+  });
+
+  it('throws when timers are disabled', async () => {
+    const timers = new ContextTimers('disabled', () => {});
+    expect(() => timers.globals().setInterval(() => {}, 10)).toThrow('disabled');
+    await expect(timers.advance(10)).rejects.toThrow('virtual time');
+  });
+});
+
+describe('virtualDate', () => {
+  it('reads the virtual clock', () => {
+    let now = 1000;
+    const VirtualDate = virtualDate(Date, () => now);
+    expect(new VirtualDate().getTime()).toBe(1000);
+    now = 2000;
+    expect(VirtualDate.now()).toBe(2000);
+    expect(new VirtualDate(5).getTime()).toBe(5);
+    expect(new VirtualDate('2020-01-01T00:00:00Z').getUTCFullYear()).toBe(2020);
+    expect(new VirtualDate()).toBeInstanceOf(Date);
+    expect(new VirtualDate()).toBeInstanceOf(VirtualDate);
+    expect((VirtualDate as any)()).toBe(new Date(2000).toString());
+    expect(VirtualDate.UTC(2020, 0, 1)).toBe(Date.UTC(2020, 0, 1));
+  });
+});
@@ -0,0 +1,277 @@
+import type { AdvanceTimeResult, TimerMode } from './api_types.js';
+
+interface VirtualTimer {
+  id: number;
+  due: number;
+  /** Breaks ties between timers that are due at the same time, in the order they were set. */
+  seq: number;
+  interval: number | null;
+  callback: () => void;
+  /** The run that set the timer. */
+  owner: unknown;
+}
+
+interface RealTimer {
+  handle: NodeJS.Timeout;
+  /** The run that set the timer. */
+  owner: unknown;
+}
+
+/** The most timers that one `advance` runs, so that a short interval can't keep the worker busy
+ * indefinitely. */
+const MAX_TIMERS_PER_ADVANCE = 100_000;
+
+/** The `Date` global for a context on virtual time. `new Date()`, `Date()`, and `Date.now()` read
+ * the virtual clock, and everything else behaves like the context's own `Date`. */
+export function virtualDate(ContextDate: DateConstructor, now: () => number) {
+  // A function rather than a class, since `Date` can also be called without `new`.
+  function VirtualDate(...args: any[]) {
+    if (!new.target) {
+      return new ContextDate(now()).toString();
+    }
+    return Reflect.construct(ContextDate, args.length === 0 ? [now()] : args, new.target);
+  }
+
+  Object.setPrototypeOf(VirtualDate, ContextDate);
+  VirtualDate.prototype = ContextDate.prototype;
+  Object.defineProperty(VirtualDate, 'now', {
+    value: () => now(),
+    writable: true,
+    configurable: true,
+  });
+  return VirtualDate as unknown as DateConstructor;
+}
+
+/** The timers of one context. Timers set by the context's code get numeric IDs, like in a
+ * browser, so that clearing them works the same way in every mode. Each timer belongs to the run
+ * that set it, as given by `owner`, so that a run can wait for or cancel just its own timers.
+ *
+ * In `real` mode, timers run on the worker's own clock. In `virtual` mode, they only run when
+ * the host calls `advance`. In `disabled` mode, setting a timer throws. */
+export class ContextTimers {
+  mode: TimerMode;
+  /** The virtual clock, in milliseconds since the epoch. */
+  now: number;
+  /** The most timers that can be set during the current run, or null for no limit. */
+  maxTimers: number | null = null;
+  /** How many timers have been set during the current run. */
+  created = 0;
+  /** The most timers that one `advance` runs. */
+  maxTimersPerAdvance = MAX_TIMERS_PER_ADVANCE;
+
+  realTimers = new Map<number, RealTimer>();
+  virtualTimers = new Map<number, VirtualTimer>();
+  nextId = 1;
+  nextSeq = 0;
+  /** Called when the last real timer of a run runs or is cleared, with the run that each one
+   * waits for, or undefined to wait for every run. */
+  idleWaiters = new Map<() => void, unknown>();
+  /** Called with errors thrown by timer callbacks. */
+  reportError: (e: unknown) => void;
+  /** The run that is setting a timer. */
+  owner: () => unknown;
+
+  constructor(
+    mode: TimerMode,
+    reportError: (e: unknown) => void,
+    now = Date.now(),
+    owner: () => unknown = () => undefined
+  ) {
+    this.mode = mode;
+    this.now = now;
+    this.reportError = reportError;
+    this.owner = owner;
+  }
+
+  /** Reset the count of timers for a new run. */
+  startRun(maxTimers: number | undefined) {
+    this.maxTimers = maxTimers ?? null;
+    this.created = 0;
+  }
+
+  /** The number of timers that haven't run or been cleared. */
+  get pending() {
+    return this.realTimers.size + this.virtualTimers.size;
+  }
+
+  /** The timer functions to place in the context. */
+  globals() {
+    return {
+      setTimeout: (callback: unknown, delay?: number, ...args: any[]) =>
+        this.set(callback, delay, args, false),
+      setInterval: (callback: unknown, delay?: number, ...args: any[]) =>
+        this.set(callback, delay, args, true),
+      clearTimeout: (id: unknown) => this.clear(id),
+      clearInterval: (id: unknown) => this.clear(id),
+    };
+  }
+
+  set(callback: unknown, delay: number | undefined, args: any[], repeat: boolean) {
+    if (this.mode === 'disabled') {
+      throw new Error('Timers are disabled in this context');
+    }
+
+    if (typeof callback !== 'function') {
+      throw new TypeError('The timer callback must be a function');
+    }
+
+    if (this.maxTimers !== null && this.created >= this.maxTimers) {
+      throw new RangeError(`Too many timers: a run can set at most ${this.maxTimers}`);
+    }
+    this.created += 1;
+
+    // Like Node.js, treat delays below 1ms, or that aren't numbers, as 1ms.
+    const ms = Math.max(Number(delay) || 0, 1);
+    const id = this.nextId++;
+    const owner = this.owner();
+    const run = () => {
+      try {
+        callback(...args);
+      } catch (e) {
+        this.reportError(e);
+      }
+    };
+
+    if (this.mode === 'virtual') {
+      this.virtualTimers.set(id, {
+        id,
+        due: this.now + ms,
+        seq: this.nextSeq++,
+        interval: repeat ? ms : null,
+        callback: run,
+        owner,
+      });
+    } else if (repeat) {
+      this.realTimers.set(id, { handle: setInterval(run, ms), owner });
+    } else {
+      const handle = setTimeout(() => {
+        this.realTimers.delete(id);
+        run();
+        this.checkIdle();
+      }, ms);
+      this.realTimers.set(id, { handle, owner });
+    }
+
+    return id;
+  }
+
+  clear(id: unknown) {
+    if (typeof id !== 'number') {
+      return;
+    }
+
+    const timer = this.realTimers.get(id);
+    if (timer) {
+      clearTimeout(timer.handle);
+      this.realTimers.delete(id);
+      this.checkIdle();
+    }
+    this.virtualTimers.delete(id);
+  }
+
+  /** Resolves once no real timers set by `owner`, or by any run if it is undefined, are waiting
+   * to run. Virtual timers only run when the host advances the clock, so they aren't waited
+   * for. */
+  idle(owner?: unknown): Promise<void> {
+    if (!this.hasRealTimers(owner)) {
+      return Promise.resolve();
+    }
+    return new Promise((resolve) => this.idleWaiters.set(resolve, owner));
+  }
+
+  hasRealTimers(owner: unknown) {
+    for (const timer of this.realTimers.values()) {
+      if (owner === undefined || timer.owner === owner) {
+        return true;
+      }
+    }
+    return false;
+  }
+
+  checkIdle() {
+    for (const [resolve, owner] of this.idleWaiters) {
+      if (!this.hasRealTimers(owner)) {
+        this.idleWaiters.delete(resolve);
+        resolve();
+      }
+    }
+  }
+
+  /** The virtual timer that is due next, if it is due by `limit`. */
+  nextDue(limit: number) {
+    let next: VirtualTimer | undefined;
+    for (const timer of this.virtualTimers.values()) {
+      if (
+        timer.due <= limit &&
+        (!next || timer.due < next.due || (timer.due === next.due && timer.seq < next.seq))
+      ) {
+        next = timer;
+      }
+    }
+    return next;
+  }
+
+  /** Move the virtual clock forward by `ms`, running each timer that comes due along the way. */
+  async advance(ms: number): Promise<AdvanceTimeResult> {
+    if (this.mode !== 'virtual') {
+      throw new Error('The context is not using virtual time');
+    }
+
+    const target = this.now + ms;
+    let timersRun = 0;
+    for (;;) {
+      const timer = this.nextDue(target);
+      if (!timer) {
+        break;
+      }
+      if (timersRun >= this.maxTimersPerAdvance) {
+        throw new RangeError(
+          `Advancing the clock ran ${timersRun} timers without reaching the end, so an interval ` +
+            'may be too short for the time advanced'
+        );
+      }
+
+      this.now = timer.due;
+      if (timer.interval === null) {
+        this.virtualTimers.delete(timer.id);
+      } else {
+        timer.due += timer.interval;
+        timer.seq = this.nextSeq++;
+      }
+
+      timer.callback();
+      timersRun += 1;
+      // Let promises started by the timer settle before the clock moves on.
+      await new Promise((resolve) => setImmediate(resolve));
+    }
+
+    this.now = target;
+    return { now: this.now, timersRun, pendingTimers: this.pending };
+  }
+
+  /** Cancel the timers set by `owner`, when a run cancels the work it left pending. */
+  cancel(owner: unknown) {
+    for (const [id, timer] of this.realTimers) {
+      if (timer.owner === owner) {
+        clearTimeout(timer.handle);
+        this.realTimers.delete(id);
+      }
+    }
+    for (const [id, timer] of this.virtualTimers) {
+      if (timer.owner === owner) {
+        this.virtualTimers.delete(id);
+      }
+    }
+    this.checkIdle();
+  }
+
+  /** Cancel every timer, when the context is thrown away. */
+  dispose() {
+    for (const timer of this.realTimers.values()) {
+      clearTimeout(timer.handle);
+    }
+    this.realTimers.clear();
+    this.virtualTimers.clear();
+    this.checkIdle();
+  }
+}
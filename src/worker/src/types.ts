@@ -1,10 +1,44 @@
 import type { Protocol } from './protocol.js';
+import type { AsyncErrorSource, LogLevel } from './api_types.js';
+import type { Annotations } from './annotations.js';
+import type { LogBudget } from './log_budget.js';
+import type { Redactor } from './secrets.js';
+import type { InputStream } from './input.js';
+import type { ScriptChannel } from './channel.js';
+import type { DryRun } from './dry_run.js';
 
 export interface MessageContext {
   protocol: Protocol;
   reqId: number;
   id: number;
-  log(message: any, level?: keyof Console): void;
+  /** Metadata from the run, included in diagnostics about the request. */
+  annotations?: Annotations;
+  /** Limits on the console messages sent for this request. */
+  logBudget?: LogBudget;
+  /** Removes the run's secrets from what is sent to the host. */
+  redactor?: Redactor;
+  /** Aborted when the host cancels the request or its timeout passes. Scripts see its signal in
+   * the `signal` global. */
+  abort?: AbortController;
+  /** The run can send output with the `write` global. */
+  streamOutput?: boolean;
+  /** The chunks that the host sends to the run, read from the `input` global. */
+  input?: InputStream;
+  /** The `channel` global, created when the script or the host first uses it. */
+  channel?: ScriptChannel;
+  /** Records the effects of a run with `dryRun` set, instead of sending them to the host. */
+  dryRun?: DryRun;
+  /** The name of the global function that handles the run's console calls. */
+  consoleHandler?: string;
+  /** Set while the console handler runs, so that its own console calls are forwarded. */
+  inConsoleHandler?: boolean;
+  /** Console messages less severe than this aren't sent to the host. */
+  minLogLevel?: LogLevel;
+  log(message: any, level?: LogLevel, namespace?: string): void;
   respond(data: any): void;
   error(e: Error): void;
+  /** Send output from the `write` global, resolving once it has been written out. */
+  output(data: Buffer): Promise<void>;
+  /** Report an error from the context's code that isn't part of the run's result. */
+  asyncError(e: unknown, source: AsyncErrorSource): void;
 }
@@ -0,0 +1,89 @@
+import { describe, it, expect, vi } from 'vitest';
+import { UrlModuleCache, importUrl, isAllowed } from './url_imports';
+
+describe('url imports', () => {
+  it('finds URL imports', () => {
+    expect(importUrl('https://esm.sh/lodash', 'main.js')).toBe('https://esm.sh/lodash');
+    expect(importUrl('/v1/a.js', 'https://esm.sh/lib/index.js')).toBe('https://esm.sh/v1/a.js');
+    expect(importUrl('./a.js', 'https://esm.sh/lib/index.js')).toBe('https://esm.sh/lib/a.js');
+    expect(importUrl('./a.js', 'lib/index.js')).toBe(null);
+    expect(importUrl('lodash', 'https://esm.sh/lib/index.js')).toBe(null);
+  });
+
+  it('checks the allowlist', () => {
+    expect(isAllowed('https://esm.sh/lodash', ['https://esm.sh/'])).toBe(true);
+    expect(isAllowed('https://esm.sh.evil.com/lodash', ['https://esm.sh/'])).toBe(false);
+    expect(isAllowed('https://esm.sh.evil.com/lodash', ['https://esm.sh'])).toBe(false);
+    expect(isAllowed('https://esm.sh@evil.com/lodash', ['https://esm.sh'])).toBe(false);
+    expect(isAllowed('https://esm.sh/lodash', [])).toBe(false);
+  });
+
+  it('compares whole path segments', () => {
+    const prefixes = ['https://cdn.example.com/pkg'];
+    expect(isAllowed('https://cdn.example.com/pkg/a.js', prefixes)).toBe(true);
+    expect(isAllowed('https://cdn.example.com/pkg-evil/a.js', prefixes)).toBe(false);
+    expect(isAllowed('https://cdn.example.com/pkg/../secret.js', prefixes)).toBe(false);
+    expect(isAllowed('https://cdn.example.com/pkg/%2e%2e/secret.js', prefixes)).toBe(false);
+  });
+
+  it('caches modules until they expire', async () => {
+    const fetch = vi.fn(async (url: string) => ({
+      url,
+      hops: [url],
+      code: `export default ${JSON.stringify(url)};`,
+    }));
+    const cache = new UrlModuleCache(fetch);
+
+    const [a, b] = await Promise.all([
+      cache.get('https://a/', 1000, []),
+      cache.get('https://a/', 1000, []),
+    ]);
+    expect(a).toBe(b);
+    expect(fetch).toHaveBeenCalledTimes(1);
+
+    await cache.get('https://a/', 1000, []);
+    expect(fetch).toHaveBeenCalledTimes(1);
+
+    await cache.get('https://a/', 0, []);
+    expect(fetch).toHaveBeenCalledTimes(2);
+  });
+
+  it('checks each redirect', async () => {
+    const responses: Record<string, Response> = {
+      'https://esm.sh/a': new Response(null, { status: 302, headers: { location: '/b' } }),
+      'https://esm.sh/b': new Response(null, {
+        status: 302,
+        headers: { location: 'https://evil.com/c' },
+      }),
+      'https://esm.sh/c': new Response(null, { status: 301, headers: { location: './d' } }),
+      'https://esm.sh/d': new Response('export default 1;'),
+    };
+    const fetchMock = vi.fn(async (url: string) => responses[url]);
+    vi.stubGlobal('fetch', fetchMock);
+    try {
+      const cache = new UrlModuleCache();
+      await expect(cache.get('https://esm.sh/a', 1000, ['https://esm.sh/'])).rejects.toThrow(
+        'redirected to https://evil.com/c'
+      );
+      expect(fetchMock).toHaveBeenCalledTimes(2);
+
+      const fetched = await cache.get('https://esm.sh/c', 1000, ['https://esm.sh/']);
+      expect(fetched.url).toBe('https://esm.sh/d');
+      expect(fetched.hops).toEqual(['https://esm.sh/c', 'https://esm.sh/d']);
+    } finally {
+      vi.unstubAllGlobals();
+    }
+  });
+
+  it("doesn't cache failures", async () => {
+    const fetch = vi.fn(async () => {
+      throw new Error('offline');
+    });
+    const cache = new UrlModuleCache(fetch);
+
+    await expect(cache.get('https://a/', 1000, [])).rejects.toThrow('offline');
+    await expect(cache.get('https://a/', 1000, [])).rejects.toThrow('offline');
+    expect(fetch).toHaveBeenCalledTimes(2);
+    expect(cache.pending.size).toBe(0);
+  });
+});
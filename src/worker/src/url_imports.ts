@@ -0,0 +1,132 @@
+import { LRUCache } from 'lru-cache';
+import { moduleError } from './errors.js';
+
+/** How long to wait for a module's source before failing the import. */
+const FETCH_TIMEOUT_MS = 30_000;
+
+/** How many redirects to follow when fetching a module. */
+const MAX_REDIRECTS = 5;
+
+/** The source of a module imported from a URL. */
+export interface FetchedModule {
+  /** The URL that the source came from, after any redirects */
+  url: string;
+  /** The URL that was imported, followed by each URL that it redirected to */
+  hops: string[];
+  code: string;
+}
+
+/** Returns true if `specifier` is an absolute HTTP or HTTPS URL. */
+export function isUrl(specifier: string) {
+  return /^https?:\/\//i.test(specifier);
+}
+
+/** The URL that `specifier` imports, or null if it isn't a URL import. Paths imported by a module
+ * that came from a URL are resolved against that module's URL, and bare names are left for the
+ * other kinds of modules. */
+export function importUrl(specifier: string, referrer: string): string | null {
+  if (isUrl(specifier)) {
+    return new URL(specifier).href;
+  }
+
+  const isPath =
+    specifier.startsWith('/') || specifier.startsWith('./') || specifier.startsWith('../');
+  if (isPath && isUrl(referrer)) {
+    return new URL(specifier, referrer).href;
+  }
+  return null;
+}
+
+/** Returns true if `url` is under one of the allowed prefixes from the handshake. The URLs are
+ * parsed, so the origin has to match exactly and the path is compared after `..` segments are
+ * resolved. A prefix whose path doesn't end with a slash still only matches whole segments. */
+export function isAllowed(url: string, prefixes: string[]) {
+  let parsed: URL;
+  try {
+    parsed = new URL(url);
+  } catch {
+    return false;
+  }
+  if (parsed.username || parsed.password) {
+    return false;
+  }
+
+  return prefixes.some((prefix) => {
+    let allowed: URL;
+    try {
+      allowed = new URL(prefix);
+    } catch {
+      return false;
+    }
+    if (parsed.origin !== allowed.origin) {
+      return false;
+    }
+    const path = allowed.pathname;
+    return (
+      parsed.pathname === path ||
+      parsed.pathname.startsWith(path.endsWith('/') ? path : `${path}/`)
+    );
+  });
+}
+
+/** Fetch a module's source, following redirects only to URLs that are also allowed. */
+async function fetchSource(url: string, prefixes: string[]): Promise<FetchedModule> {
+  const signal = AbortSignal.timeout(FETCH_TIMEOUT_MS);
+  const hops = [url];
+  for (;;) {
+    const response = await fetch(url, { signal, redirect: 'manual' });
+    const location = response.headers.get('location');
+    if (response.status >= 300 && response.status < 400 && location) {
+      if (hops.length > MAX_REDIRECTS) {
+        throw moduleError(`Too many redirects fetching ${hops[0]}`);
+      }
+      url = new URL(location, url).href;
+      if (!isAllowed(url, prefixes)) {
+        throw moduleError(`Import of ${hops[0]} redirected to ${url}, which is not allowed`);
+      }
+      hops.push(url);
+      continue;
+    }
+
+    if (!response.ok) {
+      throw moduleError(`Failed to fetch ${url}: ${response.status} ${response.statusText}`);
+    }
+    return { url, hops, code: await response.text() };
+  }
+}
+
+/** The source of modules imported from URLs, shared by every connection to the worker. */
+export class UrlModuleCache {
+  entries = new LRUCache<string, { module: FetchedModule; fetchedAt: number }>({ max: 256 });
+  /** Fetches in progress, so that concurrent imports of a URL only fetch it once. */
+  pending = new Map<string, Promise<FetchedModule>>();
+  fetchSource: (url: string, prefixes: string[]) => Promise<FetchedModule>;
+
+  constructor(fetch = fetchSource) {
+    this.fetchSource = fetch;
+  }
+
+  /** Get the module at `url`, fetching it if the cached copy is older than `ttlMs`. Redirects
+   * are only followed to URLs under `prefixes`. The cache is shared by connections with
+   * different prefixes, so callers check the redirects of a cached module themselves. */
+  get(url: string, ttlMs: number, prefixes: string[]): Promise<FetchedModule> {
+    const entry = this.entries.get(url);
+    if (entry && Date.now() - entry.fetchedAt < ttlMs) {
+      return Promise.resolve(entry.module);
+    }
+
+    let pending = this.pending.get(url);
+    if (!pending) {
+      pending = this.fetchSource(url, prefixes)
+        .then((module) => {
+          this.entries.set(url, { module, fetchedAt: Date.now() });
+          return module;
+        })
+        .finally(() => this.pending.delete(url));
+      this.pending.set(url, pending);
+    }
+    return pending;
+  }
+}
+
+export const urlModules = new UrlModuleCache();
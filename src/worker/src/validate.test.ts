@@ -0,0 +1,51 @@
+import { describe, it, expect } from 'vitest';
+import { validateStrings } from './validate';
+
+describe('validateStrings', () => {
+  it('passes valid values through unchanged', () => {
+    const value = { a: 'abc', b: ['de', { c: 'f' }] };
+    expect(validateStrings(value, 'globals', { maxStringBytes: 3 })).toBe(value);
+  });
+
+  it('reports the path of a string over the limit', () => {
+    const value = { a: 'abc', b: ['de', { c: 'ffff' }] };
+    expect(() => validateStrings(value, 'globals', { maxStringBytes: 3 })).toThrow(
+      'String at globals.b[1].c is 4 bytes, exceeding the limit of 3'
+    );
+  });
+
+  it('quotes keys that are not identifiers in the path', () => {
+    const value = { 'a.b': { '0': 'ffff' } };
+    expect(() => validateStrings(value, 'globals', { maxStringBytes: 3 })).toThrow(
+      'String at globals["a.b"]["0"] is 4 bytes'
+    );
+  });
+
+  it('measures UTF-8 bytes', () => {
+    expect(() => validateStrings('日本', 'returnValue', { maxStringBytes: 5 })).toThrow(
+      'is 6 bytes'
+    );
+  });
+
+  it('rejects unpaired surrogates', () => {
+    expect(() => validateStrings({ a: ['\uD800'] }, 'globals', {})).toThrow(
+      'String at globals.a[0] contains an unpaired UTF-16 surrogate'
+    );
+  });
+
+  it('replaces unpaired surrogates without modifying the original', () => {
+    const value = { a: ['x\uD800'], b: 'ok' };
+    const result = validateStrings(value, 'globals', { replaceInvalidUnicode: true });
+    expect(result).toEqual({ a: ['x\uFFFD'], b: 'ok' });
+    expect(value.a[0]).toBe('x\uD800');
+  });
+
+  it('replaces unpaired surrogates in objects reached through more than one reference', () => {
+    const shared = { text: 'x\uD800' };
+    const value = { a: shared, b: [shared] };
+    const result = validateStrings(value, 'globals', { replaceInvalidUnicode: true });
+    expect(result).toEqual({ a: { text: 'x\uFFFD' }, b: [{ text: 'x\uFFFD' }] });
+    expect(result.b[0]).toBe(result.a);
+    expect(shared.text).toBe('x\uD800');
+  });
+});
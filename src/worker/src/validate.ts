@@ -0,0 +1,100 @@
+import { stringTooLongError } from './errors.js';
+
+export interface StringLimits {
+  /** The maximum size of a string, in UTF-8 bytes. */
+  maxStringBytes?: number;
+  /** Replace unpaired surrogates with U+FFFD instead of throwing an error. */
+  replaceInvalidUnicode?: boolean;
+}
+
+/** Check all the strings inside `value` against the limits, and ensure that they can be
+ * represented as UTF-8 by the host. Returns the value with any invalid strings replaced if
+ * `replaceInvalidUnicode` is set, copying objects as needed so that the original is not modified.
+ * */
+export function validateStrings(value: any, path: string, limits: StringLimits): any {
+  return validate(value, path, limits, new Map());
+}
+
+/** `fixed` maps each object that has been checked to its output, so that an object reached
+ * through more than one reference is only checked once, and every reference gets the same copy. */
+function validate(value: any, path: string, limits: StringLimits, fixed: Map<object, any>): any {
+  if (typeof value === 'string') {
+    return validateString(value, path, limits);
+  }
+
+  if (value === null || typeof value !== 'object') {
+    return value;
+  }
+  if (fixed.has(value)) {
+    return fixed.get(value);
+  }
+
+  // A circular reference to an object that is still being checked keeps the original.
+  fixed.set(value, value);
+  const output = validateObject(value, path, limits, fixed);
+  fixed.set(value, output);
+  return output;
+}
+
+function validateObject(
+  value: any,
+  path: string,
+  limits: StringLimits,
+  fixed: Map<object, any>
+): any {
+  if (Array.isArray(value)) {
+    let output = value;
+    for (let i = 0; i < value.length; i++) {
+      const item = validate(value[i], `${path}[${i}]`, limits, fixed);
+      if (item !== value[i]) {
+        if (output === value) {
+          output = value.slice();
+        }
+        output[i] = item;
+      }
+    }
+    return output;
+  }
+
+  let output = value;
+  for (const key of Object.keys(value)) {
+    const item = validate(value[key], keyPath(path, key), limits, fixed);
+    if (item !== value[key]) {
+      if (output === value) {
+        output = { ...value };
+      }
+      output[key] = item;
+    }
+  }
+  return output;
+}
+
+/** The path to the `key` field of the object at `path`, quoting keys that aren't identifiers so
+ * that a key like `a.b` can't be mistaken for a nested field. */
+function keyPath(path: string, key: string) {
+  return /^[A-Za-z_$][A-Za-z0-9_$]*$/.test(key)
+    ? `${path}.${key}`
+    : `${path}[${JSON.stringify(key)}]`;
+}
+
+function validateString(value: string, path: string, limits: StringLimits) {
+  if (!value.isWellFormed()) {
+    if (!limits.replaceInvalidUnicode) {
+      throw new Error(`String at ${path} contains an unpaired UTF-16 surrogate`);
+    }
+
+    value = value.toWellFormed();
+  }
+
+  // Each UTF-16 code unit is at most 3 bytes in UTF-8, so skip measuring short strings.
+  if (limits.maxStringBytes !== undefined && value.length * 3 > limits.maxStringBytes) {
+    const bytes = Buffer.byteLength(value, 'utf8');
+    if (bytes > limits.maxStringBytes) {
+      throw stringTooLongError(
+        `String at ${path} is ${bytes} bytes, exceeding the limit of ${limits.maxStringBytes}`
+      );
+    }
+  }
+
+  return value;
+}
@@ -0,0 +1,45 @@
+import { describe, it, expect } from 'vitest';
+import * as vm from 'vm';
+import { attachWasmBytes, instantiateWasm } from './wasm';
+import type { WasmModule } from './api_types';
+
+// (module (func (export "add") (param i32 i32) (result i32) local.get 0 local.get 1 i32.add))
+const ADD = Buffer.from(
+  '0061736d0100000001070160027f7f017f030201000707010361646400000a09010700200020016a0b',
+  'hex'
+);
+
+// (module (import "env" "log" (func)))
+const NEEDS_IMPORT = Buffer.from('0061736d01000000010401600000020b0103656e76036c6f670000', 'hex');
+
+describe('wasm', () => {
+  it('splits the binary data between modules', () => {
+    const modules: WasmModule[] = [
+      { name: 'a', length: 2 },
+      { name: 'b', length: 3 },
+    ];
+    attachWasmBytes(modules, Buffer.from([1, 2, 3, 4, 5]));
+    expect(modules[0].bytes).toEqual(Buffer.from([1, 2]));
+    expect(modules[1].bytes).toEqual(Buffer.from([3, 4, 5]));
+
+    expect(() => attachWasmBytes([{ name: 'c', length: 10 }], Buffer.alloc(4))).toThrow(
+      'missing some of its bytes'
+    );
+  });
+
+  it('instantiates modules in the context', () => {
+    const context = vm.createContext({});
+    context.math = instantiateWasm({ name: 'math', length: ADD.length, bytes: ADD }, context);
+    expect(vm.runInContext('math.add(2, 3)', context)).toBe(5);
+  });
+
+  it('rejects modules with imports', () => {
+    const mod = { name: 'needy', length: NEEDS_IMPORT.length, bytes: NEEDS_IMPORT };
+    expect(() => instantiateWasm(mod, vm.createContext({}))).toThrow('env.log');
+  });
+
+  it('reports invalid modules', () => {
+    const mod = { name: 'bad', length: 4, bytes: Buffer.from([0, 1, 2, 3]) };
+    expect(() => instantiateWasm(mod, vm.createContext({}))).toThrow('Failed to compile WASM');
+  });
+});
@@ -0,0 +1,56 @@
+import * as vm from 'vm';
+import { createHash } from 'node:crypto';
+import { LRUCache } from 'lru-cache';
+import type { WasmModule } from './api_types.js';
+
+/** Compiled modules, by the hash of their bytes. */
+const moduleCache = new LRUCache<string, WebAssembly.Module>({
+  max: 32,
+});
+
+function compile(bytes: Buffer) {
+  const key = createHash('sha256').update(bytes).digest('hex');
+  let mod = moduleCache.get(key);
+  if (!mod) {
+    mod = new WebAssembly.Module(bytes);
+    moduleCache.set(key, mod);
+  }
+  return mod;
+}
+
+/** Fill in the bytes of each module from the binary data that followed the arguments. */
+export function attachWasmBytes(modules: WasmModule[], binary: Buffer) {
+  let offset = 0;
+  for (const mod of modules) {
+    if (offset + mod.length > binary.length) {
+      throw new Error(`WASM module ${mod.name} is missing some of its bytes`);
+    }
+    mod.bytes = binary.subarray(offset, offset + mod.length);
+    offset += mod.length;
+  }
+}
+
+/** Instantiate a module in the context, returning its exports. */
+export function instantiateWasm(mod: WasmModule, context: vm.Context) {
+  if (!mod.bytes) {
+    throw new Error(`WASM module ${mod.name} has no bytes`);
+  }
+
+  let compiled;
+  try {
+    compiled = compile(mod.bytes);
+  } catch (e) {
+    const message = e instanceof Error ? e.message : String(e);
+    throw new Error(`Failed to compile WASM module ${mod.name}: ${message}`);
+  }
+
+  const imports = WebAssembly.Module.imports(compiled);
+  if (imports.length) {
+    const names = imports.map((i) => `${i.module}.${i.name}`).join(', ');
+    throw new Error(`WASM module ${mod.name} needs imports, which aren't supported: ${names}`);
+  }
+
+  // Use the context's own WebAssembly, so that the exports belong to the context.
+  const ContextWebAssembly: typeof WebAssembly = vm.runInContext('WebAssembly', context);
+  return new ContextWebAssembly.Instance(compiled, {}).exports;
+}
@@ -0,0 +1,143 @@
+import { describe, it, expect, vi } from 'vitest';
+import type { Duplex } from 'node:stream';
+import { EventEmitter } from 'node:events';
+import {
+  checkUpgrade,
+  encodeFrame,
+  parseFrame,
+  WebSocketConnection,
+  type Frame,
+} from './websocket';
+
+function maskedFrame(opcode: number, payload: Buffer) {
+  const mask = Buffer.from([1, 2, 3, 4]);
+  const masked = Buffer.from(payload.map((b, i) => b ^ mask[i % 4]));
+  const unmasked = encodeFrame(opcode, payload);
+  const header = Buffer.from(unmasked.subarray(0, unmasked.length - payload.length));
+  header[1] |= 0x80;
+  return Buffer.concat([header, mask, masked]);
+}
+
+function mockSocket() {
+  const socket = new EventEmitter() as EventEmitter & { write: any; end: any; destroy: any };
+  socket.write = vi.fn();
+  socket.end = vi.fn();
+  socket.destroy = vi.fn();
+  return socket;
+}
+
+describe('parseFrame', () => {
+  it('unmasks client frames', () => {
+    const frame = parseFrame(maskedFrame(0x2, Buffer.from('hello'))) as Frame;
+    expect(frame?.opcode).toBe(0x2);
+    expect(frame?.payload.toString()).toBe('hello');
+    expect(frame?.length).toBe(11);
+  });
+
+  it('waits for the whole frame', () => {
+    const data = maskedFrame(0x2, Buffer.from('hello'));
+    expect(parseFrame(data.subarray(0, 1))).toBeNull();
+    expect(parseFrame(data.subarray(0, 8))).toBeNull();
+  });
+
+  it('reads extended lengths', () => {
+    const payload = Buffer.alloc(70000, 7);
+    const frame = parseFrame(maskedFrame(0x2, payload)) as Frame;
+    expect(frame.payload.equals(payload)).toBe(true);
+    expect(frame.length).toBe(70014);
+
+    const medium = parseFrame(maskedFrame(0x2, Buffer.alloc(300))) as Frame;
+    expect(medium?.length).toBe(308);
+  });
+
+  it('refuses unmasked and oversized frames', () => {
+    expect(parseFrame(encodeFrame(0x2, Buffer.from('hello')))).toMatchObject({ status: 1002 });
+    // The length is enough to refuse the frame before its payload arrives.
+    const large = maskedFrame(0x2, Buffer.alloc(300)).subarray(0, 4);
+    expect(parseFrame(large, 200)).toMatchObject({ status: 1009 });
+  });
+});
+
+describe('checkUpgrade', () => {
+  const headers = { upgrade: 'websocket', 'sec-websocket-key': 'abc' };
+
+  it('refuses browsers', () => {
+    expect(checkUpgrade(headers)).toBeNull();
+    expect(checkUpgrade({ ...headers, origin: 'https://example.com' })).toBe('403 Forbidden');
+  });
+
+  it('checks the token', () => {
+    expect(checkUpgrade(headers, 'secret')).toBe('401 Unauthorized');
+    expect(checkUpgrade({ ...headers, authorization: 'Bearer wrong!' }, 'secret')).toBe(
+      '401 Unauthorized'
+    );
+    expect(checkUpgrade({ ...headers, authorization: 'Bearer secret' }, 'secret')).toBeNull();
+  });
+});
+
+describe('WebSocketConnection', () => {
+  it('passes on data and answers pings', () => {
+    const socket = mockSocket();
+    const conn = new WebSocketConnection(socket as unknown as Duplex);
+    const onData = vi.fn();
+    conn.on('data', onData);
+
+    const data = Buffer.concat([
+      maskedFrame(0x2, Buffer.from('abc')),
+      maskedFrame(0x9, Buffer.from('ping')),
+    ]);
+    // Split in the middle of a frame
+    socket.emit('data', data.subarray(0, 5));
+    socket.emit('data', data.subarray(5));
+
+    expect(onData).toHaveBeenCalledWith(Buffer.from('abc'));
+    expect(socket.write).toHaveBeenCalledWith(encodeFrame(0xa, Buffer.from('ping')));
+
+    conn.write(Buffer.from('out'));
+    expect(socket.write).toHaveBeenCalledWith(encodeFrame(0x2, Buffer.from('out')));
+  });
+
+  it('reassembles large frames', () => {
+    const socket = mockSocket();
+    const conn = new WebSocketConnection(socket as unknown as Duplex);
+    const onData = vi.fn();
+    conn.on('data', onData);
+
+    const payload = Buffer.alloc(200000, 5);
+    const data = maskedFrame(0x2, payload);
+    for (let offset = 0; offset < data.length; offset += 1000) {
+      socket.emit('data', data.subarray(offset, offset + 1000));
+    }
+
+    expect(onData).toHaveBeenCalledTimes(1);
+    expect(onData.mock.calls[0][0].equals(payload)).toBe(true);
+    expect(conn.buffered).toBe(0);
+  });
+
+  it('closes on unmasked frames', () => {
+    const socket = mockSocket();
+    const conn = new WebSocketConnection(socket as unknown as Duplex);
+    const onData = vi.fn();
+    conn.on('data', onData);
+
+    socket.emit('data', encodeFrame(0x2, Buffer.from('abc')));
+    expect(onData).not.toHaveBeenCalled();
+    expect(socket.end).toHaveBeenCalledWith(encodeFrame(0x8, Buffer.from([0x03, 0xea])));
+  });
+
+  it('closes once', () => {
+    const socket = mockSocket();
+    const conn = new WebSocketConnection(socket as unknown as Duplex);
+    const onClose = vi.fn();
+    conn.on('close', onClose);
+
+    socket.emit('data', maskedFrame(0x8, Buffer.from([0x03, 0xe8])));
+    socket.emit('close');
+
+    expect(onClose).toHaveBeenCalledTimes(1);
+    expect(socket.end).toHaveBeenCalledWith(encodeFrame(0x8, Buffer.from([0x03, 0xe8])));
+
+    conn.write(Buffer.from('ignored'));
+    expect(socket.write).not.toHaveBeenCalled();
+  });
+});
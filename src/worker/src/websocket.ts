@@ -0,0 +1,307 @@
+import crypto from 'node:crypto';
+import http from 'node:http';
+import { EventEmitter } from 'node:events';
+import type { Duplex } from 'node:stream';
+import { debug } from './debug.js';
+
+const HANDSHAKE_GUID = '258EAFA5-E914-47DA-95CA-C5AB0DC85B11';
+
+/** The largest frame payload accepted from a client. Larger frames close the connection. */
+export const MAX_FRAME_BYTES = 256 * 1024 * 1024;
+
+/** The initial size of a connection's receive buffer, which grows to fit larger frames. */
+const INITIAL_BUFFER_BYTES = 64 * 1024;
+
+/** Close codes from RFC 6455 */
+const CLOSE_PROTOCOL_ERROR = 1002;
+const CLOSE_TOO_LARGE = 1009;
+
+enum Opcode {
+  Continuation = 0x0,
+  Text = 0x1,
+  Binary = 0x2,
+  Close = 0x8,
+  Ping = 0x9,
+  Pong = 0xa,
+}
+
+export interface Frame {
+  opcode: number;
+  payload: Buffer;
+  /** The number of bytes that the frame took up */
+  length: number;
+}
+
+/** A frame that the connection has to be closed for, with the close code to send. */
+export interface FrameError {
+  error: string;
+  status: number;
+}
+
+/** Parse one frame from the start of `buffer`, or return null if it doesn't contain a whole
+ * frame yet. Clients must mask their frames, and payloads can't be over `maxPayload` bytes. */
+export function parseFrame(
+  buffer: Buffer,
+  maxPayload = MAX_FRAME_BYTES
+): Frame | FrameError | null {
+  if (buffer.length < 2) {
+    return null;
+  }
+
+  const opcode = buffer[0] & 0x0f;
+  if ((buffer[1] & 0x80) === 0) {
+    return { error: 'Client frames must be masked', status: CLOSE_PROTOCOL_ERROR };
+  }
+  let payloadLength = buffer[1] & 0x7f;
+  let offset = 2;
+
+  if (payloadLength === 126) {
+    if (buffer.length < offset + 2) {
+      return null;
+    }
+    payloadLength = buffer.readUInt16BE(offset);
+    offset += 2;
+  } else if (payloadLength === 127) {
+    if (buffer.length < offset + 8) {
+      return null;
+    }
+    payloadLength = Number(buffer.readBigUInt64BE(offset));
+    offset += 8;
+  }
+
+  if (payloadLength > maxPayload) {
+    return { error: `Frame of ${payloadLength} bytes is too large`, status: CLOSE_TOO_LARGE };
+  }
+
+  if (buffer.length < offset + 4) {
+    return null;
+  }
+  const mask = buffer.subarray(offset, offset + 4);
+  offset += 4;
+
+  if (buffer.length < offset + payloadLength) {
+    return null;
+  }
+
+  const payload = Buffer.from(buffer.subarray(offset, offset + payloadLength));
+  for (let i = 0; i < payload.length; i++) {
+    payload[i] ^= mask[i % 4];
+  }
+
+  return { opcode, payload, length: offset + payloadLength };
+}
+
+/** Encode an unmasked frame, as sent by a server. */
+export function encodeFrame(opcode: number, payload: Buffer): Buffer {
+  let header: Buffer;
+  if (payload.length < 126) {
+    header = Buffer.alloc(2);
+    header[1] = payload.length;
+  } else if (payload.length < 65536) {
+    header = Buffer.alloc(4);
+    header[1] = 126;
+    header.writeUInt16BE(payload.length, 2);
+  } else {
+    header = Buffer.alloc(10);
+    header[1] = 127;
+    header.writeBigUInt64BE(BigInt(payload.length), 2);
+  }
+
+  header[0] = 0x80 | opcode;
+  return Buffer.concat([header, payload]);
+}
+
+/** The server side of a WebSocket connection, presenting the payloads of the data frames as a
+ * stream of bytes so that it can be used in place of a socket by the Protocol. */
+export class WebSocketConnection extends EventEmitter<{ data: [Buffer]; close: [] }> {
+  socket: Duplex;
+  /** Received data that doesn't make up a whole frame yet, in the first `buffered` bytes */
+  buffer: Buffer;
+  buffered: number;
+  closed: boolean;
+
+  constructor(socket: Duplex) {
+    super();
+    this.socket = socket;
+    this.buffer = Buffer.allocUnsafe(INITIAL_BUFFER_BYTES);
+    this.buffered = 0;
+    this.closed = false;
+    socket.on('data', (data: Buffer) => this.handleData(data));
+    socket.on('close', () => this.handleClose());
+    socket.on('error', (e) => {
+      debug('WebSocket error', e);
+      socket.destroy();
+    });
+  }
+
+  handleData(data: Buffer) {
+    // Grow the buffer by doubling, so that a large frame arriving in many chunks isn't copied
+    // again for each one. Frames over the limit are refused before the buffer gets that large.
+    const needed = this.buffered + data.length;
+    if (needed > this.buffer.length) {
+      const grown = Buffer.allocUnsafe(Math.max(needed, this.buffer.length * 2));
+      this.buffer.copy(grown, 0, 0, this.buffered);
+      this.buffer = grown;
+    }
+    data.copy(this.buffer, this.buffered);
+    this.buffered = needed;
+
+    let offset = 0;
+    while (!this.closed) {
+      const frame = parseFrame(this.buffer.subarray(offset, this.buffered));
+      if (!frame) {
+        break;
+      }
+      if ('error' in frame) {
+        debug(`Closing WebSocket: ${frame.error}`);
+        this.fail(frame.status);
+        return;
+      }
+      offset += frame.length;
+      this.handleFrame(frame);
+    }
+
+    this.buffer.copyWithin(0, offset, this.buffered);
+    this.buffered -= offset;
+  }
+
+  handleFrame({ opcode, payload }: Frame) {
+    switch (opcode) {
+      case Opcode.Continuation:
+      case Opcode.Text:
+      case Opcode.Binary:
+        // Message boundaries don't matter since the protocol has its own framing.
+        this.emit('data', payload);
+        break;
+      case Opcode.Ping:
+        this.socket.write(encodeFrame(Opcode.Pong, payload));
+        break;
+      case Opcode.Pong:
+        break;
+      case Opcode.Close:
+        if (!this.closed) {
+          this.socket.end(encodeFrame(Opcode.Close, payload.subarray(0, 2)));
+        }
+        this.handleClose();
+        break;
+    }
+  }
+
+  handleClose() {
+    if (!this.closed) {
+      this.closed = true;
+      this.emit('close');
+    }
+  }
+
+  write(data: Buffer, callback?: () => void) {
+    if (!this.closed) {
+      this.socket.write(encodeFrame(Opcode.Binary, data), callback);
+    }
+  }
+
+  /** Close the connection with an error status. */
+  fail(status: number) {
+    if (!this.closed) {
+      const payload = Buffer.alloc(2);
+      payload.writeUInt16BE(status);
+      this.socket.end(encodeFrame(Opcode.Close, payload));
+      this.handleClose();
+    }
+  }
+
+  /** Send a normal closure frame and close the socket. */
+  end() {
+    if (!this.closed) {
+      const status = Buffer.alloc(2);
+      status.writeUInt16BE(1000);
+      this.socket.end(encodeFrame(Opcode.Close, status));
+    }
+  }
+}
+
+/** Split a `host:port` address, as formatted by Rust's `SocketAddr`, into its parts. */
+export function parseAddress(address: string) {
+  const separator = address.lastIndexOf(':');
+  return {
+    host: address.slice(0, separator).replace(/^\[(.*)\]$/, '$1'),
+    port: parseInt(address.slice(separator + 1), 10),
+  };
+}
+
+/** Check the headers of an upgrade request, returning the HTTP status to refuse it with, or null
+ * to accept it. Requests from browsers, which always send an `Origin`, are refused so that a web
+ * page can't reach the workers, and when `token` is set the request must carry it as a bearer
+ * token. */
+export function checkUpgrade(headers: http.IncomingHttpHeaders, token?: string): string | null {
+  if (
+    headers.upgrade?.toLowerCase() !== 'websocket' ||
+    typeof headers['sec-websocket-key'] !== 'string'
+  ) {
+    return '400 Bad Request';
+  }
+  if (headers.origin !== undefined) {
+    return '403 Forbidden';
+  }
+  if (token) {
+    const expected = Buffer.from(`Bearer ${token}`);
+    const given = Buffer.from(headers.authorization ?? '');
+    if (given.length !== expected.length || !crypto.timingSafeEqual(given, expected)) {
+      return '401 Unauthorized';
+    }
+  }
+  return null;
+}
+
+/** Start an HTTP server at `address` that accepts WebSocket connections and passes them to
+ * `accept`. When `token` is set, connections must send it in an `Authorization: Bearer` header. */
+export function listenWebSocket(
+  address: string,
+  accept: (conn: WebSocketConnection) => void,
+  token = process.env.WEBSOCKET_TOKEN || undefined
+) {
+  const { host, port } = parseAddress(address);
+
+  const server = http.createServer((_req, res) => {
+    res.writeHead(426, { Connection: 'Upgrade', Upgrade: 'websocket' });
+    res.end();
+  });
+
+  server.on('upgrade', (req, socket, head) => {
+    const refused = checkUpgrade(req.headers, token);
+    if (refused) {
+      socket.end(`HTTP/1.1 ${refused}\r\n\r\n`);
+      return;
+    }
+
+    const key = req.headers['sec-websocket-key'] as string;
+
+    const accepted = crypto
+      .createHash('sha1')
+      .update(key + HANDSHAKE_GUID)
+      .digest('base64');
+    socket.write(
+      'HTTP/1.1 101 Switching Protocols\r\n' +
+        'Upgrade: websocket\r\n' +
+        'Connection: Upgrade\r\n' +
+        `Sec-WebSocket-Accept: ${accepted}\r\n\r\n`
+    );
+
+    const conn = new WebSocketConnection(socket);
+    accept(conn);
+    if (head.length > 0) {
+      conn.handleData(head);
+    }
+  });
+
+  server.on('error', (e) => {
+    console.error(e);
+    process.exit(1);
+  });
+
+  server.listen(port, host, () => {
+    debug(`Worker ${process.pid} is listening for WebSockets on ${address}`);
+  });
+
+  return server;
+}
@@ -1,24 +1,78 @@
 import net from 'node:net';
+import { monitorEventLoopDelay, type IntervalHistogram } from 'node:perf_hooks';
 import cluster from 'node:cluster';
-import { Protocol, type IncomingMessage } from './protocol.js';
+import { parentPort, workerData } from 'node:worker_threads';
+import { Protocol, splitBinaryPayload, type IncomingMessage, type Transport } from './protocol.js';
 import type { MessageContext } from './types.js';
-import { runScript } from './run_script.js';
-import { HostToWorkerMessage, WorkerToHostMessage } from './api_types.js';
+import {
+  advanceTime,
+  complete,
+  introspectContext,
+  prewarmContext,
+  reportAsyncError,
+  runScript,
+  startContextPool,
+} from './run_script.js';
+import {
+  HostToWorkerMessage,
+  WorkerToHostMessage,
+  type AsyncErrorSource,
+  type LogLevel,
+  type MemoryUsage,
+  type RunScriptArgs,
+  type WorkerLoad,
+} from './api_types.js';
 import { debug } from './debug.js';
+import { checkScript } from './check.js';
+import { activeRequests, crashReport, formatAnnotations } from './annotations.js';
+import { listenWebSocket } from './websocket.js';
+import { activeRunCount, handleStatsRequests, workerStats } from './management.js';
+import type { CrashMessage } from './events.js';
+import { sendHeapSnapshot } from './inspector.js';
+import { attachWasmBytes } from './wasm.js';
+import { Lanes } from './lanes.js';
+import { cancelledError } from './abort.js';
+import { InputStream } from './input.js';
+import { ScriptChannel } from './channel.js';
+import { errorKind, internalError } from './errors.js';
+import { cancelJob, jobStatus, startJob } from './jobs.js';
+import {
+  runInThread,
+  toBuffer,
+  ThreadTransport,
+  type Isolation,
+  type ThreadData,
+  type ThreadMessage,
+} from './threads.js';
 
-export function runWorker(socketPath: string) {
+export function runWorker(
+  socketPath: string,
+  websocketAddress?: string,
+  isolation: Isolation = 'process'
+) {
   debug(`Worker ${process.pid} started`);
-  const server = net.createServer();
+  startContextPool(parseInt(process.env.CONTEXT_POOL_SIZE ?? '0', 10));
+  const websocketServer = websocketAddress ? listenWebSocket(websocketAddress, accept) : null;
   const shutdown = () => {
     debug(`Worker ${process.pid} is shutting down`);
-    server.close(() => process.exit(0));
+    websocketServer?.close();
+    // The primary has stopped passing on connections, so exit once the host closes these.
+    const exitWhenClosed = () => {
+      if (sockets.size === 0) {
+        process.exit(0);
+      }
+      setTimeout(exitWhenClosed, 10);
+    };
+    exitWhenClosed();
   };
 
-  process.on('message', (msg) => {
+  process.on('message', (msg: unknown, handle: unknown) => {
     debug(`Worker ${process.pid} received message: ${msg}`);
     if (msg == 'shutdown') {
       debug(`Worker ${process.pid} received shutdown message`);
       shutdown();
+    } else if (msg === 'connection' && handle instanceof net.Socket) {
+      acceptSocket(handle);
     }
   });
 
@@ -30,28 +84,181 @@ export function runWorker(socketPath: string) {
   process.on('SIGTERM', shutdown);
   process.on('SIGINT', shutdown);
 
-  function accept(socket: net.Socket) {
+  // The host sends SIGUSR2 to replace a worker that is using too much memory. Stop taking new
+  // connections and exit once the current requests finish, and the primary will start a new worker.
+  process.on('SIGUSR2', () => {
+    debug(`Worker ${process.pid} is retiring`);
+    websocketServer?.close();
+    cluster.worker?.send('retiring');
+    const exitWhenIdle = () => {
+      if (activeRunCount(connections) === 0) {
+        process.exit(0);
+      }
+      setTimeout(exitWhenIdle, 10);
+    };
+    exitWhenIdle();
+  });
+
+  const connections = new Set<Protocol>();
+  /** The connections that the primary passed on from the host's socket. */
+  const sockets = new Set<net.Socket>();
+  handleStatsRequests(() => workerStats(connections, requestsHandled));
+
+  const reportInterval = parseInt(process.env.MEMORY_REPORT_INTERVAL ?? '0', 10);
+  if (reportInterval > 0) {
+    setInterval(() => reportMemoryUsage(connections), reportInterval).unref();
+  }
+
+  const loadInterval = parseInt(process.env.LOAD_REPORT_INTERVAL ?? '0', 10);
+  if (loadInterval > 0) {
+    const eventLoopDelay = monitorEventLoopDelay({ resolution: 10 });
+    eventLoopDelay.enable();
+    setInterval(() => reportLoad(connections, eventLoopDelay), loadInterval).unref();
+  }
+
+  const crash = (e: unknown) => {
+    const report = crashReport(e, activeRequests);
+    console.error(report);
+    if (!process.send) {
+      process.exit(1);
+    }
+
+    // Let the primary pass the report on to the host before exiting.
+    const message: CrashMessage = { type: 'crash', report };
+    process.send?.(message, () => process.exit(1));
+  };
+  handleAsyncErrors(crash);
+
+  function accept(socket: Transport) {
     let protocol = new Protocol(socket);
-    protocol.on('message', (message) => handleRawMessage(protocol, message));
+    connections.add(protocol);
+    socket.on('close', () => connections.delete(protocol));
+    if (isolation === 'thread') {
+      runInThread(protocol, socket, () => (requestsHandled += 1));
+    } else {
+      protocol.on('message', (message) => handleRawMessage(protocol, message));
+    }
+  }
+
+  function acceptSocket(socket: net.Socket) {
+    sockets.add(socket);
+    socket.on('close', () => sockets.delete(socket));
+    // The host can close a connection while a response is still being written to it.
+    socket.on('error', (e) => debug('Socket error', e));
+    accept(socket);
   }
 
-  server.on('error', (e) => {
-    console.error(e);
+  debug(`Worker ${process.pid} is taking connections on ${socketPath}`);
+}
+
+/** Handle the requests of one connection inside a worker thread started by `runInThread`. */
+export function runThread() {
+  const port = parentPort!;
+  const protocol = new Protocol(new ThreadTransport(port));
+  // The main thread replaces a thread that exits, failing the runs that were in it.
+  handleAsyncErrors((e) => {
+    console.error(crashReport(e, activeRequests));
     process.exit(1);
   });
+  startContextPool(parseInt(process.env.CONTEXT_POOL_SIZE ?? '0', 10));
+  const { handshake } = workerData as ThreadData;
+  if (handshake) {
+    protocol.handshake(0, toBuffer(handshake), false);
+  }
 
-  server.listen(socketPath, () => {
-    debug(`Worker ${process.pid} is listening on ${socketPath}`);
-    server.on('connection', accept);
+  port.on('message', (message: IncomingMessage) => {
+    const { reqId } = message;
+    handleRawMessage(protocol, { ...message, data: toBuffer(message.data) })?.finally(() => {
+      const finished: ThreadMessage = { type: 'finished', reqId };
+      port.postMessage(finished);
+    });
+  });
+}
+
+let requestsHandled = 0;
+const lanes = new Lanes();
+
+/** Send errors that escape from a context's code to the host, and treat any others as a crash. */
+function handleAsyncErrors(crash: (e: unknown) => void) {
+  process.on('uncaughtException', (e) => {
+    if (!reportAsyncError(e, 'uncaughtException')) {
+      crash(e);
+    }
+  });
+  process.on('unhandledRejection', (reason, promise) => {
+    if (!reportAsyncError(reason, 'unhandledRejection', promise)) {
+      crash(reason);
+    }
   });
 }
 
+function reportMemoryUsage(connections: Set<Protocol>) {
+  const { heapUsed, heapTotal, rss, external } = process.memoryUsage();
+  const usage: MemoryUsage = { pid: process.pid, heapUsed, heapTotal, rss, external };
+  const data = JSON.stringify(usage);
+  for (const protocol of connections) {
+    protocol.sendMessage(0, WorkerToHostMessage.MemoryUsage, data);
+  }
+}
+
+function reportLoad(connections: Set<Protocol>, eventLoopDelay: IntervalHistogram) {
+  const load: WorkerLoad = {
+    pid: process.pid,
+    activeRuns: activeRunCount(connections),
+    connections: connections.size,
+    // The histogram is in nanoseconds, and its mean is NaN when it has no samples.
+    eventLoopDelayMs: (eventLoopDelay.mean || 0) / 1e6,
+  };
+  eventLoopDelay.reset();
+
+  const data = JSON.stringify(load);
+  for (const protocol of connections) {
+    protocol.sendMessage(0, WorkerToHostMessage.WorkerLoad, data);
+  }
+}
+
+/** Handle a message from the host, returning a promise that settles once the request is done, for
+ * messages that start a request. */
 function handleRawMessage(protocol: Protocol, { id, reqId, type, data }: IncomingMessage) {
   if (type === HostToWorkerMessage.Ping) {
-    protocol.sendMessage(reqId, WorkerToHostMessage.Pong, Buffer.alloc(0));
+    protocol.sendMessage(reqId, WorkerToHostMessage.Pong, JSON.stringify({ pid: process.pid }));
+    return;
+  }
+
+  if (type === HostToWorkerMessage.Handshake) {
+    protocol.handshake(reqId, data);
+    return;
+  }
+
+  if (type === HostToWorkerMessage.KvResponse) {
+    protocol.kvResponse(data);
     return;
   }
 
+  if (type === HostToWorkerMessage.ResolveModuleResponse) {
+    protocol.moduleResolved(data);
+    return;
+  }
+
+  if (type === HostToWorkerMessage.Cancel) {
+    cancelRequest(protocol, reqId);
+    return;
+  }
+
+  if (type === HostToWorkerMessage.Input || type === HostToWorkerMessage.InputEnd) {
+    sendInput(protocol, reqId, type === HostToWorkerMessage.Input ? data : null);
+    return;
+  }
+
+  if (
+    type === HostToWorkerMessage.ChannelMessage ||
+    type === HostToWorkerMessage.ChannelClose
+  ) {
+    sendToChannel(protocol, reqId, type === HostToWorkerMessage.ChannelMessage ? data : null);
+    return;
+  }
+
+  requestsHandled += 1;
   let start = process.hrtime.bigint();
 
   let sentResponse = false;
@@ -59,33 +266,63 @@ function handleRawMessage(protocol: Protocol, { id, reqId, type, data }: Incomin
     protocol,
     reqId,
     id,
-    log(message: any, level: keyof Console = 'info') {
+    abort: new AbortController(),
+    log(message: any, level: LogLevel = 'info', namespace?: string) {
+      if (context.redactor) {
+        message = context.redactor.redactValue(message);
+      }
       debug(`${reqId}[${level}]:`, message);
-      protocol.log(reqId, level, message);
+      protocol.log(reqId, level, message, namespace, context.logBudget);
     },
     respond(data: any) {
       sentResponse = true;
-      protocol.respond(reqId, data);
+      sendLogSummary();
+      protocol.respond(reqId, context.redactor ? context.redactor.redactValue(data) : data);
     },
     error(e: Error) {
-      debug(`${reqId}: `, e.message);
-      protocol.error(reqId, e);
+      const kind = errorKind(e, context.abort?.signal);
+      if (context.redactor) {
+        e = context.redactor.redactError(e);
+      }
+      debug(`${reqId}: `, e.message, formatAnnotations(context.annotations));
+      sendLogSummary();
+      protocol.error(reqId, e, context.annotations, kind);
+    },
+    output(data: Buffer) {
+      return protocol.output(reqId, data);
+    },
+    asyncError(e: unknown, source: AsyncErrorSource) {
+      if (context.redactor) {
+        e = context.redactor.redactError(e);
+      }
+      debug(`${reqId}: ${source}`, e, formatAnnotations(context.annotations));
+      protocol.asyncError(reqId, e, source, context.annotations);
     },
   };
 
-  handleMessage(context, type, data)
+  /** Tell the host about console messages that were dropped, just before the run finishes. */
+  const sendLogSummary = () => {
+    const summary = context.logBudget?.summary();
+    if (summary) {
+      protocol.log(reqId, 'warn', summary);
+    }
+  };
+
+  activeRequests.add(context);
+  return handleMessage(context, type, data)
     .then((response) => {
       if (response != undefined || !sentResponse) {
         context.respond(response ?? null);
       }
 
       let elapsed = Number(process.hrtime.bigint() - start) / 1e3;
-      debug(`handle: ${elapsed}us`);
+      debug(`handle: ${elapsed}us`, formatAnnotations(context.annotations));
     })
     .catch((e) => {
       debug('Failed to handle request:');
       context.error(e);
-    });
+    })
+    .finally(() => activeRequests.delete(context));
 }
 
 async function handleMessage(
@@ -95,7 +332,134 @@ async function handleMessage(
 ): Promise<any> {
   switch (type) {
     case HostToWorkerMessage.RunScript: {
-      return runScript(JSON.parse(data.toString()), ctx);
+      return startRun(parseRequest(data), ctx);
+    }
+    case HostToWorkerMessage.RunScriptBinary: {
+      const { json, binary } = splitBinaryPayload(data);
+      attachWasmBytes(json.wasmModules ?? [], binary);
+      return startRun(json, ctx);
+    }
+    case HostToWorkerMessage.HeapSnapshot: {
+      await sendHeapSnapshot(ctx);
+      return {};
+    }
+    case HostToWorkerMessage.AdvanceTime: {
+      return { returnValue: await advanceTime(parseRequest(data), ctx) };
+    }
+    case HostToWorkerMessage.Complete: {
+      return { returnValue: complete(parseRequest(data), ctx) };
+    }
+    case HostToWorkerMessage.Introspect: {
+      return { returnValue: introspectContext(parseRequest(data), ctx) };
+    }
+    case HostToWorkerMessage.CheckScript: {
+      return { returnValue: checkScript(parseRequest(data)) };
+    }
+    case HostToWorkerMessage.JobStatus: {
+      return { returnValue: jobStatus(parseRequest(data)) };
+    }
+    case HostToWorkerMessage.JobCancel: {
+      return { returnValue: cancelJob(parseRequest(data)) };
+    }
+    case HostToWorkerMessage.Prewarm: {
+      prewarmContext(parseRequest(data));
+      return {};
+    }
+  }
+}
+
+/** Parse the JSON payload of a request. A payload that doesn't parse is the host's fault, so it
+ * isn't reported as a syntax error in the script. */
+function parseRequest(data: Buffer) {
+  try {
+    return JSON.parse(data.toString());
+  } catch (e) {
+    throw internalError(`Request payload is not valid JSON: ${(e as Error).message}`);
+  }
+}
+
+/** The request on the connection with the given ID, if it is still running. */
+function findRequest(protocol: Protocol, reqId: number) {
+  for (const request of activeRequests) {
+    if (request.protocol === protocol && request.reqId === reqId) {
+      return request;
+    }
+  }
+  return undefined;
+}
+
+/** Abort the signal of a request on the connection, if it is still running. */
+function cancelRequest(protocol: Protocol, reqId: number) {
+  const request = findRequest(protocol, reqId);
+  if (request) {
+    debug(`${reqId}: cancelled by the host`);
+    request.abort?.abort(cancelledError());
+  }
+}
+
+/** Pass a chunk of input to a request on the connection, or end its input if `data` is null.
+ * Input can arrive while the run waits for its lane, before it has started. */
+function sendInput(protocol: Protocol, reqId: number, data: Buffer | null) {
+  const request = findRequest(protocol, reqId);
+  if (!request) {
+    debug(`${reqId}: dropping input for a request that isn't running`);
+    return;
+  }
+
+  request.input ??= new InputStream(request.abort?.signal);
+  if (data) {
+    request.input.push(data);
+  } else {
+    request.input.end();
+  }
+}
+
+/** Pass a value from the host to a request's channel, or close the channel if `data` is null. */
+function sendToChannel(protocol: Protocol, reqId: number, data: Buffer | null) {
+  const request = findRequest(protocol, reqId);
+  if (!request) {
+    debug(`${reqId}: dropping a channel message for a request that isn't running`);
+    return;
+  }
+
+  request.channel ??= new ScriptChannel(request);
+  if (data) {
+    request.channel.incoming.push(JSON.parse(data.toString()));
+  } else {
+    request.channel.incoming.end();
+  }
+}
+
+/** Run a script, or start it as a background job and respond with the job's status if `detach` is
+ * set. */
+function startRun(args: RunScriptArgs, ctx: MessageContext) {
+  if (!args.detach) {
+    return runInLane(args, ctx);
+  }
+
+  // A connection's thread exits when the connection closes, which would take its jobs with it.
+  if (parentPort) {
+    throw new Error('Background jobs are not supported with thread isolation');
+  }
+  return { returnValue: startJob(args, ctx, runScript) };
+}
+
+async function runInLane(args: RunScriptArgs, ctx: MessageContext) {
+  const release = await lanes.enter(args.priority);
+  try {
+    // The run may have been cancelled while it waited for its turn.
+    ctx.abort?.signal.throwIfAborted();
+    if (parentPort) {
+      // The main thread times the run from here.
+      const started: ThreadMessage = {
+        type: 'started',
+        reqId: ctx.reqId,
+        timeoutMs: args.timeoutMs ?? null,
+      };
+      parentPort.postMessage(started);
     }
+    return await runScript(args, ctx);
+  } finally {
+    release();
   }
 }